@@ -0,0 +1,300 @@
+/*
+Copyright 2019-2024 Andy Georges <itkovian+sarchive@gmail.com>
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in
+all copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+use chrono::{Local, NaiveDate, NaiveDateTime, TimeZone};
+use crossbeam_channel::{Receiver, RecvTimeoutError, Sender};
+use log::{debug, info};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Error, ErrorKind, Seek, SeekFrom};
+use std::path::Path;
+use std::time::Duration;
+
+use crate::completion::{Completion, STARTED_STATE};
+
+/// How long to wait between checks for new records in the log
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Format of the start and end times, as in Slurm's job completion log
+const TIME_FORMAT: &str = "%Y-%m-%dT%H:%M:%S";
+
+/// Parses a record of the Torque accounting log, returning the start or the
+/// end of the job it reports, if any
+///
+/// A record such as `04/17/2024 10:22:33;E;1234.master;user=alice Exit_status=0`
+/// has the time it was logged, the record type, the job ID and the job's
+/// `key=value` fields. The start (S), end (E), deletion (D) and abort (A) of a
+/// job are reported; the other record types are skipped.
+pub fn parse_record(line: &str, cluster: &str) -> Option<Completion> {
+    let mut parts = line.splitn(4, ';');
+    let (logged, kind, jobid) = (parts.next()?, parts.next()?, parts.next()?);
+    let fields: HashMap<String, String> = parts
+        .next()
+        .unwrap_or_default()
+        .split_whitespace()
+        .filter_map(|field| field.split_once('='))
+        .map(|(key, value)| (key.to_owned(), value.to_owned()))
+        .collect();
+    let logged = NaiveDateTime::parse_from_str(logged, "%m/%d/%Y %H:%M:%S")
+        .ok()
+        .map(|t| t.format(TIME_FORMAT).to_string());
+    let epoch = |key: &str| {
+        fields
+            .get(key)
+            .and_then(|t| t.parse::<i64>().ok())
+            .and_then(|t| Local.timestamp_opt(t, 0).single())
+            .map(|t| t.format(TIME_FORMAT).to_string())
+    };
+
+    let (state, exit_code, end_time) = match kind {
+        "S" => (STARTED_STATE, None, None),
+        "E" => {
+            let exit_code = fields.get("Exit_status").cloned();
+            let state = match exit_code.as_deref().and_then(|c| c.parse::<i64>().ok()) {
+                Some(0) => "COMPLETED",
+                // killed by a signal, whose number is added to 256
+                Some(code) if code > 256 => "CANCELLED",
+                _ => "FAILED",
+            };
+            (state, exit_code, epoch("end").or(logged))
+        }
+        "D" => ("CANCELLED", None, logged),
+        "A" => ("FAILED", None, logged),
+        _ => return None,
+    };
+    let start_time = epoch("start");
+    Some(Completion {
+        jobid: spool_jobid(jobid),
+        cluster: cluster.to_owned(),
+        state: Some(state.to_owned()),
+        exit_code,
+        start_time,
+        end_time,
+        fields,
+    })
+}
+
+/// Returns the job ID the spool uses for the job ID in the accounting log,
+/// which differ for arrays: the log has `2[].master` for the array and
+/// `2[1].master` for a task, where the spool has `2.master` and `2-1.master`
+fn spool_jobid(jobid: &str) -> String {
+    let Some((array_id, rest)) = jobid.split_once('[') else {
+        return jobid.to_owned();
+    };
+    match rest.split_once(']') {
+        Some(("", suffix)) => format!("{array_id}{suffix}"),
+        Some((index, suffix)) => format!("{array_id}-{index}{suffix}"),
+        None => jobid.to_owned(),
+    }
+}
+
+/// Opens the accounting log of the given day, which Torque names after it
+/// (e.g., `20240417`), if it was created yet
+fn open(dir: &Path, day: NaiveDate) -> Result<Option<BufReader<File>>, Error> {
+    match File::open(dir.join(day.format("%Y%m%d").to_string())) {
+        Ok(file) => Ok(Some(BufReader::new(file))),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Sends the records in the complete lines read from the log, keeping a
+/// partial line for the next call
+fn read_records(
+    reader: &mut BufReader<File>,
+    line: &mut String,
+    cluster: &str,
+    s: &Sender<Completion>,
+) -> Result<(), Error> {
+    loop {
+        let n = reader.read_line(line)?;
+        if n == 0 || !line.ends_with('\n') {
+            return Ok(());
+        }
+        if let Some(completion) = parse_record(line.trim_end(), cluster) {
+            debug!(
+                "Found the {} of job {}",
+                completion.event(),
+                completion.jobid
+            );
+            if s.send(completion).is_err() {
+                return Err(Error::new(ErrorKind::BrokenPipe, "Processing stopped"));
+            }
+        }
+        line.clear();
+    }
+}
+
+/// The follow function follows the accounting log of the day in the given
+/// directory (e.g., `/var/spool/torque/server_priv/accounting`), starting at
+/// its end (or at its start, to pick up the records logged while sarchive was
+/// not running), and sends the start and end of every job it finds. At
+/// midnight, it moves on to the log of the new day, reading it from the start.
+/// At the same time, it checks if there is an incoming notification that it should
+/// stop. Upon receipt, it returns.
+pub fn follow(
+    dir: &Path,
+    cluster: &str,
+    s: &Sender<Completion>,
+    sigchannel: &Receiver<bool>,
+    from_start: bool,
+) -> Result<(), Error> {
+    info!("Following the Torque accounting logs in {:?}", dir);
+    let mut day = Local::now().date_naive();
+    let mut reader = open(dir, day)?;
+    if let Some(reader) = reader.as_mut().filter(|_| !from_start) {
+        reader.seek(SeekFrom::End(0))?;
+    }
+    let mut line = String::new();
+
+    loop {
+        if let Some(reader) = reader.as_mut() {
+            read_records(reader, &mut line, cluster, s)?;
+        }
+
+        match sigchannel.recv_timeout(POLL_INTERVAL) {
+            Ok(true) | Err(RecvTimeoutError::Disconnected) => return Ok(()),
+            _ => (),
+        }
+
+        let today = Local::now().date_naive();
+        if today != day {
+            // the records logged just before midnight go first
+            if let Some(reader) = reader.as_mut() {
+                read_records(reader, &mut line, cluster, s)?;
+            }
+            debug!("Moving on to the accounting log of {}", today);
+            day = today;
+            reader = None;
+            line.clear();
+        }
+        if reader.is_none() {
+            reader = open(dir, day)?;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crossbeam_channel::unbounded;
+    use crossbeam_utils::thread::scope;
+    use std::fs::OpenOptions;
+    use std::io::Write;
+    use std::thread::sleep;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_parse_record() {
+        let start = Local.timestamp_opt(1713342153, 0).unwrap();
+        let end = Local.timestamp_opt(1713345753, 0).unwrap();
+
+        let started = parse_record(
+            "04/17/2024 10:22:33;S;1234.master;user=alice queue=batch start=1713342153",
+            "mycluster",
+        )
+        .unwrap();
+        assert_eq!(started.jobid, "1234.master");
+        assert_eq!(started.cluster, "mycluster");
+        assert_eq!(started.state.as_deref(), Some(STARTED_STATE));
+        assert_eq!(
+            started.start_time,
+            Some(start.format(TIME_FORMAT).to_string())
+        );
+        assert_eq!(started.end_time, None);
+        assert_eq!(started.fields["queue"], "batch");
+        assert!(!started.is_final());
+        assert_eq!(started.event(), "started");
+
+        let ended = parse_record(
+            "04/17/2024 11:22:33;E;1234.master;user=alice start=1713342153 end=1713345753 Exit_status=0 resources_used.walltime=01:00:00",
+            "mycluster",
+        )
+        .unwrap();
+        assert_eq!(ended.state.as_deref(), Some("COMPLETED"));
+        assert_eq!(ended.exit_code.as_deref(), Some("0"));
+        assert_eq!(ended.end_time, Some(end.format(TIME_FORMAT).to_string()));
+        assert_eq!(ended.fields["resources_used.walltime"], "01:00:00");
+        assert!(ended.is_final());
+        assert_eq!(ended.event(), "completed");
+
+        let failed = parse_record("04/17/2024 11:22:33;E;1234.master;Exit_status=1", "c").unwrap();
+        assert_eq!(failed.state.as_deref(), Some("FAILED"));
+        assert_eq!(failed.end_time.as_deref(), Some("2024-04-17T11:22:33"));
+        let killed =
+            parse_record("04/17/2024 11:22:33;E;1234.master;Exit_status=271", "c").unwrap();
+        assert_eq!(killed.state.as_deref(), Some("CANCELLED"));
+
+        let deleted = parse_record(
+            "04/17/2024 10:25:00;D;1234.master;requestor=alice@login",
+            "c",
+        )
+        .unwrap();
+        assert_eq!(deleted.state.as_deref(), Some("CANCELLED"));
+        assert_eq!(deleted.end_time.as_deref(), Some("2024-04-17T10:25:00"));
+        assert!(deleted.is_final());
+
+        // array jobs and their tasks get the job ID of their spool files
+        let task = parse_record("04/17/2024 11:22:33;E;2[1].master;Exit_status=0", "c").unwrap();
+        assert_eq!(task.jobid, "2-1.master");
+        let array = parse_record("04/17/2024 11:22:33;E;2[].master;Exit_status=0", "c").unwrap();
+        assert_eq!(array.jobid, "2.master");
+
+        assert!(parse_record("04/17/2024 10:20:00;Q;1234.master;queue=batch", "c").is_none());
+        assert!(parse_record("not a record", "c").is_none());
+    }
+
+    #[test]
+    fn test_follow() {
+        let tdir = tempdir().unwrap();
+        let path = tdir
+            .path()
+            .join(Local::now().date_naive().format("%Y%m%d").to_string());
+        let mut log = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .unwrap();
+        writeln!(log, "04/17/2024 10:22:33;S;1.master;start=1713342153").unwrap();
+
+        let (tx, rx) = unbounded();
+        let (sig_tx, sig_rx) = unbounded();
+        scope(|s| {
+            s.spawn(|_| follow(tdir.path(), "mycluster", &tx, &sig_rx, false).unwrap());
+            sleep(Duration::from_millis(200));
+            writeln!(log, "04/17/2024 11:22:33;E;2.master;Exit_status=0").unwrap();
+
+            // the record logged before following is skipped
+            let completion = rx.recv_timeout(Duration::from_secs(5)).unwrap();
+            assert_eq!(completion.jobid, "2.master");
+            sig_tx.send(true).unwrap();
+        })
+        .unwrap();
+
+        // from the start, every record is sent
+        let (tx, rx) = unbounded();
+        sig_tx.send(true).unwrap();
+        follow(tdir.path(), "mycluster", &tx, &sig_rx, true).unwrap();
+        let jobids: Vec<_> = rx.try_iter().map(|c| c.jobid).collect();
+        assert_eq!(jobids, ["1.master", "2.master"]);
+    }
+}
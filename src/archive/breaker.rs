@@ -0,0 +1,265 @@
+/*
+Copyright 2019-2024 Andy Georges <itkovian+sarchive@gmail.com>
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in
+all copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+use log::{debug, info, warn};
+use std::fmt;
+use std::io::Error;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use super::{is_storage_full, Archive};
+use crate::completion::Completion;
+use crate::scheduler::job::JobRecord;
+
+/// How long to wait before retrying an entry after a failure that did not
+/// open the circuit
+const RETRY_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The error returned by the circuit breaker for an entry that could not be
+/// archived, telling when to try again. The entry should be held until then.
+#[derive(Debug)]
+pub struct Held {
+    /// Whether the circuit is open, i.e., the backend is given time to recover
+    pub open: bool,
+    /// How long to wait before trying again
+    pub retry_in: Duration,
+    reason: String,
+}
+
+impl fmt::Display for Held {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.reason)
+    }
+}
+
+impl std::error::Error for Held {}
+
+/// Returns the details of the error, if the entry should be held and retried
+pub fn held(e: &Error) -> Option<&Held> {
+    e.get_ref().and_then(|inner| inner.downcast_ref::<Held>())
+}
+
+fn held_error(open: bool, retry_in: Duration, reason: String) -> Error {
+    Error::other(Held {
+        open,
+        retry_in,
+        reason,
+    })
+}
+
+struct State {
+    failures: u32,
+    opened: Option<Instant>,
+}
+
+/// Wraps an archiver so a backend that keeps failing is left alone for a while
+///
+/// After the given number of consecutive failures, the circuit opens: entries
+/// are held without contacting the backend until the cooldown has passed.
+/// Then a single entry is tried; if it succeeds, the circuit closes again,
+/// otherwise it stays open for another cooldown. A full archive storage is
+/// passed on as is, as it is handled by going into standby.
+pub struct CircuitBreaker {
+    inner: Box<dyn Archive>,
+    threshold: u32,
+    cooldown: Duration,
+    state: Mutex<State>,
+}
+
+impl CircuitBreaker {
+    pub fn new(inner: Box<dyn Archive>, threshold: u32, cooldown: Duration) -> Self {
+        info!(
+            "The circuit for backend {} opens after {} consecutive failures, for {}s",
+            inner.name(),
+            threshold,
+            cooldown.as_secs()
+        );
+        CircuitBreaker {
+            inner,
+            threshold: threshold.max(1),
+            cooldown,
+            state: Mutex::new(State {
+                failures: 0,
+                opened: None,
+            }),
+        }
+    }
+
+    /// Holds the entry without contacting the backend while the circuit is
+    /// open and the cooldown has not passed
+    fn admit(&self, state: &State) -> Result<(), Error> {
+        if let Some(opened) = state.opened {
+            if let Some(remaining) = self.cooldown.checked_sub(opened.elapsed()) {
+                return Err(held_error(
+                    true,
+                    remaining,
+                    format!("circuit for backend {} is open", self.inner.name()),
+                ));
+            }
+            debug!(
+                "Cooldown of backend {} passed, trying again",
+                self.inner.name()
+            );
+        }
+        Ok(())
+    }
+
+    /// Updates the state of the circuit after archiving an entry
+    fn record(&self, state: &mut State, outcome: Result<(), Error>) -> Result<(), Error> {
+        match outcome {
+            Ok(()) => {
+                if state.opened.is_some() {
+                    info!(
+                        "Backend {} recovered, closing the circuit",
+                        self.inner.name()
+                    );
+                }
+                state.failures = 0;
+                state.opened = None;
+                Ok(())
+            }
+            Err(e) if is_storage_full(&e) => Err(e),
+            Err(e) => {
+                state.failures += 1;
+                if state.failures < self.threshold {
+                    return Err(held_error(false, RETRY_INTERVAL, e.to_string()));
+                }
+                if state.opened.is_none() {
+                    warn!(
+                        "Backend {} failed {} times in a row, opening the circuit for {}s: {}",
+                        self.inner.name(),
+                        state.failures,
+                        self.cooldown.as_secs(),
+                        e
+                    );
+                } else {
+                    warn!(
+                        "Backend {} still failing, keeping the circuit open for {}s: {}",
+                        self.inner.name(),
+                        self.cooldown.as_secs(),
+                        e
+                    );
+                }
+                state.opened = Some(Instant::now());
+                Err(held_error(true, self.cooldown, e.to_string()))
+            }
+        }
+    }
+}
+
+impl Archive for CircuitBreaker {
+    fn archive(&self, job_entry: &JobRecord) -> Result<(), Error> {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        self.admit(&state)?;
+        let outcome = self.inner.archive(job_entry);
+        self.record(&mut state, outcome)
+    }
+
+    /// The outcomes of the batch count as consecutive attempts
+    fn archive_batch(&self, job_entries: &[JobRecord]) -> Vec<Result<(), Error>> {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        if self.admit(&state).is_err() {
+            return job_entries.iter().map(|_| self.admit(&state)).collect();
+        }
+        self.inner
+            .archive_batch(job_entries)
+            .into_iter()
+            .map(|outcome| self.record(&mut state, outcome))
+            .collect()
+    }
+
+    fn archive_tombstone(&self, job_entry: &JobRecord) -> Result<(), Error> {
+        self.inner.archive_tombstone(job_entry)
+    }
+
+    fn archive_completion(&self, completion: &Completion) -> Result<(), Error> {
+        self.inner.archive_completion(completion)
+    }
+
+    fn check(&self, cluster: &str) -> Result<(), Error> {
+        self.inner.check(cluster)
+    }
+
+    fn flush(&self, timeout: Duration) -> Result<(), Error> {
+        self.inner.flush(timeout)
+    }
+
+    fn payload_size(&self, job: &JobRecord) -> u64 {
+        self.inner.payload_size(job)
+    }
+
+    fn name(&self) -> &str {
+        self.inner.name()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::scheduler::slurm::SlurmJobEntry;
+    use crate::testing::FlakyArchiver;
+    use std::path::Path;
+    use std::sync::atomic::Ordering::SeqCst;
+    use std::thread::sleep;
+
+    #[test]
+    fn test_circuit_breaker() {
+        let flaky = FlakyArchiver::down(usize::MAX);
+        let breaker = CircuitBreaker::new(Box::new(flaky.clone()), 2, Duration::from_millis(200));
+        let entry = JobRecord::new(&SlurmJobEntry::new(
+            Path::new("/spool/hash.4/job.1234"),
+            "1234",
+            "mycluster",
+        ));
+
+        let e = breaker.archive(&entry).unwrap_err();
+        assert!(!held(&e).unwrap().open);
+        let e = breaker.archive(&entry).unwrap_err();
+        assert!(held(&e).unwrap().open);
+
+        // While open, the backend is left alone
+        let e = breaker.archive(&entry).unwrap_err();
+        assert!(held(&e).unwrap().retry_in <= Duration::from_millis(200));
+        assert_eq!(flaky.attempts(), 2);
+
+        // A failed trial after the cooldown keeps the circuit open
+        sleep(Duration::from_millis(250));
+        let e = breaker.archive(&entry).unwrap_err();
+        assert!(held(&e).unwrap().open);
+        assert_eq!(flaky.attempts(), 3);
+
+        sleep(Duration::from_millis(250));
+        flaky.failures.store(0, SeqCst);
+        assert!(breaker.archive(&entry).is_ok());
+        assert!(breaker.archive(&entry).is_ok());
+        assert_eq!(flaky.attempts(), 5);
+    }
+
+    #[test]
+    fn test_held() {
+        assert!(held(&Error::other("plain")).is_none());
+        let e = held_error(true, Duration::from_secs(3), "open".to_owned());
+        assert_eq!(held(&e).unwrap().retry_in, Duration::from_secs(3));
+        assert_eq!(e.to_string(), "open");
+    }
+}
@@ -0,0 +1,154 @@
+/*
+Copyright 2019-2024 Andy Georges <itkovian+sarchive@gmail.com>
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in
+all copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+use sha2::{Digest, Sha256};
+use std::io::{Error, ErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
+
+use super::dedup::hex;
+
+/// Directory under the archive root holding the stored contents
+pub const OBJECTS_DIR: &str = "objects";
+
+/// Returns the path of the contents with the given hash, spread over
+/// subdirectories named after the first two hex digits of the hash
+pub fn object_path(root: &Path, hash: &str) -> PathBuf {
+    root.join(OBJECTS_DIR).join(&hash[..2]).join(hash)
+}
+
+/// Returns the name of the manifest of the given job
+pub fn manifest_name(jobid: &str) -> String {
+    format!("job.{jobid}_manifest")
+}
+
+/// A file of a job in the manifest, referring to its stored contents
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ManifestEntry {
+    pub hash: String,
+    pub size: u64,
+    pub name: String,
+}
+
+impl ManifestEntry {
+    /// Returns the manifest line of the entry: the hash, size and file name,
+    /// separated by a space
+    pub fn line(&self) -> String {
+        format!("{} {} {}", self.hash, self.size, self.name)
+    }
+
+    fn parse(line: &str) -> Option<Self> {
+        let mut fields = line.splitn(3, ' ');
+        let hash = fields.next()?;
+        let size = fields.next()?.parse().ok()?;
+        let name = fields.next().filter(|n| !n.is_empty())?;
+        if hash.len() != 64 || !hash.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return None;
+        }
+        Some(ManifestEntry {
+            hash: hash.to_owned(),
+            size,
+            name: name.to_owned(),
+        })
+    }
+}
+
+/// Parses the lines of a manifest, failing on the first malformed line
+pub fn parse_manifest(contents: &str) -> Result<Vec<ManifestEntry>, Error> {
+    contents
+        .lines()
+        .enumerate()
+        .map(|(n, line)| {
+            ManifestEntry::parse(line).ok_or_else(|| {
+                Error::new(
+                    ErrorKind::InvalidData,
+                    format!("Malformed manifest line {}: {:?}", n + 1, line),
+                )
+            })
+        })
+        .collect()
+}
+
+/// Copies the reader to the writer in chunks, returning the hex encoded
+/// SHA-256 hash and the size of the copied contents
+pub fn hashing_copy(reader: &mut dyn Read, writer: &mut dyn Write) -> Result<(String, u64), Error> {
+    let mut hasher = Sha256::new();
+    let mut buffer = [0u8; 64 * 1024];
+    let mut size = 0;
+    loop {
+        let n = match reader.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        hasher.update(&buffer[..n]);
+        writer.write_all(&buffer[..n])?;
+        size += n as u64;
+    }
+    Ok((hex(&hasher.finalize()), size))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::dedup::content_hash;
+    use super::*;
+    use std::io::sink;
+
+    #[test]
+    fn test_object_path() {
+        let hash = content_hash(b"contents");
+        assert_eq!(
+            object_path(Path::new("/archive"), &hash),
+            PathBuf::from(format!("/archive/objects/{}/{}", &hash[..2], hash))
+        );
+    }
+
+    #[test]
+    fn test_parse_manifest() {
+        let entry = ManifestEntry {
+            hash: content_hash(b"contents"),
+            size: 8,
+            name: "job.123_script".to_owned(),
+        };
+        let manifest = format!("{}\n", entry.line());
+        assert_eq!(parse_manifest(&manifest).unwrap(), vec![entry.clone()]);
+        assert!(parse_manifest("").unwrap().is_empty());
+
+        for line in ["abc 8 job.123_script", &format!("{} x job.123", entry.hash)] {
+            assert!(parse_manifest(line).is_err());
+        }
+        assert!(parse_manifest(&format!("{} 8", entry.hash)).is_err());
+    }
+
+    #[test]
+    fn test_hashing_copy() {
+        let contents = vec![7u8; 100 * 1024];
+        let mut copied = Vec::new();
+        let (hash, size) = hashing_copy(&mut contents.as_slice(), &mut copied).unwrap();
+        assert_eq!(hash, content_hash(&contents));
+        assert_eq!(size, contents.len() as u64);
+        assert_eq!(copied, contents);
+
+        let (hash, size) = hashing_copy(&mut &b""[..], &mut sink()).unwrap();
+        assert_eq!(hash, content_hash(b""));
+        assert_eq!(size, 0);
+    }
+}
@@ -0,0 +1,354 @@
+/*
+Copyright 2019-2024 Andy Georges <itkovian+sarchive@gmail.com>
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in
+all copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+use crossbeam_channel::{bounded, RecvTimeoutError};
+use log::{error, info};
+use std::fmt;
+use std::io::{Error, ErrorKind};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use super::document::PayloadSizes;
+use super::Archive;
+use crate::completion::Completion;
+use crate::scheduler::job::JobRecord;
+use crate::spill;
+
+/// Limits the time spent on a single job entry, reading its information and
+/// handing it to the backend
+#[derive(Clone, Debug)]
+pub struct Deadline {
+    pub limit: Duration,
+    /// File to which the entries that ran out of time are appended, in the
+    /// format of the state file
+    pub dead_letter: Option<PathBuf>,
+}
+
+impl Deadline {
+    /// Sets the job entry aside in the dead-letter file, if any, so it can be
+    /// processed again later
+    pub fn dead_letter(&self, job: &JobRecord, reason: &Error) {
+        let Some(path) = &self.dead_letter else {
+            error!("Dropping job {}: {}", job.jobid, reason);
+            return;
+        };
+        match spill::append(path, job, &reason.to_string()) {
+            Ok(()) => info!("Set job {} aside in {:?}: {}", job.jobid, path, reason),
+            Err(e) => error!(
+                "Dropping job {}, cannot write it to {:?}: {}",
+                job.jobid, path, e
+            ),
+        }
+    }
+}
+
+/// The error returned for work that did not finish within the deadline. The
+/// work goes on in the background, but its outcome is no longer waited for.
+#[derive(Clone, Debug)]
+pub struct Expired {
+    what: String,
+    limit: Duration,
+}
+
+impl fmt::Display for Expired {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} did not finish within {}s",
+            self.what,
+            self.limit.as_secs()
+        )
+    }
+}
+
+impl std::error::Error for Expired {}
+
+/// Returns whether the error tells that the deadline passed
+pub fn expired(e: &Error) -> bool {
+    e.get_ref()
+        .is_some_and(|inner| inner.downcast_ref::<Expired>().is_some())
+}
+
+/// Returns a copy of the error, which still tells whether the deadline passed
+fn duplicate(e: &Error) -> Error {
+    match e
+        .get_ref()
+        .and_then(|inner| inner.downcast_ref::<Expired>())
+    {
+        Some(expired) => Error::new(e.kind(), expired.clone()),
+        None => Error::new(e.kind(), e.to_string()),
+    }
+}
+
+/// Runs the work on a thread of its own, and waits for it until the limit
+/// passes. A thread that never finishes is left behind.
+pub fn within<T, F>(limit: Duration, what: String, work: F) -> Result<T, Error>
+where
+    T: Send + 'static,
+    F: FnOnce() -> T + Send + 'static,
+{
+    let (sender, receiver) = bounded(1);
+    thread::spawn(move || {
+        let _ = sender.send(work());
+    });
+    match receiver.recv_timeout(limit) {
+        Ok(outcome) => Ok(outcome),
+        Err(RecvTimeoutError::Timeout) => {
+            Err(Error::new(ErrorKind::TimedOut, Expired { what, limit }))
+        }
+        Err(RecvTimeoutError::Disconnected) => Err(Error::other(format!("{what} panicked"))),
+    }
+}
+
+/// Wraps an archiver so a backend call that hangs cannot hold up processing
+///
+/// Every call to the backend runs under the deadline. The job entries whose
+/// call did not finish in time are set aside in the dead-letter file. As the
+/// calls take turns on the backend, a backend that hangs makes the following
+/// calls run out of time as well, until it returns.
+///
+/// A call that ran out of time is not cancelled: the backend may still archive
+/// the job once it returns, in which case replaying the dead-letter file
+/// archives the job again. Until it returns, the following calls run out of
+/// time straight away, so no threads pile up waiting for the backend.
+pub struct DeadlineArchive {
+    inner: Arc<Mutex<Box<dyn Archive>>>,
+    name: String,
+    deadline: Deadline,
+    /// The number of calls that ran out of time and did not return yet
+    hung: Arc<AtomicUsize>,
+    /// The payload sizes the backend reported for the jobs it archived, asked
+    /// for along with the archival, so reporting them needs no further call
+    payload_sizes: Arc<PayloadSizes>,
+}
+
+impl DeadlineArchive {
+    pub fn new(inner: Box<dyn Archive>, deadline: Deadline) -> Self {
+        info!(
+            "Giving backend {} {}s for each job entry",
+            inner.name(),
+            deadline.limit.as_secs()
+        );
+        DeadlineArchive {
+            name: inner.name().to_owned(),
+            inner: Arc::new(Mutex::new(inner)),
+            deadline,
+            hung: Arc::new(AtomicUsize::new(0)),
+            payload_sizes: Arc::new(PayloadSizes::default()),
+        }
+    }
+
+    /// Calls the backend under the deadline, unless a call that ran out of
+    /// time still holds it
+    fn call<T, F>(&self, what: String, work: F) -> Result<T, Error>
+    where
+        T: Send + 'static,
+        F: FnOnce(&dyn Archive) -> T + Send + 'static,
+    {
+        let limit = self.deadline.limit;
+        if self.hung.load(Ordering::SeqCst) > 0 {
+            let what = format!("{what}, as the backend is still busy,");
+            return Err(Error::new(ErrorKind::TimedOut, Expired { what, limit }));
+        }
+        // whether the call returned, and whether it was given up on
+        let state = Arc::new(Mutex::new((false, false)));
+        let inner = Arc::clone(&self.inner);
+        let hung = Arc::clone(&self.hung);
+        let call_state = Arc::clone(&state);
+        let outcome = within(limit, what, move || {
+            // a backend that panicked earlier is still worth a try
+            let archiver = inner.lock().unwrap_or_else(|e| e.into_inner());
+            let outcome = work(archiver.as_ref());
+            let mut state = call_state.lock().unwrap_or_else(|e| e.into_inner());
+            state.0 = true;
+            if state.1 {
+                hung.fetch_sub(1, Ordering::SeqCst);
+            }
+            outcome
+        });
+        if outcome.as_ref().is_err_and(expired) {
+            let mut state = state.lock().unwrap_or_else(|e| e.into_inner());
+            if !state.0 {
+                state.1 = true;
+                self.hung.fetch_add(1, Ordering::SeqCst);
+            }
+        }
+        outcome
+    }
+}
+
+impl Archive for DeadlineArchive {
+    fn archive(&self, job_entry: &JobRecord) -> Result<(), Error> {
+        let job = job_entry.clone();
+        let what = format!("Archiving job {} with {}", job.jobid, self.name);
+        let sizes = Arc::clone(&self.payload_sizes);
+        self.call(what, move |archiver| -> Result<(), Error> {
+            archiver.archive(&job)?;
+            sizes.sent(&job, archiver.payload_size(&job));
+            Ok(())
+        })
+        .unwrap_or_else(|e| {
+            self.deadline.dead_letter(job_entry, &e);
+            Err(e)
+        })
+    }
+
+    /// The batch shares a single deadline
+    fn archive_batch(&self, job_entries: &[JobRecord]) -> Vec<Result<(), Error>> {
+        let jobs = job_entries.to_vec();
+        let what = format!(
+            "Archiving a batch of {} jobs with {}",
+            jobs.len(),
+            self.name
+        );
+        let sizes = Arc::clone(&self.payload_sizes);
+        let archive_batch = move |archiver: &dyn Archive| {
+            let outcomes = archiver.archive_batch(&jobs);
+            for (job, outcome) in jobs.iter().zip(outcomes.iter()) {
+                if outcome.is_ok() {
+                    sizes.sent(job, archiver.payload_size(job));
+                }
+            }
+            outcomes
+        };
+        match self.call(what, archive_batch) {
+            Ok(outcomes) => outcomes,
+            Err(e) => job_entries
+                .iter()
+                .map(|job| {
+                    self.deadline.dead_letter(job, &e);
+                    Err(duplicate(&e))
+                })
+                .collect(),
+        }
+    }
+
+    fn archive_tombstone(&self, job_entry: &JobRecord) -> Result<(), Error> {
+        let job = job_entry.clone();
+        let what = format!("Archiving the tombstone of job {}", job.jobid);
+        self.call(what, move |archiver| archiver.archive_tombstone(&job))?
+    }
+
+    fn archive_completion(&self, completion: &Completion) -> Result<(), Error> {
+        let completion = completion.clone();
+        let what = format!("Archiving the completion of job {}", completion.jobid);
+        self.call(what, move |archiver| {
+            archiver.archive_completion(&completion)
+        })?
+    }
+
+    fn check(&self, cluster: &str) -> Result<(), Error> {
+        let cluster = cluster.to_owned();
+        let what = format!("Checking backend {}", self.name);
+        self.call(what, move |archiver| archiver.check(&cluster))?
+    }
+
+    fn flush(&self, timeout: Duration) -> Result<(), Error> {
+        let what = format!("Flushing backend {}", self.name);
+        self.call(what, move |archiver| archiver.flush(timeout))?
+    }
+
+    /// Reports the size the backend gave when it archived the job, as asking
+    /// the backend now could wait for a call that hangs
+    fn payload_size(&self, job: &JobRecord) -> u64 {
+        self.payload_sizes.take(job)
+    }
+
+    fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scheduler::slurm::SlurmJobEntry;
+    use crate::testing::SlowArchiver;
+    use std::env::current_dir;
+    use std::fs::read_to_string;
+    use std::thread::sleep;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_within() {
+        let limit = Duration::from_millis(100);
+        assert_eq!(within(limit, "quick".to_owned(), || 42).unwrap(), 42);
+
+        let e = within(limit, "slow".to_owned(), || sleep(Duration::from_secs(1))).unwrap_err();
+        assert!(expired(&e));
+        assert_eq!(e.kind(), ErrorKind::TimedOut);
+        assert_eq!(e.to_string(), "slow did not finish within 0s");
+        assert!(!expired(&Error::new(
+            ErrorKind::TimedOut,
+            "backend timeout"
+        )));
+    }
+
+    #[test]
+    fn test_deadline_archive() {
+        let tdir = tempdir().unwrap();
+        let dead_letter = tdir.path().join("dead-letter");
+        let deadline = Deadline {
+            limit: Duration::from_millis(200),
+            dead_letter: Some(dead_letter.clone()),
+        };
+        let spool = current_dir().unwrap().join("tests");
+        let job = JobRecord::new(&SlurmJobEntry::new(
+            &spool.join("job.123456"),
+            "123456",
+            "mycluster",
+        ));
+
+        let quick = DeadlineArchive::new(
+            Box::new(SlowArchiver(Duration::from_millis(10))),
+            deadline.clone(),
+        );
+        quick.archive(&job).unwrap();
+        assert_eq!(quick.name(), "slow");
+        assert!(!dead_letter.exists());
+        assert_eq!(quick.payload_size(&job), 42);
+        assert_eq!(quick.payload_size(&job), 0);
+
+        let slow = DeadlineArchive::new(Box::new(SlowArchiver(Duration::from_secs(1))), deadline);
+        assert!(expired(&slow.archive(&job).unwrap_err()));
+        // the size is not asked from the backend that is still busy
+        let start = std::time::Instant::now();
+        assert_eq!(slow.payload_size(&job), 0);
+        assert!(start.elapsed() < Duration::from_millis(100));
+        // nor is the backend called again until it returns
+        let outcomes = slow.archive_batch(&[job.clone(), job.clone()]);
+        assert!(start.elapsed() < Duration::from_millis(100));
+        assert_eq!(outcomes.len(), 2);
+        assert!(outcomes.iter().all(|o| expired(o.as_ref().unwrap_err())));
+        // it returns once it archived the job and gave its size
+        sleep(Duration::from_secs(2));
+        slow.check("mycluster").unwrap();
+
+        let lines = read_to_string(&dead_letter).unwrap();
+        assert_eq!(lines.lines().count(), 3);
+        assert!(lines.contains("\"id\":\"123456\""));
+        assert!(lines.contains("did not finish within"));
+    }
+}
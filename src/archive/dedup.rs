@@ -0,0 +1,187 @@
+/*
+Copyright 2019-2024 Andy Georges <itkovian+sarchive@gmail.com>
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in
+all copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use crate::scheduler::job::JobInfo;
+
+/// Returns the hex encoded SHA-256 hash of the given contents
+pub fn content_hash(contents: &[u8]) -> String {
+    hex(&Sha256::digest(contents))
+}
+
+/// Returns the lowercase hex encoding of the given bytes
+pub fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+/// Returns the bytes of the given hex encoding, or None if it is not one
+pub fn parse_hex(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) || !s.is_ascii() {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&s[i..i + 2], 16).ok())
+        .collect()
+}
+
+/// Returns the script without comment lines (including the shebang and
+/// scheduler directives), blank lines and repeated whitespace, so scripts
+/// that differ only in their layout or comments can be matched.
+pub fn normalize_script(script: &str) -> String {
+    script
+        .lines()
+        .map(|line| line.split_whitespace().collect::<Vec<_>>().join(" "))
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Returns a key that identifies the job across repeated processing, built
+/// from the cluster, the job ID and the submission time (if known). Consumers
+/// can use this to drop duplicate records after a replay.
+pub fn idempotency_key(job_entry: &dyn JobInfo) -> String {
+    let submit_time = job_entry
+        .submit_time()
+        .map(|t| t.timestamp().to_string())
+        .unwrap_or_default();
+    content_hash(
+        format!(
+            "{}\0{}\0{}",
+            job_entry.cluster(),
+            job_entry.jobid(),
+            submit_time
+        )
+        .as_bytes(),
+    )
+}
+
+/// Keeps track of the script hashes that were sent recently, so backends
+/// can send a reference instead of the full script for repeated scripts
+/// (e.g., the tasks of an array job).
+pub struct ScriptCache {
+    window: Duration,
+    seen: HashMap<String, Instant>,
+}
+
+impl ScriptCache {
+    pub fn new(window: Duration) -> ScriptCache {
+        ScriptCache {
+            window,
+            seen: HashMap::new(),
+        }
+    }
+
+    /// Returns true if the script with the given hash was first sent less than
+    /// the window ago
+    pub fn contains(&mut self, hash: &str) -> bool {
+        let now = Instant::now();
+        let window = self.window;
+        self.seen
+            .retain(|_, first| now.duration_since(*first) < window);
+        self.seen.contains_key(hash)
+    }
+
+    /// Registers the script with the given hash as sent now, unless it was
+    /// sent within the window. Only call this once the script was delivered,
+    /// so a script that did not make it is sent in full again.
+    pub fn insert(&mut self, hash: &str) {
+        if !self.contains(hash) {
+            self.seen.insert(hash.to_owned(), Instant::now());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::scheduler::slurm::SlurmJobEntry;
+    use std::env::current_dir;
+    use std::thread::sleep;
+
+    #[test]
+    fn test_content_hash() {
+        assert_eq!(
+            content_hash(b"#!/bin/bash\n"),
+            content_hash(b"#!/bin/bash\n")
+        );
+        assert_eq!(
+            content_hash(b""),
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+    }
+
+    #[test]
+    fn test_hex() {
+        assert_eq!(hex(&[0, 255, 16]), "00ff10");
+        assert_eq!(parse_hex("00ff10"), Some(vec![0, 255, 16]));
+        assert_eq!(parse_hex("0"), None);
+        assert_eq!(parse_hex("zz"), None);
+    }
+
+    #[test]
+    fn test_normalize_script() {
+        let script =
+            "#!/bin/bash\n#SBATCH --time=1:00\n\nmodule load\tfoo\n   srun  ./a.out   # go\n";
+        assert_eq!(
+            normalize_script(script),
+            "module load foo\nsrun ./a.out # go"
+        );
+        assert_eq!(
+            normalize_script(script),
+            normalize_script("#!/bin/sh\n# other comment\nmodule load foo\nsrun ./a.out # go")
+        );
+        assert_eq!(normalize_script("# only comments\n\n"), "");
+    }
+
+    #[test]
+    fn test_idempotency_key() {
+        let path = current_dir().unwrap().join("tests/job.123456");
+        let read = |cluster: &str| {
+            let mut entry = SlurmJobEntry::new(&path, "123456", cluster);
+            entry.read_job_info().unwrap();
+            idempotency_key(&entry)
+        };
+
+        assert_eq!(read("mycluster"), read("mycluster"));
+        assert_ne!(read("mycluster"), read("othercluster"));
+    }
+
+    #[test]
+    fn test_script_cache() {
+        let mut cache = ScriptCache::new(Duration::from_millis(200));
+
+        assert!(!cache.contains("abc"));
+        assert!(!cache.contains("abc"));
+        cache.insert("abc");
+        assert!(cache.contains("abc"));
+        assert!(!cache.contains("def"));
+
+        sleep(Duration::from_millis(250));
+        assert!(!cache.contains("abc"));
+        cache.insert("abc");
+        assert!(cache.contains("abc"));
+    }
+}
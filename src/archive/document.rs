@@ -0,0 +1,417 @@
+/*
+Copyright 2019-2024 Andy Georges <itkovian+sarchive@gmail.com>
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in
+all copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+use chrono::Utc;
+use serde_json::{json, Map, Number, Value};
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use super::dedup::{content_hash, idempotency_key, normalize_script};
+use crate::artefact::ARTEFACT_FIELD;
+use crate::completion::Completion;
+use crate::identity::Identity;
+use crate::scheduler::job::JobInfo;
+
+/// Options for the records that backends produce, regardless of the backend
+#[derive(Clone, Debug, Default)]
+pub struct RecordOptions {
+    /// Add the normalized script and its hash to job records
+    pub normalize_script: bool,
+    /// Archive the environment with numbers and booleans as such, and the
+    /// PATH-like variables as arrays of their components
+    pub structured_environment: bool,
+}
+
+/// Prefix of the keys sarchive adds to the environment, which are left as is
+const SARCHIVE_KEY_PREFIX: &str = "sarchive_";
+
+/// Returns the environment of the job as it goes in the records, structured
+/// if requested
+pub fn environment(job_entry: &dyn JobInfo, options: &RecordOptions) -> Value {
+    match job_entry.extra_info() {
+        Some(env) if options.structured_environment => structured_environment(env),
+        env => json!(env),
+    }
+}
+
+/// Returns the environment with the values of the PATH-like variables (e.g.,
+/// PATH, LD_LIBRARY_PATH or MODULEPATH) split on ':', and values that read as
+/// an integer, a number or a boolean converted as such. Values that would not
+/// survive the round trip, such as `007` or `1e3`, stay strings.
+fn structured_environment(env: HashMap<String, String>) -> Value {
+    let mut structured = Map::new();
+    for (key, value) in env {
+        let value = if key.starts_with(SARCHIVE_KEY_PREFIX) {
+            Value::String(value)
+        } else if key.ends_with("PATH") && value.contains(':') {
+            json!(value.split(':').collect::<Vec<_>>())
+        } else {
+            coerce(value)
+        };
+        structured.insert(key, value);
+    }
+    Value::Object(structured)
+}
+
+/// Returns the value as a boolean or number, if it reads as one and turns
+/// back into the same string, else as the string itself
+fn coerce(value: String) -> Value {
+    match value.as_str() {
+        "true" => return Value::Bool(true),
+        "false" => return Value::Bool(false),
+        _ => (),
+    }
+    let number = if let Ok(i) = value.parse::<i64>() {
+        Some(Number::from(i))
+    } else {
+        value.parse::<f64>().ok().and_then(Number::from_f64)
+    };
+    match number {
+        Some(n) if n.to_string() == value => Value::Number(n),
+        _ => Value::String(value),
+    }
+}
+
+/// Returns the GPUs the job requests as numeric fields, or null when it
+/// requests none
+pub fn accelerators(job_entry: &dyn JobInfo) -> Value {
+    let accelerators = job_entry.accelerators();
+    if accelerators.is_empty() {
+        return Value::Null;
+    }
+    json!({
+        "gpus": accelerators.gpus,
+        "gpus_per_node": accelerators.gpus_per_node,
+        "gpus_per_task": accelerators.gpus_per_task,
+        "gpu_type": accelerators.gpu_type,
+    })
+}
+
+/// Returns the normalized script of the job and its hash, if requested and
+/// the script was captured
+pub fn normalized_script(
+    job_entry: &dyn JobInfo,
+    options: &RecordOptions,
+) -> Option<(String, String)> {
+    if !options.normalize_script {
+        return None;
+    }
+    let script = job_entry.script();
+    if script.is_empty() {
+        return None;
+    }
+    let normalized = normalize_script(&script);
+    let hash = content_hash(normalized.as_bytes());
+    Some((normalized, hash))
+}
+
+/// Returns the fields shared by job documents and tombstones
+fn common(job_entry: &dyn JobInfo, identity: &Identity) -> Value {
+    let mut doc = json!({
+        "id": job_entry.jobid(),
+        "idempotency_key": idempotency_key(job_entry),
+        "timestamp": Utc::now(),
+        "event_time": job_entry.event_time(),
+        "cluster": job_entry.cluster(),
+        "host": identity.hostname,
+        "sarchive_version": identity.version,
+        "instance_id": identity.instance_id,
+    });
+    add_labels(&mut doc, identity);
+    doc
+}
+
+/// Adds the labels of this instance to the document, if there are any
+fn add_labels(doc: &mut Value, identity: &Identity) {
+    if !identity.labels.is_empty() {
+        doc["labels"] = json!(identity.labels);
+    }
+}
+
+/// Returns the JSON document for the job, with the same fields as the
+/// messages of the Kafka backend
+pub fn job_document(
+    job_entry: &dyn JobInfo,
+    identity: &Identity,
+    options: &RecordOptions,
+) -> Value {
+    let mut doc = common(job_entry, identity);
+    doc["job_name"] = json!(job_entry.job_name());
+    doc["user"] = json!(job_entry.user());
+    doc["partition"] = json!(job_entry.partition());
+    doc["accelerators"] = accelerators(job_entry);
+    doc["script"] = json!(job_entry.script());
+    doc["environment"] = environment(job_entry, options);
+    doc["partial"] = json!(!job_entry.missing_files().is_empty());
+    if let Some((normalized, hash)) = normalized_script(job_entry, options) {
+        doc["script_normalized"] = json!(normalized);
+        doc["script_normalized_hash"] = json!(hash);
+    }
+    doc
+}
+
+/// Returns the size of the document as a line of JSON, including the newline
+pub fn document_size(doc: &Value) -> u64 {
+    serde_json::to_vec(doc).map_or(0, |line| line.len() as u64 + 1)
+}
+
+/// The size of the payload a backend sent for each job, by idempotency key,
+/// until it is reported. The size is that of what was sent, so archivers
+/// wrapping the backend (e.g., to pseudonymize the job) need not rebuild the
+/// job they passed on to measure it.
+#[derive(Default)]
+pub struct PayloadSizes(Mutex<HashMap<String, u64>>);
+
+impl PayloadSizes {
+    /// Remembers the size of the payload sent for the job
+    pub fn sent(&self, job_entry: &dyn JobInfo, size: u64) {
+        self.0
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .insert(idempotency_key(job_entry), size);
+    }
+
+    /// Returns the size of the payload sent for the job, which is then
+    /// forgotten, or 0 if none was sent
+    pub fn take(&self, job_entry: &dyn JobInfo) -> u64 {
+        self.0
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .remove(&idempotency_key(job_entry))
+            .unwrap_or_default()
+    }
+}
+
+/// Returns the JSON document for a job that vanished before its information
+/// could be read, whose user opted out of archival, or whose files were
+/// deleted
+pub fn tombstone_document(job_entry: &dyn JobInfo, identity: &Identity) -> Value {
+    let mut doc = common(job_entry, identity);
+    doc["event"] = json!(job_entry.tombstone_event());
+    doc
+}
+
+/// Returns the JSON document for the completion of a job. Its idempotency key
+/// is derived from the cluster, the job ID and the end time.
+pub fn completion_document(completion: &Completion, identity: &Identity) -> Value {
+    let mut doc = json!({
+        "id": completion.jobid,
+        "idempotency_key": completion_key(completion),
+        "timestamp": Utc::now(),
+        "cluster": completion.cluster,
+        "event": completion.event(),
+        "state": completion.state,
+        "exit_code": completion.exit_code,
+        "start_time": completion.start_time,
+        "end_time": completion.end_time,
+        "fields": completion.fields,
+        "host": identity.hostname,
+        "sarchive_version": identity.version,
+        "instance_id": identity.instance_id,
+    });
+    add_labels(&mut doc, identity);
+    doc
+}
+
+/// Returns the key identifying the completion across repeated processing.
+/// That of an artefact is derived from its file name, that of a job's start
+/// from its start time, instead of the end time.
+pub fn completion_key(completion: &Completion) -> String {
+    let distinct = match completion.artefact() {
+        Some(_) => completion.fields.get(ARTEFACT_FIELD).cloned(),
+        None if completion.is_start() => Some(format!(
+            "{}\0{}",
+            completion.event(),
+            completion.start_time.as_deref().unwrap_or_default()
+        )),
+        None => completion.end_time.clone(),
+    };
+    content_hash(
+        format!(
+            "{}\0{}\0{}",
+            completion.cluster,
+            completion.jobid,
+            distinct.as_deref().unwrap_or_default()
+        )
+        .as_bytes(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::artefact::ARTEFACT_KIND_FIELD;
+    use crate::completion::STARTED_STATE;
+    use crate::scheduler::slurm::SlurmJobEntry;
+    use std::env::current_dir;
+
+    #[test]
+    fn test_documents() {
+        let path = current_dir().unwrap().join("tests/job.123456");
+        let mut entry = SlurmJobEntry::new(&path, "123456", "mycluster");
+        entry.read_job_info().unwrap();
+        let identity = Identity::new(Some("ctl1".to_owned()), "");
+
+        let doc = job_document(&entry, &identity, &RecordOptions::default());
+        assert_eq!(doc["id"], "123456");
+        assert_eq!(doc["cluster"], "mycluster");
+        assert_eq!(doc["instance_id"], "ctl1");
+        assert_eq!(doc["script"], entry.script());
+        assert_eq!(doc["partial"], false);
+        assert_eq!(doc["job_name"], entry.job_name().unwrap());
+        assert_eq!(doc["user"], Value::Null);
+        assert_eq!(doc["partition"], Value::Null);
+        assert_eq!(doc["accelerators"], Value::Null);
+        assert!(doc.get("event").is_none());
+        assert!(doc.get("script_normalized").is_none());
+
+        let options = RecordOptions {
+            normalize_script: true,
+            ..Default::default()
+        };
+        let normalized = job_document(&entry, &identity, &options);
+        assert_eq!(
+            normalized["script_normalized"],
+            normalize_script(&entry.script())
+        );
+        assert_eq!(
+            normalized["script_normalized_hash"],
+            content_hash(normalize_script(&entry.script()).as_bytes())
+        );
+
+        let tombstone = tombstone_document(&entry, &identity);
+        assert_eq!(tombstone["event"], "cancelled_before_capture");
+        assert_eq!(tombstone["idempotency_key"], doc["idempotency_key"]);
+        assert!(tombstone.get("script").is_none());
+        assert!(tombstone.get("labels").is_none());
+    }
+
+    #[test]
+    fn test_structured_environment() {
+        let env: HashMap<String, String> = [
+            ("SLURM_NTASKS", "4"),
+            ("SLURM_MEM_PER_CPU", "-1"),
+            ("OMP_PROC_BIND", "true"),
+            ("LOAD", "0.5"),
+            ("UMASK", "0022"),
+            ("BIG", "1e3"),
+            ("USER", "jdoe"),
+            ("PATH", "/usr/bin:/bin"),
+            ("LD_LIBRARY_PATH", "/opt/lib"),
+            ("MANPATH", ":/usr/share/man"),
+            ("sarchive_streamed_files", "1"),
+        ]
+        .iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect();
+
+        assert_eq!(
+            structured_environment(env),
+            json!({
+                "SLURM_NTASKS": 4,
+                "SLURM_MEM_PER_CPU": -1,
+                "OMP_PROC_BIND": true,
+                "LOAD": 0.5,
+                "UMASK": "0022",
+                "BIG": "1e3",
+                "USER": "jdoe",
+                "PATH": ["/usr/bin", "/bin"],
+                "LD_LIBRARY_PATH": "/opt/lib",
+                "MANPATH": ["", "/usr/share/man"],
+                "sarchive_streamed_files": "1",
+            })
+        );
+
+        let path = current_dir().unwrap().join("tests/job.123456");
+        let mut entry = SlurmJobEntry::new(&path, "123456", "mycluster");
+        entry.read_job_info().unwrap();
+        let options = RecordOptions {
+            structured_environment: true,
+            ..Default::default()
+        };
+        let doc = job_document(&entry, &Identity::default(), &options);
+        assert_eq!(doc["environment"]["SLURM_NTASKS_PER_NODE"], 1);
+        let doc = job_document(&entry, &Identity::default(), &RecordOptions::default());
+        assert_eq!(doc["environment"]["SLURM_NTASKS_PER_NODE"], "1");
+    }
+
+    #[test]
+    fn test_documents_labels() {
+        let path = current_dir().unwrap().join("tests/job.123456");
+        let mut entry = SlurmJobEntry::new(&path, "123456", "mycluster");
+        entry.read_job_info().unwrap();
+        let mut identity = Identity::default();
+        identity
+            .labels
+            .insert("datacenter".to_owned(), "dc1".to_owned());
+        identity
+            .labels
+            .insert("environment".to_owned(), "prod".to_owned());
+
+        let doc = job_document(&entry, &identity, &RecordOptions::default());
+        assert_eq!(
+            doc["labels"],
+            json!({"datacenter": "dc1", "environment": "prod"})
+        );
+        let tombstone = tombstone_document(&entry, &identity);
+        assert_eq!(tombstone["labels"], doc["labels"]);
+        let completion = completion_document(&Completion::default(), &identity);
+        assert_eq!(completion["labels"], doc["labels"]);
+    }
+
+    #[test]
+    fn test_completion_document() {
+        let completion = Completion {
+            jobid: "123456".to_owned(),
+            cluster: "mycluster".to_owned(),
+            state: Some("COMPLETED".to_owned()),
+            exit_code: Some("0:0".to_owned()),
+            ..Default::default()
+        };
+        let doc = completion_document(&completion, &Identity::default());
+
+        assert_eq!(doc["id"], "123456");
+        assert_eq!(doc["event"], "completed");
+        assert_eq!(doc["state"], "COMPLETED");
+        assert_eq!(doc["end_time"], Value::Null);
+        assert_eq!(doc["idempotency_key"], completion_key(&completion));
+
+        let mut artefact = completion.clone();
+        artefact.fields.extend([
+            (ARTEFACT_KIND_FIELD.to_owned(), "prolog".to_owned()),
+            (ARTEFACT_FIELD.to_owned(), "prolog.123456.err".to_owned()),
+        ]);
+        let doc = completion_document(&artefact, &Identity::default());
+        assert_eq!(doc["event"], "prolog_failed");
+        assert_ne!(doc["idempotency_key"], completion_key(&completion));
+
+        let started = Completion {
+            state: Some(STARTED_STATE.to_owned()),
+            start_time: Some("2024-04-17T10:22:33".to_owned()),
+            ..completion.clone()
+        };
+        let doc = completion_document(&started, &Identity::default());
+        assert_eq!(doc["event"], "started");
+        assert_eq!(doc["start_time"], "2024-04-17T10:22:33");
+        assert_ne!(doc["idempotency_key"], completion_key(&completion));
+    }
+}
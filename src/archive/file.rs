@@ -20,19 +20,247 @@ OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
 SOFTWARE.
 */
 use clap::{Args, ValueEnum};
-use log::{debug, error, warn};
-use std::fs::{create_dir_all, File};
-use std::io::{Error, Write};
+use crossbeam_channel::{bounded, RecvTimeoutError, Sender};
+use log::{debug, error, info, warn};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::ffi::CString;
+use std::fs::{
+    copy, create_dir, read_dir, remove_file, rename, set_permissions, File, OpenOptions,
+};
+use std::io::{self, Error, ErrorKind, Read, Write};
+use std::mem::take;
+use std::os::unix::fs::{chown, OpenOptionsExt, PermissionsExt};
 use std::path::{Path, PathBuf};
-
-use super::Archive;
-use crate::scheduler::job::JobInfo;
+use std::process;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use super::cas::{hashing_copy, manifest_name, object_path, ManifestEntry, OBJECTS_DIR};
+use super::document::{normalized_script, RecordOptions};
+use super::{check_writable, is_storage_full, Archive, CLUSTER_PLACEHOLDER};
+use crate::identity::Identity;
+use crate::scheduler::job::{JobInfo, JobRecord};
 
 /// Command line options for the file archiver subcommand
 #[derive(Args, Debug)]
 pub struct FileArgs {
+    #[arg(help = "Archive directory, which may contain {cluster} to keep the clusters apart")]
     archive: PathBuf,
     period: Period,
+
+    #[arg(
+        long = "cluster-archive",
+        value_name = "CLUSTER=PATH",
+        value_parser = parse_cluster_archive,
+        help = "Archive directory for the jobs of the given cluster (can be repeated)"
+    )]
+    cluster_archives: Vec<(String, PathBuf)>,
+
+    #[arg(
+        long,
+        help = "Path of each archived file in the archive, e.g., {cluster}/{year}/{month}/{jobid}/{filename}, replacing the period subdirectory and file name"
+    )]
+    name_template: Option<String>,
+
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = Fsync::Never,
+        help = "When to flush archived files (and their directory) to disk"
+    )]
+    fsync: Fsync,
+
+    #[arg(
+        long,
+        value_name = "SECONDS",
+        default_value_t = 5,
+        help = "Time between flushes when using --fsync periodic"
+    )]
+    fsync_interval: u64,
+
+    #[arg(
+        long,
+        help = "Directory to write job entries to when the archive is full or over quota"
+    )]
+    emergency_path: Option<PathBuf>,
+
+    #[arg(
+        long,
+        help = "Directory to write job entries to when writing to the archive fails, e.g., during a storage outage; they are moved to the archive once it can be written to again"
+    )]
+    failover_path: Option<PathBuf>,
+
+    #[arg(
+        long,
+        value_name = "SECONDS",
+        default_value_t = 60,
+        help = "Time between attempts to move the job entries from the failover path to the archive"
+    )]
+    failover_interval: u64,
+
+    #[arg(
+        long,
+        help = "Store each distinct file content once, under its SHA-256 hash in the objects directory of the archive, with a manifest per job listing its files"
+    )]
+    content_store: bool,
+
+    #[command(flatten)]
+    permissions: Permissions,
+}
+
+/// Permissions, ownership and security attributes for the archived files and
+/// directories. Unset values are left to the process' umask and identity.
+#[derive(Args, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Permissions {
+    #[arg(long, value_parser = parse_mode, help = "Mode of archived files, in octal (e.g., 0640)")]
+    file_mode: Option<u32>,
+
+    #[arg(long, value_parser = parse_mode, help = "Mode of created archive directories, in octal (e.g., 0750)")]
+    dir_mode: Option<u32>,
+
+    #[arg(long, value_parser = parse_user, help = "Owner (name or uid) of archived files and directories")]
+    owner: Option<u32>,
+
+    #[arg(long, value_parser = parse_group, help = "Group (name or gid) of archived files and directories")]
+    group: Option<u32>,
+
+    #[arg(
+        long,
+        help = "Copy the extended attributes (including SELinux contexts) of the spool files to the archived files"
+    )]
+    preserve_xattrs: bool,
+
+    #[arg(
+        long,
+        help = "SELinux context to set on archived files and directories, e.g., system_u:object_r:var_t:s0"
+    )]
+    selinux_context: Option<String>,
+}
+
+/// Extended attribute holding the SELinux context
+const SELINUX_XATTR: &str = "security.selinux";
+
+impl Permissions {
+    /// Creates a file with the configured mode, ownership and attributes,
+    /// copying the latter from the given spool file if requested. Attributes
+    /// that cannot be copied are only warned about.
+    fn create_file(&self, path: &Path, source: Option<&PathBuf>) -> Result<File, Error> {
+        let mut options = OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        if let Some(mode) = self.file_mode {
+            options.mode(mode);
+        }
+        let file = options.open(path)?;
+        if let (true, Some(source)) = (self.preserve_xattrs, source) {
+            if let Err(e) = copy_xattrs(source, path) {
+                warn!(
+                    "Cannot copy the extended attributes of {:?} to {:?}: {}",
+                    source, path, e
+                );
+            }
+        }
+        self.apply(path, self.file_mode)?;
+        Ok(file)
+    }
+
+    /// Creates a directory and its missing parents, one at a time, each with
+    /// the configured mode and ownership
+    fn create_dir(&self, path: &Path) -> Result<(), Error> {
+        let missing: Vec<&Path> = path
+            .ancestors()
+            .take_while(|dir| !dir.as_os_str().is_empty() && !dir.is_dir())
+            .collect();
+        for dir in missing.into_iter().rev() {
+            match create_dir(dir) {
+                Ok(()) => self.apply(dir, self.dir_mode)?,
+                // Another thread created it in the meantime
+                Err(e) if e.kind() == ErrorKind::AlreadyExists && dir.is_dir() => (),
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    /// Sets the exact mode, as the process' umask applies on creation
+    fn apply(&self, path: &Path, mode: Option<u32>) -> Result<(), Error> {
+        if let Some(mode) = mode {
+            set_permissions(path, std::fs::Permissions::from_mode(mode))?;
+        }
+        if self.owner.is_some() || self.group.is_some() {
+            chown(path, self.owner, self.group)?;
+        }
+        if let Some(context) = &self.selinux_context {
+            xattr::set(path, SELINUX_XATTR, context.as_bytes())?;
+        }
+        Ok(())
+    }
+}
+
+/// Copies the extended attributes of the source file to the target file
+fn copy_xattrs(source: &Path, target: &Path) -> Result<(), Error> {
+    for name in xattr::list(source)? {
+        if let Some(value) = xattr::get(source, &name)? {
+            xattr::set(target, &name, &value)?;
+        }
+    }
+    Ok(())
+}
+
+fn parse_cluster_archive(s: &str) -> Result<(String, PathBuf), String> {
+    match s.split_once('=') {
+        Some((cluster, path)) if !cluster.is_empty() && !path.is_empty() => {
+            Ok((cluster.to_owned(), PathBuf::from(path)))
+        }
+        _ => Err(format!("{s} is not of the form cluster=path")),
+    }
+}
+
+/// Replaces the cluster placeholder in the path, if any
+fn with_cluster(path: &Path, cluster: &str) -> PathBuf {
+    match path.to_str() {
+        Some(p) if p.contains(CLUSTER_PLACEHOLDER) => {
+            PathBuf::from(p.replace(CLUSTER_PLACEHOLDER, cluster))
+        }
+        _ => path.to_path_buf(),
+    }
+}
+
+fn parse_mode(s: &str) -> Result<u32, String> {
+    match u32::from_str_radix(s.trim_start_matches("0o"), 8) {
+        Ok(mode) if mode <= 0o7777 => Ok(mode),
+        _ => Err(format!("{s} is not a valid octal file mode")),
+    }
+}
+
+pub(crate) fn parse_user(s: &str) -> Result<u32, String> {
+    if let Ok(uid) = s.parse() {
+        return Ok(uid);
+    }
+    let name = CString::new(s).map_err(|e| e.to_string())?;
+    // SAFETY: getpwnam is called with a valid C string, and we only read
+    // from the returned entry before any other call can overwrite it
+    let pw = unsafe { libc::getpwnam(name.as_ptr()) };
+    if pw.is_null() {
+        Err(format!("unknown user {s}"))
+    } else {
+        Ok(unsafe { (*pw).pw_uid })
+    }
+}
+
+fn parse_group(s: &str) -> Result<u32, String> {
+    if let Ok(gid) = s.parse() {
+        return Ok(gid);
+    }
+    let name = CString::new(s).map_err(|e| e.to_string())?;
+    // SAFETY: see parse_user
+    let gr = unsafe { libc::getgrnam(name.as_ptr()) };
+    if gr.is_null() {
+        Err(format!("unknown group {s}"))
+    } else {
+        Ok(unsafe { (*gr).gr_gid })
+    }
 }
 
 /// An enum to define a hierachy in the archive
@@ -48,52 +276,623 @@ pub enum Period {
     None,
 }
 
+/// When to flush the archived files to disk
+#[derive(Clone, Copy, ValueEnum, PartialEq, Debug, Eq)]
+pub enum Fsync {
+    /// Before the job entry is considered archived
+    Always,
+    /// Every --fsync-interval seconds, for the files written since the last flush
+    Periodic,
+    /// Leave it to the operating system
+    Never,
+}
+
+/// Files written since the last periodic flush
+type Unsynced = Arc<Mutex<Vec<PathBuf>>>;
+
+/// Failover paths holding job entries, each with the archive directory the
+/// entries are to be moved to
+type FailedOver = Arc<Mutex<BTreeSet<(PathBuf, PathBuf)>>>;
+
 /// An archiver that writes job script info to a file
 pub struct FileArchive {
     archive_path: PathBuf,
     period: Period,
+    fsync: Fsync,
+    fsync_interval: Duration,
+    unsynced: Unsynced,
+    /// Stops the periodic flush when dropped
+    flushing: Option<Sender<()>>,
+    emergency_path: Option<PathBuf>,
+    permissions: Permissions,
+    cluster_archives: HashMap<String, PathBuf>,
+    name_template: Option<String>,
+    options: RecordOptions,
+    labels: BTreeMap<String, String>,
+    failover_path: Option<PathBuf>,
+    failed_over: FailedOver,
+    /// Stops the migration from the failover path when dropped
+    migration: Option<Sender<()>>,
+    content_store: bool,
 }
 
+/// Numbers the temporary files of contents being stored
+static STORE_COUNTER: AtomicU64 = AtomicU64::new(0);
+
 impl FileArchive {
     pub fn new(archive_path: &PathBuf, p: &Period) -> Self {
         FileArchive {
             archive_path: archive_path.to_owned(),
             period: p.to_owned(),
+            fsync: Fsync::Never,
+            fsync_interval: Duration::from_secs(5),
+            unsynced: Arc::new(Mutex::new(Vec::new())),
+            flushing: None,
+            emergency_path: None,
+            permissions: Permissions::default(),
+            cluster_archives: HashMap::new(),
+            name_template: None,
+            options: RecordOptions::default(),
+            labels: BTreeMap::new(),
+            failover_path: None,
+            failed_over: Arc::new(Mutex::new(BTreeSet::new())),
+            migration: None,
+            content_store: false,
+        }
+    }
+
+    /// Returns the archive directory for jobs of the given cluster
+    fn archive_root(&self, cluster: &str) -> PathBuf {
+        match self.cluster_archives.get(cluster) {
+            Some(path) => path.clone(),
+            None => with_cluster(&self.archive_path, cluster),
+        }
+    }
+
+    /// Writes the job entry's files into the archive at the given path,
+    /// returning the paths of the files written
+    fn write_entry(
+        &self,
+        archive_path: &Path,
+        job_entry: &JobRecord,
+    ) -> Result<Vec<PathBuf>, Error> {
+        if !archive_path.is_dir() {
+            debug!("Archive {:?} does not yet exist, creating", archive_path);
+            self.permissions.create_dir(archive_path)?;
+        }
+        let target_path = match &self.name_template {
+            Some(_) => archive_path.to_path_buf(),
+            None => determine_target_path(archive_path, &self.period, &self.permissions)?,
+        };
+        debug!("Target path: {:?}", target_path);
+        let entry_path = |fname: &str| -> Result<PathBuf, Error> {
+            debug!("Creating an entry for {}", fname);
+            match &self.name_template {
+                Some(template) => {
+                    let path = target_path.join(render_name(template, job_entry, fname));
+                    match path.parent() {
+                        Some(dir) if !dir.is_dir() => self.permissions.create_dir(dir)?,
+                        _ => (),
+                    }
+                    Ok(path)
+                }
+                None => Ok(target_path.join(fname)),
+            }
+        };
+        let mut written = Vec::new();
+        let mut manifest = Vec::new();
+        let mut put = |fname: &str, contents: &mut dyn Read, source: Option<&PathBuf>| {
+            if self.content_store {
+                let (entry, stored) = self.store(archive_path, fname, contents, source)?;
+                written.extend(stored);
+                manifest.push(entry);
+            } else {
+                let path = entry_path(fname)?;
+                let mut f = self.permissions.create_file(&path, source)?;
+                io::copy(contents, &mut f)?;
+                written.push(path);
+            }
+            Ok::<(), Error>(())
+        };
+        for (fname, fcontents) in job_entry.files.iter() {
+            let source = job_entry.file_sources.get(fname);
+            put(fname, &mut fcontents.as_slice(), source)?;
         }
+        // files too large to be held in memory are copied in chunks
+        for (fname, source) in job_entry.streamed_files.iter() {
+            put(fname, &mut File::open(source)?, Some(source))?;
+        }
+        if let Some((normalized, hash)) = normalized_script(job_entry, &self.options) {
+            let fname = format!("job.{}_script_normalized", job_entry.jobid());
+            for (fname, contents) in [(format!("{fname}.sha256"), hash), (fname, normalized)] {
+                put(&fname, &mut contents.as_bytes(), None)?;
+            }
+        }
+        // the labels of this instance go along with the job, one key=value per line
+        if !self.labels.is_empty() {
+            let labels: String = self
+                .labels
+                .iter()
+                .map(|(key, value)| format!("{key}={value}\n"))
+                .collect();
+            let fname = format!("job.{}_labels", job_entry.jobid());
+            put(&fname, &mut labels.as_bytes(), None)?;
+        }
+        if self.content_store {
+            let path = entry_path(&manifest_name(&job_entry.jobid()))?;
+            let mut f = self.permissions.create_file(&path, None)?;
+            for entry in manifest.iter() {
+                writeln!(f, "{}", entry.line())?;
+            }
+            written.push(path);
+        }
+        Ok(written)
     }
 
-    pub fn build(args: &FileArgs) -> Result<Self, Error> {
+    /// Stores the contents under their hash in the objects directory of the
+    /// archive, unless contents with that hash are already there. Returns the
+    /// manifest entry of the file, and the path of the contents if they were
+    /// newly stored.
+    fn store(
+        &self,
+        archive_path: &Path,
+        fname: &str,
+        contents: &mut dyn Read,
+        source: Option<&PathBuf>,
+    ) -> Result<(ManifestEntry, Option<PathBuf>), Error> {
+        let objects = archive_path.join(OBJECTS_DIR);
+        if !objects.is_dir() {
+            self.permissions.create_dir(&objects)?;
+        }
+        // the hash is only known once the contents are written, so they go
+        // to a temporary file that is renamed into place
+        let temp = objects.join(format!(
+            ".{}.{}",
+            process::id(),
+            STORE_COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+        let copied = self
+            .permissions
+            .create_file(&temp, source)
+            .and_then(|mut f| hashing_copy(contents, &mut f));
+        let (hash, size) = match copied {
+            Ok(copied) => copied,
+            Err(e) => {
+                let _ = remove_file(&temp);
+                return Err(e);
+            }
+        };
+        let path = object_path(archive_path, &hash);
+        let entry = ManifestEntry {
+            hash,
+            size,
+            name: fname.to_owned(),
+        };
+        if path.is_file() {
+            debug!("Contents of {} are already stored at {:?}", fname, &path);
+            remove_file(&temp)?;
+            return Ok((entry, None));
+        }
+        match path.parent() {
+            Some(dir) if !dir.is_dir() => self.permissions.create_dir(dir)?,
+            _ => (),
+        }
+        rename(&temp, &path)?;
+        Ok((entry, Some(path)))
+    }
+
+    /// Flushes the written files and their directories to disk, according to
+    /// the fsync setting
+    fn sync(&self, written: Vec<PathBuf>) -> Result<(), Error> {
+        match self.fsync {
+            Fsync::Never => Ok(()),
+            Fsync::Always => sync_paths(&written),
+            Fsync::Periodic => {
+                self.unsynced
+                    .lock()
+                    .unwrap_or_else(|e| e.into_inner())
+                    .extend(written);
+                Ok(())
+            }
+        }
+    }
+
+    pub fn build(
+        args: &FileArgs,
+        identity: &Identity,
+        options: &RecordOptions,
+    ) -> Result<Self, Error> {
         let archive = args.archive.to_owned();
 
-        if !archive.is_dir() {
+        let placeholders = [
+            Some(&archive),
+            args.emergency_path.as_ref(),
+            args.failover_path.as_ref(),
+        ];
+        for path in placeholders.into_iter().flatten() {
+            if path
+                .to_string_lossy()
+                .replace(CLUSTER_PLACEHOLDER, "")
+                .contains(['{', '}'])
+            {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    format!(
+                        "Unsupported placeholder in {:?}, only {} is allowed",
+                        path, CLUSTER_PLACEHOLDER
+                    ),
+                ));
+            }
+        }
+
+        if let Some(template) = &args.name_template {
+            check_name_template(template)?;
+            if args.period != Period::None {
+                warn!(
+                    "The name template replaces the {:?} period subdirectory",
+                    args.period
+                );
+            }
+        }
+
+        // Per-cluster archives are created when the first job arrives
+        if archive.to_string_lossy().contains(CLUSTER_PLACEHOLDER) {
+            debug!("Archive {:?} depends on the cluster", &archive);
+        } else if !archive.is_dir() {
             warn!(
                 "Provided archive {:?} is not a valid directory, creating it.",
                 &archive
             );
-            if let Err(e) = create_dir_all(&archive) {
+            if let Err(e) = args.permissions.create_dir(&archive) {
                 error!("Unable to create archive at {:?}. {}", &archive, e);
                 return Err(e);
             }
         };
 
-        Ok(FileArchive::new(&archive, &args.period))
+        let mut file_archive = FileArchive::new(&archive, &args.period);
+        file_archive.fsync = args.fsync;
+        file_archive.fsync_interval = Duration::from_secs(args.fsync_interval);
+        file_archive.emergency_path = args.emergency_path.clone();
+        file_archive.permissions = args.permissions.clone();
+        file_archive.cluster_archives = args.cluster_archives.iter().cloned().collect();
+        file_archive.name_template = args.name_template.clone();
+        file_archive.options = options.clone();
+        file_archive.labels = identity.labels.clone();
+        file_archive.content_store = args.content_store;
+        if file_archive.fsync == Fsync::Periodic {
+            file_archive.start_flushing();
+        }
+        if let Some(failover_path) = &args.failover_path {
+            file_archive
+                .start_migration(failover_path, Duration::from_secs(args.failover_interval));
+        }
+        Ok(file_archive)
+    }
+
+    /// Fails over to the given path, and starts moving the job entries found
+    /// there to the archive every interval. Entries left behind by an earlier
+    /// run are picked up when neither path depends on the cluster.
+    fn start_migration(&mut self, failover_path: &Path, interval: Duration) {
+        self.failover_path = Some(failover_path.to_path_buf());
+        let placeholder = |p: &Path| p.to_string_lossy().contains(CLUSTER_PLACEHOLDER);
+        if failover_path.is_dir() && !placeholder(failover_path) && !placeholder(&self.archive_path)
+        {
+            self.failed_over
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .insert((failover_path.to_path_buf(), self.archive_path.clone()));
+        }
+
+        let (stop, stopped) = bounded::<()>(1);
+        let failed_over = Arc::clone(&self.failed_over);
+        let permissions = self.permissions.clone();
+        let fsync = self.fsync != Fsync::Never;
+        thread::spawn(move || {
+            while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(interval) {
+                migrate_all(&failed_over, &permissions, fsync);
+            }
+            debug!("Stopped moving job entries from the failover path");
+        });
+        self.migration = Some(stop);
+    }
+
+    /// Starts flushing the files written since the last flush to disk every
+    /// fsync interval
+    fn start_flushing(&mut self) {
+        let (stop, stopped) = bounded::<()>(1);
+        let unsynced = Arc::clone(&self.unsynced);
+        let interval = self.fsync_interval;
+        thread::spawn(move || {
+            while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(interval) {
+                if let Err(e) = flush_unsynced(&unsynced) {
+                    error!("Could not flush archived files: {}", e);
+                }
+            }
+            debug!("Stopped flushing archived files");
+        });
+        self.flushing = Some(stop);
+    }
+}
+
+/// Flushes the files written since the last flush to disk. The files are
+/// forgotten even when the flush fails, as they may have been removed.
+fn flush_unsynced(unsynced: &Mutex<Vec<PathBuf>>) -> Result<(), Error> {
+    let paths = take(&mut *unsynced.lock().unwrap_or_else(|e| e.into_inner()));
+    if paths.is_empty() {
+        return Ok(());
     }
+    debug!("Flushing {} archived files", paths.len());
+    sync_paths(&paths)
+}
+
+/// Moves the job entries from each failover path to its archive, forgetting
+/// the failover paths that were emptied. A failover path is left for the next
+/// attempt at the first file that cannot be moved.
+///
+/// The failover paths stay locked while moving, so a job is never written to
+/// a failover path that is being emptied and then forgotten.
+fn migrate_all(
+    failed_over: &Mutex<BTreeSet<(PathBuf, PathBuf)>>,
+    permissions: &Permissions,
+    fsync: bool,
+) {
+    let mut failed_over = failed_over.lock().unwrap_or_else(|e| e.into_inner());
+    let pending: Vec<(PathBuf, PathBuf)> = failed_over.iter().cloned().collect();
+    for (failover_path, archive_path) in pending {
+        match migrate(&failover_path, &archive_path, &failover_path, permissions) {
+            Ok(moved) => {
+                if !moved.is_empty() {
+                    info!(
+                        "Moved {} files from failover path {:?} to archive {:?}",
+                        moved.len(),
+                        &failover_path,
+                        &archive_path
+                    );
+                }
+                if fsync {
+                    if let Err(e) = sync_paths(&moved) {
+                        error!("Could not flush the moved files: {}", e);
+                    }
+                }
+                match holds_files(&failover_path) {
+                    Ok(false) => {
+                        failed_over.remove(&(failover_path, archive_path));
+                    }
+                    Ok(true) => debug!(
+                        "Failover path {:?} still holds files, keeping it",
+                        &failover_path
+                    ),
+                    Err(e) => debug!("Cannot check failover path {:?}: {}", &failover_path, e),
+                }
+            }
+            Err(e) => debug!(
+                "Cannot move the files in failover path {:?} to archive {:?} yet: {}",
+                &failover_path, &archive_path, e
+            ),
+        }
+    }
+}
+
+/// Returns whether a job entry file remains under the given directory,
+/// ignoring the temporary files left behind by an interrupted write
+fn holds_files(dir: &Path) -> Result<bool, Error> {
+    for entry in read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            if holds_files(&path)? {
+                return Ok(true);
+            }
+        } else if !is_temporary(&path) {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// Returns whether the file is a temporary file that is still being written,
+/// such as the content store's, which are named after a leading dot
+fn is_temporary(path: &Path) -> bool {
+    path.file_name()
+        .is_some_and(|name| name.to_string_lossy().starts_with('.'))
+}
+
+/// Moves the files under the given directory of the failover path to the same
+/// place in the archive, returning their paths in the archive. Temporary files
+/// are left alone, and a file copied to another filesystem only appears in the
+/// archive once it was copied completely.
+fn migrate(
+    dir: &Path,
+    archive_path: &Path,
+    failover_path: &Path,
+    permissions: &Permissions,
+) -> Result<Vec<PathBuf>, Error> {
+    let mut moved = Vec::new();
+    for entry in read_dir(dir)? {
+        let source = entry?.path();
+        if source.is_dir() {
+            moved.extend(migrate(&source, archive_path, failover_path, permissions)?);
+            continue;
+        }
+        if is_temporary(&source) {
+            continue;
+        }
+        let relative = source.strip_prefix(failover_path).unwrap_or(&source);
+        let target = archive_path.join(relative);
+        if let Some(parent) = target.parent().filter(|p| !p.is_dir()) {
+            permissions.create_dir(parent)?;
+        }
+        // the failover path is usually on another filesystem
+        if rename(&source, &target).is_err() {
+            let partial = target.with_file_name(format!(
+                ".{}.migrating",
+                target.file_name().unwrap_or_default().to_string_lossy()
+            ));
+            copy(&source, &partial)?;
+            permissions.apply(&partial, permissions.file_mode)?;
+            rename(&partial, &target)?;
+            remove_file(&source)?;
+        }
+        moved.push(target);
+    }
+    Ok(moved)
+}
+
+impl Drop for FileArchive {
+    /// Stops the migration from the failover path and the periodic flush,
+    /// and flushes the files still waiting for the latter
+    fn drop(&mut self) {
+        drop(self.migration.take());
+        drop(self.flushing.take());
+        if let Err(e) = flush_unsynced(&self.unsynced) {
+            error!("Could not flush archived files: {}", e);
+        }
+    }
+}
+
+/// Flushes the given files to disk, followed by their parent directories
+/// so the directory entries are durable as well
+fn sync_paths(paths: &[PathBuf]) -> Result<(), Error> {
+    let mut dirs: Vec<PathBuf> = Vec::new();
+    for path in paths {
+        File::open(path)?.sync_all()?;
+        if let Some(parent) = path.parent() {
+            if !dirs.iter().any(|d| d == parent) {
+                dirs.push(parent.to_path_buf());
+            }
+        }
+    }
+    for dir in dirs {
+        File::open(dir)?.sync_all()?;
+    }
+    Ok(())
 }
 
 impl Archive for FileArchive {
-    /// Archives the files from the given SlurmJobEntry's path.
+    /// Archives the files from the given SlurmJobEntry's path, in the
+    /// archive directory of the job's cluster.
     ///
-    fn archive(&self, job_entry: &Box<dyn JobInfo>) -> Result<(), Error> {
-        let archive_path = &self.archive_path;
-        let target_path = determine_target_path(archive_path, &self.period);
-        debug!("Target path: {:?}", target_path);
-        for (fname, fcontents) in job_entry.files().iter() {
-            debug!("Creating an entry for {}", fname);
-            let mut f = File::create(target_path.join(fname))?;
-            f.write_all(fcontents)?;
+    /// If the archive is full and an emergency path is set, the files are
+    /// written there instead. If writing fails otherwise and a failover path
+    /// is set, the files are written there, to be moved to the archive later.
+    fn archive(&self, job_entry: &JobRecord) -> Result<(), Error> {
+        let cluster = job_entry.cluster();
+        let archive_path = self.archive_root(&cluster);
+        let written = match self.write_entry(&archive_path, job_entry) {
+            Err(e) if is_storage_full(&e) && self.emergency_path.is_some() => {
+                let emergency_path = with_cluster(self.emergency_path.as_ref().unwrap(), &cluster);
+                error!(
+                    "Archive {:?} is full ({}), writing job {} to emergency path {:?}",
+                    &archive_path,
+                    e,
+                    job_entry.jobid(),
+                    &emergency_path
+                );
+                self.write_entry(&emergency_path, job_entry)?
+            }
+            Err(e) if self.failover_path.is_some() => {
+                let failover_path = with_cluster(self.failover_path.as_ref().unwrap(), &cluster);
+                error!(
+                    "Cannot write job {} to archive {:?} ({}), failing over to {:?}",
+                    job_entry.jobid(),
+                    &archive_path,
+                    e,
+                    &failover_path
+                );
+                // hold off the migration until the failover path is recorded
+                let mut failed_over = self.failed_over.lock().unwrap_or_else(|e| e.into_inner());
+                let written = self.write_entry(&failover_path, job_entry)?;
+                failed_over.insert((failover_path, archive_path));
+                written
+            }
+            result => result?,
+        };
+        self.sync(written)
+    }
+
+    /// Checks that the archive of the cluster, and the emergency and failover
+    /// paths if any, can be written to
+    fn check(&self, cluster: &str) -> Result<(), Error> {
+        check_writable(&self.archive_root(cluster))?;
+        for path in [&self.emergency_path, &self.failover_path]
+            .into_iter()
+            .flatten()
+        {
+            check_writable(&with_cluster(path, cluster))?;
         }
         Ok(())
     }
+
+    /// Flushes the files still waiting for a periodic flush
+    fn flush(&self, _timeout: Duration) -> Result<(), Error> {
+        flush_unsynced(&self.unsynced)
+    }
+
+    fn name(&self) -> &str {
+        "file"
+    }
+}
+
+/// Placeholders in the name template for the job and its file. The date is
+/// that of archival, as for the period subdirectories.
+const NAME_PLACEHOLDERS: [&str; 7] = [
+    "{cluster}",
+    "{jobid}",
+    "{year}",
+    "{month}",
+    "{day}",
+    "{filename}",
+    "{name}",
+];
+
+/// Verifies that the name template only holds known placeholders, names each
+/// file of each job separately and stays within the archive. `{name}` holds
+/// the job ID for every scheduler, `{filename}` does not, so without `{jobid}`
+/// the files of one job would overwrite those of the previous one.
+pub(crate) fn check_name_template(template: &str) -> Result<(), Error> {
+    let invalid = |reason: &str| {
+        Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("Invalid name template {template}: {reason}"),
+        ))
+    };
+    let unknown = NAME_PLACEHOLDERS
+        .iter()
+        .fold(template.to_owned(), |t, p| t.replace(p, ""));
+    if unknown.contains(['{', '}']) {
+        return invalid(&format!(
+            "supported placeholders are {}",
+            NAME_PLACEHOLDERS.join(", ")
+        ));
+    }
+    if !template.contains("{filename}") && !template.contains("{name}") {
+        return invalid("it should contain {filename} or {name}");
+    }
+    if !template.contains("{jobid}") && !template.contains("{name}") {
+        return invalid("it should contain {jobid} or {name}, so each job has its own files");
+    }
+    if Path::new(template).is_absolute() || template.split('/').any(|c| c == "..") {
+        return invalid("it should be a relative path within the archive");
+    }
+    Ok(())
+}
+
+/// Returns the path of the file in the archive according to the name template.
+/// `{name}` is the file name as given by the scheduler (e.g., `job.1234_script`
+/// for Slurm) and `{filename}` is that name without the `job.<jobid>_` prefix.
+fn render_name(template: &str, job_entry: &dyn JobInfo, name: &str) -> PathBuf {
+    let jobid = job_entry.jobid();
+    let now = chrono::Local::now();
+    let filename = name.strip_prefix(&format!("job.{jobid}_")).unwrap_or(name);
+    PathBuf::from(
+        template
+            .replace("{cluster}", &job_entry.cluster())
+            .replace("{jobid}", &jobid)
+            .replace("{year}", &now.format("%Y").to_string())
+            .replace("{month}", &now.format("%m").to_string())
+            .replace("{day}", &now.format("%d").to_string())
+            .replace("{filename}", filename)
+            .replace("{name}", name),
+    )
 }
 
 /// Determines the target path for the slurm job file
@@ -104,7 +903,11 @@ impl Archive for FileArchive {
 ///     - YYYY in case of a Yearly Period
 ///     - YYYYMM in case of a Monthly Period
 ///     - YYYYMMDD in case of a Daily Period
-fn determine_target_path(archive_path: &Path, p: &Period) -> PathBuf {
+fn determine_target_path(
+    archive_path: &Path,
+    p: &Period,
+    permissions: &Permissions,
+) -> Result<PathBuf, Error> {
     let archive_subdir = match p {
         Period::Yearly => Some(format!("{}", chrono::Local::now().format("%Y"))),
         Period::Monthly => Some(format!("{}", chrono::Local::now().format("%Y%m"))),
@@ -117,11 +920,11 @@ fn determine_target_path(archive_path: &Path, p: &Period) -> PathBuf {
             let archive_subdir_path = archive_path.join(&d);
             if !Path::exists(&archive_subdir_path) {
                 debug!("Archive subdir {:?} does not yet exist, creating", &d);
-                create_dir_all(&archive_subdir_path).unwrap();
+                permissions.create_dir(&archive_subdir_path)?;
             }
-            archive_subdir_path
+            Ok(archive_subdir_path)
         }
-        None => archive_path.to_path_buf(),
+        None => Ok(archive_path.to_path_buf()),
     }
 }
 
@@ -130,7 +933,7 @@ mod tests {
 
     extern crate tempfile;
 
-    use chrono::Local;
+    use chrono::{DateTime, Local, Utc};
     use std::collections::HashMap;
     use std::env;
     use std::fs::{create_dir, read_to_string, remove_dir_all, File};
@@ -139,6 +942,8 @@ mod tests {
     use std::time::Instant;
     use tempfile::tempdir;
 
+    use super::super::cas::parse_manifest;
+    use super::super::dedup::content_hash;
     use super::super::*;
     use super::*;
     use crate::scheduler::job::JobInfo;
@@ -164,9 +969,19 @@ mod tests {
         let args = FileArgs {
             archive: archive_path.clone(),
             period: period.clone(),
+            cluster_archives: Vec::new(),
+            name_template: None,
+            fsync: Fsync::Always,
+            fsync_interval: 5,
+            emergency_path: None,
+            failover_path: None,
+            failover_interval: 60,
+            content_store: false,
+            permissions: Permissions::default(),
         };
 
-        let file_archive = FileArchive::build(&args).unwrap();
+        let file_archive =
+            FileArchive::build(&args, &Identity::default(), &RecordOptions::default()).unwrap();
 
         assert_eq!(file_archive.archive_path, archive_path);
         assert_eq!(file_archive.period, period);
@@ -181,9 +996,19 @@ mod tests {
         let args = FileArgs {
             archive: archive_path.clone(),
             period: period.clone(),
+            cluster_archives: Vec::new(),
+            name_template: None,
+            fsync: Fsync::Always,
+            fsync_interval: 5,
+            emergency_path: None,
+            failover_path: None,
+            failover_interval: 60,
+            content_store: false,
+            permissions: Permissions::default(),
         };
 
-        let file_archive = FileArchive::build(&args).unwrap();
+        let file_archive =
+            FileArchive::build(&args, &Identity::default(), &RecordOptions::default()).unwrap();
 
         assert_eq!(file_archive.archive_path, archive_path);
         assert_eq!(file_archive.period, period);
@@ -197,6 +1022,7 @@ mod tests {
         files: Vec<(String, Vec<u8>)>,
         script: String,
         extra_info: Option<HashMap<String, String>>,
+        streamed: HashMap<String, PathBuf>,
     }
 
     impl DummyJobInfo {
@@ -211,6 +1037,7 @@ mod tests {
                 ],
                 script: "echo 'Hello, World!'".to_string(),
                 extra_info: Some(HashMap::new()),
+                streamed: HashMap::new(),
             }
         }
     }
@@ -224,6 +1051,10 @@ mod tests {
             self.moment
         }
 
+        fn event_time(&self) -> DateTime<Utc> {
+            Utc::now()
+        }
+
         fn cluster(&self) -> String {
             self.cluster.clone()
         }
@@ -237,6 +1068,10 @@ mod tests {
             self.files.clone()
         }
 
+        fn streamed_files(&self) -> HashMap<String, PathBuf> {
+            self.streamed.clone()
+        }
+
         fn script(&self) -> String {
             self.script.clone()
         }
@@ -289,8 +1124,7 @@ mod tests {
         let temp_dir = tempdir().unwrap();
         let archive_path = temp_dir.path().to_owned();
         let period = Period::Daily;
-        let job_info: Box<dyn JobInfo + 'static> =
-            Box::new(DummyJobInfo::new("123", Instant::now(), "test_cluster"));
+        let job_info = JobRecord::new(&DummyJobInfo::new("123", Instant::now(), "test_cluster"));
 
         let file_archive = FileArchive::new(&archive_path, &period);
         file_archive.archive(&job_info).unwrap();
@@ -308,41 +1142,306 @@ mod tests {
         remove_dir_all(&archive_path).unwrap();
     }
 
+    #[test]
+    fn test_file_archive_streamed() {
+        let spool = tempdir().unwrap();
+        let source = spool.path().join("script");
+        let contents = vec![b'x'; 1 << 20];
+        std::fs::write(&source, &contents).unwrap();
+
+        let temp_dir = tempdir().unwrap();
+        let archive_path = temp_dir.path().to_owned();
+        let mut dummy = DummyJobInfo::new("123", Instant::now(), "test_cluster");
+        dummy
+            .streamed
+            .insert("job.123_script".to_string(), source.clone());
+        let job_info = JobRecord::new(&dummy);
+
+        let file_archive = FileArchive::new(&archive_path, &Period::None);
+        file_archive.archive(&job_info).unwrap();
+
+        assert!(archive_path.join("file1.txt").exists());
+        let read_contents = std::fs::read(archive_path.join("job.123_script")).unwrap();
+        assert_eq!(read_contents, contents);
+    }
+
+    #[test]
+    fn test_file_archive_normalized_script() {
+        let temp_dir = tempdir().unwrap();
+        let archive_path = temp_dir.path().to_owned();
+        let mut dummy = DummyJobInfo::new("123", Instant::now(), "test_cluster");
+        dummy.script = "#!/bin/bash\n#SBATCH -N 1\n\n  srun   hostname  # run\n".to_owned();
+        let job_info = JobRecord::new(&dummy);
+
+        let mut file_archive = FileArchive::new(&archive_path, &Period::None);
+        file_archive.archive(&job_info).unwrap();
+        assert!(!archive_path.join("job.123_script_normalized").exists());
+
+        file_archive.options.normalize_script = true;
+        file_archive.archive(&job_info).unwrap();
+        let normalized = read_to_string(archive_path.join("job.123_script_normalized")).unwrap();
+        assert_eq!(normalized, "srun hostname # run");
+        let hash = read_to_string(archive_path.join("job.123_script_normalized.sha256")).unwrap();
+        assert_eq!(hash, content_hash(normalized.as_bytes()));
+    }
+
+    #[test]
+    fn test_file_archive_labels() {
+        let temp_dir = tempdir().unwrap();
+        let archive_path = temp_dir.path().to_owned();
+        let job_info = JobRecord::new(&DummyJobInfo::new("123", Instant::now(), "test_cluster"));
+
+        let mut file_archive = FileArchive::new(&archive_path, &Period::None);
+        file_archive.archive(&job_info).unwrap();
+        assert!(!archive_path.join("job.123_labels").exists());
+
+        file_archive
+            .labels
+            .insert("environment".to_owned(), "prod".to_owned());
+        file_archive
+            .labels
+            .insert("datacenter".to_owned(), "dc1".to_owned());
+        file_archive.archive(&job_info).unwrap();
+        let labels = read_to_string(archive_path.join("job.123_labels")).unwrap();
+        assert_eq!(labels, "datacenter=dc1\nenvironment=prod\n");
+    }
+
+    #[test]
+    fn test_file_archive_per_cluster() {
+        let temp_dir = tempdir().unwrap();
+        let other_path = temp_dir.path().join("other");
+        let args = FileArgs {
+            archive: temp_dir.path().join("{cluster}/jobs"),
+            period: Period::None,
+            cluster_archives: vec![parse_cluster_archive(&format!(
+                "cluster3={}",
+                other_path.display()
+            ))
+            .unwrap()],
+            name_template: None,
+            fsync: Fsync::Never,
+            fsync_interval: 5,
+            emergency_path: None,
+            failover_path: None,
+            failover_interval: 60,
+            content_store: false,
+            permissions: Permissions::default(),
+        };
+        let file_archive =
+            FileArchive::build(&args, &Identity::default(), &RecordOptions::default()).unwrap();
+
+        for cluster in ["cluster1", "cluster2", "cluster3"] {
+            let job_info = JobRecord::new(&DummyJobInfo::new("123", Instant::now(), cluster));
+            file_archive.archive(&job_info).unwrap();
+        }
+
+        assert!(temp_dir.path().join("cluster1/jobs/file1.txt").exists());
+        assert!(temp_dir.path().join("cluster2/jobs/file1.txt").exists());
+        assert!(other_path.join("file1.txt").exists());
+        assert!(!temp_dir.path().join("cluster3").exists());
+    }
+
+    #[test]
+    fn test_file_archive_failover() {
+        let temp_dir = tempdir().unwrap();
+        let archive_path = temp_dir.path().join("archive");
+        let failover_path = temp_dir.path().join("failover");
+        // the archive cannot be created while a file is in the way
+        std::fs::write(&archive_path, b"").unwrap();
+        let job_info = JobRecord::new(&DummyJobInfo::new("123", Instant::now(), "test_cluster"));
+
+        let mut file_archive = FileArchive::new(&archive_path, &Period::None);
+        file_archive.name_template = Some("{jobid}/{filename}".to_owned());
+        assert!(file_archive.archive(&job_info).is_err());
+
+        file_archive.failover_path = Some(failover_path.clone());
+        file_archive.archive(&job_info).unwrap();
+        assert!(failover_path.join("123/file1.txt").exists());
+
+        // the archive is still unavailable, so the files stay put
+        migrate_all(&file_archive.failed_over, &Permissions::default(), false);
+        assert_eq!(file_archive.failed_over.lock().unwrap().len(), 1);
+
+        // a temporary file left behind by an interrupted write is not moved
+        std::fs::write(failover_path.join("123/.1.0"), b"partial").unwrap();
+        std::fs::remove_file(&archive_path).unwrap();
+        migrate_all(&file_archive.failed_over, &Permissions::default(), false);
+        assert!(file_archive.failed_over.lock().unwrap().is_empty());
+        assert!(!archive_path.join("123/.1.0").exists());
+        assert!(failover_path.join("123/.1.0").exists());
+        assert_eq!(
+            read_to_string(archive_path.join("123/file2.txt")).unwrap(),
+            "contents2"
+        );
+        assert!(!failover_path.join("123/file1.txt").exists());
+    }
+
+    #[test]
+    fn test_file_archive_content_store() {
+        let temp_dir = tempdir().unwrap();
+        let archive_path = temp_dir.path().to_path_buf();
+        let mut file_archive = FileArchive::new(&archive_path, &Period::None);
+        file_archive.content_store = true;
+        file_archive.name_template = Some("{jobid}/{filename}".to_owned());
+
+        for jobid in ["123", "124"] {
+            let job_info = JobRecord::new(&DummyJobInfo::new(jobid, Instant::now(), "cluster"));
+            file_archive.archive(&job_info).unwrap();
+        }
+
+        // both jobs refer to the same two stored contents
+        let objects: Vec<_> = walkdir(&archive_path.join(OBJECTS_DIR));
+        assert_eq!(objects.len(), 2);
+        let manifest = |jobid: &str| {
+            let path = archive_path.join(jobid).join("manifest");
+            parse_manifest(&read_to_string(path).unwrap()).unwrap()
+        };
+        assert_eq!(manifest("123"), manifest("124"));
+        assert!(!archive_path.join("123/file1.txt").exists());
+
+        let entries = manifest("123");
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].name, "file1.txt");
+        assert_eq!(entries[0].size, 9);
+        assert_eq!(entries[0].hash, content_hash(b"contents1"));
+        assert_eq!(
+            read_to_string(object_path(&archive_path, &entries[1].hash)).unwrap(),
+            "contents2"
+        );
+    }
+
+    /// Returns the files under the given directory
+    fn walkdir(dir: &Path) -> Vec<PathBuf> {
+        let mut files = Vec::new();
+        for entry in std::fs::read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+            if path.is_dir() {
+                files.extend(walkdir(&path));
+            } else {
+                files.push(path);
+            }
+        }
+        files
+    }
+
+    #[test]
+    fn test_file_archive_check() {
+        let temp_dir = tempdir().unwrap();
+        let file_archive = FileArchive::new(&temp_dir.path().join("{cluster}"), &Period::None);
+        assert!(file_archive.check("mycluster").is_ok());
+        assert!(!temp_dir.path().join("mycluster").exists());
+
+        std::fs::write(temp_dir.path().join("full"), b"").unwrap();
+        let file_archive = FileArchive::new(&temp_dir.path().join("full"), &Period::None);
+        assert!(file_archive.check("mycluster").is_err());
+    }
+
+    #[test]
+    fn test_file_archive_build_invalid_placeholder() {
+        let args = FileArgs {
+            archive: PathBuf::from("/archive/{host}"),
+            period: Period::None,
+            cluster_archives: Vec::new(),
+            name_template: None,
+            fsync: Fsync::Never,
+            fsync_interval: 5,
+            emergency_path: None,
+            failover_path: None,
+            failover_interval: 60,
+            content_store: false,
+            permissions: Permissions::default(),
+        };
+        let err = FileArchive::build(&args, &Identity::default(), &RecordOptions::default())
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn test_parse_cluster_archive() {
+        assert_eq!(
+            parse_cluster_archive("mycluster=/archive/a=b").unwrap(),
+            ("mycluster".to_owned(), PathBuf::from("/archive/a=b"))
+        );
+        assert!(parse_cluster_archive("/archive").is_err());
+        assert!(parse_cluster_archive("=/archive").is_err());
+    }
+
+    #[test]
+    fn test_file_archive_name_template() {
+        let temp_dir = tempdir().unwrap();
+        let path = env::current_dir().unwrap().join("tests/job.123456");
+        let mut entry = SlurmJobEntry::new(&path, "123456", "mycluster");
+        entry.read_job_info().unwrap();
+        let job_info = JobRecord::new(&entry);
+
+        let mut file_archive = FileArchive::new(&temp_dir.path().to_path_buf(), &Period::None);
+        file_archive.name_template = Some("{cluster}/{year}/{jobid}/{filename}".to_owned());
+        file_archive.archive(&job_info).unwrap();
+
+        let job_dir = temp_dir
+            .path()
+            .join("mycluster")
+            .join(Local::now().format("%Y").to_string())
+            .join("123456");
+        assert!(job_dir.join("script").exists());
+        assert!(job_dir.join("environment").exists());
+    }
+
+    #[test]
+    fn test_check_name_template() {
+        assert!(check_name_template("{cluster}/{year}{month}{day}/{name}").is_ok());
+        assert!(check_name_template("{jobid}/{filename}").is_ok());
+        assert!(check_name_template("{jobid}/{host}/{filename}").is_err());
+        assert!(check_name_template("{cluster}/{jobid}").is_err());
+        assert!(check_name_template("/archive/{filename}").is_err());
+        assert!(check_name_template("../{filename}").is_err());
+        // every job would write the same files
+        assert_eq!(
+            check_name_template("{cluster}/{filename}")
+                .unwrap_err()
+                .to_string(),
+            "Invalid name template {cluster}/{filename}: it should contain {jobid} or {name}, so each job has its own files"
+        );
+        assert!(check_name_template("{cluster}/{year}/{filename}").is_err());
+    }
+
     #[test]
     fn test_determine_target_path() {
         let tdir = tempdir().unwrap();
 
         // create the basic archive path
         let archive_dir = tdir.path();
-        let _dir = create_dir(&archive_dir);
+        let _dir = create_dir(archive_dir);
 
         let p = Period::None;
-        let target_path = determine_target_path(&archive_dir, &p);
+        let target_path = determine_target_path(archive_dir, &p, &Permissions::default()).unwrap();
         assert_eq!(target_path, archive_dir);
 
         let d = format!("{}", chrono::Local::now().format("%Y"));
         let p = Period::Yearly;
-        let target_path = determine_target_path(&archive_dir, &p);
+        let target_path = determine_target_path(archive_dir, &p, &Permissions::default()).unwrap();
         assert_eq!(target_path, archive_dir.join(d));
 
         let d = format!("{}", chrono::Local::now().format("%Y%m"));
         let p = Period::Monthly;
-        let target_path = determine_target_path(&archive_dir, &p);
+        let target_path = determine_target_path(archive_dir, &p, &Permissions::default()).unwrap();
         assert_eq!(target_path, archive_dir.join(d));
 
         let d = format!("{}", chrono::Local::now().format("%Y%m%d"));
         let p = Period::Daily;
-        let target_path = determine_target_path(&archive_dir, &p);
+        let target_path = determine_target_path(archive_dir, &p, &Permissions::default()).unwrap();
         assert_eq!(target_path, archive_dir.join(d));
     }
 
     #[test]
     fn test_determine_target_path_yearly() {
         let temp_dir = env::temp_dir();
-        let target_path = determine_target_path(&temp_dir, &Period::Yearly);
+        let target_path =
+            determine_target_path(&temp_dir, &Period::Yearly, &Permissions::default()).unwrap();
         assert_eq!(
             target_path,
-            temp_dir.join(&format!("{}", Local::now().format("%Y")))
+            temp_dir.join(format!("{}", Local::now().format("%Y")))
         );
         assert!(target_path.exists());
         remove_dir_all(&target_path).unwrap();
@@ -351,10 +1450,11 @@ mod tests {
     #[test]
     fn test_determine_target_path_monthly() {
         let temp_dir = env::temp_dir();
-        let target_path = determine_target_path(&temp_dir, &Period::Monthly);
+        let target_path =
+            determine_target_path(&temp_dir, &Period::Monthly, &Permissions::default()).unwrap();
         assert_eq!(
             target_path,
-            temp_dir.join(&format!("{}", Local::now().format("%Y%m")))
+            temp_dir.join(format!("{}", Local::now().format("%Y%m")))
         );
         assert!(target_path.exists());
         remove_dir_all(&target_path).unwrap();
@@ -363,10 +1463,11 @@ mod tests {
     #[test]
     fn test_determine_target_path_daily() {
         let temp_dir = env::temp_dir();
-        let target_path = determine_target_path(&temp_dir, &Period::Daily);
+        let target_path =
+            determine_target_path(&temp_dir, &Period::Daily, &Permissions::default()).unwrap();
         assert_eq!(
             target_path,
-            temp_dir.join(&format!("{}", Local::now().format("%Y%m%d")))
+            temp_dir.join(format!("{}", Local::now().format("%Y%m%d")))
         );
         assert!(target_path.exists());
         remove_dir_all(&target_path).unwrap();
@@ -375,7 +1476,8 @@ mod tests {
     #[test]
     fn test_determine_target_path_none() {
         let temp_dir = env::temp_dir();
-        let target_path = determine_target_path(&temp_dir, &Period::None);
+        let target_path =
+            determine_target_path(&temp_dir, &Period::None, &Permissions::default()).unwrap();
         assert_eq!(target_path, temp_dir);
     }
 
@@ -394,29 +1496,159 @@ mod tests {
         // create env and script files
         let env_path = job_dir.join("environment");
         let mut env = File::create(env_path).unwrap();
-        env.write(b"environment").unwrap();
+        env.write_all(b"environment").unwrap();
 
         let job_path = job_dir.join("script");
         let mut job = File::create(&job_path).unwrap();
-        job.write(b"job script").unwrap();
+        job.write_all(b"job script").unwrap();
 
-        let mut slurm_job_entry = SlurmJobEntry::new(&job_dir, "1234", "mycluster", &None);
-        if let Err(_) = slurm_job_entry.read_job_info() {
-            assert!(false);
-        }
+        let mut slurm_job_entry = SlurmJobEntry::new(&job_dir, "1234", "mycluster");
+        slurm_job_entry.read_job_info().unwrap();
 
         let file_archiver = FileArchive::new(&archive_dir, &Period::None);
-        let jobinfo: Box<dyn JobInfo> = Box::new(slurm_job_entry);
+        let jobinfo = JobRecord::new(&slurm_job_entry);
         file_archiver.archive(&jobinfo).unwrap();
 
         assert!(Path::is_file(&archive_dir.join("job.1234_environment")));
         assert!(Path::is_file(&archive_dir.join("job.1234_script")));
 
         let archive_env_contents =
-            read_to_string(&archive_dir.join("job.1234_environment")).unwrap();
+            read_to_string(archive_dir.join("job.1234_environment")).unwrap();
         assert_eq!(&archive_env_contents, "environment");
 
-        let archive_script_contents = read_to_string(&archive_dir.join("job.1234_script")).unwrap();
+        let archive_script_contents = read_to_string(archive_dir.join("job.1234_script")).unwrap();
         assert_eq!(&archive_script_contents, "job script");
     }
+
+    #[test]
+    fn test_file_archive_fsync_periodic() {
+        let tdir = tempdir().unwrap();
+        let archive_dir = tdir.path().to_path_buf();
+        let job_info = JobRecord::new(&DummyJobInfo::new("123", Instant::now(), "test_cluster"));
+
+        let mut file_archive = FileArchive::new(&archive_dir, &Period::None);
+        file_archive.fsync = Fsync::Periodic;
+        file_archive.fsync_interval = Duration::from_millis(50);
+        file_archive.archive(&job_info).unwrap();
+        assert_eq!(file_archive.unsynced.lock().unwrap().len(), 2);
+
+        // the files are flushed without waiting for a later write
+        file_archive.start_flushing();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !file_archive.unsynced.lock().unwrap().is_empty() {
+            assert!(Instant::now() < deadline, "the files were not flushed");
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn test_parse_mode() {
+        assert_eq!(parse_mode("0640"), Ok(0o640));
+        assert_eq!(parse_mode("0o750"), Ok(0o750));
+        assert!(parse_mode("0980").is_err());
+        assert!(parse_mode("17777").is_err());
+    }
+
+    #[test]
+    fn test_parse_user_group() {
+        assert_eq!(parse_user("0"), Ok(0));
+        assert_eq!(parse_user("root"), Ok(0));
+        assert_eq!(parse_group("root"), Ok(0));
+        assert!(parse_user("no-such-user-sarchive").is_err());
+    }
+
+    #[test]
+    fn test_file_archive_permissions() {
+        use std::os::unix::fs::MetadataExt;
+
+        let tdir = tempdir().unwrap();
+        let archive_dir = tdir.path().to_path_buf();
+        let job_info = JobRecord::new(&DummyJobInfo::new("123", Instant::now(), "test_cluster"));
+
+        let mut file_archive = FileArchive::new(&archive_dir, &Period::Daily);
+        file_archive.permissions = Permissions {
+            file_mode: Some(0o600),
+            dir_mode: Some(0o710),
+            owner: Some(unsafe { libc::getuid() }),
+            group: Some(unsafe { libc::getgid() }),
+            ..Default::default()
+        };
+        file_archive.archive(&job_info).unwrap();
+
+        let subdir = archive_dir.join(format!("{}", Local::now().format("%Y%m%d")));
+        assert_eq!(subdir.metadata().unwrap().mode() & 0o7777, 0o710);
+        let file = subdir.join("file1.txt").metadata().unwrap();
+        assert_eq!(file.mode() & 0o7777, 0o600);
+        assert_eq!(file.uid(), unsafe { libc::getuid() });
+    }
+
+    #[test]
+    fn test_file_archive_permissions_template() {
+        use std::os::unix::fs::MetadataExt;
+
+        let tdir = tempdir().unwrap();
+        let archive_dir = tdir.path().to_path_buf();
+        let job_info = JobRecord::new(&DummyJobInfo::new("123", Instant::now(), "test_cluster"));
+
+        let mut file_archive = FileArchive::new(&archive_dir, &Period::None);
+        file_archive.name_template = Some("{cluster}/{year}/{jobid}/{filename}".to_owned());
+        file_archive.permissions = Permissions {
+            dir_mode: Some(0o710),
+            ..Default::default()
+        };
+        let mode = archive_dir.metadata().unwrap().mode();
+        file_archive.archive(&job_info).unwrap();
+
+        // the existing archive is left as it is, every directory the template adds gets the mode, not just the last
+        let cluster = archive_dir.join("test_cluster");
+        let year = cluster.join(Local::now().format("%Y").to_string());
+        for dir in [&cluster, &year, &year.join("123")] {
+            assert_eq!(dir.metadata().unwrap().mode() & 0o7777, 0o710);
+        }
+        assert_eq!(archive_dir.metadata().unwrap().mode(), mode);
+    }
+
+    #[test]
+    fn test_file_archive_preserve_xattrs() {
+        let tdir = tempdir().unwrap();
+        let job_dir = tdir.path().join("job.1234");
+        create_dir(&job_dir).unwrap();
+        std::fs::write(job_dir.join("script"), b"job script").unwrap();
+        std::fs::write(job_dir.join("environment"), b"environment").unwrap();
+
+        // attributes that cannot be copied do not keep the file from being archived
+        let permissions = Permissions {
+            preserve_xattrs: true,
+            ..Default::default()
+        };
+        let gone = tdir.path().join("gone");
+        assert!(permissions
+            .create_file(&tdir.path().join("copy"), Some(&gone))
+            .is_ok());
+
+        if xattr::set(job_dir.join("script"), "user.sarchive", b"spool").is_err() {
+            // The filesystem does not support user xattrs
+            return;
+        }
+
+        let mut slurm_job_entry = SlurmJobEntry::new(&job_dir, "1234", "mycluster");
+        slurm_job_entry.read_job_info().unwrap();
+
+        let archive_dir = tdir.path().join("archive");
+        create_dir(&archive_dir).unwrap();
+        let mut file_archive = FileArchive::new(&archive_dir, &Period::None);
+        file_archive.permissions.preserve_xattrs = true;
+        file_archive
+            .archive(&JobRecord::new(&slurm_job_entry))
+            .unwrap();
+
+        assert_eq!(
+            xattr::get(archive_dir.join("job.1234_script"), "user.sarchive").unwrap(),
+            Some(b"spool".to_vec())
+        );
+        assert_eq!(
+            xattr::get(archive_dir.join("job.1234_environment"), "user.sarchive").unwrap(),
+            None
+        );
+    }
 }
@@ -0,0 +1,436 @@
+/*
+Copyright 2019-2024 Andy Georges <itkovian+sarchive@gmail.com>
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in
+all copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+use chrono::{DateTime, Local};
+use clap::{Args, ValueEnum};
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use log::{debug, error, info, warn};
+use serde_json::Value;
+use std::fs::{create_dir_all, remove_file, rename, File, OpenOptions};
+use std::io::{copy, Error, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use super::document::{
+    completion_document, document_size, job_document, tombstone_document, PayloadSizes,
+    RecordOptions,
+};
+use super::{batch_failed, check_writable, Archive};
+use crate::completion::Completion;
+use crate::identity::Identity;
+use crate::scheduler::job::{JobInfo, JobRecord};
+use crate::utils;
+
+/// Command line options for the jsonl archiver subcommand
+#[derive(Args, Debug)]
+pub struct JsonlArgs {
+    /// Directory holding the JSON lines files
+    archive: PathBuf,
+
+    #[arg(long, help = "Prefix of the file names", default_value_t = String::from("sarchive"))]
+    prefix: String,
+
+    #[arg(
+        long,
+        value_name = "BYTES",
+        help = "Start a new file once the current one reaches this size"
+    )]
+    rotate_size: Option<u64>,
+
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = Rotation::None,
+        help = "Start a new file every hour or day"
+    )]
+    rotate: Rotation,
+
+    #[arg(
+        long,
+        help = "Compress the files that are no longer written to with gzip"
+    )]
+    gzip: bool,
+}
+
+/// How often to start a new file, regardless of its size
+#[derive(Clone, Copy, ValueEnum, PartialEq, Debug, Eq)]
+pub enum Rotation {
+    Hourly,
+    Daily,
+    None,
+}
+
+impl Rotation {
+    /// Returns a key that changes when a new file should be started
+    fn key(&self, t: &DateTime<Local>) -> String {
+        match self {
+            Rotation::Hourly => t.format("%Y%m%d%H").to_string(),
+            Rotation::Daily => t.format("%Y%m%d").to_string(),
+            Rotation::None => String::new(),
+        }
+    }
+}
+
+/// The file currently written to
+struct Segment {
+    file: File,
+    opened: DateTime<Local>,
+    size: u64,
+}
+
+/// An archiver that appends a JSON document per job to a rotating file
+///
+/// The current file is named `<prefix>.jsonl`. On rotation, it is renamed to
+/// `<prefix>-<time it was started>.jsonl` (and gzipped, if requested), so tools
+/// tailing the current file pick up the new one.
+pub struct JsonlArchive {
+    archive_path: PathBuf,
+    prefix: String,
+    rotate_size: Option<u64>,
+    rotate: Rotation,
+    gzip: bool,
+    segment: Mutex<Option<Segment>>,
+    identity: Identity,
+    options: RecordOptions,
+    payload_sizes: PayloadSizes,
+}
+
+impl JsonlArchive {
+    pub fn new(archive_path: &Path, prefix: &str) -> Self {
+        JsonlArchive {
+            archive_path: archive_path.to_path_buf(),
+            prefix: prefix.to_owned(),
+            rotate_size: None,
+            rotate: Rotation::None,
+            gzip: false,
+            segment: Mutex::new(None),
+            identity: Identity::default(),
+            options: RecordOptions::default(),
+            payload_sizes: PayloadSizes::default(),
+        }
+    }
+
+    pub fn build(
+        args: &JsonlArgs,
+        identity: &Identity,
+        options: &RecordOptions,
+    ) -> Result<Self, Error> {
+        if !args.archive.is_dir() {
+            warn!(
+                "Provided archive {:?} is not a valid directory, creating it.",
+                &args.archive
+            );
+            if let Err(e) = create_dir_all(&args.archive) {
+                error!("Unable to create archive at {:?}. {}", &args.archive, e);
+                return Err(e);
+            }
+        }
+        info!(
+            "Using JSON lines archival to {:?}",
+            args.archive.join(format!("{}.jsonl", args.prefix))
+        );
+
+        let mut archive = JsonlArchive::new(&args.archive, &args.prefix);
+        archive.rotate_size = args.rotate_size;
+        archive.rotate = args.rotate;
+        archive.gzip = args.gzip;
+        archive.identity = identity.clone();
+        archive.options = options.clone();
+        Ok(archive)
+    }
+
+    fn current_path(&self) -> PathBuf {
+        self.archive_path.join(format!("{}.jsonl", self.prefix))
+    }
+
+    /// Opens the current file for appending, picking up where a previous run
+    /// left off
+    fn open(&self) -> Result<Segment, Error> {
+        let path = self.current_path();
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let size = file.metadata()?.len();
+        let opened = match utils::modified_time(&path) {
+            Some(t) if size > 0 => t.with_timezone(&Local),
+            _ => Local::now(),
+        };
+        Ok(Segment { file, opened, size })
+    }
+
+    /// Checks if the segment should be closed before writing a line of the
+    /// given length, after the pending bytes
+    fn needs_rotation(&self, segment: &Segment, pending: u64, len: u64) -> bool {
+        let size = segment.size + pending;
+        let full = self
+            .rotate_size
+            .is_some_and(|max| size > 0 && size + len > max);
+        full || self.rotate.key(&segment.opened) != self.rotate.key(&Local::now())
+    }
+
+    /// Moves the current file aside, compressing it if requested
+    fn close(&self, segment: Segment) -> Result<(), Error> {
+        segment.file.sync_all()?;
+        drop(segment.file);
+
+        let stem = format!("{}-{}", self.prefix, segment.opened.format("%Y%m%dT%H%M%S"));
+        let extension = if self.gzip { "jsonl.gz" } else { "jsonl" };
+        let mut target = self.archive_path.join(format!("{stem}.jsonl"));
+        let mut n = 0;
+        while target.exists() || target.with_extension(extension).exists() {
+            n += 1;
+            target = self.archive_path.join(format!("{stem}.{n}.jsonl"));
+        }
+        debug!("Rotating {:?} to {:?}", self.current_path(), &target);
+        rename(self.current_path(), &target)?;
+
+        if self.gzip {
+            let mut gz_target = target.clone().into_os_string();
+            gz_target.push(".gz");
+            let mut encoder = GzEncoder::new(File::create(&gz_target)?, Compression::default());
+            copy(&mut File::open(&target)?, &mut encoder)?;
+            encoder.finish()?.sync_all()?;
+            remove_file(&target)?;
+        }
+        Ok(())
+    }
+
+    /// Appends the documents, a line each, rotating the file first if needed.
+    /// The lines that go to the same file are written at once.
+    fn append(&self, docs: &[Value]) -> Result<(), Error> {
+        let mut segment = self.segment.lock().unwrap_or_else(|e| e.into_inner());
+        let mut current = match segment.take() {
+            Some(s) => s,
+            None => self.open()?,
+        };
+        let mut lines = Vec::new();
+        for doc in docs {
+            let mut line = serde_json::to_vec(doc)?;
+            line.push(b'\n');
+            if self.needs_rotation(&current, lines.len() as u64, line.len() as u64) {
+                current.file.write_all(&lines)?;
+                lines.clear();
+                self.close(current)?;
+                current = self.open()?;
+            }
+            lines.extend(line);
+        }
+        let result = current.file.write_all(&lines);
+        if result.is_ok() {
+            current.size += lines.len() as u64;
+        }
+        *segment = Some(current);
+        result
+    }
+}
+
+impl Archive for JsonlArchive {
+    fn archive(&self, job_entry: &JobRecord) -> Result<(), Error> {
+        debug!(
+            "JSON lines archiver, received an entry for job ID {}",
+            job_entry.jobid()
+        );
+        let doc = job_document(job_entry, &self.identity, &self.options);
+        self.append(std::slice::from_ref(&doc))?;
+        self.payload_sizes.sent(job_entry, document_size(&doc));
+        Ok(())
+    }
+
+    /// Writes the lines of the jobs at once
+    fn archive_batch(&self, job_entries: &[JobRecord]) -> Vec<Result<(), Error>> {
+        debug!(
+            "JSON lines archiver, received a batch of {} entries",
+            job_entries.len()
+        );
+        let docs: Vec<Value> = job_entries
+            .iter()
+            .map(|job_entry| job_document(job_entry, &self.identity, &self.options))
+            .collect();
+        match self.append(&docs) {
+            Ok(()) => job_entries
+                .iter()
+                .zip(docs.iter())
+                .map(|(job_entry, doc)| {
+                    self.payload_sizes.sent(job_entry, document_size(doc));
+                    Ok(())
+                })
+                .collect(),
+            Err(e) => batch_failed(e, job_entries.len()),
+        }
+    }
+
+    fn payload_size(&self, job_entry: &JobRecord) -> u64 {
+        self.payload_sizes.take(job_entry)
+    }
+
+    fn archive_tombstone(&self, job_entry: &JobRecord) -> Result<(), Error> {
+        debug!(
+            "JSON lines archiver, received a tombstone for job ID {}",
+            job_entry.jobid()
+        );
+        self.append(&[tombstone_document(job_entry, &self.identity)])
+    }
+
+    fn archive_completion(&self, completion: &Completion) -> Result<(), Error> {
+        debug!(
+            "JSON lines archiver, received the completion of job ID {}",
+            completion.jobid
+        );
+        self.append(&[completion_document(completion, &self.identity)])
+    }
+
+    fn check(&self, _cluster: &str) -> Result<(), Error> {
+        check_writable(&self.archive_path)
+    }
+
+    fn name(&self) -> &str {
+        "jsonl"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::scheduler::slurm::SlurmJobEntry;
+    use flate2::read::GzDecoder;
+    use std::env::current_dir;
+    use std::fs::{read_dir, read_to_string};
+    use std::io::Read;
+    use tempfile::tempdir;
+
+    fn job_entry() -> JobRecord {
+        let path = current_dir().unwrap().join("tests/job.123456");
+        let mut entry = SlurmJobEntry::new(&path, "123456", "mycluster");
+        entry.read_job_info().unwrap();
+        JobRecord::new(&entry)
+    }
+
+    fn segments(dir: &Path) -> Vec<String> {
+        let mut names: Vec<String> = read_dir(dir)
+            .unwrap()
+            .map(|e| e.unwrap().file_name().to_string_lossy().to_string())
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn test_archive() {
+        let tdir = tempdir().unwrap();
+        let archive = JsonlArchive::new(tdir.path(), "jobs");
+        let entry = job_entry();
+
+        archive.archive(&entry).unwrap();
+        archive.archive_tombstone(&entry).unwrap();
+
+        let contents = read_to_string(tdir.path().join("jobs.jsonl")).unwrap();
+        let lines: Vec<Value> = contents
+            .lines()
+            .map(|l| serde_json::from_str(l).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["id"], "123456");
+        assert_eq!(lines[0]["cluster"], "mycluster");
+        assert_eq!(lines[0]["script"], entry.script());
+        assert_eq!(lines[0]["partial"], false);
+        assert_eq!(lines[1]["event"], "cancelled_before_capture");
+        assert_eq!(lines[0]["idempotency_key"], lines[1]["idempotency_key"]);
+    }
+
+    #[test]
+    fn test_archive_batch() {
+        let tdir = tempdir().unwrap();
+        let mut archive = JsonlArchive::new(tdir.path(), "jobs");
+        archive.rotate_size = Some(1);
+        let entries = vec![job_entry(), job_entry(), job_entry()];
+
+        let outcomes = archive.archive_batch(&entries);
+        assert_eq!(outcomes.len(), 3);
+        assert!(outcomes.iter().all(|outcome| outcome.is_ok()));
+
+        // every line still gets a segment of its own
+        assert_eq!(segments(tdir.path()).len(), 3);
+        let contents = read_to_string(tdir.path().join("jobs.jsonl")).unwrap();
+        assert_eq!(contents.lines().count(), 1);
+    }
+
+    #[test]
+    fn test_rotate_size() {
+        let tdir = tempdir().unwrap();
+        let mut archive = JsonlArchive::new(tdir.path(), "jobs");
+        archive.rotate_size = Some(1);
+        let entry = job_entry();
+
+        for _ in 0..3 {
+            archive.archive(&entry).unwrap();
+        }
+
+        let names = segments(tdir.path());
+        assert_eq!(names.len(), 3);
+        assert_eq!(names[2], "jobs.jsonl");
+        assert!(names[0].starts_with("jobs-") && names[0].ends_with(".jsonl"));
+        for name in names {
+            let contents = read_to_string(tdir.path().join(name)).unwrap();
+            assert_eq!(contents.lines().count(), 1);
+        }
+    }
+
+    #[test]
+    fn test_rotate_time_gzip() {
+        let tdir = tempdir().unwrap();
+        let mut archive = JsonlArchive::new(tdir.path(), "jobs");
+        archive.rotate = Rotation::Daily;
+        archive.gzip = true;
+        let entry = job_entry();
+
+        archive.archive(&entry).unwrap();
+        let opened = Local::now() - chrono::Duration::days(1);
+        archive.segment.lock().unwrap().as_mut().unwrap().opened = opened;
+        archive.archive(&entry).unwrap();
+
+        let names = segments(tdir.path());
+        let gz_name = format!("jobs-{}.jsonl.gz", opened.format("%Y%m%dT%H%M%S"));
+        assert_eq!(names, vec![gz_name.clone(), "jobs.jsonl".to_owned()]);
+
+        let mut contents = String::new();
+        GzDecoder::new(File::open(tdir.path().join(gz_name)).unwrap())
+            .read_to_string(&mut contents)
+            .unwrap();
+        let doc: Value = serde_json::from_str(contents.trim_end()).unwrap();
+        assert_eq!(doc["id"], "123456");
+    }
+
+    #[test]
+    fn test_reopen() {
+        let tdir = tempdir().unwrap();
+        let entry = job_entry();
+
+        JsonlArchive::new(tdir.path(), "jobs")
+            .archive(&entry)
+            .unwrap();
+        JsonlArchive::new(tdir.path(), "jobs")
+            .archive(&entry)
+            .unwrap();
+
+        let contents = read_to_string(tdir.path().join("jobs.jsonl")).unwrap();
+        assert_eq!(contents.lines().count(), 2);
+    }
+}
@@ -20,19 +20,41 @@ OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
 SOFTWARE.
 */
 
-use super::Archive;
-use crate::scheduler::job::JobInfo;
+use super::dedup::{content_hash, hex, idempotency_key, parse_hex, ScriptCache};
+use super::document::{
+    accelerators, completion_document, completion_key, environment, normalized_script,
+    PayloadSizes, RecordOptions,
+};
+use super::{Archive, CLUSTER_PLACEHOLDER};
+use crate::completion::Completion;
+use crate::identity::Identity;
+use crate::scheduler::job::{JobInfo, JobRecord};
 use chrono::{DateTime, Utc};
 use clap::{Args, ValueEnum};
+use ed25519_dalek::{Signer, SigningKey};
 use enum_display_derive::Display;
 use itertools::Itertools;
-use log::{debug, info};
+use log::{debug, error, info, warn};
 use rdkafka::config::ClientConfig;
-use rdkafka::producer::{BaseRecord, DefaultProducerContext, ThreadedProducer};
+use rdkafka::message::{Header, OwnedHeaders};
+use rdkafka::producer::{BaseRecord, DeliveryResult, Producer, ProducerContext, ThreadedProducer};
+use rdkafka::ClientContext;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::BTreeMap;
 use std::fmt::Display;
+use std::fs::read_to_string;
 use std::io::{Error, ErrorKind};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering::SeqCst};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// How long to wait for the brokers when checking the connection
+const CHECK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Room left in each message for the key, the headers and the protocol overhead
+const MESSAGE_OVERHEAD: usize = 1024;
 
 /// Command line options for the kafka archiver subcommand
 #[derive(Args, Debug)]
@@ -40,13 +62,17 @@ pub struct KafkaArgs {
     #[arg(long, help = "Comma-separated list of brokers")]
     brokers: String,
 
-    #[arg(long, help = "Topic under which to send messages to Kafka", default_value_t = String::from("sarchive"))]
+    #[arg(
+        long,
+        help = "Topic under which to send messages to Kafka, {cluster} is replaced by the job's cluster",
+        default_value_t = String::from("sarchive")
+    )]
     topic: String,
 
     #[arg(long, help = "Message timeout in ms", default_value_t = String::from("5000"))]
     message_timeout: String,
 
-    #[arg(long, help = "Protocol used to communicate with Kafka", default_value_t = SecurityProtocol::Plaintext)]
+    #[arg(long, value_enum, help = "Protocol used to communicate with Kafka", default_value_t = SecurityProtocol::Plaintext)]
     security_protocol: SecurityProtocol,
 
     #[arg(long, help = "SSL options for the underlying Kafka lib")]
@@ -54,6 +80,68 @@ pub struct KafkaArgs {
 
     #[arg(long, help = "SASL options for the underlying Kafka lib")]
     sasl: Option<String>,
+
+    #[arg(
+        long,
+        help = "Include a SHA-256 hash of the job script in each message"
+    )]
+    content_hash: bool,
+
+    #[arg(
+        long,
+        value_name = "SECONDS",
+        help = "Only send the script hash for scripts already delivered within this many seconds (implies --content-hash)"
+    )]
+    dedup_window: Option<u64>,
+
+    #[arg(long, value_enum, help = "Compression codec for the messages", default_value_t = Compression::None)]
+    compression: Compression,
+
+    #[arg(
+        long,
+        value_name = "MS",
+        help = "Time to wait for more messages before sending a batch"
+    )]
+    linger_ms: Option<u64>,
+
+    #[arg(
+        long,
+        value_name = "BYTES",
+        help = "Maximum size of a batch of messages"
+    )]
+    batch_size: Option<u64>,
+
+    #[arg(
+        long = "kafka-property",
+        value_name = "KEY=VALUE",
+        value_parser = parse_property,
+        help = "Set a property of the underlying Kafka lib, overriding other options (can be repeated)"
+    )]
+    properties: Vec<(String, String)>,
+
+    #[arg(
+        long,
+        value_name = "BYTES",
+        help = "Maximum size of a message, larger messages are split in parts",
+        default_value_t = 1000000,
+        value_parser = clap::value_parser!(u64).range(4096..)
+    )]
+    max_message_bytes: u64,
+
+    #[arg(
+        long,
+        value_name = "FILE",
+        help = "Sign every message with the ed25519 private key in this file (32 bytes, hex encoded)"
+    )]
+    signing_key: Option<PathBuf>,
+}
+
+/// Parses a `key=value` pair, splitting on the first '='
+fn parse_property(s: &str) -> Result<(String, String), String> {
+    match s.split_once('=') {
+        Some((key, value)) if !key.is_empty() => Ok((key.to_owned(), value.to_owned())),
+        _ => Err(format!("{s} is not of the form key=value")),
+    }
 }
 
 #[allow(non_camel_case_types)]
@@ -65,9 +153,120 @@ pub enum SecurityProtocol {
     Sasl_ssl,
 }
 
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Display, ValueEnum, Debug)]
+pub enum Compression {
+    None,
+    Gzip,
+    Snappy,
+    Lz4,
+    Zstd,
+}
+
+/// Counts the messages the brokers did not accept, so a flush can report them
+#[derive(Default)]
+struct DeliveryContext {
+    failed: AtomicU64,
+}
+
+impl ClientContext for DeliveryContext {}
+
+impl ProducerContext for DeliveryContext {
+    type DeliveryOpaque = Box<Option<Arc<ScriptDelivery>>>;
+
+    fn delivery(&self, result: &DeliveryResult<'_>, script: Self::DeliveryOpaque) {
+        if let Err((e, _)) = result {
+            error!("Kafka did not accept a message: {}", e);
+            self.failed.fetch_add(1, SeqCst);
+        }
+        if let Some(script) = script.as_ref() {
+            script.delivered(result.is_ok());
+        }
+    }
+}
+
+/// A script sent in full, which goes in the deduplication cache once every
+/// part of its message was delivered. Until then, or when a part was not,
+/// the next job with the script sends it in full as well.
+struct ScriptDelivery {
+    cache: Arc<Mutex<ScriptCache>>,
+    hash: String,
+    /// Parts of the message whose delivery was not reported yet
+    pending: AtomicUsize,
+    failed: AtomicBool,
+}
+
+impl ScriptDelivery {
+    fn delivered(&self, ok: bool) {
+        if !ok {
+            self.failed.store(true, SeqCst);
+        }
+        if self.pending.fetch_sub(1, SeqCst) == 1 && !self.failed.load(SeqCst) {
+            self.cache
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .insert(&self.hash);
+        }
+    }
+}
+
 pub struct KafkaArchive {
-    producer: ThreadedProducer<DefaultProducerContext>,
+    producer: ThreadedProducer<DeliveryContext>,
     topic: String,
+    content_hash: bool,
+    dedup: Option<Arc<Mutex<ScriptCache>>>,
+    identity: Identity,
+    options: RecordOptions,
+    /// Largest payload sent in a single message
+    max_payload: usize,
+    signer: Option<MessageSigner>,
+    /// Size of the message produced for each job, until it is reported
+    payload_sizes: PayloadSizes,
+}
+
+/// Signs the messages with an ed25519 key, so consumers holding the public
+/// key can verify they were sent by sarchive and not altered on the way
+struct MessageSigner {
+    key: SigningKey,
+    /// Identifies the public key, so consumers can tell keys apart when
+    /// they are rotated
+    key_id: String,
+}
+
+impl MessageSigner {
+    fn new(seed: [u8; 32]) -> Self {
+        let key = SigningKey::from_bytes(&seed);
+        let key_id = content_hash(key.verifying_key().as_bytes())[..16].to_owned();
+        MessageSigner { key, key_id }
+    }
+
+    /// Reads the hex encoded private key from the given file
+    fn load(path: &Path) -> Result<Self, Error> {
+        if path.metadata()?.mode() & 0o077 != 0 {
+            warn!(
+                "Signing key {:?} is readable by others than its owner",
+                path
+            );
+        }
+        let seed = parse_hex(read_to_string(path)?.trim())
+            .and_then(|bytes| <[u8; 32]>::try_from(bytes).ok())
+            .ok_or_else(|| {
+                Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("Signing key {path:?} does not hold 32 hex encoded bytes"),
+                )
+            })?;
+        Ok(MessageSigner::new(seed))
+    }
+
+    /// Returns the hex encoded public key, to hand to the consumers
+    fn public_key(&self) -> String {
+        hex(self.key.verifying_key().as_bytes())
+    }
+
+    /// Returns the hex encoded signature of the payload
+    fn sign(&self, payload: &[u8]) -> String {
+        hex(&self.key.sign(payload).to_bytes())
+    }
 }
 
 impl KafkaArchive {
@@ -91,12 +290,25 @@ impl KafkaArchive {
     /// Panics if there is an error creating the Kafka producer.
     pub fn new(
         brokers: &String,
-        topic: &String,
+        topic: &str,
         message_timeout: &String,
         security_protocol: &SecurityProtocol,
         ssl: &Option<Vec<(&str, &str)>>,
         sasl: &Option<Vec<(&str, &str)>>,
     ) -> Self {
+        let config =
+            KafkaArchive::client_config(brokers, message_timeout, security_protocol, ssl, sasl);
+        KafkaArchive::with_config(&config, topic).expect("Cannot create Kafka producer. Aborting.")
+    }
+
+    /// Returns the client configuration for the given options
+    fn client_config(
+        brokers: &String,
+        message_timeout: &String,
+        security_protocol: &SecurityProtocol,
+        ssl: &Option<Vec<(&str, &str)>>,
+        sasl: &Option<Vec<(&str, &str)>>,
+    ) -> ClientConfig {
         let mut p = ClientConfig::new()
             .set("bootstrap.servers", brokers)
             .set("message.timeout.ms", message_timeout)
@@ -111,34 +323,62 @@ impl KafkaArchive {
 
         if let Some(ssl) = ssl {
             for (k, v) in ssl.iter() {
-                debug!("Setting kafka ssl property {k} with value {v}");
+                debug!("Setting kafka ssl property {k}");
                 p.set(*k, *v);
             }
         }
 
         if let Some(sasl) = sasl {
             for (k, v) in sasl.iter() {
-                debug!("Setting kafka sasl property {k} with value {v}");
+                debug!("Setting kafka sasl property {k}");
                 p.set(*k, *v);
             }
         }
 
-        KafkaArchive {
-            producer: p.create().expect("Cannot create Kafka producer. Aborting."),
+        p
+    }
+
+    /// Creates the producer for the given configuration
+    fn with_config(config: &ClientConfig, topic: &str) -> Result<Self, Error> {
+        Ok(KafkaArchive {
+            producer: config
+                .create_with_context(DeliveryContext::default())
+                .map_err(|e| {
+                    Error::new(
+                        ErrorKind::InvalidInput,
+                        format!("Cannot create Kafka producer: {e}"),
+                    )
+                })?,
             topic: topic.to_owned(),
-        }
+            content_hash: false,
+            dedup: None,
+            identity: Identity::default(),
+            options: RecordOptions::default(),
+            max_payload: 1000000 - MESSAGE_OVERHEAD,
+            signer: None,
+            payload_sizes: PayloadSizes::default(),
+        })
     }
 
     /// Builds a `KafkaArchive` instance based on the provided `KafkaArgs`.
     ///
+    /// The topic may contain `{cluster}`, which is replaced by the cluster of
+    /// each job, so jobs from different clusters end up in different topics.
+    ///
     /// # Arguments
     ///
     /// * `args` - A reference to the `KafkaArgs` struct containing Kafka configuration.
+    /// * `identity` - The identity of this instance, added to every message.
+    /// * `options` - The optional fields to add to the job messages.
     ///
     /// # Returns
     ///
     /// A `Result` containing the created `KafkaArchive` instance or an error.
-    pub fn build(args: &KafkaArgs) -> Result<Self, Error> {
+    pub fn build(
+        args: &KafkaArgs,
+        identity: &Identity,
+        options: &RecordOptions,
+    ) -> Result<Self, Error> {
         info!(
             "Using Kafka archival, talking to {} on topic {} using protocol {}",
             args.brokers, args.topic, args.security_protocol
@@ -154,17 +394,64 @@ impl KafkaArchive {
             .as_ref()
             .map(|s| s.split(',').flat_map(|s| s.split('=')).tuples().collect());
 
-        debug!("Using ssl options {ssl:?}");
-        debug!("Using sasl options {sasl:?}");
+        if args
+            .topic
+            .replace(CLUSTER_PLACEHOLDER, "")
+            .contains(['{', '}'])
+        {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "Unsupported placeholder in topic {}, only {} is allowed",
+                    args.topic, CLUSTER_PLACEHOLDER
+                ),
+            ));
+        }
 
-        Ok(KafkaArchive::new(
+        let mut config = KafkaArchive::client_config(
             &args.brokers,
-            &args.topic,
             &args.message_timeout,
             &args.security_protocol,
             &ssl,
             &sasl,
-        ))
+        );
+        config.set(
+            "compression.codec",
+            args.compression.to_string().to_lowercase(),
+        );
+        if let Some(linger_ms) = args.linger_ms {
+            config.set("linger.ms", linger_ms.to_string());
+        }
+        if let Some(batch_size) = args.batch_size {
+            config.set("batch.size", batch_size.to_string());
+        }
+        config.set("message.max.bytes", args.max_message_bytes.to_string());
+        for (key, value) in args.properties.iter() {
+            // the values may be credentials, so only the keys are logged
+            debug!("Setting kafka property {key}");
+            config.set(key, value);
+        }
+
+        let mut archive = KafkaArchive::with_config(&config, &args.topic)?;
+        archive.identity = identity.clone();
+        archive.options = options.clone();
+        archive.max_payload = args.max_message_bytes as usize - MESSAGE_OVERHEAD;
+        archive.content_hash = args.content_hash || args.dedup_window.is_some();
+        archive.dedup = args.dedup_window.map(|w| {
+            info!("Deduplicating job scripts within a window of {w}s");
+            Arc::new(Mutex::new(ScriptCache::new(Duration::from_secs(w))))
+        });
+        if let Some(path) = &args.signing_key {
+            let signer = MessageSigner::load(path)?;
+            info!(
+                "Signing messages with key {}, public key {}",
+                signer.key_id,
+                signer.public_key()
+            );
+            archive.signer = Some(signer);
+        }
+
+        Ok(archive)
     }
 }
 
@@ -172,42 +459,282 @@ impl KafkaArchive {
 #[derive(Serialize, Deserialize)]
 struct JobMessage {
     pub id: String,
+    /// Identifies the job across replays, also used as the message key
+    pub idempotency_key: String,
+    /// Time of archival
     pub timestamp: DateTime<Utc>,
+    /// Time the job was first seen in the spool
+    pub event_time: DateTime<Utc>,
     pub cluster: String,
-    pub script: String,
-    pub environment: Option<HashMap<String, String>>,
+    pub job_name: Option<String>,
+    pub user: Option<String>,
+    pub partition: Option<String>,
+    /// GPUs the job requests, null when none
+    pub accelerators: serde_json::Value,
+    /// Left out when the same script was sent recently, see `script_hash`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub script: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub script_hash: Option<String>,
+    /// Script without comments and layout, see `--normalize-script`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub script_normalized: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub script_normalized_hash: Option<String>,
+    /// Structured as requested with `--structured-environment`
+    pub environment: serde_json::Value,
+    pub partial: bool,
+    pub host: String,
+    pub sarchive_version: String,
+    pub instance_id: String,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub labels: BTreeMap<String, String>,
+}
+
+/// Record sent for a job that vanished before its information could be read,
+/// whose user opted out of archival, or whose files were deleted
+#[cfg(feature = "kafka")]
+#[derive(Serialize, Deserialize)]
+struct TombstoneMessage {
+    pub id: String,
+    pub idempotency_key: String,
+    pub timestamp: DateTime<Utc>,
+    pub event_time: DateTime<Utc>,
+    pub cluster: String,
+    pub event: String,
+    pub host: String,
+    pub sarchive_version: String,
+    pub instance_id: String,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub labels: BTreeMap<String, String>,
+}
+
+impl TombstoneMessage {
+    fn new(job_entry: &dyn JobInfo, identity: &Identity) -> Self {
+        TombstoneMessage {
+            id: job_entry.jobid(),
+            idempotency_key: idempotency_key(job_entry),
+            timestamp: Utc::now(),
+            event_time: job_entry.event_time(),
+            cluster: job_entry.cluster(),
+            event: job_entry.tombstone_event(),
+            host: identity.hostname.clone(),
+            sarchive_version: identity.version.clone(),
+            instance_id: identity.instance_id.clone(),
+            labels: identity.labels.clone(),
+        }
+    }
+}
+
+impl KafkaArchive {
+    /// Returns the script to send along with its hash (if requested). The script
+    /// is left out if it was delivered within the deduplication window.
+    fn script_and_hash(&self, script: String) -> (Option<String>, Option<String>) {
+        if !self.content_hash {
+            return (Some(script), None);
+        }
+        let hash = content_hash(script.as_bytes());
+        match &self.dedup {
+            Some(cache)
+                if cache
+                    .lock()
+                    .unwrap_or_else(|e| e.into_inner())
+                    .contains(&hash) =>
+            {
+                debug!("Script with hash {} was sent recently", hash);
+                (None, Some(hash))
+            }
+            _ => (Some(script), Some(hash)),
+        }
+    }
+
+    /// Returns what records the script with the given hash as sent once the
+    /// given number of parts were delivered, if scripts are deduplicated
+    fn script_delivery(
+        &self,
+        script_hash: Option<&str>,
+        parts: usize,
+    ) -> Option<Arc<ScriptDelivery>> {
+        let (Some(cache), Some(hash)) = (&self.dedup, script_hash) else {
+            return None;
+        };
+        Some(Arc::new(ScriptDelivery {
+            cache: Arc::clone(cache),
+            hash: hash.to_owned(),
+            pending: AtomicUsize::new(parts),
+            failed: AtomicBool::new(false),
+        }))
+    }
+
+    /// Returns the topic for jobs of the given cluster
+    fn topic(&self, cluster: &str) -> String {
+        self.topic.replace(CLUSTER_PLACEHOLDER, cluster)
+    }
+
+    /// Adds the signature of the payload and the ID of the signing key to the
+    /// headers, if messages are signed
+    fn signed(&self, headers: OwnedHeaders, signature: &Option<String>) -> OwnedHeaders {
+        match (&self.signer, signature) {
+            (Some(signer), Some(signature)) => headers
+                .insert(Header {
+                    key: "sarchive_key_id",
+                    value: Some(&signer.key_id),
+                })
+                .insert(Header {
+                    key: "sarchive_signature",
+                    value: Some(signature),
+                }),
+            _ => headers,
+        }
+    }
+
+    /// Sends a message, split in parts when it exceeds the maximum payload.
+    /// The signature, if any, covers the whole payload, and goes along with
+    /// every part. A message or part the producer does not take is an error;
+    /// the parts produced before it are not taken back, so a retry sends the
+    /// whole message again.
+    fn produce(
+        &self,
+        topic: &str,
+        key: &str,
+        serial: &str,
+        script_hash: Option<&str>,
+    ) -> Result<(), Error> {
+        let signature = self.signer.as_ref().map(|s| s.sign(serial.as_bytes()));
+        if serial.len() <= self.max_payload {
+            return match self.producer.send::<str, str>(
+                BaseRecord::with_opaque_to(topic, Box::new(self.script_delivery(script_hash, 1)))
+                    .key(key)
+                    .payload(serial)
+                    .headers(self.signed(OwnedHeaders::new(), &signature)),
+            ) {
+                Ok(_) => {
+                    debug!("Message produced correctly");
+                    Ok(())
+                }
+                Err((e, _)) => {
+                    error!("Could not produce message with key {}: {}", key, e);
+                    Err(Error::other(format!("Cannot produce message: {e}")))
+                }
+            };
+        }
+
+        // All parts share the key, so they land in the same partition in order
+        let parts = split_payload(serial.as_bytes(), self.max_payload);
+        info!(
+            "Message with key {} is {} bytes, sending it in {} parts",
+            key,
+            serial.len(),
+            parts.len()
+        );
+        let chunk_id = content_hash(serial.as_bytes());
+        let delivery = self.script_delivery(script_hash, parts.len());
+        for (part, payload) in parts.iter().enumerate() {
+            let headers = self.signed(part_headers(&chunk_id, part + 1, parts.len()), &signature);
+            match self.producer.send::<str, [u8]>(
+                BaseRecord::with_opaque_to(topic, Box::new(delivery.clone()))
+                    .key(key)
+                    .payload(*payload)
+                    .headers(headers),
+            ) {
+                Ok(_) => debug!("Part {} of {} produced correctly", part + 1, parts.len()),
+                Err((e, _)) => {
+                    error!(
+                        "Could not produce part {} of {} of message with key {}: {}",
+                        part + 1,
+                        parts.len(),
+                        key,
+                        e
+                    );
+                    if part > 0 {
+                        warn!(
+                            "Message with key {} was sent in part: {} of {} parts went out, \
+                             which consumers cannot join until it is sent again",
+                            key,
+                            part,
+                            parts.len()
+                        );
+                    }
+                    return Err(Error::other(format!(
+                        "Cannot produce part {} of {}: {e}",
+                        part + 1,
+                        parts.len()
+                    )));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Splits a payload that does not fit a single message in parts of at most
+/// `max` bytes. Parts need not end on a character boundary, consumers join
+/// the bytes before decoding them.
+fn split_payload(payload: &[u8], max: usize) -> Vec<&[u8]> {
+    payload.chunks(max).collect()
+}
+
+/// Returns the headers identifying a part of a split message: the hash of the
+/// whole payload, the (1-based) number of the part and the number of parts.
+fn part_headers(chunk_id: &str, part: usize, parts: usize) -> OwnedHeaders {
+    OwnedHeaders::new_with_capacity(3)
+        .insert(Header {
+            key: "sarchive_chunk_id",
+            value: Some(chunk_id),
+        })
+        .insert(Header {
+            key: "sarchive_part",
+            value: Some(&part.to_string()),
+        })
+        .insert(Header {
+            key: "sarchive_parts",
+            value: Some(&parts.to_string()),
+        })
 }
 
 impl Archive for KafkaArchive {
-    fn archive(&self, job_entry: &Box<dyn JobInfo>) -> Result<(), Error> {
+    fn archive(&self, job_entry: &JobRecord) -> Result<(), Error> {
         debug!(
             "Kafka archiver, received an entry for job ID {}",
             job_entry.jobid()
         );
 
+        let (script, script_hash) = self.script_and_hash(job_entry.script());
+        let (script_normalized, script_normalized_hash) =
+            normalized_script(job_entry, &self.options).unzip();
         let doc = JobMessage {
             id: job_entry.jobid(),
+            idempotency_key: idempotency_key(job_entry),
             timestamp: Utc::now(),
+            event_time: job_entry.event_time(),
             cluster: job_entry.cluster(),
-            script: job_entry.script(),
-            environment: job_entry.extra_info(),
+            job_name: job_entry.job_name(),
+            user: job_entry.user(),
+            partition: job_entry.partition(),
+            accelerators: accelerators(job_entry),
+            script,
+            script_hash,
+            script_normalized,
+            script_normalized_hash,
+            environment: environment(job_entry, &self.options),
+            partial: !job_entry.missing_files().is_empty(),
+            host: self.identity.hostname.clone(),
+            sarchive_version: self.identity.version.clone(),
+            instance_id: self.identity.instance_id.clone(),
+            labels: self.identity.labels.clone(),
         };
 
         if let Ok(serial) = serde_json::to_string(&doc) {
             debug!("Serialisation succeeded");
-            match self
-                .producer
-                .send::<str, str>(BaseRecord::to(&self.topic).payload(&serial))
-            {
-                Ok(_) => {
-                    debug!("Message produced correctly");
-                    Ok(())
-                }
-                Err((_e, _)) => {
-                    debug!("Could not produce job entry");
-                    Ok(())
-                }
-            }
+            // a script that was left out need not be recorded again
+            self.produce(
+                &self.topic(&job_entry.cluster()),
+                &doc.idempotency_key,
+                &serial,
+                doc.script.as_ref().and(doc.script_hash.as_deref()),
+            )?;
+            self.payload_sizes.sent(job_entry, serial.len() as u64);
+            Ok(())
         } else {
             Err(Error::new(
                 ErrorKind::InvalidData,
@@ -215,6 +742,92 @@ impl Archive for KafkaArchive {
             ))
         }
     }
+
+    /// Reports the size of the message produced for the job, which is only
+    /// known once produced, as the script may have been left out
+    fn payload_size(&self, job_entry: &JobRecord) -> u64 {
+        self.payload_sizes.take(job_entry)
+    }
+
+    fn archive_tombstone(&self, job_entry: &JobRecord) -> Result<(), Error> {
+        debug!(
+            "Kafka archiver, received a tombstone for job ID {}",
+            job_entry.jobid()
+        );
+
+        let tombstone = TombstoneMessage::new(job_entry, &self.identity);
+        let serial = serde_json::to_string(&tombstone)
+            .map_err(|_| Error::new(ErrorKind::InvalidData, "Cannot convert tombstone to JSON"))?;
+        self.produce(
+            &self.topic(&job_entry.cluster()),
+            &tombstone.idempotency_key,
+            &serial,
+            None,
+        )
+    }
+
+    fn archive_completion(&self, completion: &Completion) -> Result<(), Error> {
+        debug!(
+            "Kafka archiver, received the completion of job ID {}",
+            completion.jobid
+        );
+
+        let doc = completion_document(completion, &self.identity);
+        self.produce(
+            &self.topic(&completion.cluster),
+            &completion_key(completion),
+            &doc.to_string(),
+            None,
+        )
+    }
+
+    /// Checks that the brokers answer a metadata request for the topic of
+    /// the cluster. A topic that does not exist yet is only reported, as the
+    /// brokers may create it on first use.
+    fn check(&self, cluster: &str) -> Result<(), Error> {
+        let topic = self.topic(cluster);
+        let metadata = self
+            .producer
+            .client()
+            .fetch_metadata(Some(&topic), CHECK_TIMEOUT)
+            .map_err(|e| {
+                Error::new(
+                    ErrorKind::ConnectionRefused,
+                    format!("Cannot fetch metadata from the Kafka brokers: {e}"),
+                )
+            })?;
+        info!(
+            "Kafka brokers answered, {} brokers",
+            metadata.brokers().len()
+        );
+        for t in metadata.topics() {
+            if let Some(e) = t.error() {
+                warn!("Kafka topic {} is not available: {:?}", t.name(), e);
+            }
+        }
+        Ok(())
+    }
+
+    /// Waits for the brokers to acknowledge the messages sent so far, failing
+    /// if any message was not accepted since the previous flush
+    fn flush(&self, timeout: Duration) -> Result<(), Error> {
+        self.producer.flush(timeout).map_err(|e| {
+            Error::new(
+                ErrorKind::TimedOut,
+                format!("Kafka did not confirm the delivery of all messages: {e}"),
+            )
+        })?;
+        match self.producer.context().failed.swap(0, SeqCst) {
+            0 => Ok(()),
+            failed => Err(Error::other(format!(
+                "Kafka did not accept {failed} messages"
+            ))),
+        }
+    }
+
+    fn name(&self) -> &str {
+        "kafka"
+    }
 }
 
 #[cfg(feature = "kafka")]
@@ -239,6 +852,10 @@ mod tests {
             std::time::Instant::now()
         }
 
+        fn event_time(&self) -> DateTime<Utc> {
+            "2024-01-01T12:00:00Z".parse().unwrap()
+        }
+
         fn cluster(&self) -> String {
             "test_cluster".to_string()
         }
@@ -309,11 +926,260 @@ mod tests {
             security_protocol,
             ssl,
             sasl,
+            content_hash: false,
+            dedup_window: None,
+            compression: Compression::Zstd,
+            linger_ms: Some(100),
+            batch_size: Some(1_000_000),
+            properties: vec![("acks".to_string(), "all".to_string())],
+            max_message_bytes: 1_000_000,
+            signing_key: None,
         };
 
-        let kafka_archive = KafkaArchive::build(&kafka_args).unwrap();
+        let kafka_archive =
+            KafkaArchive::build(&kafka_args, &Identity::default(), &RecordOptions::default())
+                .unwrap();
 
         // Assert that the KafkaArchive was built successfully
         assert_eq!(kafka_archive.topic, topic);
     }
+
+    #[test]
+    fn test_tombstone_message() {
+        let identity = Identity::new(Some("ctl1".to_owned()), "");
+        let tombstone = TombstoneMessage::new(&DummyJobInfo, &identity);
+        let json: serde_json::Value =
+            serde_json::from_str(&serde_json::to_string(&tombstone).unwrap()).unwrap();
+
+        assert_eq!(json["id"], "123");
+        assert_eq!(json["cluster"], "test_cluster");
+        assert_eq!(json["event"], "cancelled_before_capture");
+        assert_eq!(json["instance_id"], "ctl1");
+        assert_eq!(json["event_time"], "2024-01-01T12:00:00Z");
+        assert_eq!(json["idempotency_key"], idempotency_key(&DummyJobInfo));
+        assert_eq!(json["sarchive_version"], env!("CARGO_PKG_VERSION"));
+    }
+
+    #[test]
+    fn test_script_and_hash() {
+        let mut kafka_archive = KafkaArchive::new(
+            &"localhost:9092".to_string(),
+            "test_topic",
+            &"5000".to_string(),
+            &SecurityProtocol::Plaintext,
+            &None,
+            &None,
+        );
+        let script = DummyJobInfo.script();
+        assert_eq!(
+            kafka_archive.script_and_hash(script.clone()),
+            (Some(script.clone()), None)
+        );
+
+        kafka_archive.content_hash = true;
+        let cache = Arc::new(Mutex::new(ScriptCache::new(Duration::from_secs(60))));
+        kafka_archive.dedup = Some(Arc::clone(&cache));
+        let hash = Some(content_hash(script.as_bytes()));
+        assert_eq!(
+            kafka_archive.script_and_hash(script.clone()),
+            (Some(script.clone()), hash.clone())
+        );
+        // the script is only left out once it was delivered
+        assert_eq!(
+            kafka_archive.script_and_hash(script.clone()),
+            (Some(script.clone()), hash.clone())
+        );
+        cache.lock().unwrap().insert(hash.as_deref().unwrap());
+        assert_eq!(kafka_archive.script_and_hash(script), (None, hash));
+    }
+
+    #[test]
+    fn test_script_delivery() {
+        let mut kafka_archive = KafkaArchive::new(
+            &"localhost:9092".to_string(),
+            "test_topic",
+            &"5000".to_string(),
+            &SecurityProtocol::Plaintext,
+            &None,
+            &None,
+        );
+        assert!(kafka_archive.script_delivery(Some("abc"), 1).is_none());
+
+        let cache = Arc::new(Mutex::new(ScriptCache::new(Duration::from_secs(60))));
+        kafka_archive.dedup = Some(Arc::clone(&cache));
+        assert!(kafka_archive.script_delivery(None, 1).is_none());
+
+        // the script is recorded once all parts were delivered
+        let delivery = kafka_archive.script_delivery(Some("abc"), 2).unwrap();
+        delivery.delivered(true);
+        assert!(!cache.lock().unwrap().contains("abc"));
+        delivery.delivered(true);
+        assert!(cache.lock().unwrap().contains("abc"));
+
+        // and not when one of them was not
+        let delivery = kafka_archive.script_delivery(Some("def"), 2).unwrap();
+        delivery.delivered(false);
+        delivery.delivered(true);
+        assert!(!cache.lock().unwrap().contains("def"));
+    }
+
+    #[test]
+    fn test_topic_template() {
+        let mut kafka_args = KafkaArgs {
+            brokers: "localhost:9092".to_string(),
+            topic: "sarchive.{cluster}".to_string(),
+            message_timeout: "5000".to_string(),
+            security_protocol: SecurityProtocol::Plaintext,
+            ssl: None,
+            sasl: None,
+            content_hash: false,
+            dedup_window: None,
+            compression: Compression::Zstd,
+            linger_ms: Some(100),
+            batch_size: Some(1_000_000),
+            properties: vec![("acks".to_string(), "all".to_string())],
+            max_message_bytes: 1_000_000,
+            signing_key: None,
+        };
+
+        let kafka_archive =
+            KafkaArchive::build(&kafka_args, &Identity::default(), &RecordOptions::default())
+                .unwrap();
+        assert_eq!(
+            kafka_archive.topic(&DummyJobInfo.cluster()),
+            "sarchive.test_cluster"
+        );
+
+        kafka_args.topic = "sarchive.{partition}".to_string();
+        assert!(
+            KafkaArchive::build(&kafka_args, &Identity::default(), &RecordOptions::default())
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_kafka_archive_build_invalid_batch_size() {
+        let kafka_args = KafkaArgs {
+            brokers: "localhost:9092".to_string(),
+            topic: "sarchive".to_string(),
+            message_timeout: "5000".to_string(),
+            security_protocol: SecurityProtocol::Plaintext,
+            ssl: None,
+            sasl: None,
+            content_hash: false,
+            dedup_window: None,
+            compression: Compression::Lz4,
+            linger_ms: None,
+            batch_size: Some(0),
+            properties: Vec::new(),
+            max_message_bytes: 1_000_000,
+            signing_key: None,
+        };
+
+        assert!(
+            KafkaArchive::build(&kafka_args, &Identity::default(), &RecordOptions::default())
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_split_payload() {
+        let payload = "ab".repeat(2500);
+        let parts = split_payload(payload.as_bytes(), 2048);
+        assert_eq!(parts.len(), 3);
+        assert_eq!(parts[0].len(), 2048);
+        assert_eq!(parts[2].len(), 5000 - 2 * 2048);
+        assert_eq!(parts.concat(), payload.as_bytes());
+
+        assert_eq!(split_payload(b"abc", 2048).len(), 1);
+    }
+
+    #[test]
+    fn test_produce_failure() {
+        // nothing is delivered to the unreachable broker, so the queue fills up
+        let config = ClientConfig::new()
+            .set("bootstrap.servers", "127.0.0.1:1")
+            .set("queue.buffering.max.messages", "2")
+            .to_owned();
+        let mut kafka_archive = KafkaArchive::with_config(&config, "test_topic").unwrap();
+        assert!(kafka_archive
+            .produce("test_topic", "key", "small", None)
+            .is_ok());
+
+        kafka_archive.max_payload = 8;
+        let e = kafka_archive
+            .produce("test_topic", "key", &"x".repeat(100), None)
+            .unwrap_err();
+        assert!(e.to_string().starts_with("Cannot produce part 2 of 13"));
+
+        let job = JobRecord::new(&DummyJobInfo);
+        assert!(kafka_archive.archive(&job).is_err());
+        assert_eq!(kafka_archive.payload_size(&job), 0);
+        assert!(kafka_archive.archive_tombstone(&job).is_err());
+    }
+
+    #[test]
+    fn test_part_headers() {
+        use rdkafka::message::Headers;
+
+        let headers = part_headers("cafe", 2, 3);
+        assert_eq!(headers.count(), 3);
+        assert_eq!(headers.get(0).key, "sarchive_chunk_id");
+        assert_eq!(headers.get(0).value, Some("cafe".as_bytes()));
+        assert_eq!(headers.get(1).value, Some("2".as_bytes()));
+        assert_eq!(headers.get(2).value, Some("3".as_bytes()));
+    }
+
+    #[test]
+    fn test_message_signer() {
+        use ed25519_dalek::{Signature, Verifier, VerifyingKey};
+
+        let tdir = tempfile::tempdir().unwrap();
+        let path = tdir.path().join("key");
+        std::fs::write(&path, format!("{}\n", "ab".repeat(32))).unwrap();
+        let signer = MessageSigner::load(&path).unwrap();
+        assert_eq!(signer.key_id.len(), 16);
+
+        let public_key: [u8; 32] = parse_hex(&signer.public_key()).unwrap().try_into().unwrap();
+        let signature: [u8; 64] = parse_hex(&signer.sign(b"{\"id\":\"123\"}"))
+            .unwrap()
+            .try_into()
+            .unwrap();
+        let key = VerifyingKey::from_bytes(&public_key).unwrap();
+        let signature = Signature::from_bytes(&signature);
+        assert!(key.verify(b"{\"id\":\"123\"}", &signature).is_ok());
+        assert!(key.verify(b"{\"id\":\"124\"}", &signature).is_err());
+
+        std::fs::write(&path, "abcd").unwrap();
+        assert!(MessageSigner::load(&path).is_err());
+    }
+
+    #[test]
+    fn test_parse_property() {
+        assert_eq!(
+            parse_property("queue.buffering.max.ms=10"),
+            Ok(("queue.buffering.max.ms".to_string(), "10".to_string()))
+        );
+        assert_eq!(
+            parse_property("sasl.password=a=b"),
+            Ok(("sasl.password".to_string(), "a=b".to_string()))
+        );
+        assert!(parse_property("acks").is_err());
+        assert!(parse_property("=all").is_err());
+    }
+
+    #[test]
+    fn test_kafka_args_defaults() {
+        #[derive(clap::Parser)]
+        struct Cli {
+            #[command(flatten)]
+            kafka: KafkaArgs,
+        }
+
+        let cli =
+            <Cli as clap::Parser>::try_parse_from(["sarchive", "--brokers", "b:9092"]).unwrap();
+        assert_eq!(cli.kafka.security_protocol, SecurityProtocol::Plaintext);
+        assert_eq!(cli.kafka.compression, Compression::None);
+        assert_eq!(cli.kafka.max_message_bytes, 1_000_000);
+    }
 }
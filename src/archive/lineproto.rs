@@ -0,0 +1,544 @@
+/*
+Copyright 2019-2024 Andy Georges <itkovian+sarchive@gmail.com>
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in
+all copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+use chrono::Utc;
+use log::{debug, info, warn};
+use std::collections::BTreeMap;
+use std::io::{BufRead, BufReader, Error, ErrorKind, Write};
+use std::net::{TcpStream, UdpSocket};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use super::Archive;
+use crate::completion::Completion;
+use crate::scheduler::job::{JobInfo, JobRecord};
+use crate::stats::SubmissionSummary;
+
+/// How long to wait for the metrics endpoint
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Where the points are sent to
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Endpoint {
+    /// A UDP listener, e.g., InfluxDB 1.x or VictoriaMetrics
+    Udp(String),
+    /// A TCP listener, e.g., VictoriaMetrics with -influxListenAddr
+    Tcp(String),
+    /// An HTTP write endpoint, given as the address and the path (with query)
+    Http(String, String),
+}
+
+/// Parses an endpoint of the form udp://host:port, tcp://host:port or
+/// http://host:port/path, e.g., http://localhost:8086/write?db=sarchive
+pub fn parse_endpoint(s: &str) -> Result<Endpoint, String> {
+    let (scheme, rest) = s
+        .split_once("://")
+        .ok_or_else(|| format!("{s} is not of the form udp://, tcp:// or http://"))?;
+    let (address, path) = match rest.find('/') {
+        Some(i) => (&rest[..i], &rest[i..]),
+        None => (rest, "/write"),
+    };
+    if address.is_empty() {
+        return Err(format!("{s} lacks a host"));
+    }
+    match scheme {
+        "udp" => Ok(Endpoint::Udp(address.to_owned())),
+        "tcp" => Ok(Endpoint::Tcp(address.to_owned())),
+        "http" => Ok(Endpoint::Http(address.to_owned(), path.to_owned())),
+        _ => Err(format!("Unsupported scheme {scheme}, use udp, tcp or http")),
+    }
+}
+
+/// Escapes the commas, equal signs and spaces in a measurement, tag key or
+/// tag value
+fn escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        if matches!(c, ',' | '=' | ' ' | '\\') {
+            escaped.push('\\');
+        }
+        match c {
+            '\n' => escaped.push(' '),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Returns the line protocol point for the job, with the cluster, partition
+/// and user (if known) and the labels as tags, and the script size, number
+/// of environment variables and latency since the job event as fields
+pub fn job_point(
+    measurement: &str,
+    job_entry: &dyn JobInfo,
+    labels: &BTreeMap<String, String>,
+) -> String {
+    let script = job_entry.script();
+    let env = job_entry.extra_info().unwrap_or_default();
+    let env_count = env.keys().filter(|k| !k.starts_with("sarchive_")).count();
+    let partition = job_entry.partition();
+    let user = job_entry.user();
+
+    let mut tags = labels.clone();
+    tags.insert("cluster".to_owned(), job_entry.cluster());
+    if let Some(partition) = partition {
+        tags.insert("partition".to_owned(), partition);
+    }
+    if let Some(user) = user {
+        tags.insert("user".to_owned(), user);
+    }
+
+    let event_time = job_entry.event_time();
+    let latency = (Utc::now() - event_time).num_milliseconds().max(0);
+    let mut point = escape(measurement);
+    for (key, value) in tags.iter().filter(|(_, v)| !v.is_empty()) {
+        point.push_str(&format!(",{}={}", escape(key), escape(value)));
+    }
+    point.push_str(&format!(
+        " jobid=\"{}\",script_size={}i,env_count={}i,latency_ms={}i {}",
+        job_entry.jobid().replace('\\', "\\\\").replace('"', "\\\""),
+        script.len(),
+        env_count,
+        latency,
+        event_time.timestamp_nanos_opt().unwrap_or_default()
+    ));
+    point
+}
+
+/// Returns the line protocol points for the job submissions over a period:
+/// one with the totals for the cluster, and one per watch location with the
+/// location as a tag. The labels are tags as well.
+pub fn submission_points(
+    measurement: &str,
+    cluster: &str,
+    summary: &SubmissionSummary,
+    labels: &BTreeMap<String, String>,
+) -> Vec<String> {
+    let timestamp = Utc::now().timestamp_nanos_opt().unwrap_or_default();
+    let mut tags = labels.clone();
+    tags.insert("cluster".to_owned(), cluster.to_owned());
+    let point = |tags: &BTreeMap<String, String>, fields: String| {
+        let mut point = escape(measurement);
+        for (key, value) in tags.iter().filter(|(_, v)| !v.is_empty()) {
+            point.push_str(&format!(",{}={}", escape(key), escape(value)));
+        }
+        format!("{point} {fields} {timestamp}")
+    };
+
+    let mut points = vec![point(
+        &tags,
+        format!(
+            "jobs={}i,minutes={}i,rate={:.2},peak={}i",
+            summary.total,
+            summary.minutes,
+            summary.rate(),
+            summary.peak
+        ),
+    )];
+    for (location, jobs) in summary.locations.iter() {
+        let mut tags = tags.clone();
+        tags.insert(
+            "location".to_owned(),
+            location.to_string_lossy().into_owned(),
+        );
+        points.push(point(&tags, format!("jobs={jobs}i")));
+    }
+    points
+}
+
+/// Wraps an archiver to also send a compact point per archived job to a
+/// time series database in the InfluxDB line protocol (e.g., InfluxDB or
+/// VictoriaMetrics), for dashboards that have no use for the full records
+///
+/// Points are only sent for jobs the wrapped archiver stored. They are best
+/// effort: a point that cannot be sent is logged and dropped, without
+/// failing the archival.
+pub struct LineProtocolArchive {
+    inner: Box<dyn Archive>,
+    sender: LineSender,
+    measurement: String,
+    labels: BTreeMap<String, String>,
+}
+
+/// Sends points to an endpoint, keeping the connection for TCP
+pub struct LineSender {
+    endpoint: Endpoint,
+    connection: Mutex<Option<TcpStream>>,
+}
+
+impl LineProtocolArchive {
+    pub fn new(
+        inner: Box<dyn Archive>,
+        endpoint: &Endpoint,
+        measurement: &str,
+        labels: &BTreeMap<String, String>,
+    ) -> Self {
+        info!(
+            "Sending a {} point per job archived by backend {} to {:?}",
+            measurement,
+            inner.name(),
+            endpoint
+        );
+        LineProtocolArchive {
+            inner,
+            sender: LineSender::new(endpoint),
+            measurement: measurement.to_owned(),
+            labels: labels.clone(),
+        }
+    }
+
+    /// Sends the point for the archived job, a failure is only logged
+    fn send_point(&self, job_entry: &dyn JobInfo) {
+        let point = job_point(&self.measurement, job_entry, &self.labels);
+        debug!("Sending point {}", point);
+        if let Err(e) = self.sender.send(&point) {
+            warn!(
+                "Could not send the point for job {} to {:?}: {}",
+                job_entry.jobid(),
+                self.sender.endpoint,
+                e
+            );
+        }
+    }
+}
+
+impl LineSender {
+    pub fn new(endpoint: &Endpoint) -> Self {
+        LineSender {
+            endpoint: endpoint.clone(),
+            connection: Mutex::new(None),
+        }
+    }
+
+    /// Sends the point to the endpoint
+    pub fn send(&self, point: &str) -> Result<(), Error> {
+        let line = format!("{point}\n");
+        match &self.endpoint {
+            Endpoint::Udp(address) => {
+                let socket = UdpSocket::bind("0.0.0.0:0")?;
+                socket.send_to(line.as_bytes(), address)?;
+                Ok(())
+            }
+            Endpoint::Tcp(address) => {
+                let mut connection = self.connection.lock().unwrap_or_else(|e| e.into_inner());
+                if let Some(mut stream) = connection.take() {
+                    if stream.write_all(line.as_bytes()).is_ok() {
+                        *connection = Some(stream);
+                        return Ok(());
+                    }
+                    debug!("Lost the connection to {}, reconnecting", address);
+                }
+                let mut stream = connect(address)?;
+                stream.write_all(line.as_bytes())?;
+                *connection = Some(stream);
+                Ok(())
+            }
+            Endpoint::Http(address, path) => post(address, path, "text/plain", &line),
+        }
+    }
+}
+
+/// Connects to the given address, with a timeout on writes and reads
+fn connect(address: &str) -> Result<TcpStream, Error> {
+    let stream = TcpStream::connect(address)?;
+    stream.set_write_timeout(Some(TIMEOUT))?;
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    Ok(stream)
+}
+
+/// Posts the body to the given path over plain HTTP, expecting a 2xx status
+pub(crate) fn post(address: &str, path: &str, content_type: &str, body: &str) -> Result<(), Error> {
+    let mut stream = connect(address)?;
+    write!(
+        stream,
+        "POST {path} HTTP/1.1\r\nHost: {address}\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    )?;
+    stream.flush()?;
+    let mut status = String::new();
+    BufReader::new(stream).read_line(&mut status)?;
+    match status.split_whitespace().nth(1) {
+        Some(code) if code.starts_with('2') => Ok(()),
+        _ => Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("Unexpected response from {address}: {}", status.trim()),
+        )),
+    }
+}
+
+impl Archive for LineProtocolArchive {
+    fn archive(&self, job_entry: &JobRecord) -> Result<(), Error> {
+        self.inner.archive(job_entry)?;
+        self.send_point(job_entry);
+        Ok(())
+    }
+
+    fn archive_batch(&self, job_entries: &[JobRecord]) -> Vec<Result<(), Error>> {
+        let outcomes = self.inner.archive_batch(job_entries);
+        for (job_entry, outcome) in job_entries.iter().zip(outcomes.iter()) {
+            if outcome.is_ok() {
+                self.send_point(job_entry);
+            }
+        }
+        outcomes
+    }
+
+    fn archive_tombstone(&self, job_entry: &JobRecord) -> Result<(), Error> {
+        self.inner.archive_tombstone(job_entry)
+    }
+
+    fn archive_completion(&self, completion: &Completion) -> Result<(), Error> {
+        self.inner.archive_completion(completion)
+    }
+
+    /// Checks the wrapped archiver and, for TCP and HTTP, that the endpoint
+    /// accepts connections. A TCP connection is kept for the first point.
+    fn check(&self, cluster: &str) -> Result<(), Error> {
+        self.inner.check(cluster)?;
+        let connected = |address: &String| {
+            connect(address)
+                .map_err(|e| Error::new(e.kind(), format!("Cannot connect to {address}: {e}")))
+        };
+        match &self.sender.endpoint {
+            Endpoint::Udp(_) => (),
+            Endpoint::Tcp(address) => {
+                *self
+                    .sender
+                    .connection
+                    .lock()
+                    .unwrap_or_else(|e| e.into_inner()) = Some(connected(address)?)
+            }
+            Endpoint::Http(address, _) => drop(connected(address)?),
+        }
+        Ok(())
+    }
+
+    fn flush(&self, timeout: Duration) -> Result<(), Error> {
+        self.inner.flush(timeout)
+    }
+
+    fn payload_size(&self, job: &JobRecord) -> u64 {
+        self.inner.payload_size(job)
+    }
+
+    fn name(&self) -> &str {
+        self.inner.name()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::scheduler::slurm::SlurmJobEntry;
+    use crate::testing::{slurm_entry, RecordingArchiver};
+    use std::fs;
+    use std::io::Read;
+    use std::net::TcpListener;
+    use std::path::PathBuf;
+    use std::thread;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_parse_endpoint() {
+        assert_eq!(
+            parse_endpoint("udp://localhost:8089"),
+            Ok(Endpoint::Udp("localhost:8089".to_owned()))
+        );
+        assert_eq!(
+            parse_endpoint("tcp://vm:8189"),
+            Ok(Endpoint::Tcp("vm:8189".to_owned()))
+        );
+        assert_eq!(
+            parse_endpoint("http://influx:8086/write?db=sarchive"),
+            Ok(Endpoint::Http(
+                "influx:8086".to_owned(),
+                "/write?db=sarchive".to_owned()
+            ))
+        );
+        assert_eq!(
+            parse_endpoint("http://vm:8428"),
+            Ok(Endpoint::Http("vm:8428".to_owned(), "/write".to_owned()))
+        );
+        assert!(parse_endpoint("https://influx:8086").is_err());
+        assert!(parse_endpoint("influx:8086").is_err());
+        assert!(parse_endpoint("udp://").is_err());
+    }
+
+    #[test]
+    fn test_job_point() {
+        let entry = slurm_entry("my cluster");
+        let mut labels = BTreeMap::new();
+        labels.insert("env".to_owned(), "prod".to_owned());
+
+        let point = job_point("sarchive_job", &entry, &labels);
+        assert!(point.starts_with("sarchive_job,cluster=my\\ cluster,env=prod "));
+        assert!(point.contains(&format!(
+            "jobid=\"123456\",script_size={}i,env_count={}i,latency_ms=",
+            entry.script().len(),
+            entry
+                .extra_info()
+                .unwrap()
+                .keys()
+                .filter(|k| !k.starts_with("sarchive_"))
+                .count()
+        )));
+        assert!(point.ends_with(
+            &entry
+                .event_time()
+                .timestamp_nanos_opt()
+                .unwrap()
+                .to_string()
+        ));
+
+        let dir = tempdir().unwrap();
+        fs::write(dir.path().join("script"), "#!/bin/bash\n#SBATCH -p gpu\n").unwrap();
+        fs::write(
+            dir.path().join("environment"),
+            b"\x02\0\0\0USER=alice\0HOME=/home/alice\0",
+        )
+        .unwrap();
+        let mut entry = SlurmJobEntry::new(dir.path(), "1", "mycluster");
+        entry.read_job_info().unwrap();
+        let point = job_point("sarchive_job", &entry, &BTreeMap::new());
+        assert!(point.starts_with("sarchive_job,cluster=mycluster,partition=gpu,user=alice "));
+    }
+
+    #[test]
+    fn test_submission_points() {
+        let summary = SubmissionSummary {
+            minutes: 10,
+            total: 25,
+            peak: 12,
+            peak_at: None,
+            locations: BTreeMap::from([
+                (PathBuf::from("/spool/hash.0"), 20),
+                (PathBuf::from("/spool/hash.1"), 5),
+            ]),
+        };
+        let labels = BTreeMap::from([("env".to_owned(), "prod".to_owned())]);
+        let points = submission_points("sarchive_submissions", "mycluster", &summary, &labels);
+        assert_eq!(points.len(), 3);
+        assert!(points[0]
+            .starts_with("sarchive_submissions,cluster=mycluster,env=prod jobs=25i,minutes=10i,rate=2.50,peak=12i "));
+        assert!(points[1].starts_with(
+            "sarchive_submissions,cluster=mycluster,env=prod,location=/spool/hash.0 jobs=20i "
+        ));
+    }
+
+    #[test]
+    fn test_escape() {
+        assert_eq!(escape("a b,c=d"), "a\\ b\\,c\\=d");
+    }
+
+    #[test]
+    fn test_line_protocol_tcp() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let receiver = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut received = String::new();
+            stream.read_to_string(&mut received).unwrap();
+            received
+        });
+
+        let inner = Box::new(RecordingArchiver::default());
+        let archive = LineProtocolArchive::new(
+            inner,
+            &Endpoint::Tcp(address),
+            "sarchive_job",
+            &BTreeMap::new(),
+        );
+        archive.check("my cluster").unwrap();
+        let job_entry = JobRecord::new(&slurm_entry("my cluster"));
+        archive.archive(&job_entry).unwrap();
+        archive.archive(&job_entry).unwrap();
+        drop(archive);
+
+        let received = receiver.join().unwrap();
+        assert_eq!(received.lines().count(), 2);
+        assert!(received
+            .lines()
+            .all(|l| l.starts_with("sarchive_job,cluster=my\\ cluster")));
+    }
+
+    #[test]
+    fn test_line_protocol_http() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let receiver = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream);
+            let mut request = Vec::new();
+            let mut line = String::new();
+            while reader.read_line(&mut line).unwrap() > 2 {
+                request.push(line.trim_end().to_owned());
+                line.clear();
+            }
+            let length: usize = request
+                .iter()
+                .find_map(|h| h.strip_prefix("Content-Length: "))
+                .unwrap()
+                .parse()
+                .unwrap();
+            let mut body = vec![0; length];
+            reader.read_exact(&mut body).unwrap();
+            reader
+                .get_mut()
+                .write_all(b"HTTP/1.1 204 No Content\r\n\r\n")
+                .unwrap();
+            (request[0].clone(), String::from_utf8(body).unwrap())
+        });
+
+        let inner = Box::new(RecordingArchiver::default());
+        let endpoint = Endpoint::Http(address, "/write?db=sarchive".to_owned());
+        let archive = LineProtocolArchive::new(inner, &endpoint, "jobs", &BTreeMap::new());
+        let job_entry = JobRecord::new(&slurm_entry("my cluster"));
+        archive
+            .sender
+            .send(&job_point("jobs", &job_entry, &BTreeMap::new()))
+            .unwrap();
+
+        let (request, body) = receiver.join().unwrap();
+        assert_eq!(request, "POST /write?db=sarchive HTTP/1.1");
+        assert!(body.starts_with("jobs,cluster=my\\ cluster"));
+        assert!(body.ends_with('\n'));
+    }
+
+    #[test]
+    fn test_line_protocol_failures() {
+        // nothing listens on the endpoint, which does not fail the archival
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        drop(listener);
+
+        let archive = LineProtocolArchive::new(
+            Box::new(RecordingArchiver::default()),
+            &Endpoint::Tcp(address),
+            "jobs",
+            &BTreeMap::new(),
+        );
+        assert!(archive.check("mycluster").is_err());
+        let job_entry = JobRecord::new(&slurm_entry("my cluster"));
+        archive.archive(&job_entry).unwrap();
+        assert_eq!(archive.name(), "recording");
+    }
+}
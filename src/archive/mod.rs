@@ -20,102 +20,822 @@ OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
 SOFTWARE.
 */
 
+pub mod breaker;
+pub mod cas;
+pub mod deadline;
+pub mod dedup;
+pub mod document;
 pub mod file;
+pub mod jsonl;
+pub mod lineproto;
+pub mod optout;
+pub mod outbox;
+pub mod pseudonym;
+pub mod socket;
+pub mod stdout;
+pub mod transform;
 
 #[cfg(feature = "kafka")]
 pub mod kafka;
 
-use clap::{command, Args, Subcommand};
-use crossbeam_channel::{select, Receiver};
-use log::{debug, error, info};
-use std::io::Error;
+use clap::Subcommand;
+use crossbeam_channel::{never, select, Receiver, RecvTimeoutError};
+use log::{debug, error, info, warn};
+use std::collections::{HashSet, VecDeque};
+use std::fs::{remove_file, OpenOptions};
+use std::io::{Error, ErrorKind};
+use std::path::{Path, PathBuf};
 
 #[cfg(feature = "kafka")]
 use self::kafka::{KafkaArchive, KafkaArgs};
 
-use super::scheduler::job::JobInfo;
+use self::breaker::held;
+use self::deadline::{expired, within, Deadline};
+use self::document::RecordOptions;
+use super::completion::{ArchivedJobs, Completion};
+use super::identity::Identity;
+use super::maintenance::Maintenance;
+use super::reconcile::Reconciler;
+use super::scheduler::job::{JobInfo, JobRecord};
+use super::stats::Stats;
+use super::utils::JobContext;
 use file::{FileArchive, FileArgs};
+use jsonl::{JsonlArchive, JsonlArgs};
+use outbox::{OutboxArchive, OutboxArgs};
+use socket::{SocketArchive, SocketArgs};
 use std::thread::sleep;
 use std::time::Duration;
-
-#[derive(Args, Debug)]
-pub struct ArchiverOptions {
-    #[command(subcommand)]
-    pub archiver: Option<ArchiverArgs>,
-}
+use stdout::StdoutArchive;
 
 #[derive(Subcommand, Debug)]
 pub enum ArchiverArgs {
     File(FileArgs),
+    Jsonl(JsonlArgs),
+    Socket(SocketArgs),
+    /// Capture the jobs to a local outbox directory, from which `sarchive ship` sends them on
+    Outbox(OutboxArgs),
+    /// Print a JSON document per job on standard output
+    Stdout,
 
     #[cfg(feature = "kafka")]
     Kafka(KafkaArgs),
 }
 
-/// The Archive trait should be implemented by every backend.
-#[allow(clippy::borrowed_box)]
+/// The Archive trait should be implemented by every backend. The backends get
+/// the record of a job, read once from its job entry.
 pub trait Archive: Send {
-    fn archive(&self, slurm_job_entry: &Box<dyn JobInfo>) -> Result<(), Error>;
+    fn archive(&self, job: &JobRecord) -> Result<(), Error>;
+
+    // Archive several jobs at once, returning the outcome for each job, in
+    // order. Backends with an efficient bulk path (e.g., a single write)
+    // implement this; by default, the jobs are archived one by one.
+    fn archive_batch(&self, job_entries: &[JobRecord]) -> Vec<Result<(), Error>> {
+        job_entries
+            .iter()
+            .map(|entry| self.archive(entry))
+            .collect()
+    }
+
+    // Record that the job vanished before its information could be read.
+    // Backends that have no use for such tombstones need not implement this.
+    fn archive_tombstone(&self, _job: &JobRecord) -> Result<(), Error> {
+        Ok(())
+    }
+
+    // Record the completion of a job that was archived earlier. Backends that
+    // have no use for completion events need not implement this.
+    fn archive_completion(&self, _completion: &Completion) -> Result<(), Error> {
+        Ok(())
+    }
+
+    // Verify that the backend can take the jobs of the given cluster, e.g.,
+    // that the destination is reachable and writable. Backends that have
+    // nothing to verify need not implement this.
+    fn check(&self, _cluster: &str) -> Result<(), Error> {
+        Ok(())
+    }
+
+    // Wait until the jobs handed to the backend so far are delivered, or the
+    // timeout passes. Backends that deliver a job before archive returns need
+    // not implement this.
+    fn flush(&self, _timeout: Duration) -> Result<(), Error> {
+        Ok(())
+    }
+
+    // Return the size in bytes of the payload the backend writes or produces
+    // for the job, used when reporting statistics. By default, this is the
+    // size of the job files; backends that send a document implement this.
+    fn payload_size(&self, job: &JobRecord) -> u64 {
+        job.files_size()
+    }
+
+    // Return the name of the backend, used when reporting statistics
+    fn name(&self) -> &str;
 }
 
-pub fn archive_builder(archiver: &Option<ArchiverArgs>) -> Result<Box<dyn Archive>, Error> {
+/// Placeholder in a destination (e.g., a path or topic) that is replaced by
+/// the job's cluster
+pub const CLUSTER_PLACEHOLDER: &str = "{cluster}";
+
+/// How long to wait before retrying an entry when the archive storage is full
+const STANDBY_RETRY_INTERVAL: Duration = Duration::from_secs(30);
+
+/// How often to check whether maintenance started or ended
+const MAINTENANCE_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How long to wait for the backend to deliver a batch before taking the next
+const BATCH_FLUSH_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How long a job entry is given for its files to be written, after its event
+const SETTLE_TIME: Duration = Duration::from_millis(2000);
+
+/// Returns the outcome of a batch that failed as a whole, for each of its jobs.
+/// The OS error code is kept, so a full archive storage is still recognised.
+pub(crate) fn batch_failed(e: Error, count: usize) -> Vec<Result<(), Error>> {
+    (0..count)
+        .map(|_| match e.raw_os_error() {
+            Some(code) => Err(Error::from_raw_os_error(code)),
+            None => Err(Error::new(e.kind(), e.to_string())),
+        })
+        .collect()
+}
+
+/// Checks if the error indicates the archive storage is full or the quota
+/// is exceeded, in which case retrying later may succeed
+pub fn is_storage_full(e: &Error) -> bool {
+    matches!(e.raw_os_error(), Some(libc::ENOSPC) | Some(libc::EDQUOT))
+}
+
+/// Checks that files can be created in the given directory or, if it does not
+/// exist yet, in its closest existing ancestor, where it would be created
+pub fn check_writable(dir: &Path) -> Result<(), Error> {
+    if dir.exists() && !dir.is_dir() {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("{dir:?} is not a directory"),
+        ));
+    }
+    let existing = dir.ancestors().find(|d| d.is_dir()).ok_or_else(|| {
+        Error::new(
+            ErrorKind::NotFound,
+            format!("No existing directory on the path to {dir:?}"),
+        )
+    })?;
+    let probe = existing.join(format!(".sarchive-check-{}", std::process::id()));
+    OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&probe)
+        .map_err(|e| Error::new(e.kind(), format!("Cannot write to {existing:?}: {e}")))?;
+    remove_file(&probe)
+}
+
+/// Builds the backend for the given arguments. Backends that ship records
+/// to a shared destination tag them with the identity of this instance.
+pub fn archive_builder(
+    archiver: &ArchiverArgs,
+    identity: &Identity,
+    options: &RecordOptions,
+) -> Result<Box<dyn Archive>, Error> {
     match archiver {
-        Some(ArchiverArgs::File(args)) => {
-            let archive = FileArchive::build(args)?;
+        ArchiverArgs::File(args) => {
+            let archive = FileArchive::build(args, identity, options)?;
             Ok(Box::new(archive))
         }
+        ArchiverArgs::Jsonl(args) => {
+            let archive = JsonlArchive::build(args, identity, options)?;
+            Ok(Box::new(archive))
+        }
+        ArchiverArgs::Socket(args) => {
+            let archive = SocketArchive::build(args, identity, options)?;
+            Ok(Box::new(archive))
+        }
+        ArchiverArgs::Outbox(args) => {
+            let archive = OutboxArchive::build(args)?;
+            Ok(Box::new(archive))
+        }
+        ArchiverArgs::Stdout => Ok(Box::new(StdoutArchive::new(identity, options))),
         #[cfg(feature = "kafka")]
-        Some(ArchiverArgs::Kafka(kafka_args)) => {
-            let archive = KafkaArchive::build(kafka_args)?;
+        ArchiverArgs::Kafka(kafka_args) => {
+            let archive = KafkaArchive::build(kafka_args, identity, options)?;
             Ok(Box::new(archive))
         }
-        None => panic!("No suitable archiver provided."),
     }
 }
 
+/// A job entry after reading its information
+enum Captured {
+    /// The records of the job as a whole, or of each of the tasks it is made up of
+    Job {
+        jobid: String,
+        cluster: String,
+        event_path: Option<PathBuf>,
+        records: Vec<JobRecord>,
+    },
+    /// The job directory vanished before it could be read
+    Cancelled(Box<JobRecord>),
+    /// The job files were deleted, which is archived as a tombstone
+    Deleted(Box<JobRecord>),
+    /// The job information could not be read within the deadline, the entry
+    /// was set aside
+    Skipped { jobid: String, cluster: String },
+}
+
+impl Captured {
+    fn jobid(&self) -> &str {
+        match self {
+            Captured::Job { jobid, .. } | Captured::Skipped { jobid, .. } => jobid,
+            Captured::Cancelled(record) | Captured::Deleted(record) => &record.jobid,
+        }
+    }
+
+    fn cluster(&self) -> &str {
+        match self {
+            Captured::Job { cluster, .. } | Captured::Skipped { cluster, .. } => cluster,
+            Captured::Cancelled(record) | Captured::Deleted(record) => &record.cluster,
+        }
+    }
+
+    /// Returns the entry to save for the next run, which reads the job from
+    /// the spool again. Only a job that was read can be saved: the files of a
+    /// cancelled or deleted job are gone, and a skipped entry was set aside
+    /// already.
+    fn into_entry(self) -> Option<Box<dyn JobInfo>> {
+        match self {
+            Captured::Job {
+                jobid,
+                cluster,
+                event_path,
+                records,
+            } => records.first().map(|record| {
+                let mut record = record.bare();
+                record.jobid = jobid;
+                record.cluster = cluster;
+                record.event_path = event_path;
+                Box::new(record) as Box<dyn JobInfo>
+            }),
+            captured => {
+                warn!(
+                    "Cannot save job {} for the next run, dropping it",
+                    captured.jobid()
+                );
+                None
+            }
+        }
+    }
+}
+
+/// Read the job information, so it is kept even when the job files go away
+/// before the entry is archived.
+///
+/// A job whose directory vanished before we could read it (e.g., because it was
+/// cancelled right after submission) is not an error. We count it, so it can
+/// get a tombstone. When the monitor saw the directory being deleted, we do not
+/// even try to read it.
+///
+/// Reading the job information that takes longer than the deadline, e.g., on
+/// a hanging file system, is given up on. The entry is set aside and counted.
+fn capture(
+    entry: Box<dyn JobInfo>,
+    stats: &Stats,
+    reconciler: &Reconciler,
+    deadline: Option<&Deadline>,
+) -> Result<Captured, Error> {
+    let _context = JobContext::enter(&entry.cluster(), &entry.jobid());
+    if entry.is_deletion() {
+        info!("Job {} was deleted", entry.jobid());
+        stats.deleted();
+        return Ok(Captured::Deleted(Box::new(JobRecord::new(entry.as_ref()))));
+    }
+    if entry.event_path().is_some_and(|p| reconciler.processed(&p)) {
+        info!(
+            "Job {} was cancelled before capture, its entry was deleted",
+            entry.jobid()
+        );
+        stats.cancelled();
+        return Ok(Captured::Cancelled(Box::new(JobRecord::new(
+            entry.as_ref(),
+        ))));
+    }
+    let (entry, outcome) = match deadline {
+        Some(deadline) => {
+            let record = JobRecord::new(entry.as_ref());
+            let what = format!("Reading job {}", record.jobid);
+            match within(deadline.limit, what, move || read(entry)) {
+                Ok(read) => read,
+                Err(e) => {
+                    error!("Skipping job {}: {}", record.jobid, e);
+                    stats.read_timed_out();
+                    deadline.dead_letter(&record, &e);
+                    return Ok(Captured::Skipped {
+                        jobid: record.jobid,
+                        cluster: record.cluster,
+                    });
+                }
+            }
+        }
+        None => read(entry),
+    };
+    match outcome {
+        Ok(()) => {
+            let tasks = entry.expand();
+            let records = if tasks.is_empty() {
+                vec![JobRecord::new(entry.as_ref())]
+            } else {
+                tasks
+                    .iter()
+                    .map(|task| JobRecord::new(task.as_ref()))
+                    .collect()
+            };
+            Ok(Captured::Job {
+                jobid: entry.jobid(),
+                cluster: entry.cluster(),
+                event_path: entry.event_path(),
+                records,
+            })
+        }
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            warn!("Job {} was cancelled before capture: {}", entry.jobid(), e);
+            stats.cancelled();
+            Ok(Captured::Cancelled(Box::new(JobRecord::new(
+                entry.as_ref(),
+            ))))
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// Read the job information, handing back the entry
+fn read(mut entry: Box<dyn JobInfo>) -> (Box<dyn JobInfo>, Result<(), Error>) {
+    let outcome = entry.read_job_info();
+    (entry, outcome)
+}
+
+/// Archive the captured job entry, or, if requested, a tombstone for the job
+/// that vanished before capture. Returns whether the job was archived, i.e.,
+/// the backend took each of its records.
+fn store(
+    archiver: &dyn Archive,
+    captured: Captured,
+    stats: &Stats,
+    tombstones: bool,
+    sigchannel: Option<&Receiver<bool>>,
+) -> Result<bool, Error> {
+    let _context = JobContext::enter(captured.cluster(), captured.jobid());
+    match captured {
+        Captured::Job { jobid, records, .. } => {
+            if records.len() > 1 {
+                debug!("Archiving {} tasks of job {}", records.len(), jobid);
+            }
+            let mut archived = true;
+            for record in records.iter() {
+                let _context = JobContext::enter(&record.cluster, &record.jobid);
+                archived &= archive_record(archiver, record, stats, sigchannel)?;
+            }
+            Ok(archived)
+        }
+        Captured::Cancelled(record) if tombstones => {
+            store_tombstone(archiver, &record, stats).map(|_| false)
+        }
+        Captured::Deleted(record) => store_tombstone(archiver, &record, stats).map(|_| false),
+        Captured::Cancelled(_) | Captured::Skipped { .. } => Ok(false),
+    }
+}
+
+/// Archive the tombstone of the job, counting a backend that does not finish
+/// within the deadline rather than failing
+fn store_tombstone(archiver: &dyn Archive, record: &JobRecord, stats: &Stats) -> Result<(), Error> {
+    match archiver.archive_tombstone(record) {
+        Err(e) if expired(&e) => {
+            error!("Cannot archive tombstone of job {}: {}", record.jobid, e);
+            stats.timed_out(archiver.name());
+            Ok(())
+        }
+        outcome => outcome,
+    }
+}
+
+/// Simulate the debounced event we had before. Wait two seconds after the
+/// event of the job entry to have some assurance the files will have been
+/// written, unless the entry is known to be deleted.
+fn settle(entry: &dyn JobInfo, reconciler: &Reconciler) {
+    let elapsed = entry.moment().elapsed();
+    let deleted = entry.is_deletion()
+        || entry
+            .event_path()
+            .is_some_and(|p| reconciler.is_deleted(&p));
+    if let Some(dur) = SETTLE_TIME.checked_sub(elapsed).filter(|_| !deleted) {
+        debug!(
+            "Waiting for {} ms to elapse before checking files",
+            dur.as_millis()
+        );
+        sleep(dur);
+    }
+}
+
+/// Read the job information and archive it, keeping track of the outcome in
+/// the statistics.
+fn handle_entry(
+    archiver: &dyn Archive,
+    entry: Box<dyn JobInfo>,
+    stats: &Stats,
+    tombstones: bool,
+    sigchannel: Option<&Receiver<bool>>,
+    reconciler: &Reconciler,
+    deadline: Option<&Deadline>,
+) -> Result<(), Error> {
+    let captured = capture(entry, stats, reconciler, deadline)?;
+    store(archiver, captured, stats, tombstones, sigchannel).map(|_| ())
+}
+
+/// Read the job information of the entries and archive them as a batch,
+/// keeping track of the outcome in the statistics. Returns the IDs of the
+/// jobs that were archived, leaving out those with a record the backend did
+/// not take, and those that were skipped or only got a tombstone.
+fn handle_batch(
+    archiver: &dyn Archive,
+    entries: Vec<Box<dyn JobInfo>>,
+    stats: &Stats,
+    tombstones: bool,
+    sigchannel: Option<&Receiver<bool>>,
+    reconciler: &Reconciler,
+    deadline: Option<&Deadline>,
+) -> Result<Vec<String>, Error> {
+    let mut jobs: Vec<JobRecord> = Vec::new();
+    // the ID of the job each record belongs to
+    let mut owners: Vec<String> = Vec::new();
+    let mut archived = Vec::new();
+    for entry in entries {
+        match capture(entry, stats, reconciler, deadline)? {
+            Captured::Job { jobid, records, .. } => {
+                if records.len() > 1 {
+                    debug!("Archiving {} tasks of job {}", records.len(), jobid);
+                }
+                owners.extend(records.iter().map(|_| jobid.clone()));
+                jobs.extend(records);
+            }
+            Captured::Skipped { .. } => (),
+            // the tombstone goes after the jobs that came before it
+            tombstone => {
+                let outcomes = archive_entries(archiver, &jobs, stats, sigchannel)?;
+                archived.extend(archived_jobs(&owners, &outcomes));
+                jobs.clear();
+                owners.clear();
+                store(archiver, tombstone, stats, tombstones, sigchannel)?;
+            }
+        }
+    }
+    let outcomes = archive_entries(archiver, &jobs, stats, sigchannel)?;
+    archived.extend(archived_jobs(&owners, &outcomes));
+    Ok(archived)
+}
+
+/// Returns the IDs of the jobs all of whose records were archived, given the
+/// job each record belongs to and whether it was archived
+fn archived_jobs(owners: &[String], outcomes: &[bool]) -> Vec<String> {
+    let failed: HashSet<&String> = owners
+        .iter()
+        .zip(outcomes)
+        .filter(|(_, archived)| !**archived)
+        .map(|(jobid, _)| jobid)
+        .collect();
+    let mut jobids: Vec<String> = owners
+        .iter()
+        .filter(|jobid| !failed.contains(jobid))
+        .cloned()
+        .collect();
+    jobids.dedup();
+    jobids
+}
+
+/// Archive the job entries with a single call to the backend, and wait for
+/// their delivery before taking more. Failed entries are handled one by one,
+/// as for archive_entry. Returns whether each entry was archived.
+fn archive_entries(
+    archiver: &dyn Archive,
+    entries: &[JobRecord],
+    stats: &Stats,
+    sigchannel: Option<&Receiver<bool>>,
+) -> Result<Vec<bool>, Error> {
+    match entries {
+        [] => return Ok(Vec::new()),
+        [entry] => {
+            let _context = JobContext::enter(&entry.cluster, &entry.jobid);
+            return archive_record(archiver, entry, stats, sigchannel).map(|a| vec![a]);
+        }
+        _ => debug!("Archiving a batch of {} job entries", entries.len()),
+    }
+    entries.iter().for_each(warn_partial);
+    let outcomes = archiver.archive_batch(entries);
+    if outcomes.len() != entries.len() {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!(
+                "Backend {} reported {} outcomes for a batch of {} job entries",
+                archiver.name(),
+                outcomes.len(),
+                entries.len()
+            ),
+        ));
+    }
+    let mut archived = Vec::with_capacity(entries.len());
+    for (entry, outcome) in entries.iter().zip(outcomes) {
+        let _context = JobContext::enter(&entry.cluster, &entry.jobid);
+        archived.push(conclude(archiver, entry, stats, sigchannel, outcome)?);
+    }
+    if let Err(e) = archiver.flush(BATCH_FLUSH_TIMEOUT) {
+        warn!(
+            "Backend {} did not deliver the batch: {}",
+            archiver.name(),
+            e
+        );
+    }
+    Ok(archived)
+}
+
+/// Warn when some of the job files could not be captured
+fn warn_partial(entry: &JobRecord) {
+    if !entry.missing_files.is_empty() {
+        warn!(
+            "Archiving partial capture of job {}, missing {}",
+            entry.jobid,
+            entry.missing_files.join(", ")
+        );
+    }
+}
+
+/// Archive the job entry, keeping track of the outcome in the statistics
+///
+/// If the archive storage is full, we go into standby: the entry is retried
+/// periodically, while the following entries wait in the queue, until archival
+/// succeeds or a notification to stop arrives on the given channel. Entries
+/// held by the circuit breaker are retried in the same way, when the breaker
+/// says so. Without a channel, the error is returned immediately.
+pub(crate) fn archive_entry(
+    archiver: &dyn Archive,
+    entry: &JobRecord,
+    stats: &Stats,
+    sigchannel: Option<&Receiver<bool>>,
+) -> Result<(), Error> {
+    archive_record(archiver, entry, stats, sigchannel).map(|_| ())
+}
+
+/// Archive the job entry as archive_entry does, returning whether the backend
+/// took it, rather than giving up on it after the deadline
+fn archive_record(
+    archiver: &dyn Archive,
+    entry: &JobRecord,
+    stats: &Stats,
+    sigchannel: Option<&Receiver<bool>>,
+) -> Result<bool, Error> {
+    warn_partial(entry);
+    conclude(archiver, entry, stats, sigchannel, archiver.archive(entry))
+}
+
+/// Act on the outcome of archiving the job entry, retrying it as described
+/// for archive_entry. Returns whether the backend took the entry.
+fn conclude(
+    archiver: &dyn Archive,
+    entry: &JobRecord,
+    stats: &Stats,
+    sigchannel: Option<&Receiver<bool>>,
+    mut outcome: Result<(), Error>,
+) -> Result<bool, Error> {
+    loop {
+        match outcome {
+            Ok(()) => {
+                if stats.in_standby() {
+                    info!("Archive storage available again, leaving standby");
+                    stats.set_standby(false);
+                }
+                stats.set_circuit_open(archiver.name(), false);
+                let latency = entry.moment().elapsed();
+                info!(
+                    "Archived job {} with {} {}ms after its event",
+                    entry.jobid(),
+                    archiver.name(),
+                    latency.as_millis()
+                );
+                stats.archived(archiver.name(), latency);
+                stats.payload(
+                    archiver.name(),
+                    &entry.cluster,
+                    archiver.payload_size(entry),
+                );
+                return Ok(true);
+            }
+            Err(e) if is_storage_full(&e) && sigchannel.is_some() => {
+                error!(
+                    "Archive storage full, holding job {} and the queued entries; retrying in {}s: {}",
+                    entry.jobid(),
+                    STANDBY_RETRY_INTERVAL.as_secs(),
+                    e
+                );
+                stats.set_standby(true);
+                match sigchannel.unwrap().recv_timeout(STANDBY_RETRY_INTERVAL) {
+                    Ok(true) | Err(RecvTimeoutError::Disconnected) => {
+                        stats.archive_failed(archiver.name());
+                        return Err(e);
+                    }
+                    _ => (),
+                }
+            }
+            Err(e) if held(&e).is_some() && sigchannel.is_some() => {
+                let held = held(&e).unwrap();
+                warn!(
+                    "Holding job {} and the queued entries; retrying in {}ms: {}",
+                    entry.jobid(),
+                    held.retry_in.as_millis(),
+                    e
+                );
+                stats.set_circuit_open(archiver.name(), held.open);
+                match sigchannel.unwrap().recv_timeout(held.retry_in) {
+                    Ok(true) | Err(RecvTimeoutError::Disconnected) => {
+                        stats.archive_failed(archiver.name());
+                        return Err(e);
+                    }
+                    _ => (),
+                }
+            }
+            Err(e) if expired(&e) => {
+                error!(
+                    "Giving up on archiving job {} with {}: {}",
+                    entry.jobid(),
+                    archiver.name(),
+                    e
+                );
+                stats.timed_out(archiver.name());
+                return Ok(false);
+            }
+            Err(e) => {
+                stats.archive_failed(archiver.name());
+                return Err(e);
+            }
+        }
+        outcome = archiver.archive(entry);
+    }
+}
+
+/// What the processing of the job entries shares with the rest of sarchive,
+/// and how it goes about it
+#[derive(Clone, Copy)]
+pub struct ProcessContext<'a> {
+    pub stats: &'a Stats,
+    /// Holds the entries while archival is paused
+    pub maintenance: &'a Maintenance,
+    /// Tells the entries whose deletion is known
+    pub reconciler: &'a Reconciler,
+    /// Bounds reading the job information of an entry
+    pub deadline: Option<&'a Deadline>,
+    /// Whether deleted jobs are archived as tombstones
+    pub tombstones: bool,
+    /// Whether the queued entries are archived before stopping
+    pub cleanup: bool,
+    /// The largest number of entries archived in one batch
+    pub max_batch: usize,
+}
+
 /// The process function consumes job entries and call the archive function for each
-/// received entry.
+/// received entry. Completions of jobs archived earlier, as remembered in archived,
+/// are passed on to the archiver as well, others are ignored.
+/// During maintenance, the received entries are captured and held, to be archived
+/// in order once maintenance ends. Completions wait until the held entries are archived.
 /// At the same time, it also checks if there is an incoming notification that it should
-/// stop processing. Upon receipt, it will cease operations immediately.
+/// stop processing. Upon receipt, it will cease operations immediately. Unless it
+/// cleans up, the entries still held are then handed back in unarchived, ahead of
+/// the ones left in the queue, so they can be saved for the next run.
+/// Entries whose deletion the reconciler learnt about are dropped, without
+/// waiting for their files.
+/// When entries queue up, they are archived in batches of at most max_batch
+/// entries, sized after the queue depth, and each batch is delivered before
+/// the next one is taken.
+/// With a deadline, reading the job information of an entry is bounded by it;
+/// the archiver should be wrapped in a DeadlineArchive to bound archival too.
 pub fn process(
-    archiver: Box<dyn Archive>,
+    archiver: &dyn Archive,
     r: &Receiver<Box<dyn JobInfo>>,
+    completions: &Receiver<Completion>,
+    archived: &mut ArchivedJobs,
+    unarchived: &mut Vec<Box<dyn JobInfo>>,
     sigchannel: &Receiver<bool>,
-    cleanup: bool,
+    context: &ProcessContext,
 ) -> Result<(), Error> {
+    let ProcessContext {
+        stats,
+        maintenance,
+        reconciler,
+        deadline,
+        tombstones,
+        cleanup,
+        max_batch,
+    } = *context;
     info!("Start processing events");
+    let mut completions = completions.clone();
+    let mut held: VecDeque<Captured> = VecDeque::new();
+    let (no_entries, no_completions) = (never(), never());
 
     #[allow(clippy::zero_ptr, dropping_copy_types)]
     loop {
+        let paused = maintenance.paused();
+        if paused != stats.is_paused() {
+            if paused {
+                info!("Pausing archival for maintenance, holding the job entries");
+            } else {
+                info!(
+                    "Resuming archival, {} held job entries to archive",
+                    held.len()
+                );
+            }
+            stats.set_paused(paused);
+        }
+        // the held entries go first, so the entries are archived in order
+        let draining = !paused && !held.is_empty();
+        let entries = if draining { &no_entries } else { r };
+        let job_completions = if draining || paused {
+            &no_completions
+        } else {
+            &completions
+        };
+        let timeout = if draining {
+            Duration::ZERO
+        } else {
+            MAINTENANCE_CHECK_INTERVAL
+        };
+
         select! {
             recv(sigchannel) -> b => if let Ok(true) = b  {
                 if !cleanup {
-                    info!("Stopped processing entries, {} skipped", r.len());
+                    info!("Stopped processing entries, {} skipped", held.len() + r.len());
+                    unarchived.extend(held.drain(..).filter_map(Captured::into_entry));
                 } else {
-                    info!("Processing {} entries, then stopping", r.len());
-                    for mut entry in r.iter() {
-                        entry.read_job_info()?;
-                        archiver.archive(&entry)?;
+                    info!("Processing {} entries, then stopping", held.len() + r.len());
+                    for captured in held.drain(..) {
+                        store(archiver, captured, stats, tombstones, None)?;
+                    }
+                    for entry in r.iter() {
+                        handle_entry(archiver, entry, stats, tombstones, None, reconciler, deadline)?;
                     }
                     info!("Done processing");
                 }
+                stats.set_held(0);
                 break;
             },
-            recv(r) -> entry => {
-                if let Ok(mut job_entry) = entry {
-                    // Simulate the debounced event we had before. Wait two seconds after dir creation event to
-                    // have some assurance the files will have been written.
-                    let elapsed = job_entry.moment().elapsed();
-                    if let Some(dur) = Duration::from_millis(2000).checked_sub(elapsed) {
-                        debug!("Waiting for {} ms to elapse before checking files", dur.as_millis());
-                        sleep(dur);
+            recv(entries) -> entry => {
+                if let Ok(job_entry) = entry {
+                    settle(job_entry.as_ref(), reconciler);
+                    // take along the entries queued behind this one, up to the batch size
+                    let mut batch = vec![job_entry];
+                    let size = max_batch.min(1 + r.len());
+                    while batch.len() < size {
+                        match r.try_recv() {
+                            Ok(job_entry) => {
+                                settle(job_entry.as_ref(), reconciler);
+                                batch.push(job_entry);
+                            }
+                            Err(_) => break,
+                        }
+                    }
+                    // maintenance may have started while waiting
+                    if paused || maintenance.paused() {
+                        for job_entry in batch {
+                            let captured = capture(job_entry, stats, reconciler, deadline)?;
+                            debug!("Holding job {} until the end of maintenance", captured.jobid());
+                            held.push_back(captured);
+                        }
+                        stats.set_held(held.len());
+                        continue;
                     }
-                    job_entry.read_job_info()?;
-                    archiver.archive(&job_entry)?;
+                    let jobids = handle_batch(archiver, batch, stats, tombstones, Some(sigchannel), reconciler, deadline)?;
+                    jobids.iter().for_each(|jobid| archived.insert(jobid));
                 } else {
                     error!("Error on receiving JobEntry info");
                     break;
                 }
+            },
+            recv(job_completions) -> completion => match completion {
+                Ok(completion) if archived.accept(&completion) => {
+                    let _context = JobContext::enter(&completion.cluster, &completion.jobid);
+                    if let Err(e) = archiver.archive_completion(&completion) {
+                        error!("Cannot archive completion of job {}: {}", completion.jobid, e);
+                    }
+                }
+                Ok(completion) => debug!("Ignoring completion of job {}, which was not archived", completion.jobid),
+                Err(_) => {
+                    warn!("No longer receiving job completions");
+                    completions = never();
+                }
+            },
+            default(timeout) => if draining {
+                if let Some(captured) = held.pop_front() {
+                    let jobid = captured.jobid().to_owned();
+                    if store(archiver, captured, stats, tombstones, Some(sigchannel))? {
+                        archived.insert(&jobid);
+                    }
+                    stats.set_held(held.len());
+                    if held.is_empty() {
+                        info!("Archived the job entries held during maintenance");
+                    }
+                }
             }
         }
     }
@@ -128,41 +848,512 @@ pub fn process(
 mod tests {
 
     use super::*;
-    use crate::scheduler::job::JobInfo;
+    use crate::scheduler::job::{JobInfo, DELETED_EVENT};
     use crate::scheduler::slurm::SlurmJobEntry;
+    use crate::scheduler::torque::TorqueDeletionEntry;
+    use crate::testing::{FlakyArchiver, RecordingArchiver, SlowArchiver};
     use crossbeam_channel::unbounded;
     use crossbeam_utils::thread::scope;
     use std::env::current_dir;
-    use std::path::PathBuf;
     use std::thread::sleep;
     use std::time::Duration;
 
-    struct DummyArchiver;
+    #[test]
+    fn test_process_cleanup() {
+        let (tx1, rx1) = unbounded();
+        let (tx2, rx2) = unbounded();
+        let archiver = Box::new(RecordingArchiver::default());
 
-    impl Archive for DummyArchiver {
-        fn archive(&self, _: &Box<dyn JobInfo>) -> Result<(), Error> {
-            info!("Archiving");
-            Ok(())
+        scope(|s| {
+            let path = current_dir().unwrap().join("tests/job.123456");
+            let slurm_job_entry = SlurmJobEntry::new(&path, "123456", "mycluster");
+            s.spawn(move |_| {
+                match process(
+                    archiver.as_ref(),
+                    &rx1,
+                    &never(),
+                    &mut ArchivedJobs::default(),
+                    &mut Vec::new(),
+                    &rx2,
+                    &ProcessContext {
+                        stats: &Stats::new(),
+                        maintenance: &Maintenance::default(),
+                        reconciler: &Reconciler::default(),
+                        deadline: None,
+                        tombstones: false,
+                        cleanup: false,
+                        max_batch: 8,
+                    },
+                ) {
+                    Ok(v) => assert_eq!(v, ()),
+                    Err(_) => panic!("Unexpected error from process function"),
+                }
+            });
+            tx1.send(Box::new(slurm_job_entry)).unwrap();
+            sleep(Duration::from_millis(1000));
+            tx2.send(true).unwrap();
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn test_capture_deleted() {
+        let path = current_dir().unwrap().join("tests/job.123456");
+        let stats = Stats::new();
+        let reconciler = Reconciler::default();
+        reconciler.pending(&path);
+        reconciler.deleted(&path);
+
+        // the job files are still there, but the monitor saw the entry go
+        let entry = Box::new(SlurmJobEntry::new(&path, "123456", "mycluster"));
+        let captured = capture(entry, &stats, &reconciler, None).unwrap();
+        assert!(matches!(captured, Captured::Cancelled(_)));
+        assert_eq!(stats.cancelled_count(), 1);
+
+        let entry = Box::new(SlurmJobEntry::new(&path, "123456", "mycluster"));
+        let captured = capture(entry, &stats, &reconciler, None).unwrap();
+        assert!(matches!(captured, Captured::Job { .. }));
+    }
+
+    #[test]
+    fn test_process_completions() {
+        let (tx1, rx1) = unbounded();
+        let (tx2, rx2) = unbounded();
+        let (tx3, rx3) = unbounded();
+        let recording = RecordingArchiver::default();
+        let archiver = Box::new(recording.clone());
+        let completion = |jobid: &str| Completion {
+            jobid: jobid.to_owned(),
+            cluster: "mycluster".to_owned(),
+            ..Default::default()
+        };
+
+        scope(|s| {
+            s.spawn(|_| {
+                process(
+                    archiver.as_ref(),
+                    &rx1,
+                    &rx3,
+                    &mut ArchivedJobs::default(),
+                    &mut Vec::new(),
+                    &rx2,
+                    &ProcessContext {
+                        stats: &Stats::new(),
+                        maintenance: &Maintenance::default(),
+                        reconciler: &Reconciler::default(),
+                        deadline: None,
+                        tombstones: false,
+                        cleanup: false,
+                        max_batch: 8,
+                    },
+                )
+                .unwrap()
+            });
+            let path = current_dir().unwrap().join("tests/job.123456");
+            let entry: Box<dyn JobInfo> =
+                Box::new(SlurmJobEntry::new(&path, "123456", "mycluster"));
+            tx1.send(entry).unwrap();
+            sleep(Duration::from_millis(2500));
+
+            // Only the completion of the archived job is passed on, and only once
+            tx3.send(completion("999")).unwrap();
+            tx3.send(completion("123456")).unwrap();
+            tx3.send(completion("123456")).unwrap();
+            // A stopped tailer does not stop processing
+            drop(tx3);
+            sleep(Duration::from_millis(200));
+            tx2.send(true).unwrap();
+        })
+        .unwrap();
+
+        assert_eq!(recording.lines(), vec!["job 123456", "completion 123456"]);
+    }
+
+    #[test]
+    fn test_process_completions_unarchived() {
+        let (tx1, rx1) = unbounded();
+        let (tx2, rx2) = unbounded();
+        let (tx3, rx3) = unbounded();
+        let recording = RecordingArchiver::default();
+        let archiver = Box::new(recording.clone());
+        let path = current_dir().unwrap().join("tests/job.123456");
+        let reconciler = Reconciler::default();
+        reconciler.pending(&path);
+        reconciler.deleted(&path);
+
+        scope(|s| {
+            let rc = &reconciler;
+            s.spawn(|_| {
+                process(
+                    archiver.as_ref(),
+                    &rx1,
+                    &rx3,
+                    &mut ArchivedJobs::default(),
+                    &mut Vec::new(),
+                    &rx2,
+                    &ProcessContext {
+                        stats: &Stats::new(),
+                        maintenance: &Maintenance::default(),
+                        reconciler: rc,
+                        deadline: None,
+                        tombstones: true,
+                        cleanup: false,
+                        max_batch: 8,
+                    },
+                )
+                .unwrap()
+            });
+            let entry: Box<dyn JobInfo> =
+                Box::new(SlurmJobEntry::new(&path, "123456", "mycluster"));
+            tx1.send(entry).unwrap();
+            sleep(Duration::from_millis(500));
+
+            // the job only got a tombstone, so its completion is not passed on
+            tx3.send(Completion {
+                jobid: "123456".to_owned(),
+                cluster: "mycluster".to_owned(),
+                ..Default::default()
+            })
+            .unwrap();
+            sleep(Duration::from_millis(200));
+            tx2.send(true).unwrap();
+        })
+        .unwrap();
+
+        assert!(recording.completions().is_empty());
+    }
+
+    #[test]
+    fn test_archived_jobs() {
+        let owners: Vec<String> = ["1", "2", "2", "3"].iter().map(|s| s.to_string()).collect();
+        assert_eq!(
+            archived_jobs(&owners, &[true, false, true, true]),
+            vec!["1".to_owned(), "3".to_owned()]
+        );
+        assert_eq!(
+            archived_jobs(&owners, &[true, true, true, false]),
+            vec!["1".to_owned(), "2".to_owned()]
+        );
+    }
+
+    #[test]
+    fn test_process_maintenance() {
+        let tdir = tempfile::tempdir().unwrap();
+        let job_dir = tdir.path().join("job.123456");
+        std::fs::create_dir(&job_dir).unwrap();
+        let fixture = current_dir().unwrap().join("tests/job.123456");
+        for file in ["script", "environment"] {
+            std::fs::copy(fixture.join(file), job_dir.join(file)).unwrap();
         }
+
+        let (tx1, rx1) = unbounded();
+        let (tx2, rx2) = unbounded();
+        let (tx3, rx3) = unbounded();
+        let recording = RecordingArchiver::default();
+        let archiver = Box::new(recording.clone());
+        let stats = Stats::new();
+        let maintenance = Maintenance::default();
+        maintenance.pause();
+
+        scope(|s| {
+            let (st, m) = (&stats, &maintenance);
+            s.spawn(|_| {
+                process(
+                    archiver.as_ref(),
+                    &rx1,
+                    &rx3,
+                    &mut ArchivedJobs::default(),
+                    &mut Vec::new(),
+                    &rx2,
+                    &ProcessContext {
+                        stats: st,
+                        maintenance: m,
+                        reconciler: &Reconciler::default(),
+                        deadline: None,
+                        tombstones: false,
+                        cleanup: false,
+                        max_batch: 8,
+                    },
+                )
+                .unwrap()
+            });
+            let entry: Box<dyn JobInfo> =
+                Box::new(SlurmJobEntry::new(&job_dir, "123456", "mycluster"));
+            tx1.send(entry).unwrap();
+            sleep(Duration::from_millis(2500));
+
+            // the entry was captured, so it survives the job files going away
+            std::fs::remove_dir_all(&job_dir).unwrap();
+            tx3.send(Completion {
+                jobid: "123456".to_owned(),
+                cluster: "mycluster".to_owned(),
+                ..Default::default()
+            })
+            .unwrap();
+            sleep(Duration::from_millis(200));
+            assert!(stats.is_paused());
+            assert_eq!(stats.held_count(), 1);
+            assert!(recording.lines().is_empty());
+
+            maintenance.resume();
+            sleep(Duration::from_millis(1500));
+            tx2.send(true).unwrap();
+        })
+        .unwrap();
+
+        assert!(!stats.is_paused());
+        assert_eq!(stats.held_count(), 0);
+        assert_eq!(recording.lines(), vec!["job 123456", "completion 123456"]);
+        assert!(recording.jobs()[0].script().contains("echo"));
     }
 
     #[test]
-    fn test_process_cleanup() {
+    fn test_process_maintenance_stop() {
+        let tdir = tempfile::tempdir().unwrap();
+        let job_dir = tdir.path().join("job.123456");
+        std::fs::create_dir(&job_dir).unwrap();
+        let fixture = current_dir().unwrap().join("tests/job.123456");
+        for file in ["script", "environment"] {
+            std::fs::copy(fixture.join(file), job_dir.join(file)).unwrap();
+        }
+
         let (tx1, rx1) = unbounded();
         let (tx2, rx2) = unbounded();
-        let archiver = Box::new(DummyArchiver);
+        let recording = RecordingArchiver::default();
+        let archiver = Box::new(recording.clone());
+        let maintenance = Maintenance::default();
+        maintenance.pause();
+        let mut unarchived = Vec::new();
 
         scope(|s| {
-            let path = PathBuf::from(current_dir().unwrap().join("tests/job.123456"));
-            let slurm_job_entry = SlurmJobEntry::new(&path, "123456", "mycluster", &None);
-            s.spawn(move |_| match process(archiver, &rx1, &rx2, false) {
-                Ok(v) => assert_eq!(v, ()),
-                Err(_) => panic!("Unexpected error from process function"),
+            let (m, u) = (&maintenance, &mut unarchived);
+            s.spawn(move |_| {
+                process(
+                    archiver.as_ref(),
+                    &rx1,
+                    &never(),
+                    &mut ArchivedJobs::default(),
+                    u,
+                    &rx2,
+                    &ProcessContext {
+                        stats: &Stats::new(),
+                        maintenance: m,
+                        reconciler: &Reconciler::default(),
+                        deadline: None,
+                        tombstones: false,
+                        cleanup: false,
+                        max_batch: 8,
+                    },
+                )
+                .unwrap()
             });
-            tx1.send(Box::new(slurm_job_entry)).unwrap();
-            sleep(Duration::from_millis(1000));
+            let entry: Box<dyn JobInfo> =
+                Box::new(SlurmJobEntry::new(&job_dir, "123456", "mycluster"));
+            tx1.send(entry).unwrap();
+            sleep(Duration::from_millis(2500));
             tx2.send(true).unwrap();
         })
         .unwrap();
+
+        // the entry held when stopping is handed back, to be saved for the next run
+        assert!(recording.lines().is_empty());
+        assert_eq!(unarchived.len(), 1);
+        assert_eq!(unarchived[0].jobid(), "123456");
+        assert_eq!(unarchived[0].event_path(), Some(job_dir));
+        let state = tdir.path().join("state");
+        assert_eq!(crate::spill::save(&state, unarchived).unwrap(), 1);
+    }
+
+    #[test]
+    fn test_process_batches() {
+        let path = current_dir().unwrap().join("tests/job.123456");
+        let (tx1, rx1) = unbounded();
+        let (tx2, rx2) = unbounded();
+        let recording = RecordingArchiver::default();
+        let archiver = Box::new(recording.clone());
+        let stats = Stats::new();
+
+        // the entries queued behind the first one are archived with it
+        for jobid in 0..5 {
+            let entry: Box<dyn JobInfo> =
+                Box::new(SlurmJobEntry::new(&path, &jobid.to_string(), "mycluster"));
+            tx1.send(entry).unwrap();
+        }
+        scope(|s| {
+            let st = &stats;
+            s.spawn(|_| {
+                process(
+                    archiver.as_ref(),
+                    &rx1,
+                    &never(),
+                    &mut ArchivedJobs::default(),
+                    &mut Vec::new(),
+                    &rx2,
+                    &ProcessContext {
+                        stats: st,
+                        maintenance: &Maintenance::default(),
+                        reconciler: &Reconciler::default(),
+                        deadline: None,
+                        tombstones: false,
+                        cleanup: false,
+                        max_batch: 2,
+                    },
+                )
+                .unwrap()
+            });
+            sleep(Duration::from_millis(2500));
+            tx2.send(true).unwrap();
+        })
+        .unwrap();
+
+        // the last entry is archived on its own
+        assert_eq!(recording.batches(), vec![2, 2]);
+        assert_eq!(recording.jobs().len(), 5);
+        assert_eq!(stats.backends()["recording"].archived, 5);
+    }
+
+    #[test]
+    fn test_check_writable() {
+        let tdir = tempfile::tempdir().unwrap();
+        assert!(check_writable(tdir.path()).is_ok());
+        assert!(check_writable(&tdir.path().join("not/yet")).is_ok());
+        assert_eq!(std::fs::read_dir(tdir.path()).unwrap().count(), 0);
+
+        let file = tdir.path().join("file");
+        std::fs::write(&file, b"").unwrap();
+        assert!(check_writable(&file).is_err());
+    }
+
+    #[test]
+    fn test_handle_entry_vanished() {
+        let path = current_dir().unwrap().join("tests/job.vanished");
+        let entry = Box::new(SlurmJobEntry::new(&path, "vanished", "mycluster"));
+        let stats = Stats::new();
+
+        assert!(handle_entry(
+            &RecordingArchiver::default(),
+            entry,
+            &stats,
+            true,
+            None,
+            &Reconciler::default(),
+            None
+        )
+        .is_ok());
+        assert_eq!(stats.cancelled_count(), 1);
+        assert!(stats.backends().is_empty());
+    }
+
+    #[test]
+    fn test_handle_entry_deletion() {
+        let archiver = RecordingArchiver::default();
+        let stats = Stats::new();
+        // deletions get a tombstone, also when tombstones are not requested
+        let entry = Box::new(TorqueDeletionEntry::new("12.master", "mycluster"));
+        handle_entry(
+            &archiver,
+            entry,
+            &stats,
+            false,
+            None,
+            &Reconciler::default(),
+            None,
+        )
+        .unwrap();
+        assert_eq!(
+            archiver.lines(),
+            vec![format!("tombstone 12.master {DELETED_EVENT}")]
+        );
+        assert_eq!(stats.deleted_count(), 1);
+        assert_eq!(stats.cancelled_count(), 0);
+    }
+
+    /// Fails with ENOSPC the given number of times before succeeding
+    fn full_archiver(failures: usize) -> FlakyArchiver {
+        FlakyArchiver::new(failures, || Error::from_raw_os_error(libc::ENOSPC))
+    }
+
+    #[test]
+    fn test_archive_entry_standby() {
+        let path = current_dir().unwrap().join("tests/job.123456");
+        let entry = JobRecord::new(&SlurmJobEntry::new(&path, "123456", "mycluster"));
+        let stats = Stats::new();
+        let (tx, rx) = unbounded();
+
+        // Without a channel to wait on, the error is returned right away
+        let archiver = full_archiver(1);
+        assert!(is_storage_full(
+            &archive_entry(&archiver, &entry, &stats, None).unwrap_err()
+        ));
+
+        // A pending notification that is not a stop request makes us retry immediately
+        let archiver = full_archiver(1);
+        tx.send(false).unwrap();
+        assert!(archive_entry(&archiver, &entry, &stats, Some(&rx)).is_ok());
+        assert!(!stats.in_standby());
+        assert_eq!(stats.backends().get("flaky").unwrap().archived, 1);
+
+        // A stop request ends the standby
+        let archiver = full_archiver(2);
+        tx.send(true).unwrap();
+        assert!(archive_entry(&archiver, &entry, &stats, Some(&rx)).is_err());
+        assert!(stats.in_standby());
+    }
+
+    #[test]
+    fn test_archive_entry_deadline() {
+        let path = current_dir().unwrap().join("tests/job.123456");
+        let entry = JobRecord::new(&SlurmJobEntry::new(&path, "123456", "mycluster"));
+        let stats = Stats::new();
+        let deadline = Deadline {
+            limit: Duration::from_millis(100),
+            dead_letter: None,
+        };
+        let archiver = deadline::DeadlineArchive::new(
+            Box::new(SlowArchiver(Duration::from_secs(2))),
+            deadline,
+        );
+
+        // Running out of time is not fatal, the entry is counted and skipped
+        assert!(archive_entry(&archiver, &entry, &stats, None).is_ok());
+        let backend = stats.backends().get("slow").cloned().unwrap();
+        assert_eq!(backend.timeouts, 1);
+        assert_eq!(backend.archived, 0);
+        assert_eq!(backend.failed, 0);
+
+        // Nor is it in a batch, where the entries are not taken to be archived
+        let batch = [entry.clone(), entry.clone()];
+        assert_eq!(
+            archive_entries(&archiver, &batch, &stats, None).unwrap(),
+            vec![false, false]
+        );
+        let backend = stats.backends().get("slow").cloned().unwrap();
+        assert_eq!(backend.timeouts, 3);
+        assert_eq!(backend.failed, 0);
+    }
+
+    #[test]
+    fn test_archive_entry_circuit_breaker() {
+        let path = current_dir().unwrap().join("tests/job.123456");
+        let entry = JobRecord::new(&SlurmJobEntry::new(&path, "123456", "mycluster"));
+        let stats = Stats::new();
+        let (_tx, rx) = unbounded();
+
+        // Without a breaker, a failure is returned right away
+        let archiver = FlakyArchiver::down(1);
+        assert!(archive_entry(&archiver, &entry, &stats, Some(&rx)).is_err());
+
+        // The breaker holds the entry for the cooldown, then it is archived
+        let archiver = breaker::CircuitBreaker::new(
+            Box::new(FlakyArchiver::down(1)),
+            1,
+            Duration::from_millis(200),
+        );
+        assert!(archive_entry(&archiver, &entry, &stats, Some(&rx)).is_ok());
+        let backend = stats.backends().get("flaky").unwrap().clone();
+        assert_eq!(backend.archived, 1);
+        assert_eq!(backend.failed, 1);
+        assert_eq!(backend.circuit_trips, 1);
+        assert!(!backend.circuit_open);
     }
 }
@@ -0,0 +1,284 @@
+/*
+Copyright 2019-2024 Andy Georges <itkovian+sarchive@gmail.com>
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in
+all copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+use log::{debug, info, warn};
+use std::collections::HashSet;
+use std::fs::read_to_string;
+use std::io::Error;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering::SeqCst;
+use std::sync::{Arc, Mutex};
+
+use super::transform::{Step, Transform};
+use crate::completion::{split_id, Completion, REQUESTOR_FIELD, USER_FIELDS};
+use crate::scheduler::job::{JobRecord, OPTED_OUT_EVENT};
+
+/// The users who opted out of archival, by user name or uid, read from a file
+/// with one per line. Empty lines and lines starting with # are ignored.
+pub struct OptOutList {
+    path: PathBuf,
+    users: Mutex<HashSet<String>>,
+    /// Raised (e.g., on SIGHUP) to read the file again
+    pub reload: Arc<AtomicBool>,
+}
+
+/// Reads the user names and uids from the file
+fn read_users(path: &Path) -> Result<HashSet<String>, Error> {
+    Ok(read_to_string(path)?
+        .lines()
+        .map(|line| line.trim())
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| line.to_owned())
+        .collect())
+}
+
+impl OptOutList {
+    pub fn load(path: &Path) -> Result<Self, Error> {
+        let users = read_users(path)?;
+        info!(
+            "{} users opted out of archival, as listed in {:?}",
+            users.len(),
+            path
+        );
+        Ok(OptOutList {
+            path: path.to_path_buf(),
+            users: Mutex::new(users),
+            reload: Arc::new(AtomicBool::new(false)),
+        })
+    }
+
+    /// Reads the file again if asked to. If that fails, the list is kept.
+    fn refresh(&self) {
+        if !self.reload.swap(false, SeqCst) {
+            return;
+        }
+        match read_users(&self.path) {
+            Ok(users) => {
+                info!(
+                    "Reloaded {:?}, {} users opted out of archival",
+                    &self.path,
+                    users.len()
+                );
+                *self.users.lock().unwrap_or_else(|e| e.into_inner()) = users;
+            }
+            Err(e) => warn!("Cannot reload {:?}, keeping the list: {}", &self.path, e),
+        }
+    }
+
+    /// Whether any of the given user names or uids opted out
+    pub fn contains<'a, I>(&self, ids: I) -> bool
+    where
+        I: IntoIterator<Item = &'a str>,
+    {
+        self.refresh();
+        let users = self.users.lock().unwrap_or_else(|e| e.into_inner());
+        ids.into_iter().any(|id| users.contains(id))
+    }
+}
+
+/// The stage that keeps the jobs of the users who opted out from being
+/// archived. Only a tombstone, without the script, environment or user,
+/// records such a job, and its completion is dropped.
+impl Transform for OptOutList {
+    fn apply(&self, job: JobRecord) -> Result<Step, Error> {
+        let (user, uid) = (&job.user, &job.uid);
+        if !self.contains(user.iter().chain(uid.iter()).map(|id| id.as_str())) {
+            return Ok(Step::Keep(job));
+        }
+        info!(
+            "The user of job {} opted out of archival, archiving a tombstone only",
+            job.jobid
+        );
+        // only what identifies the job is kept
+        let mut tombstone = job.bare();
+        tombstone.tombstone_event = OPTED_OUT_EVENT.to_owned();
+        Ok(Step::Tombstone(tombstone))
+    }
+
+    /// Artefacts do not tell the user of the job, so they are dropped
+    fn completion(&self, completion: Completion) -> Result<Option<Completion>, Error> {
+        if let Some(kind) = completion.artefact() {
+            debug!(
+                "Cannot tell whether the user of job {} opted out, dropping its {} artefact",
+                completion.jobid, kind
+            );
+            return Ok(None);
+        }
+        // e.g., UserId=alice(1000) or owner=alice@login1, but not the
+        // requestor, who may have deleted the job of another user
+        let ids = USER_FIELDS
+            .iter()
+            .filter(|field| **field != REQUESTOR_FIELD)
+            .filter_map(|field| completion.fields.get(*field))
+            .flat_map(|value| {
+                let (name, uid, _) = split_id(value);
+                std::iter::once(name).chain(uid)
+            })
+            .filter(|id| !id.is_empty());
+        if self.contains(ids) {
+            debug!(
+                "The user of job {} opted out of archival, dropping its completion",
+                completion.jobid
+            );
+            return Ok(None);
+        }
+        Ok(Some(completion))
+    }
+
+    fn name(&self) -> String {
+        "opt-out".to_owned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::accounting::parse_record;
+    use crate::archive::document::tombstone_document;
+    use crate::archive::transform::{Pipeline, TransformArchive};
+    use crate::archive::Archive;
+    use crate::identity::Identity;
+    use crate::scheduler::job::JobInfo;
+    use crate::scheduler::torque::TorqueOutputEntry;
+    use crate::testing::{slurm_job, RecordingArchiver};
+    use std::fs::write;
+    use tempfile::tempdir;
+
+    fn job(tdir: &Path, jobid: &str, user: &str, uid: &str) -> JobRecord {
+        let env = format!("\0\0\0\0SLURM_JOB_USER={user}\0SLURM_JOB_UID={uid}\0");
+        slurm_job(tdir, jobid, "#!/bin/bash\n", env.as_bytes())
+    }
+
+    fn completion(jobid: &str, user: &str) -> Completion {
+        let mut completion = Completion {
+            jobid: jobid.to_owned(),
+            ..Default::default()
+        };
+        completion
+            .fields
+            .insert("UserId".to_owned(), user.to_owned());
+        completion
+    }
+
+    #[test]
+    fn test_opt_out() {
+        let tdir = tempdir().unwrap();
+        let list = tdir.path().join("opt-out");
+        write(&list, "# legal request 2024-17\nalice\n\n2000\n").unwrap();
+
+        let recording = RecordingArchiver::default();
+        let opt_out = OptOutList::load(&list).unwrap();
+        let reload = Arc::clone(&opt_out.reload);
+        let archive = TransformArchive::new(
+            Box::new(recording.clone()),
+            Pipeline::new(vec![Box::new(opt_out)]),
+        );
+        archive
+            .archive(&job(tdir.path(), "1", "alice", "1000"))
+            .unwrap();
+        archive
+            .archive(&job(tdir.path(), "2", "bob", "2000"))
+            .unwrap();
+        archive
+            .archive(&job(tdir.path(), "3", "carol", "3000"))
+            .unwrap();
+        archive
+            .archive_completion(&completion("1", "alice(1000)"))
+            .unwrap();
+        archive
+            .archive_completion(&completion("3", "carol(3000)"))
+            .unwrap();
+
+        // the list is read again when asked to
+        write(&list, "carol\n").unwrap();
+        reload.store(true, SeqCst);
+        archive
+            .archive(&job(tdir.path(), "4", "alice", "1000"))
+            .unwrap();
+        archive
+            .archive(&job(tdir.path(), "5", "carol", "3000"))
+            .unwrap();
+
+        // the user of a Torque job is named in its accounting records
+        for line in [
+            "04/17/2024 10:22:33;S;6.master;user=carol group=users owner=carol@login start=1713342153",
+            // an administrator who opted out deleted the job of another user
+            "04/17/2024 10:25:00;D;7.master;requestor=carol@login",
+        ] {
+            let completion = parse_record(line, "mycluster").unwrap();
+            archive.archive_completion(&completion).unwrap();
+        }
+
+        assert_eq!(
+            recording.lines(),
+            vec![
+                "tombstone 1 opted_out",
+                "tombstone 2 opted_out",
+                "job 3",
+                "completion 3",
+                "job 4",
+                "tombstone 5 opted_out",
+                "completion 7.master",
+            ]
+        );
+        // the tombstones do not tell whose jobs they stand for
+        for tombstone in recording.tombstones() {
+            let doc = tombstone_document(&tombstone, &Identity::default());
+            assert!(doc.get("user").is_none());
+        }
+    }
+
+    #[test]
+    fn test_opt_out_output() {
+        let tdir = tempdir().unwrap();
+        let output = tdir.path().join("8.master.OU");
+        write(&output, "done\n").unwrap();
+        let mut entry = TorqueOutputEntry::new(&output, "8.master", "mycluster", 1024);
+        entry.read_job_info().unwrap();
+        let job = JobRecord::new(&entry);
+
+        // the output belongs to the owner of the file
+        let list = tdir.path().join("opt-out");
+        let recording = RecordingArchiver::default();
+        write(&list, format!("{}\n", unsafe { libc::getuid() })).unwrap();
+        let archive = TransformArchive::new(
+            Box::new(recording.clone()),
+            Pipeline::new(vec![Box::new(OptOutList::load(&list).unwrap())]),
+        );
+        archive.archive(&job).unwrap();
+        assert_eq!(recording.lines(), vec!["tombstone 8.master opted_out"]);
+    }
+
+    #[test]
+    fn test_opt_out_reload_failure() {
+        let tdir = tempdir().unwrap();
+        let path = tdir.path().join("opt-out");
+        write(&path, "alice\n").unwrap();
+        let list = OptOutList::load(&path).unwrap();
+
+        std::fs::remove_file(&path).unwrap();
+        list.reload.store(true, SeqCst);
+        assert!(list.contains(["alice"]));
+        assert!(OptOutList::load(&path).is_err());
+    }
+}
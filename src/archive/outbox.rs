@@ -0,0 +1,616 @@
+/*
+Copyright 2019-2024 Andy Georges <itkovian+sarchive@gmail.com>
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in
+all copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+use chrono::{DateTime, Utc};
+use clap::Args;
+use crossbeam_channel::{Receiver, RecvTimeoutError};
+use log::{debug, error, info, warn};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::fs::{create_dir, create_dir_all, read, read_dir, remove_dir_all, rename, File};
+use std::io::{self, Error, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use super::{archive_entry, check_writable, Archive, ArchiverArgs};
+use crate::completion::Completion;
+use crate::maintenance::Maintenance;
+use crate::scheduler::job::{lookup, JobInfo, JobRecord, CANCELLED_EVENT, UID_VARIABLES};
+use crate::stats::Stats;
+use crate::utils::JobContext;
+
+/// Directory in the outbox where entries are put together before they are
+/// moved into place, so the shipper never sees a partial entry
+const INCOMING: &str = ".incoming";
+
+/// Directory in the outbox where entries that cannot be read are moved to
+const REJECTED: &str = ".rejected";
+
+/// File in each entry holding everything but the job files
+const ENTRY_FILE: &str = "entry.json";
+
+/// Command line options for the outbox archiver subcommand
+#[derive(Args, Debug)]
+pub struct OutboxArgs {
+    /// Directory to capture the job entries to, shipped by `sarchive ship`
+    path: PathBuf,
+}
+
+/// Command line options for the ship subcommand
+#[derive(Args, Debug)]
+pub struct ShipArgs {
+    #[arg(long, help = "Outbox directory the capturing instance writes to")]
+    pub outbox: PathBuf,
+
+    #[arg(
+        long,
+        value_name = "SECONDS",
+        default_value_t = 5,
+        help = "Time between checks of the outbox, and before retrying a failed entry"
+    )]
+    pub interval: u64,
+
+    #[command(subcommand)]
+    pub archiver: ArchiverArgs,
+}
+
+/// An archiver that captures the job entries to a local directory, from which
+/// a separate `sarchive ship` process sends them to the actual archiver
+///
+/// Each job, tombstone or completion becomes a directory holding an
+/// `entry.json` file and the job files, named after the time of the event so
+/// the entries are shipped in order. The files are flushed to disk before the
+/// directory is moved into place.
+pub struct OutboxArchive {
+    path: PathBuf,
+}
+
+impl OutboxArchive {
+    pub fn new(path: &Path) -> Self {
+        OutboxArchive {
+            path: path.to_path_buf(),
+        }
+    }
+
+    pub fn build(args: &OutboxArgs) -> Result<Self, Error> {
+        info!("Capturing the job entries to outbox {:?}", &args.path);
+        create_dir_all(args.path.join(INCOMING))?;
+        Ok(OutboxArchive::new(&args.path))
+    }
+
+    /// Writes the entry with the given document and job files to the outbox
+    fn write_entry(
+        &self,
+        time: DateTime<Utc>,
+        doc: &Value,
+        job_entry: Option<&JobRecord>,
+    ) -> Result<(), Error> {
+        let name = format!(
+            "{:020}-{}-{}-{}",
+            time.timestamp_nanos_opt().unwrap_or_default(),
+            doc["kind"].as_str().unwrap_or_default(),
+            sanitize(doc["cluster"].as_str().unwrap_or_default()),
+            sanitize(doc["id"].as_str().unwrap_or_default()),
+        );
+        let incoming = self.path.join(INCOMING);
+        create_dir_all(&incoming)?;
+        let tmp_dir = incoming.join(&name);
+        if tmp_dir.exists() {
+            remove_dir_all(&tmp_dir)?;
+        }
+        create_dir(&tmp_dir)?;
+
+        let write = |path: &Path, contents: &[u8]| -> Result<(), Error> {
+            let mut f = File::create(path)?;
+            f.write_all(contents)?;
+            f.sync_all()
+        };
+        if let Some(job_entry) = job_entry {
+            let files = tmp_dir.join("files");
+            create_dir(&files)?;
+            for (fname, contents) in job_entry.files.iter() {
+                write(&files.join(sanitize(fname)), contents)?;
+            }
+            if !job_entry.streamed_files.is_empty() {
+                create_dir(tmp_dir.join("streamed"))?;
+            }
+            for (fname, source) in job_entry.streamed_files.iter() {
+                let mut f = File::create(tmp_dir.join("streamed").join(sanitize(fname)))?;
+                io::copy(&mut File::open(source)?, &mut f)?;
+                f.sync_all()?;
+            }
+        }
+        write(&tmp_dir.join(ENTRY_FILE), doc.to_string().as_bytes())?;
+
+        rename(&tmp_dir, self.path.join(&name))?;
+        File::open(&self.path)?.sync_all()?;
+        debug!("Captured {} in outbox {:?}", name, &self.path);
+        Ok(())
+    }
+}
+
+/// Replaces the characters that cannot be part of a file name
+fn sanitize(s: &str) -> String {
+    s.replace(['/', '\0'], "_")
+}
+
+impl Archive for OutboxArchive {
+    fn archive(&self, job_entry: &JobRecord) -> Result<(), Error> {
+        debug!(
+            "Outbox archiver, received an entry for job ID {}",
+            job_entry.jobid()
+        );
+        let doc = json!({
+            "kind": "job",
+            "id": job_entry.jobid(),
+            "cluster": job_entry.cluster(),
+            "event_time": job_entry.event_time(),
+            "submit_time": job_entry.submit_time(),
+            "job_name": job_entry.job_name(),
+            "user": job_entry.user(),
+            "partition": job_entry.partition(),
+            "script": job_entry.script(),
+            "environment": job_entry.extra_info(),
+            "missing_files": job_entry.missing_files(),
+        });
+        self.write_entry(job_entry.event_time(), &doc, Some(job_entry))
+    }
+
+    fn archive_tombstone(&self, job_entry: &JobRecord) -> Result<(), Error> {
+        debug!(
+            "Outbox archiver, received a tombstone for job ID {}",
+            job_entry.jobid()
+        );
+        let doc = json!({
+            "kind": "tombstone",
+            "id": job_entry.jobid(),
+            "cluster": job_entry.cluster(),
+            "event_time": job_entry.event_time(),
+            "submit_time": job_entry.submit_time(),
+            "event": job_entry.tombstone_event(),
+        });
+        self.write_entry(job_entry.event_time(), &doc, None)
+    }
+
+    fn archive_completion(&self, completion: &Completion) -> Result<(), Error> {
+        debug!(
+            "Outbox archiver, received the completion of job ID {}",
+            completion.jobid
+        );
+        let doc = json!({
+            "kind": "completion",
+            "id": completion.jobid,
+            "cluster": completion.cluster,
+            "state": completion.state,
+            "exit_code": completion.exit_code,
+            "start_time": completion.start_time,
+            "end_time": completion.end_time,
+            "fields": completion.fields,
+        });
+        self.write_entry(Utc::now(), &doc, None)
+    }
+
+    fn check(&self, _cluster: &str) -> Result<(), Error> {
+        check_writable(&self.path.join(INCOMING))
+    }
+
+    fn name(&self) -> &str {
+        "outbox"
+    }
+}
+
+/// An entry read back from the outbox
+pub enum Outboxed {
+    Job(JobRecord),
+    Tombstone(JobRecord),
+    Completion(Completion),
+}
+
+/// Returns the names and contents of the files in the given directory of the
+/// entry, if it exists
+fn read_files(dir: &Path) -> Result<Vec<(String, PathBuf)>, Error> {
+    if !dir.is_dir() {
+        return Ok(Vec::new());
+    }
+    let mut files = Vec::new();
+    for file in read_dir(dir)? {
+        let file = file?;
+        files.push((file.file_name().to_string_lossy().into_owned(), file.path()));
+    }
+    Ok(files)
+}
+
+/// Reads the entry in the given outbox directory
+pub fn load(dir: &Path) -> Result<Outboxed, Error> {
+    let invalid = |what: &str| Error::new(ErrorKind::InvalidData, format!("{what} in {dir:?}"));
+    let doc: Value = serde_json::from_slice(&read(dir.join(ENTRY_FILE))?)?;
+    let string = |key: &str| doc[key].as_str().map(|s| s.to_owned());
+    let strings = |key: &str| -> Option<HashMap<String, String>> {
+        doc[key].as_object().map(|o| {
+            o.iter()
+                .map(|(k, v)| (k.clone(), v.as_str().unwrap_or_default().to_owned()))
+                .collect()
+        })
+    };
+    let time = |key: &str| string(key).and_then(|t| t.parse::<DateTime<Utc>>().ok());
+
+    let jobid = string("id").ok_or_else(|| invalid("No job ID"))?;
+    let cluster = string("cluster").ok_or_else(|| invalid("No cluster"))?;
+    let kind = string("kind").unwrap_or_default();
+    if kind == "completion" {
+        return Ok(Outboxed::Completion(Completion {
+            jobid,
+            cluster,
+            state: string("state"),
+            exit_code: string("exit_code"),
+            start_time: string("start_time"),
+            end_time: string("end_time"),
+            fields: strings("fields").unwrap_or_default(),
+        }));
+    }
+
+    let event_time = time("event_time").ok_or_else(|| invalid("No event time"))?;
+    // measure the latency from the original event rather than from now
+    let age = (Utc::now() - event_time).to_std().unwrap_or_default();
+    let mut files = Vec::new();
+    for (fname, path) in read_files(&dir.join("files"))? {
+        files.push((fname, read(path)?));
+    }
+    files.sort();
+    let environment = strings("environment");
+    let entry = JobRecord {
+        jobid,
+        cluster,
+        moment: Instant::now().checked_sub(age).unwrap_or_else(Instant::now),
+        event_time,
+        submit_time: time("submit_time"),
+        event_path: None,
+        script: string("script").unwrap_or_default(),
+        files,
+        file_sources: HashMap::new(),
+        streamed_files: read_files(&dir.join("streamed"))?.into_iter().collect(),
+        uid: environment
+            .as_ref()
+            .and_then(|env| lookup(env, &UID_VARIABLES)),
+        extra: environment,
+        // the environment was processed before the job was captured
+        has_environment: false,
+        missing_files: doc["missing_files"]
+            .as_array()
+            .map(|a| {
+                a.iter()
+                    .filter_map(|m| m.as_str().map(|s| s.to_owned()))
+                    .collect()
+            })
+            .unwrap_or_default(),
+        job_name: string("job_name"),
+        user: string("user"),
+        partition: string("partition"),
+        tombstone_event: string("event").unwrap_or_else(|| CANCELLED_EVENT.to_owned()),
+    };
+    match kind.as_str() {
+        "job" => Ok(Outboxed::Job(entry)),
+        "tombstone" => Ok(Outboxed::Tombstone(entry)),
+        _ => Err(invalid(&format!("Unknown kind {kind:?}"))),
+    }
+}
+
+/// Returns the entries waiting in the outbox, oldest first
+fn pending(outbox: &Path) -> Result<Vec<PathBuf>, Error> {
+    let mut entries = Vec::new();
+    for entry in read_dir(outbox)? {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() && !entry.file_name().to_string_lossy().starts_with('.') {
+            entries.push(entry.path());
+        }
+    }
+    entries.sort();
+    Ok(entries)
+}
+
+/// Sends the entry in the given outbox directory to the archiver
+fn ship_entry(
+    archiver: &dyn Archive,
+    entry: Outboxed,
+    stats: &Stats,
+    sigchannel: &Receiver<bool>,
+) -> Result<(), Error> {
+    match entry {
+        Outboxed::Job(entry) => {
+            let _context = JobContext::enter(&entry.cluster, &entry.jobid);
+            archive_entry(archiver, &entry, stats, Some(sigchannel))
+        }
+        Outboxed::Tombstone(entry) => {
+            let _context = JobContext::enter(&entry.cluster, &entry.jobid);
+            archiver.archive_tombstone(&entry)
+        }
+        Outboxed::Completion(completion) => {
+            let _context = JobContext::enter(&completion.cluster, &completion.jobid);
+            archiver.archive_completion(&completion)
+        }
+    }
+}
+
+/// Ships the entries captured in the outbox to the archiver, removing each
+/// once it is archived, until a notification to stop arrives on the channel
+///
+/// The outbox is checked every interval. When an entry cannot be archived,
+/// it and the entries after it are tried again at the next check, so they
+/// are shipped in order. Entries that cannot be read are moved aside. During
+/// maintenance, the entries stay in the outbox.
+pub fn ship(
+    archiver: &dyn Archive,
+    outbox: &Path,
+    interval: Duration,
+    sigchannel: &Receiver<bool>,
+    stats: &Stats,
+    maintenance: &Maintenance,
+) -> Result<(), Error> {
+    info!("Shipping the job entries in outbox {:?}", outbox);
+    loop {
+        let paused = maintenance.paused();
+        if paused != stats.is_paused() {
+            if paused {
+                info!("Pausing shipping for maintenance");
+            } else {
+                info!("Resuming shipping");
+            }
+            stats.set_paused(paused);
+        }
+        let entries = if paused { Vec::new() } else { pending(outbox)? };
+        for dir in entries {
+            if let Ok(true) = sigchannel.try_recv() {
+                info!("Stopped shipping");
+                return Ok(());
+            }
+            let entry = match load(&dir) {
+                Ok(entry) => entry,
+                // only an entry that can be read but not parsed is given up on
+                Err(e) if matches!(e.kind(), ErrorKind::InvalidData | ErrorKind::UnexpectedEof) => {
+                    let rejected = outbox.join(REJECTED);
+                    warn!(
+                        "Cannot read outbox entry {:?}, moving it to {:?}: {}",
+                        &dir, &rejected, e
+                    );
+                    create_dir_all(&rejected)?;
+                    rename(&dir, rejected.join(dir.file_name().unwrap_or_default()))?;
+                    continue;
+                }
+                Err(e) => {
+                    error!(
+                        "Cannot load outbox entry {:?}, retrying in {}s: {}",
+                        &dir,
+                        interval.as_secs(),
+                        e
+                    );
+                    break;
+                }
+            };
+            match ship_entry(archiver, entry, stats, sigchannel) {
+                Ok(()) => remove_dir_all(&dir)?,
+                Err(e) => {
+                    error!(
+                        "Cannot ship outbox entry {:?}, retrying in {}s: {}",
+                        &dir,
+                        interval.as_secs(),
+                        e
+                    );
+                    break;
+                }
+            }
+        }
+        match sigchannel.recv_timeout(interval) {
+            Ok(true) | Err(RecvTimeoutError::Disconnected) => {
+                info!("Stopped shipping");
+                return Ok(());
+            }
+            _ => (),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::archive::file::{FileArchive, Period};
+    use crate::testing::{record, FlakyArchiver, RecordingArchiver};
+    use crossbeam_channel::bounded;
+    use std::fs::{read_to_string, write};
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_outbox_roundtrip() {
+        let outbox = tempdir().unwrap();
+        let archive = OutboxArchive::new(outbox.path());
+        archive.check("mycluster").unwrap();
+        let original = record();
+        archive.archive(&original).unwrap();
+
+        let dirs = pending(outbox.path()).unwrap();
+        assert_eq!(dirs.len(), 1);
+        assert!(!outbox
+            .path()
+            .join(INCOMING)
+            .join(dirs[0].file_name().unwrap())
+            .exists());
+        let Outboxed::Job(loaded) = load(&dirs[0]).unwrap() else {
+            panic!("Expected a job entry");
+        };
+        assert_eq!(loaded.jobid(), original.jobid());
+        assert_eq!(loaded.cluster(), original.cluster());
+        assert_eq!(loaded.event_time(), original.event_time());
+        assert_eq!(loaded.script(), original.script());
+        assert_eq!(loaded.job_name(), original.job_name());
+        assert_eq!(loaded.extra_info(), original.extra_info());
+        let mut files = original.files();
+        files.sort();
+        assert_eq!(loaded.files(), files);
+        assert!(loaded.moment() <= Instant::now());
+    }
+
+    #[test]
+    fn test_ship() {
+        let outbox = tempdir().unwrap();
+        let archive_dir = tempdir().unwrap();
+        let capture = OutboxArchive::new(outbox.path());
+        capture.archive(&record()).unwrap();
+
+        let archiver = FileArchive::new(&archive_dir.path().to_path_buf(), &Period::None);
+        let (sig_sender, sig_receiver) = bounded(1);
+        sig_sender.send(true).unwrap();
+        ship_entry(
+            &archiver,
+            load(&pending(outbox.path()).unwrap()[0]).unwrap(),
+            &Stats::new(),
+            &sig_receiver,
+        )
+        .unwrap();
+
+        let script = read_to_string(archive_dir.path().join("job.123456_script")).unwrap();
+        assert_eq!(script, record().script());
+        assert!(archive_dir.path().join("job.123456_environment").exists());
+    }
+
+    #[test]
+    fn test_ship_in_order() {
+        let outbox = tempdir().unwrap();
+        let capture = OutboxArchive::new(outbox.path());
+        let job = record();
+        capture.archive(&job).unwrap();
+        capture.archive_tombstone(&job).unwrap();
+        capture
+            .archive_completion(&Completion {
+                jobid: "123456".to_owned(),
+                cluster: "mycluster".to_owned(),
+                state: Some("COMPLETED".to_owned()),
+                ..Default::default()
+            })
+            .unwrap();
+
+        // an entry that cannot be read is moved aside
+        let broken = outbox.path().join("00000000000000000000-job-mycluster-1");
+        create_dir(&broken).unwrap();
+        write(broken.join(ENTRY_FILE), "{}").unwrap();
+
+        let archiver = RecordingArchiver::default();
+        let (sig_sender, sig_receiver) = bounded(1);
+        sig_sender.send(false).unwrap();
+        let (s, r) = (&sig_sender, &sig_receiver);
+        crossbeam_utils::thread::scope(|scope| {
+            scope.spawn(move |_| {
+                std::thread::sleep(Duration::from_millis(200));
+                s.send(true).unwrap();
+            });
+            ship(
+                &archiver,
+                outbox.path(),
+                Duration::from_millis(50),
+                r,
+                &Stats::new(),
+                &Maintenance::default(),
+            )
+            .unwrap();
+        })
+        .unwrap();
+
+        assert_eq!(
+            archiver.lines(),
+            vec![
+                "job 123456",
+                "tombstone 123456 cancelled_before_capture",
+                "completion 123456"
+            ]
+        );
+        assert!(pending(outbox.path()).unwrap().is_empty());
+        assert!(outbox
+            .path()
+            .join(REJECTED)
+            .join("00000000000000000000-job-mycluster-1")
+            .exists());
+    }
+
+    #[test]
+    fn test_ship_backend_error() {
+        let outbox = tempdir().unwrap();
+        OutboxArchive::new(outbox.path())
+            .archive(&record())
+            .unwrap();
+
+        // the entry itself is fine, so it is kept to be shipped again
+        let (sig_sender, sig_receiver) = bounded(1);
+        let (s, r) = (&sig_sender, &sig_receiver);
+        crossbeam_utils::thread::scope(|scope| {
+            scope.spawn(move |_| {
+                std::thread::sleep(Duration::from_millis(200));
+                s.send(true).unwrap();
+            });
+            ship(
+                &FlakyArchiver::failing(|| Error::new(ErrorKind::InvalidData, "bad response")),
+                outbox.path(),
+                Duration::from_millis(50),
+                r,
+                &Stats::new(),
+                &Maintenance::default(),
+            )
+            .unwrap();
+        })
+        .unwrap();
+
+        assert_eq!(pending(outbox.path()).unwrap().len(), 1);
+        assert!(!outbox.path().join(REJECTED).exists());
+    }
+
+    #[test]
+    fn test_ship_paused() {
+        let outbox = tempdir().unwrap();
+        OutboxArchive::new(outbox.path())
+            .archive(&record())
+            .unwrap();
+
+        let archiver = RecordingArchiver::default();
+        let maintenance = Maintenance::default();
+        maintenance.pause();
+        let stats = Stats::new();
+        let (sig_sender, sig_receiver) = bounded(1);
+        let (s, r) = (&sig_sender, &sig_receiver);
+        crossbeam_utils::thread::scope(|scope| {
+            scope.spawn(move |_| {
+                std::thread::sleep(Duration::from_millis(200));
+                s.send(true).unwrap();
+            });
+            ship(
+                &archiver,
+                outbox.path(),
+                Duration::from_millis(50),
+                r,
+                &stats,
+                &maintenance,
+            )
+            .unwrap();
+        })
+        .unwrap();
+
+        assert!(stats.is_paused());
+        assert!(archiver.lines().is_empty());
+        assert_eq!(pending(outbox.path()).unwrap().len(), 1);
+    }
+}
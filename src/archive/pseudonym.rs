@@ -0,0 +1,380 @@
+/*
+Copyright 2019-2024 Andy Georges <itkovian+sarchive@gmail.com>
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in
+all copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+use hmac::{Hmac, Mac};
+use log::{info, warn};
+use sha2::Sha256;
+use std::collections::{HashMap, HashSet};
+use std::fs::{read, read_to_string, OpenOptions};
+use std::io::{Error, ErrorKind, Write};
+use std::os::unix::fs::{MetadataExt, OpenOptionsExt};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use super::dedup::hex;
+use super::transform::{Step, Transform};
+use crate::completion::{split_id, Completion, GROUP_FIELDS, USER_FIELDS};
+use crate::scheduler::job::{lookup, JobRecord, UID_VARIABLES, USER_VARIABLES};
+
+/// Number of hex digits of the HMAC kept in a pseudonym
+const PSEUDONYM_LENGTH: usize = 16;
+
+/// Returns the HMAC-SHA256 (RFC 2104) of the message with the given key
+pub fn hmac_sha256(key: &[u8], message: &[u8]) -> [u8; 32] {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC takes keys of any size");
+    mac.update(message);
+    mac.finalize().into_bytes().into()
+}
+
+/// Replaces user names and uids by pseudonyms, keyed with a site secret so
+/// they cannot be reversed by hashing candidate names. The pseudonyms are
+/// written, with what they stand for, to a local mapping file.
+pub struct Pseudonymizer {
+    key: Vec<u8>,
+    map_path: Option<PathBuf>,
+    /// The identifiers already in the mapping file
+    recorded: Mutex<HashSet<String>>,
+}
+
+impl Pseudonymizer {
+    pub fn new(key: &[u8]) -> Self {
+        Pseudonymizer {
+            key: key.to_vec(),
+            map_path: None,
+            recorded: Mutex::new(HashSet::new()),
+        }
+    }
+
+    /// Reads the secret from the key file and the pseudonyms recorded so far
+    /// from the mapping file, which is created if needed
+    pub fn load(key_file: &Path, map_path: Option<&Path>) -> Result<Self, Error> {
+        if key_file.metadata()?.mode() & 0o077 != 0 {
+            warn!(
+                "Pseudonymization key {:?} is readable by others than its owner",
+                key_file
+            );
+        }
+        let mut key = read(key_file)?;
+        while key.last().is_some_and(u8::is_ascii_whitespace) {
+            key.pop();
+        }
+        if key.is_empty() {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("Pseudonymization key {key_file:?} is empty"),
+            ));
+        }
+        let mut pseudonymizer = Pseudonymizer::new(&key);
+        if let Some(path) = map_path {
+            let recorded = match read_to_string(path) {
+                Ok(contents) => contents
+                    .lines()
+                    .filter_map(|line| line.split_once('\t'))
+                    .map(|(_, identifier)| identifier.to_owned())
+                    .collect(),
+                Err(e) if e.kind() == ErrorKind::NotFound => HashSet::new(),
+                Err(e) => return Err(e),
+            };
+            info!(
+                "Recording pseudonyms in {:?}, which has {} so far",
+                path,
+                recorded.len()
+            );
+            pseudonymizer.map_path = Some(path.to_path_buf());
+            pseudonymizer.recorded = Mutex::new(recorded);
+        }
+        Ok(pseudonymizer)
+    }
+
+    /// Returns the pseudonym for the identifier, recording it in the mapping
+    /// file the first time it is seen
+    pub fn pseudonym(&self, identifier: &str) -> Result<String, Error> {
+        let pseudonym =
+            hex(&hmac_sha256(&self.key, identifier.as_bytes()))[..PSEUDONYM_LENGTH].to_owned();
+        if let Some(path) = &self.map_path {
+            let mut recorded = self.recorded.lock().unwrap_or_else(|e| e.into_inner());
+            if !recorded.contains(identifier) {
+                let mut map = OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .mode(0o600)
+                    .open(path)?;
+                writeln!(map, "{pseudonym}\t{identifier}")?;
+                map.sync_data()?;
+                recorded.insert(identifier.to_owned());
+            }
+        }
+        Ok(pseudonym)
+    }
+
+    /// Returns the environment with the user names and uids replaced, also
+    /// where a user name is a component of a path (e.g., in `HOME`)
+    fn environment(
+        &self,
+        env: &HashMap<String, String>,
+        users: &[String],
+    ) -> Result<HashMap<String, String>, Error> {
+        let mut pseudonymized = HashMap::new();
+        for (key, value) in env.iter() {
+            let value = if USER_VARIABLES.contains(&key.as_str())
+                || UID_VARIABLES.contains(&key.as_str())
+            {
+                self.pseudonym(value)?
+            } else {
+                self.paths(value, users)?
+            };
+            pseudonymized.insert(key.clone(), value);
+        }
+        Ok(pseudonymized)
+    }
+
+    /// Replaces the path components that are one of the user names
+    fn paths(&self, text: &str, users: &[String]) -> Result<String, Error> {
+        if !users.iter().any(|u| text.contains(u.as_str())) {
+            return Ok(text.to_owned());
+        }
+        let mut components = Vec::new();
+        for component in text.split('/') {
+            if users.iter().any(|u| u == component) {
+                components.push(self.pseudonym(component)?);
+            } else {
+                components.push(component.to_owned());
+            }
+        }
+        Ok(components.join("/"))
+    }
+
+    /// Returns the value of a user or group field with the name and the id
+    /// replaced each by its own pseudonym, e.g., `alice(1000)`, so they match
+    /// the pseudonyms of the user and uid of the job. A host, as in
+    /// `alice@login1`, is kept.
+    fn id_field(&self, value: &str) -> Result<String, Error> {
+        let (name, id, host) = split_id(value);
+        let mut pseudonymized = self.pseudonym(name)?;
+        if let Some(id) = id {
+            pseudonymized = format!("{pseudonymized}({})", self.pseudonym(id)?);
+        }
+        if let Some(host) = host {
+            pseudonymized = format!("{pseudonymized}@{host}");
+        }
+        Ok(pseudonymized)
+    }
+
+    /// Returns the job record with the user names and uids replaced. The job
+    /// files are kept as they are.
+    fn job(&self, mut job_entry: JobRecord) -> Result<JobRecord, Error> {
+        let mut users: Vec<String> = job_entry.user.iter().cloned().collect();
+        if let Some(env) = &job_entry.extra {
+            users.extend(
+                USER_VARIABLES
+                    .iter()
+                    .filter_map(|v| env.get(*v))
+                    .filter(|u| !u.is_empty())
+                    .cloned(),
+            );
+        }
+        users.sort();
+        users.dedup();
+
+        let environment = job_entry
+            .extra
+            .as_ref()
+            .map(|env| self.environment(env, &users))
+            .transpose()?;
+        job_entry.script = self.paths(&job_entry.script, &users)?;
+        job_entry.user = job_entry
+            .user
+            .as_ref()
+            .map(|u| self.pseudonym(u))
+            .transpose()?;
+        job_entry.uid = environment
+            .as_ref()
+            .and_then(|env| lookup(env, &UID_VARIABLES));
+        job_entry.extra = environment;
+        Ok(job_entry)
+    }
+}
+
+/// The stage that gives the records pseudonyms instead of user names and
+/// uids: the user, the user and uid variables in the environment, paths in
+/// the environment and script that contain the user name, and the user and
+/// group of a completion. The job files are kept as they are, so the file
+/// archiver still stores the spool files as they are.
+impl Transform for Pseudonymizer {
+    fn apply(&self, job: JobRecord) -> Result<Step, Error> {
+        self.job(job).map(Step::Keep)
+    }
+
+    fn completion(&self, mut completion: Completion) -> Result<Option<Completion>, Error> {
+        for field in USER_FIELDS.iter().chain(GROUP_FIELDS.iter()) {
+            if let Some(value) = completion.fields.get_mut(*field) {
+                *value = self.id_field(value)?;
+            }
+        }
+        Ok(Some(completion))
+    }
+
+    fn name(&self) -> String {
+        "pseudonymize".to_owned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::accounting::parse_record;
+    use crate::archive::jsonl::JsonlArchive;
+    use crate::archive::transform::{Pipeline, TransformArchive};
+    use crate::archive::Archive;
+    use crate::scheduler::job::JobInfo;
+    use crate::testing::{slurm_job, RecordingArchiver};
+    use std::fs::{metadata, write};
+    use std::os::unix::fs::PermissionsExt;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_hmac_sha256() {
+        // RFC 4231, test cases 2 and 6
+        assert_eq!(
+            hex(&hmac_sha256(b"Jefe", b"what do ya want for nothing?")),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+        assert_eq!(
+            hex(&hmac_sha256(
+                &[0xaa; 131],
+                b"Test Using Larger Than Block-Size Key - Hash Key First"
+            )),
+            "60e431591ee0b67f0d8a26aacbf5b77f8e0bc6213728c5140546040f0ee37f54"
+        );
+    }
+
+    #[test]
+    fn test_pseudonym() {
+        let tdir = tempdir().unwrap();
+        let key_file = tdir.path().join("key");
+        write(&key_file, "site secret\n").unwrap();
+        let map = tdir.path().join("pseudonyms");
+
+        let pseudonymizer = Pseudonymizer::load(&key_file, Some(&map)).unwrap();
+        let alice = pseudonymizer.pseudonym("alice").unwrap();
+        assert_eq!(alice.len(), PSEUDONYM_LENGTH);
+        assert_eq!(pseudonymizer.pseudonym("alice").unwrap(), alice);
+        assert_ne!(pseudonymizer.pseudonym("bob").unwrap(), alice);
+        assert_ne!(
+            Pseudonymizer::new(b"other").pseudonym("alice").unwrap(),
+            alice
+        );
+
+        assert_eq!(metadata(&map).unwrap().permissions().mode() & 0o777, 0o600);
+        let recorded = read_to_string(&map).unwrap();
+        assert_eq!(recorded.lines().count(), 2);
+        assert!(recorded.contains(&format!("{alice}\talice\n")));
+
+        // a restart does not record the known pseudonyms again
+        let pseudonymizer = Pseudonymizer::load(&key_file, Some(&map)).unwrap();
+        pseudonymizer.pseudonym("alice").unwrap();
+        assert_eq!(read_to_string(&map).unwrap(), recorded);
+
+        write(&key_file, "\n").unwrap();
+        assert!(Pseudonymizer::load(&key_file, None).is_err());
+    }
+
+    #[test]
+    fn test_pseudonymizing_archive() {
+        let tdir = tempdir().unwrap();
+        let entry = slurm_job(
+            tdir.path(),
+            "1",
+            "#!/bin/bash\ncd /home/alice/run\n",
+            b"\0\0\0\0USER=alice\0SLURM_JOB_UID=1000\0HOME=/home/alice\0PWD=/data/alicesmith\0",
+        );
+
+        let recording = RecordingArchiver::default();
+        let pseudonymizer = Pseudonymizer::new(b"secret");
+        let alice = pseudonymizer.pseudonym("alice").unwrap();
+        let uid = pseudonymizer.pseudonym("1000").unwrap();
+        let archive = TransformArchive::new(
+            Box::new(recording.clone()),
+            Pipeline::new(vec![Box::new(pseudonymizer)]),
+        );
+        archive.archive(&entry).unwrap();
+
+        let job = recording.jobs().pop().unwrap();
+        let env = job.extra_info().unwrap();
+        assert_eq!(job.user(), Some(alice.clone()));
+        assert_eq!(env["USER"], alice);
+        assert_eq!(env["SLURM_JOB_UID"], uid);
+        assert_eq!(env["HOME"], format!("/home/{alice}"));
+        assert_eq!(env["PWD"], "/data/alicesmith");
+        assert_eq!(job.script(), format!("#!/bin/bash\ncd /home/{alice}/run\n"));
+
+        // the user of a completion gets the pseudonyms of the job
+        let mut completion = Completion::default();
+        completion
+            .fields
+            .insert("UserId".to_owned(), "alice(1000)".to_owned());
+        completion
+            .fields
+            .insert("GroupId".to_owned(), "users(100)".to_owned());
+        archive.archive_completion(&completion).unwrap();
+        let completion = recording.completions().pop().unwrap();
+        assert_eq!(completion.fields["UserId"], format!("{alice}({uid})"));
+        let users = Pseudonymizer::new(b"secret").pseudonym("users").unwrap();
+        let gid = Pseudonymizer::new(b"secret").pseudonym("100").unwrap();
+        assert_eq!(completion.fields["GroupId"], format!("{users}({gid})"));
+        assert_eq!(archive.name(), "recording");
+
+        // and so does the user in a Torque accounting record
+        let line = "04/17/2024 10:30:00;E;1.master;user=alice group=users owner=alice@login requestor=alice@login Exit_status=0";
+        let completion = parse_record(line, "mycluster").unwrap();
+        archive.archive_completion(&completion).unwrap();
+        let completion = recording.completions().pop().unwrap();
+        assert_eq!(completion.fields["user"], alice);
+        assert_eq!(completion.fields["group"], users);
+        assert_eq!(completion.fields["owner"], format!("{alice}@login"));
+        assert_eq!(completion.fields["requestor"], format!("{alice}@login"));
+    }
+
+    #[test]
+    fn test_pseudonymized_payload_size() {
+        let tdir = tempdir().unwrap();
+        let entry = slurm_job(
+            tdir.path(),
+            "1",
+            "#!/bin/bash\ncd /home/alice/run\n",
+            b"\0\0\0\0USER=alice\0",
+        );
+
+        // the size is that of the line written, with the pseudonyms
+        let jsonl = JsonlArchive::new(tdir.path(), "jobs");
+        let archive = TransformArchive::new(
+            Box::new(jsonl),
+            Pipeline::new(vec![Box::new(Pseudonymizer::new(b"secret"))]),
+        );
+        archive.archive(&entry).unwrap();
+        let written = metadata(tdir.path().join("jobs.jsonl")).unwrap().len();
+        assert_eq!(archive.payload_size(&entry), written);
+        assert!(!read_to_string(tdir.path().join("jobs.jsonl"))
+            .unwrap()
+            .contains("alice"));
+    }
+}
@@ -0,0 +1,286 @@
+/*
+Copyright 2019-2024 Andy Georges <itkovian+sarchive@gmail.com>
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in
+all copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+use clap::Args;
+use log::{debug, info, warn};
+use serde_json::Value;
+use std::fs::{metadata, File, OpenOptions};
+use std::io::{Error, Write};
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::{FileTypeExt, OpenOptionsExt};
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use super::document::{
+    completion_document, document_size, job_document, tombstone_document, PayloadSizes,
+    RecordOptions,
+};
+use super::Archive;
+use crate::completion::Completion;
+use crate::identity::Identity;
+use crate::scheduler::job::{JobInfo, JobRecord};
+
+/// Command line options for the socket archiver subcommand
+#[derive(Args, Debug)]
+pub struct SocketArgs {
+    /// Unix domain socket or named pipe (FIFO) to write the records to
+    path: PathBuf,
+}
+
+/// An archiver that streams the job records to a local consumer
+///
+/// Each record is a JSON document, preceded by its length in bytes as a
+/// 32-bit big-endian integer. The connection (or pipe) is opened on first use
+/// and reopened after the consumer goes away.
+pub struct SocketArchive {
+    path: PathBuf,
+    connection: Mutex<Option<Box<dyn Write + Send>>>,
+    identity: Identity,
+    options: RecordOptions,
+    payload_sizes: PayloadSizes,
+}
+
+impl SocketArchive {
+    pub fn new(path: &Path) -> Self {
+        SocketArchive {
+            path: path.to_path_buf(),
+            connection: Mutex::new(None),
+            identity: Identity::default(),
+            options: RecordOptions::default(),
+            payload_sizes: PayloadSizes::default(),
+        }
+    }
+
+    pub fn build(
+        args: &SocketArgs,
+        identity: &Identity,
+        options: &RecordOptions,
+    ) -> Result<Self, Error> {
+        info!("Using socket archival to {:?}", &args.path);
+        if !args.path.exists() {
+            warn!(
+                "{:?} does not exist (yet), records will fail until the consumer creates it",
+                &args.path
+            );
+        }
+        let mut archive = SocketArchive::new(&args.path);
+        archive.identity = identity.clone();
+        archive.options = options.clone();
+        Ok(archive)
+    }
+
+    /// Connects to the socket or opens the FIFO
+    ///
+    /// A FIFO is opened without blocking, so this fails rather than hangs when
+    /// no consumer has it open for reading.
+    fn connect(&self) -> Result<Box<dyn Write + Send>, Error> {
+        if metadata(&self.path)?.file_type().is_fifo() {
+            let fifo = OpenOptions::new()
+                .write(true)
+                .custom_flags(libc::O_NONBLOCK)
+                .open(&self.path)?;
+            set_blocking(&fifo)?;
+            Ok(Box::new(fifo))
+        } else {
+            Ok(Box::new(UnixStream::connect(&self.path)?))
+        }
+    }
+
+    /// Writes the length-prefixed document, reconnecting once if the
+    /// consumer went away since the last record
+    fn send(&self, doc: &Value) -> Result<(), Error> {
+        let payload = serde_json::to_vec(doc)?;
+        let length = u32::try_from(payload.len()).map_err(Error::other)?;
+        let mut record = length.to_be_bytes().to_vec();
+        record.extend(payload);
+
+        let mut connection = self.connection.lock().unwrap_or_else(|e| e.into_inner());
+        let writer = match connection.take() {
+            Some(mut writer) => match write_record(writer.as_mut(), &record) {
+                Ok(()) => writer,
+                Err(e) => {
+                    debug!(
+                        "Lost the connection to {:?}, reconnecting: {}",
+                        &self.path, e
+                    );
+                    let mut writer = self.connect()?;
+                    write_record(writer.as_mut(), &record)?;
+                    writer
+                }
+            },
+            None => {
+                let mut writer = self.connect()?;
+                write_record(writer.as_mut(), &record)?;
+                writer
+            }
+        };
+        *connection = Some(writer);
+        Ok(())
+    }
+}
+
+/// Writes the record and flushes it to the consumer
+fn write_record(writer: &mut dyn Write, record: &[u8]) -> Result<(), Error> {
+    writer.write_all(record)?;
+    writer.flush()
+}
+
+/// Clears O_NONBLOCK on the file
+fn set_blocking(file: &File) -> Result<(), Error> {
+    let fd = file.as_raw_fd();
+    // SAFETY: fcntl on a valid, open file descriptor
+    let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+    if flags < 0 || unsafe { libc::fcntl(fd, libc::F_SETFL, flags & !libc::O_NONBLOCK) } < 0 {
+        return Err(Error::last_os_error());
+    }
+    Ok(())
+}
+
+impl Archive for SocketArchive {
+    fn archive(&self, job_entry: &JobRecord) -> Result<(), Error> {
+        debug!(
+            "Socket archiver, received an entry for job ID {}",
+            job_entry.jobid()
+        );
+        let doc = job_document(job_entry, &self.identity, &self.options);
+        self.send(&doc)?;
+        self.payload_sizes.sent(job_entry, document_size(&doc));
+        Ok(())
+    }
+
+    fn payload_size(&self, job_entry: &JobRecord) -> u64 {
+        self.payload_sizes.take(job_entry)
+    }
+
+    fn archive_tombstone(&self, job_entry: &JobRecord) -> Result<(), Error> {
+        debug!(
+            "Socket archiver, received a tombstone for job ID {}",
+            job_entry.jobid()
+        );
+        self.send(&tombstone_document(job_entry, &self.identity))
+    }
+
+    fn archive_completion(&self, completion: &Completion) -> Result<(), Error> {
+        debug!(
+            "Socket archiver, received the completion of job ID {}",
+            completion.jobid
+        );
+        self.send(&completion_document(completion, &self.identity))
+    }
+
+    /// Checks that a consumer is listening on the socket or FIFO, keeping
+    /// the connection for the first record
+    fn check(&self, _cluster: &str) -> Result<(), Error> {
+        let writer = self.connect().map_err(|e| {
+            Error::new(e.kind(), format!("Cannot connect to {:?}: {e}", &self.path))
+        })?;
+        *self.connection.lock().unwrap_or_else(|e| e.into_inner()) = Some(writer);
+        Ok(())
+    }
+
+    fn name(&self) -> &str {
+        "socket"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::scheduler::slurm::SlurmJobEntry;
+    use std::env::current_dir;
+    use std::ffi::CString;
+    use std::io::Read;
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::net::UnixListener;
+    use tempfile::tempdir;
+
+    fn job_entry() -> JobRecord {
+        let path = current_dir().unwrap().join("tests/job.123456");
+        let mut entry = SlurmJobEntry::new(&path, "123456", "mycluster");
+        entry.read_job_info().unwrap();
+        JobRecord::new(&entry)
+    }
+
+    fn read_record(reader: &mut impl Read) -> Value {
+        let mut length = [0u8; 4];
+        reader.read_exact(&mut length).unwrap();
+        let mut payload = vec![0u8; u32::from_be_bytes(length) as usize];
+        reader.read_exact(&mut payload).unwrap();
+        serde_json::from_slice(&payload).unwrap()
+    }
+
+    #[test]
+    fn test_socket() {
+        let tdir = tempdir().unwrap();
+        let path = tdir.path().join("sarchive.sock");
+        let listener = UnixListener::bind(&path).unwrap();
+        let archive = SocketArchive::new(&path);
+        let entry = job_entry();
+
+        archive.archive(&entry).unwrap();
+        archive.archive_tombstone(&entry).unwrap();
+        let (mut stream, _) = listener.accept().unwrap();
+        assert_eq!(read_record(&mut stream)["id"], "123456");
+        assert_eq!(
+            read_record(&mut stream)["event"],
+            "cancelled_before_capture"
+        );
+
+        // The consumer restarts
+        drop(stream);
+        archive.archive(&entry).unwrap();
+        let (mut stream, _) = listener.accept().unwrap();
+        assert_eq!(read_record(&mut stream)["cluster"], "mycluster");
+    }
+
+    #[test]
+    fn test_fifo() {
+        let tdir = tempdir().unwrap();
+        let path = tdir.path().join("sarchive.fifo");
+        let cpath = CString::new(path.as_os_str().as_bytes()).unwrap();
+        assert_eq!(unsafe { libc::mkfifo(cpath.as_ptr(), 0o600) }, 0);
+        let archive = SocketArchive::new(&path);
+        let entry = job_entry();
+
+        // Without a reader, we fail instead of blocking
+        assert!(archive.check("mycluster").is_err());
+        assert!(archive.archive(&entry).is_err());
+
+        let mut reader = OpenOptions::new()
+            .read(true)
+            .custom_flags(libc::O_NONBLOCK)
+            .open(&path)
+            .unwrap();
+        archive.archive(&entry).unwrap();
+        set_blocking(&reader).unwrap();
+        assert_eq!(read_record(&mut reader)["id"], "123456");
+    }
+
+    #[test]
+    fn test_missing() {
+        let tdir = tempdir().unwrap();
+        let archive = SocketArchive::new(&tdir.path().join("absent.sock"));
+        assert!(archive.check("mycluster").is_err());
+        assert!(archive.archive(&job_entry()).is_err());
+    }
+}
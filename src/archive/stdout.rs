@@ -0,0 +1,122 @@
+/*
+Copyright 2019-2024 Andy Georges <itkovian+sarchive@gmail.com>
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in
+all copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+use log::debug;
+use serde_json::Value;
+use std::io::{stdout, Error, Write};
+
+use super::document::{
+    completion_document, document_size, job_document, tombstone_document, PayloadSizes,
+    RecordOptions,
+};
+use super::Archive;
+use crate::completion::Completion;
+use crate::identity::Identity;
+use crate::scheduler::job::{JobInfo, JobRecord};
+
+/// An archiver that prints a JSON document per job on standard output, e.g.,
+/// to leave the transport to the log pipeline of a container platform
+pub struct StdoutArchive {
+    identity: Identity,
+    options: RecordOptions,
+    payload_sizes: PayloadSizes,
+}
+
+impl StdoutArchive {
+    pub fn new(identity: &Identity, options: &RecordOptions) -> Self {
+        StdoutArchive {
+            identity: identity.clone(),
+            options: options.clone(),
+            payload_sizes: PayloadSizes::default(),
+        }
+    }
+}
+
+/// Writes the document as a single line and flushes it
+fn write_line(out: &mut impl Write, doc: &Value) -> Result<(), Error> {
+    serde_json::to_writer(&mut *out, doc)?;
+    out.write_all(b"\n")?;
+    out.flush()
+}
+
+impl Archive for StdoutArchive {
+    fn archive(&self, job_entry: &JobRecord) -> Result<(), Error> {
+        debug!(
+            "Stdout archiver, received an entry for job ID {}",
+            job_entry.jobid()
+        );
+        let doc = job_document(job_entry, &self.identity, &self.options);
+        write_line(&mut stdout().lock(), &doc)?;
+        self.payload_sizes.sent(job_entry, document_size(&doc));
+        Ok(())
+    }
+
+    fn payload_size(&self, job_entry: &JobRecord) -> u64 {
+        self.payload_sizes.take(job_entry)
+    }
+
+    fn archive_tombstone(&self, job_entry: &JobRecord) -> Result<(), Error> {
+        debug!(
+            "Stdout archiver, received a tombstone for job ID {}",
+            job_entry.jobid()
+        );
+        let doc = tombstone_document(job_entry, &self.identity);
+        write_line(&mut stdout().lock(), &doc)
+    }
+
+    fn archive_completion(&self, completion: &Completion) -> Result<(), Error> {
+        debug!(
+            "Stdout archiver, received the completion of job ID {}",
+            completion.jobid
+        );
+        write_line(
+            &mut stdout().lock(),
+            &completion_document(completion, &self.identity),
+        )
+    }
+
+    fn name(&self) -> &str {
+        "stdout"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_write_line() {
+        let mut out = Vec::new();
+        write_line(&mut out, &json!({"id": "1", "script": "a\nb"})).unwrap();
+        write_line(&mut out, &json!({"id": "2"})).unwrap();
+
+        let out = String::from_utf8(out).unwrap();
+        let lines: Vec<&str> = out.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(
+            serde_json::from_str::<Value>(lines[0]).unwrap()["script"],
+            "a\nb"
+        );
+        assert_eq!(serde_json::from_str::<Value>(lines[1]).unwrap()["id"], "2");
+    }
+}
@@ -0,0 +1,968 @@
+/*
+Copyright 2019-2024 Andy Georges <itkovian+sarchive@gmail.com>
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in
+all copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+use log::{debug, info, warn};
+use regex::Regex;
+use serde_json::json;
+use std::fmt;
+use std::io::{Error, Write};
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::Duration;
+
+use super::document::PayloadSizes;
+use super::lineproto::{parse_endpoint, post, Endpoint};
+use super::Archive;
+use crate::completion::Completion;
+use crate::scheduler::environment::edit_environment;
+use crate::scheduler::job::JobRecord;
+
+/// Value put in place of the redacted environment values
+pub const REDACTED: &str = "[redacted]";
+
+/// Prefix of the keys under which the directives stage puts the options in
+/// the extra info
+pub const DIRECTIVE_PREFIX: &str = "sarchive_directive_";
+
+/// Key under which the lint stage lists its findings in the extra info
+pub const LINT_KEY: &str = "sarchive_lint";
+
+/// Directive options that set the time limit of a job. For PBS, it is the
+/// walltime resource of -l.
+const TIME_OPTIONS: [&str; 3] = ["t", "time", "W"];
+
+/// Slurm options that were removed or renamed
+const DEPRECATED_OPTIONS: [&str; 4] = ["workdir", "share", "checkpoint", "checkpoint-dir"];
+
+/// Lines holding scheduler directives start with one of these
+const DIRECTIVE_PREFIXES: [&str; 3] = ["#SBATCH", "#PBS", "#BSUB"];
+
+/// Program that sends the notification mails, reading the message with its
+/// headers from its input
+const SENDMAIL: &str = "/usr/sbin/sendmail";
+
+/// What a stage makes of a job
+pub enum Step {
+    /// Pass the job, changed as the stage saw fit, on to the next stage
+    Keep(JobRecord),
+    /// Leave the job out
+    Skip,
+    /// Archive only the given tombstone for the job
+    Tombstone(JobRecord),
+}
+
+/// A stage of the record pipeline, between the schedulers and the archivers.
+/// It gets the record of a job and returns what becomes of it. A job may go
+/// through the pipeline more than once, e.g., when the backend failed, so
+/// applying a stage should have no side effects other than idempotent ones.
+/// An error fails the archival of the job, which is then retried.
+pub trait Transform: Send + Sync {
+    fn apply(&self, job: JobRecord) -> Result<Step, Error>;
+
+    // Return the completion as it should be archived, or None to drop it.
+    // Stages that leave the completions alone need not implement this.
+    fn completion(&self, completion: Completion) -> Result<Option<Completion>, Error> {
+        Ok(Some(completion))
+    }
+
+    // Return the notifications to send about the job, once it is archived.
+    // Stages that notify nobody need not implement this.
+    fn notices(&self, _job: &JobRecord) -> Vec<Notice> {
+        Vec::new()
+    }
+
+    // Return the name of the stage, used when logging
+    fn name(&self) -> String;
+}
+
+/// A field of the job record a stage can match on
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Field {
+    Cluster,
+    JobName,
+    Partition,
+    User,
+}
+
+impl Field {
+    fn get(self, job: &JobRecord) -> Option<&str> {
+        match self {
+            Field::Cluster => Some(job.cluster.as_str()),
+            Field::JobName => job.job_name.as_deref(),
+            Field::Partition => job.partition.as_deref(),
+            Field::User => job.user.as_deref(),
+        }
+    }
+}
+
+impl fmt::Display for Field {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Field::Cluster => "cluster",
+            Field::JobName => "job_name",
+            Field::Partition => "partition",
+            Field::User => "user",
+        };
+        write!(f, "{name}")
+    }
+}
+
+/// Where the notifications of the notify stage go
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Recipient {
+    /// A mail address, the mail is handed to sendmail
+    Mail(String),
+    /// A webhook, given as the address and the path, to post a JSON document to
+    Webhook(String, String),
+}
+
+impl fmt::Display for Recipient {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Recipient::Mail(address) => write!(f, "mailto:{address}"),
+            Recipient::Webhook(address, path) => write!(f, "http://{address}{path}"),
+        }
+    }
+}
+
+impl Recipient {
+    fn parse(s: &str) -> Result<Recipient, String> {
+        if let Some(address) = s.strip_prefix("mailto:").filter(|a| a.contains('@')) {
+            return Ok(Recipient::Mail(address.to_owned()));
+        }
+        match parse_endpoint(s) {
+            Ok(Endpoint::Http(address, path)) => Ok(Recipient::Webhook(address, path)),
+            _ => Err(format!(
+                "Invalid recipient {s:?}, expected mailto:ADDRESS or http://HOST:PORT/PATH"
+            )),
+        }
+    }
+
+    /// Tells the recipient about the lines of the job script that matched
+    fn notify(&self, job: &JobRecord, pattern: &Regex, lines: &[&str]) -> Result<(), Error> {
+        match self {
+            Recipient::Mail(address) => {
+                let message = format!(
+                    "To: {address}\nSubject: sarchive: job {} on {} matched {}\n\nJob {} of user {} on cluster {} has these lines matching {}:\n\n{}\n",
+                    job.jobid,
+                    job.cluster,
+                    pattern,
+                    job.jobid,
+                    job.user.as_deref().unwrap_or("unknown"),
+                    job.cluster,
+                    pattern,
+                    lines.join("\n")
+                );
+                let mut sendmail = Command::new(SENDMAIL)
+                    .arg("-t")
+                    .stdin(Stdio::piped())
+                    .spawn()?;
+                sendmail
+                    .stdin
+                    .take()
+                    .unwrap()
+                    .write_all(message.as_bytes())?;
+                match sendmail.wait()? {
+                    status if status.success() => Ok(()),
+                    status => Err(Error::other(format!("{SENDMAIL} failed: {status}"))),
+                }
+            }
+            Recipient::Webhook(address, path) => {
+                let doc = json!({
+                    "id": job.jobid,
+                    "cluster": job.cluster,
+                    "user": job.user,
+                    "partition": job.partition,
+                    "pattern": pattern.as_str(),
+                    "lines": lines,
+                });
+                post(address, path, "application/json", &doc.to_string())
+            }
+        }
+    }
+}
+
+/// A notification about the lines of a job script that matched a pattern
+pub struct Notice {
+    recipient: Recipient,
+    pattern: Regex,
+    /// Only what the notification tells about the job
+    about: JobRecord,
+    lines: Vec<String>,
+}
+
+impl Notice {
+    /// Sends the notification on a thread of its own, so it does not hold up
+    /// archival
+    fn send(self) {
+        info!(
+            "Job {} matched {}, notifying {}",
+            self.about.jobid, self.pattern, self.recipient
+        );
+        thread::spawn(move || {
+            let lines: Vec<&str> = self.lines.iter().map(|l| l.as_str()).collect();
+            if let Err(e) = self.recipient.notify(&self.about, &self.pattern, &lines) {
+                warn!(
+                    "Cannot notify {} about job {}: {}",
+                    self.recipient, self.about.jobid, e
+                );
+            }
+        });
+    }
+}
+
+/// The stages that come with sarchive
+#[derive(Clone, Debug)]
+pub enum Stage {
+    /// Replace the values of the environment variables whose name matches,
+    /// also in the environment files
+    Redact(Regex),
+    /// Leave out the environment variables whose name matches, also from the
+    /// environment files
+    DropEnv(Regex),
+    /// Leave out the jobs whose field matches
+    Skip(Field, Regex),
+    /// Add the key and value to the extra info
+    Label(String, String),
+    /// Add the options in the scheduler directives of the script to the
+    /// extra info
+    Directives,
+    /// Tell the recipient about the jobs with lines in their script that
+    /// match, keeping the job as it is
+    Notify(Recipient, Regex),
+    /// Add the common mistakes found in the script to the extra info
+    Lint,
+}
+
+/// Parses a stage given as `redact:REGEX`, `drop-env:REGEX`,
+/// `skip:FIELD=REGEX`, `label:KEY=VALUE`, `directives` or
+/// `notify:RECIPIENT REGEX` or `lint`
+pub fn parse_stage(s: &str) -> Result<Stage, String> {
+    let (kind, arg) = s.split_once(':').unwrap_or((s, ""));
+    let regex = |r: &str| Regex::new(r).map_err(|e| format!("Invalid regex {r:?}: {e}"));
+    let pair = || {
+        arg.split_once('=')
+            .filter(|(k, _)| !k.is_empty())
+            .ok_or_else(|| format!("Expected {kind}:NAME=VALUE, got {s:?}"))
+    };
+    match kind {
+        "redact" => Ok(Stage::Redact(regex(arg)?)),
+        "drop-env" => Ok(Stage::DropEnv(regex(arg)?)),
+        "skip" => {
+            let (field, r) = pair()?;
+            let field = match field {
+                "cluster" => Field::Cluster,
+                "job_name" => Field::JobName,
+                "partition" => Field::Partition,
+                "user" => Field::User,
+                _ => return Err(format!("Unknown field {field:?} in {s:?}, expected cluster, job_name, partition or user")),
+            };
+            Ok(Stage::Skip(field, regex(r)?))
+        }
+        "label" => {
+            let (key, value) = pair()?;
+            Ok(Stage::Label(key.to_owned(), value.to_owned()))
+        }
+        "directives" if arg.is_empty() => Ok(Stage::Directives),
+        "lint" if arg.is_empty() => Ok(Stage::Lint),
+        "notify" => {
+            let (recipient, r) = arg
+                .split_once(' ')
+                .ok_or_else(|| format!("Expected notify:RECIPIENT REGEX, got {s:?}"))?;
+            Ok(Stage::Notify(Recipient::parse(recipient)?, regex(r)?))
+        }
+        _ => Err(format!(
+            "Unknown stage {s:?}, expected redact:REGEX, drop-env:REGEX, skip:FIELD=REGEX, label:KEY=VALUE, directives, notify:RECIPIENT REGEX or lint"
+        )),
+    }
+}
+
+/// Returns the options in the scheduler directives of the script, in order.
+/// Options without a value get an empty one.
+pub fn directives(script: &str) -> Vec<(String, String)> {
+    let mut options = Vec::new();
+    for line in script.lines() {
+        let mut words = line.split_whitespace().peekable();
+        if !words
+            .next()
+            .is_some_and(|p| DIRECTIVE_PREFIXES.contains(&p))
+        {
+            continue;
+        }
+        while let Some(word) = words.next() {
+            let Some(option) = word.strip_prefix('-') else {
+                continue;
+            };
+            let option = option.trim_start_matches('-');
+            if let Some((name, value)) = option.split_once('=') {
+                options.push((name.to_owned(), value.to_owned()));
+            } else {
+                let value = words.next_if(|w| !w.starts_with('-')).unwrap_or_default();
+                options.push((option.to_owned(), value.to_owned()));
+            }
+        }
+    }
+    options
+}
+
+/// Returns the common mistakes found in the script:
+///
+/// - `no_time_limit`: the directives do not set a time limit
+/// - `deprecated:OPTION`: a directive uses a removed or renamed option
+/// - `late_directive`: a directive comes after the first command, so the
+///   scheduler ignores it
+/// - `no_errexit`: a bash script does not stop at the first failing command
+///   (`set -e`)
+pub fn lint(script: &str) -> Vec<String> {
+    let mut findings = Vec::new();
+    // the scheduler reads the directives up to the first command
+    let lines: Vec<&str> = script.lines().collect();
+    let header = lines
+        .iter()
+        .position(|l| !l.trim().is_empty() && !l.trim().starts_with('#'))
+        .unwrap_or(lines.len());
+    let time_limit = directives(&lines[..header].join("\n"))
+        .iter()
+        .any(|(name, value)| {
+            TIME_OPTIONS.contains(&name.as_str()) || (name == "l" && value.contains("walltime="))
+        });
+    if !time_limit {
+        findings.push("no_time_limit".to_owned());
+    }
+    for (name, _) in directives(script) {
+        let finding = format!("deprecated:{name}");
+        if DEPRECATED_OPTIONS.contains(&name.as_str()) && !findings.contains(&finding) {
+            findings.push(finding);
+        }
+    }
+    if lines[header..].iter().any(|l| {
+        let first = l.split_whitespace().next().unwrap_or_default();
+        DIRECTIVE_PREFIXES.contains(&first)
+    }) {
+        findings.push("late_directive".to_owned());
+    }
+
+    let shebang = script.lines().next().unwrap_or_default();
+    let errexit = Regex::new(r"(?m)^\s*set\s+(-[a-zA-Z]*e|-o\s+errexit)").unwrap();
+    if shebang.starts_with("#!")
+        && shebang.contains("bash")
+        && !shebang
+            .split_whitespace()
+            .skip(1)
+            .any(|w| w.starts_with('-') && w.contains('e'))
+        && !errexit.is_match(script)
+    {
+        findings.push("no_errexit".to_owned());
+    }
+    findings
+}
+
+impl Transform for Stage {
+    fn apply(&self, mut job: JobRecord) -> Result<Step, Error> {
+        match self {
+            Stage::Redact(regex) => {
+                for (_, value) in job
+                    .extra
+                    .iter_mut()
+                    .flatten()
+                    .filter(|(key, _)| regex.is_match(key))
+                {
+                    *value = REDACTED.to_owned();
+                }
+                edit_environment(&mut job, |key, value| {
+                    Some(if regex.is_match(key) { REDACTED } else { value }.to_owned())
+                });
+            }
+            Stage::DropEnv(regex) => {
+                if let Some(extra) = job.extra.as_mut() {
+                    extra.retain(|key, _| !regex.is_match(key));
+                }
+                edit_environment(&mut job, |key, value| {
+                    (!regex.is_match(key)).then(|| value.to_owned())
+                });
+            }
+            Stage::Skip(field, regex) => {
+                if field.get(&job).is_some_and(|v| regex.is_match(v)) {
+                    return Ok(Step::Skip);
+                }
+            }
+            Stage::Label(key, value) => {
+                job.extra
+                    .get_or_insert_with(Default::default)
+                    .insert(key.clone(), value.clone());
+            }
+            Stage::Directives => {
+                let options = directives(&job.script);
+                let extra = job.extra.get_or_insert_with(Default::default);
+                for (name, value) in options {
+                    extra.insert(format!("{DIRECTIVE_PREFIX}{name}"), value);
+                }
+            }
+            Stage::Lint => {
+                let findings = lint(&job.script);
+                if !findings.is_empty() {
+                    debug!("Job {} has lint findings {:?}", job.jobid, findings);
+                    job.extra
+                        .get_or_insert_with(Default::default)
+                        .insert(LINT_KEY.to_owned(), findings.join(","));
+                }
+            }
+            // the notification goes out once the job is archived
+            Stage::Notify(..) => (),
+        }
+        Ok(Step::Keep(job))
+    }
+
+    fn notices(&self, job: &JobRecord) -> Vec<Notice> {
+        let Stage::Notify(recipient, regex) = self else {
+            return Vec::new();
+        };
+        let lines: Vec<String> = job
+            .script
+            .lines()
+            .filter(|l| regex.is_match(l))
+            .map(|l| l.to_owned())
+            .collect();
+        if lines.is_empty() {
+            return Vec::new();
+        }
+        let mut about = job.bare();
+        about.user = job.user.clone();
+        about.uid = job.uid.clone();
+        about.partition = job.partition.clone();
+        vec![Notice {
+            recipient: recipient.clone(),
+            pattern: regex.clone(),
+            about,
+            lines,
+        }]
+    }
+
+    fn name(&self) -> String {
+        match self {
+            Stage::Redact(regex) => format!("redact:{regex}"),
+            Stage::DropEnv(regex) => format!("drop-env:{regex}"),
+            Stage::Skip(field, regex) => format!("skip:{field}={regex}"),
+            Stage::Label(key, value) => format!("label:{key}={value}"),
+            Stage::Directives => "directives".to_owned(),
+            Stage::Notify(recipient, regex) => format!("notify:{recipient} {regex}"),
+            Stage::Lint => "lint".to_owned(),
+        }
+    }
+}
+
+/// The stages a job record goes through, in order
+#[derive(Default)]
+pub struct Pipeline {
+    stages: Vec<Box<dyn Transform>>,
+}
+
+impl Pipeline {
+    pub fn new(stages: Vec<Box<dyn Transform>>) -> Self {
+        Pipeline { stages }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.stages.is_empty()
+    }
+
+    /// Passes the job record through the stages, stopping at the first that
+    /// leaves the job out or replaces it by a tombstone. The notifications
+    /// the stages ask for, about the job as it reached them, are returned
+    /// along with the job that is kept, to be sent once it is archived.
+    pub fn apply(&self, job: &JobRecord) -> Result<(Step, Vec<Notice>), Error> {
+        let mut notices = Vec::new();
+        let (jobid, mut job) = (&job.jobid, job.clone());
+        for stage in self.stages.iter() {
+            notices.extend(stage.notices(&job));
+            match stage.apply(job)? {
+                Step::Keep(kept) => job = kept,
+                Step::Skip => {
+                    info!("Stage {} left out job {}", stage.name(), jobid);
+                    return Ok((Step::Skip, Vec::new()));
+                }
+                tombstone => return Ok((tombstone, Vec::new())),
+            }
+        }
+        Ok((Step::Keep(job), notices))
+    }
+
+    /// Passes the completion through the stages, stopping at the first that
+    /// drops it
+    pub fn completion(&self, completion: &Completion) -> Result<Option<Completion>, Error> {
+        let mut completion = completion.clone();
+        for stage in self.stages.iter() {
+            match stage.completion(completion)? {
+                Some(kept) => completion = kept,
+                None => return Ok(None),
+            }
+        }
+        Ok(Some(completion))
+    }
+}
+
+/// What became of a job of a batch before it went to the backend
+enum Batched {
+    /// The job was handled, with the given outcome
+    Done(Result<(), Error>),
+    /// The job went to the backend, at the given position, with the
+    /// notifications to send once it is archived
+    Kept(usize, Vec<Notice>),
+}
+
+/// Wraps an archiver so the job records go through the pipeline first. The
+/// jobs the pipeline leaves out are not archived, and those it replaces by a
+/// tombstone are archived as such. The completions go through the pipeline as
+/// well; tombstones are passed on as they are.
+pub struct TransformArchive {
+    inner: Box<dyn Archive>,
+    pipeline: Pipeline,
+    /// The size of the payload of the jobs as the backend got them
+    payload_sizes: PayloadSizes,
+}
+
+impl TransformArchive {
+    pub fn new(inner: Box<dyn Archive>, pipeline: Pipeline) -> Self {
+        let names: Vec<String> = pipeline.stages.iter().map(|s| s.name()).collect();
+        info!("Passing the jobs through stages {}", names.join(", "));
+        TransformArchive {
+            inner,
+            pipeline,
+            payload_sizes: PayloadSizes::default(),
+        }
+    }
+
+    /// Records the payload of the job the backend took, and sends the
+    /// notifications about it
+    fn archived(&self, job: &JobRecord, notices: Vec<Notice>) {
+        self.payload_sizes.sent(job, self.inner.payload_size(job));
+        notices.into_iter().for_each(Notice::send);
+    }
+}
+
+impl Archive for TransformArchive {
+    /// The notifications about the job are sent once the backend took it
+    fn archive(&self, job_entry: &JobRecord) -> Result<(), Error> {
+        match self.pipeline.apply(job_entry)? {
+            (Step::Keep(job), notices) => {
+                self.inner.archive(&job)?;
+                self.archived(&job, notices);
+                Ok(())
+            }
+            (Step::Skip, _) => Ok(()),
+            (Step::Tombstone(tombstone), _) => self.inner.archive_tombstone(&tombstone),
+        }
+    }
+
+    /// The jobs that are kept go to the backend as a batch, the tombstones
+    /// one by one. The outcomes line up with the given jobs, the ones left
+    /// out having succeeded; a kept job the backend gave no outcome for has
+    /// failed.
+    fn archive_batch(&self, job_entries: &[JobRecord]) -> Vec<Result<(), Error>> {
+        let mut kept = Vec::new();
+        let mut batched = Vec::with_capacity(job_entries.len());
+        for job_entry in job_entries {
+            batched.push(match self.pipeline.apply(job_entry) {
+                Ok((Step::Keep(job), notices)) => {
+                    kept.push(job);
+                    Batched::Kept(kept.len() - 1, notices)
+                }
+                Ok((Step::Skip, _)) => Batched::Done(Ok(())),
+                Ok((Step::Tombstone(tombstone), _)) => {
+                    Batched::Done(self.inner.archive_tombstone(&tombstone))
+                }
+                Err(e) => Batched::Done(Err(e)),
+            });
+        }
+        let outcomes = self.inner.archive_batch(&kept);
+        if outcomes.len() != kept.len() {
+            warn!(
+                "Backend {} reported {} outcomes for a batch of {} jobs",
+                self.inner.name(),
+                outcomes.len(),
+                kept.len()
+            );
+        }
+        if kept.len() < job_entries.len() {
+            debug!(
+                "Archiving {} of a batch of {} jobs",
+                kept.len(),
+                job_entries.len()
+            );
+        }
+        let mut outcomes = outcomes.into_iter();
+        batched
+            .into_iter()
+            .map(|job| match job {
+                Batched::Done(outcome) => outcome,
+                Batched::Kept(position, notices) => {
+                    let job = &kept[position];
+                    let outcome = outcomes.next().unwrap_or_else(|| {
+                        Err(Error::other(format!(
+                            "Backend {} gave no outcome for job {}",
+                            self.inner.name(),
+                            job.jobid
+                        )))
+                    });
+                    if outcome.is_ok() {
+                        self.archived(job, notices);
+                    }
+                    outcome
+                }
+            })
+            .collect()
+    }
+
+    fn archive_tombstone(&self, job_entry: &JobRecord) -> Result<(), Error> {
+        self.inner.archive_tombstone(job_entry)
+    }
+
+    fn archive_completion(&self, completion: &Completion) -> Result<(), Error> {
+        match self.pipeline.completion(completion)? {
+            Some(completion) => self.inner.archive_completion(&completion),
+            None => Ok(()),
+        }
+    }
+
+    fn check(&self, cluster: &str) -> Result<(), Error> {
+        self.inner.check(cluster)
+    }
+
+    fn flush(&self, timeout: Duration) -> Result<(), Error> {
+        self.inner.flush(timeout)
+    }
+
+    /// The size was recorded when the backend took the job. A job the
+    /// pipeline left out or replaced by a tombstone has no payload.
+    fn payload_size(&self, job: &JobRecord) -> u64 {
+        self.payload_sizes.take(job)
+    }
+
+    fn name(&self) -> &str {
+        self.inner.name()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::archive::archive_entry;
+    use crate::archive::optout::OptOutList;
+    use crate::archive::pseudonym::Pseudonymizer;
+    use crate::stats::Stats;
+    use crate::testing::{slurm_job, FlakyArchiver, RecordingArchiver, ShortArchiver};
+    use std::fs::write;
+    use std::io::{BufRead, BufReader, Read};
+    use std::net::TcpListener;
+    use std::path::Path;
+    use std::sync::{Arc, Mutex};
+    use tempfile::tempdir;
+
+    fn job(tdir: &Path, jobid: &str, user: &str) -> JobRecord {
+        let script = "#!/bin/bash\n#SBATCH -N 2 --time=1:00:00\n#SBATCH --exclusive\nhostname\n";
+        let env = format!("\0\0\0\0SLURM_JOB_USER={user}\0API_TOKEN=s3cr3t\0SHELL=/bin/bash\0");
+        slurm_job(tdir, jobid, script, env.as_bytes())
+    }
+
+    #[test]
+    fn test_parse_stage() {
+        assert!(matches!(parse_stage("redact:TOKEN"), Ok(Stage::Redact(_))));
+        assert!(matches!(
+            parse_stage("skip:user=^root$"),
+            Ok(Stage::Skip(Field::User, _))
+        ));
+        assert!(matches!(
+            parse_stage("label:site=gent"),
+            Ok(Stage::Label(k, v)) if k == "site" && v == "gent"
+        ));
+        assert!(matches!(parse_stage("directives"), Ok(Stage::Directives)));
+        assert!(matches!(parse_stage("lint"), Ok(Stage::Lint)));
+        assert_eq!(
+            parse_stage("skip:user=^root$").unwrap().name(),
+            "skip:user=^root$"
+        );
+        assert!(parse_stage("skip:account=x").is_err());
+        assert!(parse_stage("label:site").is_err());
+        assert!(parse_stage("redact:(").is_err());
+        assert!(parse_stage("directives:x").is_err());
+        assert!(parse_stage("compress").is_err());
+        assert!(matches!(
+            parse_stage("notify:mailto:policy@example.org ^#SBATCH --partition=restricted"),
+            Ok(Stage::Notify(Recipient::Mail(a), r)) if a == "policy@example.org" && r.as_str() == "^#SBATCH --partition=restricted"
+        ));
+        assert_eq!(
+            parse_stage("notify:http://localhost:8000/hook?token=x gpu")
+                .unwrap()
+                .name(),
+            "notify:http://localhost:8000/hook?token=x gpu"
+        );
+        assert!(parse_stage("notify:mailto:policy gpu").is_err());
+        assert!(parse_stage("notify:udp://localhost:8000 gpu").is_err());
+        assert!(parse_stage("notify:http://localhost:8000").is_err());
+    }
+
+    /// Accepts the webhook requests, returning the request lines and bodies
+    /// received so far
+    fn webhook(listener: TcpListener) -> Arc<Mutex<Vec<(String, String)>>> {
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let requests = Arc::clone(&received);
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let stream = stream.unwrap();
+                let mut reader = BufReader::new(&stream);
+                let mut request = String::new();
+                reader.read_line(&mut request).unwrap();
+                let mut length = 0;
+                loop {
+                    let mut header = String::new();
+                    reader.read_line(&mut header).unwrap();
+                    if let Some(l) = header.strip_prefix("Content-Length: ") {
+                        length = l.trim().parse().unwrap();
+                    }
+                    if header.trim().is_empty() {
+                        break;
+                    }
+                }
+                let mut body = vec![0; length];
+                reader.read_exact(&mut body).unwrap();
+                (&stream)
+                    .write_all(b"HTTP/1.1 204 No Content\r\n\r\n")
+                    .unwrap();
+                let body = String::from_utf8(body).unwrap();
+                requests.lock().unwrap().push((request, body));
+            }
+        });
+        received
+    }
+
+    #[test]
+    fn test_notify() {
+        let tdir = tempdir().unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let received = webhook(listener);
+
+        let stage = parse_stage(&format!("notify:http://{address}/hook ^#SBATCH -N")).unwrap();
+        let alice = job(tdir.path(), "1", "alice");
+        assert!(matches!(
+            stage.apply(alice.clone()),
+            Ok(Step::Keep(kept)) if kept.script == alice.script
+        ));
+        assert_eq!(stage.notices(&alice).len(), 1);
+
+        // a job is notified about once, when the backend took it, no matter
+        // how often it was tried or its payload measured
+        let flaky = FlakyArchiver::down(1);
+        let archive = TransformArchive::new(
+            Box::new(flaky.clone()),
+            Pipeline::new(vec![Box::new(stage) as Box<dyn Transform>]),
+        );
+        assert!(archive.archive(&alice).is_err());
+        archive_entry(&archive, &alice, &Stats::new(), None).unwrap();
+        let bob = job(tdir.path(), "2", "bob");
+        let outcomes = archive.archive_batch(std::slice::from_ref(&bob));
+        assert!(outcomes.iter().all(|o| o.is_ok()));
+        archive.payload_size(&bob);
+        assert_eq!(flaky.recording.jobs().len(), 2);
+
+        for _ in 0..50 {
+            if received.lock().unwrap().len() >= 2 {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(100));
+        }
+        std::thread::sleep(Duration::from_millis(200));
+        let received = received.lock().unwrap();
+        assert_eq!(received.len(), 2);
+        let (request, body) = received
+            .iter()
+            .find(|(_, body)| body.contains("alice"))
+            .unwrap();
+        assert_eq!(request, "POST /hook HTTP/1.1\r\n");
+        let doc: serde_json::Value = serde_json::from_str(body).unwrap();
+        assert_eq!(doc["id"], "1");
+        assert_eq!(doc["user"], "alice");
+        assert_eq!(doc["lines"], json!(["#SBATCH -N 2 --time=1:00:00"]));
+    }
+
+    #[test]
+    fn test_notify_opted_out() {
+        let tdir = tempdir().unwrap();
+        let path = tdir.path().join("opt-out");
+        write(&path, "alice\n").unwrap();
+        let pipeline = Pipeline::new(vec![
+            Box::new(OptOutList::load(&path).unwrap()),
+            Box::new(parse_stage("notify:mailto:policy@example.org ^#SBATCH -N").unwrap()),
+        ]);
+
+        // the notification about a job of a user who opted out is not sent
+        let (step, notices) = pipeline.apply(&job(tdir.path(), "1", "alice")).unwrap();
+        assert!(matches!(step, Step::Tombstone(_)));
+        assert!(notices.is_empty());
+        let (step, notices) = pipeline.apply(&job(tdir.path(), "2", "bob")).unwrap();
+        assert!(matches!(step, Step::Keep(_)));
+        assert_eq!(notices[0].about.user.as_deref(), Some("bob"));
+    }
+
+    #[test]
+    fn test_notify_pseudonymized() {
+        let tdir = tempdir().unwrap();
+        let pseudonymizer = Pseudonymizer::new(b"secret");
+        let pseudonym = pseudonymizer.pseudonym("alice").unwrap();
+        let pipeline = Pipeline::new(vec![
+            Box::new(pseudonymizer),
+            Box::new(parse_stage("notify:mailto:policy@example.org ^cd ").unwrap()),
+        ]);
+
+        // the notification gives the user, and the user name in the lines, by
+        // their pseudonym
+        let mut alice = job(tdir.path(), "1", "alice");
+        alice.script.push_str("cd /home/alice/run\n");
+        let (_, notices) = pipeline.apply(&alice).unwrap();
+        assert_eq!(notices[0].about.user, Some(pseudonym.clone()));
+        assert_eq!(notices[0].lines, vec![format!("cd /home/{pseudonym}/run")]);
+    }
+
+    #[test]
+    fn test_lint() {
+        assert_eq!(
+            lint("#!/bin/bash\n#SBATCH --workdir=/tmp\n#SBATCH -N 2\nmodule load foo\n#SBATCH --time=1:00:00\n./run\n"),
+            vec!["no_time_limit", "deprecated:workdir", "late_directive", "no_errexit"]
+        );
+        assert!(lint("#!/bin/bash\n#SBATCH -t 10\nset -euo pipefail\n./run\n").is_empty());
+        assert!(lint("#!/bin/bash -e\n#SBATCH --time=10\n./run\n").is_empty());
+        assert!(lint("#!/bin/sh\n#PBS -l nodes=1,walltime=1:00:00\n./run\n").is_empty());
+        assert!(lint("#!/bin/bash\n#BSUB -W 10\nset -o errexit\n").is_empty());
+
+        let tdir = tempdir().unwrap();
+        let Ok(Step::Keep(job)) = Stage::Lint.apply(job(tdir.path(), "1", "alice")) else {
+            panic!("the lint stage keeps the job");
+        };
+        assert_eq!(job.extra.unwrap()[LINT_KEY], "no_errexit");
+    }
+
+    #[test]
+    fn test_directives() {
+        let script = "#!/bin/bash\n#SBATCH -N 2 --time=1:00:00\n#SBATCH --exclusive\n# SBATCH -p ignored\n#PBS -l walltime=1:00:00\n";
+        assert_eq!(
+            directives(script),
+            vec![
+                ("N".to_owned(), "2".to_owned()),
+                ("time".to_owned(), "1:00:00".to_owned()),
+                ("exclusive".to_owned(), String::new()),
+                ("l".to_owned(), "walltime=1:00:00".to_owned()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_transform_archive_short_batch() {
+        let tdir = tempdir().unwrap();
+        let stages = vec![Box::new(parse_stage("skip:user=^root$").unwrap()) as Box<dyn Transform>];
+        let recording = RecordingArchiver::default();
+        let archive = TransformArchive::new(
+            Box::new(ShortArchiver(recording.clone())),
+            Pipeline::new(stages),
+        );
+
+        // the outcomes still line up with the jobs, the one the backend gave
+        // no outcome for having failed
+        let jobs = [
+            job(tdir.path(), "1", "alice"),
+            job(tdir.path(), "2", "root"),
+            job(tdir.path(), "3", "bob"),
+        ];
+        let outcomes = archive.archive_batch(&jobs);
+        assert_eq!(outcomes.len(), 3);
+        assert!(outcomes[0].is_ok());
+        assert!(outcomes[1].is_ok());
+        assert_eq!(
+            outcomes[2].as_ref().unwrap_err().to_string(),
+            "Backend short gave no outcome for job 3"
+        );
+        assert_eq!(recording.jobs().len(), 1);
+    }
+
+    #[test]
+    fn test_transform_archive() {
+        let tdir = tempdir().unwrap();
+        let stages: Vec<Box<dyn Transform>> = [
+            "skip:user=^root$",
+            "redact:TOKEN",
+            "drop-env:^SHELL$",
+            "label:site=gent",
+            "directives",
+        ]
+        .iter()
+        .map(|s| Box::new(parse_stage(s).unwrap()) as Box<dyn Transform>)
+        .collect();
+        let recording = RecordingArchiver::default();
+        let archive = TransformArchive::new(Box::new(recording.clone()), Pipeline::new(stages));
+
+        let jobs = [
+            job(tdir.path(), "1", "alice"),
+            job(tdir.path(), "2", "root"),
+            job(tdir.path(), "3", "bob"),
+        ];
+        archive.archive(&jobs[1]).unwrap();
+        let outcomes = archive.archive_batch(&jobs);
+        assert_eq!(outcomes.len(), 3);
+        assert!(outcomes.iter().all(|o| o.is_ok()));
+        assert_eq!(archive.payload_size(&jobs[1]), 0);
+        // the payload is that of the job as the backend got it
+        let size = archive.payload_size(&jobs[0]);
+        assert!(size > 0 && size < jobs[0].files_size());
+        assert_eq!(archive.payload_size(&jobs[0]), 0);
+
+        assert_eq!(recording.lines(), vec!["job 1", "job 3"]);
+        let extra = recording.jobs()[0].extra.clone().unwrap();
+        assert_eq!(extra["API_TOKEN"], REDACTED);
+        assert!(!extra.contains_key("SHELL"));
+        assert_eq!(extra["SLURM_JOB_USER"], "alice");
+        assert_eq!(extra["site"], "gent");
+        assert_eq!(extra["sarchive_directive_N"], "2");
+        assert_eq!(extra["sarchive_directive_time"], "1:00:00");
+        assert_eq!(extra["sarchive_directive_exclusive"], "");
+    }
+
+    #[test]
+    fn test_environment_file() {
+        let tdir = tempdir().unwrap();
+        let pipeline = Pipeline::new(vec![
+            Box::new(parse_stage("redact:TOKEN").unwrap()),
+            Box::new(parse_stage("drop-env:^SHELL$").unwrap()),
+        ]);
+
+        // the raw environment file does not give away what the stages hide
+        let (step, _) = pipeline.apply(&job(tdir.path(), "1", "alice")).unwrap();
+        let Step::Keep(job) = step else {
+            panic!("the job is kept");
+        };
+        let (_, env) = job
+            .files
+            .iter()
+            .find(|(name, _)| name == "job.1_environment")
+            .unwrap();
+        assert_eq!(env, b"\0\0\0\0SLURM_JOB_USER=alice\0API_TOKEN=[redacted]\0");
+    }
+}
@@ -0,0 +1,245 @@
+/*
+Copyright 2019-2024 Andy Georges <itkovian+sarchive@gmail.com>
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in
+all copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+use crossbeam_channel::{Receiver, RecvTimeoutError, Sender};
+use log::{debug, info, warn};
+use regex::Regex;
+use std::collections::{HashMap, HashSet};
+use std::fs::{read_dir, File};
+use std::io::{Error, ErrorKind, Read};
+use std::path::{Path, PathBuf};
+use std::sync::LazyLock;
+use std::time::{Duration, SystemTime};
+
+use crate::completion::Completion;
+
+/// Field holding the kind of artefact, prolog or epilog, that a completion
+/// carries instead of the end of the job
+pub const ARTEFACT_KIND_FIELD: &str = "ArtefactKind";
+
+/// Field holding the file name of the artefact
+pub const ARTEFACT_FIELD: &str = "Artefact";
+
+/// Field holding the contents of the artefact
+pub const ARTEFACT_CONTENTS_FIELD: &str = "ArtefactContents";
+
+/// Only the start of a larger artefact is kept
+const MAX_ARTEFACT_SIZE: u64 = 64 << 10;
+
+/// How long to wait between looking for new artefacts
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The name of an artefact: the kind, the job ID and an optional suffix
+static ARTEFACT_NAME: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"^(prolog|epilog)[._-](\d+(?:_\d+)?)(?:[.-].*)?$").unwrap());
+
+/// Returns the kind of artefact and the job ID the file name tells, e.g.,
+/// `prolog.123456`, `epilog-123456.err` or `prolog_123456_7.log`
+pub fn parse_name(name: &str) -> Option<(&'static str, String)> {
+    let captures = ARTEFACT_NAME.captures(name)?;
+    let kind = if &captures[1] == "prolog" {
+        "prolog"
+    } else {
+        "epilog"
+    };
+    Some((kind, captures[2].to_owned()))
+}
+
+/// Reads the artefact at the given path, returning it as a completion of the
+/// job it belongs to
+pub fn read_artefact(path: &Path, cluster: &str) -> Result<Completion, Error> {
+    let name = path
+        .file_name()
+        .and_then(|n| n.to_str())
+        .unwrap_or_default();
+    let (kind, jobid) = parse_name(name).ok_or_else(|| {
+        Error::new(
+            ErrorKind::InvalidInput,
+            format!("{path:?} is not named after a job"),
+        )
+    })?;
+    let mut contents = Vec::new();
+    File::open(path)?
+        .take(MAX_ARTEFACT_SIZE)
+        .read_to_end(&mut contents)?;
+    let fields = HashMap::from([
+        (ARTEFACT_KIND_FIELD.to_owned(), kind.to_owned()),
+        (ARTEFACT_FIELD.to_owned(), name.to_owned()),
+        (
+            ARTEFACT_CONTENTS_FIELD.to_owned(),
+            String::from_utf8_lossy(&contents).into_owned(),
+        ),
+    ]);
+    Ok(Completion {
+        jobid,
+        cluster: cluster.to_owned(),
+        fields,
+        ..Default::default()
+    })
+}
+
+/// The size and modification time of a file, which stop changing once it is
+/// written
+type Written = (u64, Option<SystemTime>);
+
+/// Returns the files in the directories that are named after a job, with how
+/// much of them was written
+fn artefacts(dirs: &[PathBuf]) -> HashMap<PathBuf, Written> {
+    let mut found = HashMap::new();
+    for dir in dirs {
+        let entries = match read_dir(dir) {
+            Ok(entries) => entries,
+            Err(e) => {
+                warn!("Cannot look for artefacts in {:?}: {}", dir, e);
+                continue;
+            }
+        };
+        found.extend(
+            entries
+                .filter_map(|entry| entry.ok())
+                .filter(|entry| entry.file_type().is_ok_and(|t| t.is_file()))
+                .filter(|entry| entry.file_name().to_str().and_then(parse_name).is_some())
+                .filter_map(|entry| {
+                    let metadata = entry.metadata().ok()?;
+                    Some((entry.path(), (metadata.len(), metadata.modified().ok())))
+                }),
+        );
+    }
+    found
+}
+
+/// The watch function looks for new prolog and epilog artefacts in the given
+/// directories, and sends each as a completion of the job it belongs to. The
+/// artefacts already there when it starts are left alone. A new artefact is
+/// only read once it did not change between two looks, so it is not read while
+/// it is still being written.
+/// At the same time, it checks if there is an incoming notification that it should
+/// stop. Upon receipt, it returns.
+pub fn watch(
+    dirs: &[PathBuf],
+    cluster: &str,
+    s: &Sender<Completion>,
+    sigchannel: &Receiver<bool>,
+) -> Result<(), Error> {
+    info!("Looking for prolog and epilog artefacts in {:?}", dirs);
+    let mut seen: HashSet<PathBuf> = artefacts(dirs).into_keys().collect();
+    // the new artefacts, as they were at the last look
+    let mut pending: HashMap<PathBuf, Written> = HashMap::new();
+    loop {
+        match sigchannel.recv_timeout(POLL_INTERVAL) {
+            Ok(true) | Err(RecvTimeoutError::Disconnected) => return Ok(()),
+            _ => (),
+        }
+
+        let found = artefacts(dirs);
+        for (path, written) in &found {
+            if seen.contains(path) || pending.insert(path.clone(), *written) != Some(*written) {
+                continue;
+            }
+            pending.remove(path);
+            seen.insert(path.clone());
+            match read_artefact(path, cluster) {
+                Ok(artefact) => {
+                    debug!("Found {:?} of job {}", path, artefact.jobid);
+                    if s.send(artefact).is_err() {
+                        return Err(Error::new(ErrorKind::BrokenPipe, "Processing stopped"));
+                    }
+                }
+                Err(e) => warn!("Cannot read artefact {:?}: {}", path, e),
+            }
+        }
+        // artefacts that were removed are forgotten
+        seen.retain(|path| found.contains_key(path));
+        pending.retain(|path, _| found.contains_key(path));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crossbeam_channel::{bounded, unbounded};
+    use crossbeam_utils::thread::scope;
+    use std::fs::write;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_parse_name() {
+        assert_eq!(
+            parse_name("prolog.123456"),
+            Some(("prolog", "123456".to_owned()))
+        );
+        assert_eq!(
+            parse_name("epilog-123456.err"),
+            Some(("epilog", "123456".to_owned()))
+        );
+        assert_eq!(
+            parse_name("prolog_123456_7.log"),
+            Some(("prolog", "123456_7".to_owned()))
+        );
+        assert_eq!(parse_name("prolog.log"), None);
+        assert_eq!(parse_name("slurmd.log"), None);
+        assert_eq!(parse_name("prolog.123abc"), None);
+    }
+
+    #[test]
+    fn test_read_artefact() {
+        let tdir = tempdir().unwrap();
+        let path = tdir.path().join("epilog.123456.err");
+        write(&path, "umount: /scratch: target is busy\n").unwrap();
+
+        let artefact = read_artefact(&path, "mycluster").unwrap();
+        assert_eq!(artefact.jobid, "123456");
+        assert_eq!(artefact.cluster, "mycluster");
+        assert_eq!(artefact.artefact(), Some("epilog"));
+        assert!(!artefact.is_final());
+        assert_eq!(artefact.event(), "epilog_failed");
+        assert_eq!(artefact.fields[ARTEFACT_FIELD], "epilog.123456.err");
+        assert_eq!(
+            artefact.fields[ARTEFACT_CONTENTS_FIELD],
+            "umount: /scratch: target is busy\n"
+        );
+        assert!(read_artefact(&tdir.path().join("notes"), "mycluster").is_err());
+    }
+
+    #[test]
+    fn test_watch() {
+        let tdir = tempdir().unwrap();
+        write(tdir.path().join("prolog.1"), "old").unwrap();
+        let (tx, rx) = unbounded();
+        let (sig_tx, sig_rx) = bounded(1);
+        let dirs = vec![tdir.path().to_path_buf()];
+
+        scope(|s| {
+            s.spawn(|_| watch(&dirs, "mycluster", &tx, &sig_rx).unwrap());
+            std::thread::sleep(Duration::from_millis(200));
+            write(tdir.path().join("prolog.2"), "prolog failed").unwrap();
+            write(tdir.path().join("unrelated.txt"), "").unwrap();
+            // it is read once it stopped changing
+            let artefact = rx.recv_timeout(Duration::from_secs(5)).unwrap();
+            assert_eq!(artefact.jobid, "2");
+            assert_eq!(artefact.fields[ARTEFACT_CONTENTS_FIELD], "prolog failed");
+            sig_tx.send(true).unwrap();
+        })
+        .unwrap();
+        assert!(rx.try_recv().is_err());
+    }
+}
@@ -0,0 +1,571 @@
+/*
+Copyright 2019-2024 Andy Georges <itkovian+sarchive@gmail.com>
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in
+all copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+use crossbeam_channel::{Receiver, RecvTimeoutError, Sender};
+use log::{debug, info, warn};
+use regex::Regex;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fs::{read_to_string, rename, File, OpenOptions};
+use std::io::{BufRead, BufReader, Error, ErrorKind, Seek, SeekFrom, Write};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::sync::LazyLock;
+use std::time::{Duration, Instant};
+
+use crate::artefact::ARTEFACT_KIND_FIELD;
+
+/// How long to wait between checks for new lines in the log
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Number of archived job IDs to remember for matching completions
+pub const ARCHIVED_JOBS_CAPACITY: usize = 100_000;
+
+/// How long after the final completion of a job its artefacts are still
+/// accepted, as an epilog runs when the job ends
+const ARTEFACT_GRACE: Duration = Duration::from_secs(15 * 60);
+
+/// State of a job that started, which the completion reports instead of its end
+pub const STARTED_STATE: &str = "RUNNING";
+
+/// Fields of a completion that identify the user, e.g., `UserId=alice(1000)`
+/// in a Slurm log, or `user=alice` and `owner=alice@login1` in a Torque
+/// accounting log
+pub const USER_FIELDS: [&str; 5] = ["UserId", "User", "user", "owner", REQUESTOR_FIELD];
+
+/// Field of a Torque accounting record naming the user who deleted the job,
+/// who need not be its owner
+pub const REQUESTOR_FIELD: &str = "requestor";
+
+/// Fields of a completion that identify the group, e.g., `GroupId=users(100)`
+/// in a Slurm log or `group=users` in a Torque accounting log
+pub const GROUP_FIELDS: [&str; 2] = ["GroupId", "group"];
+
+/// Splits the value of a user or group field into the name, the id and the
+/// host, as far as given, e.g., `alice(1000)` into alice and 1000, and
+/// `alice@login1` into alice and login1
+pub fn split_id(value: &str) -> (&str, Option<&str>, Option<&str>) {
+    let (value, host) = match value.split_once('@') {
+        Some((value, host)) => (value, Some(host)),
+        None => (value, None),
+    };
+    match value.strip_suffix(')').and_then(|v| v.split_once('(')) {
+        Some((name, id)) => (name, Some(id), host),
+        None => (value, None, host),
+    }
+}
+
+/// The completion of a job, as found in a Slurm log or a Torque accounting
+/// log, or an artefact left by its prolog or epilog. The Torque accounting log
+/// also reports the start of a job.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Completion {
+    pub jobid: String,
+    pub cluster: String,
+    /// Final state of the job, e.g., COMPLETED or FAILED
+    pub state: Option<String>,
+    pub exit_code: Option<String>,
+    pub start_time: Option<String>,
+    pub end_time: Option<String>,
+    /// All fields on the log line
+    pub fields: HashMap<String, String>,
+}
+
+impl Completion {
+    /// The kind of artefact, e.g., prolog, when the completion carries one
+    pub fn artefact(&self) -> Option<&str> {
+        self.fields.get(ARTEFACT_KIND_FIELD).map(|k| k.as_str())
+    }
+
+    /// Whether this reports the start of the job rather than its end
+    pub fn is_start(&self) -> bool {
+        self.artefact().is_none() && self.state.as_deref() == Some(STARTED_STATE)
+    }
+
+    /// Whether this reports the end of the job, after which nothing more is
+    /// expected for it
+    pub fn is_final(&self) -> bool {
+        self.artefact().is_none() && !self.is_start()
+    }
+
+    /// The event recorded for the completion, e.g., completed, started or
+    /// prolog_failed
+    pub fn event(&self) -> String {
+        match self.artefact() {
+            Some(kind) => format!("{kind}_failed"),
+            None if self.is_start() => "started".to_owned(),
+            None => "completed".to_owned(),
+        }
+    }
+}
+
+/// Parses a line of the job completion log (jobcomp/filetxt) or of the
+/// slurmctld log, returning the completion it reports, if any
+///
+/// The former holds a `Key=Value` pair for every field (values may contain
+/// spaces), the latter has lines such as
+/// `_job_complete: JobId=1234 WEXITSTATUS 0`.
+pub fn parse_line(line: &str, cluster: &str) -> Option<Completion> {
+    if let Some(rest) = line.split("_job_complete: ").nth(1) {
+        let mut words = rest.split_whitespace();
+        let jobid = words.next()?.strip_prefix("JobId=")?;
+        let (state, exit_code) = match (words.next()?, words.next()) {
+            ("WEXITSTATUS", Some(code)) if code == "0" => ("COMPLETED", code),
+            ("WEXITSTATUS", Some(code)) => ("FAILED", code),
+            ("WTERMSIG", Some(signal)) => ("CANCELLED", signal),
+            _ => return None,
+        };
+        return Some(Completion {
+            jobid: jobid.to_owned(),
+            cluster: cluster.to_owned(),
+            state: Some(state.to_owned()),
+            exit_code: Some(exit_code.to_owned()),
+            ..Default::default()
+        });
+    }
+
+    if !line.starts_with("JobId=") {
+        return None;
+    }
+    let fields = parse_fields(line);
+    let jobid = fields.get("JobId")?.to_owned();
+    Some(Completion {
+        jobid,
+        cluster: cluster.to_owned(),
+        state: fields.get("JobState").cloned(),
+        exit_code: fields.get("ExitCode").cloned(),
+        start_time: fields.get("StartTime").cloned(),
+        end_time: fields.get("EndTime").cloned(),
+        fields,
+    })
+}
+
+/// The start of a `Key=Value` field in a line of the log
+static FIELD_KEY: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"(?:^|\s)([A-Za-z]+)=").unwrap());
+
+/// Splits a line into its `Key=Value` fields, where a value runs until the
+/// next key
+fn parse_fields(line: &str) -> HashMap<String, String> {
+    let keys: Vec<_> = FIELD_KEY.captures_iter(line).collect();
+    keys.iter()
+        .enumerate()
+        .map(|(i, c)| {
+            let start = c.get(0).unwrap().end();
+            let end = keys
+                .get(i + 1)
+                .map_or(line.len(), |n| n.get(0).unwrap().start());
+            (c[1].to_owned(), line[start..end].trim().to_owned())
+        })
+        .collect()
+}
+
+/// The IDs of recently archived jobs, so completions can be matched with them.
+/// The oldest IDs are forgotten once the capacity is reached.
+/// With a journal, the IDs are kept across restarts.
+pub struct ArchivedJobs {
+    capacity: usize,
+    order: VecDeque<String>,
+    ids: HashSet<String>,
+    journal: Option<Journal>,
+    /// The jobs that completed, with the time, whose artefacts are accepted
+    /// for a while longer
+    completed: VecDeque<(String, Instant)>,
+    grace: Duration,
+}
+
+/// File recording every job ID added (`+jobid`) and removed (`-jobid`), which
+/// is rewritten with only the current IDs when it has grown too long
+struct Journal {
+    path: PathBuf,
+    file: File,
+    lines: usize,
+}
+
+impl Journal {
+    /// Replaces the journal with one holding the given IDs
+    fn compact<'a>(path: &Path, ids: impl Iterator<Item = &'a String>) -> Result<Journal, Error> {
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        let mut file = File::create(&tmp)?;
+        let mut lines = 0;
+        for id in ids {
+            writeln!(file, "+{id}")?;
+            lines += 1;
+        }
+        file.sync_all()?;
+        rename(&tmp, path)?;
+        Ok(Journal {
+            path: path.to_path_buf(),
+            file: OpenOptions::new().append(true).open(path)?,
+            lines,
+        })
+    }
+
+    fn append(&mut self, line: &str) {
+        match writeln!(self.file, "{line}") {
+            Ok(()) => self.lines += 1,
+            Err(e) => warn!("Cannot record {} in {:?}: {}", line, self.path, e),
+        }
+    }
+}
+
+impl Default for ArchivedJobs {
+    fn default() -> Self {
+        ArchivedJobs::new(ARCHIVED_JOBS_CAPACITY)
+    }
+}
+
+impl ArchivedJobs {
+    pub fn new(capacity: usize) -> ArchivedJobs {
+        ArchivedJobs {
+            capacity,
+            order: VecDeque::new(),
+            ids: HashSet::new(),
+            journal: None,
+            completed: VecDeque::new(),
+            grace: ARTEFACT_GRACE,
+        }
+    }
+
+    /// Returns the job IDs kept in the journal at the given path, which is
+    /// created if needed, keeping later changes there as well
+    pub fn open(path: &Path, capacity: usize) -> Result<ArchivedJobs, Error> {
+        let mut archived = ArchivedJobs::new(capacity);
+        let contents = match read_to_string(path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(e),
+        };
+        for line in contents.lines() {
+            if let Some(jobid) = line.strip_prefix('+') {
+                archived.insert(jobid);
+            } else if let Some(jobid) = line.strip_prefix('-') {
+                archived.remove(jobid);
+            } else if !line.is_empty() {
+                warn!("Skipping line {:?} in {:?}", line, path);
+            }
+        }
+        archived.journal = Some(Journal::compact(path, archived.order.iter())?);
+        Ok(archived)
+    }
+
+    /// Number of job IDs awaiting their completion
+    pub fn len(&self) -> usize {
+        self.ids.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ids.is_empty()
+    }
+
+    pub fn insert(&mut self, jobid: &str) {
+        if self.ids.insert(jobid.to_owned()) {
+            self.order.push_back(jobid.to_owned());
+            self.record(&format!("+{jobid}"));
+        }
+        while self.order.len() > self.capacity {
+            if let Some(old) = self.order.pop_front() {
+                self.ids.remove(&old);
+                self.record(&format!("-{old}"));
+            }
+        }
+        self.compact();
+    }
+
+    /// Records the change in the journal, if any
+    fn record(&mut self, line: &str) {
+        if let Some(journal) = self.journal.as_mut() {
+            journal.append(line);
+        }
+    }
+
+    /// Compacts the journal, if any, once it holds many more lines than IDs
+    fn compact(&mut self) {
+        let Some(journal) = self.journal.as_mut() else {
+            return;
+        };
+        if journal.lines > 2 * self.capacity {
+            match Journal::compact(&journal.path, self.order.iter()) {
+                Ok(compacted) => *journal = compacted,
+                Err(e) => warn!("Cannot compact {:?}: {}", journal.path, e),
+            }
+        }
+    }
+
+    /// Returns whether the completion is for an archived job. The job ID is
+    /// removed at its final completion, so that is only forwarded once, even
+    /// if several log lines report it. Artefacts of the job are accepted for
+    /// a grace period after that, as they may arrive with or after the end of
+    /// the job.
+    pub fn accept(&mut self, completion: &Completion) -> bool {
+        while let Some((_, at)) = self.completed.front() {
+            if at.elapsed() < self.grace && self.completed.len() <= self.capacity {
+                break;
+            }
+            self.completed.pop_front();
+        }
+        if completion.is_final() {
+            let removed = self.remove(&completion.jobid);
+            if removed {
+                self.completed
+                    .push_back((completion.jobid.clone(), Instant::now()));
+            }
+            removed
+        } else {
+            self.ids.contains(&completion.jobid)
+                || self.completed.iter().any(|(id, _)| id == &completion.jobid)
+        }
+    }
+
+    /// Removes the job ID, returning whether it was present
+    pub fn remove(&mut self, jobid: &str) -> bool {
+        if self.ids.remove(jobid) {
+            self.order.retain(|id| id != jobid);
+            self.record(&format!("-{jobid}"));
+            self.compact();
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// The tail function follows the log at the given path, starting at its end
+/// (or at its start, to pick up the completions logged while sarchive was not
+/// running), and sends every completion it finds. A rotated or truncated log
+/// is read from the start.
+/// At the same time, it checks if there is an incoming notification that it should
+/// stop. Upon receipt, it returns.
+pub fn tail(
+    path: &Path,
+    cluster: &str,
+    s: &Sender<Completion>,
+    sigchannel: &Receiver<bool>,
+    from_start: bool,
+) -> Result<(), Error> {
+    info!("Following job completions in {:?}", path);
+    let mut reader = BufReader::new(File::open(path)?);
+    if !from_start {
+        reader.seek(SeekFrom::End(0))?;
+    }
+    let mut inode = reader.get_ref().metadata()?.ino();
+    let mut line = String::new();
+
+    loop {
+        loop {
+            let n = reader.read_line(&mut line)?;
+            if n == 0 || !line.ends_with('\n') {
+                break;
+            }
+            if let Some(completion) = parse_line(line.trim_end(), cluster) {
+                debug!("Found completion of job {}", completion.jobid);
+                if s.send(completion).is_err() {
+                    return Err(Error::new(ErrorKind::BrokenPipe, "Processing stopped"));
+                }
+            }
+            line.clear();
+        }
+
+        match sigchannel.recv_timeout(POLL_INTERVAL) {
+            Ok(true) | Err(RecvTimeoutError::Disconnected) => return Ok(()),
+            _ => (),
+        }
+
+        // Reopen the log after rotation or truncation
+        let position = reader.stream_position()?;
+        match path.metadata() {
+            Ok(m) if m.ino() != inode || m.len() < position => {
+                debug!("{:?} was rotated, reading it from the start", path);
+                reader = BufReader::new(File::open(path)?);
+                inode = m.ino();
+                line.clear();
+            }
+            Ok(_) => (),
+            Err(e) => warn!("Cannot check {:?}: {}", path, e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crossbeam_channel::unbounded;
+    use crossbeam_utils::thread::scope;
+    use std::fs::{rename, OpenOptions};
+    use std::io::Write;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_parse_jobcomp_line() {
+        let line = "JobId=1234 UserId=user(1000) GroupId=group(1000) Name=my job JobState=FAILED \
+            Partition=batch TimeLimit=60 StartTime=2024-01-01T10:00:00 EndTime=2024-01-01T10:05:00 \
+            NodeList=node1 ExitCode=1:0";
+        let completion = parse_line(line, "mycluster").unwrap();
+
+        assert_eq!(completion.jobid, "1234");
+        assert_eq!(completion.cluster, "mycluster");
+        assert_eq!(completion.state.as_deref(), Some("FAILED"));
+        assert_eq!(completion.exit_code.as_deref(), Some("1:0"));
+        assert_eq!(completion.end_time.as_deref(), Some("2024-01-01T10:05:00"));
+        assert_eq!(completion.fields.get("Name").unwrap(), "my job");
+        assert_eq!(completion.fields.get("UserId").unwrap(), "user(1000)");
+    }
+
+    #[test]
+    fn test_parse_slurmctld_line() {
+        let completion = parse_line(
+            "[2024-01-01T10:05:00.123] _job_complete: JobId=1234 WEXITSTATUS 0",
+            "mycluster",
+        )
+        .unwrap();
+        assert_eq!(completion.jobid, "1234");
+        assert_eq!(completion.state.as_deref(), Some("COMPLETED"));
+        assert_eq!(completion.exit_code.as_deref(), Some("0"));
+
+        let completion = parse_line(
+            "[2024-01-01T10:05:00.123] _job_complete: JobId=1235 WTERMSIG 9",
+            "mycluster",
+        )
+        .unwrap();
+        assert_eq!(completion.state.as_deref(), Some("CANCELLED"));
+
+        assert_eq!(
+            parse_line(
+                "[2024-01-01T10:05:00.123] _job_complete: JobId=1234 done",
+                "mycluster"
+            ),
+            None
+        );
+        assert_eq!(
+            parse_line(
+                "[2024-01-01T10:05:00.123] sched: Allocate JobId=1234",
+                "mycluster"
+            ),
+            None
+        );
+    }
+
+    #[test]
+    fn test_archived_jobs() {
+        let mut archived = ArchivedJobs::new(2);
+        archived.insert("1");
+        archived.insert("2");
+        archived.insert("3");
+
+        assert!(!archived.remove("1"));
+        assert!(archived.remove("2"));
+        assert!(!archived.remove("2"));
+        assert!(archived.remove("3"));
+    }
+
+    #[test]
+    fn test_archived_jobs_journal() {
+        let tdir = tempdir().unwrap();
+        let path = tdir.path().join("archived");
+        {
+            let mut archived = ArchivedJobs::open(&path, 2).unwrap();
+            assert!(archived.is_empty());
+            archived.insert("1");
+            archived.insert("2");
+            archived.insert("2");
+            assert!(archived.remove("1"));
+            assert_eq!(std::fs::read_to_string(&path).unwrap(), "+1\n+2\n-1\n");
+            // compacted once it holds more than twice the capacity
+            archived.insert("3");
+            archived.insert("4");
+            assert_eq!(std::fs::read_to_string(&path).unwrap(), "+3\n+4\n");
+        }
+
+        // kept across restarts
+        let mut archived = ArchivedJobs::open(&path, 2).unwrap();
+        assert_eq!(archived.len(), 2);
+        assert!(!archived.remove("2"));
+        assert!(archived.remove("3"));
+        for id in ["5", "6", "7"] {
+            archived.insert(id);
+        }
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "+5\n+6\n+7\n-5\n");
+    }
+
+    #[test]
+    fn test_archived_jobs_accept() {
+        let mut archived = ArchivedJobs::new(2);
+        archived.insert("1");
+        let completion = Completion {
+            jobid: "1".to_owned(),
+            ..Default::default()
+        };
+        let mut artefact = completion.clone();
+        artefact
+            .fields
+            .insert(ARTEFACT_KIND_FIELD.to_owned(), "prolog".to_owned());
+
+        // artefacts come before the end of the job, and do not end it
+        assert!(archived.accept(&artefact));
+        assert!(archived.accept(&artefact));
+        assert!(archived.accept(&completion));
+        assert!(!archived.accept(&completion));
+        // nor are those that come right after it dropped
+        assert!(archived.accept(&artefact));
+        assert!(archived.is_empty());
+
+        // until the grace period passes
+        archived.insert("2");
+        archived.grace = Duration::ZERO;
+        let mut completion = completion.clone();
+        completion.jobid = "2".to_owned();
+        artefact.jobid = "2".to_owned();
+        assert!(archived.accept(&completion));
+        assert!(!archived.accept(&artefact));
+    }
+
+    #[test]
+    fn test_tail() {
+        let tdir = tempdir().unwrap();
+        let path = tdir.path().join("jobcomp.log");
+        std::fs::write(&path, "JobId=1 JobState=COMPLETED\n").unwrap();
+        let (tx, rx) = unbounded();
+        let (sig_tx, sig_rx) = unbounded();
+
+        scope(|s| {
+            s.spawn(|_| tail(&path, "mycluster", &tx, &sig_rx, false).unwrap());
+
+            let append = |line: &str| {
+                let mut f = OpenOptions::new().append(true).open(&path).unwrap();
+                writeln!(f, "{line}").unwrap();
+            };
+            std::thread::sleep(Duration::from_millis(200));
+            append("JobId=2 JobState=COMPLETED");
+            let completion = rx.recv_timeout(Duration::from_secs(5)).unwrap();
+            assert_eq!(completion.jobid, "2");
+
+            // After rotation, the new log is read from the start
+            rename(&path, tdir.path().join("jobcomp.log.1")).unwrap();
+            std::fs::write(&path, "JobId=3 JobState=FAILED\n").unwrap();
+            let completion = rx.recv_timeout(Duration::from_secs(5)).unwrap();
+            assert_eq!(completion.jobid, "3");
+
+            sig_tx.send(true).unwrap();
+        })
+        .unwrap();
+        assert!(rx.try_recv().is_err());
+    }
+}
@@ -0,0 +1,268 @@
+/*
+Copyright 2019-2024 Andy Georges <itkovian+sarchive@gmail.com>
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in
+all copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+use clap::Args;
+use crossbeam_channel::{Receiver, RecvTimeoutError};
+use log::{debug, error, info, warn};
+use std::fs::remove_file;
+use std::io::{Error, ErrorKind, Read, Write};
+use std::net::Shutdown;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering::SeqCst;
+use std::thread::sleep;
+use std::time::Duration;
+
+use crate::maintenance::Maintenance;
+use crate::scheduler::job::JobInfo;
+use crate::stats::Stats;
+
+/// How long to wait between checking for connections on the control socket
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How long to wait for a request before answering with the statistics
+const REQUEST_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// Command line options for the status subcommand
+#[derive(Args, Debug)]
+pub struct StatusArgs {
+    #[arg(
+        long,
+        help = "Path to the control socket of the running sarchive instance"
+    )]
+    pub socket: PathBuf,
+}
+
+/// The serve function listens on a Unix domain socket at the given path and
+/// answers every connection. A connection that sends `pause` or `resume`
+/// starts or ends maintenance, one that sends `ready` learns whether sarchive
+/// is ready, others get a report of the current statistics.
+/// At the same time, it checks if there is an incoming notification that it should
+/// stop. Upon receipt, it removes the socket and returns.
+pub fn serve(
+    path: &Path,
+    stats: &Stats,
+    queue: &Receiver<Box<dyn JobInfo>>,
+    sigchannel: &Receiver<bool>,
+    maintenance: &Maintenance,
+) -> Result<(), Error> {
+    // A socket left behind by a previous run prevents binding
+    if path.exists() {
+        warn!("Removing existing control socket {:?}", path);
+        remove_file(path)?;
+    }
+    let listener = UnixListener::bind(path)?;
+    listener.set_nonblocking(true)?;
+
+    info!("Listening for status requests on {:?}", path);
+
+    let result = loop {
+        if let Ok(true) = sigchannel.try_recv() {
+            break Ok(());
+        }
+        match listener.accept() {
+            Ok((stream, _)) => {
+                if let Err(e) = respond(stream, stats, queue, maintenance) {
+                    warn!("Could not answer control request: {}", e);
+                }
+            }
+            Err(e) if e.kind() == ErrorKind::WouldBlock => sleep(POLL_INTERVAL),
+            Err(e) => {
+                error!("Error on control socket {:?}: {}", path, e);
+                break Err(e);
+            }
+        }
+    };
+
+    remove_file(path)?;
+    result
+}
+
+/// The dump function writes a report of the current statistics to the log
+/// whenever the dump flag is raised (e.g., on SIGUSR1), until it is notified
+/// that it should stop.
+pub fn dump(
+    flag: &AtomicBool,
+    stats: &Stats,
+    queue: &Receiver<Box<dyn JobInfo>>,
+    sigchannel: &Receiver<bool>,
+) {
+    loop {
+        match sigchannel.recv_timeout(POLL_INTERVAL) {
+            Ok(true) | Err(RecvTimeoutError::Disconnected) => break,
+            _ => (),
+        }
+        if flag.swap(false, SeqCst) {
+            for line in stats.report(queue.len()).lines() {
+                info!("State: {}", line);
+            }
+        }
+    }
+}
+
+/// Reads the request, if the client sends one before closing its end, and answers it
+fn respond(
+    mut stream: UnixStream,
+    stats: &Stats,
+    queue: &Receiver<Box<dyn JobInfo>>,
+    maintenance: &Maintenance,
+) -> Result<(), Error> {
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+    let mut request = Vec::new();
+    match stream.read_to_end(&mut request) {
+        Ok(_) => (),
+        Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => (),
+        Err(e) => return Err(e),
+    }
+    let request = String::from_utf8_lossy(&request);
+    debug!("Received control request {:?}", request.trim());
+    let answer = match request.trim() {
+        "" | "status" => stats.report(queue.len()),
+        "pause" => {
+            maintenance.pause();
+            "archival paused\n".to_owned()
+        }
+        "resume" => {
+            maintenance.resume();
+            "archival resumed\n".to_owned()
+        }
+        "ready" if stats.is_ready() => "ready\n".to_owned(),
+        "ready" => "not ready\n".to_owned(),
+        other => format!("unknown request {other:?}\n"),
+    };
+    stream.write_all(answer.as_bytes())
+}
+
+/// Sends the request to the instance listening on the given control socket
+/// and returns the answer
+pub fn request(path: &Path, request: &str) -> Result<String, Error> {
+    let mut stream = UnixStream::connect(path)?;
+    writeln!(stream, "{request}")?;
+    stream.shutdown(Shutdown::Write)?;
+    let mut answer = String::new();
+    stream.read_to_string(&mut answer)?;
+    Ok(answer)
+}
+
+/// Retrieves the status report from the instance listening on the given control socket
+pub fn status(path: &Path) -> Result<String, Error> {
+    request(path, "status")
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crossbeam_channel::unbounded;
+    use crossbeam_utils::thread::scope;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_serve_status() {
+        let tdir = tempdir().unwrap();
+        let socket = tdir.path().join("control.sock");
+        let stats = Stats::new();
+        stats.job(Path::new("/spool/hash.0"));
+        let maintenance = Maintenance::default();
+
+        let (_tx, rx) = unbounded::<Box<dyn JobInfo>>();
+        let (sig_tx, sig_rx) = unbounded();
+
+        scope(|s| {
+            let (sp, st, q) = (&socket, &stats, &rx);
+            let m = &maintenance;
+            let server = s.spawn(move |_| serve(sp, st, q, &sig_rx, m));
+
+            // Give the server some time to bind the socket
+            sleep(Duration::from_millis(300));
+
+            let report = status(&socket).unwrap();
+            assert!(report.contains("queue length: 0\n"));
+            assert!(report.contains("location /spool/hash.0: 0 events, 1 jobs, last event never\n"));
+
+            // a client that sends nothing gets the report as well
+            let mut report = String::new();
+            UnixStream::connect(&socket)
+                .unwrap()
+                .read_to_string(&mut report)
+                .unwrap();
+            assert!(report.contains("queue length: 0\n"));
+
+            assert_eq!(request(&socket, "pause").unwrap(), "archival paused\n");
+            assert!(maintenance.paused());
+            assert_eq!(request(&socket, "resume").unwrap(), "archival resumed\n");
+            assert!(!maintenance.paused());
+            assert!(request(&socket, "reboot").unwrap().starts_with("unknown request"));
+            assert_eq!(request(&socket, "ready").unwrap(), "not ready\n");
+            stats.set_ready(&[]);
+            assert_eq!(request(&socket, "ready").unwrap(), "ready\n");
+
+            sig_tx.send(true).unwrap();
+            assert!(server.join().unwrap().is_ok());
+        })
+        .unwrap();
+
+        assert!(!socket.exists());
+    }
+
+    #[test]
+    fn test_serve_stale_socket() {
+        let tdir = tempdir().unwrap();
+        let socket = tdir.path().join("control.sock");
+        let _stale = UnixListener::bind(&socket).unwrap();
+
+        let stats = Stats::new();
+        let (_tx, rx) = unbounded::<Box<dyn JobInfo>>();
+        let (sig_tx, sig_rx) = unbounded();
+        sig_tx.send(true).unwrap();
+
+        assert!(serve(&socket, &stats, &rx, &sig_rx, &Maintenance::default()).is_ok());
+        assert!(!socket.exists());
+    }
+
+    #[test]
+    fn test_dump() {
+        let stats = Stats::new();
+        let flag = AtomicBool::new(true);
+        let (_tx, rx) = unbounded::<Box<dyn JobInfo>>();
+        let (sig_tx, sig_rx) = unbounded();
+
+        scope(|s| {
+            let (f, st, q) = (&flag, &stats, &rx);
+            let dumper = s.spawn(move |_| dump(f, st, q, &sig_rx));
+
+            sleep(Duration::from_millis(300));
+            assert!(!flag.load(SeqCst));
+
+            sig_tx.send(true).unwrap();
+            dumper.join().unwrap();
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn test_status_no_socket() {
+        let tdir = tempdir().unwrap();
+        assert!(status(&tdir.path().join("control.sock")).is_err());
+    }
+}
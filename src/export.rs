@@ -0,0 +1,503 @@
+/*
+Copyright 2019-2024 Andy Georges <itkovian+sarchive@gmail.com>
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in
+all copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+use chrono::NaiveDate;
+use clap::{Args, ValueEnum};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::io::{Error, Write};
+use std::path::{Path, PathBuf};
+
+use crate::index::{ArchivedJob, Index, Layout};
+use crate::scheduler::job::{
+    accelerators, lookup, script_job_name, script_partition, Accelerators, JOB_NAME_VARIABLES,
+    PARTITION_VARIABLES,
+};
+
+/// Columns of the CSV and Parquet exports, in order
+pub const COLUMNS: [&str; 10] = [
+    "jobid",
+    "date",
+    "user",
+    "job_name",
+    "partition",
+    "gpus",
+    "gpus_per_node",
+    "gpus_per_task",
+    "gpu_type",
+    "script",
+];
+
+/// Formats the archive can be exported to
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ExportFormat {
+    /// One JSON document per job, with its environment
+    Jsonl,
+    /// One row per job, with a header line
+    Csv,
+    /// Columnar, for analysis tools; needs the parquet feature
+    Parquet,
+}
+
+/// Command line options for the export subcommand
+#[derive(Args, Debug)]
+pub struct ExportArgs {
+    #[arg(long, help = "Archive directory written by the file archiver")]
+    pub from: PathBuf,
+
+    #[arg(
+        long,
+        value_name = "TEMPLATE",
+        help = "Name template the archive was written with, if any, to find the job files by"
+    )]
+    pub name_template: Option<String>,
+
+    #[arg(
+        long,
+        value_enum,
+        default_value = "jsonl",
+        help = "Format to export to"
+    )]
+    pub format: ExportFormat,
+
+    #[arg(
+        long,
+        value_name = "DATE",
+        help = "Only export the jobs archived on or after this day, as YYYY-MM-DD"
+    )]
+    pub since: Option<NaiveDate>,
+
+    #[arg(
+        long,
+        value_name = "DATE",
+        help = "Only export the jobs archived on or before this day, as YYYY-MM-DD"
+    )]
+    pub until: Option<NaiveDate>,
+
+    #[arg(
+        long,
+        value_name = "FILE",
+        help = "File to write the export to, instead of the standard output"
+    )]
+    pub output: Option<PathBuf>,
+}
+
+/// An archived job, with the fields analysts look for taken from its script
+/// and environment
+#[derive(Debug, PartialEq, Eq)]
+pub struct ExportedJob {
+    pub jobid: String,
+    pub date: NaiveDate,
+    pub user: Option<String>,
+    pub job_name: Option<String>,
+    pub partition: Option<String>,
+    pub accelerators: Accelerators,
+    pub script: Option<String>,
+    pub environment: Option<HashMap<String, String>>,
+}
+
+impl ExportedJob {
+    pub fn new(job: &ArchivedJob) -> Self {
+        let script = job.script();
+        let environment = job.environment();
+        let attribute = |names: &[&str], from_script: fn(&str) -> Option<String>| {
+            environment
+                .as_ref()
+                .and_then(|env| lookup(env, names))
+                .or_else(|| script.as_deref().and_then(from_script))
+        };
+        ExportedJob {
+            jobid: job.jobid.clone(),
+            date: job.date,
+            user: job.user.clone(),
+            job_name: attribute(&JOB_NAME_VARIABLES, script_job_name),
+            partition: attribute(&PARTITION_VARIABLES, script_partition),
+            accelerators: accelerators(environment.as_ref(), script.as_deref().unwrap_or("")),
+            script,
+            environment,
+        }
+    }
+
+    /// Returns the values of the job for the COLUMNS, empty when unknown
+    fn row(&self) -> [String; COLUMNS.len()] {
+        let text = |v: &Option<String>| v.clone().unwrap_or_default();
+        let number = |v: Option<u64>| v.map(|n| n.to_string()).unwrap_or_default();
+        [
+            self.jobid.clone(),
+            self.date.to_string(),
+            text(&self.user),
+            text(&self.job_name),
+            text(&self.partition),
+            number(self.accelerators.gpus),
+            number(self.accelerators.gpus_per_node),
+            number(self.accelerators.gpus_per_task),
+            text(&self.accelerators.gpu_type),
+            text(&self.script),
+        ]
+    }
+
+    fn document(&self) -> Value {
+        json!({
+            "jobid": self.jobid,
+            "date": self.date,
+            "user": self.user,
+            "job_name": self.job_name,
+            "partition": self.partition,
+            "gpus": self.accelerators.gpus,
+            "gpus_per_node": self.accelerators.gpus_per_node,
+            "gpus_per_task": self.accelerators.gpus_per_task,
+            "gpu_type": self.accelerators.gpu_type,
+            "script": self.script,
+            "environment": self.environment,
+        })
+    }
+}
+
+/// Returns the field quoted for CSV, if it holds a separator, a quote or a
+/// line break
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_owned()
+    }
+}
+
+fn csv_line(fields: &[&str]) -> String {
+    let fields: Vec<String> = fields.iter().map(|f| csv_field(f)).collect();
+    fields.join(",") + "\n"
+}
+
+/// Returns the jobs in the archive archived between since and until,
+/// inclusive, oldest first
+pub fn select(
+    archive: &Path,
+    layout: &Layout,
+    since: Option<NaiveDate>,
+    until: Option<NaiveDate>,
+) -> Result<Vec<ArchivedJob>, Error> {
+    let mut jobs: Vec<ArchivedJob> = Index::build(archive, layout)?
+        .jobs
+        .into_iter()
+        .filter(|job| since.iter().all(|d| job.date >= *d) && until.iter().all(|d| job.date <= *d))
+        .collect();
+    jobs.sort_by(|a, b| a.date.cmp(&b.date).then_with(|| a.jobid.cmp(&b.jobid)));
+    Ok(jobs)
+}
+
+/// Writes the selected jobs of the archive in the format, returning how many
+/// were written
+pub fn export<W: Write + Send>(
+    archive: &Path,
+    layout: &Layout,
+    format: ExportFormat,
+    since: Option<NaiveDate>,
+    until: Option<NaiveDate>,
+    mut out: W,
+) -> Result<usize, Error> {
+    let jobs = select(archive, layout, since, until)?;
+    match format {
+        ExportFormat::Jsonl => {
+            for job in jobs.iter() {
+                writeln!(out, "{}", ExportedJob::new(job).document())?;
+            }
+        }
+        ExportFormat::Csv => {
+            out.write_all(csv_line(&COLUMNS).as_bytes())?;
+            for job in jobs.iter() {
+                let row = ExportedJob::new(job).row();
+                let fields: Vec<&str> = row.iter().map(|f| f.as_str()).collect();
+                out.write_all(csv_line(&fields).as_bytes())?;
+            }
+        }
+        #[cfg(feature = "parquet")]
+        ExportFormat::Parquet => columnar::write(&jobs, &mut out)?,
+        #[cfg(not(feature = "parquet"))]
+        ExportFormat::Parquet => {
+            return Err(Error::new(
+                std::io::ErrorKind::Unsupported,
+                "sarchive was built without the parquet feature",
+            ))
+        }
+    }
+    out.flush()?;
+    Ok(jobs.len())
+}
+
+#[cfg(feature = "parquet")]
+mod columnar {
+    use chrono::NaiveDate;
+    use parquet::data_type::{ByteArray, ByteArrayType, Int32Type, Int64Type};
+    use parquet::file::properties::WriterProperties;
+    use parquet::file::writer::SerializedFileWriter;
+    use parquet::schema::parser::parse_message_type;
+    use std::io::{Error, Write};
+    use std::sync::Arc;
+
+    use super::ExportedJob;
+    use crate::index::ArchivedJob;
+
+    /// Schema of the Parquet export, with the columns of the CSV export and
+    /// the day as a date
+    const SCHEMA: &str = "message sarchive_job {
+        REQUIRED BYTE_ARRAY jobid (UTF8);
+        REQUIRED INT32 date (DATE);
+        OPTIONAL BYTE_ARRAY user (UTF8);
+        OPTIONAL BYTE_ARRAY job_name (UTF8);
+        OPTIONAL BYTE_ARRAY partition (UTF8);
+        OPTIONAL INT64 gpus;
+        OPTIONAL INT64 gpus_per_node;
+        OPTIONAL INT64 gpus_per_task;
+        OPTIONAL BYTE_ARRAY gpu_type (UTF8);
+        OPTIONAL BYTE_ARRAY script (UTF8);
+    }";
+
+    /// This many jobs go in a row group, so their scripts need not all be
+    /// held at once
+    const ROW_GROUP_SIZE: usize = 10_000;
+
+    /// The values of an optional column, with their definition levels
+    fn optional<T, V>(
+        values: impl Iterator<Item = Option<T>>,
+        f: impl Fn(T) -> V,
+    ) -> (Vec<V>, Vec<i16>) {
+        let mut defined = Vec::new();
+        let mut levels = Vec::new();
+        for value in values {
+            levels.push(value.is_some() as i16);
+            defined.extend(value.map(&f));
+        }
+        (defined, levels)
+    }
+
+    fn text(value: &Option<String>) -> Option<ByteArray> {
+        value.as_deref().map(ByteArray::from)
+    }
+
+    pub fn write<W: Write + Send>(jobs: &[ArchivedJob], out: W) -> Result<(), Error> {
+        let epoch = NaiveDate::from_ymd_opt(1970, 1, 1).unwrap();
+        let schema = Arc::new(parse_message_type(SCHEMA).map_err(Error::other)?);
+        let properties = Arc::new(WriterProperties::builder().build());
+        let mut writer =
+            SerializedFileWriter::new(out, schema, properties).map_err(Error::other)?;
+        for chunk in jobs.chunks(ROW_GROUP_SIZE) {
+            let rows: Vec<ExportedJob> = chunk.iter().map(ExportedJob::new).collect();
+            let strings =
+                |f: fn(&ExportedJob) -> Option<ByteArray>| optional(rows.iter().map(f), |v| v);
+            let numbers =
+                |f: fn(&ExportedJob) -> Option<u64>| optional(rows.iter().map(f), |n| n as i64);
+            let mut group = writer.next_row_group().map_err(Error::other)?;
+            let mut index = 0;
+            while let Some(mut column) = group.next_column().map_err(Error::other)? {
+                match index {
+                    0 => {
+                        let ids: Vec<ByteArray> = rows
+                            .iter()
+                            .map(|r| ByteArray::from(r.jobid.as_str()))
+                            .collect();
+                        column
+                            .typed::<ByteArrayType>()
+                            .write_batch(&ids, None, None)
+                    }
+                    1 => {
+                        let days: Vec<i32> = rows
+                            .iter()
+                            .map(|r| (r.date - epoch).num_days() as i32)
+                            .collect();
+                        column.typed::<Int32Type>().write_batch(&days, None, None)
+                    }
+                    5..=7 => {
+                        let (values, levels) = numbers(match index {
+                            5 => |r| r.accelerators.gpus,
+                            6 => |r| r.accelerators.gpus_per_node,
+                            _ => |r| r.accelerators.gpus_per_task,
+                        });
+                        column
+                            .typed::<Int64Type>()
+                            .write_batch(&values, Some(&levels), None)
+                    }
+                    _ => {
+                        let (values, levels) = strings(match index {
+                            2 => |r| text(&r.user),
+                            3 => |r| text(&r.job_name),
+                            4 => |r| text(&r.partition),
+                            8 => |r| text(&r.accelerators.gpu_type),
+                            _ => |r| text(&r.script),
+                        });
+                        column
+                            .typed::<ByteArrayType>()
+                            .write_batch(&values, Some(&levels), None)
+                    }
+                }
+                .map_err(Error::other)?;
+                column.close().map_err(Error::other)?;
+                index += 1;
+            }
+            group.close().map_err(Error::other)?;
+        }
+        writer.close().map_err(Error::other)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::archive;
+    use chrono::{Days, Local};
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_exported_job() {
+        let tdir = tempdir().unwrap();
+        archive(tdir.path());
+        let jobs = select(tdir.path(), &Layout::default(), None, None).unwrap();
+        let job = ExportedJob::new(&jobs[0]);
+        assert_eq!(job.jobid, "123");
+        assert_eq!(job.user.as_deref(), Some("alice"));
+        assert_eq!(job.job_name.as_deref(), Some("train, again"));
+        assert_eq!(job.partition.as_deref(), Some("gpu"));
+        assert_eq!(job.accelerators.gpus_per_node, Some(2));
+        assert_eq!(job.environment.unwrap().len(), 3);
+
+        let job = ExportedJob::new(&jobs[1]);
+        assert_eq!(job.user, None);
+        assert!(job.accelerators.is_empty());
+        assert_eq!(job.script.as_deref(), Some("#!/bin/sh\n"));
+    }
+
+    #[test]
+    fn test_export() {
+        let tdir = tempdir().unwrap();
+        archive(tdir.path());
+        let today = Local::now().date_naive();
+
+        let mut out = Vec::new();
+        let n = export(
+            tdir.path(),
+            &Layout::default(),
+            ExportFormat::Jsonl,
+            Some(today),
+            Some(today),
+            &mut out,
+        )
+        .unwrap();
+        assert_eq!(n, 3);
+        let docs: Vec<Value> = String::from_utf8(out)
+            .unwrap()
+            .lines()
+            .map(|l| serde_json::from_str(l).unwrap())
+            .collect();
+        assert_eq!(docs[0]["jobid"], "123");
+        assert_eq!(docs[0]["gpus_per_node"], 2);
+        assert_eq!(docs[0]["gpu_type"], "a100");
+        assert_eq!(docs[0]["environment"]["SLURM_JOB_USER"], "alice");
+        assert_eq!(docs[1]["environment"], Value::Null);
+
+        let mut out = Vec::new();
+        export(
+            tdir.path(),
+            &Layout::default(),
+            ExportFormat::Csv,
+            None,
+            None,
+            &mut out,
+        )
+        .unwrap();
+        let csv = String::from_utf8(out).unwrap();
+        assert!(csv.starts_with("jobid,date,user,job_name,partition,gpus,"));
+        assert!(csv.contains(&format!(
+            "123,{today},alice,\"train, again\",gpu,,2,,a100,\"#!/bin/bash\n#SBATCH -J \"\"train, again\"\"\n"
+        )));
+        assert!(csv.contains(&format!("\n4567,{today},,,,,,,,\"#!/bin/sh\n\"\n")));
+        assert!(csv.ends_with(&format!("890,{today},,,,,,,,\"#!/bin/bash\nhostname\n\"\n")));
+
+        let tomorrow = today.checked_add_days(Days::new(1)).unwrap();
+        let yesterday = today.checked_sub_days(Days::new(1)).unwrap();
+        let n = export(
+            tdir.path(),
+            &Layout::default(),
+            ExportFormat::Jsonl,
+            Some(tomorrow),
+            None,
+            Vec::new(),
+        )
+        .unwrap();
+        assert_eq!(n, 0);
+        let n = export(
+            tdir.path(),
+            &Layout::default(),
+            ExportFormat::Csv,
+            None,
+            Some(yesterday),
+            Vec::new(),
+        )
+        .unwrap();
+        assert_eq!(n, 0);
+    }
+
+    #[cfg(not(feature = "parquet"))]
+    #[test]
+    fn test_export_parquet_unsupported() {
+        let tdir = tempdir().unwrap();
+        assert!(export(
+            tdir.path(),
+            &Layout::default(),
+            ExportFormat::Parquet,
+            None,
+            None,
+            Vec::new()
+        )
+        .is_err());
+    }
+
+    #[cfg(feature = "parquet")]
+    #[test]
+    fn test_export_parquet() {
+        use parquet::file::reader::{FileReader, SerializedFileReader};
+        use std::fs::File;
+
+        let tdir = tempdir().unwrap();
+        archive(&tdir.path().join("archive"));
+        let path = tdir.path().join("jobs.parquet");
+        let n = export(
+            &tdir.path().join("archive"),
+            &Layout::default(),
+            ExportFormat::Parquet,
+            None,
+            None,
+            File::create(&path).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(n, 3);
+        let reader = SerializedFileReader::new(File::open(&path).unwrap()).unwrap();
+        let metadata = reader.metadata().file_metadata();
+        assert_eq!(metadata.num_rows(), 3);
+        let names: Vec<&str> = metadata
+            .schema_descr()
+            .columns()
+            .iter()
+            .map(|c| c.name())
+            .collect();
+        assert_eq!(names, COLUMNS);
+    }
+}
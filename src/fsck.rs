@@ -0,0 +1,339 @@
+/*
+Copyright 2019-2024 Andy Georges <itkovian+sarchive@gmail.com>
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in
+all copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+use clap::Args;
+use log::debug;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fs::{read_dir, read_to_string, symlink_metadata, File};
+use std::io::{sink, Error};
+use std::path::{Path, PathBuf};
+
+use crate::archive::cas::{hashing_copy, manifest_name, object_path, parse_manifest, OBJECTS_DIR};
+
+/// Suffix of the files holding the checksum of the file next to them
+const CHECKSUM_SUFFIX: &str = ".sha256";
+
+#[derive(Args, Debug)]
+pub struct FsckArgs {
+    #[arg(
+        long,
+        help = "Archive directory to verify, as given to the file archiver (with {cluster} filled in)"
+    )]
+    pub archive: PathBuf,
+}
+
+/// An inconsistency found in the archive
+#[derive(Debug, PartialEq, Eq)]
+pub enum Problem {
+    /// The manifest cannot be read or parsed
+    BadManifest { manifest: PathBuf, reason: String },
+    /// The manifest lists a file whose contents are not stored
+    Missing { manifest: PathBuf, name: String },
+    /// The stored contents cannot be read, or do not match their hash or
+    /// the size in a manifest
+    Corrupt { path: PathBuf, reason: String },
+    /// The file does not match the checksum archived along with it
+    Checksum { path: PathBuf, expected: String },
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Problem::BadManifest { manifest, reason } => {
+                write!(f, "bad manifest {}: {}", manifest.display(), reason)
+            }
+            Problem::Missing { manifest, name } => {
+                write!(f, "missing {} of manifest {}", name, manifest.display())
+            }
+            Problem::Corrupt { path, reason } => {
+                write!(f, "corrupt {}: {}", path.display(), reason)
+            }
+            Problem::Checksum { path, expected } => {
+                write!(
+                    f,
+                    "checksum mismatch {}: expected {}",
+                    path.display(),
+                    expected
+                )
+            }
+        }
+    }
+}
+
+/// What was verified in the archive, and the problems found
+#[derive(Debug, Default)]
+pub struct Report {
+    pub manifests: usize,
+    pub objects: usize,
+    pub checksums: usize,
+    /// Stored contents that no manifest refers to, e.g., left by a job whose
+    /// manifest was removed; these take space but are not an inconsistency
+    pub unreferenced: usize,
+    pub problems: Vec<Problem>,
+}
+
+/// Verifies the archive without changing it: every stored content must match
+/// its hash, every file listed in a manifest must be stored with the listed
+/// size, and every archived checksum must match the file it belongs to.
+pub fn fsck(archive: &Path) -> Result<Report, Error> {
+    let mut report = Report::default();
+
+    // hash and size of the intact contents in the store
+    let mut stored: HashMap<String, u64> = HashMap::new();
+    let objects = archive.join(OBJECTS_DIR);
+    if objects.is_dir() {
+        for path in files(&objects)? {
+            let Some(name) = path.file_name().and_then(|n| n.to_str()) else {
+                continue;
+            };
+            // left by an interrupted write
+            if name.starts_with('.') {
+                debug!("Skipping temporary file {:?}", &path);
+                continue;
+            }
+            report.objects += 1;
+            match hash_file(&path) {
+                Ok((hash, size)) if hash == name && path == object_path(archive, &hash) => {
+                    stored.insert(hash, size);
+                }
+                Ok((hash, _)) => report.problems.push(Problem::Corrupt {
+                    path,
+                    reason: format!("contents hash to {hash}"),
+                }),
+                Err(e) => report.problems.push(Problem::Corrupt {
+                    path,
+                    reason: e.to_string(),
+                }),
+            }
+        }
+    }
+
+    let mut referenced = HashSet::new();
+    for path in files(archive)? {
+        if path.starts_with(&objects) {
+            continue;
+        }
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        if is_manifest(&name) {
+            report.manifests += 1;
+            let entries = match read_to_string(&path).and_then(|c| parse_manifest(&c)) {
+                Ok(entries) => entries,
+                Err(e) => {
+                    report.problems.push(Problem::BadManifest {
+                        manifest: path,
+                        reason: e.to_string(),
+                    });
+                    continue;
+                }
+            };
+            for entry in entries.iter() {
+                referenced.insert(entry.hash.clone());
+                let object = object_path(archive, &entry.hash);
+                match stored.get(&entry.hash) {
+                    Some(&size) if size == entry.size => (),
+                    Some(&size) => report.problems.push(Problem::Corrupt {
+                        path: object,
+                        reason: format!(
+                            "{} bytes, but {} lists {} with {} bytes",
+                            size,
+                            path.display(),
+                            entry.name,
+                            entry.size
+                        ),
+                    }),
+                    // already reported when it was found corrupt
+                    None if object.exists() => (),
+                    None => report.problems.push(Problem::Missing {
+                        manifest: path.clone(),
+                        name: entry.name.clone(),
+                    }),
+                }
+            }
+            // the checksum files are stored like any other file
+            for entry in entries.iter() {
+                let Some(target) = entry.name.strip_suffix(CHECKSUM_SUFFIX) else {
+                    continue;
+                };
+                let Some(file) = entries.iter().find(|e| e.name == target) else {
+                    continue;
+                };
+                let Ok(expected) = read_to_string(object_path(archive, &entry.hash)) else {
+                    continue;
+                };
+                report.checksums += 1;
+                if expected.trim() != file.hash {
+                    report.problems.push(Problem::Checksum {
+                        path: object_path(archive, &file.hash),
+                        expected: expected.trim().to_owned(),
+                    });
+                }
+            }
+        } else if let Some(target) = name.strip_suffix(CHECKSUM_SUFFIX) {
+            let target = path.with_file_name(target);
+            if !target.is_file() {
+                continue;
+            }
+            report.checksums += 1;
+            let expected = match read_to_string(&path) {
+                Ok(expected) => expected.trim().to_owned(),
+                Err(e) => {
+                    report.problems.push(Problem::Corrupt {
+                        path,
+                        reason: e.to_string(),
+                    });
+                    continue;
+                }
+            };
+            match hash_file(&target) {
+                Ok((hash, _)) if hash == expected => (),
+                Ok(_) => report.problems.push(Problem::Checksum {
+                    path: target,
+                    expected,
+                }),
+                Err(e) => report.problems.push(Problem::Corrupt {
+                    path: target,
+                    reason: e.to_string(),
+                }),
+            }
+        }
+    }
+    report.unreferenced = stored
+        .keys()
+        .filter(|hash| !referenced.contains(*hash))
+        .count();
+    Ok(report)
+}
+
+/// Whether the file name is that of the manifest of a job
+fn is_manifest(name: &str) -> bool {
+    name.strip_prefix("job.")
+        .and_then(|n| n.strip_suffix("_manifest"))
+        .is_some_and(|jobid| !jobid.is_empty() && manifest_name(jobid) == name)
+}
+
+/// Returns the hex encoded SHA-256 hash and the size of the file's contents
+fn hash_file(path: &Path) -> Result<(String, u64), Error> {
+    hashing_copy(&mut File::open(path)?, &mut sink())
+}
+
+/// Returns the regular files under the directory, without following
+/// symlinks, sorted
+pub(crate) fn files(dir: &Path) -> Result<Vec<PathBuf>, Error> {
+    let mut found = Vec::new();
+    let mut pending = vec![dir.to_path_buf()];
+    while let Some(dir) = pending.pop() {
+        for entry in read_dir(&dir)? {
+            let path = entry?.path();
+            match symlink_metadata(&path) {
+                Ok(m) if m.is_dir() => pending.push(path),
+                Ok(m) if m.is_file() => found.push(path),
+                _ => (),
+            }
+        }
+    }
+    found.sort();
+    Ok(found)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::archive::dedup::content_hash;
+    use std::fs::{create_dir_all, remove_file, write};
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_fsck() {
+        let archive = tempdir().unwrap();
+        let root = archive.path();
+        let store = |contents: &[u8]| {
+            let hash = content_hash(contents);
+            let path = object_path(root, &hash);
+            create_dir_all(path.parent().unwrap()).unwrap();
+            write(&path, contents).unwrap();
+            hash
+        };
+        let script = store(b"#!/bin/bash\necho hi\n");
+        let checksum = store(format!("{script}\n").as_bytes());
+        let environ = store(b"A=1");
+        let manifest = format!(
+            "{script} 20 job.1_script\n{checksum} 65 job.1_script.sha256\n{environ} 3 job.1_environment\n"
+        );
+        write(root.join("job.1_manifest"), &manifest).unwrap();
+        write(root.join("job.2_manifest"), &manifest).unwrap();
+        // a plain archived file with its checksum
+        write(root.join("job.3_script"), b"echo hi").unwrap();
+        write(
+            root.join("job.3_script.sha256"),
+            content_hash(b"echo hi").as_bytes(),
+        )
+        .unwrap();
+        store(b"unreferenced");
+        // not manifests, despite their names
+        write(root.join("job.3_notes.manifest"), b"not a manifest").unwrap();
+        write(root.join("job.3_manifest.txt"), b"not a manifest").unwrap();
+        write(root.join(OBJECTS_DIR).join(".123.0"), b"partial").unwrap();
+
+        let report = fsck(root).unwrap();
+        assert_eq!(report.problems, vec![]);
+        assert_eq!(report.manifests, 2);
+        assert_eq!(report.objects, 4);
+        assert_eq!(report.checksums, 3);
+        assert_eq!(report.unreferenced, 1);
+
+        // damage the archive in every way fsck knows of
+        write(object_path(root, &environ), b"A=2").unwrap();
+        remove_file(object_path(root, &checksum)).unwrap();
+        write(root.join("job.3_script"), b"echo ho").unwrap();
+        write(root.join("job.4_manifest"), b"not a manifest").unwrap();
+        // neither can be read as text, but the run goes on
+        write(root.join("job.5_manifest"), b"\xff\xfe").unwrap();
+        write(root.join("job.6_script"), b"echo hi").unwrap();
+        write(root.join("job.6_script.sha256"), b"\xff\xfe").unwrap();
+
+        let report = fsck(root).unwrap();
+        let problems: Vec<String> = report.problems.iter().map(|p| p.to_string()).collect();
+        assert_eq!(report.problems.len(), 7, "{problems:?}");
+        assert!(
+            matches!(&report.problems[0], Problem::Corrupt { path, .. } if *path == object_path(root, &environ))
+        );
+        for (i, jobid) in [(1, 1), (2, 2)] {
+            assert_eq!(
+                report.problems[i],
+                Problem::Missing {
+                    manifest: root.join(format!("job.{jobid}_manifest")),
+                    name: "job.1_script.sha256".to_owned()
+                }
+            );
+        }
+        assert!(
+            matches!(&report.problems[3], Problem::Checksum { path, .. } if *path == root.join("job.3_script"))
+        );
+        assert!(matches!(&report.problems[4], Problem::BadManifest { .. }));
+        assert!(
+            matches!(&report.problems[5], Problem::BadManifest { manifest, .. } if *manifest == root.join("job.5_manifest"))
+        );
+        assert!(
+            matches!(&report.problems[6], Problem::Corrupt { path, .. } if *path == root.join("job.6_script.sha256"))
+        );
+    }
+}
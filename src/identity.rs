@@ -0,0 +1,124 @@
+/*
+Copyright 2019-2024 Andy Georges <itkovian+sarchive@gmail.com>
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in
+all copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+use std::collections::BTreeMap;
+use std::ffi::CStr;
+
+use crate::archive::dedup::content_hash;
+
+/// Identifies the sarchive instance that archived a job, so records coming
+/// from several instances can be told apart downstream.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Identity {
+    /// Name of the host sarchive runs on
+    pub hostname: String,
+    /// Version of sarchive
+    pub version: String,
+    /// Given instance ID, or a hash of the configuration
+    pub instance_id: String,
+    /// User defined labels (e.g., datacenter or environment) added to every
+    /// archived record
+    pub labels: BTreeMap<String, String>,
+}
+
+impl Identity {
+    /// Returns the identity of this instance. Without an explicit instance ID,
+    /// the first 16 hex digits of the hash of the given configuration are used.
+    pub fn new(instance_id: Option<String>, config: &str) -> Identity {
+        Identity {
+            hostname: hostname(),
+            version: env!("CARGO_PKG_VERSION").to_owned(),
+            instance_id: instance_id
+                .unwrap_or_else(|| content_hash(config.as_bytes())[..16].to_owned()),
+            labels: BTreeMap::new(),
+        }
+    }
+}
+
+impl Default for Identity {
+    fn default() -> Self {
+        Identity::new(None, "")
+    }
+}
+
+/// Parses a `key=value` label, splitting on the first '='
+pub fn parse_label(s: &str) -> Result<(String, String), String> {
+    match s.split_once('=') {
+        Some((key, value)) if !key.is_empty() => Ok((key.to_owned(), value.to_owned())),
+        _ => Err(format!("{s} is not of the form key=value")),
+    }
+}
+
+/// Returns the name of this host, or "unknown" if it cannot be determined
+pub(crate) fn hostname() -> String {
+    let mut buf = [0 as libc::c_char; 256];
+    // SAFETY: the buffer is valid for its full length, and the last byte is
+    // never written, so the name is always NUL terminated
+    let rc = unsafe { libc::gethostname(buf.as_mut_ptr(), buf.len() - 1) };
+    if rc != 0 {
+        return "unknown".to_owned();
+    }
+    // SAFETY: see above
+    unsafe { CStr::from_ptr(buf.as_ptr()) }
+        .to_string_lossy()
+        .into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_identity() {
+        let identity = Identity::new(None, "cluster=mycluster");
+        assert!(!identity.hostname.is_empty());
+        assert_eq!(identity.version, env!("CARGO_PKG_VERSION"));
+        assert_eq!(identity.instance_id.len(), 16);
+        assert_eq!(identity, Identity::new(None, "cluster=mycluster"));
+        assert_ne!(
+            identity.instance_id,
+            Identity::new(None, "cluster=other").instance_id
+        );
+
+        let identity = Identity::new(Some("ctl1".to_owned()), "cluster=mycluster");
+        assert_eq!(identity.instance_id, "ctl1");
+        assert!(identity.labels.is_empty());
+    }
+
+    #[test]
+    fn test_parse_label() {
+        assert_eq!(
+            parse_label("env=prod"),
+            Ok(("env".to_owned(), "prod".to_owned()))
+        );
+        assert_eq!(
+            parse_label("query=a=b"),
+            Ok(("query".to_owned(), "a=b".to_owned()))
+        );
+        assert_eq!(
+            parse_label("empty="),
+            Ok(("empty".to_owned(), String::new()))
+        );
+        assert!(parse_label("=prod").is_err());
+        assert!(parse_label("prod").is_err());
+    }
+}
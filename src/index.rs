@@ -0,0 +1,309 @@
+/*
+Copyright 2019-2024 Andy Georges <itkovian+sarchive@gmail.com>
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in
+all copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+use chrono::{DateTime, Local, NaiveDate};
+use log::warn;
+use regex::Regex;
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{read, read_to_string};
+use std::io::Error;
+use std::path::{Path, PathBuf};
+
+use crate::archive::cas::{object_path, parse_manifest};
+use crate::archive::file::check_name_template;
+use crate::fsck::files;
+use crate::scheduler::job::{lookup, USER_VARIABLES};
+
+/// Suffixes of the archived files that hold a job script
+pub const SCRIPT_FILES: [&str; 4] = ["script", "script_normalized", "jobfile", "SC"];
+
+/// A job found in the archive, with its archived files
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ArchivedJob {
+    pub jobid: String,
+    pub user: Option<String>,
+    /// Day the job was archived
+    pub date: NaiveDate,
+    /// The files, by the name the job gave them (e.g., script), and where
+    /// their contents are kept
+    pub files: BTreeMap<String, PathBuf>,
+}
+
+impl ArchivedJob {
+    /// Returns the archived script of the job, if there is one
+    pub fn script(&self) -> Option<String> {
+        SCRIPT_FILES
+            .iter()
+            .find_map(|name| self.files.get(*name))
+            .and_then(|path| read(path).ok())
+            .map(|contents| String::from_utf8_lossy(&contents).into_owned())
+    }
+
+    /// Returns the archived environment of the job, if there is one
+    pub fn environment(&self) -> Option<HashMap<String, String>> {
+        self.files
+            .get("environment")
+            .and_then(|path| read_environment(path))
+    }
+}
+
+/// The jobs in the archive, found by walking it
+#[derive(Debug, Default)]
+pub struct Index {
+    pub jobs: Vec<ArchivedJob>,
+}
+
+/// How the files in the archive are named: as the file archiver names them
+/// by default, or according to the name template it was given
+#[derive(Clone, Debug, Default)]
+pub struct Layout {
+    template: Option<Regex>,
+}
+
+impl Layout {
+    /// Returns the layout for the name template, which matches the path of an
+    /// archived file within the archive
+    pub fn new(template: Option<&str>) -> Result<Layout, Error> {
+        let Some(template) = template else {
+            return Ok(Layout::default());
+        };
+        check_name_template(template)?;
+        let mut pattern = regex::escape(template);
+        for (placeholder, matches) in [
+            ("jobid", "(?P<jobid>[^/]+?)"),
+            ("filename", "(?P<filename>[^/]+)"),
+            ("name", "(?P<name>[^/]+)"),
+        ] {
+            let escaped = regex::escape(&format!("{{{placeholder}}}"));
+            pattern = pattern
+                .replacen(&escaped, matches, 1)
+                .replace(&escaped, "[^/]+");
+        }
+        for (placeholder, matches) in [
+            ("cluster", "[^/]+"),
+            ("year", "[0-9]{4}"),
+            ("month", "[0-9]{2}"),
+            ("day", "[0-9]{2}"),
+        ] {
+            pattern = pattern.replace(&regex::escape(&format!("{{{placeholder}}}")), matches);
+        }
+        Regex::new(&format!("^{pattern}$"))
+            .map(|re| Layout { template: Some(re) })
+            .map_err(|e| Error::new(std::io::ErrorKind::InvalidInput, e.to_string()))
+    }
+
+    /// Returns the job ID and the name of the file, for a file at the given
+    /// path within the archive
+    fn split(&self, path: &Path) -> Option<(String, String)> {
+        let Some(template) = &self.template else {
+            return split_name(path);
+        };
+        let captures = template.captures(path.to_str()?)?;
+        let jobid = captures.name("jobid").map(|m| m.as_str().to_owned());
+        match (captures.name("name"), captures.name("filename")) {
+            (Some(name), _) => split_name(Path::new(name.as_str())),
+            (None, Some(filename)) => Some((jobid?, filename.as_str().to_owned())),
+            (None, None) => None,
+        }
+    }
+}
+
+/// Returns the job ID and the name of the file, for an archived file named
+/// `job.{jobid}_{name}` (Slurm and LSF) or `{jobid}.{suffix}` (Torque, named
+/// by the suffix)
+fn split_name(path: &Path) -> Option<(String, String)> {
+    let fname = path.file_name()?.to_str()?;
+    if let Some((jobid, name)) = fname.strip_prefix("job.").and_then(|f| f.split_once('_')) {
+        return Some((jobid.to_owned(), name.to_owned()));
+    }
+    let (jobid, suffix) = fname.rsplit_once('.')?;
+    let torque = jobid.starts_with(|c: char| c.is_ascii_digit())
+        && !suffix.is_empty()
+        && suffix.chars().all(|c| c.is_ascii_alphanumeric());
+    torque.then(|| (jobid.to_owned(), suffix.to_owned()))
+}
+
+/// Returns the path of the stored contents, under the first ancestor of the
+/// manifest that holds them
+fn stored(manifest: &Path, hash: &str) -> Option<PathBuf> {
+    manifest
+        .ancestors()
+        .skip(1)
+        .map(|root| object_path(root, hash))
+        .find(|path| path.is_file())
+}
+
+/// Returns the variables in an archived environment, which holds them
+/// separated by NUL bytes (Slurm) or newlines (Torque and LSF)
+fn read_environment(path: &Path) -> Option<HashMap<String, String>> {
+    let contents = read(path).ok()?;
+    let env = contents
+        .split(|b| *b == b'\0' || *b == b'\n')
+        .filter_map(|entry| {
+            let eq = entry.iter().position(|b| *b == b'=')?;
+            Some((
+                String::from_utf8_lossy(&entry[..eq]).into_owned(),
+                String::from_utf8_lossy(&entry[eq + 1..]).into_owned(),
+            ))
+        })
+        .collect();
+    Some(env)
+}
+
+impl Index {
+    /// Walks the archive, grouping the files of each job by the directory
+    /// they are in. Job files stored with --content-store are found through
+    /// their manifests.
+    pub fn build(archive: &Path, layout: &Layout) -> Result<Index, Error> {
+        let mut jobs: BTreeMap<(String, PathBuf), ArchivedJob> = BTreeMap::new();
+        for path in files(archive)? {
+            let within = path.strip_prefix(archive).unwrap_or(&path);
+            let Some((jobid, name)) = layout.split(within) else {
+                continue;
+            };
+            let Some(dir) = path.parent() else {
+                continue;
+            };
+            // a file removed since the walk is left out
+            let date = match path.metadata().and_then(|m| m.modified()) {
+                Ok(modified) => DateTime::<Local>::from(modified).date_naive(),
+                Err(e) => {
+                    warn!("Skipping {:?}: {}", path, e);
+                    continue;
+                }
+            };
+            let job = jobs
+                .entry((jobid.clone(), dir.to_path_buf()))
+                .or_insert_with(|| ArchivedJob {
+                    jobid: jobid.clone(),
+                    user: None,
+                    date,
+                    files: BTreeMap::new(),
+                });
+            if name == "manifest" {
+                let entries = match read_to_string(&path).map(|c| parse_manifest(&c)) {
+                    Ok(Ok(entries)) => entries,
+                    Ok(Err(e)) | Err(e) => {
+                        warn!("Skipping manifest {:?}: {}", path, e);
+                        continue;
+                    }
+                };
+                for entry in entries {
+                    let name = split_name(Path::new(&entry.name)).map_or(entry.name, |(_, n)| n);
+                    if let Some(object) = stored(&path, &entry.hash) {
+                        job.files.insert(name, object);
+                    }
+                }
+            } else {
+                job.files.insert(name, path);
+            }
+        }
+        let mut jobs: Vec<ArchivedJob> = jobs.into_values().collect();
+        for job in jobs.iter_mut() {
+            job.user = job
+                .environment()
+                .and_then(|env| lookup(&env, &USER_VARIABLES));
+        }
+        jobs.sort_by(|a, b| b.date.cmp(&a.date).then_with(|| a.jobid.cmp(&b.jobid)));
+        Ok(Index { jobs })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{create_dir_all, write};
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_split_name() {
+        let split = |name: &str| split_name(Path::new(name));
+        assert_eq!(
+            split("job.123_script"),
+            Some(("123".into(), "script".into()))
+        );
+        assert_eq!(
+            split("2024/1234.master.SC"),
+            Some(("1234.master".into(), "SC".into()))
+        );
+        assert_eq!(
+            split("1234[1].master.JB"),
+            Some(("1234[1].master".into(), "JB".into()))
+        );
+        assert_eq!(split("objects/ab/abcdef"), None);
+        assert_eq!(split("notes.txt"), None);
+    }
+
+    #[test]
+    fn test_build_layouts() {
+        let tdir = tempdir().unwrap();
+        let root = tdir.path();
+        let jobids = |layout: &Layout| -> Vec<(String, Vec<String>)> {
+            Index::build(root, layout)
+                .unwrap()
+                .jobs
+                .into_iter()
+                .map(|j| (j.jobid, j.files.into_keys().collect()))
+                .collect()
+        };
+
+        create_dir_all(root.join("2024")).unwrap();
+        write(root.join("2024/1234.master.SC"), "#PBS -l nodes=1\n").unwrap();
+        write(root.join("2024/1234.master.JB"), "<job/>").unwrap();
+        write(root.join("2024/job.99_script"), "#!/bin/sh\n").unwrap();
+        assert_eq!(
+            jobids(&Layout::default()),
+            vec![
+                ("1234.master".into(), vec!["JB".into(), "SC".into()]),
+                ("99".into(), vec!["script".into()]),
+            ]
+        );
+
+        let tdir = tempdir().unwrap();
+        let root = tdir.path();
+        create_dir_all(root.join("hpc/2024/07/1234")).unwrap();
+        write(root.join("hpc/2024/07/1234/script"), "#!/bin/sh\n").unwrap();
+        write(root.join("hpc/2024/07/1234/environment"), "USER=alice\n").unwrap();
+        let layout = Layout::new(Some("{cluster}/{year}/{month}/{jobid}/{filename}")).unwrap();
+        let index = Index::build(root, &layout).unwrap();
+        assert_eq!(index.jobs.len(), 1);
+        assert_eq!(index.jobs[0].jobid, "1234");
+        assert_eq!(index.jobs[0].user.as_deref(), Some("alice"));
+        assert!(index.jobs[0].script().is_some());
+        // the default layout finds nothing in it
+        assert!(Index::build(root, &Layout::default())
+            .unwrap()
+            .jobs
+            .is_empty());
+
+        let tdir = tempdir().unwrap();
+        let root = tdir.path();
+        create_dir_all(root.join("hpc/20240701")).unwrap();
+        write(root.join("hpc/20240701/job.5_script.bak"), "#!/bin/sh\n").unwrap();
+        let layout = Layout::new(Some("{cluster}/{year}{month}{day}/{name}.bak")).unwrap();
+        let index = Index::build(root, &layout).unwrap();
+        assert_eq!(index.jobs[0].jobid, "5");
+        assert!(index.jobs[0].files.contains_key("script"));
+
+        assert!(Layout::new(Some("{cluster}/{filename}")).is_err());
+    }
+}
@@ -19,7 +19,29 @@ LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
 OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
 SOFTWARE.
 */
+pub mod accounting;
 pub mod archive;
+pub mod artefact;
+pub mod completion;
+pub mod control;
+pub mod export;
+pub mod fsck;
+pub mod identity;
+pub mod index;
+pub mod maintenance;
 pub mod monitor;
+pub mod preflight;
+pub mod profile;
+pub mod readiness;
+pub mod reconcile;
 pub mod scheduler;
+pub mod selftest;
+pub mod spill;
+pub mod stats;
+pub mod supervisor;
+#[cfg(test)]
+mod testing;
+pub mod trace;
 pub mod utils;
+#[cfg(feature = "web")]
+pub mod web;
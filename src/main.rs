@@ -20,30 +20,80 @@ OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
 SOFTWARE.
 */
 
-use clap::Parser;
+use clap::error::ErrorKind;
+use clap::{CommandFactory, Parser, Subcommand};
 
-use crossbeam_channel::{bounded, unbounded};
+use crossbeam_channel::{bounded, never, unbounded};
 use crossbeam_utils::sync::Parker;
 use crossbeam_utils::thread::scope;
-use log::{error, info};
+use glob::Pattern;
+use log::{error, info, warn};
 use regex::Regex;
+use std::fs::File;
+use std::io::BufWriter;
 use std::path::PathBuf;
 use std::process::exit;
-use std::sync::atomic::AtomicBool;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-mod archive;
-mod monitor;
-mod scheduler;
-mod utils;
+use sarchive::accounting::follow;
+use sarchive::archive::breaker::CircuitBreaker;
+use sarchive::archive::deadline::{Deadline, DeadlineArchive};
+use sarchive::archive::document::RecordOptions;
+use sarchive::archive::lineproto::{
+    parse_endpoint, submission_points, Endpoint, LineProtocolArchive, LineSender,
+};
+use sarchive::archive::optout::OptOutList;
+use sarchive::archive::outbox::{ship, ShipArgs};
+use sarchive::archive::pseudonym::Pseudonymizer;
+use sarchive::archive::transform::{parse_stage, Pipeline, Stage, Transform, TransformArchive};
+use sarchive::archive::{archive_builder, process, Archive, ArchiverArgs, ProcessContext};
+use sarchive::artefact::watch;
+use sarchive::completion::{tail, ArchivedJobs, ARCHIVED_JOBS_CAPACITY};
+use sarchive::control::{dump, request, serve, status, StatusArgs};
+use sarchive::export::{export, ExportArgs};
+use sarchive::fsck::{fsck, FsckArgs};
+use sarchive::identity::{parse_label, Identity};
+use sarchive::index::Layout;
+use sarchive::maintenance::{parse_window, Maintenance, Window};
+use sarchive::monitor::{discover, inotify_queue_size, manage, WatchCommand};
+use sarchive::preflight::{check_spool, setup_acl, SetupAclArgs};
+use sarchive::profile::{self, DEFAULT_CONFIG};
+use sarchive::readiness::{await_ready, check_backend};
+use sarchive::reconcile::Reconciler;
+use sarchive::scheduler::environment::{EnvBaseline, EnvPolicy, EnvironmentStage};
+use sarchive::scheduler::rules::FileRules;
+use sarchive::scheduler::spool::{spool_roots, SpoolRoot};
+use sarchive::scheduler::torque::TorqueArgs;
+use sarchive::scheduler::{create, Capture, JobEvent, Scheduler, SchedulerKind, SchedulerOptions};
+use sarchive::selftest::{selftest, SelftestArgs};
+use sarchive::spill;
+use sarchive::stats::{report_submissions, Stats};
+use sarchive::supervisor::{Supervisor, DEFAULT_RESTARTS};
+use sarchive::trace::{read_trace, replay, EventLog, EventRecorder, ReplayArgs, TraceRecorder};
+use sarchive::utils::{
+    job_context, register_signal_handler, signal_handler_atomic, EXIT_BACKEND, EXIT_CONFIG,
+    EXIT_RUNTIME, EXIT_SPOOL,
+};
+#[cfg(feature = "web")]
+use sarchive::web::{serve as browse, BrowseArgs};
 
-use archive::{archive_builder, process, Archive, ArchiverOptions};
+/// Documents the exit status in the help text
+const EXIT_STATUS_HELP: &str = "Exit status:
+  0  stopped by SIGINT or SIGTERM (or, for status, pause, resume and ready, request answered)
+  1  fatal error while running, or stopped by SIGQUIT (or, for fsck, problems found; for ready, not ready)
+  2  invalid options or configuration
+  3  spool directory (or, for ship, outbox directory) missing or unreadable
+  4  archiver could not be set up, failed --check-backends or failed the selftest";
 
-use monitor::monitor;
-use scheduler::{create, SchedulerKind};
-use utils::{register_signal_handler, signal_handler_atomic};
-
-fn setup_logging(debug: bool, logfile: Option<PathBuf>) -> Result<(), log::SetLoggerError> {
+/// Sets up logging to the given file, or else to stdout. When stdout carries
+/// the archived jobs, logging goes to stderr instead.
+fn setup_logging(
+    debug: bool,
+    logfile: Option<PathBuf>,
+    stdout_archiver: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
     let level_filter = if debug {
         log::LevelFilter::Debug
     } else {
@@ -52,11 +102,13 @@ fn setup_logging(debug: bool, logfile: Option<PathBuf>) -> Result<(), log::SetLo
 
     let base_config = fern::Dispatch::new()
         .format(|out, message, record| {
+            let context = job_context().map(|c| format!("[{c}]")).unwrap_or_default();
             out.finish(format_args!(
-                "[{}][{}][{}] {}",
+                "[{}][{}][{}]{} {}",
                 chrono::Local::now().to_rfc3339(),
                 record.target(),
                 record.level(),
+                context,
                 message
             ))
         })
@@ -64,22 +116,94 @@ fn setup_logging(debug: bool, logfile: Option<PathBuf>) -> Result<(), log::SetLo
 
     match logfile {
         Some(filename) => {
-            let r = fern::log_reopen(&filename, Some(libc::SIGHUP)).unwrap();
+            let r = fern::log_reopen(&filename, Some(libc::SIGHUP))?;
             base_config.chain(r)
         }
+        None if stdout_archiver => base_config.chain(std::io::stderr()),
         None => base_config.chain(std::io::stdout()),
     }
-    .apply()
+    .apply()?;
+    Ok(())
+}
+
+#[derive(Subcommand)]
+enum Command {
+    #[command(flatten)]
+    Archiver(ArchiverArgs),
+
+    /// Report the internal state of a running sarchive instance
+    Status(StatusArgs),
+
+    /// Pause archival in a running sarchive instance for maintenance
+    Pause(StatusArgs),
+
+    /// Resume archival in a running sarchive instance after maintenance
+    Resume(StatusArgs),
+
+    /// Check whether a running sarchive instance is ready, i.e., watches every
+    /// watch location and its archiver passed its check, for readiness probes
+    Ready(StatusArgs),
+
+    /// Ship the jobs captured in an outbox directory by the outbox archiver
+    Ship(ShipArgs),
+
+    /// Run a synthetic job through the pipeline into the archiver, and report
+    /// whether it arrived
+    Selftest(SelftestArgs),
+
+    /// Set up the POSIX ACLs that let a service user read the spool, so
+    /// sarchive need not run as root
+    SetupAcl(SetupAclArgs),
+
+    /// Verify a file archive without changing it: the stored contents against
+    /// their hashes, the manifests against the stored contents, and the
+    /// archived checksums
+    Fsck(FsckArgs),
+
+    /// Convert the jobs in a file archive, archived between the given days,
+    /// to JSON lines, CSV or Parquet for analysis
+    Export(ExportArgs),
+
+    /// Replay the events recorded with --record-trace against this
+    /// configuration, and report how the pipeline kept up
+    Replay(ReplayArgs),
+
+    /// Serve read-only pages to search a file archive by job ID, user and
+    /// date, and show the archived jobs
+    #[cfg(feature = "web")]
+    Browse(BrowseArgs),
 }
 
 #[derive(Parser)]
-#[command(author, version, about)]
+#[command(
+    author,
+    version,
+    about,
+    subcommand_negates_reqs = true,
+    after_help = EXIT_STATUS_HELP
+)]
 struct Cli {
     #[arg(
         long,
+        value_name = "FILE",
+        default_value = DEFAULT_CONFIG,
+        help = "File holding the profiles that can be selected with --profile"
+    )]
+    config: PathBuf,
+
+    #[arg(
+        long,
+        value_name = "NAME",
+        help = "Take the options, and possibly the archiver, from this profile in the --config file, as if given right after --profile"
+    )]
+    profile: Option<String>,
+
+    #[arg(
+        long,
+        required = true,
         help = "Name of the cluster where the jobs have been submitted to."
     )]
-    cluster: String,
+    cluster: Option<String>,
 
     #[arg(long)]
     debug: bool,
@@ -90,99 +214,1289 @@ struct Cli {
     )]
     cleanup: bool,
 
+    #[arg(
+        long,
+        help = "Ask the archiver to record jobs that vanished before their information could be read"
+    )]
+    tombstones: bool,
+
+    #[arg(
+        long,
+        help = "Verify that the archiver can reach and write to its destination before watching the spool, and exit otherwise"
+    )]
+    check_backends: bool,
+
+    #[arg(
+        long,
+        value_name = "FAILURES",
+        help = "Hold the jobs for a while after this many consecutive archival failures, rather than retrying right away"
+    )]
+    breaker_threshold: Option<u32>,
+
+    #[arg(
+        long,
+        value_name = "SECONDS",
+        default_value_t = 60,
+        help = "How long to hold the jobs once --breaker-threshold is reached"
+    )]
+    breaker_cooldown: u64,
+
+    #[arg(
+        long,
+        value_name = "SECONDS",
+        value_parser = clap::value_parser!(u64).range(1..),
+        help = "Give up on a job entry when reading it or handing it to the archiver takes longer than this"
+    )]
+    entry_deadline: Option<u64>,
+
+    #[arg(
+        long,
+        value_name = "PATH",
+        requires = "entry_deadline",
+        help = "Append the job entries given up on with --entry-deadline to this file, which can be used as --state-file to process them again"
+    )]
+    dead_letter: Option<PathBuf>,
+
+    #[arg(
+        long,
+        value_name = "JOBS",
+        default_value_t = 32,
+        value_parser = clap::value_parser!(u64).range(1..),
+        help = "Archive at most this many queued jobs at once, waiting for the backend to deliver them before taking more"
+    )]
+    max_batch_size: u64,
+
+    #[arg(
+        long,
+        value_name = "URL",
+        value_parser = parse_endpoint,
+        help = "Also send a line protocol point per archived job (cluster, partition, user, script size, environment size, latency) to InfluxDB or VictoriaMetrics, at udp://HOST:PORT, tcp://HOST:PORT or http://HOST:PORT/PATH"
+    )]
+    line_protocol: Option<Endpoint>,
+
+    #[arg(
+        long,
+        default_value = "sarchive_job",
+        help = "Measurement of the points sent with --line-protocol"
+    )]
+    line_protocol_measurement: String,
+
+    #[arg(
+        long,
+        value_name = "MINUTES",
+        help = "Log a summary of the job submissions (jobs per minute, busiest minute, jobs per watch location) this often, also sent as sarchive_submissions points with --line-protocol"
+    )]
+    submission_report_interval: Option<u64>,
+
+    #[arg(
+        long,
+        value_name = "FILE",
+        help = "Replace user names and uids in archived records by pseudonyms, keyed with the secret in this file"
+    )]
+    pseudonymize_key: Option<PathBuf>,
+
+    #[arg(
+        long,
+        value_name = "FILE",
+        help = "File, readable only by its owner, in which to record what each pseudonym stands for"
+    )]
+    pseudonym_map: Option<PathBuf>,
+
+    #[arg(
+        long,
+        value_name = "FILE",
+        help = "File listing the user names and uids who opted out of archival, whose jobs only get a tombstone (reloaded on SIGHUP)"
+    )]
+    opt_out_list: Option<PathBuf>,
+
+    #[arg(
+        long = "transform",
+        value_name = "STAGE",
+        value_parser = parse_stage,
+        help = "Pass the job records through this stage before archival: redact:REGEX, drop-env:REGEX, skip:FIELD=REGEX, label:KEY=VALUE, directives, notify:RECIPIENT REGEX or lint (can be repeated, the stages run in the given order)"
+    )]
+    transforms: Vec<Stage>,
+
+    #[arg(
+        long = "maintenance-window",
+        value_name = "HH:MM-HH:MM",
+        value_parser = parse_window,
+        help = "Daily window, in local time, during which job entries are captured but not archived or shipped until it ends (can be repeated)"
+    )]
+    maintenance_windows: Vec<Window>,
+
+    #[arg(
+        long,
+        help = "File in which to keep the job entries that are still queued when stopping, to process them after a restart"
+    )]
+    state_file: Option<PathBuf>,
+
+    #[arg(
+        long,
+        value_name = "PATH",
+        help = "Record the events received on the watch locations to this trace file, to replay them later with the replay subcommand"
+    )]
+    record_trace: Option<PathBuf>,
+
+    #[arg(
+        long,
+        value_name = "PATH",
+        help = "Append every event received on the watch locations to this file as a JSON line, whatever becomes of the job"
+    )]
+    event_log: Option<PathBuf>,
+
     #[arg(long, help = "Log file name.")]
     logfile: Option<PathBuf>,
 
-    #[arg(long)]
-    torque_subdirs: bool,
+    #[command(flatten)]
+    torque: TorqueArgs,
 
-    #[arg(long)]
-    spool: PathBuf,
+    #[arg(
+        long,
+        required = true,
+        help = "Spool directory of the scheduler, which may contain {cluster} and {hostname} (can be repeated to watch several spools)"
+    )]
+    spool: Vec<PathBuf>,
 
     #[arg(long, required = true)]
-    scheduler: SchedulerKind,
+    scheduler: Option<SchedulerKind>,
 
     #[arg(long)]
     filter_regex: Option<String>,
 
-    #[command(flatten)]
-    archiver: ArchiverOptions,
+    #[arg(
+        long,
+        value_delimiter = ',',
+        default_value = "create",
+        help = "Filesystem events that announce a new job entry"
+    )]
+    event_kinds: Vec<JobEvent>,
+
+    #[arg(
+        long,
+        help = "Environment shared by most jobs (output of env or env -0); archive only how a job's environment differs from it"
+    )]
+    env_baseline: Option<PathBuf>,
+
+    #[arg(
+        long,
+        value_name = "BYTES",
+        help = "Leave out environment values larger than this"
+    )]
+    env_max_size: Option<usize>,
+
+    #[arg(
+        long,
+        help = "Truncate environment values larger than --env-max-size instead of leaving them out"
+    )]
+    env_truncate: bool,
+
+    #[arg(
+        long,
+        value_name = "BITS",
+        help = "Leave out environment values that look like secrets: long words with at least this entropy per character (e.g., 4.5)"
+    )]
+    env_entropy_threshold: Option<f64>,
+
+    #[arg(
+        long,
+        value_name = "BYTES",
+        help = "Stream job files larger than this from the spool instead of reading them into memory (Slurm). Only the file archiver stores such files; other archivers list them as streamed."
+    )]
+    max_buffered_size: Option<u64>,
+
+    #[arg(
+        long,
+        help = "Also archive the contents of credential and GRES files found in the job directories (Slurm); by default only their names and sizes are recorded"
+    )]
+    capture_credentials: bool,
+
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = Capture::All,
+        help = "Job files to capture (Slurm); the files left out are never read"
+    )]
+    capture: Capture,
+
+    #[arg(
+        long,
+        value_name = "BYTES",
+        help = "Also archive the file a job script sources, if it sources a single one that everyone can read and that is no larger than this (Slurm)"
+    )]
+    resolve_sourced: Option<u64>,
+
+    #[arg(
+        long,
+        value_name = "GLOB",
+        help = "Also capture the other files in the job directories whose name matches this glob, e.g., burst_buffer (Slurm; can be repeated)"
+    )]
+    slurm_include: Vec<Pattern>,
+
+    #[arg(
+        long,
+        value_name = "GLOB",
+        help = "Leave out the files in the job directories whose name matches this glob, e.g., '*.tmp', including the script, environment and credential files (Slurm; can be repeated)"
+    )]
+    slurm_exclude: Vec<Pattern>,
+
+    #[arg(
+        long,
+        help = "Path of the Unix domain socket on which to report the internal state"
+    )]
+    control_socket: Option<PathBuf>,
+
+    #[arg(
+        long,
+        help = "Identifier for this instance in archived records [default: hash of the configuration]"
+    )]
+    instance_id: Option<String>,
+
+    #[arg(
+        long,
+        help = "Also archive the job script without comments and repeated whitespace, with its hash, to match scripts that differ only in layout"
+    )]
+    normalize_script: bool,
+
+    #[arg(
+        long,
+        help = "Archive the environment with numbers and booleans as such, and PATH-like variables split into arrays, rather than all values as strings"
+    )]
+    structured_environment: bool,
+
+    #[arg(
+        long = "label",
+        value_name = "KEY=VALUE",
+        value_parser = parse_label,
+        help = "Label to add to every archived record, e.g., datacenter=dc1 (can be repeated)"
+    )]
+    labels: Vec<(String, String)>,
+
+    #[arg(
+        long,
+        value_name = "SECONDS",
+        help = "Watch a location anew when it gets no events for this long while other locations do"
+    )]
+    starvation_threshold: Option<u64>,
+
+    #[arg(
+        long,
+        value_name = "COUNT",
+        default_value_t = DEFAULT_RESTARTS,
+        help = "Restart a watcher, or the processing, that panics at most this many times"
+    )]
+    max_restarts: u32,
+
+    #[arg(
+        long,
+        value_name = "SECONDS",
+        help = "Check the watch locations this often for job entries whose event was missed"
+    )]
+    reconcile_interval: Option<u64>,
+
+    #[arg(
+        long,
+        help = "Queue the job entries whose event was missed, as found with --reconcile-interval"
+    )]
+    reconcile_enqueue: bool,
+
+    #[arg(
+        long,
+        help = "Slurm job completion log (jobcomp/filetxt) or slurmctld log to follow, sending completion events for archived jobs"
+    )]
+    completion_log: Option<PathBuf>,
+
+    #[arg(
+        long,
+        value_name = "PATH",
+        help = "File keeping the archived jobs awaiting their completion across restarts, reading the --completion-log and --torque-accounting logs from their start"
+    )]
+    completion_state: Option<PathBuf>,
+
+    #[arg(
+        long,
+        value_name = "DIR",
+        help = "Directory where the prolog and epilog leave their failure artefacts, named after the job (e.g., prolog.1234.err), to send along with the archived jobs (can be repeated)"
+    )]
+    prolog_artefacts: Vec<PathBuf>,
+
+    #[command(subcommand)]
+    command: Command,
 }
 
-fn main() -> Result<(), std::io::Error> {
-    //let matches = args();
-    let cli = Cli::parse();
+/// Unwrap an argument that is only required when running the archival daemon,
+/// as subcommands such as status do not need it
+fn required<T>(value: Option<T>, name: &str) -> T {
+    value.unwrap_or_else(|| {
+        Cli::command()
+            .error(
+                ErrorKind::MissingRequiredArgument,
+                format!("the following required argument was not provided: --{name}"),
+            )
+            .exit()
+    })
+}
 
-    match setup_logging(cli.debug, cli.logfile) {
-        Ok(_) => (),
-        Err(e) => panic!("Cannot set up logging: {e:?}"),
+/// Returns the identity of this instance, with the given configuration
+fn instance_identity(cli: &Cli, config: &str) -> Identity {
+    let mut identity = Identity::new(cli.instance_id.clone(), config);
+    identity.labels = cli.labels.iter().cloned().collect();
+    info!(
+        "sarchive {} running on {} as instance {}",
+        identity.version, identity.hostname, identity.instance_id
+    );
+    identity
+}
+
+/// Returns the stage that filters the job environments and applies the
+/// environment policy, if either is asked for
+fn environment_stage(cli: &Cli) -> Option<EnvironmentStage> {
+    let filter_regex = cli.filter_regex.as_ref().map(|r| {
+        Regex::new(r).unwrap_or_else(|e| {
+            error!("Invalid filter regex {:?}: {}", r, e);
+            exit(EXIT_CONFIG);
+        })
+    });
+
+    if cli.env_truncate && cli.env_max_size.is_none() {
+        Cli::command()
+            .error(
+                ErrorKind::MissingRequiredArgument,
+                "--env-truncate requires --env-max-size",
+            )
+            .exit()
+    }
+    let env_policy = EnvPolicy {
+        baseline: cli.env_baseline.as_ref().map(|path| {
+            EnvBaseline::load(path).unwrap_or_else(|e| {
+                error!("Cannot read baseline environment {:?}: {}", path, e);
+                exit(EXIT_CONFIG);
+            })
+        }),
+        max_value_size: cli.env_max_size,
+        truncate: cli.env_truncate,
+        entropy_threshold: cli.env_entropy_threshold,
     };
-    let base = cli.spool.to_owned();
+    if filter_regex.is_none() && env_policy.is_empty() {
+        return None;
+    }
+    Some(EnvironmentStage::new(filter_regex, env_policy))
+}
 
-    // FIXME: Check for permissions to read directory contents
-    if !base.is_dir() {
-        error!("Provided spool {:?} is not a valid directory", &base);
-        exit(1);
+/// Builds the archiver, wrapped as requested on the command line, and checks
+/// it is ready for jobs of the given cluster if --check-backends is given
+fn setup_archiver(
+    cli: &Cli,
+    archiver_args: &ArchiverArgs,
+    identity: &Identity,
+    cluster: &str,
+) -> Box<dyn Archive> {
+    let record_options = RecordOptions {
+        normalize_script: cli.normalize_script,
+        structured_environment: cli.structured_environment,
+    };
+    let mut archiver: Box<dyn Archive> = archive_builder(archiver_args, identity, &record_options)
+        .unwrap_or_else(|e| {
+            error!("Cannot set up the archiver: {}", e);
+            exit(EXIT_BACKEND);
+        });
+    if let Some(endpoint) = &cli.line_protocol {
+        let measurement = &cli.line_protocol_measurement;
+        archiver = Box::new(LineProtocolArchive::new(
+            archiver,
+            endpoint,
+            measurement,
+            &identity.labels,
+        ));
+    }
+    if cli.pseudonym_map.is_some() && cli.pseudonymize_key.is_none() {
+        Cli::command()
+            .error(
+                ErrorKind::MissingRequiredArgument,
+                "--pseudonym-map requires --pseudonymize-key",
+            )
+            .exit()
+    }
+    // the opt-out list is checked before pseudonymization, and the other
+    // stages see the pseudonyms
+    let mut stages: Vec<Box<dyn Transform>> = Vec::new();
+    if let Some(stage) = environment_stage(cli) {
+        stages.push(Box::new(stage));
+    }
+    let pseudonymizer = cli.pseudonymize_key.as_ref().map(|key_file| {
+        if matches!(archiver_args, ArchiverArgs::File(_)) {
+            warn!("The file archiver keeps the user names in the spool files it copies");
+        }
+        Pseudonymizer::load(key_file, cli.pseudonym_map.as_deref()).unwrap_or_else(|e| {
+            error!("Cannot set up pseudonymization: {}", e);
+            exit(EXIT_CONFIG);
+        })
+    });
+    if let Some(path) = &cli.opt_out_list {
+        let list = OptOutList::load(path).unwrap_or_else(|e| {
+            error!("Cannot read the opt-out list {:?}: {}", path, e);
+            exit(EXIT_CONFIG);
+        });
+        if let Err(e) =
+            signal_hook::flag::register(signal_hook::consts::SIGHUP, Arc::clone(&list.reload))
+        {
+            error!(
+                "Cannot register SIGHUP for reloading the opt-out list: {:?}",
+                e
+            );
+            exit(EXIT_RUNTIME);
+        }
+        stages.push(Box::new(list));
+    }
+    if let Some(pseudonymizer) = pseudonymizer {
+        stages.push(Box::new(pseudonymizer));
+    }
+    stages.extend(
+        cli.transforms
+            .iter()
+            .map(|stage| Box::new(stage.clone()) as Box<dyn Transform>),
+    );
+    if !stages.is_empty() {
+        archiver = Box::new(TransformArchive::new(archiver, Pipeline::new(stages)));
+    }
+    if let Some(threshold) = cli.breaker_threshold {
+        let cooldown = Duration::from_secs(cli.breaker_cooldown);
+        archiver = Box::new(CircuitBreaker::new(archiver, threshold, cooldown));
+    }
+    if let Some(deadline) = entry_deadline(cli) {
+        archiver = Box::new(DeadlineArchive::new(archiver, deadline));
+    }
+    if cli.check_backends {
+        match archiver.check(cluster) {
+            Ok(()) => info!("Archiver {} is ready", archiver.name()),
+            Err(e) => {
+                error!("Archiver {} is not ready: {}", archiver.name(), e);
+                exit(EXIT_BACKEND);
+            }
+        }
     }
+    archiver
+}
 
-    let scheduler = cli.scheduler;
-    let archiver: Box<dyn Archive> = archive_builder(&cli.archiver.archiver).unwrap();
-    let cluster = cli.cluster;
-    let filter_regex = if let Some(r) = cli.filter_regex {
-        Regex::new(&r).ok()
-    } else {
-        None
+/// Returns the deadline for each job entry, if --entry-deadline is given
+fn entry_deadline(cli: &Cli) -> Option<Deadline> {
+    cli.entry_deadline.map(|seconds| Deadline {
+        limit: Duration::from_secs(seconds),
+        dead_letter: cli.dead_letter.clone(),
+    })
+}
+
+/// Sets up the maintenance windows, and SIGUSR2 to start or end maintenance
+fn setup_maintenance(windows: &[Window]) -> Maintenance {
+    let maintenance = Maintenance::new(windows);
+    if let Err(e) = signal_hook::flag::register(
+        signal_hook::consts::SIGUSR2,
+        Arc::clone(&maintenance.toggle),
+    ) {
+        error!("Cannot register SIGUSR2 for maintenance: {:?}", e);
+        exit(EXIT_RUNTIME);
+    }
+    maintenance
+}
+
+/// Ships the jobs captured in the outbox by another instance to the archiver,
+/// until SIGINT or SIGTERM arrives
+fn run_ship(cli: &Cli, args: &ShipArgs) -> ! {
+    let stdout_archiver = matches!(args.archiver, ArchiverArgs::Stdout);
+    if let Err(e) = setup_logging(cli.debug, cli.logfile.clone(), stdout_archiver) {
+        eprintln!("Cannot set up logging: {e}");
+        exit(EXIT_CONFIG);
+    }
+    if !args.outbox.is_dir() {
+        error!(
+            "Provided outbox {:?} is not a valid directory",
+            &args.outbox
+        );
+        exit(EXIT_SPOOL);
+    }
+
+    let identity = instance_identity(cli, &format!("{:?} {:?}", &args.outbox, &args.archiver));
+    let cluster = cli.cluster.clone().unwrap_or_default();
+    let archiver = setup_archiver(cli, &args.archiver, &identity, &cluster);
+
+    let notification = Arc::new(AtomicBool::new(false));
+    let parker = Parker::new();
+    register_signal_handler(
+        signal_hook::consts::SIGTERM,
+        parker.unparker(),
+        &notification,
+    );
+    register_signal_handler(
+        signal_hook::consts::SIGINT,
+        parker.unparker(),
+        &notification,
+    );
+
+    let maintenance = setup_maintenance(&cli.maintenance_windows);
+
+    let (sig_sender, sig_receiver) = bounded(20);
+    let stats = Stats::new();
+    let interval = Duration::from_secs(args.interval);
+    if let Err(e) = scope(|s| {
+        let ss = &sig_sender;
+        s.spawn(move |_| {
+            signal_handler_atomic(ss, notification, &AtomicBool::new(false), &parker);
+            info!("Signal handled");
+        });
+
+        if let Err(e) = ship(
+            archiver.as_ref(),
+            &args.outbox,
+            interval,
+            &sig_receiver,
+            &stats,
+            &maintenance,
+        ) {
+            error!("Shipping failed: {:?}", e);
+            exit(EXIT_RUNTIME);
+        }
+    }) {
+        error!("sarchive stopping due to error: {:?}", e);
+        exit(EXIT_RUNTIME);
+    };
+
+    info!("Sarchive finished");
+    exit(0);
+}
+
+/// Runs a synthetic job through the pipeline into the archiver, and exits
+/// with the outcome
+fn run_selftest(cli: &Cli, args: &SelftestArgs) -> ! {
+    let stdout_archiver = matches!(args.archiver, ArchiverArgs::Stdout);
+    if let Err(e) = setup_logging(cli.debug, cli.logfile.clone(), stdout_archiver) {
+        eprintln!("Cannot set up logging: {e}");
+        exit(EXIT_CONFIG);
+    }
+
+    let cluster = required(cli.cluster.clone(), "cluster");
+    let identity = instance_identity(cli, &format!("selftest {cluster} {:?}", &args.archiver));
+    let archiver = setup_archiver(cli, &args.archiver, &identity, &cluster);
+    let name = archiver.name().to_owned();
+    match selftest(archiver, &cluster, Duration::from_secs(args.timeout)) {
+        Ok(latency) => {
+            info!(
+                "Selftest passed: archiver {} took the job in {}ms",
+                name,
+                latency.as_millis()
+            );
+            exit(0);
+        }
+        Err(e) => {
+            error!("Selftest failed for archiver {}: {}", name, e);
+            exit(EXIT_BACKEND);
+        }
+    }
+}
+
+/// Returns the spool roots given on the command line, with their
+/// placeholders filled in, exiting if any of them is not a directory
+fn spools(cli: &Cli) -> Vec<PathBuf> {
+    let templates = required(Some(cli.spool.clone()).filter(|s| !s.is_empty()), "spool");
+    let roots = spool_roots(&templates, cli.cluster.as_deref()).unwrap_or_else(|e| {
+        error!("{}", e);
+        exit(EXIT_CONFIG);
+    });
+    for root in roots.iter() {
+        if !root.is_dir() {
+            error!("Provided spool {:?} is not a valid directory", root);
+            exit(EXIT_SPOOL);
+        }
+    }
+    roots
+}
+
+/// Creates the scheduler for each spool root, checking that the spool can be
+/// read, and exits if that fails
+fn setup_schedulers(
+    cli: &Cli,
+    scheduler: &SchedulerKind,
+    cluster: &str,
+    roots: &[PathBuf],
+) -> Vec<(PathBuf, Box<dyn Scheduler>)> {
+    let options = SchedulerOptions {
+        torque_args: &cli.torque,
+        event_kinds: &cli.event_kinds,
+        max_buffered_size: cli.max_buffered_size,
+        capture_credentials: cli.capture_credentials,
+        capture: cli.capture,
+        resolve_sourced: cli.resolve_sourced,
+        slurm_rules: Arc::new(FileRules::new(&cli.slurm_include, &cli.slurm_exclude)),
     };
 
-    info!("sarchive starting. Watching spool {:?}.", &base);
+    // with several spool roots, each job is tagged with the root it was found in
+    let tag = roots.len() > 1;
+    roots
+        .iter()
+        .map(|base| {
+            let sched = create(scheduler, base, cluster, &options).unwrap_or_else(|e| {
+                error!("{}", e);
+                exit(EXIT_CONFIG);
+            });
+            if let Err(e) = check_spool(base, sched.as_ref()) {
+                error!("{}", e);
+                exit(EXIT_SPOOL);
+            }
+            let sched: Box<dyn Scheduler> = Box::new(SpoolRoot::new(base, sched, tag));
+            (base.clone(), sched)
+        })
+        .collect()
+}
+
+/// Sets up the ACLs on the spools for the service user, and exits
+fn run_setup_acl(cli: &Cli, args: &SetupAclArgs) -> ! {
+    if let Err(e) = setup_logging(cli.debug, cli.logfile.clone(), false) {
+        eprintln!("Cannot set up logging: {e}");
+        exit(EXIT_CONFIG);
+    }
+    for base in spools(cli) {
+        if let Err(e) = setup_acl(&base, args) {
+            error!("Cannot set up the ACLs on spool {:?}: {}", &base, e);
+            exit(EXIT_RUNTIME);
+        }
+    }
+    exit(0)
+}
+
+/// Replays the recorded events through the configured scheduler into the
+/// archiver, and exits once the queue is processed
+fn run_replay(cli: &Cli, args: &ReplayArgs) -> ! {
+    let stdout_archiver = matches!(args.archiver, ArchiverArgs::Stdout);
+    if let Err(e) = setup_logging(cli.debug, cli.logfile.clone(), stdout_archiver) {
+        eprintln!("Cannot set up logging: {e}");
+        exit(EXIT_CONFIG);
+    }
+    let cluster = required(cli.cluster.clone(), "cluster");
+    let scheduler = required(cli.scheduler.clone(), "scheduler");
+    let roots = spools(cli);
+    let mut events = read_trace(&args.trace).unwrap_or_else(|e| {
+        error!("Cannot read the trace {:?}: {}", &args.trace, e);
+        exit(EXIT_CONFIG);
+    });
+    if let Some(recorded) = &args.recorded_spool {
+        if roots.len() != 1 {
+            error!("--recorded-spool needs a single --spool to replace it");
+            exit(EXIT_CONFIG);
+        }
+        events
+            .iter_mut()
+            .for_each(|event| event.rebase(recorded, &roots[0]));
+    }
+
+    let identity = instance_identity(cli, &format!("replay {cluster} {roots:?} {args:?}"));
+    let archiver = setup_archiver(cli, &args.archiver, &identity, &cluster);
+    let scheds = setup_schedulers(cli, &scheduler, &cluster, &roots);
+    let maintenance = Maintenance::default();
+    let reconciler = Reconciler::default();
+    let stats = Stats::new();
+
+    let notification = Arc::new(AtomicBool::new(false));
+    let parker = Parker::new();
+    register_signal_handler(
+        signal_hook::consts::SIGTERM,
+        parker.unparker(),
+        &notification,
+    );
+    register_signal_handler(
+        signal_hook::consts::SIGINT,
+        parker.unparker(),
+        &notification,
+    );
+
+    info!(
+        "Replaying {} events from {:?} at {}x",
+        events.len(),
+        &args.trace,
+        args.speed
+    );
+    let (sig_sender, sig_receiver) = bounded(20);
+    let (sender, receiver) = unbounded();
+    let (finished, unparker) = (Arc::clone(&notification), parker.unparker().clone());
+    let start = Instant::now();
+    if let Err(e) = scope(|s| {
+        let ss = &sig_sender;
+        s.spawn(move |_| {
+            signal_handler_atomic(ss, notification, &AtomicBool::new(false), &parker);
+            info!("Signal handled");
+        });
+
+        let (r, sr, st, m, rc) = (&receiver, &sig_receiver, &stats, &maintenance, &reconciler);
+        let (cleanup, tombstones, max_batch) = (cli.cleanup, cli.tombstones, cli.max_batch_size);
+        let deadline = entry_deadline(cli);
+        s.spawn(move |_| {
+            if let Err(e) = process(
+                archiver.as_ref(),
+                r,
+                &never(),
+                &mut ArchivedJobs::default(),
+                &mut Vec::new(),
+                sr,
+                &ProcessContext {
+                    stats: st,
+                    maintenance: m,
+                    reconciler: rc,
+                    deadline: deadline.as_ref(),
+                    tombstones,
+                    cleanup,
+                    max_batch: max_batch as usize,
+                },
+            ) {
+                error!("processing failed: {:?}", e);
+                exit(EXIT_RUNTIME);
+            }
+        });
+
+        match replay(
+            events,
+            args.speed,
+            &scheds,
+            &sender,
+            &sig_receiver,
+            &stats,
+            &reconciler,
+        ) {
+            Ok(longest) => info!(
+                "Replayed the events in {:.1}s, the queue held up to {} job entries",
+                start.elapsed().as_secs_f64(),
+                longest
+            ),
+            Err(e) => error!("Replay failed: {}", e),
+        }
+        // wait for the queue to be processed, unless stopped meanwhile
+        while !receiver.is_empty() {
+            if let Ok(true) = sig_receiver.recv_timeout(Duration::from_millis(100)) {
+                break;
+            }
+        }
+        // stop the same way a signal does
+        finished.store(true, Ordering::SeqCst);
+        unparker.unpark();
+    }) {
+        error!("sarchive stopping due to error: {:?}", e);
+        exit(EXIT_RUNTIME);
+    };
+
+    info!("{}", stats.report(0));
+    exit(0);
+}
+
+/// Serves the pages to browse the file archive, until stopped
+#[cfg(feature = "web")]
+fn run_browse(cli: &Cli, args: &BrowseArgs) -> ! {
+    if let Err(e) = setup_logging(cli.debug, cli.logfile.clone(), false) {
+        eprintln!("Cannot set up logging: {e}");
+        exit(EXIT_CONFIG);
+    }
+    if !args.archive.is_dir() {
+        error!(
+            "Provided archive {:?} is not a valid directory",
+            &args.archive
+        );
+        exit(EXIT_CONFIG);
+    }
+    let layout = Layout::new(args.name_template.as_deref()).unwrap_or_else(|e| {
+        error!("{}", e);
+        exit(EXIT_CONFIG);
+    });
+    let listener = std::net::TcpListener::bind(&args.listen).unwrap_or_else(|e| {
+        error!("Cannot listen on {}: {}", &args.listen, e);
+        exit(EXIT_CONFIG);
+    });
+    match browse(&args.archive, layout, listener) {
+        Ok(()) => exit(0),
+        Err(e) => {
+            error!("Browsing {:?} failed: {}", &args.archive, e);
+            exit(EXIT_RUNTIME);
+        }
+    }
+}
+
+/// Exports the selected jobs of the file archive, and exits
+fn run_export(cli: &Cli, args: &ExportArgs) -> ! {
+    // Keep the standard output for the export, when it goes there
+    if let Err(e) = setup_logging(cli.debug, cli.logfile.clone(), args.output.is_none()) {
+        eprintln!("Cannot set up logging: {e}");
+        exit(EXIT_CONFIG);
+    }
+    if !args.from.is_dir() {
+        error!("Provided archive {:?} is not a valid directory", &args.from);
+        exit(EXIT_CONFIG);
+    }
+    let layout = Layout::new(args.name_template.as_deref()).unwrap_or_else(|e| {
+        error!("{}", e);
+        exit(EXIT_CONFIG);
+    });
+    let exported = match &args.output {
+        Some(path) => File::create(path).and_then(|file| {
+            export(
+                &args.from,
+                &layout,
+                args.format,
+                args.since,
+                args.until,
+                BufWriter::new(file),
+            )
+        }),
+        None => export(
+            &args.from,
+            &layout,
+            args.format,
+            args.since,
+            args.until,
+            BufWriter::new(std::io::stdout()),
+        ),
+    };
+    match exported {
+        Ok(n) => {
+            info!("Exported {} jobs from {:?}", n, &args.from);
+            exit(0);
+        }
+        Err(e) => {
+            error!("Exporting {:?} failed: {}", &args.from, e);
+            exit(EXIT_RUNTIME);
+        }
+    }
+}
+
+/// Verifies the file archive, logging every problem found, and exits
+fn run_fsck(cli: &Cli, args: &FsckArgs) -> ! {
+    if let Err(e) = setup_logging(cli.debug, cli.logfile.clone(), false) {
+        eprintln!("Cannot set up logging: {e}");
+        exit(EXIT_CONFIG);
+    }
+    if !args.archive.is_dir() {
+        error!(
+            "Provided archive {:?} is not a valid directory",
+            &args.archive
+        );
+        exit(EXIT_CONFIG);
+    }
+    match fsck(&args.archive) {
+        Ok(report) => {
+            for problem in report.problems.iter() {
+                error!("{}", problem);
+            }
+            info!(
+                "Verified {} manifests, {} stored contents ({} unreferenced) and {} checksums in {:?}: {} problems",
+                report.manifests,
+                report.objects,
+                report.unreferenced,
+                report.checksums,
+                &args.archive,
+                report.problems.len()
+            );
+            exit(if report.problems.is_empty() {
+                0
+            } else {
+                EXIT_RUNTIME
+            });
+        }
+        Err(e) => {
+            error!("Cannot verify archive {:?}: {}", &args.archive, e);
+            exit(EXIT_RUNTIME);
+        }
+    }
+}
+
+fn main() -> Result<(), std::io::Error> {
+    let args = profile::expand(std::env::args_os().collect())
+        .unwrap_or_else(|e| Cli::command().error(ErrorKind::InvalidValue, e).exit());
+    let cli = Cli::parse_from(args);
+
+    let archiver_args = match &cli.command {
+        Command::Status(args) => match status(&args.socket) {
+            Ok(report) => {
+                print!("{report}");
+                exit(0);
+            }
+            Err(e) => {
+                eprintln!("Cannot retrieve status from {:?}: {}", &args.socket, e);
+                exit(EXIT_RUNTIME);
+            }
+        },
+        Command::Ready(args) => match request(&args.socket, "ready") {
+            Ok(answer) => {
+                print!("{answer}");
+                exit(if answer.trim() == "ready" {
+                    0
+                } else {
+                    EXIT_RUNTIME
+                });
+            }
+            Err(e) => {
+                eprintln!("Cannot check readiness through {:?}: {}", &args.socket, e);
+                exit(EXIT_RUNTIME);
+            }
+        },
+        Command::Pause(args) | Command::Resume(args) => {
+            let command = if matches!(cli.command, Command::Pause(_)) {
+                "pause"
+            } else {
+                "resume"
+            };
+            match request(&args.socket, command) {
+                Ok(answer) => {
+                    print!("{answer}");
+                    exit(0);
+                }
+                Err(e) => {
+                    eprintln!(
+                        "Cannot {} archival through {:?}: {}",
+                        command, &args.socket, e
+                    );
+                    exit(EXIT_RUNTIME);
+                }
+            }
+        }
+        Command::Ship(args) => run_ship(&cli, args),
+        Command::Selftest(args) => run_selftest(&cli, args),
+        Command::SetupAcl(args) => run_setup_acl(&cli, args),
+        Command::Fsck(args) => run_fsck(&cli, args),
+        Command::Export(args) => run_export(&cli, args),
+        Command::Replay(args) => run_replay(&cli, args),
+        #[cfg(feature = "web")]
+        Command::Browse(args) => run_browse(&cli, args),
+        Command::Archiver(args) => args,
+    };
+    let cluster = required(cli.cluster.clone(), "cluster");
+    let scheduler = required(cli.scheduler.clone(), "scheduler");
+
+    let stdout_archiver = matches!(archiver_args, ArchiverArgs::Stdout);
+    match setup_logging(cli.debug, cli.logfile.clone(), stdout_archiver) {
+        Ok(_) => (),
+        Err(e) => {
+            eprintln!("Cannot set up logging: {e}");
+            exit(EXIT_CONFIG);
+        }
+    };
+    if let Some(name) = &cli.profile {
+        info!("Using profile {} from {:?}", name, &cli.config);
+    }
+
+    let roots = spools(&cli);
+
+    let identity = instance_identity(
+        &cli,
+        &format!("{cluster} {roots:?} {scheduler:?} {archiver_args:?}"),
+    );
+    let archiver = setup_archiver(&cli, archiver_args, &identity, &cluster);
+    info!("sarchive starting. Watching spool {:?}.", &roots);
+    if let Some(size) = inotify_queue_size() {
+        info!(
+            "The inotify queue holds {} events (fs.inotify.max_queued_events)",
+            size
+        );
+    }
 
     let notification = Arc::new(AtomicBool::new(false));
     let parker = Parker::new();
     let unparker = parker.unparker();
 
+    // SIGQUIT stops the program without processing the queued job entries
+    let quit = Arc::new(AtomicBool::new(false));
+    if let Err(e) = signal_hook::flag::register(signal_hook::consts::SIGQUIT, Arc::clone(&quit)) {
+        error!("Cannot register SIGQUIT for quitting: {:?}", e);
+        exit(EXIT_RUNTIME);
+    }
+
     register_signal_handler(signal_hook::consts::SIGTERM, unparker, &notification);
     register_signal_handler(signal_hook::consts::SIGINT, unparker, &notification);
+    register_signal_handler(signal_hook::consts::SIGQUIT, unparker, &notification);
 
-    let (sig_sender, sig_receiver) = bounded(20);
+    // SIGHUP reopens the log file and reloads the watch locations
+    let reload = Arc::new(AtomicBool::new(false));
+    if let Err(e) = signal_hook::flag::register(signal_hook::consts::SIGHUP, Arc::clone(&reload)) {
+        error!(
+            "Cannot register SIGHUP for reloading watch locations: {:?}",
+            e
+        );
+        exit(EXIT_RUNTIME);
+    }
+
+    // SIGUSR1 writes the internal state to the log
+    let dump_state = Arc::new(AtomicBool::new(false));
+    if let Err(e) =
+        signal_hook::flag::register(signal_hook::consts::SIGUSR1, Arc::clone(&dump_state))
+    {
+        error!("Cannot register SIGUSR1 for dumping the state: {:?}", e);
+        exit(EXIT_RUNTIME);
+    }
+
+    // SIGUSR2 starts or ends maintenance
+    let maintenance = setup_maintenance(&cli.maintenance_windows);
+
+    // every spool root adds a thread managing its watch locations and one
+    // discovering them
+    let (sig_sender, sig_receiver) = bounded(20 + 2 * roots.len());
     let cleanup = cli.cleanup;
+    let tombstones = cli.tombstones;
+    let max_batch = cli.max_batch_size as usize;
+    let starvation = cli.starvation_threshold.map(Duration::from_secs);
+    let supervisor = Supervisor::new(cli.max_restarts);
+    let reconciler = Reconciler::new(
+        cli.reconcile_interval.map(Duration::from_secs),
+        cli.reconcile_enqueue,
+    );
+    if let Some(interval) = cli.reconcile_interval {
+        info!(
+            "Checking the watch locations for missed job entries every {}s",
+            interval
+        );
+    }
+    let stats = Stats::new();
+    if cli.check_backends {
+        stats.set_backend_checked();
+    }
+    let trace = cli.record_trace.as_ref().map(|path| {
+        TraceRecorder::create(path).unwrap_or_else(|e| {
+            error!("Cannot record the events to {:?}: {}", path, e);
+            exit(EXIT_CONFIG);
+        })
+    });
+    let event_log = cli.event_log.as_ref().map(|path| {
+        EventLog::open(path).unwrap_or_else(|e| {
+            error!("Cannot log the events to {:?}: {}", path, e);
+            exit(EXIT_CONFIG);
+        })
+    });
+    let mut archived = match &cli.completion_state {
+        Some(path) => ArchivedJobs::open(path, ARCHIVED_JOBS_CAPACITY).unwrap_or_else(|e| {
+            error!("Cannot read the archived jobs from {:?}: {}", path, e);
+            exit(EXIT_CONFIG);
+        }),
+        None => ArchivedJobs::default(),
+    };
+    if !archived.is_empty() {
+        info!(
+            "Awaiting the completion of {} archived jobs",
+            archived.len()
+        );
+    }
+    // the entries held for maintenance when stopping, saved with the queue
+    let mut unarchived = Vec::new();
+    let recorders: Vec<&dyn EventRecorder> = trace
+        .iter()
+        .map(|t| t as &dyn EventRecorder)
+        .chain(event_log.iter().map(|l| l as &dyn EventRecorder))
+        .collect();
 
-    // we will watch the locations provided by the scheduler
+    // we will watch the locations provided by the scheduler, as well as those
+    // that are discovered while running
     let (sender, receiver) = unbounded();
-    let sched = create(&scheduler, &base, &cluster, &filter_regex);
+    let (completion_sender, completion_receiver) = unbounded();
+    let scheds = setup_schedulers(&cli, &scheduler, &cluster, &roots);
+    // sarchive is ready once these all have an active watcher
+    let mut initial_locations = Vec::new();
+    let locations: Vec<_> = scheds
+        .iter()
+        .map(|(_, sched)| {
+            let (location_sender, location_receiver) = unbounded();
+            for loc in sched.watch_locations() {
+                initial_locations.push(loc.clone());
+                location_sender.send(WatchCommand::Add(loc)).unwrap();
+            }
+            (location_sender, location_receiver)
+        })
+        .collect();
+    if let Some(path) = &cli.state_file {
+        let restorers: Vec<&dyn Scheduler> = scheds.iter().map(|(_, s)| s.as_ref()).collect();
+        match spill::restore(path, &restorers) {
+            Ok(entries) => {
+                if !entries.is_empty() {
+                    info!(
+                        "Picking up {} job entries saved in {:?}",
+                        entries.len(),
+                        path
+                    );
+                }
+                for entry in entries {
+                    sender.send(entry).unwrap();
+                }
+            }
+            Err(e) => {
+                error!("Cannot read the saved job entries from {:?}: {}", path, e);
+                exit(EXIT_CONFIG);
+            }
+        }
+    }
     if let Err(e) = scope(|s| {
         let ss = &sig_sender;
+        let q = &quit;
         s.spawn(move |_| {
-            signal_handler_atomic(ss, notification, &parker);
+            signal_handler_atomic(ss, notification, q, &parker);
             info!("Signal handled");
         });
 
-        for loc in sched.watch_locations() {
+        for ((_, sched), (location_sender, location_receiver)) in
+            scheds.iter().zip(locations.iter())
+        {
+            if let Some(loc) = sched.discovery_location() {
+                let ls = location_sender;
+                let sr = &sig_receiver;
+                let st = &stats;
+                s.spawn(move |_| {
+                    let what = format!("discovery in {:?}", &loc);
+                    match supervisor.run(&what, st, || discover(sched, &loc, ls, sr)) {
+                        Some(Ok(_)) => info!("Stopped discovering watch locations in {:?}", &loc),
+                        Some(Err(e)) => {
+                            error!("Error discovering watch locations in {:?}: {:?}", &loc, e)
+                        }
+                        None => (),
+                    }
+                });
+            }
+
             let t = &sender;
+            let lr = location_receiver;
             let sr = &sig_receiver;
-            let sl = &sched;
-            let b = &base;
-            s.spawn(move |_| match monitor(sl, &loc, t, sr) {
-                Ok(_) => info!("Stopped watching location {:?}", &loc),
-                Err(e) => {
-                    error!("{:?}", e);
-                    panic!("Error watching {:?}", &b);
-                }
+            let rl = &reload;
+            let st = &stats;
+            let rc = &reconciler;
+            let tr = recorders.as_slice();
+            s.spawn(move |s| {
+                manage(s, sched, lr, t, sr, rl, st, starvation, rc, tr, supervisor);
+                info!("Stopped managing watch locations");
+            });
+        }
+
+        let (il, sr, st) = (&initial_locations, &sig_receiver, &stats);
+        s.spawn(move |_| await_ready(st, il, sr));
+
+        if let Some(path) = &cli.control_socket {
+            let r = &receiver;
+            let sr = &sig_receiver;
+            let st = &stats;
+            let m = &maintenance;
+            s.spawn(move |_| match serve(path, st, r, sr, m) {
+                Ok(()) => info!("Stopped listening on control socket {:?}", path),
+                Err(e) => error!("Control socket {:?} failed: {:?}", path, e),
+            });
+        }
+
+        if let Some(minutes) = cli.submission_report_interval {
+            let (sr, st, c) = (&sig_receiver, &stats, &cluster);
+            let (endpoint, labels) = (&cli.line_protocol, &identity.labels);
+            s.spawn(move |_| {
+                let sender = endpoint.as_ref().map(LineSender::new);
+                let interval = Duration::from_secs(minutes * 60);
+                report_submissions(st, c, interval, sr, |summary| {
+                    let Some(sender) = &sender else { return };
+                    for point in submission_points("sarchive_submissions", c, summary, labels) {
+                        if let Err(e) = sender.send(&point) {
+                            warn!("Could not send the submission summary: {}", e);
+                            break;
+                        }
+                    }
+                });
+                info!("Stopped reporting submissions");
+            });
+        }
+
+        let (d, r, sr, st) = (&dump_state, &receiver, &sig_receiver, &stats);
+        s.spawn(move |_| {
+            dump(d, st, r, sr);
+            info!("Stopped listening for state dump requests");
+        });
+
+        if let Some(path) = &cli.completion_log {
+            let cs = &completion_sender;
+            let sr = &sig_receiver;
+            let c = &cluster;
+            let from_start = cli.completion_state.is_some();
+            s.spawn(move |_| match tail(path, c, cs, sr, from_start) {
+                Ok(()) => info!("Stopped following job completions in {:?}", path),
+                Err(e) => error!("Following job completions in {:?} failed: {:?}", path, e),
+            });
+        }
+
+        if let Some(dir) = &cli.torque.accounting {
+            let cs = &completion_sender;
+            let sr = &sig_receiver;
+            let c = &cluster;
+            let from_start = cli.completion_state.is_some();
+            s.spawn(move |_| match follow(dir, c, cs, sr, from_start) {
+                Ok(()) => info!("Stopped following the Torque accounting logs in {:?}", dir),
+                Err(e) => error!(
+                    "Following the Torque accounting logs in {:?} failed: {:?}",
+                    dir, e
+                ),
+            });
+        }
+
+        if !cli.prolog_artefacts.is_empty() {
+            let dirs = &cli.prolog_artefacts;
+            let cs = &completion_sender;
+            let sr = &sig_receiver;
+            let c = &cluster;
+            s.spawn(move |_| match watch(dirs, c, cs, sr) {
+                Ok(()) => info!("Stopped looking for prolog and epilog artefacts"),
+                Err(e) => error!("Looking for prolog and epilog artefacts failed: {:?}", e),
             });
         }
 
         let r = &receiver;
+        let cr = &completion_receiver;
         let sr = &sig_receiver;
+        let st = &stats;
+        let m = &maintenance;
+        let rc = &reconciler;
+        let deadline = entry_deadline(&cli);
+        let c = &cluster;
+        let u = &mut unarchived;
         s.spawn(move |_| {
-            match process(archiver, r, sr, cleanup) {
-                Ok(()) => info!("Processing completed succesfully"),
-                Err(e) => error!("processing failed: {:?}", e),
+            // job entries wait in the queue until the archiver can take them
+            if !st.backend_checked() && !check_backend(archiver.as_ref(), c, st, sr) {
+                info!("Stopped before the archiver passed its check");
+                return;
+            }
+            let outcome = supervisor.run("processing", st, || {
+                process(
+                    archiver.as_ref(),
+                    r,
+                    cr,
+                    &mut archived,
+                    u,
+                    sr,
+                    &ProcessContext {
+                        stats: st,
+                        maintenance: m,
+                        reconciler: rc,
+                        deadline: deadline.as_ref(),
+                        tombstones,
+                        cleanup,
+                        max_batch,
+                    },
+                )
+            });
+            match outcome {
+                Some(Ok(())) => info!("Processing completed succesfully"),
+                Some(Err(e)) => {
+                    error!("processing failed: {:?}", e);
+                    exit(EXIT_RUNTIME);
+                }
+                None => {
+                    error!("processing stopped after panicking too often");
+                    exit(EXIT_RUNTIME);
+                }
             };
         });
     }) {
         error!("sarchive stopping due to error: {:?}", e);
-        exit(1);
+        exit(EXIT_RUNTIME);
     };
 
+    if let Some(path) = &cli.state_file {
+        match spill::save(path, unarchived.into_iter().chain(receiver.try_iter())) {
+            Ok(0) => (),
+            Ok(count) => info!("Saved {} queued job entries to {:?}", count, path),
+            Err(e) => error!("Cannot save the queued job entries to {:?}: {}", path, e),
+        }
+    }
+
     info!("Sarchive finished");
     exit(0);
 }
@@ -0,0 +1,207 @@
+/*
+Copyright 2019-2024 Andy Georges <itkovian+sarchive@gmail.com>
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in
+all copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+use chrono::{DateTime, Local, NaiveTime, TimeDelta};
+use log::info;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering::SeqCst;
+use std::sync::{Arc, Mutex};
+
+/// A daily maintenance window in local time, which may run past midnight
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Window {
+    start: NaiveTime,
+    end: NaiveTime,
+}
+
+impl Window {
+    /// Returns when the window holding the given time started, if it holds it
+    fn started(&self, now: DateTime<Local>) -> Option<DateTime<Local>> {
+        let day = TimeDelta::days(1);
+        let normalize = |delta: TimeDelta| {
+            if delta < TimeDelta::zero() {
+                delta + day
+            } else {
+                delta
+            }
+        };
+        let since = normalize(now.time() - self.start);
+        if since < normalize(self.end - self.start) {
+            Some(now - since)
+        } else {
+            None
+        }
+    }
+}
+
+/// Parses a maintenance window given as HH:MM-HH:MM
+pub fn parse_window(s: &str) -> Result<Window, String> {
+    let parse = |t: &str| {
+        NaiveTime::parse_from_str(t.trim(), "%H:%M").map_err(|e| format!("invalid time {t:?}: {e}"))
+    };
+    let (start, end) = s
+        .split_once('-')
+        .ok_or_else(|| format!("expected HH:MM-HH:MM, got {s:?}"))?;
+    let window = Window {
+        start: parse(start)?,
+        end: parse(end)?,
+    };
+    if window.start == window.end {
+        return Err(format!("window {s:?} is empty"));
+    }
+    Ok(window)
+}
+
+#[derive(Default)]
+struct State {
+    /// Paused by hand, until resumed by hand
+    manual: bool,
+    /// When archival was last resumed by hand, which ends a scheduled window early
+    resumed: Option<DateTime<Local>>,
+}
+
+/// Whether archival is paused for maintenance. It is paused during the
+/// scheduled windows, and from a pause to a resume request on the control
+/// socket or SIGUSR2. A resume request also ends the current window.
+#[derive(Default)]
+pub struct Maintenance {
+    windows: Vec<Window>,
+    /// Raised (e.g., on SIGUSR2) to pause when running, or resume when paused
+    pub toggle: Arc<AtomicBool>,
+    state: Mutex<State>,
+}
+
+impl Maintenance {
+    pub fn new(windows: &[Window]) -> Self {
+        Maintenance {
+            windows: windows.to_vec(),
+            ..Default::default()
+        }
+    }
+
+    /// Pauses archival until it is resumed
+    pub fn pause(&self) {
+        info!("Pause for maintenance requested");
+        self.state.lock().unwrap().manual = true;
+    }
+
+    /// Resumes archival, also when paused by a scheduled window
+    pub fn resume(&self) {
+        info!("End of maintenance requested");
+        let mut state = self.state.lock().unwrap();
+        state.manual = false;
+        state.resumed = Some(Local::now());
+    }
+
+    /// Whether archival is paused now
+    pub fn paused(&self) -> bool {
+        self.paused_at(Local::now())
+    }
+
+    fn paused_at(&self, now: DateTime<Local>) -> bool {
+        if self.toggle.swap(false, SeqCst) {
+            if self.paused_at(now) {
+                self.resume();
+            } else {
+                self.pause();
+            }
+        }
+        let state = self.state.lock().unwrap();
+        state.manual
+            || self
+                .windows
+                .iter()
+                .filter_map(|w| w.started(now))
+                .any(|started| match state.resumed {
+                    Some(resumed) => resumed < started,
+                    None => true,
+                })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use chrono::TimeZone;
+
+    fn at(hour: u32, minute: u32) -> DateTime<Local> {
+        Local
+            .with_ymd_and_hms(2024, 3, 12, hour, minute, 0)
+            .unwrap()
+    }
+
+    #[test]
+    fn test_parse_window() {
+        let window = parse_window("22:00-02:30").unwrap();
+        assert_eq!(window.start, NaiveTime::from_hms_opt(22, 0, 0).unwrap());
+        assert_eq!(window.end, NaiveTime::from_hms_opt(2, 30, 0).unwrap());
+        assert!(parse_window("22:00").is_err());
+        assert!(parse_window("22:00-25:00").is_err());
+        assert!(parse_window("08:00-08:00").is_err());
+    }
+
+    #[test]
+    fn test_window_started() {
+        let day = parse_window("08:00-12:00").unwrap();
+        assert_eq!(day.started(at(9, 15)), Some(at(8, 0)));
+        assert_eq!(day.started(at(8, 0)), Some(at(8, 0)));
+        assert_eq!(day.started(at(12, 0)), None);
+        assert_eq!(day.started(at(7, 59)), None);
+
+        let night = parse_window("22:00-02:00").unwrap();
+        assert_eq!(night.started(at(23, 0)), Some(at(22, 0)));
+        assert_eq!(
+            night.started(at(1, 0)),
+            Some(at(22, 0) - TimeDelta::days(1))
+        );
+        assert_eq!(night.started(at(2, 0)), None);
+        assert_eq!(night.started(at(12, 0)), None);
+    }
+
+    #[test]
+    fn test_maintenance() {
+        let maintenance = Maintenance::default();
+        assert!(!maintenance.paused());
+        maintenance.pause();
+        assert!(maintenance.paused());
+        maintenance.resume();
+        assert!(!maintenance.paused());
+
+        maintenance.toggle.store(true, SeqCst);
+        assert!(maintenance.paused());
+        assert!(maintenance.paused());
+        maintenance.toggle.store(true, SeqCst);
+        assert!(!maintenance.paused());
+    }
+
+    #[test]
+    fn test_maintenance_window() {
+        let maintenance = Maintenance::new(&[parse_window("08:00-12:00").unwrap()]);
+        assert!(maintenance.paused_at(at(9, 0)));
+        assert!(!maintenance.paused_at(at(13, 0)));
+
+        // resuming ends the current window, but not the next one
+        maintenance.state.lock().unwrap().resumed = Some(at(10, 0));
+        assert!(!maintenance.paused_at(at(11, 0)));
+        assert!(maintenance.paused_at(at(8, 0) + TimeDelta::days(1)));
+    }
+}
@@ -23,55 +23,452 @@ extern crate chrono;
 extern crate crossbeam_channel;
 extern crate crossbeam_utils;
 
-use crossbeam_channel::{select, unbounded, Receiver, Sender};
+use crossbeam_channel::{bounded, select, unbounded, Receiver, Sender, TryRecvError};
+use crossbeam_utils::thread::Scope;
 use log::*;
-use notify::event::Event;
+use notify::event::{CreateKind, Event, EventKind, ModifyKind, RenameMode};
 use notify::{recommended_watcher, RecursiveMode, Watcher};
-use std::io::{Error, ErrorKind};
-use std::path::Path;
-
+use std::collections::{BTreeMap, HashMap};
+use std::fs::read_to_string;
+use std::io::Error;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering::SeqCst;
+use std::time::{Duration, Instant};
+
+use super::reconcile::Reconciler;
 use super::scheduler::job::JobInfo;
 use super::scheduler::Scheduler;
+use super::stats::{LocationStats, Stats};
+use super::supervisor::Supervisor;
+use super::trace::EventRecorder;
+use super::utils::JobContext;
+
+/// How often the manager checks if the watch locations need to be reloaded
+const RELOAD_CHECK_INTERVAL: Duration = Duration::from_secs(1);
 
 /// The check_and_queue function verifies that the inotify event pertains
 /// and actual Slurm job entry and pushes the correct information to the
 /// channel so it can be processed later on.
+///
+/// Returns true if a job entry was queued.
 #[allow(clippy::borrowed_box)]
 fn check_and_queue(
     scheduler: &Box<dyn Scheduler>,
     s: &Sender<Box<dyn JobInfo>>,
     event: Event,
-) -> Result<(), std::io::Error> {
+    reconciler: &Reconciler,
+) -> Result<bool, std::io::Error> {
     debug!("Event received: {:?}", event);
 
+    // Entries that are not jobs, e.g., a temporary name that is later renamed
+    // to the job entry, are skipped
     match scheduler.verify_event_kind(&event) {
-        Some(paths) => scheduler
-            .create_job_info(&paths[0])
-            .ok_or_else(|| {
-                Error::new(
-                    ErrorKind::Other,
-                    "Could not create job info structure".to_owned(),
-                )
-            })
-            .and_then(|jobinfo| {
+        Some(paths) => match scheduler.create_job_info(&paths[0]) {
+            Some(jobinfo) => {
+                let _context = JobContext::enter(&jobinfo.cluster(), &jobinfo.jobid());
+                debug!("Queueing job entry for {:?}", &paths[0]);
+                reconciler.queued(&jobinfo.jobid());
+                if let Some(path) = jobinfo.event_path() {
+                    reconciler.pending(&path);
+                }
                 s.send(jobinfo)
-                    .map_err(|err| Error::new(ErrorKind::Other, err.to_string()))
-            }),
-        _ => Ok(()),
+                    .map_err(|err| Error::other(err.to_string()))
+                    .map(|_| true)
+            }
+            None => {
+                debug!("Not a job entry: {:?}", &paths[0]);
+                Ok(false)
+            }
+        },
+        _ => Ok(false),
+    }
+}
+
+/// Handles an event on the watched path: a job entry is queued, and when events
+/// were lost because the queue overflowed, the location is reconciled. The
+/// deletion of a job entry that is still queued is passed on to the reconciler,
+/// so processing can drop it. When the scheduler archives deletions, an entry
+/// reporting the deletion is queued as well.
+#[allow(clippy::borrowed_box)]
+pub fn handle_event(
+    scheduler: &Box<dyn Scheduler>,
+    path: &Path,
+    s: &Sender<Box<dyn JobInfo>>,
+    stats: &Stats,
+    reconciler: &Reconciler,
+    event: Event,
+) -> Result<(), Error> {
+    if event.need_rescan() {
+        error!(
+            "Lost events for {:?}, the inotify queue overflowed (see fs.inotify.max_queued_events)",
+            path
+        );
+        stats.overflow(path);
+        reconciler.trigger();
+        return Ok(());
+    }
+    stats.event(path);
+    if let Some(job_path) = scheduler.verify_job_removal(&event) {
+        if reconciler.deleted(&job_path) {
+            debug!("Queued job entry {:?} was deleted", &job_path);
+        }
+        if let Some(deletion) = scheduler.deletion_entry(&job_path) {
+            debug!("Queueing the deletion of job entry {:?}", &job_path);
+            s.send(deletion)
+                .map_err(|err| Error::other(err.to_string()))?;
+        }
+        return Ok(());
+    }
+    if check_and_queue(scheduler, s, event, reconciler)? {
+        stats.job(path);
     }
+    Ok(())
 }
 
 /// The monitor function uses a platform-specific watcher to track inotify events on
 /// the given path, formed by joining the base and the hash path.
 /// At the same time, it check for a notification indicating that it should stop operations
 /// upon receipt of which it immediately returns.
+/// Each event is written to the recorders (the trace and the event log, if
+/// any) before it is handled. The stats tell whether the watcher is active.
 #[allow(clippy::borrowed_box)]
 pub fn monitor(
     scheduler: &Box<dyn Scheduler>,
     path: &Path,
     s: &Sender<Box<dyn JobInfo>>,
     sigchannel: &Receiver<bool>,
+    stats: &Stats,
+    reconciler: &Reconciler,
+    recorders: &[&dyn EventRecorder],
+) -> notify::Result<()> {
+    let outcome = watch(
+        path,
+        sigchannel,
+        || stats.watching(path, true),
+        |event| {
+            for recorder in recorders {
+                recorder.record(path, &event);
+            }
+            handle_event(scheduler, path, s, stats, reconciler, event)
+        },
+    );
+    stats.watching(path, false);
+    outcome
+}
+
+/// Returns the number of events the inotify queue holds, if known
+pub fn inotify_queue_size() -> Option<u64> {
+    read_to_string("/proc/sys/fs/inotify/max_queued_events")
+        .ok()
+        .and_then(|size| size.trim().parse().ok())
+}
+
+/// Changes to the set of locations that are being watched
+#[derive(Debug, PartialEq, Eq)]
+pub enum WatchCommand {
+    /// Start monitoring the location
+    Add(PathBuf),
+    /// Stop monitoring the location
+    Remove(PathBuf),
+}
+
+/// The discover function watches the given path for locations that appear or
+/// vanish, e.g., hash directories in the Slurm spool that are created after we
+/// started. Each such change is sent to the manager, which then starts or stops
+/// monitoring the location.
+#[allow(clippy::borrowed_box)]
+pub fn discover(
+    scheduler: &Box<dyn Scheduler>,
+    path: &Path,
+    commands: &Sender<WatchCommand>,
+    sigchannel: &Receiver<bool>,
 ) -> notify::Result<()> {
+    watch(
+        path,
+        sigchannel,
+        || (),
+        |event| {
+            debug!("Discovery event received: {:?}", event);
+            let command = if let Some(location) = scheduler.verify_discovery_event(&event) {
+                info!("Discovered new watch location {:?}", &location);
+                WatchCommand::Add(location)
+            } else if let Some(location) = scheduler.verify_removal_event(&event) {
+                info!("Watch location {:?} was removed", &location);
+                WatchCommand::Remove(location)
+            } else {
+                return Ok(());
+            };
+            commands
+                .send(command)
+                .map_err(|err| Error::other(err.to_string()))
+        },
+    )
+}
+
+/// A monitor thread watching a single location
+struct WatchHandle {
+    /// Notifies the thread it should stop
+    stop: Sender<bool>,
+    /// Disconnects once the thread has exited
+    alive: Receiver<()>,
+    /// Time the thread started watching
+    started: Instant,
+}
+
+impl WatchHandle {
+    fn is_alive(&self) -> bool {
+        !matches!(self.alive.try_recv(), Err(TryRecvError::Disconnected))
+    }
+
+    fn stop(&self) {
+        // The monitor thread may already have exited due to an error
+        let _ = self.stop.send(true);
+    }
+}
+
+/// Returns the watched locations that went without events for longer than the
+/// threshold, while some other location did receive events in that time. If
+/// all locations are quiet, there is simply no activity and none is starved.
+fn starved(
+    watched: &HashMap<PathBuf, WatchHandle>,
+    locations: &BTreeMap<PathBuf, LocationStats>,
+    threshold: Duration,
+) -> Vec<PathBuf> {
+    let last_event = |location: &PathBuf| locations.get(location).and_then(|l| l.last_event);
+    let busy = watched
+        .keys()
+        .any(|location| last_event(location).is_some_and(|t| t.elapsed() < threshold));
+    if !busy {
+        return Vec::new();
+    }
+    watched
+        .iter()
+        .filter(|(location, handle)| {
+            let last_activity =
+                last_event(location).map_or(handle.started, |t| t.max(handle.started));
+            last_activity.elapsed() >= threshold
+        })
+        .map(|(location, _)| location.clone())
+        .collect()
+}
+
+/// Looks for job entries in the location whose event was missed, counting
+/// them and, if requested, queueing them after all
+#[allow(clippy::borrowed_box)]
+fn reconcile(
+    scheduler: &Box<dyn Scheduler>,
+    location: &Path,
+    s: &Sender<Box<dyn JobInfo>>,
+    stats: &Stats,
+    reconciler: &Reconciler,
+) {
+    let missed = match reconciler.scan(scheduler, location) {
+        Ok(missed) => missed,
+        Err(e) => {
+            warn!("Cannot reconcile watch location {:?}: {}", location, e);
+            return;
+        }
+    };
+    for job_entry in missed {
+        let _context = JobContext::enter(&job_entry.cluster(), &job_entry.jobid());
+        warn!(
+            "Missed the event for job {} in {:?}",
+            job_entry.jobid(),
+            location
+        );
+        stats.missed(location);
+        if reconciler.enqueue {
+            info!("Queueing job entry {} after all", job_entry.jobid());
+            reconciler.queued(&job_entry.jobid());
+            if s.send(job_entry).is_ok() {
+                stats.job(location);
+            }
+        }
+    }
+}
+
+/// The manage function runs a monitor thread in the given scope for each
+/// watched location. Locations are added and removed through the commands
+/// channel. When the reload flag is raised (e.g., on SIGHUP), the scheduler is
+/// asked for its watch locations again and the set of monitor threads is adjusted
+/// to match.
+/// With a starvation threshold, a location that has received no events for that
+/// long while others were busy is watched anew, as its watch may have been lost.
+/// When the reconciler says so, the watched locations are checked for job
+/// entries whose event was missed.
+/// A monitor thread that panics is restarted by the supervisor, until it has
+/// been restarted too often.
+/// Upon receipt of a notification that it should stop, it passes this on to
+/// every monitor thread it started and returns.
+#[allow(clippy::borrowed_box, clippy::too_many_arguments)]
+pub fn manage<'env>(
+    scope: &Scope<'env>,
+    scheduler: &'env Box<dyn Scheduler>,
+    commands: &Receiver<WatchCommand>,
+    s: &'env Sender<Box<dyn JobInfo>>,
+    sigchannel: &Receiver<bool>,
+    reload: &AtomicBool,
+    stats: &'env Stats,
+    starvation: Option<Duration>,
+    reconciler: &'env Reconciler,
+    recorders: &'env [&'env dyn EventRecorder],
+    supervisor: Supervisor,
+) {
+    let mut watched: HashMap<PathBuf, WatchHandle> = HashMap::new();
+
+    let start = |location: PathBuf| {
+        reconciler.watching(&location);
+        let (stop_sender, stop_receiver) = bounded(1);
+        let (alive_sender, alive_receiver) = bounded::<()>(0);
+        let path = location.clone();
+        scope.spawn(move |_| {
+            let _alive = alive_sender;
+            let what = format!("watcher of {:?}", &path);
+            match supervisor.run(&what, stats, || {
+                monitor(
+                    scheduler,
+                    &path,
+                    s,
+                    &stop_receiver,
+                    stats,
+                    reconciler,
+                    recorders,
+                )
+            }) {
+                Some(Ok(_)) => info!("Stopped watching location {:?}", &path),
+                Some(Err(e)) => error!("Error watching {:?}: {:?}", &path, e),
+                None => (),
+            }
+        });
+        WatchHandle {
+            stop: stop_sender,
+            alive: alive_receiver,
+            started: Instant::now(),
+        }
+    };
+
+    #[allow(clippy::zero_ptr, dropping_copy_types)]
+    loop {
+        // Forget about monitor threads that exited by themselves, so their
+        // location can be picked up again
+        watched.retain(|location, handle| {
+            let alive = handle.is_alive();
+            if !alive {
+                warn!("No longer watching location {:?}", location);
+            }
+            alive
+        });
+
+        select! {
+            recv(sigchannel) -> b => if let Ok(true) = b {
+                break;
+            },
+            recv(commands) -> command => match command {
+                Ok(WatchCommand::Add(location)) => {
+                    if watched.contains_key(&location) {
+                        debug!("Already watching location {:?}", &location);
+                    } else {
+                        watched.insert(location.clone(), start(location));
+                    }
+                }
+                Ok(WatchCommand::Remove(location)) => {
+                    if let Some(handle) = watched.remove(&location) {
+                        info!("Removing watch location {:?}", &location);
+                        handle.stop();
+                        reconciler.unwatched(&location);
+                    }
+                }
+                Err(e) => {
+                    error!("Error on receiving watch command: {:?}", e);
+                    break;
+                }
+            },
+            default(RELOAD_CHECK_INTERVAL) => {
+                if let Some(threshold) = starvation {
+                    for location in starved(&watched, &stats.locations(), threshold) {
+                        warn!(
+                            "No events on {:?} for {}s while other locations are busy, watching it anew",
+                            &location,
+                            threshold.as_secs()
+                        );
+                        if let Some(handle) = watched.remove(&location) {
+                            handle.stop();
+                        }
+                        watched.insert(location.clone(), start(location));
+                    }
+                }
+                if reconciler.due() {
+                    for location in watched.keys() {
+                        reconcile(scheduler, location, s, stats, reconciler);
+                    }
+                }
+                if reload.swap(false, SeqCst) {
+                    let locations = scheduler.watch_locations();
+                    info!("Reloading watch locations: {:?}", &locations);
+                    watched.retain(|location, handle| {
+                        let keep = locations.contains(location);
+                        if !keep {
+                            info!("Removing watch location {:?}", location);
+                            handle.stop();
+                            reconciler.unwatched(location);
+                        }
+                        keep
+                    });
+                    for location in locations {
+                        if !watched.contains_key(&location) {
+                            watched.insert(location.clone(), start(location));
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    for handle in watched.values() {
+        handle.stop();
+    }
+}
+
+/// Brings the events of the platform-specific watcher in line with those of
+/// inotify on Linux, which the schedulers expect. FSEvents on macOS may not know
+/// what kind of entry was created, and neither FSEvents nor kqueue on the BSDs
+/// tell the old name of a rename from the new one. We look at the filesystem to
+/// fill in the blanks: a renamed path that exists is the new name.
+fn normalize(mut event: Event) -> Event {
+    if let Some(path) = event.paths.first() {
+        event.kind = match event.kind {
+            EventKind::Create(CreateKind::Any | CreateKind::Other) if path.is_dir() => {
+                EventKind::Create(CreateKind::Folder)
+            }
+            EventKind::Create(CreateKind::Any | CreateKind::Other) if path.is_file() => {
+                EventKind::Create(CreateKind::File)
+            }
+            EventKind::Modify(ModifyKind::Name(RenameMode::Any)) if path.exists() => {
+                EventKind::Modify(ModifyKind::Name(RenameMode::To))
+            }
+            EventKind::Modify(ModifyKind::Name(RenameMode::Any)) => {
+                EventKind::Modify(ModifyKind::Name(RenameMode::From))
+            }
+            kind => kind,
+        };
+    }
+    event
+}
+
+/// Track events on the given path with a platform-specific watcher, handing
+/// each event to the provided closure until we are notified to stop. Once the
+/// watcher is active, watching is called.
+fn watch<W, F>(
+    path: &Path,
+    sigchannel: &Receiver<bool>,
+    watching: W,
+    mut handle: F,
+) -> notify::Result<()>
+where
+    W: FnOnce(),
+    F: FnMut(Event) -> Result<(), Error>,
+{
     let (tx, rx) = unbounded();
 
     // create a platform-specific watcher
@@ -80,6 +477,7 @@ pub fn monitor(
     info!("Watching path {:?}", path);
 
     watcher.watch(path, RecursiveMode::NonRecursive)?;
+    watching();
 
     #[allow(clippy::zero_ptr, dropping_copy_types)]
     loop {
@@ -89,7 +487,7 @@ pub fn monitor(
             },
             recv(rx) -> event => {
                 match event {
-                    Ok(Ok(e)) => check_and_queue(scheduler, s, e)?,
+                    Ok(Ok(e)) => handle(normalize(e))?,
                     Ok(Err(_)) | Err(_) => {
                         error!("Error on received event: {:?}", event);
                         break Err(notify::Error::new(notify::ErrorKind::Generic("Problem receiving event".to_string())));
@@ -104,8 +502,11 @@ pub fn monitor(
 mod tests {
 
     use super::*;
+    use crate::scheduler::slurm::Slurm;
+    use crate::scheduler::JobEvent;
     use crossbeam_channel::unbounded;
-    use notify::event::{CreateKind, Event, EventKind};
+    use crossbeam_utils::thread::scope;
+    use notify::event::RemoveKind;
     use std::collections::HashMap;
     use std::path::PathBuf;
     use std::time::{Duration, Instant};
@@ -148,6 +549,10 @@ mod tests {
             Instant::now()
         }
 
+        fn event_time(&self) -> chrono::DateTime<chrono::Utc> {
+            chrono::Utc::now()
+        }
+
         fn cluster(&self) -> String {
             "dummy_cluster".to_string()
         }
@@ -181,12 +586,20 @@ mod tests {
         let (sig_tx, sig_rx) = unbounded();
 
         // Setup: Create a dummy scheduler
-        let scheduler: Box<(dyn Scheduler + 'static)> = Box::new(DummyScheduler);
+        let scheduler: Box<dyn Scheduler + 'static> = Box::new(DummyScheduler);
 
         // Test: Spawn a thread for the monitor function
         let monitor_thread = std::thread::spawn(move || {
-            monitor(&scheduler, &temp_dir_path_clone, &tx, &sig_rx)
-                .expect("Monitor function failed");
+            monitor(
+                &scheduler,
+                &temp_dir_path_clone,
+                &tx,
+                &sig_rx,
+                &Stats::new(),
+                &Reconciler::default(),
+                &[],
+            )
+            .expect("Monitor function failed");
         });
 
         // Introduce a delay to allow the monitor thread to start watching
@@ -214,6 +627,88 @@ mod tests {
             .expect("Failed to join monitor thread");
     }
 
+    #[test]
+    fn test_monitor_rename() {
+        let temp_dir = tempdir().unwrap();
+        let hash_dir = temp_dir.path().join("hash.4");
+        std::fs::create_dir(&hash_dir).unwrap();
+        let watched = hash_dir.clone();
+
+        let (tx, rx) = unbounded();
+        let (sig_tx, sig_rx) = unbounded();
+
+        let mut slurm = Slurm::new(temp_dir.path(), "mycluster");
+        slurm.event_kinds = vec![JobEvent::Create, JobEvent::Rename];
+        let scheduler: Box<dyn Scheduler> = Box::new(slurm);
+
+        let monitor_thread = std::thread::spawn(move || {
+            monitor(
+                &scheduler,
+                &watched,
+                &tx,
+                &sig_rx,
+                &Stats::new(),
+                &Reconciler::default(),
+                &[],
+            )
+            .expect("Monitor function failed");
+        });
+        std::thread::sleep(Duration::from_millis(1000));
+
+        // The job directory is filled under a temporary name and moved in place
+        let tmp_dir = hash_dir.join("tmp.1234");
+        std::fs::create_dir(&tmp_dir).unwrap();
+        std::fs::rename(&tmp_dir, hash_dir.join("job.1234")).unwrap();
+        std::thread::sleep(Duration::from_millis(100));
+
+        let job_info = rx.try_recv().expect("No JobInfo received");
+        assert_eq!(job_info.jobid(), "1234");
+        assert!(rx.try_recv().is_err());
+
+        sig_tx.send(true).unwrap();
+        monitor_thread.join().unwrap();
+    }
+
+    #[test]
+    fn test_normalize() {
+        let temp_dir = tempdir().unwrap();
+        let job_dir = temp_dir.path().join("job.1234");
+        let job_file = temp_dir.path().join("1234.SC");
+        std::fs::create_dir(&job_dir).unwrap();
+        std::fs::write(&job_file, "#!/bin/bash").unwrap();
+        let event = |kind: EventKind, path: &Path| Event {
+            kind,
+            paths: vec![path.to_path_buf()],
+            ..Default::default()
+        };
+
+        // FSEvents without the kind of the created entry
+        let folder = normalize(event(EventKind::Create(CreateKind::Any), &job_dir));
+        assert_eq!(folder.kind, EventKind::Create(CreateKind::Folder));
+        let file = normalize(event(EventKind::Create(CreateKind::Other), &job_file));
+        assert_eq!(file.kind, EventKind::Create(CreateKind::File));
+
+        // FSEvents and kqueue report both sides of a rename alike
+        let rename = EventKind::Modify(ModifyKind::Name(RenameMode::Any));
+        assert_eq!(
+            normalize(event(rename, &job_dir)).kind,
+            EventKind::Modify(ModifyKind::Name(RenameMode::To))
+        );
+        assert_eq!(
+            normalize(event(rename, &temp_dir.path().join("tmp.1234"))).kind,
+            EventKind::Modify(ModifyKind::Name(RenameMode::From))
+        );
+
+        // inotify events are left alone
+        let inotify = event(EventKind::Create(CreateKind::Folder), &job_dir);
+        assert_eq!(normalize(inotify.clone()), inotify);
+        let gone = event(
+            EventKind::Create(CreateKind::Any),
+            &temp_dir.path().join("gone"),
+        );
+        assert_eq!(normalize(gone).kind, EventKind::Create(CreateKind::Any));
+    }
+
     #[test]
     fn test_check_and_queue() {
         // Setup: Create a temporary directory
@@ -224,7 +719,7 @@ mod tests {
         let (tx, rx) = unbounded();
 
         // Setup: Create a dummy scheduler
-        let scheduler: Box<(dyn Scheduler + 'static)> = Box::new(DummyScheduler);
+        let scheduler: Box<dyn Scheduler + 'static> = Box::new(DummyScheduler);
 
         // Test: Create a dummy file in the temporary directory
         let dummy_file_path = temp_dir_path.join("dummy_file.txt");
@@ -238,11 +733,258 @@ mod tests {
         };
 
         // Test: Call check_and_queue function
-        let result = check_and_queue(&scheduler, &tx, dummy_event);
+        let result = check_and_queue(&scheduler, &tx, dummy_event, &Reconciler::default());
 
         // Assert: Check the result and verify if JobInfo was sent through the channel
-        assert!(result.is_ok());
+        assert!(result.unwrap());
         let job_info = rx.try_recv().expect("No JobInfo received");
         assert_eq!(job_info.jobid(), "dummy_job");
     }
+
+    #[test]
+    fn test_handle_event_overflow() {
+        let (tx, rx) = unbounded();
+        let scheduler: Box<dyn Scheduler + 'static> = Box::new(DummyScheduler);
+        let stats = Stats::new();
+        let reconciler = Reconciler::default();
+        let location = Path::new("/spool/hash.0");
+
+        let overflow = Event::new(EventKind::Other).set_flag(notify::event::Flag::Rescan);
+        handle_event(&scheduler, location, &tx, &stats, &reconciler, overflow).unwrap();
+
+        assert!(rx.try_recv().is_err());
+        let location_stats = stats.locations()[location].clone();
+        assert_eq!(location_stats.overflows, 1);
+        assert_eq!(location_stats.events, 0);
+        assert!(reconciler.due());
+    }
+
+    #[test]
+    fn test_handle_event_deleted() {
+        let tdir = tempdir().unwrap();
+        let location = tdir.path().join("hash.0");
+        let job_dir = location.join("job.1234");
+        std::fs::create_dir_all(&job_dir).unwrap();
+        let (tx, rx) = unbounded();
+        let scheduler: Box<dyn Scheduler> = Box::new(Slurm::new(tdir.path(), "mycluster"));
+        let stats = Stats::new();
+        let reconciler = Reconciler::default();
+        let event = |kind: EventKind| Event {
+            kind,
+            paths: vec![job_dir.clone()],
+            ..Default::default()
+        };
+
+        handle_event(
+            &scheduler,
+            &location,
+            &tx,
+            &stats,
+            &reconciler,
+            event(EventKind::Create(CreateKind::Folder)),
+        )
+        .unwrap();
+        assert!(rx.try_recv().is_ok());
+
+        std::fs::remove_dir(&job_dir).unwrap();
+        handle_event(
+            &scheduler,
+            &location,
+            &tx,
+            &stats,
+            &reconciler,
+            event(EventKind::Remove(RemoveKind::Folder)),
+        )
+        .unwrap();
+        assert!(rx.try_recv().is_err());
+        assert!(reconciler.is_deleted(&job_dir));
+        assert_eq!(stats.locations()[&location].jobs, 1);
+    }
+
+    #[test]
+    fn test_manage() {
+        // Setup: Create a temporary directory to watch
+        let temp_dir = tempdir().unwrap();
+        let temp_dir_path = temp_dir.path().to_owned();
+
+        let (tx, rx) = unbounded();
+        let (cmd_tx, cmd_rx) = unbounded();
+        let (sig_tx, sig_rx) = unbounded();
+        let reload = AtomicBool::new(false);
+        let stats = Stats::new();
+        let reconciler = Reconciler::default();
+        let scheduler: Box<dyn Scheduler + 'static> = Box::new(DummyScheduler);
+
+        scope(|s| {
+            let sl = &scheduler;
+            let t = &tx;
+            let rl = &reload;
+            let st = &stats;
+            let rc = &reconciler;
+            s.spawn(move |s| {
+                manage(
+                    s,
+                    sl,
+                    &cmd_rx,
+                    t,
+                    &sig_rx,
+                    rl,
+                    st,
+                    None,
+                    rc,
+                    &[],
+                    Supervisor::default(),
+                )
+            });
+
+            // Test: Add the location twice, which should only lead to a single watcher
+            cmd_tx
+                .send(WatchCommand::Add(temp_dir_path.clone()))
+                .unwrap();
+            cmd_tx
+                .send(WatchCommand::Add(temp_dir_path.clone()))
+                .unwrap();
+            std::thread::sleep(Duration::from_millis(1000));
+
+            std::fs::write(temp_dir_path.join("dummy_file.txt"), "dummy_content")
+                .expect("Failed to create dummy file");
+            std::thread::sleep(Duration::from_millis(100));
+
+            // Assert: Exactly one JobInfo instance has been sent through the channel
+            let job_info = rx.try_recv().expect("No JobInfo received");
+            assert_eq!(job_info.jobid(), "dummy_job");
+            assert!(rx.try_recv().is_err());
+            assert_eq!(stats.locations().get(&temp_dir_path).unwrap().jobs, 1);
+
+            // Test: Once removed, the location is no longer watched
+            cmd_tx
+                .send(WatchCommand::Remove(temp_dir_path.clone()))
+                .unwrap();
+            std::thread::sleep(Duration::from_millis(500));
+
+            std::fs::write(temp_dir_path.join("other_file.txt"), "dummy_content")
+                .expect("Failed to create dummy file");
+            std::thread::sleep(Duration::from_millis(100));
+            assert!(rx.try_recv().is_err());
+
+            // Stopping the manager should also stop the watchers it started
+            sig_tx.send(true).unwrap();
+        })
+        .expect("Failed to join the manager and watcher threads");
+    }
+
+    #[test]
+    fn test_manage_reload() {
+        // Setup: Create a temporary directory to watch
+        let temp_dir = tempdir().unwrap();
+        let temp_dir_path = temp_dir.path().to_owned();
+
+        let (tx, rx) = unbounded();
+        let (cmd_tx, cmd_rx) = unbounded();
+        let (sig_tx, sig_rx) = unbounded();
+        let reload = AtomicBool::new(false);
+        let stats = Stats::new();
+        let reconciler = Reconciler::default();
+        let scheduler: Box<dyn Scheduler + 'static> = Box::new(DummyScheduler);
+
+        scope(|s| {
+            let sl = &scheduler;
+            let t = &tx;
+            let rl = &reload;
+            let st = &stats;
+            let rc = &reconciler;
+            s.spawn(move |s| {
+                manage(
+                    s,
+                    sl,
+                    &cmd_rx,
+                    t,
+                    &sig_rx,
+                    rl,
+                    st,
+                    None,
+                    rc,
+                    &[],
+                    Supervisor::default(),
+                )
+            });
+
+            cmd_tx
+                .send(WatchCommand::Add(temp_dir_path.clone()))
+                .unwrap();
+            std::thread::sleep(Duration::from_millis(500));
+
+            // Test: The dummy scheduler does not list the temporary directory
+            // as a watch location, so reloading should stop watching it
+            reload.store(true, SeqCst);
+            std::thread::sleep(RELOAD_CHECK_INTERVAL + Duration::from_millis(500));
+            assert!(!reload.load(SeqCst));
+
+            std::fs::write(temp_dir_path.join("dummy_file.txt"), "dummy_content")
+                .expect("Failed to create dummy file");
+            std::thread::sleep(Duration::from_millis(100));
+            assert!(rx.try_recv().is_err());
+
+            sig_tx.send(true).unwrap();
+        })
+        .expect("Failed to join the manager and watcher threads");
+    }
+
+    #[test]
+    fn test_watch_handle_is_alive() {
+        let (stop_sender, _stop_receiver) = bounded(1);
+        let (alive_sender, alive_receiver) = bounded::<()>(0);
+        let handle = WatchHandle {
+            stop: stop_sender,
+            alive: alive_receiver,
+            started: Instant::now(),
+        };
+
+        assert!(handle.is_alive());
+        drop(alive_sender);
+        assert!(!handle.is_alive());
+    }
+
+    #[test]
+    fn test_starved() {
+        let threshold = Duration::from_secs(60);
+        let long_ago = Instant::now() - Duration::from_secs(120);
+        let handle = |started: Instant| WatchHandle {
+            stop: bounded(1).0,
+            alive: bounded(0).1,
+            started,
+        };
+        let location_stats = |last_event: Option<Instant>| LocationStats {
+            events: 1,
+            jobs: 1,
+            last_event,
+            ..Default::default()
+        };
+        let watched: HashMap<PathBuf, WatchHandle> = [
+            (PathBuf::from("hash.0"), handle(long_ago)),
+            (PathBuf::from("hash.1"), handle(long_ago)),
+            (PathBuf::from("hash.2"), handle(long_ago)),
+            (PathBuf::from("hash.3"), handle(Instant::now())),
+        ]
+        .into_iter()
+        .collect();
+
+        // All quiet, nothing is starved
+        let mut locations = BTreeMap::new();
+        locations.insert(PathBuf::from("hash.0"), location_stats(Some(long_ago)));
+        assert!(starved(&watched, &locations, threshold).is_empty());
+
+        // A busy location reveals the starved ones, except for the one we just
+        // started watching
+        locations.insert(
+            PathBuf::from("hash.1"),
+            location_stats(Some(Instant::now())),
+        );
+        let mut starved_locations = starved(&watched, &locations, threshold);
+        starved_locations.sort();
+        assert_eq!(
+            starved_locations,
+            vec![PathBuf::from("hash.0"), PathBuf::from("hash.2")]
+        );
+    }
 }
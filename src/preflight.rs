@@ -0,0 +1,328 @@
+/*
+Copyright 2019-2024 Andy Georges <itkovian+sarchive@gmail.com>
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in
+all copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+use clap::Args;
+use log::{debug, info};
+use std::ffi::{CString, OsString};
+use std::fs::{read_dir, symlink_metadata, File};
+use std::io::{Error, ErrorKind};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use crate::archive::file::parse_user;
+use crate::scheduler::Scheduler;
+use crate::utils::user_name;
+
+/// Number of entries sampled in each watch location
+const SAMPLE_SIZE: usize = 3;
+
+/// Number of paths given to a single setfacl command
+const SETFACL_BATCH: usize = 256;
+
+#[derive(Args, Debug)]
+pub struct SetupAclArgs {
+    #[arg(
+        long,
+        value_parser = parse_user,
+        help = "Service user (name or uid) that sarchive runs as, and that should be able to read the spool"
+    )]
+    pub user: u32,
+
+    #[arg(
+        long,
+        help = "Only print the setfacl commands, rather than running them"
+    )]
+    pub dry_run: bool,
+}
+
+/// Checks, before any event comes in, that sarchive can read the spool: the
+/// spool itself, each watch location, and a few of the job entries already in
+/// them, down to their files. A job entry that vanishes meanwhile is fine, but
+/// any other failure to read is returned as an error that tells which user
+/// could not read which path, and why.
+pub fn check_spool(spool: &Path, scheduler: &dyn Scheduler) -> Result<(), Error> {
+    check_access(spool)?;
+    let locations = scheduler.watch_locations();
+    for location in &locations {
+        check_access(location)?;
+        for entry in sample(location)? {
+            let mut paths = vec![entry.clone()];
+            if entry.is_dir() {
+                paths.extend(sample(&entry)?);
+            }
+            for path in paths {
+                match check_access(&path) {
+                    Err(e) if e.kind() == ErrorKind::NotFound => {
+                        debug!("Sampled job entry {:?} vanished", path)
+                    }
+                    result => result?,
+                }
+            }
+        }
+    }
+    info!(
+        "Spool {:?} and its {} watch locations are readable",
+        spool,
+        locations.len()
+    );
+    Ok(())
+}
+
+/// Returns the first few entries of the directory
+fn sample(dir: &Path) -> Result<Vec<PathBuf>, Error> {
+    let entries = read_dir(dir).map_err(|e| actionable(dir, e))?;
+    Ok(entries
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .take(SAMPLE_SIZE)
+        .collect())
+}
+
+/// Lists the directory or opens the file at the given path
+fn check_access(path: &Path) -> Result<(), Error> {
+    let result = if path.is_dir() {
+        read_dir(path).map(|_| ())
+    } else {
+        File::open(path).map(|_| ())
+    };
+    result.map_err(|e| actionable(path, e))
+}
+
+/// Returns the error for the path, adding who sarchive runs as and what the
+/// path allows, if permission was denied
+fn actionable(path: &Path, e: Error) -> Error {
+    if e.kind() != ErrorKind::PermissionDenied {
+        return Error::new(e.kind(), format!("Cannot read {path:?}: {e}"));
+    }
+    let (uid, gid) = unsafe { (libc::geteuid(), libc::getegid()) };
+    let user = user_name(uid).unwrap_or_else(|| uid.to_string());
+    let cause = match path.metadata() {
+        Ok(metadata) => format!(
+            "it is owned by uid {} and gid {} with mode {:o}{}",
+            metadata.uid(),
+            metadata.gid(),
+            metadata.mode() & 0o7777,
+            if has_acl(path) {
+                ", and its ACL does not grant access either"
+            } else {
+                ""
+            }
+        ),
+        Err(_) => "one of its parent directories cannot be searched".to_owned(),
+    };
+    Error::new(
+        ErrorKind::PermissionDenied,
+        format!(
+            "User {user} (uid {uid}, gid {gid}) cannot read {path:?}: {cause}. Run sarchive as a user \
+             that can read the spool, or grant access, e.g., with setfacl -R -m u:{user}:rX {}",
+            path.display()
+        ),
+    )
+}
+
+/// Returns the setfacl arguments that let the user with the given uid read
+/// the spool: search access to the parent directories that do not grant it to
+/// everyone, read access to everything in the spool, and the same access as
+/// the default ACL of every directory in it, so the job entries that appear
+/// later inherit it. The default ACL gets a mask of its own, as the one derived
+/// from the private modes Slurm gives the spool would mask the access.
+pub fn acl_commands(spool: &Path, uid: u32) -> Result<Vec<Vec<OsString>>, Error> {
+    let read = OsString::from(format!("u:{uid}:rX"));
+    let mut commands = Vec::new();
+    let closed: Vec<&Path> = spool
+        .ancestors()
+        .skip(1)
+        .filter(|dir| dir.metadata().is_ok_and(|m| m.mode() & 0o001 == 0))
+        .collect();
+    if !closed.is_empty() {
+        let mut command = vec!["-m".into(), format!("u:{uid}:x").into()];
+        command.extend(closed.iter().rev().map(|dir| dir.as_os_str().to_owned()));
+        commands.push(command);
+    }
+    commands.push(vec![
+        "-R".into(),
+        "-m".into(),
+        read.clone(),
+        spool.as_os_str().to_owned(),
+    ]);
+    for dirs in directories(spool)?.chunks(SETFACL_BATCH) {
+        let mut default = read.clone();
+        default.push(",m::rX");
+        let mut command = vec!["-d".into(), "-m".into(), default];
+        command.extend(dirs.iter().map(|dir| dir.as_os_str().to_owned()));
+        commands.push(command);
+    }
+    Ok(commands)
+}
+
+/// Returns the directory and every directory below it, without following
+/// symlinks
+fn directories(dir: &Path) -> Result<Vec<PathBuf>, Error> {
+    let mut found = Vec::new();
+    let mut pending = vec![dir.to_path_buf()];
+    while let Some(dir) = pending.pop() {
+        for entry in read_dir(&dir).map_err(|e| actionable(&dir, e))? {
+            let path = entry?.path();
+            if symlink_metadata(&path).is_ok_and(|m| m.is_dir()) {
+                pending.push(path);
+            }
+        }
+        found.push(dir);
+    }
+    found.sort();
+    Ok(found)
+}
+
+/// Sets up the ACLs that let the given user read the spool, as returned by
+/// acl_commands, or only prints the setfacl commands when asked to
+pub fn setup_acl(spool: &Path, args: &SetupAclArgs) -> Result<(), Error> {
+    let user = user_name(args.user).unwrap_or_else(|| args.user.to_string());
+    for command in acl_commands(spool, args.user)? {
+        let line = command
+            .iter()
+            .map(|arg| arg.to_string_lossy())
+            .collect::<Vec<_>>()
+            .join(" ");
+        if args.dry_run {
+            println!("setfacl {line}");
+            continue;
+        }
+        let status = Command::new("setfacl")
+            .args(&command)
+            .status()
+            .map_err(|e| match e.kind() {
+                ErrorKind::NotFound => Error::new(
+                    e.kind(),
+                    "setfacl was not found, install the acl package".to_owned(),
+                ),
+                _ => Error::new(e.kind(), format!("Cannot run setfacl: {e}")),
+            })?;
+        if !status.success() {
+            return Err(Error::other(format!("setfacl {line} failed with {status}")));
+        }
+    }
+    if !args.dry_run {
+        info!("User {} can now read spool {:?}", user, spool);
+    }
+    Ok(())
+}
+
+/// Whether the path has a POSIX access ACL
+fn has_acl(path: &Path) -> bool {
+    let (Ok(cpath), Ok(attribute)) = (
+        CString::new(path.as_os_str().as_bytes()),
+        CString::new("system.posix_acl_access"),
+    ) else {
+        return false;
+    };
+    // SAFETY: both are valid C strings, and a size of zero only asks for the
+    // size of the attribute
+    unsafe { libc::getxattr(cpath.as_ptr(), attribute.as_ptr(), std::ptr::null_mut(), 0) > 0 }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::scheduler::slurm::Slurm;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_check_spool() {
+        let tdir = tempdir().unwrap();
+        let job = tdir.path().join("hash.3").join("job.1233");
+        std::fs::create_dir_all(&job).unwrap();
+        std::fs::write(job.join("script"), b"#!/bin/bash\n").unwrap();
+        std::fs::create_dir(tdir.path().join("hash.4")).unwrap();
+
+        let slurm = Slurm::new(tdir.path(), "mycluster");
+        assert!(check_spool(tdir.path(), &slurm).is_ok());
+
+        let missing = tdir.path().join("missing");
+        let e = check_spool(&missing, &slurm).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::NotFound);
+        assert!(e
+            .to_string()
+            .starts_with(&format!("Cannot read {missing:?}")));
+    }
+
+    #[test]
+    fn test_acl_commands() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let tdir = tempdir().unwrap();
+        let spool = tdir.path().join("spool");
+        std::fs::create_dir_all(spool.join("hash.3").join("job.1233")).unwrap();
+        std::fs::write(spool.join("hash.3").join("job.1233").join("script"), b"").unwrap();
+        std::os::unix::fs::symlink(tdir.path(), spool.join("elsewhere")).unwrap();
+        std::fs::set_permissions(tdir.path(), std::fs::Permissions::from_mode(0o750)).unwrap();
+
+        let commands = acl_commands(&spool, 4242).unwrap();
+        let commands: Vec<Vec<String>> = commands
+            .iter()
+            .map(|c| c.iter().map(|a| a.to_string_lossy().into_owned()).collect())
+            .collect();
+        let path = |p: &Path| p.to_string_lossy().into_owned();
+        assert_eq!(commands.len(), 3);
+        assert_eq!(commands[0][..2], ["-m", "u:4242:x"]);
+        assert_eq!(commands[0].last().unwrap(), &path(tdir.path()));
+        assert_eq!(commands[1], ["-R", "-m", "u:4242:rX", &path(&spool)]);
+        assert_eq!(
+            commands[2],
+            [
+                "-d".to_owned(),
+                "-m".to_owned(),
+                "u:4242:rX,m::rX".to_owned(),
+                path(&spool),
+                path(&spool.join("hash.3")),
+                path(&spool.join("hash.3").join("job.1233")),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_actionable() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let tdir = tempdir().unwrap();
+        std::fs::set_permissions(tdir.path(), std::fs::Permissions::from_mode(0o750)).unwrap();
+        let e = actionable(tdir.path(), Error::from(ErrorKind::PermissionDenied));
+        assert_eq!(e.kind(), ErrorKind::PermissionDenied);
+        let message = e.to_string();
+        let uid = unsafe { libc::geteuid() };
+        assert!(message.contains(&format!("(uid {uid}, gid ")));
+        assert!(message.contains(&format!(
+            "cannot read {:?}: it is owned by uid {uid}",
+            tdir.path()
+        )));
+        assert!(message.contains("with mode 750"));
+        assert!(message.contains("setfacl -R -m u:"));
+
+        let e = actionable(
+            &tdir.path().join("gone/deeper"),
+            Error::from(ErrorKind::PermissionDenied),
+        );
+        assert!(e
+            .to_string()
+            .contains("one of its parent directories cannot be searched"));
+    }
+}
@@ -0,0 +1,292 @@
+/*
+Copyright 2019-2024 Andy Georges <itkovian+sarchive@gmail.com>
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in
+all copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+use std::collections::BTreeMap;
+use std::ffi::OsString;
+use std::fs::read_to_string;
+use std::path::{Path, PathBuf};
+
+/// File holding the profiles, unless --config is given
+pub const DEFAULT_CONFIG: &str = "/etc/sarchive/sarchive.conf";
+
+/// Splits the line into words as a shell would, keeping what is between
+/// single or double quotes together. A backslash escapes the next character,
+/// except between single quotes.
+pub fn split_words(line: &str) -> Result<Vec<String>, String> {
+    let mut words = Vec::new();
+    let mut word: Option<String> = None;
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            c if c.is_whitespace() => words.extend(word.take()),
+            '\'' => {
+                let w = word.get_or_insert_with(String::new);
+                loop {
+                    match chars.next() {
+                        Some('\'') => break,
+                        Some(c) => w.push(c),
+                        None => return Err("unterminated single quote".to_owned()),
+                    }
+                }
+            }
+            '"' => {
+                let w = word.get_or_insert_with(String::new);
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => match chars.next() {
+                            Some(c @ ('"' | '\\')) => w.push(c),
+                            Some(c) => {
+                                w.push('\\');
+                                w.push(c);
+                            }
+                            None => return Err("unterminated double quote".to_owned()),
+                        },
+                        Some(c) => w.push(c),
+                        None => return Err("unterminated double quote".to_owned()),
+                    }
+                }
+            }
+            '\\' => match chars.next() {
+                Some(c) => word.get_or_insert_with(String::new).push(c),
+                None => return Err("trailing backslash".to_owned()),
+            },
+            c => word.get_or_insert_with(String::new).push(c),
+        }
+    }
+    words.extend(word);
+    Ok(words)
+}
+
+/// Reads the profiles from the contents of a configuration file. A profile
+/// starts with its name between square brackets, followed by the command line
+/// arguments it stands for, on as many lines as convenient. Empty lines and
+/// lines starting with # are ignored.
+pub fn parse_profiles(contents: &str) -> Result<BTreeMap<String, Vec<String>>, String> {
+    let mut profiles: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    let mut current: Option<String> = None;
+    for (number, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if let Some(name) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+            let name = name.trim();
+            if name.is_empty() || profiles.contains_key(name) {
+                return Err(format!(
+                    "line {}: empty or duplicate profile name {:?}",
+                    number + 1,
+                    name
+                ));
+            }
+            profiles.insert(name.to_owned(), Vec::new());
+            current = Some(name.to_owned());
+            continue;
+        }
+        let Some(name) = &current else {
+            return Err(format!("line {}: arguments outside a profile", number + 1));
+        };
+        let words = split_words(line).map_err(|e| format!("line {}: {}", number + 1, e))?;
+        if words
+            .iter()
+            .any(|w| w == "--profile" || w.starts_with("--profile="))
+        {
+            return Err(format!(
+                "line {}: a profile cannot select another profile",
+                number + 1
+            ));
+        }
+        profiles.get_mut(name).unwrap().extend(words);
+    }
+    Ok(profiles)
+}
+
+/// Reads the profiles from the configuration file
+pub fn load(path: &Path) -> Result<BTreeMap<String, Vec<String>>, String> {
+    let contents = read_to_string(path).map_err(|e| format!("Cannot read {path:?}: {e}"))?;
+    parse_profiles(&contents).map_err(|e| format!("Invalid configuration {path:?}, {e}"))
+}
+
+/// Returns the value of the option, given as `--name value` or
+/// `--name=value`, and the position of the option
+fn option(args: &[OsString], name: &str) -> Option<(usize, OsString)> {
+    let prefix = format!("{name}=");
+    args.iter()
+        .take_while(|arg| *arg != "--")
+        .enumerate()
+        .find_map(|(i, arg)| {
+            let arg = arg.to_str()?;
+            if arg == name {
+                args.get(i + 1).map(|value| (i + 1, value.clone()))
+            } else {
+                arg.strip_prefix(&prefix).map(|value| (i, value.into()))
+            }
+        })
+}
+
+/// Expands --profile on the command line: the arguments of the profile, read
+/// from the --config file, are put right after it, so the options given
+/// before --profile come first and an archiver in the profile comes last.
+pub fn expand(args: Vec<OsString>) -> Result<Vec<OsString>, String> {
+    let Some((position, name)) = option(&args, "--profile") else {
+        return Ok(args);
+    };
+    let config = option(&args, "--config")
+        .map_or_else(|| PathBuf::from(DEFAULT_CONFIG), |(_, path)| path.into());
+    let profiles = load(&config)?;
+    let name = name.to_string_lossy();
+    let profile = profiles.get(name.as_ref()).ok_or_else(|| {
+        let known: Vec<&str> = profiles.keys().map(|k| k.as_str()).collect();
+        format!(
+            "No profile {:?} in {:?}, it has {}",
+            name,
+            config,
+            known.join(", ")
+        )
+    })?;
+    let mut expanded = args[..=position].to_vec();
+    expanded.extend(profile.iter().map(OsString::from));
+    expanded.extend_from_slice(&args[position + 1..]);
+    Ok(expanded)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use std::fs::write;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_split_words() {
+        assert_eq!(
+            split_words(
+                r#"--transform 'skip:user=^(root|adm)$' --label "site=Ghent \"HPC\"" a\ b"#
+            )
+            .unwrap(),
+            vec![
+                "--transform",
+                "skip:user=^(root|adm)$",
+                "--label",
+                "site=Ghent \"HPC\"",
+                "a b"
+            ]
+        );
+        assert_eq!(split_words("  '' x ").unwrap(), vec!["", "x"]);
+        assert!(split_words("'open").is_err());
+        assert!(split_words("\"open").is_err());
+    }
+
+    #[test]
+    fn test_parse_profiles() {
+        let contents = "# sarchive profiles\n[prod-kafka]\n--cluster huppel --spool /var/spool/slurm\n--transform redact:TOKEN\n\nkafka --brokers b1:9092\n[debug-file]\n--debug\n";
+        let profiles = parse_profiles(contents).unwrap();
+        assert_eq!(
+            profiles["prod-kafka"],
+            vec![
+                "--cluster",
+                "huppel",
+                "--spool",
+                "/var/spool/slurm",
+                "--transform",
+                "redact:TOKEN",
+                "kafka",
+                "--brokers",
+                "b1:9092"
+            ]
+        );
+        assert_eq!(profiles["debug-file"], vec!["--debug"]);
+
+        assert!(parse_profiles("--debug\n").is_err());
+        assert!(parse_profiles("[a]\n[a]\n").is_err());
+        assert!(parse_profiles("[a]\n--profile b\n").is_err());
+    }
+
+    #[test]
+    fn test_expand() {
+        let tdir = tempdir().unwrap();
+        let config = tdir.path().join("sarchive.conf");
+        write(&config, "[debug-file]\n--debug\nfile /tmp/jobs none\n").unwrap();
+        let args = |args: &[&str]| -> Vec<OsString> { args.iter().map(OsString::from).collect() };
+
+        let plain = args(&["sarchive", "--cluster", "huppel", "stdout"]);
+        assert_eq!(expand(plain.clone()).unwrap(), plain);
+
+        let config = config.to_str().unwrap();
+        assert_eq!(
+            expand(args(&[
+                "sarchive",
+                "--config",
+                config,
+                "--cluster",
+                "huppel",
+                "--profile",
+                "debug-file"
+            ]))
+            .unwrap(),
+            args(&[
+                "sarchive",
+                "--config",
+                config,
+                "--cluster",
+                "huppel",
+                "--profile",
+                "debug-file",
+                "--debug",
+                "file",
+                "/tmp/jobs",
+                "none"
+            ])
+        );
+        let profile = format!("--config={config}");
+        assert_eq!(
+            expand(args(&["sarchive", &profile, "--profile=debug-file"])).unwrap(),
+            args(&[
+                "sarchive",
+                &profile,
+                "--profile=debug-file",
+                "--debug",
+                "file",
+                "/tmp/jobs",
+                "none"
+            ])
+        );
+        // the archiver arguments of the profile are those of the file archiver
+        #[derive(clap::Parser)]
+        enum Archiver {
+            File(crate::archive::file::FileArgs),
+        }
+        let expanded = expand(args(&["sarchive", &profile, "--profile=debug-file"])).unwrap();
+        let file = expanded.iter().position(|arg| arg == "file").unwrap();
+        let archiver = std::iter::once(OsString::from("sarchive")).chain(expanded[file..].to_vec());
+        assert!(<Archiver as clap::Parser>::try_parse_from(archiver).is_ok());
+        assert!(expand(args(&["sarchive", "--config", config, "--profile", "prod"])).is_err());
+        assert!(expand(args(&[
+            "sarchive",
+            "--config",
+            "/nonexistent",
+            "--profile",
+            "prod"
+        ]))
+        .is_err());
+    }
+}
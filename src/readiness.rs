@@ -0,0 +1,220 @@
+/*
+Copyright 2019-2024 Andy Georges <itkovian+sarchive@gmail.com>
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in
+all copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+use crossbeam_channel::{Receiver, RecvTimeoutError};
+use log::{info, warn};
+use std::env::var;
+use std::io::Error;
+use std::os::unix::net::UnixDatagram;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+use crate::archive::Archive;
+use crate::stats::Stats;
+
+/// How long to wait between checks whether sarchive is ready
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How long to wait before checking a backend that failed its check again
+const CHECK_RETRY_INTERVAL: Duration = Duration::from_secs(10);
+
+/// How often to log what sarchive is still waiting for
+const WAITING_REPORT_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Returns the watch locations that have no active watcher yet
+pub fn pending(stats: &Stats, locations: &[PathBuf]) -> Vec<PathBuf> {
+    let watched = stats.locations();
+    locations
+        .iter()
+        .filter(|location| !watched.get(*location).is_some_and(|l| l.watching))
+        .cloned()
+        .collect()
+}
+
+/// The check_backend function runs the connectivity check of the archiver
+/// until it passes, recording that in the stats. Meanwhile, it checks if there
+/// is an incoming notification that it should stop. Upon receipt, it returns
+/// false.
+pub fn check_backend(
+    archiver: &dyn Archive,
+    cluster: &str,
+    stats: &Stats,
+    sigchannel: &Receiver<bool>,
+) -> bool {
+    loop {
+        match archiver.check(cluster) {
+            Ok(()) => {
+                info!("Archiver {} is ready", archiver.name());
+                stats.set_backend_checked();
+                return true;
+            }
+            Err(e) => warn!(
+                "Archiver {} is not ready, checking again in {}s: {}",
+                archiver.name(),
+                CHECK_RETRY_INTERVAL.as_secs(),
+                e
+            ),
+        }
+        match sigchannel.recv_timeout(CHECK_RETRY_INTERVAL) {
+            Ok(true) | Err(RecvTimeoutError::Disconnected) => return false,
+            _ => (),
+        }
+    }
+}
+
+/// Sends the state (e.g., `READY=1`) to the systemd notification socket at
+/// the given address, which is abstract when it starts with `@`
+fn notify(address: &str, state: &str) -> Result<(), Error> {
+    let socket = UnixDatagram::unbound()?;
+    #[cfg(target_os = "linux")]
+    if let Some(name) = address.strip_prefix('@') {
+        use std::os::linux::net::SocketAddrExt;
+        use std::os::unix::net::SocketAddr;
+        let address = SocketAddr::from_abstract_name(name)?;
+        return socket.send_to_addr(state.as_bytes(), &address).map(|_| ());
+    }
+    socket.send_to(state.as_bytes(), address).map(|_| ())
+}
+
+/// Tells systemd the state of the service, if it was started by systemd with
+/// `Type=notify`. Returns whether systemd was told.
+pub fn notify_systemd(state: &str) -> Result<bool, Error> {
+    match var("NOTIFY_SOCKET") {
+        Ok(address) if !address.is_empty() => notify(&address, state).map(|_| true),
+        _ => Ok(false),
+    }
+}
+
+/// The await_ready function waits until every given watch location has an
+/// active watcher and the archiver passed its connectivity check. It then
+/// marks sarchive as ready in the stats, as reported on the control socket
+/// for as long as these locations stay watched, and tells systemd, if it
+/// started sarchive.
+/// At the same time, it checks if there is an incoming notification that it
+/// should stop. Upon receipt, it returns.
+pub fn await_ready(stats: &Stats, locations: &[PathBuf], sigchannel: &Receiver<bool>) {
+    let mut reported = Instant::now();
+    loop {
+        let waiting = pending(stats, locations);
+        if waiting.is_empty() && stats.backend_checked() {
+            break;
+        }
+        if reported.elapsed() >= WAITING_REPORT_INTERVAL {
+            info!(
+                "Not ready yet, waiting for the watchers of {:?}{}",
+                waiting,
+                if stats.backend_checked() {
+                    ""
+                } else {
+                    " and the archiver check"
+                }
+            );
+            reported = Instant::now();
+        }
+        match sigchannel.recv_timeout(POLL_INTERVAL) {
+            Ok(true) | Err(RecvTimeoutError::Disconnected) => return,
+            _ => (),
+        }
+    }
+
+    stats.set_ready(locations);
+    info!("Ready, watching {} locations", locations.len());
+    let state = format!("READY=1\nSTATUS=Watching {} locations", locations.len());
+    match notify_systemd(&state) {
+        Ok(true) => info!("Told systemd sarchive is ready"),
+        Ok(false) => (),
+        Err(e) => warn!("Cannot tell systemd sarchive is ready: {}", e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::testing::FlakyArchiver;
+    use crossbeam_channel::unbounded;
+    use crossbeam_utils::thread::scope;
+    use std::path::Path;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_await_ready() {
+        let stats = Stats::new();
+        let locations = [
+            PathBuf::from("/spool/hash.0"),
+            PathBuf::from("/spool/hash.1"),
+        ];
+        let (sig_tx, sig_rx) = unbounded();
+
+        stats.watching(Path::new("/spool/hash.0"), true);
+        assert_eq!(
+            pending(&stats, &locations),
+            [PathBuf::from("/spool/hash.1")]
+        );
+        stats.watching(Path::new("/spool/hash.1"), true);
+        assert!(pending(&stats, &locations).is_empty());
+
+        // the backend check is still missing
+        sig_tx.send(true).unwrap();
+        await_ready(&stats, &locations, &sig_rx);
+        assert!(!stats.is_ready());
+
+        stats.set_backend_checked();
+        await_ready(&stats, &locations, &sig_rx);
+        assert!(stats.is_ready());
+
+        // a watcher that stops makes sarchive no longer ready
+        stats.watching(Path::new("/spool/hash.1"), false);
+        assert!(!stats.is_ready());
+        stats.watching(Path::new("/spool/hash.1"), true);
+        assert!(stats.is_ready());
+    }
+
+    #[test]
+    fn test_check_backend() {
+        let stats = Stats::new();
+        let (sig_tx, sig_rx) = unbounded();
+
+        // stopped while waiting to check again
+        let archiver = FlakyArchiver::down(1);
+        sig_tx.send(true).unwrap();
+        assert!(!check_backend(&archiver, "mycluster", &stats, &sig_rx));
+        assert!(!stats.backend_checked());
+
+        assert!(check_backend(&archiver, "mycluster", &stats, &sig_rx));
+        assert!(stats.backend_checked());
+    }
+
+    #[test]
+    fn test_notify() {
+        let tdir = tempdir().unwrap();
+        let path = tdir.path().join("notify");
+        let socket = UnixDatagram::bind(&path).unwrap();
+
+        scope(|s| {
+            s.spawn(|_| notify(path.to_str().unwrap(), "READY=1").unwrap());
+            let mut buf = [0; 64];
+            let n = socket.recv(&mut buf).unwrap();
+            assert_eq!(&buf[..n], b"READY=1");
+        })
+        .unwrap();
+    }
+}
@@ -0,0 +1,337 @@
+/*
+Copyright 2019-2024 Andy Georges <itkovian+sarchive@gmail.com>
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in
+all copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+use log::debug;
+use std::collections::HashMap;
+use std::fs::read_dir;
+use std::io::Error;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering::SeqCst;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::scheduler::job::JobInfo;
+use crate::scheduler::Scheduler;
+
+/// How long an entry may take to be queued after it appeared before it
+/// counts as missed
+const GRACE: Duration = Duration::from_secs(10);
+
+/// How far back a scan looks at least, and so how long queued job IDs are kept
+const LOOKBACK: Duration = Duration::from_secs(600);
+
+/// Compares the job entries in the watch locations with the job entries
+/// queued from their events, to find the entries whose event got lost, e.g.,
+/// when the inotify queue overflows during a submission storm.
+///
+/// Each scan looks at the entries that appeared in a location since the
+/// previous scan (or since it was first watched), up to a short grace period
+/// ago, so every entry is looked at once.
+///
+/// It also follows the job directories that were queued until they are
+/// processed, so the entry of a job whose directory is deleted again in the
+/// meantime (e.g., because it was cancelled right away) can be dropped.
+pub struct Reconciler {
+    /// Time between scans, if scanning periodically
+    interval: Option<Duration>,
+    /// Whether to queue the entries whose event was missed
+    pub enqueue: bool,
+    /// Raised to scan at the next opportunity
+    triggered: AtomicBool,
+    /// When a scan was last triggered, if the entries that were too recent
+    /// for that scan still need to be looked at
+    follow_up: Mutex<Option<Instant>>,
+    /// The recently queued job IDs, with the time they were queued
+    queued: Mutex<HashMap<String, Instant>>,
+    /// Per location, up to when entries were looked at
+    scanned: Mutex<HashMap<PathBuf, SystemTime>>,
+    last_scan: Mutex<Instant>,
+    /// The job directories queued and not yet processed, with whether they
+    /// were deleted since
+    pending: Mutex<HashMap<PathBuf, bool>>,
+}
+
+impl Default for Reconciler {
+    fn default() -> Self {
+        Self::new(None, false)
+    }
+}
+
+impl Reconciler {
+    pub fn new(interval: Option<Duration>, enqueue: bool) -> Self {
+        Reconciler {
+            interval,
+            enqueue,
+            triggered: AtomicBool::new(false),
+            follow_up: Mutex::new(None),
+            queued: Mutex::new(HashMap::new()),
+            scanned: Mutex::new(HashMap::new()),
+            last_scan: Mutex::new(Instant::now()),
+            pending: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// How far back scans look, and queued job IDs are kept
+    fn lookback(&self) -> Duration {
+        self.interval.map_or(LOOKBACK, |i| LOOKBACK.max(2 * i)) + GRACE
+    }
+
+    /// Records a job entry queued from an event
+    pub fn queued(&self, jobid: &str) {
+        let now = Instant::now();
+        let lookback = self.lookback();
+        let mut queued = self.queued.lock().unwrap();
+        queued.retain(|_, t| now.duration_since(*t) < lookback);
+        queued.insert(jobid.to_owned(), now);
+    }
+
+    /// Records a job directory queued from an event, until it is processed
+    pub fn pending(&self, path: &Path) {
+        self.pending
+            .lock()
+            .unwrap()
+            .insert(path.to_path_buf(), false);
+    }
+
+    /// Records that the job directory was deleted, returning whether it was
+    /// still waiting to be processed
+    pub fn deleted(&self, path: &Path) -> bool {
+        match self.pending.lock().unwrap().get_mut(path) {
+            Some(deleted) => {
+                *deleted = true;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Whether the job directory waiting to be processed was deleted
+    pub fn is_deleted(&self, path: &Path) -> bool {
+        self.pending
+            .lock()
+            .unwrap()
+            .get(path)
+            .copied()
+            .unwrap_or(false)
+    }
+
+    /// Forgets about the job directory once it is processed, returning
+    /// whether it was deleted before
+    pub fn processed(&self, path: &Path) -> bool {
+        self.pending.lock().unwrap().remove(path).unwrap_or(false)
+    }
+
+    /// Records that the location is watched from now on, unless it was before,
+    /// so only the entries that appear afterwards are looked at
+    pub fn watching(&self, location: &Path) {
+        self.scanned
+            .lock()
+            .unwrap()
+            .entry(location.to_path_buf())
+            .or_insert_with(SystemTime::now);
+    }
+
+    /// Forgets about a location that is no longer watched
+    pub fn unwatched(&self, location: &Path) {
+        self.scanned.lock().unwrap().remove(location);
+    }
+
+    /// Asks for a scan as soon as possible, e.g., because events were lost,
+    /// and another once the grace period passed for the entries that are too
+    /// recent for the first one
+    pub fn trigger(&self) {
+        *self.follow_up.lock().unwrap() = Some(Instant::now());
+        self.triggered.store(true, SeqCst);
+    }
+
+    /// Whether it is time for a scan, periodic or triggered
+    pub fn due(&self) -> bool {
+        let mut last_scan = self.last_scan.lock().unwrap();
+        let periodic = self.interval.is_some_and(|i| last_scan.elapsed() >= i);
+        let follow_up = {
+            let mut follow_up = self.follow_up.lock().unwrap();
+            let due = follow_up.is_some_and(|t| t.elapsed() >= GRACE);
+            if due {
+                *follow_up = None;
+            }
+            due
+        };
+        if self.triggered.swap(false, SeqCst) || periodic || follow_up {
+            *last_scan = Instant::now();
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Returns the job entries that appeared in the location since the last
+    /// scan without being queued
+    #[allow(clippy::borrowed_box)]
+    pub fn scan(
+        &self,
+        scheduler: &Box<dyn Scheduler>,
+        location: &Path,
+    ) -> Result<Vec<Box<dyn JobInfo>>, Error> {
+        self.scan_at(scheduler, location, SystemTime::now())
+    }
+
+    #[allow(clippy::borrowed_box)]
+    fn scan_at(
+        &self,
+        scheduler: &Box<dyn Scheduler>,
+        location: &Path,
+        now: SystemTime,
+    ) -> Result<Vec<Box<dyn JobInfo>>, Error> {
+        let until = now - GRACE;
+        let since = {
+            let scanned = self.scanned.lock().unwrap();
+            let previous = scanned.get(location).copied().unwrap_or(until);
+            previous.max(now - self.lookback())
+        };
+        if since >= until {
+            return Ok(Vec::new());
+        }
+
+        let mut missed = Vec::new();
+        for entry in read_dir(location)? {
+            let entry = entry?;
+            // The entry may be gone by now
+            let Ok(metadata) = entry.metadata() else {
+                continue;
+            };
+            // Renaming an entry into place changes its ctime, not its mtime
+            let changed =
+                UNIX_EPOCH + Duration::new(metadata.ctime() as u64, metadata.ctime_nsec() as u32);
+            if changed <= since || changed > until {
+                continue;
+            }
+            if let Some(job_entry) = scheduler.create_job_info(&entry.path()) {
+                if !self.queued.lock().unwrap().contains_key(&job_entry.jobid()) {
+                    missed.push(job_entry);
+                }
+            }
+        }
+        self.scanned
+            .lock()
+            .unwrap()
+            .insert(location.to_path_buf(), until);
+        debug!(
+            "Reconciled {:?}, {} entries without an event",
+            location,
+            missed.len()
+        );
+        Ok(missed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::scheduler::slurm::Slurm;
+    use std::fs::create_dir;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_due() {
+        let reconciler = Reconciler::default();
+        assert!(!reconciler.due());
+        reconciler.trigger();
+        assert!(reconciler.due());
+        assert!(!reconciler.due());
+
+        // the follow up scan comes after the grace period
+        *reconciler.follow_up.lock().unwrap() = Some(Instant::now() - GRACE);
+        assert!(reconciler.due());
+        assert!(!reconciler.due());
+
+        let reconciler = Reconciler::new(Some(Duration::ZERO), false);
+        assert!(reconciler.due());
+    }
+
+    #[test]
+    fn test_scan() {
+        let tdir = tempdir().unwrap();
+        let location = tdir.path().join("hash.0");
+        create_dir(&location).unwrap();
+        let scheduler: Box<dyn Scheduler> = Box::new(Slurm::new(tdir.path(), "mycluster"));
+
+        let reconciler = Reconciler::new(Some(Duration::from_secs(60)), false);
+        let start = SystemTime::now() - Duration::from_secs(1);
+        reconciler
+            .scanned
+            .lock()
+            .unwrap()
+            .insert(location.clone(), start);
+        for name in ["job.10", "job.20", "tmp.30"] {
+            create_dir(location.join(name)).unwrap();
+        }
+        reconciler.queued("10");
+
+        // the entries are too recent to have been missed
+        assert!(reconciler.scan(&scheduler, &location).unwrap().is_empty());
+
+        // once the grace period passed, the job entry without an event is missed
+        let later = SystemTime::now() + 2 * GRACE;
+        let missed = reconciler.scan_at(&scheduler, &location, later).unwrap();
+        let missed: Vec<String> = missed.iter().map(|j| j.jobid()).collect();
+        assert_eq!(missed, vec!["20"]);
+
+        // and only reported once
+        let missed = reconciler
+            .scan_at(&scheduler, &location, later + Duration::from_secs(1))
+            .unwrap();
+        assert!(missed.is_empty());
+    }
+
+    #[test]
+    fn test_pending() {
+        let reconciler = Reconciler::default();
+        let path = Path::new("/spool/hash.0/job.10");
+        assert!(!reconciler.deleted(path));
+
+        reconciler.pending(path);
+        assert!(!reconciler.is_deleted(path));
+        assert!(reconciler.deleted(path));
+        assert!(reconciler.is_deleted(path));
+        assert!(reconciler.processed(path));
+
+        // once processed, the directory is forgotten
+        assert!(!reconciler.deleted(path));
+        assert!(!reconciler.processed(path));
+    }
+
+    #[test]
+    fn test_scan_unwatched() {
+        let tdir = tempdir().unwrap();
+        let scheduler: Box<dyn Scheduler> = Box::new(Slurm::new(tdir.path(), "mycluster"));
+        create_dir(tdir.path().join("job.10")).unwrap();
+
+        // the entries that were there before watching are not missed
+        let reconciler = Reconciler::default();
+        reconciler.watching(tdir.path());
+        let later = SystemTime::now() + 2 * GRACE;
+        let missed = reconciler.scan_at(&scheduler, tdir.path(), later).unwrap();
+        assert!(missed.is_empty());
+    }
+}
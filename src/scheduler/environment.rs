@@ -0,0 +1,508 @@
+/*
+Copyright 2019-2024 Andy Georges <itkovian+sarchive@gmail.com>
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in
+all copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+use log::{debug, info, warn};
+use regex::Regex;
+use std::collections::HashMap;
+use std::fs;
+use std::io::Error;
+use std::path::Path;
+
+use super::job::JobRecord;
+use super::lsf::edit_exports;
+use super::slurm;
+use crate::archive::dedup::content_hash;
+use crate::archive::transform::{Step, Transform};
+
+/// Key under which the hash of the baseline environment is stored in the
+/// extra info, when only the difference with the baseline is kept
+pub const BASELINE_KEY: &str = "sarchive_env_baseline";
+
+/// Key under which the baseline variables that are absent from the job's
+/// environment are listed in the extra info
+pub const BASELINE_REMOVED_KEY: &str = "sarchive_env_removed";
+
+/// An environment most jobs share, e.g., that of a login shell
+#[derive(Debug, PartialEq, Eq)]
+pub struct EnvBaseline {
+    vars: HashMap<String, String>,
+    /// Hash of the baseline file, so consumers can tell which baseline was used
+    pub hash: String,
+}
+
+impl EnvBaseline {
+    /// Reads the baseline from a file holding `NAME=value` entries, one per
+    /// line as printed by `env`, or separated by NUL bytes as by `env -0`
+    pub fn load(path: &Path) -> Result<EnvBaseline, Error> {
+        let contents = fs::read(path)?;
+        let baseline = EnvBaseline::parse(&contents);
+        info!(
+            "Loaded baseline environment {:?} with {} variables ({})",
+            path,
+            baseline.vars.len(),
+            baseline.hash
+        );
+        Ok(baseline)
+    }
+
+    fn parse(contents: &[u8]) -> EnvBaseline {
+        let separator = if contents.contains(&b'\0') {
+            b'\0'
+        } else {
+            b'\n'
+        };
+        let vars = contents
+            .split(|b| *b == separator)
+            .map(String::from_utf8_lossy)
+            .filter_map(|entry| {
+                entry
+                    .split_once('=')
+                    .map(|(k, v)| (k.trim().to_owned(), v.to_owned()))
+            })
+            .filter(|(k, _)| !k.is_empty())
+            .collect();
+        EnvBaseline {
+            vars,
+            hash: content_hash(contents),
+        }
+    }
+
+    /// Returns the variables of the environment that are not in the baseline
+    /// or have a different value there, along with the baseline hash under
+    /// `BASELINE_KEY`. Baseline variables missing from the environment are
+    /// listed under `BASELINE_REMOVED_KEY`, unless the filter drops them anyway.
+    pub fn diff(
+        &self,
+        env: HashMap<String, String>,
+        filter_regex: &Option<Regex>,
+    ) -> HashMap<String, String> {
+        let mut removed: Vec<&str> = self
+            .vars
+            .keys()
+            .filter(|k| !env.contains_key(*k))
+            .filter(|k| !filter_regex.as_ref().is_some_and(|r| r.is_match(k)))
+            .map(|k| k.as_str())
+            .collect();
+        let mut diff: HashMap<String, String> = env
+            .into_iter()
+            .filter(|(k, v)| self.vars.get(k) != Some(v))
+            .collect();
+        diff.insert(BASELINE_KEY.to_owned(), self.hash.clone());
+        if !removed.is_empty() {
+            removed.sort();
+            diff.insert(BASELINE_REMOVED_KEY.to_owned(), removed.join(","));
+        }
+        diff
+    }
+}
+
+/// Key under which the number of environment values that were left out is
+/// stored in the extra info
+pub const SKIPPED_KEY: &str = "sarchive_env_skipped";
+
+/// Key under which the number of environment values that were truncated is
+/// stored in the extra info
+pub const TRUNCATED_KEY: &str = "sarchive_env_truncated";
+
+/// Values shorter than this are never considered to be secrets
+const SECRET_MIN_LENGTH: usize = 16;
+
+/// How the job environments are processed before they are archived
+#[derive(Debug, Default)]
+pub struct EnvPolicy {
+    /// Keep only the difference with this baseline
+    pub baseline: Option<EnvBaseline>,
+    /// Values larger than this (in bytes) are left out or truncated
+    pub max_value_size: Option<usize>,
+    /// Truncate values that are too large, rather than leaving them out
+    pub truncate: bool,
+    /// Values with at least this entropy (in bits per character) are
+    /// considered secrets and left out
+    pub entropy_threshold: Option<f64>,
+}
+
+/// What the policy does with a value of the environment
+#[derive(Debug, PartialEq, Eq)]
+enum Verdict {
+    Keep,
+    Skip,
+    /// Keep the value up to the given length
+    Truncate(usize),
+}
+
+impl EnvPolicy {
+    /// Whether the policy leaves the environments as they are
+    pub fn is_empty(&self) -> bool {
+        self.baseline.is_none() && self.max_value_size.is_none() && self.entropy_threshold.is_none()
+    }
+
+    fn verdict(&self, key: &str, value: &str) -> Verdict {
+        // Leave the information added by sarchive alone
+        if key.starts_with("sarchive_") {
+            return Verdict::Keep;
+        }
+        if self
+            .entropy_threshold
+            .is_some_and(|threshold| is_secret(value, threshold))
+        {
+            debug!("Leaving out {}, which looks like a secret", key);
+            return Verdict::Skip;
+        }
+        match self.max_value_size {
+            Some(max) if value.len() > max && self.truncate => {
+                Verdict::Truncate(floor_char_boundary(value, max))
+            }
+            Some(max) if value.len() > max => {
+                debug!("Leaving out {}, which is {} bytes", key, value.len());
+                Verdict::Skip
+            }
+            _ => Verdict::Keep,
+        }
+    }
+
+    /// Applies the policy to the (filtered) environment of a job. Values that
+    /// are left out or truncated are counted under `SKIPPED_KEY` and
+    /// `TRUNCATED_KEY`.
+    pub fn apply(
+        &self,
+        env: HashMap<String, String>,
+        filter_regex: &Option<Regex>,
+    ) -> HashMap<String, String> {
+        let env = match &self.baseline {
+            Some(baseline) => baseline.diff(env, filter_regex),
+            None => env,
+        };
+        if self.max_value_size.is_none() && self.entropy_threshold.is_none() {
+            return env;
+        }
+
+        let (mut skipped, mut truncated) = (0, 0);
+        let mut env: HashMap<String, String> = env
+            .into_iter()
+            .filter_map(|(key, mut value)| match self.verdict(&key, &value) {
+                Verdict::Keep => Some((key, value)),
+                Verdict::Skip => {
+                    skipped += 1;
+                    None
+                }
+                Verdict::Truncate(length) => {
+                    value.truncate(length);
+                    truncated += 1;
+                    Some((key, value))
+                }
+            })
+            .collect();
+        if skipped > 0 {
+            env.insert(SKIPPED_KEY.to_owned(), skipped.to_string());
+        }
+        if truncated > 0 {
+            env.insert(TRUNCATED_KEY.to_owned(), truncated.to_string());
+        }
+        env
+    }
+}
+
+/// Returns the Shannon entropy of the value, in bits per character
+fn entropy(value: &str) -> f64 {
+    let mut counts: HashMap<char, usize> = HashMap::new();
+    for c in value.chars() {
+        *counts.entry(c).or_default() += 1;
+    }
+    let length = value.chars().count() as f64;
+    counts
+        .values()
+        .map(|&n| {
+            let p = n as f64 / length;
+            -p * p.log2()
+        })
+        .sum()
+}
+
+/// Tells if the value looks like a secret, such as a token or a key: a long
+/// word without whitespace that has at least the given entropy
+fn is_secret(value: &str, threshold: f64) -> bool {
+    value.len() >= SECRET_MIN_LENGTH
+        && !value.chars().any(char::is_whitespace)
+        && entropy(value) >= threshold
+}
+
+/// Returns the largest index not exceeding `index` that lies on a character
+/// boundary of the value
+fn floor_char_boundary(value: &str, index: usize) -> usize {
+    (0..=index.min(value.len()))
+        .rev()
+        .find(|&i| value.is_char_boundary(i))
+        .unwrap_or(0)
+}
+
+/// Passes each variable in the environment files of the job, i.e., the Slurm
+/// environment file and the exports in the LSF job file, through the edit,
+/// which gets the name and value and returns the value to keep, or None to
+/// leave the variable out. Files streamed from the spool are archived as they
+/// are.
+pub fn edit_environment(job: &mut JobRecord, mut edit: impl FnMut(&str, &str) -> Option<String>) {
+    let environment = format!("job.{}_environment", job.jobid);
+    let jobfile = format!("job.{}_jobfile", job.jobid);
+    for (name, contents) in job.files.iter_mut() {
+        if *name == environment {
+            *contents = slurm::edit_environment(contents, &mut edit);
+        } else if *name == jobfile {
+            *contents = edit_exports(contents, &mut edit);
+        }
+    }
+    if job.streamed_files.contains_key(&environment) || job.streamed_files.contains_key(&jobfile) {
+        warn!(
+            "Job {} has its environment in a file streamed from the spool, which is archived as it is",
+            job.jobid
+        );
+    }
+}
+
+/// The stage that leaves out the variables matching the filter from the job
+/// environments and applies the environment policy, to the extra info as well
+/// as to the environment files. Jobs whose extra info does not hold their
+/// environment (e.g., for Torque) are passed on as they are.
+pub struct EnvironmentStage {
+    filter_regex: Option<Regex>,
+    policy: EnvPolicy,
+}
+
+impl EnvironmentStage {
+    pub fn new(filter_regex: Option<Regex>, policy: EnvPolicy) -> Self {
+        EnvironmentStage {
+            filter_regex,
+            policy,
+        }
+    }
+
+    fn filtered(&self, key: &str) -> bool {
+        self.filter_regex.as_ref().is_some_and(|r| r.is_match(key))
+    }
+}
+
+impl Transform for EnvironmentStage {
+    fn apply(&self, mut job: JobRecord) -> Result<Step, Error> {
+        if !job.has_environment {
+            return Ok(Step::Keep(job));
+        }
+        if let Some(extra) = job.extra.take() {
+            let env = extra
+                .into_iter()
+                .filter(|(key, _)| key.starts_with("sarchive_") || !self.filtered(key))
+                .collect();
+            job.extra = Some(self.policy.apply(env, &self.filter_regex));
+        }
+        edit_environment(&mut job, |key, value| {
+            if self.filtered(key) {
+                return None;
+            }
+            match self.policy.verdict(key, value) {
+                Verdict::Keep => Some(value.to_owned()),
+                Verdict::Skip => None,
+                Verdict::Truncate(length) => Some(value[..length].to_owned()),
+            }
+        });
+        Ok(Step::Keep(job))
+    }
+
+    fn name(&self) -> String {
+        "environment".to_owned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::scheduler::slurm::EXPORT_MODE_KEY;
+    use crate::testing::slurm_job;
+    use std::fs::write;
+    use tempfile::tempdir;
+
+    /// Returns the record of a Slurm job with the given environment file
+    fn job(tdir: &Path, env: &[u8]) -> JobRecord {
+        slurm_job(tdir, "1", "#!/bin/bash\n", env)
+    }
+
+    fn apply(stage: &EnvironmentStage, job: JobRecord) -> JobRecord {
+        match stage.apply(job) {
+            Ok(Step::Keep(job)) => job,
+            _ => panic!("the environment stage keeps the job"),
+        }
+    }
+
+    #[test]
+    fn test_environment_stage() {
+        let tdir = tempdir().unwrap();
+        let env = b"\x04\0\0\0VAR1=value1\0VAR2=value2\0TOKEN=ghp_8fK2xQ9vLm3ZpR7tYw1Nc5Hs\0VAR3=value3\0";
+        let policy = EnvPolicy {
+            entropy_threshold: Some(4.0),
+            ..Default::default()
+        };
+        let stage = EnvironmentStage::new(Regex::new("VAR[12]").ok(), policy);
+
+        // neither the extra info nor the environment file hold what is left out
+        let job = apply(&stage, job(tdir.path(), env));
+        let extra = job.extra.as_ref().unwrap();
+        assert_eq!(extra.get("VAR1"), None);
+        assert_eq!(extra.get("TOKEN"), None);
+        assert_eq!(extra["VAR3"], "value3");
+        assert_eq!(extra[SKIPPED_KEY], "1");
+        assert!(extra.contains_key(EXPORT_MODE_KEY));
+        assert_eq!(job.files[1].0, "job.1_environment");
+        assert_eq!(job.files[1].1, b"\x01\0\0\0VAR3=value3\0");
+        assert_eq!(job.user, None);
+
+        // a job whose extra info does not hold its environment is left alone
+        let mut processed = job.clone();
+        processed.has_environment = false;
+        processed
+            .extra
+            .as_mut()
+            .unwrap()
+            .insert("VAR2".to_owned(), "x".repeat(32));
+        let kept = apply(&stage, processed.clone());
+        assert_eq!(kept.extra, processed.extra);
+    }
+
+    #[test]
+    fn test_environment_stage_baseline() {
+        let tdir = tempdir().unwrap();
+        let baseline = tdir.path().join("baseline");
+        write(
+            &baseline,
+            "VAR1=value1\nVAR2=value2\nVAR4=value4\nSECRET=x\n",
+        )
+        .unwrap();
+        let env = b"\x04\0\0\0USER=alice\0VAR1=value1\0VAR2=changed\0VAR3=value3\0";
+        let policy = EnvPolicy {
+            baseline: Some(EnvBaseline::load(&baseline).unwrap()),
+            ..Default::default()
+        };
+        let stage = EnvironmentStage::new(Regex::new("^(SECRET|USER)$").ok(), policy);
+
+        // the user is found even though the stage leaves it out of the
+        // extra info, and the file keeps the variables the baseline has
+        let job = apply(&stage, job(tdir.path(), env));
+        let extra = job.extra.as_ref().unwrap();
+        assert_eq!(extra.get("VAR1"), None);
+        assert_eq!(extra.get("USER"), None);
+        assert_eq!(extra["VAR2"], "changed");
+        assert_eq!(extra["VAR3"], "value3");
+        assert_eq!(extra[BASELINE_REMOVED_KEY], "VAR4");
+        assert!(extra.contains_key(BASELINE_KEY));
+        assert_eq!(job.user.as_deref(), Some("alice"));
+        assert_eq!(
+            job.files[1].1,
+            b"\x03\0\0\0VAR1=value1\0VAR2=changed\0VAR3=value3\0"
+        );
+    }
+
+    #[test]
+    fn test_apply_max_value_size() {
+        let env = HashMap::from([
+            ("SHORT".to_owned(), "abc".to_owned()),
+            ("LONG".to_owned(), "caf\u{e9}caf\u{e9}".to_owned()),
+        ]);
+        let mut policy = EnvPolicy {
+            max_value_size: Some(4),
+            ..Default::default()
+        };
+
+        let skipped = policy.apply(env.clone(), &None);
+        assert_eq!(skipped.get("SHORT").unwrap(), "abc");
+        assert_eq!(skipped.get("LONG"), None);
+        assert_eq!(skipped.get(SKIPPED_KEY).unwrap(), "1");
+
+        policy.truncate = true;
+        let truncated = policy.apply(env, &None);
+        assert_eq!(truncated.get("LONG").unwrap(), "caf");
+        assert_eq!(truncated.get(TRUNCATED_KEY).unwrap(), "1");
+        assert_eq!(truncated.get(SKIPPED_KEY), None);
+    }
+
+    #[test]
+    fn test_apply_entropy_threshold() {
+        let env = HashMap::from([
+            (
+                "TOKEN".to_owned(),
+                "ghp_8fK2xQ9vLm3ZpR7tYw1Nc5Hs".to_owned(),
+            ),
+            ("HOME".to_owned(), "/home/user/projects/sarchive".to_owned()),
+            ("REPEATED".to_owned(), "aaaaaaaaaaaaaaaaaaaaaaaa".to_owned()),
+            (
+                "MESSAGE".to_owned(),
+                "the quick brown fox jumps over".to_owned(),
+            ),
+        ]);
+        let policy = EnvPolicy {
+            entropy_threshold: Some(4.0),
+            ..Default::default()
+        };
+
+        let env = policy.apply(env, &None);
+        assert_eq!(env.get("TOKEN"), None);
+        assert!(env.contains_key("HOME"));
+        assert!(env.contains_key("REPEATED"));
+        assert!(env.contains_key("MESSAGE"));
+        assert_eq!(env.get(SKIPPED_KEY).unwrap(), "1");
+    }
+
+    #[test]
+    fn test_entropy() {
+        assert_eq!(entropy("aaaa"), 0.0);
+        assert_eq!(entropy("abab"), 1.0);
+        assert_eq!(entropy("abcd"), 2.0);
+    }
+
+    #[test]
+    fn test_parse() {
+        let lines = EnvBaseline::parse(b"HOME=/home/user\nPS1=a=b\n\nnot a variable\n");
+        let nul = EnvBaseline::parse(b"HOME=/home/user\0PS1=a=b\0");
+        assert_eq!(lines.vars, nul.vars);
+        assert_eq!(lines.vars["PS1"], "a=b");
+        assert_eq!(lines.vars.len(), 2);
+        assert_ne!(lines.hash, nul.hash);
+    }
+
+    #[test]
+    fn test_diff() {
+        let baseline =
+            EnvBaseline::parse(b"HOME=/home/user\nSHELL=/bin/bash\nTERM=xterm\nSECRET=x\n");
+        let env = HashMap::from([
+            ("HOME".to_owned(), "/home/user".to_owned()),
+            ("SHELL".to_owned(), "/bin/zsh".to_owned()),
+            ("OMP_NUM_THREADS".to_owned(), "4".to_owned()),
+        ]);
+
+        let diff = baseline.diff(env, &Regex::new("^SECRET$").ok());
+        assert_eq!(
+            diff,
+            HashMap::from([
+                ("SHELL".to_owned(), "/bin/zsh".to_owned()),
+                ("OMP_NUM_THREADS".to_owned(), "4".to_owned()),
+                (BASELINE_KEY.to_owned(), baseline.hash.clone()),
+                (BASELINE_REMOVED_KEY.to_owned(), "TERM".to_owned()),
+            ])
+        );
+    }
+}
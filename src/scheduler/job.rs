@@ -20,10 +20,301 @@ OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
 SOFTWARE.
 */
 
+use chrono::{DateTime, Utc};
 use std::collections::HashMap;
 use std::io::Error;
+use std::path::PathBuf;
 use std::time::Instant;
 
+/// Environment variables that hold the name of a job, by scheduler
+pub const JOB_NAME_VARIABLES: [&str; 3] = ["SLURM_JOB_NAME", "PBS_JOBNAME", "LSB_JOBNAME"];
+
+/// Environment variables that hold the user of a job, by scheduler
+pub const USER_VARIABLES: [&str; 4] = ["SLURM_JOB_USER", "PBS_O_LOGNAME", "USER", "LOGNAME"];
+
+/// Environment variables that hold the uid of the user of a job
+pub const UID_VARIABLES: [&str; 3] = ["SLURM_JOB_UID", "PBS_O_UID", "UID"];
+
+/// Event recorded in the tombstone of a job that vanished before capture
+pub const CANCELLED_EVENT: &str = "cancelled_before_capture";
+
+/// Event recorded in the tombstone of a job whose user opted out of archival
+pub const OPTED_OUT_EVENT: &str = "opted_out";
+
+/// Event recorded in the tombstone of a job whose files were deleted from the
+/// spool
+pub const DELETED_EVENT: &str = "deleted";
+
+/// Environment variables that hold the partition (or queue) of a job
+pub const PARTITION_VARIABLES: [&str; 5] = [
+    "SLURM_JOB_PARTITION",
+    "SBATCH_PARTITION",
+    "PBS_O_QUEUE",
+    "PBS_QUEUE",
+    "LSB_QUEUE",
+];
+
+/// Directive options that set the name of a job, by directive prefix
+const JOB_NAME_OPTIONS: [(&str, &[&str]); 3] = [
+    ("#SBATCH", &["-J", "--job-name"]),
+    ("#PBS", &["-N"]),
+    ("#BSUB", &["-J"]),
+];
+
+/// Directive options that set the partition (or queue) of a job
+const PARTITION_OPTIONS: [(&str, &[&str]); 3] = [
+    ("#SBATCH", &["-p", "--partition"]),
+    ("#PBS", &["-q"]),
+    ("#BSUB", &["-q"]),
+];
+
+/// Environment variables that hold the GPUs requested for the whole job, as
+/// `[TYPE:]COUNT`
+const GPUS_VARIABLES: [&str; 2] = ["SLURM_GPUS", "SBATCH_GPUS"];
+
+/// Environment variables that hold the GPUs requested per node
+const GPUS_PER_NODE_VARIABLES: [&str; 2] = ["SLURM_GPUS_PER_NODE", "SBATCH_GPUS_PER_NODE"];
+
+/// Environment variables that hold the GPUs requested per task
+const GPUS_PER_TASK_VARIABLES: [&str; 2] = ["SLURM_GPUS_PER_TASK", "SBATCH_GPUS_PER_TASK"];
+
+/// Environment variables that hold the generic resources requested per node,
+/// e.g., `gpu:a100:2`
+const GRES_VARIABLES: [&str; 2] = ["SLURM_GRES", "SBATCH_GRES"];
+
+/// Directive options that request GPUs for the whole job
+const GPUS_OPTIONS: [(&str, &[&str]); 1] = [("#SBATCH", &["-G", "--gpus"])];
+
+/// Directive options that request GPUs per node
+const GPUS_PER_NODE_OPTIONS: [(&str, &[&str]); 1] = [("#SBATCH", &["--gpus-per-node"])];
+
+/// Directive options that request GPUs per task
+const GPUS_PER_TASK_OPTIONS: [(&str, &[&str]); 1] = [("#SBATCH", &["--gpus-per-task"])];
+
+/// Directive options that request generic resources per node
+const GRES_OPTIONS: [(&str, &[&str]); 1] = [("#SBATCH", &["--gres"])];
+
+/// Directive options that request resources for Torque or PBS, e.g.,
+/// `-l nodes=1:ppn=8:gpus=2` or `-l select=1:ngpus=2`
+const RESOURCE_OPTIONS: [(&str, &[&str]); 1] = [("#PBS", &["-l"])];
+
+/// Directive options that request GPUs for LSF, e.g., `-gpu "num=2"`
+const LSF_GPU_OPTIONS: [(&str, &[&str]); 1] = [("#BSUB", &["-gpu"])];
+
+/// GPUs a job requests, from the environment or the directives of its script
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Accelerators {
+    /// GPUs for the whole job
+    pub gpus: Option<u64>,
+    pub gpus_per_node: Option<u64>,
+    pub gpus_per_task: Option<u64>,
+    /// Type of the GPUs, when requested, e.g., `a100`
+    pub gpu_type: Option<String>,
+}
+
+impl Accelerators {
+    pub fn is_empty(&self) -> bool {
+        self == &Accelerators::default()
+    }
+}
+
+/// Returns the type and total count of a list of `[TYPE:]COUNT` GPU requests,
+/// e.g., `2`, `a100:2` or `a100:2,v100:1`
+fn typed_count(value: &str) -> Option<(Option<String>, u64)> {
+    let mut kind = None;
+    let mut total = 0;
+    for item in value.split(',') {
+        let (t, count) = match item.rsplit_once(':') {
+            Some((t, count)) => (Some(t.to_owned()), count),
+            None => (None, item),
+        };
+        total += count.trim().parse::<u64>().ok()?;
+        kind = kind.or(t);
+    }
+    Some((kind, total))
+}
+
+/// Returns the type and total count of the GPUs in a list of generic
+/// resources, e.g., `gpu:2`, `gpu:a100:2,shard:1` or `gpu`, which counts as
+/// one. Gives None when no GPUs are requested.
+fn gres_gpus(value: &str) -> Option<(Option<String>, u64)> {
+    let mut found = None;
+    for item in value.split(',') {
+        let item = item.trim().trim_start_matches("gres/");
+        let rest = match item.strip_prefix("gpu") {
+            Some("") => "1",
+            Some(rest) => match rest.strip_prefix(':') {
+                Some(rest) => rest,
+                None => continue,
+            },
+            None => continue,
+        };
+        let (kind, count) = typed_count(rest)
+            .or_else(|| (!rest.contains(':')).then(|| (Some(rest.to_owned()), 1)))?;
+        let (k, c) = found.get_or_insert((None, 0));
+        *c += count;
+        if k.is_none() {
+            *k = kind;
+        }
+    }
+    found
+}
+
+/// Returns the GPUs per node in Torque or PBS resource requests, given as
+/// `gpus=COUNT` or `ngpus=COUNT` among the `,` or `:` separated resources
+fn resource_gpus(value: &str) -> Option<u64> {
+    value.split([',', ':']).find_map(|resource| {
+        let (name, count) = resource.split_once('=')?;
+        ["gpus", "ngpus"]
+            .contains(&name)
+            .then(|| count.parse().ok())
+            .flatten()
+    })
+}
+
+/// Returns the type and count of the GPUs per host in an LSF GPU request,
+/// e.g., `"num=2:gmodel=TeslaV100"`. The default request, `-`, is for one.
+fn lsf_gpus(value: &str) -> Option<(Option<String>, u64)> {
+    let value = value.trim_matches(|c| c == '"' || c == '\'');
+    if value == "-" {
+        return Some((None, 1));
+    }
+    let mut kind = None;
+    let mut count = 1;
+    for option in value.split(':') {
+        match option.split_once('=') {
+            Some(("num", n)) => count = n.parse().ok()?,
+            Some(("gmodel", model)) => kind = Some(model.to_owned()),
+            _ => (),
+        }
+    }
+    Some((kind, count))
+}
+
+/// Returns the GPUs the job requests, each from the environment or else from
+/// the directives in the script
+pub fn accelerators(env: Option<&HashMap<String, String>>, script: &str) -> Accelerators {
+    let value = |names: &[&str], options: &[(&str, &[&str])]| {
+        env.and_then(|env| lookup(env, names))
+            .or_else(|| directive(script, options))
+    };
+    let mut types = Vec::new();
+    let mut count = |names: &[&str], options: &[(&str, &[&str])]| {
+        let (kind, count) = typed_count(&value(names, options)?)?;
+        types.extend(kind);
+        Some(count)
+    };
+    let gpus = count(&GPUS_VARIABLES, &GPUS_OPTIONS);
+    let gpus_per_task = count(&GPUS_PER_TASK_VARIABLES, &GPUS_PER_TASK_OPTIONS);
+    let gpus_per_node = count(&GPUS_PER_NODE_VARIABLES, &GPUS_PER_NODE_OPTIONS)
+        .or_else(|| {
+            let (kind, count) = gres_gpus(&value(&GRES_VARIABLES, &GRES_OPTIONS)?)?;
+            types.extend(kind);
+            Some(count)
+        })
+        .or_else(|| {
+            directive_values(script, &RESOURCE_OPTIONS)
+                .iter()
+                .find_map(|v| resource_gpus(v))
+        })
+        .or_else(|| {
+            let (kind, count) = lsf_gpus(&directive(script, &LSF_GPU_OPTIONS)?)?;
+            types.extend(kind);
+            Some(count)
+        });
+    Accelerators {
+        gpus,
+        gpus_per_node,
+        gpus_per_task,
+        gpu_type: types.into_iter().next(),
+    }
+}
+
+/// Returns the non-empty value of the first of the given variables in the
+/// environment
+pub fn lookup(env: &HashMap<String, String>, names: &[&str]) -> Option<String> {
+    names
+        .iter()
+        .find_map(|name| env.get(*name).filter(|v| !v.is_empty()))
+        .cloned()
+}
+
+/// Returns the value of the first of the given options in the scheduler
+/// directives of the script, e.g., `#SBATCH -p batch`, `#SBATCH -pbatch` or
+/// `#SBATCH --partition=batch`
+pub fn directive(script: &str, options: &[(&str, &[&str])]) -> Option<String> {
+    directive_values(script, options).into_iter().next()
+}
+
+/// Splits the line into words as the shell does, so a quoted value such as
+/// `"train, again"` is a single word, without the quotes
+fn shell_words(line: &str) -> Vec<String> {
+    let mut words = Vec::new();
+    let mut word: Option<String> = None;
+    let mut quote: Option<char> = None;
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        match (quote, c) {
+            (Some(q), c) if c == q => quote = None,
+            (Some('"'), '\\') | (None, '\\') => {
+                word.get_or_insert_with(String::new).extend(chars.next());
+            }
+            (Some(_), c) => word.get_or_insert_with(String::new).push(c),
+            (None, '"' | '\'') => {
+                quote = Some(c);
+                word.get_or_insert_with(String::new);
+            }
+            (None, c) if c.is_whitespace() => words.extend(word.take()),
+            (None, c) => word.get_or_insert_with(String::new).push(c),
+        }
+    }
+    words.extend(word);
+    words
+}
+
+/// Returns the values of all the given options in the scheduler directives of
+/// the script, in order
+pub fn directive_values(script: &str, options: &[(&str, &[&str])]) -> Vec<String> {
+    let mut values = Vec::new();
+    for line in script.lines() {
+        let words = shell_words(line);
+        let mut words = words.iter().map(|word| word.as_str());
+        let Some(prefix) = words.next() else {
+            continue;
+        };
+        let Some((_, flags)) = options.iter().find(|(p, _)| *p == prefix) else {
+            continue;
+        };
+        while let Some(word) = words.next() {
+            if let Some((flag, value)) = word.split_once('=') {
+                if flags.contains(&flag) {
+                    values.push(value.to_owned());
+                }
+            } else if flags.contains(&word) {
+                values.extend(words.next().map(|v| v.to_owned()));
+            } else if let Some(value) = flags
+                .iter()
+                .filter(|f| f.len() == 2)
+                .find_map(|f| word.strip_prefix(f))
+                .filter(|v| !v.is_empty())
+            {
+                values.push(value.to_owned());
+            }
+        }
+    }
+    values
+}
+
+/// Returns the job name requested in the directives of the script
+pub fn script_job_name(script: &str) -> Option<String> {
+    directive(script, &JOB_NAME_OPTIONS)
+}
+
+/// Returns the partition (or queue) requested in the directives of the script
+pub fn script_partition(script: &str) -> Option<String> {
+    directive(script, &PARTITION_OPTIONS)
+}
+
 pub trait JobInfo: Send {
     // Return the job ID
     fn jobid(&self) -> String;
@@ -31,9 +322,24 @@ pub trait JobInfo: Send {
     // Return the moment of event occurence
     fn moment(&self) -> Instant;
 
+    // Return the wall-clock time of event occurence
+    fn event_time(&self) -> DateTime<Utc>;
+
+    // Return the time the job was submitted, if known. Unlike the event
+    // time, this does not change when the same job is processed again.
+    fn submit_time(&self) -> Option<DateTime<Utc>> {
+        None
+    }
+
     // Return the cluster to which the job was submitted
     fn cluster(&self) -> String;
 
+    // Return the spool path whose event announced the job, from which the
+    // scheduler can create the entry anew, if there is one
+    fn event_path(&self) -> Option<PathBuf> {
+        None
+    }
+
     // Retrieve all the information for the job from the spool location
     // This fills up the required data structures to be able to write
     // the backup or ship the information to some consumer
@@ -43,11 +349,256 @@ pub trait JobInfo: Send {
     // each file that needs to be written as a backup
     fn files(&self) -> Vec<(String, Vec<u8>)>;
 
+    // Return the path in the spool of each file returned by files(), keyed
+    // by the same filename, for backends that copy file metadata
+    fn file_sources(&self) -> HashMap<String, PathBuf> {
+        HashMap::new()
+    }
+
+    // Return the spool path of each file that was too large to be read
+    // into memory, keyed by the filename it is archived under. Backends
+    // that write files copy these straight from the spool.
+    fn streamed_files(&self) -> HashMap<String, PathBuf> {
+        HashMap::new()
+    }
+
     // Return the actual job script as a String
     fn script(&self) -> String;
 
+    // Return the individual jobs this entry is made up of (e.g., the tasks
+    // of an array job) when these should be archived separately, or an
+    // empty Vec to archive the entry as a whole
+    fn expand(&self) -> Vec<Box<dyn JobInfo>> {
+        Vec::new()
+    }
+
     // Return additional information as a set of key-value pairs
     fn extra_info(&self) -> Option<HashMap<String, String>>;
+
+    // Return the names of the job files that never appeared, in which
+    // case only part of the job information was captured
+    fn missing_files(&self) -> Vec<String> {
+        Vec::new()
+    }
+
+    // Return whether the extra info holds the environment of the job, to
+    // which the environment filter and policy apply
+    fn has_environment(&self) -> bool {
+        false
+    }
+
+    // Return the name of the job, from the environment or else from the
+    // directives in the script
+    fn job_name(&self) -> Option<String> {
+        self.extra_info()
+            .and_then(|env| lookup(&env, &JOB_NAME_VARIABLES))
+            .or_else(|| script_job_name(&self.script()))
+    }
+
+    // Return the user who submitted the job, from the environment
+    fn user(&self) -> Option<String> {
+        self.extra_info()
+            .and_then(|env| lookup(&env, &USER_VARIABLES))
+    }
+
+    // Return the uid of the user who submitted the job, from the environment
+    fn uid(&self) -> Option<String> {
+        self.extra_info()
+            .and_then(|env| lookup(&env, &UID_VARIABLES))
+    }
+
+    // Return the partition (or queue) of the job, from the environment or
+    // else from the directives in the script
+    fn partition(&self) -> Option<String> {
+        self.extra_info()
+            .and_then(|env| lookup(&env, &PARTITION_VARIABLES))
+            .or_else(|| script_partition(&self.script()))
+    }
+
+    // Return the GPUs the job requests, from the environment or else from the
+    // directives in the script
+    fn accelerators(&self) -> Accelerators {
+        accelerators(self.extra_info().as_ref(), &self.script())
+    }
+
+    // Return the event recorded when only a tombstone is archived for the job
+    fn tombstone_event(&self) -> String {
+        CANCELLED_EVENT.to_owned()
+    }
+
+    // Return whether the entry reports the deletion of the job files, so
+    // there is nothing to read and only a tombstone is archived
+    fn is_deletion(&self) -> bool {
+        false
+    }
+}
+
+/// The information of a job, read once from its job entry, as it is handed
+/// to the archivers. Backends can use the fields directly, rather than going
+/// through the JobInfo methods, which copy what they return.
+#[derive(Clone, Debug)]
+pub struct JobRecord {
+    pub jobid: String,
+    pub cluster: String,
+    pub moment: Instant,
+    pub event_time: DateTime<Utc>,
+    pub submit_time: Option<DateTime<Utc>>,
+    pub event_path: Option<PathBuf>,
+    pub script: String,
+    pub files: Vec<(String, Vec<u8>)>,
+    pub file_sources: HashMap<String, PathBuf>,
+    pub streamed_files: HashMap<String, PathBuf>,
+    pub extra: Option<HashMap<String, String>>,
+    pub has_environment: bool,
+    pub missing_files: Vec<String>,
+    pub job_name: Option<String>,
+    pub user: Option<String>,
+    pub uid: Option<String>,
+    pub partition: Option<String>,
+    pub tombstone_event: String,
+}
+
+impl JobRecord {
+    /// Takes the information of the job entry, which should have been read
+    /// already
+    pub fn new(job_entry: &dyn JobInfo) -> Self {
+        JobRecord {
+            jobid: job_entry.jobid(),
+            cluster: job_entry.cluster(),
+            moment: job_entry.moment(),
+            event_time: job_entry.event_time(),
+            submit_time: job_entry.submit_time(),
+            event_path: job_entry.event_path(),
+            script: job_entry.script(),
+            files: job_entry.files(),
+            file_sources: job_entry.file_sources(),
+            streamed_files: job_entry.streamed_files(),
+            extra: job_entry.extra_info(),
+            has_environment: job_entry.has_environment(),
+            missing_files: job_entry.missing_files(),
+            job_name: job_entry.job_name(),
+            user: job_entry.user(),
+            uid: job_entry.uid(),
+            partition: job_entry.partition(),
+            tombstone_event: job_entry.tombstone_event(),
+        }
+    }
+
+    /// Returns a record with only the identity and timing of the job, e.g.,
+    /// for a tombstone that should not reveal anything else
+    pub fn bare(&self) -> Self {
+        JobRecord {
+            jobid: self.jobid.clone(),
+            cluster: self.cluster.clone(),
+            moment: self.moment,
+            event_time: self.event_time,
+            submit_time: self.submit_time,
+            event_path: None,
+            script: String::new(),
+            files: Vec::new(),
+            file_sources: HashMap::new(),
+            streamed_files: HashMap::new(),
+            extra: None,
+            has_environment: false,
+            missing_files: Vec::new(),
+            job_name: None,
+            user: None,
+            uid: None,
+            partition: None,
+            tombstone_event: self.tombstone_event.clone(),
+        }
+    }
+
+    /// Total size of the job files, including those streamed from the spool
+    pub fn files_size(&self) -> u64 {
+        let held: u64 = self.files.iter().map(|(_, c)| c.len() as u64).sum();
+        let streamed: u64 = self
+            .streamed_files
+            .values()
+            .filter_map(|source| source.metadata().ok())
+            .map(|m| m.len())
+            .sum();
+        held + streamed
+    }
+}
+
+impl JobInfo for JobRecord {
+    fn jobid(&self) -> String {
+        self.jobid.clone()
+    }
+
+    fn moment(&self) -> Instant {
+        self.moment
+    }
+
+    fn event_time(&self) -> DateTime<Utc> {
+        self.event_time
+    }
+
+    fn submit_time(&self) -> Option<DateTime<Utc>> {
+        self.submit_time
+    }
+
+    fn cluster(&self) -> String {
+        self.cluster.clone()
+    }
+
+    fn event_path(&self) -> Option<PathBuf> {
+        self.event_path.clone()
+    }
+
+    /// The information was read when the record was made
+    fn read_job_info(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn files(&self) -> Vec<(String, Vec<u8>)> {
+        self.files.clone()
+    }
+
+    fn file_sources(&self) -> HashMap<String, PathBuf> {
+        self.file_sources.clone()
+    }
+
+    fn streamed_files(&self) -> HashMap<String, PathBuf> {
+        self.streamed_files.clone()
+    }
+
+    fn script(&self) -> String {
+        self.script.clone()
+    }
+
+    fn extra_info(&self) -> Option<HashMap<String, String>> {
+        self.extra.clone()
+    }
+
+    fn missing_files(&self) -> Vec<String> {
+        self.missing_files.clone()
+    }
+
+    fn has_environment(&self) -> bool {
+        self.has_environment
+    }
+
+    fn job_name(&self) -> Option<String> {
+        self.job_name.clone()
+    }
+
+    fn user(&self) -> Option<String> {
+        self.user.clone()
+    }
+
+    fn uid(&self) -> Option<String> {
+        self.uid.clone()
+    }
+
+    fn partition(&self) -> Option<String> {
+        self.partition.clone()
+    }
+
+    fn tombstone_event(&self) -> String {
+        self.tombstone_event.clone()
+    }
 }
 
 #[cfg(test)]
@@ -63,6 +614,7 @@ mod tests {
     struct DummyJobInfo {
         job_id: String,
         moment: Instant,
+        event_time: DateTime<Utc>,
         cluster: String,
         script: String,
         extra_info: Option<HashMap<String, String>>,
@@ -79,6 +631,7 @@ mod tests {
             DummyJobInfo {
                 job_id: job_id.to_string(),
                 moment: Instant::now(),
+                event_time: Utc::now(),
                 cluster: cluster.to_string(),
                 script: script.to_string(),
                 extra_info,
@@ -100,6 +653,10 @@ mod tests {
             self.moment
         }
 
+        fn event_time(&self) -> DateTime<Utc> {
+            self.event_time
+        }
+
         fn cluster(&self) -> String {
             self.cluster.clone()
         }
@@ -142,6 +699,7 @@ mod tests {
     fn test_moment() {
         let job_info = DummyJobInfo::new("job123", "cluster1", "script1", None);
         assert!(job_info.moment() <= Instant::now());
+        assert!(job_info.event_time() <= Utc::now());
     }
 
     #[test]
@@ -177,4 +735,133 @@ mod tests {
         let job_info = DummyJobInfo::new("job123", "cluster1", "script1", Some(extra_info.clone()));
         assert_eq!(job_info.extra_info(), Some(extra_info));
     }
+
+    #[test]
+    fn test_directives() {
+        let script = "#!/bin/bash\n#SBATCH -N 1\n#SBATCH -p gpu --job-name=train\nsrun -p other\n";
+        assert_eq!(script_partition(script), Some("gpu".to_owned()));
+        assert_eq!(script_job_name(script), Some("train".to_owned()));
+        assert_eq!(
+            script_partition("#SBATCH --time=1:00 --partition debug\n"),
+            Some("debug".to_owned())
+        );
+        assert_eq!(
+            script_partition("#SBATCH -pbatch\n"),
+            Some("batch".to_owned())
+        );
+        assert_eq!(script_partition("#PBS -q long\n"), Some("long".to_owned()));
+        assert_eq!(script_job_name("#BSUB -J sim\n"), Some("sim".to_owned()));
+        assert_eq!(script_partition("# -p gpu\nsrun -p gpu\n"), None);
+
+        // quoted values are taken as a whole
+        assert_eq!(
+            script_job_name("#SBATCH -J \"train, again\"\n"),
+            Some("train, again".to_owned())
+        );
+        assert_eq!(
+            script_job_name("#SBATCH --job-name='it''s' -p gpu\n"),
+            Some("its".to_owned())
+        );
+        assert_eq!(
+            script_job_name("#PBS -N \"say \\\"hi\\\"\"\n"),
+            Some("say \"hi\"".to_owned())
+        );
+    }
+
+    #[test]
+    fn test_accelerators() {
+        assert_eq!(
+            accelerators(None, "#!/bin/bash\nsrun hostname\n"),
+            Accelerators::default()
+        );
+        assert!(accelerators(None, "").is_empty());
+
+        let job = accelerators(None, "#SBATCH --gres=gpu:a100:2,shard:1\n#SBATCH -G 4\n");
+        assert_eq!(job.gpus, Some(4));
+        assert_eq!(job.gpus_per_node, Some(2));
+        assert_eq!(job.gpus_per_task, None);
+        assert_eq!(job.gpu_type, Some("a100".to_owned()));
+
+        let job = accelerators(None, "#SBATCH --gres=gpu --gpus-per-task=v100:1\n");
+        assert_eq!(job.gpus_per_node, Some(1));
+        assert_eq!(job.gpus_per_task, Some(1));
+        assert_eq!(job.gpu_type, Some("v100".to_owned()));
+        assert!(accelerators(None, "#SBATCH --gres=shard:2\n").is_empty());
+
+        let env = HashMap::from([
+            ("SLURM_GPUS".to_owned(), "h100:8".to_owned()),
+            ("SLURM_GPUS_PER_NODE".to_owned(), "4".to_owned()),
+        ]);
+        let job = accelerators(Some(&env), "#SBATCH --gpus=2\n");
+        assert_eq!(job.gpus, Some(8));
+        assert_eq!(job.gpus_per_node, Some(4));
+        assert_eq!(job.gpu_type, Some("h100".to_owned()));
+
+        let job = accelerators(
+            None,
+            "#PBS -l walltime=1:00:00\n#PBS -l nodes=2:ppn=8:gpus=2\n",
+        );
+        assert_eq!(job.gpus_per_node, Some(2));
+        let job = accelerators(None, "#PBS -l select=1:ncpus=4:ngpus=1\n");
+        assert_eq!(job.gpus_per_node, Some(1));
+
+        let job = accelerators(None, "#BSUB -gpu \"num=2:gmodel=TeslaV100\"\n");
+        assert_eq!(job.gpus_per_node, Some(2));
+        assert_eq!(job.gpu_type, Some("TeslaV100".to_owned()));
+        assert_eq!(accelerators(None, "#BSUB -gpu -\n").gpus_per_node, Some(1));
+
+        let job_info = DummyJobInfo::new("job123", "cluster1", "#SBATCH --gpus=3\n", None);
+        assert_eq!(job_info.accelerators().gpus, Some(3));
+    }
+
+    #[test]
+    fn test_job_attributes() {
+        let env = HashMap::from([
+            ("USER".to_owned(), "alice".to_owned()),
+            ("SLURM_JOB_USER".to_owned(), String::new()),
+            ("PBS_JOBNAME".to_owned(), "sim".to_owned()),
+        ]);
+        assert_eq!(
+            lookup(&env, &["SLURM_JOB_USER", "USER"]),
+            Some("alice".to_owned())
+        );
+        assert_eq!(lookup(&env, &["LOGNAME"]), None);
+
+        let job_info = DummyJobInfo::new("job123", "cluster1", "#PBS -q long\n", Some(env));
+        assert_eq!(job_info.job_name(), Some("sim".to_owned()));
+        assert_eq!(job_info.user(), Some("alice".to_owned()));
+        assert_eq!(job_info.partition(), Some("long".to_owned()));
+
+        let job_info = DummyJobInfo::new("job123", "cluster1", "script1", None);
+        assert_eq!(job_info.job_name(), None);
+        assert_eq!(job_info.user(), None);
+        assert_eq!(job_info.partition(), None);
+    }
+
+    #[test]
+    fn test_job_record() {
+        let env = HashMap::from([
+            ("SLURM_JOB_USER".to_owned(), "alice".to_owned()),
+            ("SLURM_JOB_UID".to_owned(), "1000".to_owned()),
+        ]);
+        let mut job_info = DummyJobInfo::new("job123", "cluster1", "#SBATCH -p gpu\n", Some(env));
+        job_info.read_job_info().unwrap();
+
+        let record = JobRecord::new(&job_info);
+        assert_eq!(record.jobid, "job123");
+        assert_eq!(record.files, job_info.files());
+        assert_eq!(record.user, Some("alice".to_owned()));
+        assert_eq!(record.uid, Some("1000".to_owned()));
+        assert_eq!(record.partition, Some("gpu".to_owned()));
+        assert_eq!(record.tombstone_event, CANCELLED_EVENT);
+        assert_eq!(record.extra_info(), job_info.extra_info());
+
+        let bare = record.bare();
+        assert_eq!(bare.jobid, "job123");
+        assert_eq!(bare.event_time, record.event_time);
+        assert!(bare.files.is_empty());
+        assert_eq!(bare.script(), "");
+        assert_eq!(bare.user(), None);
+        assert_eq!(bare.uid(), None);
+    }
 }
@@ -0,0 +1,349 @@
+/*
+Copyright 2019-2024 Andy Georges <itkovian+sarchive@gmail.com>
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in
+all copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+use chrono::{DateTime, Utc};
+use log::debug;
+use notify::event::{CreateKind, Event};
+use regex::Regex;
+use std::collections::HashMap;
+use std::fs::read_dir;
+use std::io::Error;
+use std::path::{Path, PathBuf};
+use std::sync::LazyLock;
+use std::time::Instant;
+
+use super::job::JobInfo;
+use super::{job_event_paths, JobEvent, Scheduler};
+use crate::utils;
+
+/// Marks the start of the user's script in an LSF job file
+const USER_INPUT_START: &str = "# LSBATCH: User input";
+/// Marks the end of the user's script in an LSF job file
+const USER_INPUT_END: &str = "# LSBATCH: End user input";
+
+/// A line of the job file that exports a variable of the job environment
+static EXPORT: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"^([A-Za-z_][A-Za-z0-9_]*)='(.*)'; export ([A-Za-z_][A-Za-z0-9_]*)$").unwrap()
+});
+
+/// Representation of a job file in the LSF info directory
+///
+/// LSF stores a job file named `<submission time>.<job ID>` for every job. This
+/// is a shell script wrapping the user's script, followed by the exported
+/// job environment.
+pub struct LsfJobEntry {
+    /// The full path to the job file
+    path_: PathBuf,
+    /// The job ID
+    jobid_: String,
+    /// The name of the cluster
+    cluster_: String,
+    /// Time of event notification and instance creation
+    moment_: Instant,
+    /// Wall-clock time of event notification
+    event_time_: DateTime<Utc>,
+    /// The contents of the job file
+    jobfile_: Option<Vec<u8>>,
+}
+
+impl LsfJobEntry {
+    pub fn new(path: &Path, id: &str, cluster: &str) -> LsfJobEntry {
+        LsfJobEntry {
+            path_: path.to_path_buf(),
+            jobid_: id.to_owned(),
+            cluster_: cluster.to_owned(),
+            moment_: Instant::now(),
+            event_time_: Utc::now(),
+            jobfile_: None,
+        }
+    }
+
+    fn jobfile(&self) -> String {
+        self.jobfile_
+            .as_ref()
+            .map(|s| String::from_utf8_lossy(s).to_string())
+            .unwrap_or_default()
+    }
+}
+
+impl JobInfo for LsfJobEntry {
+    fn jobid(&self) -> String {
+        self.jobid_.clone()
+    }
+
+    fn moment(&self) -> Instant {
+        self.moment_
+    }
+
+    // Return the wall-clock time of event occurence
+    fn event_time(&self) -> DateTime<Utc> {
+        self.event_time_
+    }
+
+    fn cluster(&self) -> String {
+        self.cluster_.clone()
+    }
+
+    fn event_path(&self) -> Option<PathBuf> {
+        Some(self.path_.clone())
+    }
+
+    fn read_job_info(&mut self) -> Result<(), Error> {
+        let (dir, filename) = utils::split_path(&self.path_)?;
+        self.jobfile_ = Some(utils::read_file(dir, filename, None)?);
+        Ok(())
+    }
+
+    /// Returns the submission time, which LSF puts in the job file name
+    fn submit_time(&self) -> Option<DateTime<Utc>> {
+        let name = self.path_.file_name()?.to_str()?;
+        let (time, _) = name.split_once('.')?;
+        DateTime::from_timestamp(time.parse().ok()?, 0)
+    }
+
+    fn files(&self) -> Vec<(String, Vec<u8>)> {
+        self.jobfile_
+            .iter()
+            .map(|s| (format!("job.{}_jobfile", self.jobid_), s.to_owned()))
+            .collect()
+    }
+
+    fn file_sources(&self) -> HashMap<String, PathBuf> {
+        self.jobfile_
+            .iter()
+            .map(|_| (format!("job.{}_jobfile", self.jobid_), self.path_.clone()))
+            .collect()
+    }
+
+    /// Returns the user's script, i.e., the part of the job file between the
+    /// LSBATCH markers, or the entire job file if these are absent
+    fn script(&self) -> String {
+        let jobfile = self.jobfile();
+        match (jobfile.find(USER_INPUT_START), jobfile.find(USER_INPUT_END)) {
+            (Some(start), Some(end)) if start < end => jobfile[start + USER_INPUT_START.len()..end]
+                .trim_start_matches('\n')
+                .to_owned(),
+            _ => jobfile,
+        }
+    }
+
+    /// Returns the environment exported in the job file, i.e., the lines
+    /// of the form `NAME='value'; export NAME`
+    fn extra_info(&self) -> Option<HashMap<String, String>> {
+        self.jobfile_.as_ref().map(|_| {
+            self.jobfile()
+                .lines()
+                .filter_map(|line| EXPORT.captures(line))
+                .filter(|c| c[1] == c[3])
+                .map(|c| (c[1].to_owned(), c[2].to_owned()))
+                .collect()
+        })
+    }
+
+    fn has_environment(&self) -> bool {
+        self.jobfile_.is_some()
+    }
+}
+
+/// Returns the job file with each exported variable passed through the edit,
+/// which gets the name and value and returns the value to keep, or None to
+/// leave the variable out. The other lines are kept as they are.
+pub fn edit_exports(
+    contents: &[u8],
+    mut edit: impl FnMut(&str, &str) -> Option<String>,
+) -> Vec<u8> {
+    let mut edited = Vec::with_capacity(contents.len());
+    for line in contents.split_inclusive(|b| *b == b'\n') {
+        let export = std::str::from_utf8(line)
+            .ok()
+            .and_then(|l| EXPORT.captures(l.trim_end_matches('\n')))
+            .filter(|c| c[1] == c[3]);
+        match export {
+            Some(c) => match edit(&c[1], &c[2]) {
+                Some(value) if value == c[2] => edited.extend_from_slice(line),
+                Some(value) => {
+                    let value = value.replace('\'', r"'\''");
+                    edited.extend_from_slice(
+                        format!("{}='{value}'; export {}", &c[1], &c[1]).as_bytes(),
+                    );
+                    if line.ends_with(b"\n") {
+                        edited.push(b'\n');
+                    }
+                }
+                None => (),
+            },
+            None => edited.extend_from_slice(line),
+        }
+    }
+    edited
+}
+
+/// Representation of the LSF scheduler
+pub struct Lsf {
+    /// The cluster's directory under LSB_SHAREDIR
+    pub base: PathBuf,
+    pub cluster: String,
+    /// The events that announce a new job entry
+    pub event_kinds: Vec<JobEvent>,
+}
+
+impl Lsf {
+    pub fn new(base: &Path, cluster: &str) -> Lsf {
+        Lsf {
+            base: base.to_path_buf(),
+            cluster: cluster.to_owned(),
+            event_kinds: vec![JobEvent::Create],
+        }
+    }
+
+    fn info_dir(&self) -> PathBuf {
+        self.base.join("logdir").join("info")
+    }
+}
+
+impl Scheduler for Lsf {
+    /// Returns the info directory, along with its numbered subdirectories
+    /// (present when MAX_INFO_DIRS is set in lsb.params)
+    fn watch_locations(&self) -> Vec<PathBuf> {
+        let info = self.info_dir();
+        let mut locations: Vec<PathBuf> = read_dir(&info)
+            .map(|entries| {
+                entries
+                    .filter_map(|entry| entry.ok().map(|e| e.path()))
+                    .filter(|p| {
+                        p.is_dir()
+                            && p.file_name()
+                                .and_then(|n| n.to_str())
+                                .is_some_and(|n| n.chars().all(|c| c.is_ascii_digit()))
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+        locations.sort();
+        locations.insert(0, info);
+        locations
+    }
+
+    fn create_job_info(&self, event_path: &Path) -> Option<Box<dyn JobInfo>> {
+        is_job_path(event_path).map(|jobid| {
+            Box::new(LsfJobEntry::new(event_path, jobid, &self.cluster)) as Box<dyn JobInfo>
+        })
+    }
+
+    fn verify_event_kind(&self, event: &Event) -> Option<Vec<PathBuf>> {
+        job_event_paths(event, CreateKind::File, &self.event_kinds)
+    }
+}
+
+/// Verifies that the path is that of an LSF job file, named
+/// `<submission time>.<job ID>`, and returns the job ID
+fn is_job_path(path: &Path) -> Option<&str> {
+    if path.is_file() {
+        if let Some((time, jobid)) = path.file_name()?.to_str()?.split_once('.') {
+            let numeric = |s: &str| !s.is_empty() && s.chars().all(|c| c.is_ascii_digit());
+            if numeric(time) && numeric(jobid) {
+                return Some(jobid);
+            }
+        }
+    }
+    debug!("{:?} is not a considered job path", &path);
+    None
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use std::env::current_dir;
+    use std::fs::{create_dir_all, File};
+    use tempfile::tempdir;
+
+    fn job_entry() -> LsfJobEntry {
+        let path = current_dir()
+            .unwrap()
+            .join("tests/lsf_info/1700000000.1234");
+        let mut entry = LsfJobEntry::new(&path, "1234", "mycluster");
+        entry.read_job_info().unwrap();
+        entry
+    }
+
+    #[test]
+    fn test_script() {
+        let entry = job_entry();
+        assert_eq!(
+            entry.script(),
+            "#BSUB -n 4\n./my_simulation --input data.in\nExitStat=$?\nwait\n"
+        );
+        assert_eq!(entry.files().len(), 1);
+        assert_eq!(entry.files()[0].0, "job.1234_jobfile");
+        assert_eq!(entry.submit_time(), DateTime::from_timestamp(1700000000, 0));
+    }
+
+    #[test]
+    fn test_extra_info() {
+        let hm = job_entry().extra_info().unwrap();
+        assert_eq!(hm.len(), 4);
+        assert_eq!(hm.get("LSB_JOBNAME").unwrap(), "my_simulation");
+        assert_eq!(hm.get("PATH").unwrap(), "/usr/local/bin:/usr/bin:/bin");
+    }
+
+    #[test]
+    fn test_edit_exports() {
+        let jobfile = b"#!/bin/sh\nLSB_QUEUE='normal'; export LSB_QUEUE\nTOKEN='s3cr3t'; export TOKEN\nPATH='/bin'; export PATH\n./run\n";
+        let edited = edit_exports(jobfile, |key, value| match key {
+            "TOKEN" => Some("it's".to_owned()),
+            "LSB_QUEUE" => None,
+            _ => Some(value.to_owned()),
+        });
+        assert_eq!(
+            String::from_utf8(edited).unwrap(),
+            "#!/bin/sh\nTOKEN='it'\\''s'; export TOKEN\nPATH='/bin'; export PATH\n./run\n"
+        );
+    }
+
+    #[test]
+    fn test_is_job_path() {
+        let tdir = tempdir().unwrap();
+        let jobfile = tdir.path().join("1700000000.1234");
+        File::create(&jobfile).unwrap();
+        let other = tdir.path().join("1234.out");
+        File::create(&other).unwrap();
+
+        assert_eq!(is_job_path(&jobfile), Some("1234"));
+        assert_eq!(is_job_path(&other), None);
+        assert_eq!(is_job_path(&tdir.path().join("1700000000.5678")), None);
+    }
+
+    #[test]
+    fn test_watch_locations() {
+        let tdir = tempdir().unwrap();
+        let info = tdir.path().join("logdir/info");
+        create_dir_all(info.join("1")).unwrap();
+        create_dir_all(info.join("0")).unwrap();
+        File::create(info.join("1700000000.1234")).unwrap();
+
+        let lsf = Lsf::new(tdir.path(), "mycluster");
+        assert_eq!(
+            lsf.watch_locations(),
+            vec![info.clone(), info.join("0"), info.join("1")]
+        );
+    }
+}
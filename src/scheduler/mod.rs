@@ -20,40 +20,320 @@ OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
 SOFTWARE.
 */
 
+pub mod environment;
 pub mod job;
+pub mod lsf;
+pub mod rules;
 pub mod slurm;
+pub mod spool;
 pub mod torque;
 
 use clap::ValueEnum;
-use notify::event::Event;
-use regex::Regex;
+use log::info;
+use notify::event::{CreateKind, Event, EventKind, ModifyKind, RenameMode};
+use std::fs::read_dir;
+use std::io::{Error, ErrorKind};
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
 use job::JobInfo;
+use rules::FileRules;
+use torque::{Suffixes, TorqueArgs};
 
-#[derive(ValueEnum, Clone, Debug)]
+#[derive(ValueEnum, Clone, Debug, PartialEq, Eq)]
 pub enum SchedulerKind {
     Slurm,
     Torque,
+    Lsf,
+    /// Detect the scheduler from the layout of the spool
+    Auto,
+}
+
+/// Returns the scheduler whose layout the spool has: Slurm keeps its jobs in
+/// hash.* directories, LSF in the logdir/info directory, and Torque keeps the
+/// job files in the spool itself or in its numbered subdirectories. Fails
+/// when the spool matches none, or more than one, of these layouts.
+pub fn detect(spool_path: &Path, torque_args: &TorqueArgs) -> Result<SchedulerKind, Error> {
+    let entries: Vec<PathBuf> = read_dir(spool_path)?
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .collect();
+    let name = |path: &Path| {
+        path.file_name()
+            .and_then(|n| n.to_str())
+            .map(|n| n.to_owned())
+            .unwrap_or_default()
+    };
+    let suffixes = Suffixes::from_args(torque_args);
+    let has_job_files = |dir: &Path| {
+        read_dir(dir).is_ok_and(|mut files| {
+            files.any(|file| {
+                file.is_ok_and(|f| {
+                    f.path()
+                        .extension()
+                        .and_then(|e| e.to_str())
+                        .is_some_and(|e| e == suffixes.script || e == suffixes.jb)
+                })
+            })
+        })
+    };
+    let subdirs: Vec<&PathBuf> = entries
+        .iter()
+        .filter(|p| p.is_dir() && name(p).len() == 1 && name(p).chars().all(|c| c.is_ascii_digit()))
+        .collect();
+
+    let mut found = Vec::new();
+    if entries
+        .iter()
+        .any(|p| p.is_dir() && name(p).starts_with("hash."))
+    {
+        found.push(SchedulerKind::Slurm);
+    }
+    if has_job_files(spool_path) || subdirs.len() == 10 || subdirs.iter().any(|d| has_job_files(d))
+    {
+        found.push(SchedulerKind::Torque);
+    }
+    if spool_path.join("logdir").join("info").is_dir() {
+        found.push(SchedulerKind::Lsf);
+    }
+    match found.as_slice() {
+        [kind] => Ok(kind.clone()),
+        [] => Err(Error::new(
+            ErrorKind::NotFound,
+            format!(
+                "Cannot tell the scheduler from spool {spool_path:?}: it has no hash.* directories (Slurm), \
+                 .{} or .{} files (Torque) or logdir/info directory (LSF); set --scheduler",
+                suffixes.script, suffixes.jb
+            ),
+        )),
+        kinds => Err(Error::new(
+            ErrorKind::InvalidData,
+            format!(
+                "Cannot tell the scheduler from spool {spool_path:?}, its layout fits {}; set --scheduler",
+                kinds
+                    .iter()
+                    .map(|k| format!("{k:?}"))
+                    .collect::<Vec<_>>()
+                    .join(" and ")
+            ),
+        )),
+    }
+}
+
+/// The job files to capture
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Capture {
+    /// Only the job script; the environment is never read
+    Script,
+    /// Only the job environment; the script is never read
+    Environment,
+    /// Both the script and the environment
+    #[default]
+    All,
+}
+
+impl Capture {
+    /// Whether the job script is captured
+    pub fn script(&self) -> bool {
+        *self != Capture::Environment
+    }
+
+    /// Whether the job environment is captured
+    pub fn environment(&self) -> bool {
+        *self != Capture::Script
+    }
+}
+
+/// Filesystem events that announce a new job entry
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum JobEvent {
+    /// The job entry is created in the watched location
+    Create,
+    /// The job entry is renamed or moved into the watched location, e.g., when
+    /// it is written under a temporary name first
+    Rename,
+}
+
+/// Returns the paths of the event if it is one of the given kinds. Creation
+/// must be of the given kind (file or folder). For a rename, we only consider
+/// the new name, so a rename within the watched location is seen only once.
+pub fn job_event_paths(
+    event: &Event,
+    create: CreateKind,
+    kinds: &[JobEvent],
+) -> Option<Vec<PathBuf>> {
+    let accepted = match event.kind {
+        EventKind::Create(kind) => kind == create && kinds.contains(&JobEvent::Create),
+        EventKind::Modify(ModifyKind::Name(RenameMode::To)) => kinds.contains(&JobEvent::Rename),
+        _ => false,
+    };
+    if accepted {
+        Some(event.paths.to_vec())
+    } else {
+        None
+    }
 }
 
 pub trait Scheduler: Send + Sync {
     fn watch_locations(&self) -> Vec<PathBuf>;
     fn create_job_info(&self, event_path: &Path) -> Option<Box<dyn JobInfo>>;
     fn verify_event_kind(&self, event: &Event) -> Option<Vec<PathBuf>>;
+
+    // Return the location to watch for new watch locations appearing, if any
+    fn discovery_location(&self) -> Option<PathBuf> {
+        None
+    }
+
+    // Return the new watch location the event announces, if any
+    fn verify_discovery_event(&self, _event: &Event) -> Option<PathBuf> {
+        None
+    }
+
+    // Return the watch location the event reports as removed, if any
+    fn verify_removal_event(&self, _event: &Event) -> Option<PathBuf> {
+        None
+    }
+
+    // Return the job entry the event reports as deleted, if any, as the
+    // event path of its job info
+    fn verify_job_removal(&self, _event: &Event) -> Option<PathBuf> {
+        None
+    }
+
+    // Return the entry that reports the deletion of the job entry at the
+    // given path, if deletions are archived
+    fn deletion_entry(&self, _path: &Path) -> Option<Box<dyn JobInfo>> {
+        None
+    }
+}
+
+/// How the schedulers read the job entries, shared by every spool root
+pub struct SchedulerOptions<'a> {
+    pub torque_args: &'a TorqueArgs,
+    pub event_kinds: &'a [JobEvent],
+    pub max_buffered_size: Option<u64>,
+    pub capture_credentials: bool,
+    pub capture: Capture,
+    pub resolve_sourced: Option<u64>,
+    pub slurm_rules: Arc<FileRules>,
 }
 
+/// Creates the scheduler of the given kind, detecting it from the spool
+/// first, if requested
 pub fn create(
     scheduler: &SchedulerKind,
     spool_path: &Path,
     cluster: &str,
-    filter_regex: &Option<Regex>,
-) -> Box<dyn Scheduler> {
-    match scheduler {
-        SchedulerKind::Slurm => Box::new(slurm::Slurm::new(spool_path, cluster, &filter_regex)),
-        SchedulerKind::Torque => Box::new(torque::Torque::new(spool_path, cluster)),
-    }
+    options: &SchedulerOptions,
+) -> Result<Box<dyn Scheduler>, Error> {
+    let capture = options.capture;
+    let capture_slurm_only = |kind: &str| {
+        Error::new(
+            ErrorKind::InvalidInput,
+            format!("Capturing only the script or the environment is not supported for {kind}"),
+        )
+    };
+    let sched: Box<dyn Scheduler> = match scheduler {
+        SchedulerKind::Slurm => {
+            let mut slurm = slurm::Slurm::new(spool_path, cluster);
+            slurm.event_kinds = options.event_kinds.to_vec();
+            slurm.max_buffered_size = options.max_buffered_size;
+            slurm.capture_credentials = options.capture_credentials;
+            slurm.capture = capture;
+            slurm.resolve_sourced = options.resolve_sourced;
+            slurm.file_rules = Arc::clone(&options.slurm_rules);
+            Box::new(slurm)
+        }
+        SchedulerKind::Torque if capture != Capture::All => {
+            return Err(capture_slurm_only("Torque"))
+        }
+        SchedulerKind::Lsf if capture != Capture::All => return Err(capture_slurm_only("LSF")),
+        SchedulerKind::Torque => {
+            let mut torque = torque::Torque::new(spool_path, cluster, options.torque_args);
+            torque.event_kinds = options.event_kinds.to_vec();
+            torque.max_buffered_size = options.max_buffered_size;
+            Box::new(torque)
+        }
+        SchedulerKind::Lsf => {
+            let mut lsf = lsf::Lsf::new(spool_path, cluster);
+            lsf.event_kinds = options.event_kinds.to_vec();
+            Box::new(lsf)
+        }
+        SchedulerKind::Auto => {
+            let kind = detect(spool_path, options.torque_args)?;
+            info!(
+                "Detected the layout of a {:?} spool in {:?}",
+                kind, spool_path
+            );
+            return create(&kind, spool_path, cluster, options);
+        }
+    };
+    Ok(sched)
 }
 
 #[cfg(test)]
-mod tests {}
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_job_event_paths() {
+        let event = |kind: EventKind| Event {
+            kind,
+            paths: vec![PathBuf::from("/spool/hash.4/job.1234")],
+            ..Default::default()
+        };
+        let create = event(EventKind::Create(CreateKind::Folder));
+        let rename_to = event(EventKind::Modify(ModifyKind::Name(RenameMode::To)));
+        let rename_both = event(EventKind::Modify(ModifyKind::Name(RenameMode::Both)));
+        let all = [JobEvent::Create, JobEvent::Rename];
+
+        assert!(job_event_paths(&create, CreateKind::Folder, &[JobEvent::Create]).is_some());
+        assert!(job_event_paths(&create, CreateKind::File, &all).is_none());
+        assert!(job_event_paths(&rename_to, CreateKind::Folder, &[JobEvent::Create]).is_none());
+        assert_eq!(
+            job_event_paths(&rename_to, CreateKind::Folder, &all),
+            Some(vec![PathBuf::from("/spool/hash.4/job.1234")])
+        );
+        assert!(job_event_paths(&rename_both, CreateKind::Folder, &all).is_none());
+    }
+
+    #[test]
+    fn test_detect() {
+        #[derive(clap::Parser)]
+        struct Cli {
+            #[command(flatten)]
+            torque: TorqueArgs,
+        }
+        let args = <Cli as clap::Parser>::parse_from(["sarchive"]).torque;
+        let spool = |dirs: &[&str], files: &[&str]| {
+            let tdir = tempfile::tempdir().unwrap();
+            for dir in dirs {
+                std::fs::create_dir_all(tdir.path().join(dir)).unwrap();
+            }
+            for file in files {
+                std::fs::write(tdir.path().join(file), b"").unwrap();
+            }
+            tdir
+        };
+
+        let slurm = spool(&["hash.0", "hash.1"], &["job_state"]);
+        assert_eq!(detect(slurm.path(), &args).unwrap(), SchedulerKind::Slurm);
+        let torque = spool(&[], &["1.master.SC", "1.master.JB"]);
+        assert_eq!(detect(torque.path(), &args).unwrap(), SchedulerKind::Torque);
+        let subdirs: Vec<String> = (0..=9).map(|d| d.to_string()).collect();
+        let subdirs: Vec<&str> = subdirs.iter().map(|d| d.as_str()).collect();
+        let torque = spool(&subdirs, &[]);
+        assert_eq!(detect(torque.path(), &args).unwrap(), SchedulerKind::Torque);
+        let lsf = spool(&["logdir/info/1"], &[]);
+        assert_eq!(detect(lsf.path(), &args).unwrap(), SchedulerKind::Lsf);
+
+        let empty = spool(&[], &[]);
+        assert_eq!(
+            detect(empty.path(), &args).unwrap_err().kind(),
+            ErrorKind::NotFound
+        );
+        let both = spool(&["hash.0", "logdir/info"], &[]);
+        let e = detect(both.path(), &args).unwrap_err();
+        assert!(e.to_string().contains("Slurm and Lsf"));
+    }
+}
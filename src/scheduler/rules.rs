@@ -0,0 +1,89 @@
+/*
+Copyright 2019-2024 Andy Georges <itkovian+sarchive@gmail.com>
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in
+all copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+use glob::Pattern;
+
+/// Glob rules on the names of the files of a job, deciding which of them are
+/// captured. A file the scheduler captures by default is captured unless an
+/// exclude rule matches it. Another file of the job is captured as well when
+/// an include rule matches it and no exclude rule does.
+#[derive(Clone, Debug, Default)]
+pub struct FileRules {
+    include: Vec<Pattern>,
+    exclude: Vec<Pattern>,
+}
+
+impl FileRules {
+    pub fn new(include: &[Pattern], exclude: &[Pattern]) -> FileRules {
+        FileRules {
+            include: include.to_vec(),
+            exclude: exclude.to_vec(),
+        }
+    }
+
+    /// Whether a file the scheduler captures by default is captured
+    pub fn keeps(&self, name: &str) -> bool {
+        !self.exclude.iter().any(|p| p.matches(name))
+    }
+
+    /// Whether a file the scheduler does not capture by default is captured
+    pub fn adds(&self, name: &str) -> bool {
+        self.include.iter().any(|p| p.matches(name)) && self.keeps(name)
+    }
+
+    /// Whether any file is added to those captured by default
+    pub fn has_includes(&self) -> bool {
+        !self.include.is_empty()
+    }
+
+    /// Builds the rules from the globs, which must be valid
+    #[cfg(test)]
+    pub(crate) fn from_globs(include: &[&str], exclude: &[&str]) -> FileRules {
+        let patterns = |globs: &[&str]| -> Vec<Pattern> {
+            globs.iter().map(|g| Pattern::new(g).unwrap()).collect()
+        };
+        FileRules::new(&patterns(include), &patterns(exclude))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_file_rules() {
+        let rules = FileRules::from_globs(&["burst_buffer", "*.log"], &["*.tmp", "debug.log"]);
+
+        assert!(rules.keeps("script"));
+        assert!(!rules.keeps("script.tmp"));
+        assert!(rules.adds("burst_buffer"));
+        assert!(rules.adds("prolog.log"));
+        assert!(!rules.adds("debug.log"));
+        assert!(!rules.adds("environment"));
+        assert!(rules.has_includes());
+
+        let none = FileRules::default();
+        assert!(none.keeps("anything.tmp"));
+        assert!(!none.adds("burst_buffer"));
+        assert!(!none.has_includes());
+    }
+}
@@ -19,17 +19,25 @@ LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
 OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
 SOFTWARE.
 */
-use log::debug;
-use notify::event::{CreateKind, Event, EventKind};
-use regex::Regex;
+use chrono::{DateTime, Utc};
+use log::{debug, error, info, warn};
+use notify::event::{CreateKind, Event, EventKind, RemoveKind};
 use std::collections::HashMap;
-use std::io::Error;
+use std::fs::{self, read_dir, OpenOptions};
+use std::io::{Error, ErrorKind, Read};
+use std::os::unix::fs::{MetadataExt, OpenOptionsExt};
 use std::path::{Path, PathBuf};
 use std::string::String;
-use std::time::Instant;
+use std::sync::Arc;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
 
-use super::job::JobInfo;
-use super::Scheduler;
+use super::job::{
+    directive, lookup, script_job_name, script_partition, JobInfo, JOB_NAME_VARIABLES,
+    PARTITION_VARIABLES, UID_VARIABLES, USER_VARIABLES,
+};
+use super::rules::FileRules;
+use super::{job_event_paths, Capture, JobEvent, Scheduler};
 use crate::utils;
 
 /// Representation of an entry in the Slurm job spool hash directories
@@ -42,14 +50,118 @@ pub struct SlurmJobEntry {
     cluster_: String,
     /// Time of event notification and instance creation
     moment_: Instant,
+    /// Wall-clock time of event notification
+    event_time_: DateTime<Utc>,
     /// The actual job script
     script_: Option<Vec<u8>>,
     /// The job's environment in Slurm
     env_: Option<Vec<u8>>,
-    /// Filter for the environment
-    filter_regex: Option<Regex>,
+    /// Job files that did not appear in time
+    missing_: Vec<String>,
+    /// Size above which job files are not read into memory
+    max_buffered_size: Option<u64>,
+    /// Job files that are left in the spool because of their size
+    streamed_: Vec<String>,
+    /// Modification time of the first job file that was read
+    submit_time_: Option<DateTime<Utc>>,
+    /// Archive the contents of the credential and GRES files, rather than
+    /// only their names and sizes
+    capture_credentials: bool,
+    /// Credential and GRES files found in the job directory
+    credentials_: Vec<CredentialFile>,
+    /// The job files to read
+    capture: Capture,
+    /// Size up to which the file the script sources is archived, if at all
+    resolve_sourced: Option<u64>,
+    /// The file the script sources, and its contents
+    sourced_: Option<(PathBuf, Vec<u8>)>,
+    /// Why the file the script sources was not archived
+    sourced_skipped_: Option<String>,
+    /// Rules on the files in the job directory to capture or leave out
+    file_rules: Arc<FileRules>,
+    /// Other files in the job directory that the rules include
+    included_: Vec<(String, Vec<u8>)>,
 }
 
+/// A credential or GRES related file in the job directory
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct CredentialFile {
+    name: String,
+    size: u64,
+    /// Only read when the contents are to be archived
+    contents: Option<Vec<u8>>,
+}
+
+/// The job files Slurm writes in the job directory, which are captured by default
+const JOB_FILES: [&str; 2] = ["script", "environment"];
+
+/// Key under which the missing job files are listed in the extra info
+pub const MISSING_FILES_KEY: &str = "sarchive_missing_files";
+
+/// Key under which the job files that were too large to be read into memory
+/// are listed in the extra info
+pub const STREAMED_FILES_KEY: &str = "sarchive_streamed_files";
+
+/// Key under which the environment variables that are not valid UTF-8 are
+/// listed in the extra info. Their invalid bytes are replaced by U+FFFD.
+pub const LOSSY_ENV_KEY: &str = "sarchive_lossy_environment";
+
+/// Key under which a mismatch between the count at the start of the
+/// environment file and the entries that follow is recorded in the extra info
+pub const ENV_COUNT_MISMATCH_KEY: &str = "sarchive_env_count_mismatch";
+
+/// Key under which the credential and GRES files in the job directory are
+/// listed in the extra info, as name:size pairs
+pub const CREDENTIAL_FILES_KEY: &str = "sarchive_credential_files";
+
+/// Key under which the expected location of the job's stdout is recorded in
+/// the extra info
+pub const STDOUT_KEY: &str = "sarchive_stdout";
+
+/// Key under which the expected location of the job's stderr is recorded in
+/// the extra info
+pub const STDERR_KEY: &str = "sarchive_stderr";
+
+/// Key under which the file the job script sources is recorded in the extra
+/// info, when it is archived as well
+pub const SOURCED_KEY: &str = "sarchive_sourced";
+
+/// Key under which the file the job script sources is recorded in the extra
+/// info, along with the reason, when it is not archived
+pub const SOURCED_SKIPPED_KEY: &str = "sarchive_sourced_skipped";
+
+/// Key under which the way sbatch exported the submission environment to the
+/// job (following `--export`) is recorded in the extra info
+pub const EXPORT_MODE_KEY: &str = "sarchive_export_mode";
+
+/// Environment variable in which sbatch passes the `--export` setting
+const EXPORT_VARIABLE: &str = "SLURM_EXPORT_ENV";
+
+/// Time between checks of an environment file that may still be written
+const ENV_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Number of checks of an environment file that may still be written, as
+/// long as job files are waited for to appear
+const ENV_POLL_ITERS: u32 = 100;
+
+/// Directive options that set which environment variables are exported
+const EXPORT_OPTIONS: [(&str, &[&str]); 1] = [("#SBATCH", &["--export"])];
+
+/// Directive options that set the stdout of a job
+const STDOUT_OPTIONS: [(&str, &[&str]); 1] = [("#SBATCH", &["-o", "--output"])];
+
+/// Directive options that set the stderr of a job
+const STDERR_OPTIONS: [(&str, &[&str]); 1] = [("#SBATCH", &["-e", "--error"])];
+
+/// Directive options that set the working directory of a job
+const CHDIR_OPTIONS: [(&str, &[&str]); 1] = [("#SBATCH", &["-D", "--chdir"])];
+
+/// Directive options that make a job an array job
+const ARRAY_OPTIONS: [(&str, &[&str]); 1] = [("#SBATCH", &["-a", "--array"])];
+
+/// Environment variables that hold the directory the job was submitted from
+const SUBMIT_DIR_VARIABLES: [&str; 2] = ["SLURM_SUBMIT_DIR", "PWD"];
+
 impl SlurmJobEntry {
     /// Returns a new SlurmJobEntry with the given path to the job info and the given job ID
     ///
@@ -68,35 +180,573 @@ impl SlurmJobEntry {
     /// let id = "1234";
     /// let cluster = "mycluster";
     ///
-    /// let job_entry = SlurmJobEntry::new(&p, &id, &cluster, &None);
+    /// let job_entry = SlurmJobEntry::new(&p, &id, &cluster);
     ///
     /// assert_eq!(job_entry.path_, p);
     /// ```
-    pub fn new(
-        path: &Path,
-        id: &str,
-        cluster: &str,
-        filter_regex: &Option<Regex>,
-    ) -> SlurmJobEntry {
+    pub fn new(path: &Path, id: &str, cluster: &str) -> SlurmJobEntry {
         SlurmJobEntry {
             path_: path.to_path_buf(),
             jobid_: id.to_string(),
             cluster_: cluster.to_string(),
             moment_: Instant::now(),
+            event_time_: Utc::now(),
             script_: None,
             env_: None,
-            filter_regex: filter_regex.clone(),
+            missing_: Vec::new(),
+            max_buffered_size: None,
+            streamed_: Vec::new(),
+            submit_time_: None,
+            capture_credentials: false,
+            credentials_: Vec::new(),
+            capture: Capture::All,
+            resolve_sourced: None,
+            sourced_: None,
+            sourced_skipped_: None,
+            file_rules: Arc::new(FileRules::default()),
+            included_: Vec::new(),
+        }
+    }
+
+    /// Reads the given job file, returning `None` if it did not appear in time
+    /// or if it is larger than the maximal buffered size, in which case it is
+    /// left in the spool for streaming. A vanished job directory is still an
+    /// error.
+    fn read_partial(&mut self, filename: &str) -> Result<Option<Vec<u8>>, Error> {
+        match utils::wait_for_file(&self.path_, Path::new(filename), None) {
+            Ok(path) => {
+                if let Some(max_size) = self.max_buffered_size {
+                    let size = fs::metadata(&path)?.len();
+                    if size > max_size {
+                        info!(
+                            "Job {} has a {} file of {} bytes, streaming it from the spool",
+                            self.jobid_, filename, size
+                        );
+                        self.streamed_.push(filename.to_owned());
+                        return Ok(None);
+                    }
+                }
+                fs::read(path).map(Some)
+            }
+            Err(e) if e.kind() == ErrorKind::TimedOut => {
+                warn!("Job {} is missing its {} file", self.jobid_, filename);
+                self.missing_.push(filename.to_owned());
+                Ok(None)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Reads the environment file, which Slurm does not write atomically. It
+    /// is accepted once it holds as many entries as its count prefix says, or
+    /// once its size stays the same between two checks, and read again
+    /// otherwise, for as long as job files are waited for to appear. After
+    /// that, what was read last is kept.
+    fn read_environment(&mut self) -> Result<Option<Vec<u8>>, Error> {
+        let path = self.path_.join("environment");
+        let mut env = self.read_partial("environment")?;
+        for _ in 0..ENV_POLL_ITERS {
+            let contents = match &env {
+                Some(contents) if !env_complete(contents) => contents,
+                _ => return Ok(env),
+            };
+            sleep(ENV_POLL_INTERVAL);
+            match fs::metadata(&path) {
+                Ok(m) if m.len() == contents.len() as u64 => return Ok(env),
+                _ => {
+                    debug!(
+                        "Job {} environment file is still being written",
+                        self.jobid_
+                    );
+                    env = self.read_partial("environment")?;
+                }
+            }
+        }
+        warn!(
+            "Job {} environment file kept changing, archiving it as last read",
+            self.jobid_
+        );
+        Ok(env)
+    }
+
+    /// Looks for credential and GRES files in the job directory, recording
+    /// their names and sizes. Their contents are only read when requested.
+    /// A file that goes away while it is read is left out with a warning.
+    fn read_credentials(&mut self) -> Result<(), Error> {
+        self.credentials_.clear();
+        for entry in read_dir(&self.path_)? {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if !is_credential_name(&name) || !self.file_rules.keeps(&name) {
+                continue;
+            }
+            match self.read_credential(&name) {
+                Ok(Some(credential)) => self.credentials_.push(credential),
+                Ok(None) => (),
+                Err(e) if e.kind() == ErrorKind::NotFound => {
+                    warn!(
+                        "Job {} {} file went away, leaving it out",
+                        self.jobid_, name
+                    );
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        self.credentials_.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(())
+    }
+
+    /// Reads the size of the credential file with the given name and, when
+    /// requested, its contents, or returns `None` if it is not a regular file
+    fn read_credential(&self, name: &str) -> Result<Option<CredentialFile>, Error> {
+        let path = self.path_.join(name);
+        let metadata = fs::symlink_metadata(&path)?;
+        if !metadata.is_file() {
+            return Ok(None);
+        }
+        let contents = if self.capture_credentials {
+            Some(fs::read(&path)?)
+        } else {
+            None
+        };
+        Ok(Some(CredentialFile {
+            name: name.to_owned(),
+            size: metadata.len(),
+            contents,
+        }))
+    }
+
+    /// Reads the other files in the job directory that the rules include,
+    /// e.g., a `burst_buffer` script. Files larger than the maximal buffered
+    /// size are left in the spool for streaming. A file that cannot be read,
+    /// e.g., because it went away, is left out with a warning.
+    fn read_included(&mut self) -> Result<(), Error> {
+        self.included_.clear();
+        if !self.file_rules.has_includes() {
+            return Ok(());
+        }
+        for entry in read_dir(&self.path_)?.filter_map(Result::ok) {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if JOB_FILES.contains(&name.as_str())
+                || is_credential_name(&name)
+                || !self.file_rules.adds(&name)
+            {
+                continue;
+            }
+            if let Err(e) = self.read_included_file(name) {
+                warn!(
+                    "Cannot read {:?} for job {}: {}",
+                    entry.path(),
+                    self.jobid_,
+                    e
+                );
+            }
+        }
+        self.included_.sort();
+        Ok(())
+    }
+
+    /// Reads the included file with the given name, if it is a regular file,
+    /// or leaves it for streaming if it is too large
+    fn read_included_file(&mut self, name: String) -> Result<(), Error> {
+        let path = self.path_.join(&name);
+        let metadata = fs::symlink_metadata(&path)?;
+        if !metadata.is_file() {
+            return Ok(());
+        }
+        if self
+            .max_buffered_size
+            .is_some_and(|max_size| metadata.len() > max_size)
+        {
+            info!(
+                "Job {} has a {} file of {} bytes, streaming it from the spool",
+                self.jobid_,
+                name,
+                metadata.len()
+            );
+            self.streamed_.push(name);
+            return Ok(());
+        }
+        let contents = fs::read(&path)?;
+        self.included_.push((name, contents));
+        Ok(())
+    }
+
+    /// Parses the job environment (if any) into a HashMap, mapping env keys to values
+    ///
+    /// Each entry is split on its first '=', so values may contain '=' as well.
+    /// Entries that are not valid UTF-8 are converted lossily and listed under
+    /// `LOSSY_ENV_KEY`. When the number of entries differs from the count at
+    /// the start of the file, both are recorded under `ENV_COUNT_MISMATCH_KEY`.
+    fn environment(&self) -> Option<HashMap<String, String>> {
+        self.env_.as_ref().map(|s| {
+            let mut env = HashMap::new();
+            let Some((prefix, entries)) = env_entries(s) else {
+                warn!("Job {} has a truncated environment file", self.jobid_);
+                return env;
+            };
+            let count = env_count(prefix, entries.len());
+            if count as usize != entries.len() {
+                warn!(
+                    "Job {} has {} environment variables, but its environment file gives {}",
+                    self.jobid_,
+                    entries.len(),
+                    count
+                );
+                env.insert(
+                    ENV_COUNT_MISMATCH_KEY.to_owned(),
+                    format!("expected {count}, found {}", entries.len()),
+                );
+            }
+            let mut lossy = Vec::new();
+            for entry in entries {
+                let (key, value) = match entry.iter().position(|b| *b == b'=') {
+                    Some(i) => (&entry[..i], &entry[i + 1..]),
+                    None => (entry, &entry[entry.len()..]),
+                };
+                let key = String::from_utf8_lossy(key).trim().to_owned();
+                if key.is_empty() {
+                    continue;
+                }
+                if std::str::from_utf8(entry).is_err() {
+                    lossy.push(key.clone());
+                }
+                env.insert(key, String::from_utf8_lossy(value).into_owned());
+            }
+            if !lossy.is_empty() {
+                lossy.sort();
+                warn!(
+                    "Job {} has environment variables that are not valid UTF-8: {}",
+                    self.jobid_,
+                    lossy.join(", ")
+                );
+                env.insert(LOSSY_ENV_KEY.to_owned(), lossy.join(","));
+            }
+            env
+        })
+    }
+
+    /// Returns the working directory of the job, from the `--chdir` directive
+    /// and the directory the job was submitted from, if known
+    fn workdir(&self) -> Option<PathBuf> {
+        let env = self.environment().unwrap_or_default();
+        let submit_dir = lookup(&env, &SUBMIT_DIR_VARIABLES).map(PathBuf::from);
+        match directive(&self.script(), &CHDIR_OPTIONS).map(PathBuf::from) {
+            Some(dir) if dir.is_relative() => submit_dir.map(|d| d.join(dir)),
+            Some(dir) => Some(dir),
+            None => submit_dir,
+        }
+    }
+
+    /// Archives the file the script sources, if requested and allowed,
+    /// relative to the working directory of the job. When it is not, the
+    /// reason is kept instead.
+    fn resolve_sourced(&mut self) {
+        self.sourced_ = None;
+        self.sourced_skipped_ = None;
+        let (Some(max_size), Some(path)) = (self.resolve_sourced, sourced_path(&self.script()))
+        else {
+            return;
+        };
+        let path = match self.workdir() {
+            Some(dir) if path.is_relative() => dir.join(path),
+            None if path.is_relative() => {
+                self.sourced_skipped_ = Some(format!(
+                    "{}: the working directory is unknown",
+                    path.display()
+                ));
+                return;
+            }
+            _ => path,
+        };
+        match read_sourced(&path, max_size) {
+            Ok(contents) => {
+                debug!("Job {} sources {:?}, archiving it", self.jobid_, path);
+                self.sourced_ = Some((path, contents));
+            }
+            Err(reason) => {
+                warn!(
+                    "Job {} sources {:?}, which is not archived: {}",
+                    self.jobid_, path, reason
+                );
+                self.sourced_skipped_ = Some(format!("{}: {}", path.display(), reason));
+            }
+        }
+    }
+
+    /// Returns the expected locations of the stdout and stderr of the job,
+    /// keyed by `STDOUT_KEY` and `STDERR_KEY`. These come from the #SBATCH
+    /// directives or else Slurm's defaults, and are relative to the working
+    /// directory of the job. Locations that cannot be made absolute, because
+    /// the working directory is unknown, are left out.
+    fn output_locations(&self) -> Vec<(&'static str, String)> {
+        let script = self.script();
+        let workdir = self.workdir();
+        let default = if directive(&script, &ARRAY_OPTIONS).is_some() {
+            "slurm-%A_%a.out"
+        } else {
+            "slurm-%j.out"
+        };
+        let stdout = directive(&script, &STDOUT_OPTIONS).unwrap_or(default.to_owned());
+        // Without a separate file, stderr goes to the stdout file
+        let stderr = directive(&script, &STDERR_OPTIONS).unwrap_or(stdout.clone());
+
+        let name = self.job_name().unwrap_or_default();
+        let user = self.user().unwrap_or_default();
+        [(STDOUT_KEY, stdout), (STDERR_KEY, stderr)]
+            .into_iter()
+            .filter_map(|(key, pattern)| {
+                let path =
+                    PathBuf::from(expand_output_pattern(&pattern, &self.jobid_, &name, &user));
+                let path = match &workdir {
+                    _ if path.is_absolute() => path,
+                    Some(dir) => dir.join(path),
+                    None => return None,
+                };
+                Some((key, path.to_string_lossy().into_owned()))
+            })
+            .collect()
+    }
+}
+
+/// Fills in the replacement symbols of an sbatch output file name that are
+/// known at submission: the job ID (`%j`, and `%A` for an array job), the
+/// job name (`%x`) and the user (`%u`), with an optional zero padding width
+/// for the job ID. Others, such as the array task ID (`%a`) or the node name
+/// (`%N`), are kept. As for sbatch, a backslash disables the replacements.
+fn expand_output_pattern(pattern: &str, jobid: &str, name: &str, user: &str) -> String {
+    if pattern.contains('\\') {
+        return pattern.replace('\\', "");
+    }
+    let mut expanded = String::new();
+    let mut chars = pattern.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            expanded.push(c);
+            continue;
+        }
+        let mut width = String::new();
+        while let Some(d) = chars.next_if(|d| d.is_ascii_digit()) {
+            width.push(d);
+        }
+        let padding = width.parse::<usize>().unwrap_or(0);
+        match chars.next() {
+            Some('j' | 'A') => expanded.push_str(&format!("{jobid:0>padding$}")),
+            Some('x') => expanded.push_str(name),
+            Some('u') => expanded.push_str(user),
+            Some('%') => expanded.push('%'),
+            Some(other) => {
+                expanded.push('%');
+                expanded.push_str(&width);
+                expanded.push(other);
+            }
+            None => {
+                expanded.push('%');
+                expanded.push_str(&width);
+            }
+        }
+    }
+    expanded
+}
+
+/// Returns the number of entries the count prefix of an environment file
+/// gives, given the number of entries that follow it. The byte order of the
+/// count follows the host that wrote it, so the order in which it matches the
+/// entries is taken, or else the smaller of both, as a count in the wrong
+/// byte order is far too large.
+fn env_count(prefix: [u8; 4], entries: usize) -> u32 {
+    let (le, be) = (u32::from_le_bytes(prefix), u32::from_be_bytes(prefix));
+    if le as usize == entries {
+        le
+    } else if be as usize == entries {
+        be
+    } else {
+        le.min(be)
+    }
+}
+
+/// Splits the environment file into its count prefix and its entries, the
+/// last of which may lack its terminating NUL byte if the file is truncated.
+/// Returns `None` when the file is too short to hold a count.
+fn env_entries(contents: &[u8]) -> Option<([u8; 4], Vec<&[u8]>)> {
+    let prefix = <[u8; 4]>::try_from(contents.get(..4)?).ok()?;
+    let mut entries: Vec<&[u8]> = contents[4..].split(|b| *b == b'\0').collect();
+    if entries.last().is_some_and(|e| e.is_empty()) {
+        entries.pop();
+    }
+    Some((prefix, entries))
+}
+
+/// Whether the environment file holds as many entries as its count prefix
+/// says, each terminated by a NUL byte
+fn env_complete(contents: &[u8]) -> bool {
+    match env_entries(contents) {
+        Some((prefix, entries)) => {
+            env_count(prefix, entries.len()) as usize == entries.len()
+                && (contents.len() == 4 || contents.ends_with(&[0]))
+        }
+        None => false,
+    }
+}
+
+/// Returns the environment file with each variable passed through the edit,
+/// which gets the name and value and returns the value to keep, or None to
+/// leave the variable out. Entries whose value is kept as it is keep their
+/// bytes, and the count prefix is lowered by the number of variables left
+/// out, in the byte order it was written in. A file too short to hold a count
+/// is returned as it is.
+pub fn edit_environment(
+    contents: &[u8],
+    mut edit: impl FnMut(&str, &str) -> Option<String>,
+) -> Vec<u8> {
+    let Some((prefix, entries)) = env_entries(contents) else {
+        return contents.to_vec();
+    };
+    let count = env_count(prefix, entries.len());
+    let little_endian = u32::from_le_bytes(prefix) == count;
+    let mut edited = Vec::with_capacity(contents.len());
+    let mut dropped = 0;
+    for entry in entries.iter() {
+        let (key, value) = match entry.iter().position(|b| *b == b'=') {
+            Some(i) => (&entry[..i], &entry[i + 1..]),
+            None => (*entry, &entry[entry.len()..]),
+        };
+        let name = String::from_utf8_lossy(key);
+        let value = String::from_utf8_lossy(value);
+        match edit(name.trim(), &value) {
+            Some(kept) if kept == value => edited.extend_from_slice(entry),
+            Some(kept) => {
+                edited.extend_from_slice(key);
+                edited.push(b'=');
+                edited.extend_from_slice(kept.as_bytes());
+            }
+            None => {
+                dropped += 1;
+                continue;
+            }
         }
+        edited.push(b'\0');
+    }
+    let count = count.saturating_sub(dropped);
+    let prefix = if little_endian {
+        count.to_le_bytes()
+    } else {
+        count.to_be_bytes()
+    };
+    [prefix.as_slice(), edited.as_slice()].concat()
+}
+
+/// Verifies the name of a file in the job directory is that of a credential
+/// or GRES file
+fn is_credential_name(name: &str) -> bool {
+    let name = name.to_lowercase();
+    name.contains("cred") || name.contains("gres")
+}
+
+/// Returns the export mode for the given `--export` setting: `none` when
+/// only the Slurm variables (and those given along) reach the job, so its
+/// environment is minimal, `nil` when not even those do, `listed` when only
+/// the listed variables are exported, and `all`, Slurm's default, when the
+/// whole submission environment is.
+fn export_mode(setting: Option<&str>) -> &'static str {
+    let first = setting
+        .and_then(|s| s.split(',').next())
+        .map(|s| s.trim().to_ascii_uppercase())
+        .unwrap_or_default();
+    match first.as_str() {
+        "" | "ALL" => "all",
+        "NONE" => "none",
+        "NIL" => "nil",
+        _ => "listed",
     }
 }
 
-fn filter_env(r: &Option<Regex>, env: &str) -> bool {
-    if let Some(rs) = r {
-        if rs.is_match(env) {
-            return true;
+/// Returns the path of the file the script includes with `source` or `.`, if
+/// it includes exactly one and gives its path literally, i.e., without
+/// variables, substitutions or wildcards
+fn sourced_path(script: &str) -> Option<PathBuf> {
+    let mut sourced = script.lines().filter_map(|line| {
+        let mut words = line.split_whitespace();
+        match words.next()? {
+            "source" | "." => words.next(),
+            _ => None,
         }
+    });
+    let path = sourced.next()?;
+    if sourced.next().is_some() {
+        return None;
+    }
+    let path = path.trim_matches(|c| c == '"' || c == '\'');
+    if path.is_empty() || path.contains(['$', '`', '~', '*', '?', '[']) {
+        return None;
     }
-    false
+    Some(PathBuf::from(path))
+}
+
+/// Checks whether everyone has the permission in `bits` (e.g., 0o4 to read)
+/// on the file or directory. Owner and group permissions are not considered:
+/// the spool does not tell who submitted the job, as the job script belongs
+/// to SlurmUser and the uid in the environment is the user's to set.
+fn permitted(metadata: &fs::Metadata, bits: u32) -> bool {
+    metadata.mode() & bits != 0
+}
+
+/// Checks whether everyone can traverse every directory on the path to the
+/// file, as given and after following symlinks
+fn reachable(path: &Path) -> Result<bool, String> {
+    let canonical = fs::canonicalize(path).map_err(|e| e.to_string())?;
+    let dirs = path
+        .ancestors()
+        .skip(1)
+        .chain(canonical.ancestors().skip(1));
+    for dir in dirs.filter(|dir| !dir.as_os_str().is_empty()) {
+        let metadata = fs::metadata(dir).map_err(|e| e.to_string())?;
+        if !permitted(&metadata, 0o001) {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+/// Reads the file at the given path, following symlinks, if it is a regular
+/// file of at most the given size that anyone can read: it must be readable
+/// by everyone, and each directory leading to it must be searchable by
+/// everyone. As sarchive may read far more than the user of the job, nothing
+/// else is read.
+///
+/// The file is checked before it is opened, and opened without blocking, so
+/// a FIFO or device in its place cannot stall the monitor.
+fn read_sourced(path: &Path, max_size: u64) -> Result<Vec<u8>, String> {
+    if !fs::metadata(path).map_err(|e| e.to_string())?.is_file() {
+        return Err("not a regular file".to_owned());
+    }
+    let file = OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_NONBLOCK)
+        .open(path)
+        .map_err(|e| e.to_string())?;
+    // the path may have been swapped since it was checked
+    let metadata = file.metadata().map_err(|e| e.to_string())?;
+    if !metadata.is_file() {
+        return Err("not a regular file".to_owned());
+    }
+    if !permitted(&metadata, 0o004) {
+        return Err("not readable by everyone".to_owned());
+    }
+    if !reachable(path)? {
+        return Err("not reachable by everyone".to_owned());
+    }
+    if metadata.len() > max_size {
+        return Err(format!("larger than {max_size} bytes"));
+    }
+    let mut contents = Vec::new();
+    file.take(max_size + 1)
+        .read_to_end(&mut contents)
+        .map_err(|e| e.to_string())?;
+    if contents.len() as u64 > max_size {
+        return Err(format!("larger than {max_size} bytes"));
+    }
+    Ok(contents)
 }
 
 impl JobInfo for SlurmJobEntry {
@@ -110,83 +760,227 @@ impl JobInfo for SlurmJobEntry {
         self.moment_
     }
 
+    // Return the wall-clock time of event occurence
+    fn event_time(&self) -> DateTime<Utc> {
+        self.event_time_
+    }
+
     // Return the cluster to which the job was submitted
     fn cluster(&self) -> String {
         self.cluster_.clone()
     }
 
+    /// Returns the path of the job directory
+    fn event_path(&self) -> Option<PathBuf> {
+        Some(self.path_.clone())
+    }
+
     /// Populates the job entry structure with the relevant information
     ///
-    /// For Slurm, this encompasses the job script and the job environment
+    /// For Slurm, this encompasses the job script and the job environment.
+    /// If only one of these files appears in time, we keep what we have and
+    /// remember the missing file, so the entry can be archived partially.
+    /// A file that is not to be captured, or that the rules exclude, is not
+    /// read at all. Credential and GRES files that are present are recorded
+    /// as well, as are the other files the rules include.
     fn read_job_info(&mut self) -> Result<(), Error> {
-        self.script_ = {
-            let mut s = utils::read_file(&self.path_, Path::new("script"), None)?;
-            if let Some(0) = s.last() {
-                s.pop();
-            }
-            Some(s)
-        };
-        self.env_ = Some(utils::read_file(
-            &self.path_,
-            Path::new("environment"),
-            None,
-        )?);
-        Ok(())
+        self.missing_.clear();
+        self.streamed_.clear();
+        self.script_ = None;
+        if self.capture.script() && self.file_rules.keeps("script") {
+            self.script_ = self.read_partial("script")?.map(|mut s| {
+                if let Some(0) = s.last() {
+                    s.pop();
+                }
+                s
+            });
+        }
+        self.env_ = None;
+        if self.capture.environment() && self.file_rules.keeps("environment") {
+            self.env_ = self.read_environment()?;
+        }
+        self.submit_time_ = ["script", "environment"]
+            .iter()
+            .find_map(|f| utils::modified_time(&self.path_.join(f)));
+
+        if self.script_.is_none() && self.env_.is_none() && self.streamed_.is_empty() {
+            return Err(Error::new(
+                ErrorKind::TimedOut,
+                format!("No job files appeared in {:?}", &self.path_),
+            ));
+        }
+        self.resolve_sourced();
+        self.read_credentials()?;
+        self.read_included()
     }
 
     /// Returns a `Vector` with tuples containing the filename and the
-    /// file contents for the script and environment files
+    /// file contents for the script and environment files, for the file the
+    /// script sources if it was archived, for the credential and GRES
+    /// files if their contents were captured, and for the included files
     fn files(&self) -> Vec<(String, Vec<u8>)> {
         [
             ("script", self.script_.as_ref()),
             ("environment", self.env_.as_ref()),
+            ("sourced", self.sourced_.as_ref().map(|(_, c)| c)),
         ]
-        .iter()
+        .into_iter()
+        .chain(
+            self.credentials_
+                .iter()
+                .map(|c| (c.name.as_str(), c.contents.as_ref())),
+        )
+        .chain(self.included_.iter().map(|(n, c)| (n.as_str(), Some(c))))
         .filter_map(|(filename, v)| {
             v.map(|s| (format!("job.{}_{}", self.jobid_, filename), s.to_owned()))
         })
         .collect()
     }
 
-    /// Returns the job script as a `String`
-    fn script(&self) -> String {
-        match &self.script_ {
-            Some(s) => String::from_utf8_lossy(s).to_string(),
-            None => panic!("No script available for job {}", self.jobid_),
+    /// Returns the spool paths of the script and environment files, of the
+    /// credential and GRES files if their contents were captured, and of the
+    /// included files, along with the path of the file the script sources if
+    /// it was archived
+    fn file_sources(&self) -> HashMap<String, PathBuf> {
+        let mut sources: HashMap<String, PathBuf> = [
+            ("script", self.script_.is_some()),
+            ("environment", self.env_.is_some()),
+        ]
+        .into_iter()
+        .chain(
+            self.credentials_
+                .iter()
+                .map(|c| (c.name.as_str(), c.contents.is_some())),
+        )
+        .chain(self.included_.iter().map(|(n, _)| (n.as_str(), true)))
+        .filter(|(_, present)| *present)
+        .map(|(filename, _)| {
+            (
+                format!("job.{}_{}", self.jobid_, filename),
+                self.path_.join(filename),
+            )
+        })
+        .collect();
+        if let Some((path, _)) = &self.sourced_ {
+            sources.insert(format!("job.{}_sourced", self.jobid_), path.clone());
         }
+        sources
+    }
+
+    /// Returns the spool paths of the job files that were too large to be
+    /// read into memory
+    fn streamed_files(&self) -> HashMap<String, PathBuf> {
+        self.streamed_
+            .iter()
+            .map(|filename| {
+                (
+                    format!("job.{}_{}", self.jobid_, filename),
+                    self.path_.join(filename),
+                )
+            })
+            .collect()
+    }
+
+    /// Returns the job script as a `String`, which is empty if the script
+    /// was not captured or was too large to be read into memory
+    fn script(&self) -> String {
+        self.script_
+            .as_ref()
+            .map(|s| String::from_utf8_lossy(s).to_string())
+            .unwrap_or_default()
     }
 
     /// Returns the environment info (if any) as a HashMap, mapping env keys
-    /// to values
+    /// to values. Missing job files
+    /// are listed under `MISSING_FILES_KEY`, those that were too large to be
+    /// read under `STREAMED_FILES_KEY`, the credential and GRES files under
+    /// `CREDENTIAL_FILES_KEY`, the expected stdout and stderr locations
+    /// under `STDOUT_KEY` and `STDERR_KEY`, and, unless the environment is
+    /// missing or truncated, its export mode under `EXPORT_MODE_KEY`.
     fn extra_info(&self) -> Option<HashMap<String, String>> {
-        let r = self.filter_regex.clone();
-        self.env_.as_ref().map(|s| {
-            let env_string = String::from_utf8_lossy(s.split_at(4).1).to_string();
-            env_string
-                .split('\0')
-                .filter_map(|entry| {
-                    let entry = entry.trim();
-                    if !entry.is_empty() {
-                        let parts: Vec<_> = entry.split('=').collect();
-                        match parts.len() {
-                            2 => {
-                                let key = parts[0].trim();
-                                println!("Checking for key {}", &key);
-                                if !key.is_empty() && !filter_env(&r, key) {
-                                    println!("Keeping key {}", &key);
-                                    Some((key.to_owned(), parts[1].to_owned()))
-                                } else {
-                                    None
-                                }
-                            }
-                            _ => Some((entry.to_owned(), String::from(""))),
-                        }
-                    } else {
-                        None
-                    }
-                })
-                .collect::<HashMap<String, String>>()
-        })
+        let mut info = self.environment().map(|mut env| {
+            // sbatch passes --export from the command line or a directive in
+            // the environment, but the directive is checked in case it did not
+            let setting = env
+                .get(EXPORT_VARIABLE)
+                .cloned()
+                .or_else(|| directive(&self.script(), &EXPORT_OPTIONS));
+            if !env.is_empty() {
+                env.insert(
+                    EXPORT_MODE_KEY.to_owned(),
+                    export_mode(setting.as_deref()).to_owned(),
+                );
+            }
+            env
+        });
+        if !self.missing_.is_empty() {
+            info.get_or_insert_with(HashMap::new)
+                .insert(MISSING_FILES_KEY.to_owned(), self.missing_.join(","));
+        }
+        if !self.streamed_.is_empty() {
+            info.get_or_insert_with(HashMap::new)
+                .insert(STREAMED_FILES_KEY.to_owned(), self.streamed_.join(","));
+        }
+        if !self.credentials_.is_empty() {
+            let credentials = self
+                .credentials_
+                .iter()
+                .map(|c| format!("{}:{}", c.name, c.size))
+                .collect::<Vec<_>>()
+                .join(",");
+            info.get_or_insert_with(HashMap::new)
+                .insert(CREDENTIAL_FILES_KEY.to_owned(), credentials);
+        }
+        if let Some((path, _)) = &self.sourced_ {
+            info.get_or_insert_with(HashMap::new)
+                .insert(SOURCED_KEY.to_owned(), path.display().to_string());
+        }
+        if let Some(skipped) = &self.sourced_skipped_ {
+            info.get_or_insert_with(HashMap::new)
+                .insert(SOURCED_SKIPPED_KEY.to_owned(), skipped.clone());
+        }
+        for (key, location) in self.output_locations() {
+            info.get_or_insert_with(HashMap::new)
+                .insert(key.to_owned(), location);
+        }
+        info
+    }
+
+    fn missing_files(&self) -> Vec<String> {
+        self.missing_.clone()
+    }
+
+    /// Returns the time Slurm wrote the job files
+    fn submit_time(&self) -> Option<DateTime<Utc>> {
+        self.submit_time_
+    }
+
+    fn has_environment(&self) -> bool {
+        self.env_.is_some()
+    }
+
+    /// Returns the job name from the environment Slurm recorded, before it
+    /// is reduced for archival, or else from the #SBATCH directives
+    fn job_name(&self) -> Option<String> {
+        self.environment()
+            .and_then(|env| lookup(&env, &JOB_NAME_VARIABLES))
+            .or_else(|| script_job_name(&self.script()))
+    }
+
+    fn user(&self) -> Option<String> {
+        self.environment()
+            .and_then(|env| lookup(&env, &USER_VARIABLES))
+    }
+
+    fn uid(&self) -> Option<String> {
+        self.environment()
+            .and_then(|env| lookup(&env, &UID_VARIABLES))
+    }
+
+    fn partition(&self) -> Option<String> {
+        self.environment()
+            .and_then(|env| lookup(&env, &PARTITION_VARIABLES))
+            .or_else(|| script_partition(&self.script()))
     }
 }
 
@@ -195,7 +989,21 @@ pub struct Slurm {
     /// The absolute path to the spool directory
     pub base: PathBuf,
     pub cluster: String,
-    pub filter_regex: Option<Regex>,
+    /// The events that announce a new job entry
+    pub event_kinds: Vec<JobEvent>,
+    /// Size above which job files are streamed from the spool instead of
+    /// being read into memory
+    pub max_buffered_size: Option<u64>,
+    /// Archive the contents of credential and GRES files in the job
+    /// directories, rather than only their names and sizes
+    pub capture_credentials: bool,
+    /// The job files to read; those left out are not even opened
+    pub capture: Capture,
+    /// Size up to which the file a job script sources is archived as well,
+    /// if at all
+    pub resolve_sourced: Option<u64>,
+    /// Rules on the files in the job directories to capture or leave out
+    pub file_rules: Arc<FileRules>,
 }
 
 impl Slurm {
@@ -210,23 +1018,27 @@ impl Slurm {
     /// # Example
     ///
     /// ```
-    /// # use regex::Regex;
     /// # use std::path::PathBuf;
     /// # use sarchive::scheduler::slurm::{Slurm};
     ///
     /// let base = PathBuf::from("/var/spool/slurm/hash.3/5678");
     ///
-    /// let slurm = Slurm::new(&base, "mycluster", &Regex::new(".*").ok());
+    /// let slurm = Slurm::new(&base, "mycluster");
     ///
     /// assert_eq!(slurm.base, base);
     /// assert_eq!(slurm.cluster, "mycluster");
     /// ```
     ///
-    pub fn new(base: &Path, cluster: &str, filter_regex: &Option<Regex>) -> Slurm {
+    pub fn new(base: &Path, cluster: &str) -> Slurm {
         Slurm {
             base: base.to_path_buf(),
             cluster: cluster.to_string(),
-            filter_regex: filter_regex.clone(),
+            event_kinds: vec![JobEvent::Create],
+            max_buffered_size: None,
+            capture_credentials: false,
+            capture: Capture::All,
+            resolve_sourced: None,
+            file_rules: Arc::new(FileRules::default()),
         }
     }
 }
@@ -234,16 +1046,23 @@ impl Slurm {
 impl Scheduler for Slurm {
     /// Return a `Vector` with the locations that need to be watched.
     ///
-    /// The is the base path + hash.{0..9}
-    ///
-    /// # Arguments
-    ///
-    /// * _matches: reference the ArgMatches in case we pass command line
-    ///             options, which is not done atm.
+    /// These are the hash.* directories present under the base path at
+    /// the time of the call, sorted by name.
     fn watch_locations(&self) -> Vec<PathBuf> {
-        (0..=9)
-            .map(|hash| self.base.join(format!("hash.{hash}")))
-            .collect()
+        match read_dir(&self.base) {
+            Ok(entries) => {
+                let mut locations: Vec<PathBuf> = entries
+                    .filter_map(|entry| entry.ok().map(|e| e.path()))
+                    .filter(|path| is_hash_path(path))
+                    .collect();
+                locations.sort();
+                locations
+            }
+            Err(e) => {
+                error!("Cannot list hash directories in {:?}: {}", &self.base, e);
+                Vec::new()
+            }
+        }
     }
 
     /// Returns a Box wrapping the actual job info data structure.App
@@ -253,31 +1072,97 @@ impl Scheduler for Slurm {
     /// * event_path: A `Path to the job directory that
     fn create_job_info(&self, event_path: &Path) -> Option<Box<dyn JobInfo>> {
         if let Some((jobid, _dirname)) = is_job_path(event_path) {
-            Some(Box::new(SlurmJobEntry::new(
-                event_path,
-                jobid,
-                &self.cluster,
-                &self.filter_regex,
-            )))
+            let mut job_entry = SlurmJobEntry::new(event_path, jobid, &self.cluster);
+            job_entry.max_buffered_size = self.max_buffered_size;
+            job_entry.capture_credentials = self.capture_credentials;
+            job_entry.capture = self.capture;
+            job_entry.resolve_sourced = self.resolve_sourced;
+            job_entry.file_rules = Arc::clone(&self.file_rules);
+            Some(Box::new(job_entry))
         } else {
             None
         }
     }
 
     fn verify_event_kind(&self, event: &Event) -> Option<Vec<PathBuf>> {
+        job_event_paths(event, CreateKind::Folder, &self.event_kinds)
+    }
+
+    /// New hash directories may appear directly under the base path
+    fn discovery_location(&self) -> Option<PathBuf> {
+        Some(self.base.clone())
+    }
+
+    /// Returns the path of a newly created hash directory under the base path
+    fn verify_discovery_event(&self, event: &Event) -> Option<PathBuf> {
         if let Event {
             kind: EventKind::Create(CreateKind::Folder),
             paths,
             ..
         } = event
         {
-            Some(paths.to_vec())
+            paths
+                .iter()
+                .find(|path| path.parent() == Some(self.base.as_path()) && is_hash_path(path))
+                .cloned()
+        } else {
+            None
+        }
+    }
+
+    /// Returns the path of a hash directory that was removed from the base path
+    fn verify_removal_event(&self, event: &Event) -> Option<PathBuf> {
+        if let Event {
+            kind: EventKind::Remove(RemoveKind::Folder),
+            paths,
+            ..
+        } = event
+        {
+            paths
+                .iter()
+                .find(|path| path.parent() == Some(self.base.as_path()) && is_hash_name(path))
+                .cloned()
+        } else {
+            None
+        }
+    }
+
+    /// Returns the path of a job directory that was deleted from a hash
+    /// directory
+    fn verify_job_removal(&self, event: &Event) -> Option<PathBuf> {
+        if let Event {
+            kind: EventKind::Remove(RemoveKind::Folder),
+            paths,
+            ..
+        } = event
+        {
+            paths
+                .iter()
+                .find(|path| {
+                    path.file_name()
+                        .and_then(|name| name.to_str())
+                        .is_some_and(|name| name.starts_with("job."))
+                })
+                .cloned()
         } else {
             None
         }
     }
 }
 
+/// Verifies that the path is a Slurm hash directory, i.e., a directory
+/// named hash.<something>
+fn is_hash_path(path: &Path) -> bool {
+    path.is_dir() && is_hash_name(path)
+}
+
+/// Verifies the last path component is named like a Slurm hash directory
+fn is_hash_name(path: &Path) -> bool {
+    path.file_name()
+        .and_then(|name| name.to_str())
+        .is_some_and(|name| name.starts_with("hash."))
+}
+
 /// Verifies that the path metioned in the event is a that of a file that
 /// needs archival
 ///
@@ -291,11 +1176,11 @@ impl Scheduler for Slurm {
 /// an Option.
 pub fn is_job_path(path: &Path) -> Option<(&str, &str)> {
     if path.is_dir() {
-        let dirname = path.file_name().unwrap().to_str().unwrap();
-
-        if dirname.starts_with("job.") {
-            return Some((path.extension().unwrap().to_str().unwrap(), dirname));
-        };
+        if let Some(dirname) = path.file_name().and_then(|n| n.to_str()) {
+            if let Some(jobid) = dirname.strip_prefix("job.").filter(|id| !id.is_empty()) {
+                return Some((jobid, dirname));
+            }
+        }
     }
     debug!("{:?} is not a considered job path", &path);
     None
@@ -306,9 +1191,75 @@ mod tests {
 
     use super::*;
     use std::env::current_dir;
-    use std::fs::create_dir;
+    use std::ffi::CString;
+    use std::fs::{create_dir, File};
+    use std::os::unix::ffi::OsStrExt;
     use tempfile::tempdir;
 
+    #[test]
+    fn test_watch_locations() {
+        let tdir = tempdir().unwrap();
+        let _dir = create_dir(tdir.path().join("hash.3"));
+        let _dir = create_dir(tdir.path().join("hash.0"));
+        let _dir = create_dir(tdir.path().join("other"));
+        let _file = File::create(tdir.path().join("hash.7"));
+
+        let slurm = Slurm::new(tdir.path(), "mycluster");
+        assert_eq!(
+            slurm.watch_locations(),
+            vec![tdir.path().join("hash.0"), tdir.path().join("hash.3")]
+        );
+    }
+
+    #[test]
+    fn test_verify_discovery_event() {
+        let tdir = tempdir().unwrap();
+        let hashdir = tdir.path().join("hash.4");
+        let _dir = create_dir(&hashdir);
+        let jobdir = hashdir.join("job.1234");
+        let _dir = create_dir(&jobdir);
+
+        let slurm = Slurm::new(tdir.path(), "mycluster");
+        let event = |path: &Path| Event {
+            kind: EventKind::Create(CreateKind::Folder),
+            paths: vec![path.to_path_buf()],
+            ..Default::default()
+        };
+
+        assert_eq!(slurm.discovery_location(), Some(tdir.path().to_path_buf()));
+        assert_eq!(
+            slurm.verify_discovery_event(&event(&hashdir)),
+            Some(hashdir)
+        );
+        assert_eq!(slurm.verify_discovery_event(&event(&jobdir)), None);
+    }
+
+    #[test]
+    fn test_verify_removal_event() {
+        let tdir = tempdir().unwrap();
+        let slurm = Slurm::new(tdir.path(), "mycluster");
+        let event = |path: PathBuf| Event {
+            kind: EventKind::Remove(RemoveKind::Folder),
+            paths: vec![path],
+            ..Default::default()
+        };
+
+        // The directory no longer exists when we get the event
+        let hashdir = tdir.path().join("hash.4");
+        assert_eq!(
+            slurm.verify_removal_event(&event(hashdir.clone())),
+            Some(hashdir.clone())
+        );
+        assert_eq!(
+            slurm.verify_removal_event(&event(hashdir.join("job.1234"))),
+            None
+        );
+        assert_eq!(
+            slurm.verify_removal_event(&event(tdir.path().join("other"))),
+            None
+        );
+    }
+
     #[test]
     fn test_is_job_path() {
         let tdir = tempdir().unwrap();
@@ -322,12 +1273,17 @@ mod tests {
         let fdir = tdir.path().join("fubar");
         let _faildir = create_dir(&fdir);
         assert_eq!(is_job_path(&fdir), None);
+
+        // a job directory without a job ID should fail rather than panic
+        let emptydir = tdir.path().join("job.");
+        let _emptydir = create_dir(&emptydir);
+        assert_eq!(is_job_path(&emptydir), None);
     }
 
     #[test]
     fn test_read_job_script_drop_zero() {
-        let path = PathBuf::from(current_dir().unwrap().join("tests/job.123456"));
-        let mut slurm_job_entry = SlurmJobEntry::new(&path, "123456", "mycluster", &None);
+        let path = current_dir().unwrap().join("tests/job.123456");
+        let mut slurm_job_entry = SlurmJobEntry::new(&path, "123456", "mycluster");
         slurm_job_entry.read_job_info().unwrap();
 
         // check the script
@@ -337,71 +1293,554 @@ mod tests {
 
     #[test]
     fn test_read_job_extra_info() {
-        let path = PathBuf::from(current_dir().unwrap().join("tests/job.123456"));
-        let mut slurm_job_entry = SlurmJobEntry::new(&path, "123456", "mycluster", &None);
+        let path = current_dir().unwrap().join("tests/job.123456");
+        let mut slurm_job_entry = SlurmJobEntry::new(&path, "123456", "mycluster");
         slurm_job_entry.read_job_info().unwrap();
 
         // check the environment information
         if let Some(hm) = slurm_job_entry.extra_info() {
             println!("hm length: {}", hm.len());
-            assert_eq!(hm.len(), 45);
+            assert_eq!(hm.len(), 48);
+            assert_eq!(hm.get(EXPORT_MODE_KEY).unwrap(), "none");
             assert_eq!(hm.get("SLURM_CLUSTERS").unwrap(), "cluster");
+            assert_eq!(
+                hm.get(STDOUT_KEY).unwrap(),
+                "/my/directory/in/some/user0001/thesis/kdld/ParkScene_1920x1080_24/slurm-123456.out"
+            );
+            assert_eq!(hm.get(STDERR_KEY), hm.get(STDOUT_KEY));
             assert_eq!(hm.get("SLURM_NTASKS_PER_NODE").unwrap(), "1");
         } else {
-            assert!(false);
+            panic!("no extra info");
         }
     }
 
+    #[test]
+    fn test_read_job_info_partial() {
+        let tdir = tempdir().unwrap();
+        std::fs::write(tdir.path().join("script"), b"#!/bin/bash\0").unwrap();
+
+        let mut slurm_job_entry = SlurmJobEntry::new(tdir.path(), "1234", "mycluster");
+        slurm_job_entry.read_job_info().unwrap();
+
+        assert_eq!(slurm_job_entry.script(), "#!/bin/bash");
+        assert_eq!(slurm_job_entry.missing_files(), vec!["environment"]);
+        assert_eq!(slurm_job_entry.files().len(), 1);
+
+        let hm = slurm_job_entry.extra_info().unwrap();
+        assert_eq!(hm.len(), 1);
+        assert_eq!(hm.get(MISSING_FILES_KEY).unwrap(), "environment");
+    }
+
+    #[test]
+    fn test_read_job_info_capture() {
+        let tdir = tempdir().unwrap();
+        std::fs::write(tdir.path().join("script"), b"#!/bin/bash\0").unwrap();
+        std::fs::write(tdir.path().join("environment"), b"\0\0\0\0A=1\0").unwrap();
+
+        let mut slurm_job_entry = SlurmJobEntry::new(tdir.path(), "1234", "mycluster");
+        slurm_job_entry.capture = Capture::Script;
+        slurm_job_entry.read_job_info().unwrap();
+        assert_eq!(slurm_job_entry.script(), "#!/bin/bash");
+        assert_eq!(slurm_job_entry.files().len(), 1);
+        assert!(slurm_job_entry.missing_files().is_empty());
+        assert_eq!(slurm_job_entry.extra_info(), None);
+
+        slurm_job_entry.capture = Capture::Environment;
+        slurm_job_entry.read_job_info().unwrap();
+        assert_eq!(slurm_job_entry.script(), "");
+        assert_eq!(slurm_job_entry.files()[0].0, "job.1234_environment");
+        assert_eq!(slurm_job_entry.extra_info().unwrap().get("A").unwrap(), "1");
+    }
+
+    #[test]
+    fn test_sourced_path() {
+        assert_eq!(
+            sourced_path("#!/bin/bash\nsource /apps/setup.sh\nrun\n"),
+            Some(PathBuf::from("/apps/setup.sh"))
+        );
+        assert_eq!(
+            sourced_path("#!/bin/bash\n  . \"env/run.sh\" arg\n"),
+            Some(PathBuf::from("env/run.sh"))
+        );
+        assert_eq!(sourced_path("#!/bin/bash\n# source /apps/setup.sh\n"), None);
+        assert_eq!(sourced_path("source a.sh\nsource b.sh\n"), None);
+        assert_eq!(sourced_path("source $HOME/setup.sh\n"), None);
+        assert_eq!(sourced_path("source ~/setup.sh\n"), None);
+    }
+
+    #[test]
+    fn test_resolve_sourced() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let tdir = tempdir().unwrap();
+        let workdir = tdir.path().join("work");
+        std::fs::create_dir(&workdir).unwrap();
+        let sourced = workdir.join("run.sh");
+        std::fs::write(&sourced, b"module load foss\n").unwrap();
+        std::fs::set_permissions(&sourced, fs::Permissions::from_mode(0o644)).unwrap();
+        std::fs::write(tdir.path().join("script"), b"#!/bin/bash\nsource run.sh\0").unwrap();
+        let environment = |uid: u32| {
+            format!(
+                "\0\0\0\0SLURM_SUBMIT_DIR={}\0SLURM_JOB_UID={uid}\0",
+                workdir.display()
+            )
+        };
+        std::fs::write(tdir.path().join("environment"), environment(4242)).unwrap();
+
+        let mut slurm_job_entry = SlurmJobEntry::new(tdir.path(), "1234", "mycluster");
+        slurm_job_entry.read_job_info().unwrap();
+        assert_eq!(slurm_job_entry.files().len(), 2);
+
+        slurm_job_entry.resolve_sourced = Some(1024);
+        slurm_job_entry.read_job_info().unwrap();
+        let files = slurm_job_entry.files();
+        assert_eq!(files.len(), 3);
+        assert!(files.contains(&(
+            "job.1234_sourced".to_owned(),
+            b"module load foss\n".to_vec()
+        )));
+        assert_eq!(
+            slurm_job_entry.file_sources().get("job.1234_sourced"),
+            Some(&sourced)
+        );
+        let hm = slurm_job_entry.extra_info().unwrap();
+        assert_eq!(hm.get(SOURCED_KEY).unwrap(), &sourced.display().to_string());
+
+        // a file only its owner can read is not archived, whoever owns it, as
+        // neither the script in the spool nor the environment tells who that is
+        std::fs::set_permissions(&sourced, fs::Permissions::from_mode(0o600)).unwrap();
+        std::fs::write(tdir.path().join("environment"), environment(0)).unwrap();
+        slurm_job_entry.read_job_info().unwrap();
+        assert_eq!(slurm_job_entry.files().len(), 2);
+        let hm = slurm_job_entry.extra_info().unwrap();
+        assert_eq!(
+            hm.get(SOURCED_SKIPPED_KEY).unwrap(),
+            &format!("{}: not readable by everyone", sourced.display())
+        );
+        assert_eq!(
+            read_sourced(&sourced, 1024),
+            Err("not readable by everyone".to_owned())
+        );
+
+        // nor is a file behind a directory only its owner can search
+        std::fs::set_permissions(&sourced, fs::Permissions::from_mode(0o644)).unwrap();
+        std::fs::set_permissions(tdir.path(), fs::Permissions::from_mode(0o755)).unwrap();
+        assert!(read_sourced(&sourced, 1024).is_ok());
+        std::fs::set_permissions(&workdir, fs::Permissions::from_mode(0o700)).unwrap();
+        assert_eq!(
+            read_sourced(&sourced, 1024),
+            Err("not reachable by everyone".to_owned())
+        );
+        std::fs::set_permissions(&workdir, fs::Permissions::from_mode(0o755)).unwrap();
+
+        slurm_job_entry.resolve_sourced = Some(4);
+        slurm_job_entry.read_job_info().unwrap();
+        assert_eq!(slurm_job_entry.files().len(), 2);
+        let hm = slurm_job_entry.extra_info().unwrap();
+        assert!(hm
+            .get(SOURCED_SKIPPED_KEY)
+            .unwrap()
+            .ends_with("larger than 4 bytes"));
+    }
+
+    #[test]
+    fn test_read_sourced_fifo() {
+        let tdir = tempdir().unwrap();
+        let path = tdir.path().join("sourced.fifo");
+        let cpath = CString::new(path.as_os_str().as_bytes()).unwrap();
+        assert_eq!(unsafe { libc::mkfifo(cpath.as_ptr(), 0o644) }, 0);
+
+        // Without a writer, opening the FIFO would block forever
+        assert_eq!(
+            read_sourced(&path, 1024),
+            Err("not a regular file".to_owned())
+        );
+    }
+
+    #[test]
+    fn test_env_complete() {
+        let env = |count: u32, entries: &[u8]| {
+            let mut env = count.to_ne_bytes().to_vec();
+            env.extend(entries);
+            env
+        };
+        assert!(env_complete(&env(2, b"A=1\0B=2\0")));
+        assert!(env_complete(&env(0, b"")));
+        assert!(!env_complete(&env(2, b"A=1\0")));
+        assert!(!env_complete(&env(2, b"A=1\0B=")));
+        assert!(!env_complete(b"\0\0"));
+
+        // a file that stays the same is accepted, even if incomplete
+        let tdir = tempdir().unwrap();
+        std::fs::write(tdir.path().join("script"), b"#!/bin/bash\0").unwrap();
+        std::fs::write(tdir.path().join("environment"), env(1, b"A=1")).unwrap();
+        let mut slurm_job_entry = SlurmJobEntry::new(tdir.path(), "1234", "mycluster");
+        slurm_job_entry.read_job_info().unwrap();
+        assert_eq!(slurm_job_entry.env_, Some(env(1, b"A=1")));
+    }
+
+    #[test]
+    fn test_env_count() {
+        assert_eq!(env_count(2u32.to_le_bytes(), 2), 2);
+        assert_eq!(env_count(2u32.to_be_bytes(), 2), 2);
+        assert_eq!(env_count(3u32.to_be_bytes(), 2), 3);
+        assert!(env_complete(b"\0\0\0\x02A=1\0B=2\0"));
+
+        let job_entry = |env: &[u8]| {
+            let mut job_entry = SlurmJobEntry::new(Path::new("/some/path"), "1", "c");
+            job_entry.env_ = Some(env.to_vec());
+            job_entry.environment().unwrap()
+        };
+        let env = job_entry(b"\0\0\0\x02A=1\0B=2\0");
+        assert_eq!(env.len(), 2);
+        let env = job_entry(b"\x03\0\0\0A=1\0B=2\0");
+        assert_eq!(env.len(), 3);
+        assert_eq!(
+            env.get(ENV_COUNT_MISMATCH_KEY).unwrap(),
+            "expected 3, found 2"
+        );
+    }
+
+    #[test]
+    fn test_read_job_info_streamed() {
+        let tdir = tempdir().unwrap();
+        std::fs::write(tdir.path().join("script"), vec![b'x'; 4096]).unwrap();
+        std::fs::write(tdir.path().join("environment"), b"\0\0\0\0A=1\0").unwrap();
+
+        let mut slurm_job_entry = SlurmJobEntry::new(tdir.path(), "1234", "mycluster");
+        slurm_job_entry.max_buffered_size = Some(1024);
+        slurm_job_entry.read_job_info().unwrap();
+
+        assert_eq!(slurm_job_entry.script(), "");
+        assert_eq!(slurm_job_entry.files().len(), 1);
+        assert_eq!(
+            slurm_job_entry.streamed_files(),
+            HashMap::from([("job.1234_script".to_owned(), tdir.path().join("script"))])
+        );
+        assert!(slurm_job_entry.missing_files().is_empty());
+
+        let hm = slurm_job_entry.extra_info().unwrap();
+        assert_eq!(hm.get("A").unwrap(), "1");
+        assert_eq!(hm.get(STREAMED_FILES_KEY).unwrap(), "script");
+    }
+
+    #[test]
+    fn test_read_job_info_credentials() {
+        let tdir = tempdir().unwrap();
+        std::fs::write(tdir.path().join("script"), b"#!/bin/bash\n").unwrap();
+        std::fs::write(tdir.path().join("environment"), b"\0\0\0\0A=1\0").unwrap();
+        std::fs::write(tdir.path().join("cred"), vec![0u8; 512]).unwrap();
+        std::fs::write(tdir.path().join("gres_alloc"), b"gpu:2").unwrap();
+        std::fs::write(tdir.path().join("other"), b"ignored").unwrap();
+
+        let mut slurm_job_entry = SlurmJobEntry::new(tdir.path(), "1234", "mycluster");
+        slurm_job_entry.read_job_info().unwrap();
+
+        let hm = slurm_job_entry.extra_info().unwrap();
+        assert_eq!(
+            hm.get(CREDENTIAL_FILES_KEY).unwrap(),
+            "cred:512,gres_alloc:5"
+        );
+        assert_eq!(slurm_job_entry.files().len(), 2);
+
+        slurm_job_entry.capture_credentials = true;
+        slurm_job_entry.read_job_info().unwrap();
+        let files = slurm_job_entry.files();
+        assert_eq!(files.len(), 4);
+        assert!(files.contains(&("job.1234_gres_alloc".to_owned(), b"gpu:2".to_vec())));
+        assert_eq!(
+            slurm_job_entry.file_sources().get("job.1234_cred"),
+            Some(&tdir.path().join("cred"))
+        );
+
+        // a credential file that went away is left out, as is a dangling link
+        assert!(slurm_job_entry
+            .read_credential("cred_gone")
+            .is_err_and(|e| e.kind() == ErrorKind::NotFound));
+        std::os::unix::fs::symlink(tdir.path().join("gone"), tdir.path().join("cred_link"))
+            .unwrap();
+        slurm_job_entry.read_job_info().unwrap();
+        assert_eq!(slurm_job_entry.files().len(), 4);
+    }
+
+    #[test]
+    fn test_read_job_info_file_rules() {
+        let tdir = tempdir().unwrap();
+        std::fs::write(tdir.path().join("script"), b"#!/bin/bash\n").unwrap();
+        std::fs::write(tdir.path().join("environment"), b"\0\0\0\0A=1\0").unwrap();
+        std::fs::write(tdir.path().join("cred"), vec![0u8; 512]).unwrap();
+        std::fs::write(tdir.path().join("burst_buffer"), b"#DW jobdw").unwrap();
+        std::fs::write(tdir.path().join("burst_buffer.tmp"), b"partial").unwrap();
+        std::fs::write(tdir.path().join("burst_buffer.log"), vec![b'x'; 4096]).unwrap();
+
+        let mut slurm_job_entry = SlurmJobEntry::new(tdir.path(), "1234", "mycluster");
+        slurm_job_entry.file_rules = Arc::new(FileRules::from_globs(
+            &["burst_buffer*"],
+            &["*.tmp", "environment", "cred"],
+        ));
+        slurm_job_entry.max_buffered_size = Some(1024);
+        slurm_job_entry.read_job_info().unwrap();
+
+        let files = slurm_job_entry.files();
+        assert_eq!(files.len(), 2);
+        assert!(files.contains(&("job.1234_script".to_owned(), b"#!/bin/bash\n".to_vec())));
+        assert!(files.contains(&("job.1234_burst_buffer".to_owned(), b"#DW jobdw".to_vec())));
+        assert_eq!(
+            slurm_job_entry.file_sources().get("job.1234_burst_buffer"),
+            Some(&tdir.path().join("burst_buffer"))
+        );
+        // the large included file is streamed from the spool
+        assert_eq!(
+            slurm_job_entry.streamed_files(),
+            HashMap::from([(
+                "job.1234_burst_buffer.log".to_owned(),
+                tdir.path().join("burst_buffer.log")
+            )])
+        );
+        // neither the environment nor the credential file
+        let hm = slurm_job_entry.extra_info().unwrap();
+        assert_eq!(hm.len(), 1);
+        assert_eq!(hm.get(STREAMED_FILES_KEY).unwrap(), "burst_buffer.log");
+
+        // an included file that went away after the job directory was
+        // listed is left out, not the job
+        assert!(slurm_job_entry
+            .read_included_file("burst_buffer.gone".to_owned())
+            .is_err());
+        std::fs::remove_file(tdir.path().join("burst_buffer.log")).unwrap();
+        slurm_job_entry.read_job_info().unwrap();
+        assert_eq!(slurm_job_entry.files().len(), 2);
+        assert!(slurm_job_entry.streamed_files().is_empty());
+    }
+
+    #[test]
+    fn test_output_locations() {
+        let tdir = tempdir().unwrap();
+        std::fs::write(
+            tdir.path().join("script"),
+            b"#!/bin/bash\n#SBATCH -J sim\n#SBATCH --output=logs/%x-%8j.out\n#SBATCH -e /scratch/%u/%A_%a.err\n#SBATCH --array=1-4\n",
+        )
+        .unwrap();
+        std::fs::write(
+            tdir.path().join("environment"),
+            b"\0\0\0\0SLURM_SUBMIT_DIR=/home/jdoe\0USER=jdoe\0",
+        )
+        .unwrap();
+
+        let mut slurm_job_entry = SlurmJobEntry::new(tdir.path(), "1234", "mycluster");
+        slurm_job_entry.read_job_info().unwrap();
+        let hm = slurm_job_entry.extra_info().unwrap();
+        assert_eq!(
+            hm.get(STDOUT_KEY).unwrap(),
+            "/home/jdoe/logs/sim-00001234.out"
+        );
+        assert_eq!(hm.get(STDERR_KEY).unwrap(), "/scratch/jdoe/1234_%a.err");
+
+        // without a working directory, relative locations are left out
+        std::fs::write(tdir.path().join("environment"), b"\0\0\0\0USER=jdoe\0").unwrap();
+        slurm_job_entry.read_job_info().unwrap();
+        let hm = slurm_job_entry.extra_info().unwrap();
+        assert_eq!(hm.get(STDOUT_KEY), None);
+        assert_eq!(hm.get(STDERR_KEY).unwrap(), "/scratch/jdoe/1234_%a.err");
+    }
+
+    #[test]
+    fn test_export_mode() {
+        assert_eq!(export_mode(None), "all");
+        assert_eq!(export_mode(Some("ALL,EXTRA=1")), "all");
+        assert_eq!(export_mode(Some("NONE")), "none");
+        assert_eq!(export_mode(Some("none,OMP_NUM_THREADS=4")), "none");
+        assert_eq!(export_mode(Some("NIL")), "nil");
+        assert_eq!(export_mode(Some("PATH,HOME")), "listed");
+
+        let tdir = tempdir().unwrap();
+        std::fs::write(
+            tdir.path().join("script"),
+            b"#!/bin/bash\n#SBATCH --export=NONE\n",
+        )
+        .unwrap();
+        std::fs::write(
+            tdir.path().join("environment"),
+            b"\0\0\0\0SLURM_EXPORT_ENV=PATH\0PATH=/usr/bin\0",
+        )
+        .unwrap();
+        let mut slurm_job_entry = SlurmJobEntry::new(tdir.path(), "1234", "mycluster");
+        slurm_job_entry.read_job_info().unwrap();
+        let hm = slurm_job_entry.extra_info().unwrap();
+        assert_eq!(hm.get(EXPORT_MODE_KEY).unwrap(), "listed");
+
+        // the directive counts when sbatch did not pass the setting
+        std::fs::write(
+            tdir.path().join("environment"),
+            b"\0\0\0\0SLURM_JOB_ID=1234\0",
+        )
+        .unwrap();
+        slurm_job_entry.read_job_info().unwrap();
+        let hm = slurm_job_entry.extra_info().unwrap();
+        assert_eq!(hm.get(EXPORT_MODE_KEY).unwrap(), "none");
+
+        // an environment that was not captured has no export mode
+        std::fs::remove_file(tdir.path().join("environment")).unwrap();
+        let mut slurm_job_entry = SlurmJobEntry::new(tdir.path(), "1234", "mycluster");
+        slurm_job_entry.read_job_info().unwrap();
+        let hm = slurm_job_entry.extra_info().unwrap();
+        assert_eq!(hm.get(EXPORT_MODE_KEY), None);
+    }
+
+    #[test]
+    fn test_expand_output_pattern() {
+        assert_eq!(
+            expand_output_pattern("slurm-%j.out", "42", "n", "u"),
+            "slurm-42.out"
+        );
+        assert_eq!(
+            expand_output_pattern("%x_%u_%4A_%a_%N%%", "42", "n", "u"),
+            "n_u_0042_%a_%N%"
+        );
+        assert_eq!(expand_output_pattern("out\\%j", "42", "n", "u"), "out%j");
+    }
+
+    #[test]
+    fn test_is_credential_name() {
+        assert!(is_credential_name("cred"));
+        assert!(is_credential_name("job_cred"));
+        assert!(is_credential_name("GRES"));
+        assert!(!is_credential_name("script"));
+        assert!(!is_credential_name("environment"));
+    }
+
+    #[test]
+    fn test_read_job_info_nothing() {
+        let tdir = tempdir().unwrap();
+        let mut slurm_job_entry = SlurmJobEntry::new(tdir.path(), "1234", "mycluster");
+
+        let err = slurm_job_entry.read_job_info().unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::TimedOut);
+    }
+
     #[test]
     fn test_extra_info_drop_u32_prefix() {
-        let path = PathBuf::from(current_dir().unwrap().join("tests/job.8897161"));
-        let mut slurm_job_entry = SlurmJobEntry::new(&path, "8897161", "mycluster", &None);
+        let path = current_dir().unwrap().join("tests/job.8897161");
+        let mut slurm_job_entry = SlurmJobEntry::new(&path, "8897161", "mycluster");
         if let Err(e) = slurm_job_entry.read_job_info() {
-            println!("Could not read job info: {:?}", e);
-            assert!(false);
+            panic!("Could not read job info: {:?}", e);
         }
 
-        if let Some(_hm) = slurm_job_entry.extra_info() {
-        } else {
-            assert!(false);
-        }
+        assert!(slurm_job_entry.extra_info().is_some());
+    }
+
+    #[test]
+    fn test_extra_info_truncated_environment() {
+        let tdir = tempdir().unwrap();
+        std::fs::write(tdir.path().join("script"), b"#!/bin/bash\n").unwrap();
+        std::fs::write(tdir.path().join("environment"), b"\0\0").unwrap();
+        let mut slurm_job_entry = SlurmJobEntry::new(tdir.path(), "1234", "mycluster");
+        slurm_job_entry.read_job_info().unwrap();
+
+        let hm = slurm_job_entry.extra_info().unwrap();
+        assert!(hm.is_empty());
     }
 
     #[test]
     fn test_extra_info() {
         let env_data = b"\0\0\0\0VAR1=value1\0VAR2=value2\0VAR3=value3\0";
-        let filter_regex = Regex::new("VAR[12]").ok();
 
         let job_entry = SlurmJobEntry {
             path_: PathBuf::from("/some/path"),
             jobid_: "12345".to_string(),
             cluster_: "mycluster".to_string(),
             moment_: Instant::now(),
+            event_time_: Utc::now(),
             script_: None,
             env_: Some(env_data.to_vec()),
-            filter_regex,
+            missing_: Vec::new(),
+            max_buffered_size: None,
+            streamed_: Vec::new(),
+            submit_time_: None,
+            capture_credentials: false,
+            credentials_: Vec::new(),
+            capture: Capture::All,
+            resolve_sourced: None,
+            sourced_: None,
+            sourced_skipped_: None,
+            file_rules: Arc::new(FileRules::default()),
+            included_: Vec::new(),
         };
 
         let extra_info = job_entry.extra_info().unwrap();
 
-        assert_eq!(extra_info.get("VAR1"), None);
-        assert_eq!(extra_info.get("VAR2"), None);
+        assert_eq!(extra_info.get("VAR1"), Some(&"value1".to_string()));
+        assert_eq!(extra_info.get("VAR2"), Some(&"value2".to_string()));
         assert_eq!(extra_info.get("VAR3"), Some(&"value3".to_string()));
     }
 
     #[test]
-    fn test_filter_env() {
-        let regex = Regex::new("VAR.*").ok();
-        assert!(filter_env(&regex, "VAR1"));
-        assert!(filter_env(&regex, "VAR2"));
-        assert!(!filter_env(&regex, "OTHER"));
+    fn test_extra_info_values_and_invalid_utf8() {
+        let env_data = b"\0\0\0\0OPTS=--a=1 --b=2\0EMPTY=\0BAD=caf\xe9\0NOVALUE\0";
+
+        let job_entry = SlurmJobEntry {
+            path_: PathBuf::from("/some/path"),
+            jobid_: "12345".to_string(),
+            cluster_: "mycluster".to_string(),
+            moment_: Instant::now(),
+            event_time_: Utc::now(),
+            script_: None,
+            env_: Some(env_data.to_vec()),
+            missing_: Vec::new(),
+            max_buffered_size: None,
+            streamed_: Vec::new(),
+            submit_time_: None,
+            capture_credentials: false,
+            credentials_: Vec::new(),
+            capture: Capture::All,
+            resolve_sourced: None,
+            sourced_: None,
+            sourced_skipped_: None,
+            file_rules: Arc::new(FileRules::default()),
+            included_: Vec::new(),
+        };
+
+        let extra_info = job_entry.extra_info().unwrap();
+
+        assert_eq!(extra_info.get("OPTS").unwrap(), "--a=1 --b=2");
+        assert_eq!(extra_info.get("EMPTY").unwrap(), "");
+        assert_eq!(extra_info.get("NOVALUE").unwrap(), "");
+        assert_eq!(extra_info.get("BAD").unwrap(), "caf\u{fffd}");
+        assert_eq!(extra_info.get(LOSSY_ENV_KEY).unwrap(), "BAD");
     }
 
     #[test]
-    fn test_filter_env_none() {
-        let regex = None;
-        assert!(!filter_env(&regex, "VAR1"));
-        assert!(!filter_env(&regex, "VAR2"));
-        assert!(!filter_env(&regex, "OTHER"));
+    fn test_job_attributes() {
+        let env_data = b"\0\0\0\0USER=alice\0SLURM_JOB_NAME=train\0";
+
+        let mut job_entry = SlurmJobEntry::new(Path::new("/some/path"), "12345", "mycluster");
+        job_entry.env_ = Some(env_data.to_vec());
+        job_entry.script_ = Some(b"#!/bin/bash\n#SBATCH --partition=gpu\n".to_vec());
+
+        assert!(job_entry.has_environment());
+        assert_eq!(job_entry.user(), Some("alice".to_owned()));
+        assert_eq!(job_entry.job_name(), Some("train".to_owned()));
+        assert_eq!(job_entry.partition(), Some("gpu".to_owned()));
+    }
+
+    #[test]
+    fn test_edit_environment() {
+        let edit = |key: &str, value: &str| match key {
+            "TOKEN" => Some("[redacted]".to_owned()),
+            "SHELL" => None,
+            _ => Some(value.to_owned()),
+        };
+        let edited = edit_environment(
+            b"\x03\0\0\0TOKEN=s3cr3t\0SHELL=/bin/bash\0BAD=caf\xe9\0",
+            edit,
+        );
+        assert_eq!(edited, b"\x02\0\0\0TOKEN=[redacted]\0BAD=caf\xe9\0");
+
+        // the count keeps its byte order, and a mismatch stays one
+        let edited = edit_environment(b"\0\0\0\x02SHELL=/bin/bash\0HOME=/home/a\0", edit);
+        assert_eq!(edited, b"\0\0\0\x01HOME=/home/a\0");
+        let edited = edit_environment(b"\x05\0\0\0SHELL=/bin/bash\0HOME=/home/a\0", edit);
+        assert_eq!(edited, b"\x04\0\0\0HOME=/home/a\0");
+        assert_eq!(edit_environment(b"\0\0", edit), b"\0\0");
     }
 }
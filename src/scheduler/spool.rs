@@ -0,0 +1,342 @@
+/*
+Copyright 2019-2024 Andy Georges <itkovian+sarchive@gmail.com>
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in
+all copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+use chrono::{DateTime, Utc};
+use notify::event::Event;
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+use super::job::{Accelerators, JobInfo};
+use super::Scheduler;
+use crate::identity::hostname;
+
+/// Extra info key holding the spool root a job was found in, when watching
+/// several roots
+pub const SPOOL_KEY: &str = "sarchive_spool";
+
+/// Placeholders that may be used in spool paths
+const CLUSTER_PLACEHOLDER: &str = "{cluster}";
+const HOSTNAME_PLACEHOLDER: &str = "{hostname}";
+
+/// Returns the spool roots to watch, filling in the cluster and the name of
+/// this host in the given paths, and dropping repeated roots. Fails when a
+/// path contains another placeholder, or the cluster placeholder while the
+/// cluster is not known.
+pub fn spool_roots(templates: &[PathBuf], cluster: Option<&str>) -> Result<Vec<PathBuf>, Error> {
+    let mut roots: Vec<PathBuf> = Vec::new();
+    for template in templates {
+        let mut root = template.to_string_lossy().into_owned();
+        if root.contains(HOSTNAME_PLACEHOLDER) {
+            root = root.replace(HOSTNAME_PLACEHOLDER, &hostname());
+        }
+        if root.contains(CLUSTER_PLACEHOLDER) {
+            let Some(cluster) = cluster else {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    format!(
+                        "Spool {template:?} contains {CLUSTER_PLACEHOLDER}, but no cluster is set"
+                    ),
+                ));
+            };
+            root = root.replace(CLUSTER_PLACEHOLDER, cluster);
+        }
+        if root.contains(['{', '}']) {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "Unsupported placeholder in spool {template:?}, only {CLUSTER_PLACEHOLDER} and {HOSTNAME_PLACEHOLDER} are allowed"
+                ),
+            ));
+        }
+        let root = PathBuf::from(root);
+        if !roots.contains(&root) {
+            roots.push(root);
+        }
+    }
+    Ok(roots)
+}
+
+/// The scheduler for one of several spool roots. It only creates the jobs
+/// found under its root, so the job entries saved by an earlier run can be
+/// handed to each root in turn, and, if asked to, tags them with the root.
+pub struct SpoolRoot {
+    root: PathBuf,
+    inner: Box<dyn Scheduler>,
+    tag: bool,
+}
+
+impl SpoolRoot {
+    pub fn new(root: &Path, inner: Box<dyn Scheduler>, tag: bool) -> Self {
+        SpoolRoot {
+            root: root.to_path_buf(),
+            inner,
+            tag,
+        }
+    }
+
+    fn wrap(&self, job: Box<dyn JobInfo>) -> Box<dyn JobInfo> {
+        if !self.tag {
+            return job;
+        }
+        Box::new(TaggedJob {
+            inner: job,
+            root: self.root.to_string_lossy().into_owned(),
+        })
+    }
+}
+
+impl Scheduler for SpoolRoot {
+    fn watch_locations(&self) -> Vec<PathBuf> {
+        self.inner.watch_locations()
+    }
+
+    fn create_job_info(&self, event_path: &Path) -> Option<Box<dyn JobInfo>> {
+        if !event_path.starts_with(&self.root) {
+            return None;
+        }
+        self.inner
+            .create_job_info(event_path)
+            .map(|job| self.wrap(job))
+    }
+
+    fn verify_event_kind(&self, event: &Event) -> Option<Vec<PathBuf>> {
+        self.inner.verify_event_kind(event)
+    }
+
+    fn discovery_location(&self) -> Option<PathBuf> {
+        self.inner.discovery_location()
+    }
+
+    fn verify_discovery_event(&self, event: &Event) -> Option<PathBuf> {
+        self.inner.verify_discovery_event(event)
+    }
+
+    fn verify_removal_event(&self, event: &Event) -> Option<PathBuf> {
+        self.inner.verify_removal_event(event)
+    }
+
+    fn verify_job_removal(&self, event: &Event) -> Option<PathBuf> {
+        self.inner.verify_job_removal(event)
+    }
+
+    fn deletion_entry(&self, path: &Path) -> Option<Box<dyn JobInfo>> {
+        if !path.starts_with(&self.root) {
+            return None;
+        }
+        self.inner.deletion_entry(path).map(|job| self.wrap(job))
+    }
+}
+
+/// A job with the spool root it was found in added to its extra info
+struct TaggedJob {
+    inner: Box<dyn JobInfo>,
+    root: String,
+}
+
+impl JobInfo for TaggedJob {
+    fn jobid(&self) -> String {
+        self.inner.jobid()
+    }
+
+    fn moment(&self) -> Instant {
+        self.inner.moment()
+    }
+
+    fn event_time(&self) -> DateTime<Utc> {
+        self.inner.event_time()
+    }
+
+    fn submit_time(&self) -> Option<DateTime<Utc>> {
+        self.inner.submit_time()
+    }
+
+    fn cluster(&self) -> String {
+        self.inner.cluster()
+    }
+
+    fn event_path(&self) -> Option<PathBuf> {
+        self.inner.event_path()
+    }
+
+    fn read_job_info(&mut self) -> Result<(), Error> {
+        self.inner.read_job_info()
+    }
+
+    fn files(&self) -> Vec<(String, Vec<u8>)> {
+        self.inner.files()
+    }
+
+    fn file_sources(&self) -> HashMap<String, PathBuf> {
+        self.inner.file_sources()
+    }
+
+    fn streamed_files(&self) -> HashMap<String, PathBuf> {
+        self.inner.streamed_files()
+    }
+
+    fn script(&self) -> String {
+        self.inner.script()
+    }
+
+    fn expand(&self) -> Vec<Box<dyn JobInfo>> {
+        self.inner
+            .expand()
+            .into_iter()
+            .map(|job| {
+                Box::new(TaggedJob {
+                    inner: job,
+                    root: self.root.clone(),
+                }) as Box<dyn JobInfo>
+            })
+            .collect()
+    }
+
+    fn extra_info(&self) -> Option<HashMap<String, String>> {
+        let mut extra = self.inner.extra_info().unwrap_or_default();
+        extra.insert(SPOOL_KEY.to_owned(), self.root.clone());
+        Some(extra)
+    }
+
+    fn missing_files(&self) -> Vec<String> {
+        self.inner.missing_files()
+    }
+
+    fn has_environment(&self) -> bool {
+        self.inner.has_environment()
+    }
+
+    fn job_name(&self) -> Option<String> {
+        self.inner.job_name()
+    }
+
+    fn user(&self) -> Option<String> {
+        self.inner.user()
+    }
+
+    fn uid(&self) -> Option<String> {
+        self.inner.uid()
+    }
+
+    fn partition(&self) -> Option<String> {
+        self.inner.partition()
+    }
+
+    fn accelerators(&self) -> Accelerators {
+        self.inner.accelerators()
+    }
+
+    fn tombstone_event(&self) -> String {
+        self.inner.tombstone_event()
+    }
+
+    fn is_deletion(&self) -> bool {
+        self.inner.is_deletion()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scheduler::slurm::Slurm;
+    use crate::scheduler::torque::{Torque, TorqueArgs};
+    use clap::Parser;
+    use std::env::current_dir;
+
+    #[test]
+    fn test_spool_roots() {
+        let templates = [
+            PathBuf::from("/var/spool/{cluster}/slurm"),
+            PathBuf::from("/var/spool/mycluster/slurm"),
+            PathBuf::from("/spool/{hostname}"),
+        ];
+        let roots = spool_roots(&templates, Some("mycluster")).unwrap();
+        assert_eq!(
+            roots,
+            vec![
+                PathBuf::from("/var/spool/mycluster/slurm"),
+                PathBuf::from(format!("/spool/{}", hostname())),
+            ]
+        );
+
+        assert!(spool_roots(&templates, None).is_err());
+        assert!(spool_roots(&[PathBuf::from("/spool/{user}")], Some("c")).is_err());
+    }
+
+    #[test]
+    fn test_spool_root() {
+        let spool = current_dir().unwrap().join("tests");
+        let event_path = spool.join("job.123456");
+        let root = |tag: bool| {
+            let slurm = Slurm::new(&spool, "mycluster");
+            SpoolRoot::new(&spool, Box::new(slurm), tag)
+        };
+
+        let mut job = root(false).create_job_info(&event_path).unwrap();
+        job.read_job_info().unwrap();
+        let untagged = job.extra_info().unwrap();
+        assert!(!untagged.contains_key(SPOOL_KEY));
+
+        let mut job = root(true).create_job_info(&event_path).unwrap();
+        job.read_job_info().unwrap();
+        assert_eq!(job.jobid(), "123456");
+        let mut extra = job.extra_info().unwrap();
+        assert_eq!(extra.remove(SPOOL_KEY).unwrap(), spool.to_string_lossy());
+        assert_eq!(extra, untagged);
+
+        // jobs of other roots are left to their own scheduler
+        let other = current_dir().unwrap().join("other/job.123456");
+        assert!(root(true).create_job_info(&other).is_none());
+    }
+
+    #[test]
+    fn test_spool_root_deletion() {
+        let tdir = tempfile::tempdir().unwrap();
+        #[derive(Parser)]
+        struct Cli {
+            #[command(flatten)]
+            torque: TorqueArgs,
+        }
+        let args = Cli::parse_from(["torque", "--torque-deletions"]).torque;
+        let root = |tag: bool| {
+            let torque = Torque::new(tdir.path(), "mycluster", &args);
+            SpoolRoot::new(tdir.path(), Box::new(torque), tag)
+        };
+        let script = tdir.path().join("0/12.master.SC");
+
+        let deletion = root(false).deletion_entry(&script).unwrap();
+        assert!(deletion.is_deletion());
+        assert_eq!(deletion.jobid(), "12.master");
+
+        // a tagged deletion still only gets a tombstone
+        let deletion = root(true).deletion_entry(&script).unwrap();
+        assert!(deletion.is_deletion());
+        assert_eq!(
+            deletion.extra_info().unwrap().get(SPOOL_KEY).unwrap(),
+            &tdir.path().to_string_lossy()
+        );
+        assert!(deletion.accelerators().is_empty());
+
+        let other = current_dir().unwrap().join("other/12.master.SC");
+        assert!(root(true).deletion_entry(&other).is_none());
+    }
+}
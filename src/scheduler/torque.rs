@@ -19,24 +19,158 @@ LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
 OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
 SOFTWARE.
 */
+use chrono::{DateTime, Utc};
 use clap::Args;
-use glob::glob;
-use log::debug;
-use notify::event::{CreateKind, Event, EventKind};
+use glob::{glob, Pattern};
+use log::{debug, info, warn};
+use notify::event::{AccessKind, AccessMode, CreateKind, Event, EventKind, RemoveKind};
+use quick_xml::events::{BytesStart, Event as XmlEvent};
+use quick_xml::Reader;
+use serde_json::{Map, Value};
 use std::collections::HashMap;
-use std::io::Error;
+use std::fs::File;
+use std::io::{Error, ErrorKind, Read, Seek, SeekFrom};
+use std::os::unix::fs::MetadataExt;
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
 use std::time::Instant;
 
-use super::job::JobInfo;
-use super::Scheduler;
+use super::job::{JobInfo, DELETED_EVENT};
+use super::rules::FileRules;
+use super::slurm::STREAMED_FILES_KEY;
+use super::{job_event_paths, JobEvent, Scheduler};
 
 use crate::utils;
 
 /// Arguments for the Torque scheduler command
 #[derive(Args, Debug)]
 pub struct TorqueArgs {
-    subdirs: bool,
+    #[arg(long = "torque-subdirs")]
+    pub subdirs: bool,
+
+    #[arg(
+        long = "torque-jb-json",
+        help = "Convert the XML contents of the .JB files to JSON in the job's extra info"
+    )]
+    pub jb_json: bool,
+
+    #[arg(
+        long = "torque-expand-arrays",
+        help = "Archive every task of an array job as a separate job entry"
+    )]
+    pub expand_arrays: bool,
+
+    #[arg(
+        long = "torque-script-suffix",
+        default_value = "SC",
+        help = "Suffix of the job script files, after the job ID and a dot"
+    )]
+    pub script_suffix: String,
+
+    #[arg(
+        long = "torque-jb-suffix",
+        default_value = "JB",
+        help = "Suffix of the job description files"
+    )]
+    pub jb_suffix: String,
+
+    #[arg(
+        long = "torque-ta-suffix",
+        default_value = "TA",
+        help = "Suffix of the job array files"
+    )]
+    pub ta_suffix: String,
+
+    #[arg(
+        long = "torque-output-spool",
+        help = "Also archive the .OU and .ER output files of jobs from this spool when they complete"
+    )]
+    pub output_spool: Option<PathBuf>,
+
+    #[arg(
+        long = "torque-output-max-size",
+        default_value_t = 1048576,
+        help = "Keep only the last part of output files larger than this, in bytes"
+    )]
+    pub output_max_size: u64,
+
+    #[arg(
+        long = "torque-deletions",
+        help = "Archive a tombstone with the event deleted when the script file of a job is removed from the spool"
+    )]
+    pub deletions: bool,
+
+    #[arg(
+        long = "torque-accounting",
+        value_name = "DIR",
+        help = "Directory of the Torque accounting logs (e.g., server_priv/accounting) to follow, sending the start and end of archived jobs"
+    )]
+    pub accounting: Option<PathBuf>,
+
+    #[arg(
+        long = "torque-include",
+        value_name = "GLOB",
+        help = "Also capture the other files in the spool named after the job (e.g., its ID followed by a suffix) whose name matches this glob (can be repeated)"
+    )]
+    pub include: Vec<Pattern>,
+
+    #[arg(
+        long = "torque-exclude",
+        value_name = "GLOB",
+        help = "Leave out the .TA and .JB files whose name matches this glob, and the included files that do (can be repeated)"
+    )]
+    pub exclude: Vec<Pattern>,
+}
+
+/// The suffixes of the job output files in the spool, for stdout and stderr
+const OUTPUT_SUFFIXES: [&str; 2] = ["OU", "ER"];
+
+/// Key in the extra info of an output file that was truncated, with the
+/// size of the full file
+pub const OUTPUT_TRUNCATED_KEY: &str = "sarchive_output_truncated";
+
+/// The suffixes of the files Torque keeps for a job, which differ between
+/// Torque variants and builds
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Suffixes {
+    /// The job script
+    pub script: String,
+    /// The job description, one per task for an array job
+    pub jb: String,
+    /// The array description
+    pub ta: String,
+}
+
+impl Default for Suffixes {
+    fn default() -> Self {
+        Suffixes {
+            script: "SC".to_owned(),
+            jb: "JB".to_owned(),
+            ta: "TA".to_owned(),
+        }
+    }
+}
+
+impl Suffixes {
+    /// Returns the suffixes given on the command line, without leading dots
+    pub fn from_args(args: &TorqueArgs) -> Suffixes {
+        let trim = |s: &str| s.trim_start_matches('.').to_owned();
+        Suffixes {
+            script: trim(&args.script_suffix),
+            jb: trim(&args.jb_suffix),
+            ta: trim(&args.ta_suffix),
+        }
+    }
+
+    /// Returns the `.JB` suffix, including the dot
+    fn jb(&self) -> String {
+        format!(".{}", self.jb)
+    }
+
+    /// Returns the `.TA` suffix, including the dot
+    fn ta(&self) -> String {
+        format!(".{}", self.ta)
+    }
 }
 
 pub struct TorqueJobEntry {
@@ -51,22 +185,72 @@ pub struct TorqueJobEntry {
     cluster_: String,
     /// Time of event notification and instance creation
     moment_: Instant,
+    /// Wall-clock time of event notification
+    event_time_: DateTime<Utc>,
     /// The actual job script
     script_: Option<Vec<u8>>,
     /// Additional info for the job
     env_: HashMap<String, Vec<u8>>,
+    /// Convert the .JB XML to JSON when providing the extra info
+    jb_json: bool,
+    /// Modification time of the script file
+    submit_time_: Option<DateTime<Utc>>,
+    /// Archive the tasks of an array job separately
+    expand_arrays: bool,
+    /// The suffixes of the job files
+    suffixes: Suffixes,
+    /// Rules on the job files to capture or leave out
+    file_rules: Arc<FileRules>,
+    /// Size above which included files are streamed from the spool instead
+    /// of being read into memory
+    max_buffered_size: Option<u64>,
+    /// The included files that were too large to be read into memory
+    streamed_: Vec<String>,
 }
 
 impl TorqueJobEntry {
-    fn new(p: &Path, id: &str, cluster: &str) -> TorqueJobEntry {
+    fn new(p: &Path, id: &str, cluster: &str, jb_json: bool) -> TorqueJobEntry {
         TorqueJobEntry {
             path_: p.to_path_buf(),
             jobname_: None,
             cluster_: cluster.to_string(),
             jobid_: id.to_owned(),
             moment_: Instant::now(),
+            event_time_: Utc::now(),
             script_: None,
             env_: HashMap::new(),
+            jb_json,
+            submit_time_: None,
+            expand_arrays: false,
+            suffixes: Suffixes::default(),
+            file_rules: Arc::new(FileRules::default()),
+            max_buffered_size: None,
+            streamed_: Vec::new(),
+        }
+    }
+
+    /// Returns a job entry for the array task described by the given .JB
+    /// file, sharing the script with the array job
+    fn array_task(&self, jb_filename: &str, jb: &[u8]) -> TorqueJobEntry {
+        let task_id = jb_filename
+            .strip_suffix(&self.suffixes.jb())
+            .unwrap_or(jb_filename);
+        TorqueJobEntry {
+            path_: self.path_.clone(),
+            jobname_: self.jobname_.clone(),
+            jobid_: task_id.to_owned(),
+            cluster_: self.cluster_.clone(),
+            moment_: self.moment_,
+            event_time_: self.event_time_,
+            script_: self.script_.clone(),
+            env_: HashMap::from([(jb_filename.to_owned(), jb.to_vec())]),
+            jb_json: self.jb_json,
+            submit_time_: self.submit_time_,
+            expand_arrays: false,
+            suffixes: self.suffixes.clone(),
+            file_rules: Arc::clone(&self.file_rules),
+            max_buffered_size: self.max_buffered_size,
+            streamed_: Vec::new(),
         }
     }
 }
@@ -81,62 +265,109 @@ impl JobInfo for TorqueJobEntry {
         self.moment_
     }
 
+    // Return the wall-clock time of event occurence
+    fn event_time(&self) -> DateTime<Utc> {
+        self.event_time_
+    }
+
     // Return the cluster to which the job was submitted
     fn cluster(&self) -> String {
         self.cluster_.clone()
     }
 
+    /// Returns the path of the script file
+    fn event_path(&self) -> Option<PathBuf> {
+        Some(self.path_.clone())
+    }
+
     // Retrieve all the information for the job from the spool location
     // This fills up the required data structures to be able to write
-    // the backup or ship the information to some consumer
+    // the backup or ship the information to some consumer. The .TA and .JB
+    // files the rules exclude are left out, and the other files named after
+    // the job that the rules include are added.
     fn read_job_info(&mut self) -> Result<(), Error> {
-        let dir = self.path_.parent().unwrap();
-        let filename = self.path_.strip_prefix(dir).unwrap();
-        self.jobname_ = Some(filename.to_str().unwrap().to_string());
+        let (dir, filename) = utils::split_path(&self.path_)?;
+        let filename_str = filename.to_string_lossy().to_string();
+        self.jobname_ = Some(filename_str.clone());
         self.script_ = Some(utils::read_file(dir, filename, None)?);
+        self.submit_time_ = utils::modified_time(&self.path_);
+        let stem = filename_str
+            .strip_suffix(&format!(".{}", self.suffixes.script))
+            .unwrap_or(&filename_str);
 
         // check for the presence of a .TA file
-        let ta_filename = filename.with_extension("TA");
-        let ta = utils::read_file(dir, &ta_filename, Some(10));
+        let ta_filename = PathBuf::from(format!("{stem}{}", self.suffixes.ta()));
+        let ta = read_spool_file(dir, &ta_filename, Some(10));
         if let Ok(ta_contents) = ta {
-            self.env_
-                .insert(ta_filename.to_str().unwrap().to_string(), ta_contents);
+            let ta_name = ta_filename.to_string_lossy().to_string();
+            if self.file_rules.keeps(&ta_name) {
+                self.env_.insert(ta_name, ta_contents);
+            }
             // If the job is an array job, there are multiple JB files.
             // The file name pattern is: 2720868-946.master.cluster.JB
             // Split the filename into appropriate parts
-            let fparts = filename.to_str().unwrap().split('.').collect::<Vec<&str>>();
+            let array_id = filename_str.split('.').next().unwrap_or_default();
             debug!(
                 "Found TA file, looking for JB files in {:?} with name {}",
-                dir, fparts[0]
+                dir, array_id
             );
-            glob(&format!("{}/{}-*.JB", dir.display(), fparts[0]))
-                .unwrap()
-                .filter_map(|jb_path| {
-                    if let Ok(jb_path) = jb_path {
-                        let jb_dir = jb_path.parent()?;
-                        let jb_filename = jb_path.strip_prefix(jb_dir).unwrap();
-                        let jb = utils::read_file(jb_dir, jb_filename, Some(10)).unwrap();
-                        Some((jb_filename.to_owned(), jb))
-                    } else {
-                        None
+            let mut jb_paths = Vec::new();
+            for extension in [self.suffixes.jb.clone(), format!("{}.gz", self.suffixes.jb)] {
+                let pattern = format!(
+                    "{}/{}-*.{}",
+                    Pattern::escape(&dir.to_string_lossy()),
+                    Pattern::escape(array_id),
+                    extension
+                );
+                let paths = glob(&pattern).map_err(|e| Error::new(ErrorKind::InvalidInput, e))?;
+                jb_paths.extend(paths.filter_map(Result::ok));
+            }
+            for jb_path in jb_paths {
+                let (jb_dir, jb_filename) = utils::split_path(&jb_path)?;
+                let jb_filename = jb_filename
+                    .to_string_lossy()
+                    .trim_end_matches(".gz")
+                    .to_owned();
+                if !self.file_rules.keeps(&jb_filename) {
+                    continue;
+                }
+                match read_spool_file(jb_dir, Path::new(&jb_filename), Some(10)) {
+                    Ok(jb) => {
+                        self.env_.insert(jb_filename, jb);
                     }
-                })
-                .map(|(jb_filename, jb)| {
-                    self.env_
-                        .insert(jb_filename.to_str().unwrap().to_string(), jb);
-                    Some(())
-                })
-                .for_each(drop);
+                    Err(e) => warn!("Cannot read {:?} for job {}: {}", jb_path, self.jobid_, e),
+                }
+            }
 
-            return Ok(());
+            return self.read_included(stem);
         }
 
         // If it  was no array job, there should be a single .JB file to pick up.
-        let jb_filename = filename.with_extension("JB");
-        let jb = utils::read_file(dir, &jb_filename, None)?;
-        self.env_
-            .insert(jb_filename.to_str().unwrap().to_string(), jb);
-        Ok(())
+        let jb_filename = PathBuf::from(format!("{stem}{}", self.suffixes.jb()));
+        let jb_name = jb_filename.to_string_lossy().to_string();
+        if self.file_rules.keeps(&jb_name) {
+            let jb = read_spool_file(dir, &jb_filename, None)?;
+            self.env_.insert(jb_name, jb);
+        }
+        self.read_included(stem)
+    }
+
+    // Return one entry per array task, if requested and this is an array job
+    fn expand(&self) -> Vec<Box<dyn JobInfo>> {
+        let is_array = self.env_.keys().any(|k| k.ends_with(&self.suffixes.ta()));
+        if !self.expand_arrays || !is_array {
+            return Vec::new();
+        }
+        let mut tasks: Vec<(&String, &Vec<u8>)> = self
+            .env_
+            .iter()
+            .filter(|(k, _)| k.ends_with(&self.suffixes.jb()))
+            .collect();
+        tasks.sort();
+        tasks
+            .into_iter()
+            .map(|(k, v)| Box::new(self.array_task(k, v)) as Box<dyn JobInfo>)
+            .collect()
     }
 
     // Return a Vec of tuples with the filename and file contents for
@@ -154,22 +385,531 @@ impl JobInfo for TorqueJobEntry {
         fs
     }
 
+    // Return the spool paths of the script, .TA and .JB files, which may be
+    // compressed
+    fn file_sources(&self) -> HashMap<String, PathBuf> {
+        let dir = self.path_.parent().unwrap_or(Path::new(""));
+        let mut sources: HashMap<String, PathBuf> = self
+            .env_
+            .keys()
+            .map(|k| (k.clone(), gz_path(&dir.join(k)).unwrap_or(dir.join(k))))
+            .collect();
+        if let (Some(jn), Some(_)) = (&self.jobname_, &self.script_) {
+            sources.insert(jn.clone(), self.path_.clone());
+        }
+        sources
+    }
+
+    // Return the spool paths of the included files that were too large to be
+    // read into memory
+    fn streamed_files(&self) -> HashMap<String, PathBuf> {
+        let dir = self.path_.parent().unwrap_or(Path::new(""));
+        self.streamed_
+            .iter()
+            .map(|name| (name.clone(), dir.join(name)))
+            .collect()
+    }
+
+    // Return the time Torque wrote the script file
+    fn submit_time(&self) -> Option<DateTime<Utc>> {
+        self.submit_time_
+    }
+
     // Return the actual job script as a String
     fn script(&self) -> String {
         match &self.script_ {
             Some(s) => String::from_utf8_lossy(s).to_string(),
-            None => panic!("No script available for job {}", self.jobid_),
+            None => {
+                warn!("No script available for job {}", self.jobid_);
+                String::new()
+            }
         }
     }
 
     // Return additional information as a set of key-value pairs
+    //
+    // If requested, the XML in the .JB files is converted to JSON. Should
+    // this fail, we fall back to the raw contents. For an array job, the .TA
+    // file is replaced by the array information under the `array` key.
     fn extra_info(&self) -> Option<HashMap<String, String>> {
-        Some(
+        let mut info: HashMap<String, String> = self
+            .env_
+            .iter()
+            .filter(|(k, _)| !k.ends_with(&self.suffixes.ta()))
+            .map(|(k, v)| {
+                if self.jb_json && k.ends_with(&self.suffixes.jb()) {
+                    match xml_to_json(v) {
+                        Ok(json) => return (k.clone(), json),
+                        Err(e) => warn!("Cannot convert {} to JSON: {}", k, e),
+                    }
+                }
+                (k.clone(), String::from_utf8_lossy(v).to_string())
+            })
+            .collect();
+        if let Some(array) = self.array_info() {
+            info.insert("array".to_owned(), array.to_string());
+        }
+        if !self.streamed_.is_empty() {
+            info.insert(STREAMED_FILES_KEY.to_owned(), self.streamed_.join(","));
+        }
+        Some(info)
+    }
+}
+
+impl TorqueJobEntry {
+    /// Reads the other files in the spool named after the job (i.e., its
+    /// script file name without the suffix, followed by a dot) that the rules
+    /// include. Only regular files are read, not what a symlink points to, and
+    /// files larger than the maximal buffered size are left in the spool for
+    /// streaming.
+    fn read_included(&mut self, stem: &str) -> Result<(), Error> {
+        self.streamed_.clear();
+        if !self.file_rules.has_includes() {
+            return Ok(());
+        }
+        let dir = self.path_.parent().unwrap_or(Path::new("")).to_path_buf();
+        let pattern = format!(
+            "{}/{}.*",
+            Pattern::escape(&dir.to_string_lossy()),
+            Pattern::escape(stem)
+        );
+        let paths = glob(&pattern).map_err(|e| Error::new(ErrorKind::InvalidInput, e))?;
+        for path in paths.filter_map(Result::ok) {
+            let name = path
+                .file_name()
+                .map(|n| n.to_string_lossy().into_owned())
+                .unwrap_or_default();
+            if self.jobname_.as_ref() == Some(&name)
+                || self.env_.contains_key(&name)
+                || !self.file_rules.adds(&name)
+            {
+                continue;
+            }
+            if let Err(e) = self.read_included_file(&path, name) {
+                warn!("Cannot read {:?} for job {}: {}", path, self.jobid_, e);
+            }
+        }
+        self.streamed_.sort();
+        Ok(())
+    }
+
+    /// Reads the included file at the given path, if it is a regular file, or
+    /// leaves it for streaming if it is too large
+    fn read_included_file(&mut self, path: &Path, name: String) -> Result<(), Error> {
+        let metadata = std::fs::symlink_metadata(path)?;
+        if !metadata.is_file() {
+            return Ok(());
+        }
+        if self
+            .max_buffered_size
+            .is_some_and(|max_size| metadata.len() > max_size)
+        {
+            info!(
+                "Job {} has a {} file of {} bytes, streaming it from the spool",
+                self.jobid_,
+                name,
+                metadata.len()
+            );
+            self.streamed_.push(name);
+            return Ok(());
+        }
+        let contents = std::fs::read(path)?;
+        self.env_.insert(name, contents);
+        Ok(())
+    }
+
+    /// Returns the array information for an array job: its ID, the requested
+    /// range of task IDs, the slot limit and the number of tasks. The range and
+    /// slot limit come from the `job_array_request` attribute (e.g., `1-10%2`)
+    /// or the `ranges` and `slot_limit` elements in the .TA file. When the
+    /// range is unknown, the tasks are counted from their .JB files.
+    fn array_info(&self) -> Option<Value> {
+        let (ta_filename, ta) = self
+            .env_
+            .iter()
+            .find(|(k, _)| k.ends_with(&self.suffixes.ta()))?;
+        let array_id = ta_filename.split('.').next().unwrap_or_default();
+        let ta = match xml_to_value(ta) {
+            Ok(ta) => ta,
+            Err(e) => {
+                warn!("Cannot parse {}: {}", ta_filename, e);
+                Value::Null
+            }
+        };
+
+        let request = xml_find(&ta, "job_array_request");
+        let (range, slot_limit) = match request.as_deref().map(|r| r.split_once('%')) {
+            Some(Some((range, limit))) => (Some(range.to_owned()), Some(limit.to_owned())),
+            Some(None) => (request.clone(), xml_find(&ta, "slot_limit")),
+            None => (xml_find(&ta, "ranges"), xml_find(&ta, "slot_limit")),
+        };
+        let task_count = range.as_deref().and_then(count_tasks).unwrap_or_else(|| {
             self.env_
-                .iter()
-                .map(|(k, v)| (k.clone(), String::from_utf8_lossy(v).to_string()))
-                .collect(),
-        )
+                .keys()
+                .filter(|k| {
+                    k.starts_with(&format!("{array_id}-")) && k.ends_with(&self.suffixes.jb())
+                })
+                .count()
+        });
+
+        Some(serde_json::json!({
+            "array_id": array_id,
+            "range": range,
+            // Torque uses -1 for no limit
+            "slot_limit": slot_limit.and_then(|l| l.trim().parse::<u32>().ok()),
+            "task_count": task_count,
+        }))
+    }
+}
+
+/// The stdout or stderr file of a completed job, which is archived as an
+/// entry of its own, after the job script
+pub struct TorqueOutputEntry {
+    /// The full path to the output file in the spool
+    path_: PathBuf,
+    /// The job ID
+    jobid_: String,
+    /// The name of the cluster
+    cluster_: String,
+    /// Time of event notification and instance creation
+    moment_: Instant,
+    /// Wall-clock time of event notification
+    event_time_: DateTime<Utc>,
+    /// Size above which only the last part of the file is kept
+    max_size: u64,
+    /// The (last part of the) output
+    output_: Option<Vec<u8>>,
+    /// The size of the full file, if it was truncated
+    truncated_: Option<u64>,
+    /// The uid of the owner of the file, which Torque creates as the user
+    /// of the job
+    owner_: Option<u32>,
+}
+
+impl TorqueOutputEntry {
+    pub fn new(p: &Path, id: &str, cluster: &str, max_size: u64) -> TorqueOutputEntry {
+        TorqueOutputEntry {
+            path_: p.to_path_buf(),
+            jobid_: id.to_owned(),
+            cluster_: cluster.to_string(),
+            moment_: Instant::now(),
+            event_time_: Utc::now(),
+            max_size,
+            output_: None,
+            truncated_: None,
+            owner_: None,
+        }
+    }
+
+    fn filename(&self) -> String {
+        self.path_
+            .file_name()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .to_string()
+    }
+}
+
+impl JobInfo for TorqueOutputEntry {
+    fn jobid(&self) -> String {
+        self.jobid_.clone()
+    }
+
+    fn moment(&self) -> Instant {
+        self.moment_
+    }
+
+    fn event_time(&self) -> DateTime<Utc> {
+        self.event_time_
+    }
+
+    fn cluster(&self) -> String {
+        self.cluster_.clone()
+    }
+
+    /// Returns the path of the output file
+    fn event_path(&self) -> Option<PathBuf> {
+        Some(self.path_.clone())
+    }
+
+    // Read the output file, keeping only its last part if it is too large,
+    // as that is where a failing job usually reports why
+    fn read_job_info(&mut self) -> Result<(), Error> {
+        let mut file = File::open(&self.path_)?;
+        let metadata = file.metadata()?;
+        let size = metadata.len();
+        self.owner_ = Some(metadata.uid());
+        self.truncated_ = None;
+        if size > self.max_size {
+            file.seek(SeekFrom::Start(size - self.max_size))?;
+            self.truncated_ = Some(size);
+        }
+        let mut output = Vec::new();
+        file.take(self.max_size).read_to_end(&mut output)?;
+        self.output_ = Some(output);
+        Ok(())
+    }
+
+    fn files(&self) -> Vec<(String, Vec<u8>)> {
+        match &self.output_ {
+            Some(output) => vec![(self.filename(), output.clone())],
+            None => Vec::new(),
+        }
+    }
+
+    fn file_sources(&self) -> HashMap<String, PathBuf> {
+        HashMap::from([(self.filename(), self.path_.clone())])
+    }
+
+    // An output entry has no script
+    fn script(&self) -> String {
+        String::new()
+    }
+
+    // Return the name of the owner of the output file
+    fn user(&self) -> Option<String> {
+        self.owner_.and_then(utils::user_name)
+    }
+
+    // Return the uid of the owner of the output file
+    fn uid(&self) -> Option<String> {
+        self.owner_.map(|uid| uid.to_string())
+    }
+
+    // Return the output under its file name, along with the size of the full
+    // file if it was truncated
+    fn extra_info(&self) -> Option<HashMap<String, String>> {
+        let mut info = HashMap::new();
+        if let Some(output) = &self.output_ {
+            info.insert(self.filename(), String::from_utf8_lossy(output).to_string());
+        }
+        if let Some(size) = self.truncated_ {
+            info.insert(OUTPUT_TRUNCATED_KEY.to_owned(), size.to_string());
+        }
+        Some(info)
+    }
+}
+
+/// Reports that the files of a job were deleted from the spool, which Torque
+/// does when the job is deleted (e.g., with qdel) or purged after completion
+pub struct TorqueDeletionEntry {
+    jobid_: String,
+    cluster_: String,
+    moment_: Instant,
+    event_time_: DateTime<Utc>,
+}
+
+impl TorqueDeletionEntry {
+    pub fn new(id: &str, cluster: &str) -> TorqueDeletionEntry {
+        TorqueDeletionEntry {
+            jobid_: id.to_owned(),
+            cluster_: cluster.to_string(),
+            moment_: Instant::now(),
+            event_time_: Utc::now(),
+        }
+    }
+}
+
+impl JobInfo for TorqueDeletionEntry {
+    fn jobid(&self) -> String {
+        self.jobid_.clone()
+    }
+
+    fn moment(&self) -> Instant {
+        self.moment_
+    }
+
+    fn event_time(&self) -> DateTime<Utc> {
+        self.event_time_
+    }
+
+    fn cluster(&self) -> String {
+        self.cluster_.clone()
+    }
+
+    // There is nothing left to read
+    fn read_job_info(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn files(&self) -> Vec<(String, Vec<u8>)> {
+        Vec::new()
+    }
+
+    fn script(&self) -> String {
+        String::new()
+    }
+
+    fn extra_info(&self) -> Option<HashMap<String, String>> {
+        None
+    }
+
+    fn tombstone_event(&self) -> String {
+        DELETED_EVENT.to_owned()
+    }
+
+    fn is_deletion(&self) -> bool {
+        true
+    }
+}
+
+/// Counts the task IDs in an array range such as `0-9,15,20-22`
+fn count_tasks(range: &str) -> Option<usize> {
+    range
+        .split(',')
+        .map(|part| match part.trim().split_once('-') {
+            Some((first, last)) => {
+                let first: usize = first.trim().parse().ok()?;
+                let last: usize = last.trim().parse().ok()?;
+                last.checked_sub(first).map(|d| d + 1)
+            }
+            None => part.trim().parse::<usize>().ok().map(|_| 1),
+        })
+        .sum()
+}
+
+/// Looks up the text of the first element with the given name in the JSON
+/// representation of an XML document
+fn xml_find(value: &Value, name: &str) -> Option<String> {
+    match value {
+        Value::Object(map) => match map.get(name) {
+            Some(Value::String(text)) => Some(text.clone()),
+            _ => map.values().find_map(|v| xml_find(v, name)),
+        },
+        Value::Array(values) => values.iter().find_map(|v| xml_find(v, name)),
+        _ => None,
+    }
+}
+
+/// Returns the path of the gzip compressed version of the spool file, if
+/// it exists instead of the file itself
+fn gz_path(path: &Path) -> Option<PathBuf> {
+    let mut gz = path.as_os_str().to_owned();
+    gz.push(".gz");
+    let gz = PathBuf::from(gz);
+    (!path.exists() && gz.exists()).then_some(gz)
+}
+
+/// Reads a .JB or .TA file, which some Torque configurations compress. The
+/// compressed file either keeps its name or gets an additional `.gz`
+/// extension. Either way, we return the decompressed contents.
+fn read_spool_file(dir: &Path, filename: &Path, iters: Option<u32>) -> Result<Vec<u8>, Error> {
+    let contents = match gz_path(&dir.join(filename)) {
+        Some(gz) => {
+            let (gz_dir, gz_filename) = utils::split_path(&gz)?;
+            utils::read_file(gz_dir, gz_filename, iters)?
+        }
+        None => utils::read_file(dir, filename, iters)?,
+    };
+    utils::decompress(contents)
+}
+
+/// Converts an XML document to a JSON string, see `xml_to_value`
+fn xml_to_json(xml: &[u8]) -> Result<String, Error> {
+    serde_json::to_string(&xml_to_value(xml)?).map_err(|e| Error::new(ErrorKind::InvalidData, e))
+}
+
+/// Converts an XML document to a JSON value.
+///
+/// Elements become objects keyed by their tag name, attributes are
+/// prefixed with `@` and text next to child elements is stored under
+/// `#text`. Elements holding only text become plain strings and
+/// repeated elements are gathered into an array.
+fn xml_to_value(xml: &[u8]) -> Result<Value, Error> {
+    let invalid = |e: quick_xml::Error| Error::new(ErrorKind::InvalidData, e);
+
+    let mut reader = Reader::from_reader(xml);
+    reader.config_mut().trim_text(true);
+
+    // Each open element keeps its name, its children (and attributes) and its text
+    let mut stack: Vec<(String, Map<String, Value>, String)> =
+        vec![(String::new(), Map::new(), String::new())];
+
+    loop {
+        match reader.read_event().map_err(invalid)? {
+            XmlEvent::Start(e) => {
+                let name = String::from_utf8_lossy(e.name().as_ref()).to_string();
+                stack.push((name, xml_attributes(&e)?, String::new()));
+            }
+            XmlEvent::Empty(e) => {
+                let name = String::from_utf8_lossy(e.name().as_ref()).to_string();
+                let value = xml_element_value(xml_attributes(&e)?, String::new());
+                xml_insert(&mut stack.last_mut().unwrap().1, name, value);
+            }
+            XmlEvent::Text(t) => {
+                let text = t.unescape().map_err(invalid)?;
+                stack.last_mut().unwrap().2.push_str(&text);
+            }
+            XmlEvent::CData(t) => {
+                let text = String::from_utf8_lossy(&t.into_inner()).to_string();
+                stack.last_mut().unwrap().2.push_str(&text);
+            }
+            XmlEvent::End(_) => {
+                let (name, children, text) = stack.pop().unwrap();
+                let value = xml_element_value(children, text);
+                match stack.last_mut() {
+                    Some((_, parent, _)) => xml_insert(parent, name, value),
+                    None => {
+                        return Err(Error::new(
+                            ErrorKind::InvalidData,
+                            "Unbalanced XML document",
+                        ))
+                    }
+                }
+            }
+            XmlEvent::Eof => break,
+            _ => (),
+        }
+    }
+
+    match stack.pop() {
+        Some((_, root, _)) if stack.is_empty() => Ok(Value::Object(root)),
+        _ => Err(Error::new(
+            ErrorKind::InvalidData,
+            "Unexpected end of XML document",
+        )),
+    }
+}
+
+/// Collects the attributes of an XML element, keyed by `@name`
+fn xml_attributes(e: &BytesStart) -> Result<Map<String, Value>, Error> {
+    let mut attributes = Map::new();
+    for attr in e.attributes() {
+        let attr = attr.map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+        let value = attr
+            .unescape_value()
+            .map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+        attributes.insert(
+            format!("@{}", String::from_utf8_lossy(attr.key.as_ref())),
+            Value::String(value.into_owned()),
+        );
+    }
+    Ok(attributes)
+}
+
+/// Determines the JSON value for a closed XML element
+fn xml_element_value(mut children: Map<String, Value>, text: String) -> Value {
+    if children.is_empty() {
+        Value::String(text)
+    } else {
+        if !text.is_empty() {
+            children.insert("#text".to_owned(), Value::String(text));
+        }
+        Value::Object(children)
+    }
+}
+
+/// Adds a child element to its parent, gathering repeated elements in an array
+fn xml_insert(parent: &mut Map<String, Value>, name: String, value: Value) {
+    match parent.get_mut(&name) {
+        Some(Value::Array(values)) => values.push(value),
+        Some(existing) => {
+            let previous = existing.take();
+            *existing = Value::Array(vec![previous, value]);
+        }
+        None => {
+            parent.insert(name, value);
+        }
     }
 }
 
@@ -177,15 +917,53 @@ pub struct Torque {
     pub base: PathBuf,
     pub cluster: String,
     pub subdirs: bool,
+    pub jb_json: bool,
+    pub expand_arrays: bool,
+    pub suffixes: Suffixes,
+    /// The events that announce a new job entry
+    pub event_kinds: Vec<JobEvent>,
+    /// The spool holding the output files of running jobs, if these are archived
+    pub output_spool: Option<PathBuf>,
+    /// Size above which only the last part of an output file is archived
+    pub output_max_size: u64,
+    /// Archive the deletion of the job files
+    pub deletions: bool,
+    /// Rules on the job files to capture or leave out
+    pub file_rules: Arc<FileRules>,
+    /// Size above which included files are streamed from the spool instead
+    /// of being read into memory
+    pub max_buffered_size: Option<u64>,
 }
 
 impl Torque {
-    pub fn new(base: &Path, cluster: &str) -> Torque {
+    pub fn new(base: &Path, cluster: &str, args: &TorqueArgs) -> Torque {
         Torque {
             base: base.to_path_buf(),
             cluster: cluster.to_string(),
             subdirs: true, // FIXME: get from the cli argument
+            jb_json: args.jb_json,
+            expand_arrays: args.expand_arrays,
+            suffixes: Suffixes::from_args(args),
+            event_kinds: vec![JobEvent::Create],
+            output_spool: args.output_spool.clone(),
+            output_max_size: args.output_max_size,
+            deletions: args.deletions,
+            file_rules: Arc::new(FileRules::new(&args.include, &args.exclude)),
+            max_buffered_size: None,
+        }
+    }
+
+    /// Returns the job ID if the path is that of an output file in the
+    /// output spool
+    fn output_jobid<'a>(&self, path: &'a Path) -> Option<&'a str> {
+        let spool = self.output_spool.as_ref()?;
+        if path.parent() != Some(spool.as_path()) {
+            return None;
         }
+        OUTPUT_SUFFIXES
+            .iter()
+            .find_map(|suffix| is_job_path(path, suffix))
+            .map(|(jobid, _)| jobid)
     }
 }
 
@@ -196,50 +974,95 @@ impl Scheduler for Torque {
         } else {
             [self.base.clone()].to_vec()
         }
+        .into_iter()
+        .chain(self.output_spool.clone())
+        .collect()
     }
 
     fn create_job_info(&self, event_path: &Path) -> Option<Box<dyn JobInfo>> {
-        if let Some((jobid, filename)) = is_job_path(event_path) {
-            Some(Box::new(TorqueJobEntry::new(
-                filename,
+        if let Some(jobid) = self.output_jobid(event_path) {
+            return Some(Box::new(TorqueOutputEntry::new(
+                event_path,
                 jobid,
                 &self.cluster,
-            )))
+                self.output_max_size,
+            )));
+        }
+        if let Some((jobid, filename)) = is_job_path(event_path, &self.suffixes.script) {
+            let mut job_entry = TorqueJobEntry::new(filename, jobid, &self.cluster, self.jb_json);
+            job_entry.expand_arrays = self.expand_arrays;
+            job_entry.suffixes = self.suffixes.clone();
+            job_entry.file_rules = Arc::clone(&self.file_rules);
+            job_entry.max_buffered_size = self.max_buffered_size;
+            Some(Box::new(job_entry))
         } else {
             None
         }
     }
 
+    // The output files of a job are complete once the job closes them
     fn verify_event_kind(&self, event: &Event) -> Option<Vec<PathBuf>> {
-        if let Event {
-            kind: EventKind::Create(CreateKind::File),
-            paths,
-            ..
-        } = event
-        {
-            Some(paths.to_vec())
-        } else {
-            None
+        if event.kind == EventKind::Access(AccessKind::Close(AccessMode::Write)) {
+            return event
+                .paths
+                .first()
+                .filter(|path| self.output_jobid(path).is_some())
+                .map(|_| event.paths.to_vec());
+        }
+        job_event_paths(event, CreateKind::File, &self.event_kinds)
+    }
+
+    /// Returns the path of a script file that was removed from a watched
+    /// location other than the output spool
+    fn verify_job_removal(&self, event: &Event) -> Option<PathBuf> {
+        if event.kind != EventKind::Remove(RemoveKind::File) {
+            return None;
+        }
+        event
+            .paths
+            .iter()
+            .find(|path| {
+                path.parent() != self.output_spool.as_deref()
+                    && job_id(path, &self.suffixes.script).is_some()
+            })
+            .cloned()
+    }
+
+    fn deletion_entry(&self, path: &Path) -> Option<Box<dyn JobInfo>> {
+        if !self.deletions {
+            return None;
         }
+        let jobid = job_id(path, &self.suffixes.script)?;
+        Some(Box::new(TorqueDeletionEntry::new(jobid, &self.cluster)))
     }
 }
 
+/// Returns the job ID in the name of a job file with the given suffix, i.e.,
+/// the part before the dot and the suffix
+fn job_id<'a>(path: &'a Path, suffix: &str) -> Option<&'a str> {
+    path.file_name()
+        .and_then(|name| name.to_str())
+        .and_then(|name| name.strip_suffix(suffix))
+        .and_then(|name| name.strip_suffix('.'))
+        .filter(|jobid| !jobid.is_empty())
+}
+
 /// Verifies that the path metioned in the event is a that of a file that
 /// needs archival
 ///
 /// This ignores the path prefix, but verifies that
 /// - the path points to a file
-/// - the extension of the file is .SC (indicating a torque script file)
+/// - the file name is a job ID followed by the script suffix (.SC by default,
+///   indicating a torque script file). Files without an extension, or without
+///   a job ID before it, are not job paths.
 ///
 /// We return a tuple of two strings: the job ID and the filename, wrapped in
 /// an Option.
-fn is_job_path(path: &Path) -> Option<(&str, &Path)> {
-    if path.is_file() {
-        let jobid = path.file_stem().unwrap().to_str().unwrap();
-        return match path.extension().unwrap().to_str().unwrap() {
-            "SC" => Some((jobid, path)),
-            _ => None,
-        };
+fn is_job_path<'a>(path: &'a Path, suffix: &str) -> Option<(&'a str, &'a Path)> {
+    if let Some(jobid) = job_id(path, suffix) {
+        if path.is_file() {
+            return Some((jobid, path));
+        }
     }
     debug!("{:?} is not a considered job path", &path);
     None
@@ -249,16 +1072,16 @@ fn is_job_path(path: &Path) -> Option<(&str, &Path)> {
 mod tests {
 
     use super::*;
+    use crate::scheduler::job::JobRecord;
+    use clap::Parser;
     use std::env::current_dir;
 
     #[test]
     fn test_read_info() {
-        let path = PathBuf::from(
-            current_dir()
-                .unwrap()
-                .join("tests/torque_job.1/1.mymaster.mycluster.SC"),
-        );
-        let mut torque_job_entry = TorqueJobEntry::new(&path, "1", "mycluster");
+        let path = current_dir()
+            .unwrap()
+            .join("tests/torque_job.1/1.mymaster.mycluster.SC");
+        let mut torque_job_entry = TorqueJobEntry::new(&path, "1", "mycluster", false);
         torque_job_entry.read_job_info().unwrap();
 
         assert!(torque_job_entry
@@ -272,12 +1095,10 @@ mod tests {
 
     #[test]
     fn test_read_info_job_array() {
-        let path = PathBuf::from(
-            current_dir()
-                .unwrap()
-                .join("tests/torque_job.2/2.mymaster.mycluster.SC"),
-        );
-        let mut torque_job_entry = TorqueJobEntry::new(&path, "2", "mycluster");
+        let path = current_dir()
+            .unwrap()
+            .join("tests/torque_job.2/2.mymaster.mycluster.SC");
+        let mut torque_job_entry = TorqueJobEntry::new(&path, "2", "mycluster", false);
         torque_job_entry.read_job_info().unwrap();
 
         assert!(torque_job_entry
@@ -297,5 +1118,441 @@ mod tests {
             torque_job_entry.env_.get("2-2.mymaster.mycluster.JB"),
             Some(&String::from("<some><xml>M2</xml></some>").into_bytes())
         );
+        assert!(torque_job_entry.expand().is_empty());
+    }
+
+    #[test]
+    fn test_expand_job_array() {
+        let path = current_dir()
+            .unwrap()
+            .join("tests/torque_job.2/2.mymaster.mycluster.SC");
+        let mut torque_job_entry = TorqueJobEntry::new(&path, "2", "mycluster", false);
+        torque_job_entry.expand_arrays = true;
+        torque_job_entry.read_job_info().unwrap();
+
+        let tasks = torque_job_entry.expand();
+        assert_eq!(tasks.len(), 2);
+        assert_eq!(tasks[0].jobid(), "2-1.mymaster.mycluster");
+        assert_eq!(tasks[1].jobid(), "2-2.mymaster.mycluster");
+        assert_eq!(tasks[0].script(), torque_job_entry.script());
+        assert_eq!(
+            tasks[1].extra_info(),
+            Some(HashMap::from([(
+                "2-2.mymaster.mycluster.JB".to_owned(),
+                "<some><xml>M2</xml></some>".to_owned()
+            )]))
+        );
+        assert!(tasks[0].expand().is_empty());
+    }
+
+    #[test]
+    fn test_extra_info_jb_json() {
+        let path = current_dir()
+            .unwrap()
+            .join("tests/torque_job.1/1.mymaster.mycluster.SC");
+        let mut torque_job_entry = TorqueJobEntry::new(&path, "1", "mycluster", true);
+        torque_job_entry.read_job_info().unwrap();
+
+        let extra_info = torque_job_entry.extra_info().unwrap();
+        assert_eq!(
+            extra_info.get("1.mymaster.mycluster.JB"),
+            Some(&String::from(r#"{"some":{"xml":"M"}}"#))
+        );
+    }
+
+    #[test]
+    fn test_xml_to_json() {
+        let xml =
+            br#"<job id="1"><name>test &amp; run</name><var>A</var><var>B</var><empty/></job>"#;
+        let json: Value = serde_json::from_str(&xml_to_json(xml).unwrap()).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "job": {
+                    "@id": "1",
+                    "name": "test & run",
+                    "var": ["A", "B"],
+                    "empty": "",
+                }
+            })
+        );
+    }
+
+    #[test]
+    fn test_extra_info_job_array() {
+        let path = current_dir()
+            .unwrap()
+            .join("tests/torque_job.2/2.mymaster.mycluster.SC");
+        let mut torque_job_entry = TorqueJobEntry::new(&path, "2", "mycluster", false);
+        torque_job_entry.read_job_info().unwrap();
+
+        // The .TA file holds no array information, so the tasks are counted
+        let extra_info = torque_job_entry.extra_info().unwrap();
+        assert!(!extra_info.contains_key("2.mymaster.mycluster.TA"));
+        let array: Value = serde_json::from_str(&extra_info["array"]).unwrap();
+        assert_eq!(
+            array,
+            serde_json::json!({"array_id": "2", "range": null, "slot_limit": null, "task_count": 2})
+        );
+    }
+
+    #[test]
+    fn test_array_info() {
+        let mut torque_job_entry =
+            TorqueJobEntry::new(Path::new("/spool/3.SC"), "3", "mycluster", false);
+        torque_job_entry.env_.insert(
+            "3.mymaster.mycluster.TA".to_owned(),
+            b"<job><attributes><job_array_request>0-9,15%4</job_array_request></attributes></job>"
+                .to_vec(),
+        );
+        assert_eq!(
+            torque_job_entry.array_info(),
+            Some(
+                serde_json::json!({"array_id": "3", "range": "0-9,15", "slot_limit": 4, "task_count": 11})
+            )
+        );
+
+        torque_job_entry.env_.insert(
+            "3.mymaster.mycluster.TA".to_owned(),
+            b"<array><slot_limit>-1</slot_limit><ranges>1-4</ranges></array>".to_vec(),
+        );
+        assert_eq!(
+            torque_job_entry.array_info(),
+            Some(
+                serde_json::json!({"array_id": "3", "range": "1-4", "slot_limit": null, "task_count": 4})
+            )
+        );
+    }
+
+    #[test]
+    fn test_count_tasks() {
+        assert_eq!(count_tasks("0-9"), Some(10));
+        assert_eq!(count_tasks("1,3, 5-6"), Some(4));
+        assert_eq!(count_tasks("9-1"), None);
+        assert_eq!(count_tasks("a-b"), None);
+    }
+
+    #[test]
+    fn test_read_info_compressed() {
+        use flate2::write::GzEncoder;
+        use flate2::Compression;
+        use std::io::Write;
+
+        let gzip = |contents: &[u8]| {
+            let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+            encoder.write_all(contents).unwrap();
+            encoder.finish().unwrap()
+        };
+        let tdir = tempfile::tempdir().unwrap();
+        let dir = tdir.path();
+        std::fs::write(dir.join("3.master.SC"), b"#!/bin/bash\n").unwrap();
+        std::fs::write(dir.join("3.master.TA"), gzip(b"<array/>")).unwrap();
+        std::fs::write(dir.join("3-1.master.JB"), gzip(b"<job>1</job>")).unwrap();
+        std::fs::write(dir.join("3-2.master.JB.gz"), gzip(b"<job>2</job>")).unwrap();
+        std::fs::write(dir.join("4.master.SC"), b"#!/bin/bash\n").unwrap();
+        std::fs::write(dir.join("4.master.JB.gz"), gzip(b"<job>4</job>")).unwrap();
+
+        let mut array_entry =
+            TorqueJobEntry::new(&dir.join("3.master.SC"), "3", "mycluster", false);
+        array_entry.read_job_info().unwrap();
+        assert_eq!(array_entry.env_["3.master.TA"], b"<array/>");
+        assert_eq!(array_entry.env_["3-1.master.JB"], b"<job>1</job>");
+        assert_eq!(array_entry.env_["3-2.master.JB"], b"<job>2</job>");
+        assert_eq!(
+            array_entry.file_sources()["3-2.master.JB"],
+            dir.join("3-2.master.JB.gz")
+        );
+
+        let mut job_entry = TorqueJobEntry::new(&dir.join("4.master.SC"), "4", "mycluster", false);
+        job_entry.read_job_info().unwrap();
+        assert_eq!(job_entry.env_["4.master.JB"], b"<job>4</job>");
+    }
+
+    #[test]
+    fn test_read_info_file_rules() {
+        let tdir = tempfile::tempdir().unwrap();
+        let dir = tdir.path();
+        std::fs::write(dir.join("5.master.SC"), b"#!/bin/bash\n").unwrap();
+        std::fs::write(dir.join("5.master.JB"), b"<job>5</job>").unwrap();
+        std::fs::write(dir.join("5.master.CK"), b"checkpoint").unwrap();
+        std::fs::write(dir.join("5.master.tmp"), b"partial").unwrap();
+        std::fs::write(dir.join("50.master.CK"), b"other job").unwrap();
+        #[derive(Parser)]
+        struct Cli {
+            #[command(flatten)]
+            torque: TorqueArgs,
+        }
+        let args = Cli::parse_from([
+            "torque",
+            "--torque-include",
+            "*.CK",
+            "--torque-include",
+            "*.tmp",
+            "--torque-exclude",
+            "*.tmp",
+            "--torque-exclude",
+            "*.JB",
+        ])
+        .torque;
+
+        let torque = Torque::new(dir, "mycluster", &args);
+        let mut job_entry = torque.create_job_info(&dir.join("5.master.SC")).unwrap();
+        job_entry.read_job_info().unwrap();
+        let mut names: Vec<_> = job_entry.files().into_iter().map(|(n, _)| n).collect();
+        names.sort();
+        assert_eq!(names, ["5.master.CK", "5.master.SC"]);
+        assert_eq!(
+            job_entry.file_sources()["5.master.CK"],
+            dir.join("5.master.CK")
+        );
+
+        // a large checkpoint is left in the spool for streaming, and a
+        // symlink is not followed
+        let mut torque = Torque::new(dir, "mycluster", &args);
+        torque.max_buffered_size = Some(4);
+        std::os::unix::fs::symlink(dir.join("50.master.CK"), dir.join("5.master.CK2")).unwrap();
+        let args = Cli::parse_from(["torque", "--torque-include", "*.CK*"]).torque;
+        torque.file_rules = Arc::new(FileRules::new(&args.include, &args.exclude));
+        let mut job_entry = torque.create_job_info(&dir.join("5.master.SC")).unwrap();
+        job_entry.read_job_info().unwrap();
+        let names: Vec<_> = job_entry.files().into_iter().map(|(n, _)| n).collect();
+        assert!(!names.contains(&"5.master.CK".to_owned()));
+        assert!(!names.contains(&"5.master.CK2".to_owned()));
+        assert_eq!(
+            job_entry.streamed_files(),
+            HashMap::from([("5.master.CK".to_owned(), dir.join("5.master.CK"))])
+        );
+        assert_eq!(
+            job_entry.extra_info().unwrap()[STREAMED_FILES_KEY],
+            "5.master.CK"
+        );
+    }
+
+    #[test]
+    fn test_xml_to_json_invalid() {
+        assert!(xml_to_json(b"<some><xml>M</some>").is_err());
+        assert!(xml_to_json(b"<some><xml>M</xml>").is_err());
+    }
+
+    #[test]
+    fn test_is_job_path() {
+        let tdir = tempfile::tempdir().unwrap();
+        let script = tdir.path().join("1.mymaster.mycluster.SC");
+        let jb = tdir.path().join("1.mymaster.mycluster.JB");
+        let no_ext = tdir.path().join("README");
+        let only_suffix = tdir.path().join("SC");
+        let hidden = tdir.path().join(".SC");
+        for p in [&script, &jb, &no_ext, &only_suffix, &hidden] {
+            std::fs::write(p, b"").unwrap();
+        }
+
+        assert_eq!(
+            is_job_path(&script, "SC"),
+            Some(("1.mymaster.mycluster", script.as_path()))
+        );
+        assert_eq!(is_job_path(&jb, "SC"), None);
+        assert_eq!(is_job_path(&no_ext, "SC"), None);
+        assert_eq!(is_job_path(&only_suffix, "SC"), None);
+        assert_eq!(is_job_path(&hidden, "SC"), None);
+        assert_eq!(is_job_path(&tdir.path().join("2.SC"), "SC"), None);
+        assert_eq!(
+            is_job_path(&jb, "JB"),
+            Some(("1.mymaster.mycluster", jb.as_path()))
+        );
+    }
+
+    #[test]
+    fn test_custom_suffixes() {
+        let tdir = tempfile::tempdir().unwrap();
+        let dir = tdir.path();
+        std::fs::write(dir.join("5.master.script"), b"#!/bin/sh").unwrap();
+        std::fs::write(dir.join("5.master.jobinfo"), b"<some><xml>M</xml></some>").unwrap();
+        std::fs::write(dir.join("6.master.script"), b"#!/bin/sh").unwrap();
+        std::fs::write(dir.join("6.master.array"), b"<array></array>").unwrap();
+        std::fs::write(dir.join("6-1.master.jobinfo"), b"<some/>").unwrap();
+
+        #[derive(Parser)]
+        struct Cli {
+            #[command(flatten)]
+            torque: TorqueArgs,
+        }
+        let args = Cli::parse_from([
+            "torque",
+            "--torque-script-suffix",
+            "script",
+            "--torque-jb-suffix",
+            ".jobinfo",
+            "--torque-ta-suffix",
+            "array",
+        ])
+        .torque;
+        let torque = Torque::new(dir, "mycluster", &args);
+        assert_eq!(
+            torque.suffixes,
+            Suffixes {
+                script: "script".to_owned(),
+                jb: "jobinfo".to_owned(),
+                ta: "array".to_owned(),
+            }
+        );
+        assert!(torque
+            .create_job_info(&dir.join("5.master.jobinfo"))
+            .is_none());
+
+        let mut job_entry = torque
+            .create_job_info(&dir.join("5.master.script"))
+            .unwrap();
+        assert_eq!(job_entry.jobid(), "5.master");
+        job_entry.read_job_info().unwrap();
+        assert_eq!(
+            job_entry.extra_info(),
+            Some(HashMap::from([(
+                "5.master.jobinfo".to_owned(),
+                "<some><xml>M</xml></some>".to_owned()
+            )]))
+        );
+
+        let mut array_entry = torque
+            .create_job_info(&dir.join("6.master.script"))
+            .unwrap();
+        array_entry.read_job_info().unwrap();
+        let info = array_entry.extra_info().unwrap();
+        assert!(info.contains_key("6-1.master.jobinfo"));
+        assert!(!info.contains_key("6.master.array"));
+        assert!(info["array"].contains("\"task_count\":1"));
+    }
+
+    #[test]
+    fn test_output_capture() {
+        let tdir = tempfile::tempdir().unwrap();
+        let spool = tdir.path().join("spool");
+        std::fs::create_dir(&spool).unwrap();
+        std::fs::write(spool.join("7.master.OU"), b"0123456789").unwrap();
+        std::fs::write(spool.join("7.master.ER"), b"oops").unwrap();
+        std::fs::write(spool.join("7.master.SC"), b"#!/bin/sh").unwrap();
+
+        #[derive(Parser)]
+        struct Cli {
+            #[command(flatten)]
+            torque: TorqueArgs,
+        }
+        let args = Cli::parse_from([
+            "torque",
+            "--torque-output-spool",
+            spool.to_str().unwrap(),
+            "--torque-output-max-size",
+            "4",
+        ])
+        .torque;
+        let mut torque = Torque::new(tdir.path(), "mycluster", &args);
+        torque.subdirs = false;
+        assert_eq!(
+            torque.watch_locations(),
+            vec![tdir.path().to_path_buf(), spool.clone()]
+        );
+
+        let closed = |path: PathBuf| Event {
+            kind: EventKind::Access(AccessKind::Close(AccessMode::Write)),
+            paths: vec![path],
+            ..Default::default()
+        };
+        assert!(torque
+            .verify_event_kind(&closed(spool.join("7.master.SC")))
+            .is_none());
+        assert!(torque
+            .verify_event_kind(&closed(tdir.path().join("7.master.OU")))
+            .is_none());
+        let paths = torque
+            .verify_event_kind(&closed(spool.join("7.master.OU")))
+            .unwrap();
+
+        let mut output = torque.create_job_info(&paths[0]).unwrap();
+        assert_eq!(output.jobid(), "7.master");
+        output.read_job_info().unwrap();
+        assert_eq!(
+            output.files(),
+            vec![("7.master.OU".to_owned(), b"6789".to_vec())]
+        );
+        assert_eq!(output.extra_info().unwrap()[OUTPUT_TRUNCATED_KEY], "10");
+        let uid = unsafe { libc::getuid() };
+        assert_eq!(output.uid(), Some(uid.to_string()));
+        assert_eq!(output.user(), utils::user_name(uid));
+
+        let mut error = torque.create_job_info(&spool.join("7.master.ER")).unwrap();
+        error.read_job_info().unwrap();
+        assert_eq!(
+            error.files(),
+            vec![("7.master.ER".to_owned(), b"oops".to_vec())]
+        );
+        assert!(!error
+            .extra_info()
+            .unwrap()
+            .contains_key(OUTPUT_TRUNCATED_KEY));
+
+        // without an output spool, output files are not job entries
+        let torque = Torque::new(
+            tdir.path(),
+            "mycluster",
+            &Cli::parse_from(["torque"]).torque,
+        );
+        assert!(torque.create_job_info(&spool.join("7.master.OU")).is_none());
+    }
+
+    #[test]
+    fn test_deletions() {
+        let tdir = tempfile::tempdir().unwrap();
+        #[derive(Parser)]
+        struct Cli {
+            #[command(flatten)]
+            torque: TorqueArgs,
+        }
+        let removed = |path: PathBuf| Event {
+            kind: EventKind::Remove(RemoveKind::File),
+            paths: vec![path],
+            ..Default::default()
+        };
+        let script = tdir.path().join("0/12.master.SC");
+
+        let torque = Torque::new(
+            tdir.path(),
+            "mycluster",
+            &Cli::parse_from(["torque"]).torque,
+        );
+        assert_eq!(
+            torque.verify_job_removal(&removed(script.clone())),
+            Some(script.clone())
+        );
+        assert!(torque.deletion_entry(&script).is_none());
+
+        let torque = Torque::new(
+            tdir.path(),
+            "mycluster",
+            &Cli::parse_from(["torque", "--torque-deletions"]).torque,
+        );
+        assert!(torque
+            .verify_job_removal(&removed(tdir.path().join("0/12.master.JB")))
+            .is_none());
+        let created = Event {
+            kind: EventKind::Create(CreateKind::File),
+            paths: vec![script.clone()],
+            ..Default::default()
+        };
+        assert!(torque.verify_job_removal(&created).is_none());
+
+        let deletion = torque.deletion_entry(&script).unwrap();
+        assert!(deletion.is_deletion());
+        assert_eq!(deletion.jobid(), "12.master");
+        assert_eq!(deletion.cluster(), "mycluster");
+        let record = JobRecord::new(deletion.as_ref());
+        assert_eq!(record.tombstone_event(), DELETED_EVENT);
+    }
+
+    #[test]
+    fn test_script_missing() {
+        let path = current_dir()
+            .unwrap()
+            .join("tests/torque_job.1/1.mymaster.mycluster.SC");
+        let torque_job_entry = TorqueJobEntry::new(&path, "1", "mycluster", false);
+        assert_eq!(torque_job_entry.script(), "");
     }
 }
@@ -0,0 +1,292 @@
+/*
+Copyright 2019-2024 Andy Georges <itkovian+sarchive@gmail.com>
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in
+all copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+//! The selftest subcommand, which runs a synthetic Slurm job through the
+//! watcher and processing pipeline into the configured archiver, to verify a
+//! deployment (e.g., after an upgrade) without waiting for a real job.
+
+use chrono::Utc;
+use clap::Args;
+use crossbeam_channel::{bounded, never, unbounded, RecvTimeoutError, Sender};
+use crossbeam_utils::thread::scope;
+use log::{info, warn};
+use std::fs;
+use std::io::{Error, ErrorKind};
+use std::path::Path;
+use std::sync::atomic::AtomicBool;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use crate::archive::{process, Archive, ArchiverArgs, ProcessContext};
+use crate::completion::{ArchivedJobs, Completion};
+use crate::maintenance::Maintenance;
+use crate::monitor::{manage, WatchCommand};
+use crate::reconcile::Reconciler;
+use crate::scheduler::job::{JobInfo, JobRecord};
+use crate::scheduler::slurm::Slurm;
+use crate::scheduler::{JobEvent, Scheduler};
+use crate::stats::Stats;
+use crate::supervisor::Supervisor;
+
+/// Command line options for the selftest subcommand
+#[derive(Args, Debug)]
+pub struct SelftestArgs {
+    #[arg(
+        long,
+        value_name = "SECONDS",
+        default_value_t = 30,
+        help = "Time to wait for the synthetic job to be archived and delivered"
+    )]
+    pub timeout: u64,
+
+    #[command(subcommand)]
+    pub archiver: ArchiverArgs,
+}
+
+/// Name of the synthetic job, so it can be told apart in the archive
+pub const SELFTEST_JOB_NAME: &str = "sarchive-selftest";
+
+/// How long the watchers get to start before the job is submitted
+const WATCH_SETTLE: Duration = Duration::from_secs(1);
+
+/// Passes the jobs on to the archiver under test, reporting the outcome for
+/// the synthetic job once the archiver delivered it
+struct Probe {
+    inner: Box<dyn Archive>,
+    jobid: String,
+    timeout: Duration,
+    outcome: Sender<Result<(), String>>,
+}
+
+impl Archive for Probe {
+    fn archive(&self, job_entry: &JobRecord) -> Result<(), Error> {
+        let result = self
+            .inner
+            .archive(job_entry)
+            .and_then(|_| self.inner.flush(self.timeout));
+        if job_entry.jobid() == self.jobid {
+            let _ = self
+                .outcome
+                .try_send(result.as_ref().map(|_| ()).map_err(|e| e.to_string()));
+        }
+        result
+    }
+
+    fn archive_tombstone(&self, job_entry: &JobRecord) -> Result<(), Error> {
+        self.inner.archive_tombstone(job_entry)
+    }
+
+    fn archive_completion(&self, completion: &Completion) -> Result<(), Error> {
+        self.inner.archive_completion(completion)
+    }
+
+    fn check(&self, cluster: &str) -> Result<(), Error> {
+        self.inner.check(cluster)
+    }
+
+    fn flush(&self, timeout: Duration) -> Result<(), Error> {
+        self.inner.flush(timeout)
+    }
+
+    fn payload_size(&self, job: &JobRecord) -> u64 {
+        self.inner.payload_size(job)
+    }
+
+    fn name(&self) -> &str {
+        self.inner.name()
+    }
+}
+
+/// Submits the synthetic job the way slurmctld does: the job directory is
+/// filled under a temporary name and moved into its hash directory
+fn submit(hash_dir: &Path, jobid: &str) -> Result<(), Error> {
+    let tmp_dir = hash_dir.join(format!("tmp.{jobid}"));
+    fs::create_dir(&tmp_dir)?;
+    fs::write(
+        tmp_dir.join("script"),
+        format!(
+            "#!/bin/bash\n#SBATCH --job-name={SELFTEST_JOB_NAME}\necho \"sarchive selftest\"\n"
+        ),
+    )?;
+    // as Slurm does, the environment starts with the number of entries
+    let mut environment = 2u32.to_ne_bytes().to_vec();
+    environment
+        .extend(format!("SLURM_JOB_NAME={SELFTEST_JOB_NAME}\0SLURM_JOB_ID={jobid}\0").bytes());
+    fs::write(tmp_dir.join("environment"), environment)?;
+    fs::rename(&tmp_dir, hash_dir.join(format!("job.{jobid}")))
+}
+
+/// Runs a synthetic job through the pipeline into the archiver, in a
+/// temporary spool, and returns how long it took the archiver to deliver it.
+/// Fails if the archiver reports an error or the job does not arrive within
+/// the timeout.
+pub fn selftest(
+    archiver: Box<dyn Archive>,
+    cluster: &str,
+    timeout: Duration,
+) -> Result<Duration, Error> {
+    let spool = std::env::temp_dir().join(format!("sarchive-selftest-{}", std::process::id()));
+    fs::create_dir_all(spool.join("hash.0"))?;
+    let result = run(archiver, &spool, cluster, timeout);
+    if let Err(e) = fs::remove_dir_all(&spool) {
+        warn!("Cannot remove the selftest spool {:?}: {}", &spool, e);
+    }
+    result
+}
+
+fn run(
+    archiver: Box<dyn Archive>,
+    spool: &Path,
+    cluster: &str,
+    timeout: Duration,
+) -> Result<Duration, Error> {
+    let jobid = format!("selftest-{}", Utc::now().timestamp());
+    let (outcome_sender, outcome_receiver) = bounded(1);
+    let archiver: Box<dyn Archive> = Box::new(Probe {
+        inner: archiver,
+        jobid: jobid.clone(),
+        timeout,
+        outcome: outcome_sender,
+    });
+
+    let mut slurm = Slurm::new(spool, cluster);
+    slurm.event_kinds = vec![JobEvent::Create, JobEvent::Rename];
+    let sched: Box<dyn Scheduler> = Box::new(slurm);
+    let stats = Stats::new();
+    let reload = AtomicBool::new(false);
+    let reconciler = Reconciler::default();
+    let maintenance = Maintenance::default();
+
+    let (sig_sender, sig_receiver) = bounded(20);
+    let (sender, receiver) = unbounded();
+    let (location_sender, location_receiver) = unbounded();
+    for loc in sched.watch_locations() {
+        location_sender.send(WatchCommand::Add(loc)).unwrap();
+    }
+
+    let outcome = scope(|s| {
+        let (sl, lr, t, sr, rl, st, rc) = (
+            &sched,
+            &location_receiver,
+            &sender,
+            &sig_receiver,
+            &reload,
+            &stats,
+            &reconciler,
+        );
+        s.spawn(move |s| {
+            manage(
+                s,
+                sl,
+                lr,
+                t,
+                sr,
+                rl,
+                st,
+                None,
+                rc,
+                &[],
+                Supervisor::default(),
+            )
+        });
+
+        let (r, sr, st, m, rc) = (&receiver, &sig_receiver, &stats, &maintenance, &reconciler);
+        s.spawn(move |_| {
+            process(
+                archiver.as_ref(),
+                r,
+                &never(),
+                &mut ArchivedJobs::default(),
+                &mut Vec::new(),
+                sr,
+                &ProcessContext {
+                    stats: st,
+                    maintenance: m,
+                    reconciler: rc,
+                    deadline: None,
+                    tombstones: false,
+                    cleanup: false,
+                    max_batch: 1,
+                },
+            )
+        });
+
+        sleep(WATCH_SETTLE);
+        info!("Submitting synthetic job {} to {:?}", &jobid, spool);
+        let start = Instant::now();
+        let outcome = submit(&spool.join("hash.0"), &jobid)
+            .map_err(|e| Error::new(e.kind(), format!("Cannot submit the synthetic job: {e}")))
+            .and_then(|_| match outcome_receiver.recv_timeout(timeout) {
+                Ok(Ok(())) => Ok(start.elapsed()),
+                Ok(Err(e)) => Err(Error::other(format!("The archiver failed: {e}"))),
+                Err(RecvTimeoutError::Timeout) => Err(Error::new(
+                    ErrorKind::TimedOut,
+                    format!("The job was not archived within {}s", timeout.as_secs()),
+                )),
+                Err(RecvTimeoutError::Disconnected) => Err(Error::other(
+                    "Processing stopped before the job was archived",
+                )),
+            });
+        for _ in 0..20 {
+            let _ = sig_sender.send(true);
+        }
+        outcome
+    })
+    .map_err(|_| Error::other("The pipeline panicked"))?;
+    outcome
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::archive::file::{FileArchive, Period};
+    use crate::testing::FlakyArchiver;
+    use std::path::PathBuf;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_selftest() {
+        let tdir = tempdir().unwrap();
+        let archiver = Box::new(FileArchive::new(&tdir.path().to_path_buf(), &Period::None));
+        let latency = selftest(archiver, "mycluster", Duration::from_secs(10)).unwrap();
+        assert!(latency >= Duration::from_secs(1));
+
+        let archived: Vec<PathBuf> = fs::read_dir(tdir.path())
+            .unwrap()
+            .map(|e| e.unwrap().path())
+            .collect();
+        assert!(archived
+            .iter()
+            .any(|p| p.to_string_lossy().ends_with("_script")));
+
+        let err = selftest(
+            Box::new(FlakyArchiver::failing(|| {
+                Error::other("backend unreachable")
+            })),
+            "mycluster",
+            Duration::from_secs(10),
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("backend unreachable"));
+    }
+}
@@ -0,0 +1,189 @@
+/*
+Copyright 2019-2024 Andy Georges <itkovian+sarchive@gmail.com>
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in
+all copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+use log::{info, warn};
+use serde_json::{json, Value};
+use std::fs::{read_to_string, remove_file, rename, File, OpenOptions};
+use std::io::{BufWriter, Error, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+
+use crate::scheduler::job::JobInfo;
+use crate::scheduler::Scheduler;
+
+/// Writes the job entries that are still waiting to be processed to the
+/// given state file, one JSON document per line, so they can be picked up
+/// again by the next run. Returns the number of entries written.
+///
+/// The file is replaced atomically, or removed when there is nothing to save.
+/// Entries that cannot be created anew from a spool path are left out.
+pub fn save<I>(path: &Path, entries: I) -> Result<usize, Error>
+where
+    I: IntoIterator<Item = Box<dyn JobInfo>>,
+{
+    let tmp_path = path.with_extension("tmp");
+    let mut writer = BufWriter::new(File::create(&tmp_path)?);
+    let mut count = 0;
+    for entry in entries {
+        let Some(event_path) = entry.event_path() else {
+            warn!(
+                "Cannot save job {} for the next run, dropping it",
+                entry.jobid()
+            );
+            continue;
+        };
+        writeln!(writer, "{}", line(entry.as_ref(), &event_path))?;
+        count += 1;
+    }
+    writer
+        .into_inner()
+        .map_err(|e| e.into_error())?
+        .sync_all()?;
+    if count == 0 {
+        remove_file(&tmp_path)?;
+        if path.exists() {
+            remove_file(path)?;
+        }
+        return Ok(0);
+    }
+    rename(&tmp_path, path)?;
+    Ok(count)
+}
+
+/// Appends the job entry to the given file, in the format of the state file,
+/// together with the reason it was set aside. The file can be handed to a
+/// later run as its state file, so the entry is processed again.
+pub fn append(path: &Path, entry: &dyn JobInfo, reason: &str) -> Result<(), Error> {
+    let Some(event_path) = entry.event_path() else {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "the job cannot be created anew from the spool",
+        ));
+    };
+    let mut doc = line(entry, &event_path);
+    doc["reason"] = Value::from(reason);
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    // a single write, so concurrent appends do not interleave
+    file.write_all(format!("{doc}\n").as_bytes())
+}
+
+fn line(entry: &dyn JobInfo, event_path: &Path) -> Value {
+    json!({
+        "path": event_path,
+        "id": entry.jobid(),
+        "cluster": entry.cluster(),
+        "event_time": entry.event_time(),
+    })
+}
+
+/// Reads the job entries saved by a previous run from the given state file,
+/// creating them anew with the first of the schedulers that knows them, and
+/// removes the file. A missing file means there is nothing to pick up. Entries
+/// that are no longer in the spool are skipped.
+pub fn restore(path: &Path, schedulers: &[&dyn Scheduler]) -> Result<Vec<Box<dyn JobInfo>>, Error> {
+    let contents = match read_to_string(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+
+    let mut entries = Vec::new();
+    for line in contents.lines().filter(|l| !l.trim().is_empty()) {
+        let event_path = serde_json::from_str::<Value>(line)
+            .ok()
+            .and_then(|doc| doc["path"].as_str().map(PathBuf::from));
+        match event_path {
+            Some(event_path) => match schedulers
+                .iter()
+                .find_map(|scheduler| scheduler.create_job_info(&event_path))
+            {
+                Some(entry) => entries.push(entry),
+                None => info!(
+                    "Saved job at {:?} is no longer in the spool, skipping",
+                    &event_path
+                ),
+            },
+            None => warn!("Skipping invalid line in state file {:?}: {}", path, line),
+        }
+    }
+
+    remove_file(path)?;
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::scheduler::slurm::{Slurm, SlurmJobEntry};
+    use std::env::current_dir;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_save_restore() {
+        let tdir = tempdir().unwrap();
+        let state = tdir.path().join("queue.state");
+        let spool = current_dir().unwrap().join("tests");
+        let entries: Vec<Box<dyn JobInfo>> = ["job.123456", "job.vanished"]
+            .iter()
+            .map(|dir| {
+                let jobid = dir.strip_prefix("job.").unwrap();
+                Box::new(SlurmJobEntry::new(&spool.join(dir), jobid, "mycluster"))
+                    as Box<dyn JobInfo>
+            })
+            .collect();
+
+        assert_eq!(save(&state, entries).unwrap(), 2);
+        assert!(!state.with_extension("tmp").exists());
+
+        let slurm = Slurm::new(&spool, "mycluster");
+        let restored = restore(&state, &[&slurm]).unwrap();
+        assert_eq!(restored.len(), 1);
+        assert_eq!(restored[0].jobid(), "123456");
+        assert_eq!(restored[0].cluster(), "mycluster");
+        assert!(!state.exists());
+
+        // Nothing saved, nothing to restore
+        assert_eq!(save(&state, Vec::new()).unwrap(), 0);
+        assert!(!state.exists());
+        assert!(restore(&state, &[&slurm]).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_append() {
+        let tdir = tempdir().unwrap();
+        let dead_letter = tdir.path().join("dead-letter");
+        let spool = current_dir().unwrap().join("tests");
+        let entry = SlurmJobEntry::new(&spool.join("job.123456"), "123456", "mycluster");
+
+        append(&dead_letter, &entry, "took too long").unwrap();
+        append(&dead_letter, &entry, "took too long").unwrap();
+        let contents = read_to_string(&dead_letter).unwrap();
+        assert_eq!(contents.lines().count(), 2);
+        assert!(contents.contains("\"reason\":\"took too long\""));
+
+        // The entries are picked up again as a state file
+        let slurm = Slurm::new(&spool, "mycluster");
+        let restored = restore(&dead_letter, &[&slurm]).unwrap();
+        assert_eq!(restored.len(), 2);
+        assert_eq!(restored[0].jobid(), "123456");
+    }
+}
@@ -0,0 +1,813 @@
+/*
+Copyright 2019-2024 Andy Georges <itkovian+sarchive@gmail.com>
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in
+all copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+use chrono::{DateTime, Local, TimeZone, Utc};
+use crossbeam_channel::{Receiver, RecvTimeoutError};
+use log::info;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::Ordering::Relaxed;
+use std::sync::atomic::{AtomicBool, AtomicU64};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Counters for a single watch location
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct LocationStats {
+    /// Number of filesystem events received
+    pub events: u64,
+    /// Number of job entries queued for processing
+    pub jobs: u64,
+    /// Time of the last filesystem event
+    pub last_event: Option<Instant>,
+    /// Number of job entries found by reconciliation whose event was missed
+    pub missed: u64,
+    /// Number of times events were lost because the event queue overflowed
+    pub overflows: u64,
+    /// Whether a watcher is active on the location
+    pub watching: bool,
+}
+
+/// Counters for a single archival backend
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct BackendStats {
+    /// Number of job entries archived succesfully
+    pub archived: u64,
+    /// Number of job entries that could not be archived
+    pub failed: u64,
+    /// Time of the last succesful archival
+    pub last_success: Option<DateTime<Local>>,
+    /// Sum of the times from event receipt to succesful archival
+    pub total_latency: Duration,
+    /// Longest time from event receipt to succesful archival
+    pub max_latency: Duration,
+    /// Whether the circuit breaker holds the entries for this backend
+    pub circuit_open: bool,
+    /// Number of times the circuit breaker opened
+    pub circuit_trips: u64,
+    /// Number of job entries the backend did not take within the deadline
+    pub timeouts: u64,
+}
+
+impl BackendStats {
+    /// Average time from event receipt to succesful archival
+    pub fn average_latency(&self) -> Duration {
+        match u32::try_from(self.archived) {
+            Ok(archived) if archived > 0 => self.total_latency / archived,
+            _ => Duration::ZERO,
+        }
+    }
+}
+
+/// Upper bounds, in bytes, of the payload size histogram buckets; larger
+/// payloads fall in a final, unbounded bucket
+pub const PAYLOAD_BUCKETS: [u64; 8] = [
+    1 << 10,
+    4 << 10,
+    16 << 10,
+    64 << 10,
+    256 << 10,
+    1 << 20,
+    4 << 20,
+    16 << 20,
+];
+
+/// Sizes of the payloads a backend wrote or produced for a single cluster
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct PayloadStats {
+    /// Number of archived job entries
+    pub jobs: u64,
+    /// Total size of their payloads
+    pub bytes: u64,
+    /// Largest payload
+    pub max: u64,
+    /// Number of payloads in each bucket of PAYLOAD_BUCKETS, and above
+    pub buckets: [u64; PAYLOAD_BUCKETS.len() + 1],
+}
+
+impl PayloadStats {
+    fn record(&mut self, size: u64) {
+        self.jobs += 1;
+        self.bytes += size;
+        self.max = self.max.max(size);
+        let bucket = PAYLOAD_BUCKETS
+            .iter()
+            .position(|&bound| size <= bound)
+            .unwrap_or(PAYLOAD_BUCKETS.len());
+        self.buckets[bucket] += 1;
+    }
+
+    /// Average payload size
+    pub fn average(&self) -> u64 {
+        self.bytes.checked_div(self.jobs).unwrap_or(0)
+    }
+}
+
+/// Formats a bucket bound as, e.g., 4K or 16M
+fn bucket_label(bound: u64) -> String {
+    if bound >= 1 << 20 {
+        format!("{}M", bound >> 20)
+    } else {
+        format!("{}K", bound >> 10)
+    }
+}
+
+/// Job submissions counted per minute, since the last summary
+#[derive(Debug, Default)]
+struct Submissions {
+    /// Start of the period, in minutes since the epoch
+    since: u64,
+    /// Minute being counted, and the jobs queued in it
+    minute: u64,
+    count: u64,
+    /// Busiest minute of the period so far, and the jobs queued in it
+    peak: Option<(u64, u64)>,
+    /// Jobs queued in the period, per watch location
+    locations: BTreeMap<PathBuf, u64>,
+}
+
+impl Submissions {
+    fn record(&mut self, location: &Path, minute: u64) {
+        if minute != self.minute {
+            self.minute = minute;
+            self.count = 0;
+        }
+        self.count += 1;
+        match self.peak {
+            Some((_, peak)) if peak >= self.count => (),
+            _ => self.peak = Some((minute, self.count)),
+        }
+        *self.locations.entry(location.to_path_buf()).or_default() += 1;
+    }
+
+    /// Summarizes the period up to the given minute and starts a new one
+    fn summary(&mut self, minute: u64) -> SubmissionSummary {
+        let (peak_minute, peak) = self.peak.take().unzip();
+        let locations = std::mem::take(&mut self.locations);
+        let summary = SubmissionSummary {
+            minutes: minute.saturating_sub(self.since).max(1),
+            total: locations.values().sum(),
+            peak: peak.unwrap_or(0),
+            peak_at: peak_minute.and_then(|m| Local.timestamp_opt(m as i64 * 60, 0).single()),
+            locations,
+        };
+        self.since = minute;
+        self.count = 0;
+        summary
+    }
+}
+
+/// The job submissions over a period
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SubmissionSummary {
+    /// Length of the period, in minutes
+    pub minutes: u64,
+    /// Number of job entries queued in the period
+    pub total: u64,
+    /// Number of job entries queued in the busiest minute
+    pub peak: u64,
+    /// Start of the busiest minute
+    pub peak_at: Option<DateTime<Local>>,
+    /// Number of job entries queued per watch location
+    pub locations: BTreeMap<PathBuf, u64>,
+}
+
+impl SubmissionSummary {
+    /// Average number of job entries queued per minute
+    pub fn rate(&self) -> f64 {
+        self.total as f64 / self.minutes.max(1) as f64
+    }
+}
+
+/// Returns the current minute since the epoch
+fn current_minute() -> u64 {
+    Utc::now().timestamp() as u64 / 60
+}
+
+/// Live statistics on the operation of sarchive, shared between the
+/// monitoring and processing threads.
+pub struct Stats {
+    started: Instant,
+    locations: Mutex<BTreeMap<PathBuf, LocationStats>>,
+    backends: Mutex<BTreeMap<String, BackendStats>>,
+    payloads: Mutex<BTreeMap<(String, String), PayloadStats>>,
+    cancelled: AtomicU64,
+    deleted: AtomicU64,
+    read_timeouts: AtomicU64,
+    panics: Mutex<BTreeMap<String, u64>>,
+    standby: AtomicBool,
+    paused: AtomicBool,
+    held: AtomicU64,
+    backend_checked: AtomicBool,
+    ready: Mutex<Option<Vec<PathBuf>>>,
+    submissions: Mutex<Submissions>,
+}
+
+impl Default for Stats {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Stats {
+    pub fn new() -> Stats {
+        Stats {
+            started: Instant::now(),
+            locations: Mutex::new(BTreeMap::new()),
+            backends: Mutex::new(BTreeMap::new()),
+            payloads: Mutex::new(BTreeMap::new()),
+            cancelled: AtomicU64::new(0),
+            deleted: AtomicU64::new(0),
+            read_timeouts: AtomicU64::new(0),
+            panics: Mutex::new(BTreeMap::new()),
+            standby: AtomicBool::new(false),
+            paused: AtomicBool::new(false),
+            held: AtomicU64::new(0),
+            backend_checked: AtomicBool::new(false),
+            ready: Mutex::new(None),
+            submissions: Mutex::new(Submissions {
+                since: current_minute(),
+                ..Default::default()
+            }),
+        }
+    }
+
+    /// Time elapsed since the statistics were created
+    pub fn uptime(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    /// Records an event received for the given watch location
+    pub fn event(&self, location: &Path) {
+        let mut locations = self.locations.lock().unwrap_or_else(|e| e.into_inner());
+        let stats = locations.entry(location.to_path_buf()).or_default();
+        stats.events += 1;
+        stats.last_event = Some(Instant::now());
+    }
+
+    /// Records whether a watcher is active on the given watch location
+    pub fn watching(&self, location: &Path, watching: bool) {
+        self.locations
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .entry(location.to_path_buf())
+            .or_default()
+            .watching = watching;
+    }
+
+    /// Records a job entry being queued from the given watch location
+    pub fn job(&self, location: &Path) {
+        self.locations
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .entry(location.to_path_buf())
+            .or_default()
+            .jobs += 1;
+        self.submissions
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .record(location, current_minute());
+    }
+
+    /// Returns the job submissions since the previous summary, and starts
+    /// counting anew
+    pub fn submission_summary(&self) -> SubmissionSummary {
+        self.submissions
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .summary(current_minute())
+    }
+
+    /// Records a job entry in the given watch location whose event was missed
+    pub fn missed(&self, location: &Path) {
+        self.locations
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .entry(location.to_path_buf())
+            .or_default()
+            .missed += 1;
+    }
+
+    /// Records that events for the given watch location were lost because
+    /// the event queue overflowed
+    pub fn overflow(&self, location: &Path) {
+        self.locations
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .entry(location.to_path_buf())
+            .or_default()
+            .overflows += 1;
+    }
+
+    /// Records a succesful archival by the given backend, the given time
+    /// after the job's event was received
+    pub fn archived(&self, backend: &str, latency: Duration) {
+        let mut backends = self.backends.lock().unwrap_or_else(|e| e.into_inner());
+        let stats = backends.entry(backend.to_owned()).or_default();
+        stats.archived += 1;
+        stats.last_success = Some(Local::now());
+        stats.total_latency += latency;
+        stats.max_latency = stats.max_latency.max(latency);
+    }
+
+    /// Records the size of the payload the given backend wrote or produced
+    /// for a job of the given cluster
+    pub fn payload(&self, backend: &str, cluster: &str, size: u64) {
+        self.payloads
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .entry((backend.to_owned(), cluster.to_owned()))
+            .or_default()
+            .record(size);
+    }
+
+    /// Records a failed archival by the given backend
+    pub fn archive_failed(&self, backend: &str) {
+        self.backends
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .entry(backend.to_owned())
+            .or_default()
+            .failed += 1;
+    }
+
+    /// Records a job entry the given backend did not take within the deadline
+    pub fn timed_out(&self, backend: &str) {
+        self.backends
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .entry(backend.to_owned())
+            .or_default()
+            .timeouts += 1;
+    }
+
+    /// Records whether the circuit breaker of the given backend is open
+    pub fn set_circuit_open(&self, backend: &str, open: bool) {
+        let mut backends = self.backends.lock().unwrap_or_else(|e| e.into_inner());
+        let stats = backends.entry(backend.to_owned()).or_default();
+        if open && !stats.circuit_open {
+            stats.circuit_trips += 1;
+        }
+        stats.circuit_open = open;
+    }
+
+    /// Records a job that vanished before its information could be read
+    pub fn cancelled(&self) {
+        self.cancelled.fetch_add(1, Relaxed);
+    }
+
+    /// Number of jobs that vanished before their information could be read
+    pub fn cancelled_count(&self) -> u64 {
+        self.cancelled.load(Relaxed)
+    }
+
+    /// Records a job whose files were deleted from the spool
+    pub fn deleted(&self) {
+        self.deleted.fetch_add(1, Relaxed);
+    }
+
+    /// Number of jobs whose files were deleted from the spool
+    pub fn deleted_count(&self) -> u64 {
+        self.deleted.load(Relaxed)
+    }
+
+    /// Records a job entry whose information could not be read within the deadline
+    pub fn read_timed_out(&self) {
+        self.read_timeouts.fetch_add(1, Relaxed);
+    }
+
+    /// Number of job entries whose information could not be read within the deadline
+    pub fn read_timeout_count(&self) -> u64 {
+        self.read_timeouts.load(Relaxed)
+    }
+
+    /// Records a panic of the given thread, e.g., the watcher of a location
+    pub fn panicked(&self, what: &str) {
+        *self
+            .panics
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .entry(what.to_owned())
+            .or_default() += 1;
+    }
+
+    /// Number of panics per thread that panicked
+    pub fn panics(&self) -> BTreeMap<String, u64> {
+        self.panics
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+
+    /// Records whether archival is on hold because the archive storage is full
+    pub fn set_standby(&self, standby: bool) {
+        self.standby.store(standby, Relaxed);
+    }
+
+    /// Whether archival is on hold because the archive storage is full
+    pub fn in_standby(&self) -> bool {
+        self.standby.load(Relaxed)
+    }
+
+    /// Records whether archival is paused for maintenance
+    pub fn set_paused(&self, paused: bool) {
+        self.paused.store(paused, Relaxed);
+    }
+
+    /// Whether archival is paused for maintenance
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(Relaxed)
+    }
+
+    /// Records the number of captured job entries held until the end of maintenance
+    pub fn set_held(&self, held: usize) {
+        self.held.store(held as u64, Relaxed);
+    }
+
+    /// Number of captured job entries held until the end of maintenance
+    pub fn held_count(&self) -> u64 {
+        self.held.load(Relaxed)
+    }
+
+    /// Records that the archiver passed its connectivity check
+    pub fn set_backend_checked(&self) {
+        self.backend_checked.store(true, Relaxed);
+    }
+
+    /// Whether the archiver passed its connectivity check
+    pub fn backend_checked(&self) -> bool {
+        self.backend_checked.load(Relaxed)
+    }
+
+    /// Records that every given watch location is watched and the archiver
+    /// passed its check
+    pub fn set_ready(&self, locations: &[PathBuf]) {
+        *self.ready.lock().unwrap_or_else(|e| e.into_inner()) = Some(locations.to_vec());
+    }
+
+    /// Whether sarchive became ready and every watch location it became ready
+    /// with is still watched
+    pub fn is_ready(&self) -> bool {
+        let Some(required) = self.ready.lock().unwrap_or_else(|e| e.into_inner()).clone() else {
+            return false;
+        };
+        let watched = self.locations();
+        required
+            .iter()
+            .all(|location| watched.get(location).is_some_and(|l| l.watching))
+    }
+
+    /// Returns a copy of the counters for each watch location
+    pub fn locations(&self) -> BTreeMap<PathBuf, LocationStats> {
+        self.locations
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+
+    /// Returns a copy of the counters for each backend
+    pub fn backends(&self) -> BTreeMap<String, BackendStats> {
+        self.backends
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+
+    /// Returns a copy of the payload sizes for each backend and cluster
+    pub fn payloads(&self) -> BTreeMap<(String, String), PayloadStats> {
+        self.payloads
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+
+    /// Returns a human readable report of the current state, given the
+    /// number of job entries waiting to be processed.
+    pub fn report(&self, queue_length: usize) -> String {
+        let mut report = String::new();
+        writeln!(report, "uptime: {}s", self.uptime().as_secs()).unwrap();
+        writeln!(report, "ready: {}", self.is_ready()).unwrap();
+        writeln!(report, "queue length: {queue_length}").unwrap();
+        writeln!(report, "standby: {}", self.in_standby()).unwrap();
+        writeln!(report, "paused: {}", self.is_paused()).unwrap();
+        writeln!(report, "held for maintenance: {}", self.held_count()).unwrap();
+        writeln!(
+            report,
+            "cancelled before capture: {}",
+            self.cancelled_count()
+        )
+        .unwrap();
+        writeln!(report, "deleted: {}", self.deleted_count()).unwrap();
+        writeln!(report, "timed out reading: {}", self.read_timeout_count()).unwrap();
+        for (what, count) in self.panics() {
+            writeln!(report, "panics of {what}: {count}").unwrap();
+        }
+        for (location, stats) in self.locations() {
+            let last_event = stats.last_event.map_or_else(
+                || "never".to_owned(),
+                |t| format!("{}s ago", t.elapsed().as_secs()),
+            );
+            write!(
+                report,
+                "location {}: {} events, {} jobs, last event {}",
+                location.display(),
+                stats.events,
+                stats.jobs,
+                last_event
+            )
+            .unwrap();
+            if stats.missed > 0 {
+                write!(report, ", {} missed events", stats.missed).unwrap();
+            }
+            if stats.overflows > 0 {
+                write!(report, ", {} queue overflows", stats.overflows).unwrap();
+            }
+            writeln!(report).unwrap();
+        }
+        for (backend, stats) in self.backends() {
+            let last_success = stats
+                .last_success
+                .map_or_else(|| "never".to_owned(), |t| t.to_rfc3339());
+            write!(
+                report,
+                "backend {}: {} archived, {} failed, last success {}",
+                backend, stats.archived, stats.failed, last_success
+            )
+            .unwrap();
+            if stats.circuit_trips > 0 {
+                write!(
+                    report,
+                    ", circuit {} (opened {} times)",
+                    if stats.circuit_open { "open" } else { "closed" },
+                    stats.circuit_trips
+                )
+                .unwrap();
+            }
+            if stats.timeouts > 0 {
+                write!(report, ", {} timed out", stats.timeouts).unwrap();
+            }
+            if stats.archived > 0 {
+                write!(
+                    report,
+                    ", latency average {}ms, max {}ms",
+                    stats.average_latency().as_millis(),
+                    stats.max_latency.as_millis()
+                )
+                .unwrap();
+            }
+            writeln!(report).unwrap();
+        }
+        for ((backend, cluster), stats) in self.payloads() {
+            write!(
+                report,
+                "payload {} {}: {} bytes, average {}, max {}, sizes",
+                backend,
+                cluster,
+                stats.bytes,
+                stats.average(),
+                stats.max
+            )
+            .unwrap();
+            for (i, count) in stats.buckets.iter().enumerate() {
+                if *count == 0 {
+                    continue;
+                }
+                match PAYLOAD_BUCKETS.get(i) {
+                    Some(&bound) => write!(report, " <={}:{}", bucket_label(bound), count),
+                    None => write!(
+                        report,
+                        " >{}:{}",
+                        bucket_label(PAYLOAD_BUCKETS[PAYLOAD_BUCKETS.len() - 1]),
+                        count
+                    ),
+                }
+                .unwrap();
+            }
+            writeln!(report).unwrap();
+        }
+        report
+    }
+}
+
+/// Logs a summary of the job submissions every interval, and hands it to
+/// the given function (e.g., to publish it as metrics), until notified to stop
+pub fn report_submissions<F>(
+    stats: &Stats,
+    cluster: &str,
+    interval: Duration,
+    sigchannel: &Receiver<bool>,
+    mut publish: F,
+) where
+    F: FnMut(&SubmissionSummary),
+{
+    loop {
+        match sigchannel.recv_timeout(interval) {
+            Ok(true) | Err(RecvTimeoutError::Disconnected) => break,
+            Ok(false) => continue,
+            Err(RecvTimeoutError::Timeout) => (),
+        }
+        let summary = stats.submission_summary();
+        info!("Submissions: {}", summary_line(cluster, &summary));
+        publish(&summary);
+    }
+}
+
+/// Describes the submissions in a single line: the totals, the busiest minute
+/// and the jobs per watch location
+fn summary_line(cluster: &str, summary: &SubmissionSummary) -> String {
+    let mut line = format!(
+        "cluster {} queued {} jobs in {} minutes ({:.1}/min)",
+        cluster,
+        summary.total,
+        summary.minutes,
+        summary.rate()
+    );
+    if let Some(peak_at) = summary.peak_at {
+        write!(
+            line,
+            ", peak {}/min at {}",
+            summary.peak,
+            peak_at.format("%H:%M")
+        )
+        .unwrap();
+    }
+    for (location, jobs) in summary.locations.iter() {
+        write!(line, ", {}: {}", location.display(), jobs).unwrap();
+    }
+    line
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_location_counters() {
+        let stats = Stats::new();
+        let location = PathBuf::from("/var/spool/slurm/hash.1");
+
+        stats.event(&location);
+        stats.event(&location);
+        stats.job(&location);
+
+        let counters = stats.locations().get(&location).unwrap().clone();
+        assert_eq!(counters.events, 2);
+        assert_eq!(counters.jobs, 1);
+        assert!(counters.last_event.is_some());
+        assert!(!counters.watching);
+
+        stats.watching(&location, true);
+        assert!(stats.locations()[&location].watching);
+    }
+
+    #[test]
+    fn test_backend_counters() {
+        let stats = Stats::new();
+
+        stats.archive_failed("file");
+        assert_eq!(stats.backends().get("file").unwrap().last_success, None);
+
+        stats.archived("file", Duration::from_millis(2100));
+        stats.archived("file", Duration::from_millis(2500));
+        let backend = stats.backends().get("file").unwrap().clone();
+        assert_eq!(backend.archived, 2);
+        assert_eq!(backend.failed, 1);
+        assert!(backend.last_success.is_some());
+        assert_eq!(backend.average_latency(), Duration::from_millis(2300));
+        assert_eq!(backend.max_latency, Duration::from_millis(2500));
+
+        stats.set_circuit_open("file", true);
+        stats.set_circuit_open("file", true);
+        stats.set_circuit_open("file", false);
+        stats.set_circuit_open("file", true);
+        let backend = stats.backends().get("file").unwrap().clone();
+        assert!(backend.circuit_open);
+        assert_eq!(backend.circuit_trips, 2);
+    }
+
+    #[test]
+    fn test_submissions() {
+        let (hash0, hash1) = (Path::new("/spool/hash.0"), Path::new("/spool/hash.1"));
+        let mut submissions = Submissions {
+            since: 100,
+            ..Default::default()
+        };
+        submissions.record(hash0, 100);
+        for _ in 0..3 {
+            submissions.record(hash1, 102);
+        }
+        submissions.record(hash0, 103);
+
+        let summary = submissions.summary(110);
+        assert_eq!(summary.minutes, 10);
+        assert_eq!(summary.total, 5);
+        assert_eq!(summary.peak, 3);
+        assert_eq!(summary.peak_at, Local.timestamp_opt(102 * 60, 0).single());
+        assert_eq!(summary.locations[hash0], 2);
+        assert_eq!(summary.rate(), 0.5);
+        assert_eq!(
+            summary_line("mycluster", &summary),
+            format!(
+                "cluster mycluster queued 5 jobs in 10 minutes (0.5/min), peak 3/min at {}, /spool/hash.0: 2, /spool/hash.1: 3",
+                summary.peak_at.unwrap().format("%H:%M")
+            )
+        );
+
+        // the next period starts empty
+        let summary = submissions.summary(110);
+        assert_eq!((summary.minutes, summary.total, summary.peak), (1, 0, 0));
+        assert!(summary.locations.is_empty());
+        assert_eq!(
+            summary_line("mycluster", &summary),
+            "cluster mycluster queued 0 jobs in 1 minutes (0.0/min)"
+        );
+
+        let stats = Stats::new();
+        stats.job(hash0);
+        assert_eq!(stats.submission_summary().total, 1);
+    }
+
+    #[test]
+    fn test_report() {
+        let stats = Stats::new();
+        stats.event(Path::new("/spool/hash.0"));
+        stats.job(Path::new("/spool/hash.0"));
+        stats.archive_failed("kafka");
+        stats.archived("file", Duration::from_millis(2000));
+        stats.cancelled();
+        stats.deleted();
+        stats.read_timed_out();
+        stats.panicked("processing");
+        stats.timed_out("kafka");
+        stats.missed(Path::new("/spool/hash.1"));
+        stats.overflow(Path::new("/spool/hash.1"));
+
+        let report = stats.report(3);
+        assert!(report.starts_with("uptime: 0s\n"));
+        assert!(report.contains("queue length: 3\n"));
+        assert!(report.contains("standby: false\n"));
+        assert!(report.contains("paused: false\n"));
+        assert!(report.contains("held for maintenance: 0\n"));
+        assert!(report.contains("cancelled before capture: 1\n"));
+        assert!(report.contains("deleted: 1\n"));
+        assert!(report.contains("timed out reading: 1\n"));
+        assert!(report.contains("panics of processing: 1\n"));
+        assert!(report.contains("location /spool/hash.0: 1 events, 1 jobs, last event 0s ago\n"));
+        assert!(report.contains(
+            "location /spool/hash.1: 0 events, 0 jobs, last event never, 1 missed events, 1 queue overflows\n"
+        ));
+        assert!(report
+            .contains("backend kafka: 0 archived, 1 failed, last success never, 1 timed out\n"));
+        assert!(report.contains(", latency average 2000ms, max 2000ms\n"));
+    }
+
+    #[test]
+    fn test_payloads() {
+        let stats = Stats::new();
+        stats.payload("kafka", "alpha", 100);
+        stats.payload("kafka", "alpha", 5000);
+        stats.payload("kafka", "alpha", 32 << 20);
+        stats.payload("file", "beta", 1024);
+
+        let payloads = stats.payloads();
+        let kafka = &payloads[&("kafka".to_owned(), "alpha".to_owned())];
+        assert_eq!(kafka.jobs, 3);
+        assert_eq!(kafka.bytes, 5100 + (32 << 20));
+        assert_eq!(kafka.max, 32 << 20);
+        assert_eq!(kafka.buckets, [1, 0, 1, 0, 0, 0, 0, 0, 1]);
+        assert_eq!(
+            payloads[&("file".to_owned(), "beta".to_owned())].buckets[0],
+            1
+        );
+
+        let report = stats.report(0);
+        assert!(report
+            .contains("payload file beta: 1024 bytes, average 1024, max 1024, sizes <=1K:1\n"));
+        assert!(report.contains(&format!(
+            "payload kafka alpha: {} bytes, average {}, max {}, sizes <=1K:1 <=16K:1 >16M:1\n",
+            5100 + (32 << 20),
+            (5100 + (32 << 20)) / 3,
+            32 << 20
+        )));
+    }
+}
@@ -0,0 +1,215 @@
+/*
+Copyright 2019-2024 Andy Georges <itkovian+sarchive@gmail.com>
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in
+all copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+use log::{error, warn};
+use std::any::Any;
+use std::cell::{Cell, RefCell};
+use std::panic::{catch_unwind, set_hook, take_hook, AssertUnwindSafe};
+use std::sync::Once;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use crate::stats::Stats;
+use crate::utils::job_context;
+
+/// How often a thread that panics is restarted by default
+pub const DEFAULT_RESTARTS: u32 = 3;
+
+/// How long to wait before restarting a thread that panicked, so a panic
+/// that recurs right away does not spin
+const RESTART_DELAY: Duration = Duration::from_secs(1);
+
+/// How long work has to run without panicking before its earlier restarts
+/// are forgotten, so panics spread over a long time do not add up
+const HEALTHY_PERIOD: Duration = Duration::from_secs(3600);
+
+thread_local! {
+    /// Whether the current thread runs supervised work
+    static SUPERVISED: Cell<bool> = const { Cell::new(false) };
+    /// Where the last panic of the current thread happened, and in the
+    /// context of which job
+    static LAST_PANIC: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+static HOOK: Once = Once::new();
+
+/// Installs a panic hook that keeps where supervised work panicked, as the
+/// job context is gone once the panic is caught. Other panics are reported
+/// by the hook that was in place.
+fn install_hook() {
+    HOOK.call_once(|| {
+        let previous = take_hook();
+        set_hook(Box::new(move |info| {
+            if !SUPERVISED.with(|s| s.get()) {
+                return previous(info);
+            }
+            let location = info
+                .location()
+                .map(|l| format!(" at {}:{}", l.file(), l.line()))
+                .unwrap_or_default();
+            let context = job_context().map(|c| format!(" ({c})")).unwrap_or_default();
+            LAST_PANIC.with(|p| *p.borrow_mut() = Some(format!("{location}{context}")));
+        }));
+    });
+}
+
+/// Returns the message a panic was raised with
+fn message(payload: &(dyn Any + Send)) -> &str {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message
+    } else {
+        "unknown cause"
+    }
+}
+
+/// Runs the work of a watcher or processing thread, so that a panic does not
+/// silently end it: the panic is logged and counted, and the work is started
+/// again, a bounded number of times in a row.
+///
+/// Archivers recover the locks that a panic poisoned, so a restarted thread
+/// does not panic again on the state the panic left behind.
+#[derive(Clone, Copy, Debug)]
+pub struct Supervisor {
+    /// How often the work is started again after a panic
+    pub max_restarts: u32,
+    delay: Duration,
+    healthy: Duration,
+}
+
+impl Default for Supervisor {
+    fn default() -> Self {
+        Self::new(DEFAULT_RESTARTS)
+    }
+}
+
+impl Supervisor {
+    pub fn new(max_restarts: u32) -> Self {
+        Supervisor {
+            max_restarts,
+            delay: RESTART_DELAY,
+            healthy: HEALTHY_PERIOD,
+        }
+    }
+
+    /// Runs the work in the current thread, returning its result. When the
+    /// work panics, it is run again, up to the maximum number of restarts,
+    /// after which None is returned. Work that ran for the healthy period
+    /// before panicking starts counting its restarts anew.
+    pub fn run<T>(&self, what: &str, stats: &Stats, mut work: impl FnMut() -> T) -> Option<T> {
+        install_hook();
+        let mut restarts = 0;
+        loop {
+            let supervised = SUPERVISED.with(|s| s.replace(true));
+            let started = Instant::now();
+            let outcome = catch_unwind(AssertUnwindSafe(&mut work));
+            SUPERVISED.with(|s| s.set(supervised));
+            let payload = match outcome {
+                Ok(result) => return Some(result),
+                Err(payload) => payload,
+            };
+            let place = LAST_PANIC
+                .with(|p| p.borrow_mut().take())
+                .unwrap_or_default();
+            error!("{} panicked{}: {}", what, place, message(payload.as_ref()));
+            stats.panicked(what);
+            if started.elapsed() >= self.healthy {
+                restarts = 0;
+            }
+            if restarts >= self.max_restarts {
+                error!(
+                    "Not restarting {} after {} restarts",
+                    what, self.max_restarts
+                );
+                return None;
+            }
+            restarts += 1;
+            warn!(
+                "Restarting {} ({} of {})",
+                what, restarts, self.max_restarts
+            );
+            sleep(self.delay);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::JobContext;
+
+    #[test]
+    fn test_run() {
+        let stats = Stats::new();
+        let supervisor = Supervisor {
+            max_restarts: 2,
+            delay: Duration::ZERO,
+            healthy: Duration::from_secs(60),
+        };
+        assert_eq!(supervisor.run("work", &stats, || 42), Some(42));
+        assert!(stats.panics().is_empty());
+
+        // panics twice, then succeeds on the second restart
+        let mut attempts = 0;
+        let outcome = supervisor.run("flaky", &stats, || {
+            attempts += 1;
+            let _context = JobContext::enter("mycluster", "12");
+            if attempts < 3 {
+                panic!("attempt {attempts}");
+            }
+            attempts
+        });
+        assert_eq!(outcome, Some(3));
+        assert_eq!(stats.panics()["flaky"], 2);
+
+        // gives up after the restarts
+        let outcome: Option<()> = supervisor.run("broken", &stats, || panic!("always"));
+        assert_eq!(outcome, None);
+        assert_eq!(stats.panics()["broken"], 3);
+
+        // panics that each follow a healthy run do not add up
+        let supervisor = Supervisor {
+            max_restarts: 1,
+            delay: Duration::ZERO,
+            healthy: Duration::from_millis(20),
+        };
+        let mut attempts = 0;
+        let outcome = supervisor.run("healthy", &stats, || {
+            attempts += 1;
+            if attempts < 4 {
+                sleep(Duration::from_millis(30));
+                panic!("attempt {attempts}");
+            }
+            attempts
+        });
+        assert_eq!(outcome, Some(4));
+        assert_eq!(stats.panics()["healthy"], 3);
+    }
+
+    #[test]
+    fn test_message() {
+        assert_eq!(message(&"static"), "static");
+        assert_eq!(message(&"owned".to_owned()), "owned");
+        assert_eq!(message(&42), "unknown cause");
+    }
+}
@@ -0,0 +1,265 @@
+/*
+Copyright 2019-2024 Andy Georges <itkovian+sarchive@gmail.com>
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in
+all copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+//! Fixtures and test doubles shared by the unit tests of several modules
+
+use std::env::current_dir;
+use std::fs::{create_dir, create_dir_all, write};
+use std::io::{Error, ErrorKind};
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering::SeqCst};
+use std::sync::{Arc, Mutex};
+use std::thread::sleep;
+use std::time::Duration;
+
+use crate::archive::cas::{object_path, ManifestEntry};
+use crate::archive::dedup::content_hash;
+use crate::archive::Archive;
+use crate::completion::Completion;
+use crate::scheduler::job::{JobInfo, JobRecord};
+use crate::scheduler::slurm::SlurmJobEntry;
+
+/// Returns the entry of the Slurm job in tests/job.123456, read for the
+/// given cluster
+pub fn slurm_entry(cluster: &str) -> SlurmJobEntry {
+    let path = current_dir().unwrap().join("tests/job.123456");
+    let mut entry = SlurmJobEntry::new(&path, "123456", cluster);
+    entry.read_job_info().unwrap();
+    entry
+}
+
+/// Returns the record of the Slurm job in tests/job.123456
+pub fn record() -> JobRecord {
+    JobRecord::new(&slurm_entry("mycluster"))
+}
+
+/// Writes a Slurm job entry with the given script and environment file in
+/// the directory, and returns its record
+pub fn slurm_job(dir: &Path, jobid: &str, script: &str, environment: &[u8]) -> JobRecord {
+    let job_dir = dir.join(format!("job.{jobid}"));
+    create_dir(&job_dir).unwrap();
+    write(job_dir.join("script"), script).unwrap();
+    write(job_dir.join("environment"), environment).unwrap();
+    let mut entry = SlurmJobEntry::new(&job_dir, jobid, "mycluster");
+    entry.read_job_info().unwrap();
+    JobRecord::new(&entry)
+}
+
+/// Records what it is asked to archive
+#[derive(Clone, Default)]
+pub struct RecordingArchiver {
+    /// A line per call, in order: `job ID`, `tombstone ID EVENT` or
+    /// `completion ID`
+    pub lines: Arc<Mutex<Vec<String>>>,
+    pub jobs: Arc<Mutex<Vec<JobRecord>>>,
+    pub tombstones: Arc<Mutex<Vec<JobRecord>>>,
+    pub completions: Arc<Mutex<Vec<Completion>>>,
+    /// The size of each batch
+    pub batches: Arc<Mutex<Vec<usize>>>,
+}
+
+impl RecordingArchiver {
+    pub fn lines(&self) -> Vec<String> {
+        self.lines.lock().unwrap().clone()
+    }
+
+    pub fn jobs(&self) -> Vec<JobRecord> {
+        self.jobs.lock().unwrap().clone()
+    }
+
+    pub fn tombstones(&self) -> Vec<JobRecord> {
+        self.tombstones.lock().unwrap().clone()
+    }
+
+    pub fn completions(&self) -> Vec<Completion> {
+        self.completions.lock().unwrap().clone()
+    }
+
+    pub fn batches(&self) -> Vec<usize> {
+        self.batches.lock().unwrap().clone()
+    }
+}
+
+impl Archive for RecordingArchiver {
+    fn archive(&self, job_entry: &JobRecord) -> Result<(), Error> {
+        let line = format!("job {}", job_entry.jobid());
+        self.lines.lock().unwrap().push(line);
+        self.jobs.lock().unwrap().push(job_entry.clone());
+        Ok(())
+    }
+
+    fn archive_batch(&self, job_entries: &[JobRecord]) -> Vec<Result<(), Error>> {
+        self.batches.lock().unwrap().push(job_entries.len());
+        job_entries.iter().map(|job| self.archive(job)).collect()
+    }
+
+    fn archive_tombstone(&self, job_entry: &JobRecord) -> Result<(), Error> {
+        let line = format!(
+            "tombstone {} {}",
+            job_entry.jobid(),
+            job_entry.tombstone_event()
+        );
+        self.lines.lock().unwrap().push(line);
+        self.tombstones.lock().unwrap().push(job_entry.clone());
+        Ok(())
+    }
+
+    fn archive_completion(&self, completion: &Completion) -> Result<(), Error> {
+        let line = format!("completion {}", completion.jobid);
+        self.lines.lock().unwrap().push(line);
+        self.completions.lock().unwrap().push(completion.clone());
+        Ok(())
+    }
+
+    fn name(&self) -> &str {
+        "recording"
+    }
+}
+
+/// Fails the given number of archive calls and connectivity checks with
+/// the given error, counting the attempts, then passes the jobs on to a
+/// recording archiver
+#[derive(Clone)]
+pub struct FlakyArchiver {
+    pub failures: Arc<AtomicUsize>,
+    pub error: fn() -> Error,
+    pub attempts: Arc<AtomicUsize>,
+    pub recording: RecordingArchiver,
+}
+
+impl FlakyArchiver {
+    pub fn new(failures: usize, error: fn() -> Error) -> Self {
+        FlakyArchiver {
+            failures: Arc::new(AtomicUsize::new(failures)),
+            error,
+            attempts: Arc::default(),
+            recording: RecordingArchiver::default(),
+        }
+    }
+
+    /// Fails every call
+    pub fn failing(error: fn() -> Error) -> Self {
+        Self::new(usize::MAX, error)
+    }
+
+    /// Fails the given number of calls as if the backend were unreachable
+    pub fn down(failures: usize) -> Self {
+        Self::new(failures, || {
+            Error::new(ErrorKind::ConnectionRefused, "backend down")
+        })
+    }
+
+    pub fn attempts(&self) -> usize {
+        self.attempts.load(SeqCst)
+    }
+
+    fn fail(&self) -> Result<(), Error> {
+        self.attempts.fetch_add(1, SeqCst);
+        match self
+            .failures
+            .fetch_update(SeqCst, SeqCst, |n| n.checked_sub(1))
+        {
+            Ok(_) => Err((self.error)()),
+            Err(_) => Ok(()),
+        }
+    }
+}
+
+impl Archive for FlakyArchiver {
+    fn archive(&self, job_entry: &JobRecord) -> Result<(), Error> {
+        self.fail()?;
+        self.recording.archive(job_entry)
+    }
+
+    fn check(&self, _cluster: &str) -> Result<(), Error> {
+        self.fail()
+    }
+
+    fn name(&self) -> &str {
+        "flaky"
+    }
+}
+
+/// Takes the given time to archive a job or tell its payload size
+pub struct SlowArchiver(pub Duration);
+
+impl Archive for SlowArchiver {
+    fn archive(&self, _job_entry: &JobRecord) -> Result<(), Error> {
+        sleep(self.0);
+        Ok(())
+    }
+
+    fn payload_size(&self, _job: &JobRecord) -> u64 {
+        sleep(self.0);
+        42
+    }
+
+    fn name(&self) -> &str {
+        "slow"
+    }
+}
+
+/// Takes only the first job of a batch
+pub struct ShortArchiver(pub RecordingArchiver);
+
+impl Archive for ShortArchiver {
+    fn archive(&self, job_entry: &JobRecord) -> Result<(), Error> {
+        self.0.archive(job_entry)
+    }
+
+    fn archive_batch(&self, job_entries: &[JobRecord]) -> Vec<Result<(), Error>> {
+        vec![self.archive(&job_entries[0])]
+    }
+
+    fn name(&self) -> &str {
+        "short"
+    }
+}
+
+/// The script of job 123 in the archive fixture
+pub const SCRIPT: &str = "#!/bin/bash\n#SBATCH -J \"train, again\"\n#SBATCH --gres=gpu:a100:2\n#SBATCH --time=1:00:00\n# run it\necho \"<done>\"\n";
+
+/// Writes a file archive holding a day of jobs: job 123 with its script and
+/// environment, job 4567 with only a script, job 890 in the content store,
+/// and a file that belongs to no job
+pub fn archive(root: &Path) {
+    let dir = root.join("20240101");
+    create_dir_all(&dir).unwrap();
+    write(dir.join("job.123_script"), SCRIPT).unwrap();
+    write(
+        dir.join("job.123_environment"),
+        "\0\0\0\0SLURM_JOB_USER=alice\0SLURM_JOB_PARTITION=gpu\0HOME=/home/alice\0",
+    )
+    .unwrap();
+    write(dir.join("job.4567_script"), "#!/bin/sh\n").unwrap();
+    write(dir.join("notes.txt"), "").unwrap();
+
+    let hash = content_hash(b"#!/bin/bash\nhostname\n");
+    let object = object_path(root, &hash);
+    create_dir_all(object.parent().unwrap()).unwrap();
+    write(&object, "#!/bin/bash\nhostname\n").unwrap();
+    let entry = ManifestEntry {
+        hash,
+        size: 21,
+        name: "job.890_script".to_owned(),
+    };
+    write(dir.join("job.890_manifest"), entry.line() + "\n").unwrap();
+}
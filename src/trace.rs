@@ -0,0 +1,424 @@
+/*
+Copyright 2019-2024 Andy Georges <itkovian+sarchive@gmail.com>
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in
+all copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+use chrono::Utc;
+use clap::Args;
+use crossbeam_channel::{Receiver, RecvTimeoutError, Sender};
+use log::{debug, info, warn};
+use notify::event::{
+    AccessKind, AccessMode, CreateKind, DataChange, Event, EventKind, Flag, ModifyKind, RemoveKind,
+    RenameMode,
+};
+use serde_json::{json, Value};
+use std::fs::{read_to_string, File, OpenOptions};
+use std::io::{Error, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::archive::ArchiverArgs;
+use crate::monitor::handle_event;
+use crate::reconcile::Reconciler;
+use crate::scheduler::job::JobInfo;
+use crate::scheduler::Scheduler;
+use crate::stats::Stats;
+
+/// Event kinds that can be replayed, recorded by their debug representation
+const KINDS: [EventKind; 16] = [
+    EventKind::Any,
+    EventKind::Other,
+    EventKind::Create(CreateKind::Any),
+    EventKind::Create(CreateKind::File),
+    EventKind::Create(CreateKind::Folder),
+    EventKind::Create(CreateKind::Other),
+    EventKind::Modify(ModifyKind::Any),
+    EventKind::Modify(ModifyKind::Data(DataChange::Any)),
+    EventKind::Modify(ModifyKind::Name(RenameMode::Any)),
+    EventKind::Modify(ModifyKind::Name(RenameMode::To)),
+    EventKind::Modify(ModifyKind::Name(RenameMode::From)),
+    EventKind::Modify(ModifyKind::Name(RenameMode::Both)),
+    EventKind::Remove(RemoveKind::Any),
+    EventKind::Remove(RemoveKind::File),
+    EventKind::Remove(RemoveKind::Folder),
+    EventKind::Access(AccessKind::Close(AccessMode::Write)),
+];
+
+#[derive(Args, Debug)]
+pub struct ReplayArgs {
+    #[arg(help = "Trace file written with --record-trace")]
+    pub trace: PathBuf,
+
+    #[arg(
+        long,
+        value_parser = parse_speed,
+        default_value = "1x",
+        help = "How many times faster than recorded to replay the events, e.g., 10x"
+    )]
+    pub speed: f64,
+
+    #[arg(
+        long,
+        value_name = "PATH",
+        help = "Spool the trace was recorded in, replaced by --spool in the event paths to replay against a copy of the spool"
+    )]
+    pub recorded_spool: Option<PathBuf>,
+
+    #[command(subcommand)]
+    pub archiver: ArchiverArgs,
+}
+
+/// Parses a replay speed such as 10x or 0.5
+pub fn parse_speed(s: &str) -> Result<f64, String> {
+    match s.trim_end_matches('x').parse::<f64>() {
+        Ok(speed) if speed > 0.0 && speed.is_finite() => Ok(speed),
+        _ => Err(format!("{s} is not a speed such as 10x")),
+    }
+}
+
+/// Keeps the events received on the watch locations, before they are handled
+pub trait EventRecorder: Send + Sync {
+    fn record(&self, location: &Path, event: &Event);
+}
+
+/// Returns the paths as text, replacing what is not UTF-8, which JSON cannot
+/// hold
+fn lossy(paths: &[PathBuf]) -> Vec<String> {
+    paths
+        .iter()
+        .map(|path| path.to_string_lossy().into_owned())
+        .collect()
+}
+
+/// Writes the events received on the watch locations to a trace file, as
+/// JSON lines with the time since recording started
+pub struct TraceRecorder {
+    file: Mutex<File>,
+    start: Instant,
+}
+
+impl TraceRecorder {
+    pub fn create(path: &Path) -> Result<Self, Error> {
+        Ok(TraceRecorder {
+            file: Mutex::new(File::create(path)?),
+            start: Instant::now(),
+        })
+    }
+}
+
+impl EventRecorder for TraceRecorder {
+    fn record(&self, location: &Path, event: &Event) {
+        let line = json!({
+            "offset": self.start.elapsed().as_secs_f64(),
+            "location": location.to_string_lossy(),
+            "kind": format!("{:?}", event.kind),
+            "paths": lossy(&event.paths),
+            "rescan": event.need_rescan(),
+        });
+        if let Err(e) = writeln!(
+            self.file.lock().unwrap_or_else(|e| e.into_inner()),
+            "{line}"
+        ) {
+            warn!("Cannot record event {:?}: {}", event, e);
+        }
+    }
+}
+
+/// Appends the events received on the watch locations to a log file, as JSON
+/// lines with the time they were received, for auditing what the spool saw
+/// regardless of what was archived
+pub struct EventLog {
+    file: Mutex<File>,
+}
+
+impl EventLog {
+    /// Opens the log for appending, creating it if needed
+    pub fn open(path: &Path) -> Result<Self, Error> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(EventLog {
+            file: Mutex::new(file),
+        })
+    }
+}
+
+impl EventRecorder for EventLog {
+    fn record(&self, location: &Path, event: &Event) {
+        let line = json!({
+            "timestamp": Utc::now(),
+            "location": location.to_string_lossy(),
+            "kind": format!("{:?}", event.kind),
+            "paths": lossy(&event.paths),
+            "rescan": event.need_rescan(),
+        });
+        if let Err(e) = writeln!(
+            self.file.lock().unwrap_or_else(|e| e.into_inner()),
+            "{line}"
+        ) {
+            warn!("Cannot log event {:?}: {}", event, e);
+        }
+    }
+}
+
+/// An event read back from a trace file
+#[derive(Debug, PartialEq)]
+pub struct TracedEvent {
+    pub offset: Duration,
+    pub location: PathBuf,
+    pub event: Event,
+}
+
+impl TracedEvent {
+    fn parse(line: &str) -> Option<Self> {
+        let doc: Value = serde_json::from_str(line).ok()?;
+        let kind = doc["kind"].as_str()?;
+        let kind = KINDS.into_iter().find(|k| format!("{k:?}") == kind)?;
+        let mut event = Event::new(kind);
+        for path in doc["paths"].as_array()? {
+            event = event.add_path(PathBuf::from(path.as_str()?));
+        }
+        if doc["rescan"].as_bool() == Some(true) {
+            event = event.set_flag(Flag::Rescan);
+        }
+        Some(TracedEvent {
+            offset: Duration::try_from_secs_f64(doc["offset"].as_f64()?).ok()?,
+            location: PathBuf::from(doc["location"].as_str()?),
+            event,
+        })
+    }
+
+    /// Moves the paths of the event from the recorded spool to the given one
+    pub fn rebase(&mut self, from: &Path, to: &Path) {
+        let rebase = |path: &mut PathBuf| {
+            if let Ok(relative) = path.strip_prefix(from) {
+                *path = to.join(relative);
+            }
+        };
+        rebase(&mut self.location);
+        self.event.paths.iter_mut().for_each(rebase);
+    }
+}
+
+/// Reads the events from a trace file, skipping the lines that cannot be
+/// replayed
+pub fn read_trace(path: &Path) -> Result<Vec<TracedEvent>, Error> {
+    let mut events = Vec::new();
+    for line in read_to_string(path)?.lines() {
+        match TracedEvent::parse(line) {
+            Some(event) => events.push(event),
+            None => warn!("Skipping event that cannot be replayed: {}", line),
+        }
+    }
+    if events.is_empty() {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("No events to replay in {path:?}"),
+        ));
+    }
+    Ok(events)
+}
+
+/// Hands the events to the scheduler of the spool root they belong to, at the
+/// pace they were recorded, sped up by the given factor, as the monitors of the
+/// watch locations would. Returns the longest queue seen, or stops early when
+/// notified.
+pub fn replay(
+    events: Vec<TracedEvent>,
+    speed: f64,
+    schedulers: &[(PathBuf, Box<dyn Scheduler>)],
+    s: &Sender<Box<dyn JobInfo>>,
+    sigchannel: &Receiver<bool>,
+    stats: &Stats,
+    reconciler: &Reconciler,
+) -> Result<usize, Error> {
+    let start = Instant::now();
+    let mut longest = 0;
+    for traced in events {
+        if let Some(wait) = traced.offset.div_f64(speed).checked_sub(start.elapsed()) {
+            match sigchannel.recv_timeout(wait) {
+                Ok(true) => {
+                    info!("Stopped replaying");
+                    break;
+                }
+                Ok(false) | Err(RecvTimeoutError::Timeout) => (),
+                Err(RecvTimeoutError::Disconnected) => break,
+            }
+        }
+        let Some((_, scheduler)) = schedulers
+            .iter()
+            .find(|(root, _)| traced.location.starts_with(root))
+        else {
+            debug!("Skipping event outside the spool: {:?}", &traced.event);
+            continue;
+        };
+        handle_event(
+            scheduler,
+            &traced.location,
+            s,
+            stats,
+            reconciler,
+            traced.event,
+        )?;
+        longest = longest.max(s.len());
+    }
+    Ok(longest)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scheduler::slurm::Slurm;
+    use crossbeam_channel::{never, unbounded};
+    use notify::event::CreateKind;
+    use std::fs::{create_dir_all, write};
+    use std::os::unix::ffi::OsStrExt;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_parse_speed() {
+        assert_eq!(parse_speed("10x"), Ok(10.0));
+        assert_eq!(parse_speed("0.5"), Ok(0.5));
+        assert!(parse_speed("0x").is_err());
+        assert!(parse_speed("fast").is_err());
+    }
+
+    #[test]
+    fn test_record_read_trace() {
+        let tdir = tempdir().unwrap();
+        let path = tdir.path().join("trace.jsonl");
+        let location = PathBuf::from("/spool/hash.3");
+        let events = [
+            Event::new(EventKind::Create(CreateKind::Folder)).add_path(location.join("job.12")),
+            Event::new(EventKind::Modify(ModifyKind::Name(RenameMode::To)))
+                .add_path(location.join("job.13")),
+            Event::new(EventKind::Other).set_flag(Flag::Rescan),
+        ];
+        let recorder = TraceRecorder::create(&path).unwrap();
+        for event in events.iter() {
+            recorder.record(&location, event);
+        }
+        drop(recorder);
+        write(
+            &path,
+            read_to_string(&path).unwrap() + "{\"kind\": \"Unknown\"}\n",
+        )
+        .unwrap();
+
+        let mut traced = read_trace(&path).unwrap();
+        assert_eq!(traced.len(), 3);
+        for (traced, event) in traced.iter().zip(events.iter()) {
+            assert_eq!(&traced.event, event);
+            assert_eq!(traced.location, location);
+        }
+        assert!(traced[0].offset <= traced[1].offset);
+
+        traced[0].rebase(Path::new("/spool"), Path::new("/copy"));
+        assert_eq!(traced[0].location, PathBuf::from("/copy/hash.3"));
+        assert_eq!(
+            traced[0].event.paths,
+            vec![PathBuf::from("/copy/hash.3/job.12")]
+        );
+    }
+
+    #[test]
+    fn test_event_log() {
+        let tdir = tempdir().unwrap();
+        let path = tdir.path().join("events.jsonl");
+        let location = PathBuf::from("/spool/hash.3");
+        write(&path, "{}\n").unwrap();
+
+        let log = EventLog::open(&path).unwrap();
+        log.record(
+            &location,
+            &Event::new(EventKind::Remove(RemoveKind::Folder)).add_path(location.join("job.12")),
+        );
+        log.record(
+            &location,
+            &Event::new(EventKind::Other).set_flag(Flag::Rescan),
+        );
+        // a file name that is not UTF-8 is logged as well
+        let odd = location.join(std::ffi::OsStr::from_bytes(b"job.\xff"));
+        log.record(
+            &location,
+            &Event::new(EventKind::Create(CreateKind::File)).add_path(odd),
+        );
+        drop(log);
+
+        // earlier lines are kept
+        let lines: Vec<Value> = read_to_string(&path)
+            .unwrap()
+            .lines()
+            .map(|l| serde_json::from_str(l).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 4);
+        assert_eq!(lines[3]["paths"], json!(["/spool/hash.3/job.\u{fffd}"]));
+        assert_eq!(lines[1]["location"], "/spool/hash.3");
+        assert_eq!(lines[1]["kind"], "Remove(Folder)");
+        assert_eq!(lines[1]["paths"], json!(["/spool/hash.3/job.12"]));
+        assert_eq!(lines[1]["rescan"], false);
+        assert!(lines[1]["timestamp"]
+            .as_str()
+            .unwrap()
+            .parse::<chrono::DateTime<Utc>>()
+            .is_ok());
+        assert_eq!(lines[2]["rescan"], true);
+    }
+
+    #[test]
+    fn test_replay() {
+        let tdir = tempdir().unwrap();
+        let spool = tdir.path().to_path_buf();
+        let location = spool.join("hash.2");
+        create_dir_all(location.join("job.12")).unwrap();
+        let event = |offset: u64, name: &str| TracedEvent {
+            offset: Duration::from_millis(offset),
+            location: location.clone(),
+            event: Event::new(EventKind::Create(CreateKind::Folder)).add_path(location.join(name)),
+        };
+        let elsewhere = TracedEvent {
+            location: PathBuf::from("/elsewhere/hash.2"),
+            ..event(0, "job.14")
+        };
+        let events = vec![event(0, "job.12"), event(400, "tmp.13"), elsewhere];
+
+        let schedulers: Vec<(PathBuf, Box<dyn Scheduler>)> =
+            vec![(spool.clone(), Box::new(Slurm::new(&spool, "mycluster")))];
+        let (sender, receiver) = unbounded();
+        let stats = Stats::new();
+        let start = Instant::now();
+        let longest = replay(
+            events,
+            4.0,
+            &schedulers,
+            &sender,
+            &never(),
+            &stats,
+            &Reconciler::default(),
+        )
+        .unwrap();
+
+        // the events take a quarter of the recorded time
+        assert!(start.elapsed() >= Duration::from_millis(100));
+        assert!(start.elapsed() < Duration::from_millis(400));
+        assert_eq!(longest, 1);
+        assert_eq!(receiver.try_recv().unwrap().jobid(), "12");
+        assert!(receiver.try_recv().is_err());
+        assert_eq!(stats.locations()[&location].events, 2);
+    }
+}
@@ -19,13 +19,17 @@ LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
 OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
 SOFTWARE.
 */
+use chrono::{DateTime, Utc};
 use crossbeam_channel::Sender;
 use crossbeam_utils::sync::{Parker, Unparker};
 use crossbeam_utils::Backoff;
+use flate2::read::MultiGzDecoder;
 use log::{debug, error, info, warn};
+use std::cell::RefCell;
+use std::ffi::CStr;
 use std::fs;
-use std::io::{Error, ErrorKind};
-use std::path::Path;
+use std::io::{Error, ErrorKind, Read};
+use std::path::{Path, PathBuf};
 use std::process::exit;
 use std::sync::atomic::AtomicBool;
 use std::sync::atomic::Ordering::SeqCst;
@@ -33,13 +37,72 @@ use std::sync::Arc;
 use std::thread::sleep;
 use std::time::Duration;
 
+/// Exit status for a fatal error while running, e.g., when archiving fails
+/// for other reasons than a full archive. Restarting may help.
+pub const EXIT_RUNTIME: i32 = 1;
+/// Exit status for invalid command line options or configuration files. This
+/// matches the status of clap's usage errors.
+pub const EXIT_CONFIG: i32 = 2;
+/// Exit status when the spool directory does not exist
+pub const EXIT_SPOOL: i32 = 3;
+/// Exit status when the archiver cannot be set up or fails its startup check
+pub const EXIT_BACKEND: i32 = 4;
+
+thread_local! {
+    static JOB_CONTEXT: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+/// Tags every log line of the current thread with the given job, until the
+/// returned value is dropped. Contexts can be nested, e.g., for the tasks of
+/// an array job.
+pub struct JobContext {
+    previous: Option<String>,
+}
+
+impl JobContext {
+    pub fn enter(cluster: &str, jobid: &str) -> JobContext {
+        let context = format!("cluster={cluster} jobid={jobid}");
+        JobContext {
+            previous: JOB_CONTEXT.with(|c| c.replace(Some(context))),
+        }
+    }
+}
+
+impl Drop for JobContext {
+    fn drop(&mut self) {
+        JOB_CONTEXT.with(|c| *c.borrow_mut() = self.previous.take());
+    }
+}
+
+/// Returns the job context of the current thread, if any, for use in log lines
+pub fn job_context() -> Option<String> {
+    JOB_CONTEXT.with(|c| c.borrow().clone())
+}
+
+/// Returns the modification time of the given path, if available
+pub fn modified_time(path: &Path) -> Option<DateTime<Utc>> {
+    fs::metadata(path)
+        .and_then(|m| m.modified())
+        .ok()
+        .map(DateTime::<Utc>::from)
+}
+
 /// Read file contents of the file given by the path. Separating the
 /// directory from the filename (which may contain directory hierarchy)
 /// is that we are able to monitor the path in case it dissapears (e.g.,
 /// when a job is removed before we can get the information)
 ///
-/// We return the raw bytes, so the contents can be processed later if needed
+/// We return the raw bytes, so the contents can be processed later if needed.
+/// If the directory vanishes, the error is of kind `NotFound`; if the file does
+/// not appear in time, it is of kind `TimedOut`.
 pub fn read_file(path: &Path, filename: &Path, iters: Option<u32>) -> Result<Vec<u8>, Error> {
+    fs::read(wait_for_file(path, filename, iters)?)
+}
+
+/// Waits for the file to appear in the given directory and returns its path,
+/// failing as `read_file` does. Callers that should not read the whole file
+/// into memory can use this to check its size first.
+pub fn wait_for_file(path: &Path, filename: &Path, iters: Option<u32>) -> Result<PathBuf, Error> {
     let fpath = path.join(filename);
     let mut iters = iters.unwrap_or(100);
     let ten_millis = Duration::from_millis(10);
@@ -59,14 +122,49 @@ pub fn read_file(path: &Path, filename: &Path, iters: Option<u32>) -> Result<Vec
         0 => {
             warn!("Timeout waiting for {:?} to appear", &fpath);
             Err(Error::new(
-                ErrorKind::NotFound,
+                ErrorKind::TimedOut,
                 format!("File {:?} did not appear after waiting 1s", &fpath),
             ))
         }
-        _ => fs::read(&fpath),
+        _ => Ok(fpath),
+    }
+}
+
+/// Decompresses the contents if they are gzip compressed, as recognised by
+/// their magic number. Other contents are returned as they are.
+pub fn decompress(contents: Vec<u8>) -> Result<Vec<u8>, Error> {
+    if !contents.starts_with(&[0x1f, 0x8b]) {
+        return Ok(contents);
+    }
+    let mut decompressed = Vec::new();
+    MultiGzDecoder::new(contents.as_slice()).read_to_end(&mut decompressed)?;
+    Ok(decompressed)
+}
+
+/// Splits the path into its parent directory and file name, so it can be
+/// handed to `read_file`
+pub fn split_path(path: &Path) -> Result<(&Path, &Path), Error> {
+    match (path.parent(), path.file_name()) {
+        (Some(dir), Some(filename)) => Ok((dir, Path::new(filename))),
+        _ => Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("{:?} is not a file path", path),
+        )),
     }
 }
 
+/// Returns the name of the user with the given uid, if it has one
+pub fn user_name(uid: u32) -> Option<String> {
+    // SAFETY: we only read from the returned entry before any other call can
+    // overwrite it
+    let pw = unsafe { libc::getpwuid(uid) };
+    if pw.is_null() {
+        return None;
+    }
+    let name = unsafe { CStr::from_ptr((*pw).pw_name) };
+    Some(name.to_string_lossy().into_owned())
+}
+
 /// Register the handler for the given signal, so we can properly cleanup all threads
 pub fn register_signal_handler(signal: i32, unparker: &Unparker, notification: &Arc<AtomicBool>) {
     info!("Registering signal handler for signal {}", signal);
@@ -79,13 +177,22 @@ pub fn register_signal_handler(signal: i32, unparker: &Unparker, notification: &
             u1.unpark()
         }) {
             error!("Cannot register signal {}: {:?}", signal, e);
-            exit(1);
+            exit(EXIT_RUNTIME);
         }
     };
 }
 
 /// Handle the signal
-pub fn signal_handler_atomic(sender: &Sender<bool>, sig: Arc<AtomicBool>, p: &Parker) {
+///
+/// When the quit flag is raised as well (e.g., on SIGQUIT), the program exits
+/// right away instead of notifying the other threads, so job entries still
+/// waiting in the queue are not processed.
+pub fn signal_handler_atomic(
+    sender: &Sender<bool>,
+    sig: Arc<AtomicBool>,
+    quit: &AtomicBool,
+    p: &Parker,
+) {
     let backoff = Backoff::new();
 
     while !sig.load(SeqCst) {
@@ -96,11 +203,18 @@ pub fn signal_handler_atomic(sender: &Sender<bool>, sig: Arc<AtomicBool>, p: &Pa
         }
     }
 
-    for _ in 0..20 {
+    if quit.load(SeqCst) {
+        warn!("Quitting immediately, queued job entries are not processed");
+        exit(EXIT_RUNTIME);
+    }
+
+    // one for each thread listening, as many as the channel holds
+    let notifications = sender.capacity().unwrap_or(20);
+    for _ in 0..notifications {
         sender.send(true).unwrap();
     }
 
-    info!("Sent 20 notifications");
+    info!("Sent {} notifications", notifications);
 }
 
 #[cfg(test)]
@@ -125,7 +239,7 @@ mod tests {
         fs::write(&file_path, b"test contents").expect("Failed to write to test file");
 
         // Test: Read the contents of the existing file
-        let result = read_file(temp_dir.path(), &Path::new("test_file.txt"), None);
+        let result = read_file(temp_dir.path(), Path::new("test_file.txt"), None);
         assert!(result.is_ok());
         assert_eq!(result.unwrap(), b"test contents");
     }
@@ -136,10 +250,12 @@ mod tests {
         let temp_dir = tempdir().expect("Failed to create temporary directory");
 
         // Test: Attempt to read contents of a nonexistent file
-        let result = read_file(temp_dir.path(), &Path::new("nonexistent_file.txt"), Some(1));
+        let result = read_file(temp_dir.path(), Path::new("nonexistent_file.txt"), Some(1));
         assert!(result.is_err());
+        let err = result.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::TimedOut);
         assert_eq!(
-            result.unwrap_err().to_string(),
+            err.to_string(),
             format!(
                 "File \"{}/nonexistent_file.txt\" did not appear after waiting 1s",
                 temp_dir.path().display()
@@ -147,6 +263,54 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_job_context() {
+        assert_eq!(job_context(), None);
+        {
+            let _job = JobContext::enter("mycluster", "2");
+            assert_eq!(job_context().unwrap(), "cluster=mycluster jobid=2");
+            {
+                let _task = JobContext::enter("mycluster", "2-1");
+                assert_eq!(job_context().unwrap(), "cluster=mycluster jobid=2-1");
+            }
+            assert_eq!(job_context().unwrap(), "cluster=mycluster jobid=2");
+        }
+        assert_eq!(job_context(), None);
+    }
+
+    #[test]
+    fn test_read_file_vanished_directory() {
+        let temp_dir = tempdir().expect("Failed to create temporary directory");
+        let job_dir = temp_dir.path().join("job.1234");
+
+        let result = read_file(&job_dir, Path::new("script"), Some(10));
+        assert_eq!(result.unwrap_err().kind(), ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn test_decompress() {
+        use flate2::write::GzEncoder;
+        use flate2::Compression;
+        use std::io::Write;
+
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(b"<job><id>1</id></job>").unwrap();
+        let compressed = encoder.finish().unwrap();
+
+        assert_eq!(decompress(compressed).unwrap(), b"<job><id>1</id></job>");
+        assert_eq!(decompress(b"plain".to_vec()).unwrap(), b"plain");
+        assert!(decompress(vec![0x1f, 0x8b, 0x00]).is_err());
+    }
+
+    #[test]
+    fn test_split_path() {
+        let (dir, filename) = split_path(Path::new("/var/spool/torque/1.SC")).unwrap();
+        assert_eq!(dir, Path::new("/var/spool/torque"));
+        assert_eq!(filename, Path::new("1.SC"));
+
+        assert!(split_path(Path::new("/")).is_err());
+    }
+
     #[test]
     fn test_register_signal_handler() {
         // Setup: Create a mock unparker and an atomic boolean
@@ -154,7 +318,7 @@ mod tests {
         let notification = Arc::new(AtomicBool::new(false));
 
         // Test: Register a mock signal handler and trigger the signal
-        register_signal_handler(1, &unparker.unparker(), &notification);
+        register_signal_handler(1, unparker.unparker(), &notification);
 
         // Introduce a delay to allow the signal handler to register
         std::thread::sleep(Duration::from_millis(100));
@@ -184,7 +348,7 @@ mod tests {
 
         // Spawn a thread to run the signal_handler_atomic function
         let handle = std::thread::spawn(move || {
-            signal_handler_atomic(&sender_clone, sig_clone, &parker);
+            signal_handler_atomic(&sender_clone, sig_clone, &AtomicBool::new(false), &parker);
         });
 
         // Simulate the signal being set to true
@@ -197,7 +361,7 @@ mod tests {
 
         // Verify that the sender sent the correct number of messages
         let mut count = 0;
-        while let Ok(_) = receiver.try_recv() {
+        while receiver.try_recv().is_ok() {
             count += 1;
         }
 
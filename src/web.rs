@@ -0,0 +1,522 @@
+/*
+Copyright 2019-2024 Andy Georges <itkovian+sarchive@gmail.com>
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in
+all copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+use chrono::NaiveDate;
+use clap::Args;
+use log::{debug, info, warn};
+use std::fmt::Write as _;
+use std::fs::read;
+use std::io::{BufRead, BufReader, Error, ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::index::{ArchivedJob, Index, Layout, SCRIPT_FILES};
+
+/// How long the index of the archive is used before it is built anew
+const REFRESH_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How long to wait for a browser to send its request
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Requests with headers larger than this are turned down
+const MAX_REQUEST_SIZE: u64 = 16 << 10;
+
+/// Connections beyond this many at a time are closed right away
+const MAX_CONNECTIONS: usize = 32;
+
+/// At most this many jobs are listed for a search
+const MAX_RESULTS: usize = 200;
+
+/// Lines holding scheduler directives start with one of these
+const DIRECTIVE_PREFIXES: [&str; 3] = ["#SBATCH", "#PBS", "#BSUB"];
+
+const STYLE: &str = "body{font-family:sans-serif;margin:2em}\
+table{border-collapse:collapse}td,th{padding:.2em .8em;text-align:left}\
+pre{background:#f6f8fa;padding:1em;overflow:auto}\
+.comment{color:#6a737d}.directive{color:#005cc5;font-weight:bold}";
+
+/// Command line options for the browse subcommand
+#[derive(Args, Debug)]
+pub struct BrowseArgs {
+    #[arg(long, help = "Archive directory written by the file archiver")]
+    pub archive: PathBuf,
+
+    #[arg(
+        long,
+        value_name = "TEMPLATE",
+        help = "Name template the archive was written with, if any, to find the job files by"
+    )]
+    pub name_template: Option<String>,
+
+    #[arg(
+        long,
+        value_name = "ADDRESS",
+        default_value = "127.0.0.1:8080",
+        help = "Address and port to serve the pages on"
+    )]
+    pub listen: String,
+}
+
+/// What to look for in the archive; the criteria that are given must all match
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Query {
+    /// Job IDs starting with this, so array tasks are found with the job
+    pub jobid: Option<String>,
+    pub user: Option<String>,
+    pub date: Option<NaiveDate>,
+}
+
+impl Query {
+    /// Reads the query from the query string of a URL, ignoring empty fields
+    pub fn parse(query: &str) -> Result<Query, String> {
+        let mut parsed = Query::default();
+        for (key, value) in query.split('&').filter_map(|f| f.split_once('=')) {
+            let value = percent_decode(value);
+            let value = value.trim();
+            if value.is_empty() {
+                continue;
+            }
+            match key {
+                "jobid" => parsed.jobid = Some(value.to_owned()),
+                "user" => parsed.user = Some(value.to_owned()),
+                "date" => {
+                    let date = NaiveDate::parse_from_str(value, "%Y-%m-%d")
+                        .map_err(|_| format!("Invalid date {value:?}, expected YYYY-MM-DD"))?;
+                    parsed.date = Some(date);
+                }
+                _ => (),
+            }
+        }
+        Ok(parsed)
+    }
+
+    fn is_empty(&self) -> bool {
+        *self == Query::default()
+    }
+
+    fn matches(&self, job: &ArchivedJob) -> bool {
+        self.jobid
+            .iter()
+            .all(|id| job.jobid.starts_with(id.as_str()))
+            && self.user.iter().all(|u| job.user.as_ref() == Some(u))
+            && self.date.iter().all(|d| job.date == *d)
+    }
+}
+
+impl Index {
+    /// Returns the jobs that match the query, most recently archived first
+    pub fn search<'a>(&'a self, query: &'a Query) -> impl Iterator<Item = &'a ArchivedJob> {
+        self.jobs.iter().filter(move |job| query.matches(job))
+    }
+}
+
+/// Decodes the `%XX` escapes and the `+` for spaces of a URL query value
+pub fn percent_decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => decoded.push(b' '),
+            b'%' if i + 2 < bytes.len() => {
+                let hex = |b: u8| (b as char).to_digit(16);
+                match (hex(bytes[i + 1]), hex(bytes[i + 2])) {
+                    (Some(high), Some(low)) => {
+                        decoded.push((high * 16 + low) as u8);
+                        i += 2;
+                    }
+                    _ => decoded.push(b'%'),
+                }
+            }
+            b => decoded.push(b),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+/// Escapes the text for use in HTML
+pub fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Returns the script as HTML, with the scheduler directives and the
+/// comments marked
+pub fn highlight(script: &str) -> String {
+    let mut html = String::new();
+    for line in script.lines() {
+        let first = line.split_whitespace().next().unwrap_or_default();
+        let class = if DIRECTIVE_PREFIXES.contains(&first) {
+            Some("directive")
+        } else if line.trim_start().starts_with('#') {
+            Some("comment")
+        } else {
+            None
+        };
+        match class {
+            Some(class) => writeln!(html, "<span class=\"{class}\">{}</span>", escape(line)),
+            None => writeln!(html, "{}", escape(line)),
+        }
+        .unwrap();
+    }
+    html
+}
+
+fn page(title: &str, body: &str) -> String {
+    format!(
+        "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\"><title>{}</title><style>{STYLE}</style></head>\n<body><h1><a href=\"/\">sarchive</a></h1>\n{body}</body></html>\n",
+        escape(title)
+    )
+}
+
+/// Returns the search page, with the jobs matching the query, if any
+pub fn search_page(index: &Index, query: &Query) -> String {
+    let value = |v: Option<String>| escape(&v.unwrap_or_default());
+    let mut body = format!(
+        "<form action=\"/\">Job ID <input name=\"jobid\" value=\"{}\"> User <input name=\"user\" value=\"{}\"> Date <input name=\"date\" placeholder=\"YYYY-MM-DD\" value=\"{}\"> <button>Search</button></form>\n",
+        value(query.jobid.clone()),
+        value(query.user.clone()),
+        value(query.date.map(|d| d.to_string())),
+    );
+    if query.is_empty() {
+        writeln!(body, "<p>{} jobs in the archive</p>", index.jobs.len()).unwrap();
+        return page("sarchive", &body);
+    }
+    let found: Vec<&ArchivedJob> = index.search(query).take(MAX_RESULTS + 1).collect();
+    if found.is_empty() {
+        body.push_str("<p>No jobs found</p>\n");
+        return page("sarchive", &body);
+    }
+    body.push_str("<table><tr><th>Job</th><th>User</th><th>Archived</th><th>Files</th></tr>\n");
+    for job in found.iter().take(MAX_RESULTS) {
+        let names: Vec<&str> = job.files.keys().map(|n| n.as_str()).collect();
+        writeln!(
+            body,
+            "<tr><td><a href=\"/job/{0}\">{0}</a></td><td>{1}</td><td>{2}</td><td>{3}</td></tr>",
+            escape(&job.jobid),
+            escape(job.user.as_deref().unwrap_or("")),
+            job.date,
+            escape(&names.join(", "))
+        )
+        .unwrap();
+    }
+    body.push_str("</table>\n");
+    if found.len() > MAX_RESULTS {
+        writeln!(body, "<p>Only the first {MAX_RESULTS} jobs are shown</p>").unwrap();
+    }
+    page("sarchive", &body)
+}
+
+/// Returns the page showing the archived files of the job, or None if the
+/// archive does not hold it
+pub fn job_page(index: &Index, jobid: &str) -> Option<String> {
+    let jobs: Vec<&ArchivedJob> = index.jobs.iter().filter(|j| j.jobid == jobid).collect();
+    if jobs.is_empty() {
+        return None;
+    }
+    let mut body = String::new();
+    for job in jobs {
+        writeln!(
+            body,
+            "<h2>Job {}</h2>\n<p>User {}, archived {}</p>",
+            escape(&job.jobid),
+            escape(job.user.as_deref().unwrap_or("unknown")),
+            job.date
+        )
+        .unwrap();
+        for (name, path) in job.files.iter() {
+            let contents = match read(path) {
+                Ok(contents) => String::from_utf8_lossy(&contents).into_owned(),
+                Err(e) => format!("Cannot read {path:?}: {e}"),
+            };
+            // environments separate their variables with NUL bytes
+            let contents = contents.replace('\0', "\n");
+            let html = if SCRIPT_FILES.contains(&name.as_str()) {
+                highlight(&contents)
+            } else {
+                escape(&contents)
+            };
+            writeln!(body, "<h3>{}</h3>\n<pre>{}</pre>", escape(name), html).unwrap();
+        }
+    }
+    Some(page(&format!("Job {jobid}"), &body))
+}
+
+/// Keeps the index of the archive, building it anew when it gets old
+struct Browser {
+    archive: PathBuf,
+    layout: Layout,
+    index: Index,
+    built: Instant,
+}
+
+impl Browser {
+    fn index(&mut self) -> &Index {
+        if self.built.elapsed() >= REFRESH_INTERVAL {
+            match Index::build(&self.archive, &self.layout) {
+                Ok(index) => {
+                    debug!("Indexed {} jobs in {:?}", index.jobs.len(), &self.archive);
+                    self.index = index;
+                }
+                Err(e) => warn!("Cannot index {:?}, keeping the index: {}", &self.archive, e),
+            }
+            self.built = Instant::now();
+        }
+        &self.index
+    }
+
+    /// Returns the status, content type and body of the answer to the request
+    fn answer(&mut self, method: &str, target: &str) -> (&'static str, &'static str, String) {
+        let html = "text/html; charset=utf-8";
+        let text = "text/plain; charset=utf-8";
+        if method != "GET" {
+            return (
+                "405 Method Not Allowed",
+                text,
+                "Only GET is supported\n".to_owned(),
+            );
+        }
+        let (path, query) = target.split_once('?').unwrap_or((target, ""));
+        match path {
+            "/health" => ("200 OK", text, "ok\n".to_owned()),
+            "/" => match Query::parse(query) {
+                Ok(query) => ("200 OK", html, search_page(self.index(), &query)),
+                Err(e) => ("400 Bad Request", text, format!("{e}\n")),
+            },
+            _ => match path.strip_prefix("/job/").map(percent_decode) {
+                Some(jobid) => match job_page(self.index(), &jobid) {
+                    Some(page) => ("200 OK", html, page),
+                    None => (
+                        "404 Not Found",
+                        text,
+                        format!("No job {jobid} in the archive\n"),
+                    ),
+                },
+                None => ("404 Not Found", text, "Not found\n".to_owned()),
+            },
+        }
+    }
+}
+
+/// Reads the request line from the stream, skipping the headers. A browser
+/// gets a while for each read, and only so much can be sent.
+fn read_request(stream: &TcpStream) -> Result<String, Error> {
+    stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+    let mut reader = BufReader::new(stream.take(MAX_REQUEST_SIZE));
+    let too_large = || Error::new(ErrorKind::InvalidData, "Request too large");
+    let mut request = String::new();
+    reader.read_line(&mut request)?;
+    if !request.ends_with('\n') {
+        return Err(too_large());
+    }
+    // the headers are of no use
+    let mut header = String::new();
+    while reader.read_line(&mut header)? > 0 && !header.trim().is_empty() {
+        if !header.ends_with('\n') {
+            return Err(too_large());
+        }
+        header.clear();
+    }
+    Ok(request)
+}
+
+/// Answers the request on the stream, only holding the browser while the
+/// answer is put together
+fn respond(browser: &Mutex<Browser>, stream: TcpStream) -> Result<(), Error> {
+    let request = read_request(&stream)?;
+    let mut words = request.split_whitespace();
+    let (method, target) = (
+        words.next().unwrap_or_default(),
+        words.next().unwrap_or("/"),
+    );
+    let (status, content_type, body) = browser
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .answer(method, target);
+    debug!("{} {} {}", method, target, status);
+    let mut stream = &stream;
+    write!(
+        stream,
+        "HTTP/1.1 {status}\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    )?;
+    stream.flush()
+}
+
+/// The serve function answers the requests on the listener, each connection on
+/// a thread of its own, with pages to search the archive and show the archived
+/// jobs. It never changes the archive.
+pub fn serve(archive: &Path, layout: Layout, listener: TcpListener) -> Result<(), Error> {
+    let index = Index::build(archive, &layout)?;
+    info!(
+        "Serving {} jobs in {:?} on http://{}",
+        index.jobs.len(),
+        archive,
+        listener.local_addr()?
+    );
+    let browser = Arc::new(Mutex::new(Browser {
+        archive: archive.to_path_buf(),
+        layout,
+        index,
+        built: Instant::now(),
+    }));
+    let connections = Arc::new(AtomicUsize::new(0));
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                warn!("Could not accept connection: {}", e);
+                continue;
+            }
+        };
+        if connections.fetch_add(1, Ordering::SeqCst) >= MAX_CONNECTIONS {
+            connections.fetch_sub(1, Ordering::SeqCst);
+            warn!(
+                "Closing connection, already serving {} others",
+                MAX_CONNECTIONS
+            );
+            continue;
+        }
+        let browser = Arc::clone(&browser);
+        let connections = Arc::clone(&connections);
+        thread::spawn(move || {
+            if let Err(e) = respond(&browser, stream) {
+                warn!("Could not answer request: {}", e);
+            }
+            connections.fetch_sub(1, Ordering::SeqCst);
+        });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::testing::archive;
+    use chrono::Local;
+    use std::io::Read;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_query() {
+        let query = Query::parse("jobid=12&user=al%20ice&date=2024-01-31&other=x").unwrap();
+        assert_eq!(query.jobid.as_deref(), Some("12"));
+        assert_eq!(query.user.as_deref(), Some("al ice"));
+        assert_eq!(query.date, NaiveDate::from_ymd_opt(2024, 1, 31));
+        assert!(Query::parse("jobid=&user=").unwrap().is_empty());
+        assert!(Query::parse("date=yesterday").is_err());
+        assert_eq!(percent_decode("a+b%2Fc%zz%4"), "a b/c%zz%4");
+    }
+
+    #[test]
+    fn test_index() {
+        let tdir = tempdir().unwrap();
+        archive(tdir.path());
+        let index = Index::build(tdir.path(), &Layout::default()).unwrap();
+        let jobids: Vec<&str> = index.jobs.iter().map(|j| j.jobid.as_str()).collect();
+        assert_eq!(jobids, vec!["123", "4567", "890"]);
+        assert_eq!(index.jobs[0].user.as_deref(), Some("alice"));
+        assert_eq!(
+            index.jobs[2].files.keys().collect::<Vec<_>>(),
+            vec!["script"]
+        );
+
+        let today = Local::now().date_naive();
+        let found = |query: &str| -> Vec<String> {
+            let query = Query::parse(query).unwrap();
+            index.search(&query).map(|j| j.jobid.clone()).collect()
+        };
+        assert_eq!(found("jobid=12"), vec!["123"]);
+        assert_eq!(found("user=alice"), vec!["123"]);
+        assert_eq!(found(&format!("date={today}")).len(), 3);
+        assert!(found("date=2000-01-01").is_empty());
+    }
+
+    #[test]
+    fn test_pages() {
+        let tdir = tempdir().unwrap();
+        archive(tdir.path());
+        let index = Index::build(tdir.path(), &Layout::default()).unwrap();
+
+        let page = search_page(&index, &Query::parse("user=alice").unwrap());
+        assert!(page.contains("<a href=\"/job/123\">123</a>"));
+        assert!(!page.contains("4567"));
+
+        let page = job_page(&index, "123").unwrap();
+        assert!(page.contains("<span class=\"directive\">#SBATCH --time=1:00:00</span>"));
+        assert!(page.contains("<span class=\"comment\"># run it</span>"));
+        assert!(page.contains("echo &quot;&lt;done&gt;&quot;"));
+        assert!(page.contains("HOME=/home/alice"));
+        assert!(job_page(&index, "124").is_none());
+        assert!(job_page(&index, "890").unwrap().contains("hostname"));
+    }
+
+    #[test]
+    fn test_serve() {
+        let tdir = tempdir().unwrap();
+        archive(tdir.path());
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let root = tdir.path().to_path_buf();
+        std::thread::spawn(move || serve(&root, Layout::default(), listener));
+
+        let get = |request: &str| {
+            let mut stream = TcpStream::connect(address).unwrap();
+            write!(stream, "{request}\r\nHost: localhost\r\n\r\n").unwrap();
+            let mut answer = String::new();
+            stream.read_to_string(&mut answer).unwrap();
+            answer
+        };
+        assert!(get("GET /health HTTP/1.1").starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(get("GET /?jobid=4567 HTTP/1.1").contains("/job/4567"));
+        assert!(get("GET /job/999 HTTP/1.1").starts_with("HTTP/1.1 404"));
+        assert!(get("POST / HTTP/1.1").starts_with("HTTP/1.1 405"));
+
+        // an idle browser does not hold up the others
+        let _idle = TcpStream::connect(address).unwrap();
+        assert!(get("GET /health HTTP/1.1").starts_with("HTTP/1.1 200 OK\r\n"));
+
+        // nor is a request of any size read
+        let mut stream = TcpStream::connect(address).unwrap();
+        let long = "x".repeat(MAX_REQUEST_SIZE as usize);
+        let _ = write!(stream, "GET /{long} HTTP/1.1\r\n\r\n");
+        let mut answer = String::new();
+        let _ = stream.read_to_string(&mut answer);
+        assert!(answer.is_empty());
+    }
+}
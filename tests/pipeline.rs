@@ -0,0 +1,273 @@
+/*
+Copyright 2019-2024 Andy Georges <itkovian+sarchive@gmail.com>
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in
+all copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+//! End-to-end tests of the monitor → process → archive pipeline, fed by a
+//! fake Slurm spool. The tests against external services are ignored by
+//! default; see the README for running them.
+
+use clap::Parser;
+use crossbeam_channel::{bounded, never, unbounded};
+use crossbeam_utils::thread::scope;
+use std::fs;
+use std::path::Path;
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+use tempfile::tempdir;
+
+use sarchive::archive::document::RecordOptions;
+use sarchive::archive::{archive_builder, process, ArchiverArgs, ProcessContext};
+use sarchive::completion::ArchivedJobs;
+use sarchive::identity::Identity;
+use sarchive::maintenance::Maintenance;
+use sarchive::monitor::{manage, WatchCommand};
+use sarchive::reconcile::Reconciler;
+use sarchive::scheduler::rules::FileRules;
+use sarchive::scheduler::torque::TorqueArgs;
+use sarchive::scheduler::{create, Capture, JobEvent, SchedulerKind, SchedulerOptions};
+use sarchive::stats::Stats;
+use sarchive::supervisor::Supervisor;
+
+#[derive(Parser)]
+struct Cli {
+    #[command(flatten)]
+    torque: TorqueArgs,
+
+    #[command(subcommand)]
+    archiver: ArchiverArgs,
+}
+
+/// Submits a job to the fake spool the way slurmctld does: the job directory
+/// is filled under a temporary name and moved into its hash directory.
+fn submit(spool: &Path, jobid: u32) {
+    let fixture = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/job.123456");
+    let hash_dir = spool.join(format!("hash.{}", jobid % 10));
+    let tmp_dir = hash_dir.join(format!("tmp.{jobid}"));
+    fs::create_dir(&tmp_dir).unwrap();
+    for file in ["script", "environment"] {
+        fs::copy(fixture.join(file), tmp_dir.join(file)).unwrap();
+    }
+    fs::rename(&tmp_dir, hash_dir.join(format!("job.{jobid}"))).unwrap();
+}
+
+/// Runs the pipeline against a fake Slurm spool with the given archiver,
+/// submitting the given jobs once the spool is watched. The pipeline stops
+/// when `done` holds, or after a timeout.
+fn run_pipeline<F>(args: &[&str], jobids: &[u32], done: F)
+where
+    F: Fn() -> bool,
+{
+    let spool = tempdir().unwrap();
+    for hash in 0..10 {
+        fs::create_dir(spool.path().join(format!("hash.{hash}"))).unwrap();
+    }
+
+    let cli = Cli::parse_from(["sarchive"].iter().chain(args));
+    let archiver = archive_builder(
+        &cli.archiver,
+        &Identity::default(),
+        &RecordOptions::default(),
+    )
+    .unwrap();
+    let sched = create(
+        &SchedulerKind::Slurm,
+        spool.path(),
+        "mycluster",
+        &SchedulerOptions {
+            torque_args: &cli.torque,
+            event_kinds: &[JobEvent::Create, JobEvent::Rename],
+            max_buffered_size: None,
+            capture_credentials: false,
+            capture: Capture::All,
+            resolve_sourced: None,
+            slurm_rules: Arc::new(FileRules::default()),
+        },
+    )
+    .unwrap();
+    let stats = Stats::new();
+    let reload = AtomicBool::new(false);
+    let reconciler = Reconciler::default();
+
+    let (sig_sender, sig_receiver) = bounded(20);
+    let (sender, receiver) = unbounded();
+    let (location_sender, location_receiver) = unbounded();
+    for loc in sched.watch_locations() {
+        location_sender.send(WatchCommand::Add(loc)).unwrap();
+    }
+
+    scope(|s| {
+        let (sl, lr, t, sr, rl, st, rc) = (
+            &sched,
+            &location_receiver,
+            &sender,
+            &sig_receiver,
+            &reload,
+            &stats,
+            &reconciler,
+        );
+        s.spawn(move |s| {
+            manage(
+                s,
+                sl,
+                lr,
+                t,
+                sr,
+                rl,
+                st,
+                None,
+                rc,
+                &[],
+                Supervisor::default(),
+            )
+        });
+
+        let (r, sr, st, rc) = (&receiver, &sig_receiver, &stats, &reconciler);
+        s.spawn(move |_| {
+            process(
+                archiver.as_ref(),
+                r,
+                &never(),
+                &mut ArchivedJobs::default(),
+                &mut Vec::new(),
+                sr,
+                &ProcessContext {
+                    stats: st,
+                    maintenance: &Maintenance::default(),
+                    reconciler: rc,
+                    deadline: None,
+                    tombstones: false,
+                    cleanup: false,
+                    max_batch: 8,
+                },
+            )
+            .unwrap()
+        });
+
+        // give the watchers time to start
+        sleep(Duration::from_millis(1000));
+        for jobid in jobids {
+            submit(spool.path(), *jobid);
+        }
+
+        let start = Instant::now();
+        while !done() && start.elapsed() < Duration::from_secs(10) {
+            sleep(Duration::from_millis(100));
+        }
+        for _ in 0..20 {
+            sig_sender.send(true).unwrap();
+        }
+    })
+    .unwrap();
+}
+
+#[test]
+fn test_pipeline_file() {
+    let archive = tempdir().unwrap();
+    let archive_path = archive.path().to_str().unwrap();
+    let jobids = [123456, 123457, 123458];
+    let archived = |jobid: &u32| {
+        archive
+            .path()
+            .join(format!("job.{jobid}_environment"))
+            .exists()
+    };
+
+    run_pipeline(&["file", archive_path, "none"], &jobids, || {
+        jobids.iter().all(archived)
+    });
+
+    for jobid in jobids {
+        let script =
+            fs::read_to_string(archive.path().join(format!("job.{jobid}_script"))).unwrap();
+        assert!(script.starts_with("#!/bin/bash"));
+        assert!(script.contains("echo \"hello\""));
+        assert!(archived(&jobid));
+    }
+}
+
+#[test]
+fn test_pipeline_jsonl() {
+    let archive = tempdir().unwrap();
+    let archive_path = archive.path().to_str().unwrap();
+    let jsonl = archive.path().join("sarchive.jsonl");
+    let lines = || {
+        fs::read_to_string(&jsonl)
+            .map(|s| s.lines().count())
+            .unwrap_or(0)
+    };
+
+    run_pipeline(&["jsonl", archive_path], &[123456, 123457], || lines() == 2);
+
+    let mut ids: Vec<String> = fs::read_to_string(&jsonl)
+        .unwrap()
+        .lines()
+        .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+        .map(|doc| doc["id"].as_str().unwrap().to_owned())
+        .collect();
+    ids.sort();
+    assert_eq!(ids, vec!["123456", "123457"]);
+}
+
+/// Requires a Kafka broker, e.g.,
+/// `docker run -d -p 9092:9092 apache/kafka`, given in the
+/// `SARCHIVE_TEST_KAFKA_BROKERS` environment variable.
+#[cfg(feature = "kafka")]
+#[test]
+#[ignore]
+fn test_pipeline_kafka() {
+    use rdkafka::config::ClientConfig;
+    use rdkafka::consumer::{BaseConsumer, Consumer};
+    use rdkafka::message::Message;
+
+    let brokers = std::env::var("SARCHIVE_TEST_KAFKA_BROKERS")
+        .expect("SARCHIVE_TEST_KAFKA_BROKERS should point to a Kafka broker");
+    let topic = format!("sarchive-test-{}", std::process::id());
+
+    run_pipeline(
+        &["kafka", "--brokers", &brokers, "--topic", &topic],
+        &[123456],
+        || false,
+    );
+
+    let consumer: BaseConsumer = ClientConfig::new()
+        .set("bootstrap.servers", &brokers)
+        .set("group.id", &topic)
+        .set("auto.offset.reset", "earliest")
+        .create()
+        .unwrap();
+    consumer.subscribe(&[&topic]).unwrap();
+
+    let start = Instant::now();
+    let doc = loop {
+        assert!(
+            start.elapsed() < Duration::from_secs(30),
+            "No message on topic {topic}"
+        );
+        if let Some(message) = consumer.poll(Duration::from_secs(1)) {
+            let message = message.unwrap();
+            break serde_json::from_slice::<serde_json::Value>(message.payload().unwrap()).unwrap();
+        }
+    };
+    assert_eq!(doc["id"], "123456");
+    assert_eq!(doc["cluster"], "mycluster");
+}